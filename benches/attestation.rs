@@ -0,0 +1,200 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Benchmarks for the attestation hot paths: quote generation, IMA
+// measurement list reading and serialization, payload decryption and HMAC
+// verification.
+//
+// The functions are those of the agent, through the keylime_agent_sys
+// library: the payload crypto and the IMA measurement list reading it shares
+// with the agent, and the identity quote of its C API, see ffi.rs.
+//
+// The quote benchmark needs a running agent and only runs when
+// KEYLIME_AGENT_SOCKET is set to its 'admin_socket', as root. Each quote is
+// then assembled by the agent as for /quotes/identity, see tpm::quote().
+
+use criterion::{
+    criterion_group, criterion_main, BenchmarkId, Criterion, Throughput,
+};
+use keylime_agent_sys::{
+    aead, ima, keylime_agent_error, keylime_agent_identity_quote,
+    keylime_agent_string_free,
+};
+use openssl::hash::MessageDigest;
+use std::{
+    ffi::{CStr, CString},
+    fs::File,
+    io::Write,
+    path::Path,
+    ptr,
+};
+use tempfile::NamedTempFile;
+
+const PAYLOAD_SIZES: [usize; 3] = [1024, 64 * 1024, 1024 * 1024];
+const IMA_ENTRIES: [usize; 3] = [1_000, 10_000, 100_000];
+
+fn ima_log(entries: usize) -> NamedTempFile {
+    let sample = std::fs::read_to_string(
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("ima")
+            .join("ascii_runtime_measurements"),
+    )
+    .expect("unable to read sample IMA log");
+    let lines: Vec<&str> = sample.lines().collect();
+
+    let mut tf = NamedTempFile::new().expect("unable to create IMA log");
+    for line in lines.iter().cycle().take(entries) {
+        writeln!(tf, "{}", line).expect("unable to write IMA log");
+    }
+    tf.flush().expect("unable to write IMA log");
+    tf
+}
+
+fn bench_ima(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ima_measurement_list");
+    for entries in IMA_ENTRIES {
+        let log = ima_log(entries);
+        let _ = group.throughput(Throughput::Elements(entries as u64));
+
+        // A verifier polling for the first time reads the whole list
+        let _ = group.bench_with_input(
+            BenchmarkId::new("read_full", entries),
+            &entries,
+            |b, _| {
+                b.iter(|| {
+                    let mut ima_ml = ima::ImaMeasurementList::new();
+                    let mut file =
+                        File::open(log.path()).expect("unable to open log");
+                    ima::read_measurement_list(&mut ima_ml, &mut file, 0)
+                        .expect("unable to read IMA log")
+                })
+            },
+        );
+
+        // Subsequent polls only read the delta since the last entry seen
+        let _ = group.bench_with_input(
+            BenchmarkId::new("read_delta", entries),
+            &entries,
+            |b, &entries| {
+                let mut ima_ml = ima::ImaMeasurementList::new();
+                let mut file =
+                    File::open(log.path()).expect("unable to open log");
+                let _ = ima::read_measurement_list(&mut ima_ml, &mut file, 0)
+                    .expect("unable to read IMA log");
+                let nth_entry = (entries - entries / 100) as u64;
+                b.iter(|| {
+                    ima::read_measurement_list(
+                        &mut ima_ml,
+                        &mut file,
+                        nth_entry,
+                    )
+                    .expect("unable to read IMA log")
+                })
+            },
+        );
+
+        let mut ima_ml = ima::ImaMeasurementList::new();
+        let mut file = File::open(log.path()).expect("unable to open log");
+        let (ml, _, _) =
+            ima::read_measurement_list(&mut ima_ml, &mut file, 0)
+                .expect("unable to read IMA log");
+        let ml = ml.expect("empty IMA log");
+        let _ = group.bench_with_input(
+            BenchmarkId::new("serialize", entries),
+            &ml,
+            |b, ml| {
+                b.iter(|| {
+                    serde_json::to_string(&serde_json::json!({
+                        "ima_measurement_list": ml,
+                    }))
+                    .expect("unable to serialize IMA list")
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_payload_decryption(c: &mut Criterion) {
+    let key = [0x42u8; 32];
+    let iv = [0x24u8; aead::IV_LEN];
+
+    let mut group = c.benchmark_group("payload_decryption");
+    for size in PAYLOAD_SIZES {
+        let payload = vec![0xa5u8; size];
+        let encrypted = aead::encrypt(&key, &iv, &payload)
+            .expect("unable to encrypt payload");
+        let _ = group.throughput(Throughput::Bytes(size as u64));
+        let _ = group.bench_with_input(
+            BenchmarkId::new("decrypt_aead", size),
+            &encrypted,
+            |b, encrypted| {
+                b.iter(|| {
+                    aead::decrypt(&key, encrypted)
+                        .expect("unable to decrypt payload")
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_hmac(c: &mut Criterion) {
+    let key = [0x42u8; 32];
+    // The HMAC is computed over the agent UUID, with the default hmac_alg
+    let data = b"d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
+    let hmac = aead::compute_hmac(MessageDigest::sha384(), &key, data)
+        .expect("unable to compute HMAC");
+
+    let _ = c.bench_function("verify_hmac", |b| {
+        b.iter(|| {
+            aead::verify_hmac(MessageDigest::sha384(), &key, data, &hmac)
+                .expect("unable to verify HMAC")
+        })
+    });
+}
+
+fn bench_quote(c: &mut Criterion) {
+    let socket = match std::env::var("KEYLIME_AGENT_SOCKET") {
+        Ok(socket) => CString::new(socket).expect("invalid socket path"),
+        Err(_) => {
+            eprintln!(
+                "KEYLIME_AGENT_SOCKET is not set, skipping quote benchmark"
+            );
+            return;
+        }
+    };
+    // A new nonce for each quote, so that it is neither served from the
+    // quote cache nor refused as replayed
+    let mut count = 0u64;
+    let _ = c.bench_function("quote", |b| {
+        b.iter(|| {
+            count += 1;
+            let nonce = CString::new(format!("{:020}", count))
+                .expect("invalid nonce");
+            let mut quote = ptr::null_mut();
+            let result = unsafe {
+                keylime_agent_identity_quote(
+                    socket.as_ptr(),
+                    nonce.as_ptr(),
+                    &mut quote,
+                )
+            };
+            if result != 0 {
+                let error = unsafe { CStr::from_ptr(keylime_agent_error()) };
+                panic!("unable to quote: {}", error.to_string_lossy());
+            }
+            unsafe { keylime_agent_string_free(quote) };
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_ima,
+    bench_payload_decryption,
+    bench_hmac,
+    bench_quote
+);
+criterion_main!(benches);
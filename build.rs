@@ -0,0 +1,167 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Collects the build information reported by `keylime_agent --build-info`
+// and the /version endpoint that cannot be determined at runtime, and the
+// CycloneDX SBOM served at /agent/sbom.
+
+use std::{env, fs, path::Path, process::Command};
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8(output.stdout).ok()?;
+    Some(out.trim().to_string()).filter(|s| !s.is_empty())
+}
+
+// Version of a crate as resolved in Cargo.lock
+fn locked_version(name: &str) -> Option<String> {
+    let lock = fs::read_to_string("Cargo.lock").ok()?;
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line == format!("name = \"{}\"", name) {
+            return lines
+                .next()?
+                .strip_prefix("version = \"")?
+                .strip_suffix('"')
+                .map(str::to_string);
+        }
+    }
+    None
+}
+
+#[derive(Default)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    source: Option<String>,
+    checksum: Option<String>,
+}
+
+// Packages of Cargo.lock. It also lists the optional dependencies of the
+// features that are not enabled, the SBOM lists the features alongside.
+fn locked_packages() -> Vec<LockedPackage> {
+    let lock = match fs::read_to_string("Cargo.lock") {
+        Ok(lock) => lock,
+        Err(_) => return Vec::new(),
+    };
+    let mut packages = Vec::new();
+    for entry in lock.split("[[package]]").skip(1) {
+        let mut package = LockedPackage::default();
+        for line in entry.lines() {
+            let (key, value) = match line.split_once(" = \"") {
+                Some((key, value)) => {
+                    (key, value.trim_end_matches('"').to_string())
+                }
+                None => continue,
+            };
+            match key {
+                "name" => package.name = value,
+                "version" => package.version = value,
+                "source" => package.source = Some(value),
+                "checksum" => package.checksum = Some(value),
+                _ => (),
+            }
+        }
+        packages.push(package);
+    }
+    packages
+}
+
+// Features enabled for this build, as resolved by cargo
+fn enabled_features() -> Vec<String> {
+    let mut features = env::vars()
+        .filter_map(|(name, _)| {
+            name.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .collect::<Vec<_>>();
+    features.sort();
+    features
+}
+
+// Quoted JSON string of a value read from Cargo.lock or the environment
+fn json_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// CycloneDX 1.4 SBOM of the agent and the crates it was built with
+fn sbom(git_commit: &str) -> String {
+    let name = env::var("CARGO_PKG_NAME").unwrap_or_default();
+    let version = env::var("CARGO_PKG_VERSION").unwrap_or_default();
+    let components = locked_packages()
+        .iter()
+        .filter(|package| package.name != name)
+        .map(|package| {
+            let mut component = format!(
+                "{{\"type\":\"library\",\"name\":{},\"version\":{},\"purl\":{}",
+                json_string(&package.name),
+                json_string(&package.version),
+                json_string(&format!(
+                    "pkg:cargo/{}@{}",
+                    package.name, package.version
+                ))
+            );
+            if let Some(source) = &package.source {
+                component.push_str(&format!(
+                    ",\"properties\":[{{\"name\":\"cargo:source\",\"value\":{}}}]",
+                    json_string(source)
+                ));
+            }
+            if let Some(checksum) = &package.checksum {
+                component.push_str(&format!(
+                    ",\"hashes\":[{{\"alg\":\"SHA-256\",\"content\":{}}}]",
+                    json_string(checksum)
+                ));
+            }
+            component.push('}');
+            component
+        })
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"bomFormat\":\"CycloneDX\",\"specVersion\":\"1.4\",\"version\":1,\"metadata\":{{\"component\":{{\"type\":\"application\",\"name\":{},\"version\":{},\"purl\":{},\"properties\":[{{\"name\":\"cargo:features\",\"value\":{}}},{{\"name\":\"git:commit\",\"value\":{}}}]}}}},\"components\":[{}]}}",
+        json_string(&name),
+        json_string(&version),
+        json_string(&format!("pkg:cargo/{}@{}", name, version)),
+        json_string(&enabled_features().join(",")),
+        json_string(git_commit),
+        components.join(",")
+    )
+}
+
+fn main() {
+    // Source tarballs have no git metadata, KEYLIME_AGENT_GIT_COMMIT can be
+    // set by packagers instead
+    let git_commit = std::env::var("KEYLIME_AGENT_GIT_COMMIT")
+        .ok()
+        .or_else(|| command_output("git", &["rev-parse", "--short", "HEAD"]))
+        .unwrap_or_else(|| "unknown".to_string());
+    let tss_esapi =
+        locked_version("tss-esapi").unwrap_or_else(|| "unknown".to_string());
+    let tss2_esys =
+        command_output("pkg-config", &["--modversion", "tss2-esys"])
+            .unwrap_or_else(|| "unknown".to_string());
+
+    if let Ok(out_dir) = env::var("OUT_DIR") {
+        let path = Path::new(&out_dir).join("sbom.json");
+        if let Err(e) = fs::write(&path, sbom(&git_commit)) {
+            panic!("Unable to write {}: {}", path.display(), e);
+        }
+    }
+
+    println!("cargo:rustc-env=KEYLIME_AGENT_GIT_COMMIT={}", git_commit);
+    println!(
+        "cargo:rustc-env=KEYLIME_AGENT_TSS_ESAPI_VERSION={}",
+        tss_esapi
+    );
+    println!(
+        "cargo:rustc-env=KEYLIME_AGENT_TSS2_ESYS_VERSION={}",
+        tss2_esys
+    );
+    println!("cargo:rerun-if-env-changed=KEYLIME_AGENT_GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+}
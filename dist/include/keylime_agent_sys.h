@@ -0,0 +1,52 @@
+/* SPDX-License-Identifier: Apache-2.0 */
+/* Copyright 2022 Keylime Authors */
+
+/*
+ * C API of the Keylime agent, a client of the admin socket of the agent
+ * running on the machine. See src/ffi.rs for the details.
+ *
+ * The functions return 0 on success and -1 on failure, the reason of the
+ * last failure in the calling thread is returned by keylime_agent_error().
+ */
+
+#ifndef KEYLIME_AGENT_SYS_H
+#define KEYLIME_AGENT_SYS_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Reason of the last failure in the calling thread, NULL if none */
+const char *keylime_agent_error(void);
+
+/* Status report of the agent, as a JSON object to free with
+ * keylime_agent_string_free() */
+int keylime_agent_status(const char *socket, char **status);
+
+/* Identity quote for the alphanumerical nonce, as a JSON object to free with
+ * keylime_agent_string_free() */
+int keylime_agent_identity_quote(const char *socket, const char *nonce,
+                                 char **quote);
+
+/* Data sealed under the payload key, to free with
+ * keylime_agent_buffer_free() */
+int keylime_agent_seal(const char *socket, const uint8_t *data, size_t len,
+                       uint8_t **sealed, size_t *sealed_len);
+
+/* Data unsealed, while the agent is attested and not revoked, to free with
+ * keylime_agent_buffer_free() */
+int keylime_agent_unseal(const char *socket, const uint8_t *sealed,
+                         size_t len, uint8_t **data, size_t *data_len);
+
+void keylime_agent_string_free(char *string);
+
+void keylime_agent_buffer_free(uint8_t *buffer, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KEYLIME_AGENT_SYS_H */
@@ -0,0 +1,777 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Local admin socket of the agent.
+//
+// The socket is a Unix domain socket, only accessible by the user that
+// started the agent. Each connection carries a single request and its
+// response, both framed as a 4 bytes big-endian length followed by that
+// many bytes of JSON.
+//
+// Besides the status, the socket offers operations that are not exposed on
+// the network API. These are privileged and only accepted from root, as
+// identified by the credentials of the connecting process.
+
+use crate::{
+    common::KeylimeConfig,
+    crypto,
+    error::{Error, Result},
+    log_level::LogHandle,
+    mtls_enrollment, quotes_handler,
+    self_test::{self, SelfTestReport},
+    status::{
+        AgentStatus, Maintenance, StatusReport, DEFAULT_MAINTENANCE_DURATION,
+        MAINTENANCE_FILE,
+    },
+    vault, QuoteData, RegistrationData,
+};
+use actix_web::web;
+use log::*;
+use openssl::x509::X509;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    fs,
+    io::{Read, Write},
+    os::unix::{fs::FileTypeExt, fs::PermissionsExt, net},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+
+// Requests and responses are small, anything larger is a protocol error
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+const MAINTENANCE_NOTIFY_TIMEOUT: Duration = Duration::from_secs(10);
+// Connections are served one at a time, a client that does not send its
+// request in time is dropped so it does not hold the others
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub(crate) enum Request {
+    Status,
+    // Status checked by the client, see StatusReport::ready()
+    Ready,
+    Reregister,
+    RotateMtlsCert,
+    FlushQuoteCache,
+    SelfTest,
+    SetLogLevel {
+        level: String,
+    },
+    Decrypt {
+        path: String,
+    },
+    // Identity quote, and data sealed under the payload key, for the clients
+    // of the C API, see ffi.rs. The data is base64 encoded.
+    Quote {
+        nonce: String,
+    },
+    Seal {
+        data: String,
+    },
+    Unseal {
+        data: String,
+    },
+    UnlockKeys,
+    // Maintenance mode, quotes are refused while the agent is deactivated.
+    // With notify, maintenance_notify_url is told about it.
+    Deactivate {
+        #[serde(default)]
+        reason: Option<String>,
+        // Seconds, DEFAULT_MAINTENANCE_DURATION when not given
+        #[serde(default)]
+        duration: Option<u64>,
+        #[serde(default)]
+        notify: bool,
+    },
+    Activate {
+        #[serde(default)]
+        notify: bool,
+    },
+}
+
+impl Request {
+    fn privileged(&self) -> bool {
+        !matches!(self, Request::Status | Request::Ready)
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub(crate) enum Response {
+    Status(StatusReport),
+    SelfTest(SelfTestReport),
+    // Base64 encoded content of the decrypted file, or of the unsealed data
+    Decrypted { data: String },
+    // Base64 encoded sealed data
+    Sealed { data: String },
+    // Identity quote, as served on /quotes/identity
+    Quote { quote: serde_json::Value },
+    Done { message: String },
+    Error { message: String },
+}
+
+// State used by the privileged operations
+#[derive(Debug)]
+pub(crate) struct Admin {
+    pub config: KeylimeConfig,
+    pub quote_data: web::Data<QuoteData>,
+    pub registration: Mutex<RegistrationData>,
+    // Identity of the mTLS server and the CA verifying the peers, None when
+    // mTLS is disabled
+    pub mtls: Option<(crypto::MtlsIdentity, X509)>,
+    pub log_handle: LogHandle,
+    // Contact IP last registered, it follows agent_contact_interface
+    pub contact_ip: Mutex<Option<String>>,
+}
+
+fn check_frame_len(len: u32) -> Result<usize> {
+    if len > MAX_FRAME_LEN {
+        return Err(Error::Other(format!(
+            "admin socket frame of {} bytes exceeds the maximum of {} bytes",
+            len, MAX_FRAME_LEN
+        )));
+    }
+    Ok(len as usize)
+}
+
+/*
+ * Input: socket path
+ * Return: Result wrap the listening socket
+ *
+ * Binds the admin socket, replacing a stale socket left behind by a previous
+ * run. This is done before dropping privileges, so that only the user that
+ * started the agent can connect.
+ */
+pub(crate) fn bind(path: &Path) -> Result<net::UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::Configuration(format!(
+                "admin socket path {} exists and is not a socket",
+                path.display()
+            )));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = net::UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    listener.set_nonblocking(true)?;
+    info!("Listening for admin requests on {}", path.display());
+    Ok(listener)
+}
+
+pub(crate) fn contact_ip(admin: &Admin) -> Option<String> {
+    let contact_ip = admin.contact_ip.lock().unwrap(); //#[allow_ci]
+    contact_ip.clone()
+}
+
+async fn reregister(
+    admin: &Admin,
+    registration: &RegistrationData,
+    contact_ip: Option<String>,
+    status: &AgentStatus,
+) -> Result<()> {
+    let config = KeylimeConfig {
+        agent_contact_ip: contact_ip,
+        ..admin.config.clone()
+    };
+    crate::register_agent(
+        &admin.quote_data.tpm,
+        &admin.quote_data.tpm_handles,
+        &config,
+        registration,
+        admin.quote_data.ak_handle,
+        None,
+        status,
+        false,
+    )
+    .await?;
+    Ok(())
+}
+
+/*
+ * Input: admin state, new contact IP and agent status
+ *
+ * Registers the agent again with the new contact IP, which is kept for the
+ * later registrations once the registrar accepted it.
+ */
+pub(crate) async fn update_contact_ip(
+    admin: &Admin,
+    ip: &str,
+    status: &AgentStatus,
+) -> Result<()> {
+    let registration = {
+        let registration = admin.registration.lock().unwrap(); //#[allow_ci]
+        registration.clone()
+    };
+    reregister(admin, &registration, Some(ip.to_string()), status).await?;
+    *admin.contact_ip.lock().unwrap() = Some(ip.to_string()); //#[allow_ci]
+    Ok(())
+}
+
+/*
+ * Input: admin state and agent status
+ *
+ * Generates a new mTLS certificate for the NK, or enrolls one when
+ * 'mtls_cert_enrollment' is set, and registers it. The running server only
+ * switches to the new certificate, and the agent data only records it, once
+ * the registrar accepted it.
+ */
+pub(crate) async fn rotate_mtls_cert(
+    admin: &Admin,
+    status: &AgentStatus,
+) -> Result<()> {
+    let (identity, keylime_ca_cert) =
+        admin.mtls.as_ref().ok_or_else(|| {
+            Error::Other(
+                "mTLS is disabled, there is no certificate to rotate"
+                    .to_string(),
+            )
+        })?;
+    let key = &admin.quote_data.priv_key;
+    let (cert, chain) =
+        mtls_enrollment::issue(&admin.config, key, keylime_ca_cert).await?;
+    let context = crypto::generate_mtls_identity(
+        &cert,
+        &chain,
+        key,
+        keylime_ca_cert,
+        crypto::TlsOptions::from(&admin.config),
+    )?;
+
+    let mut registration = {
+        let registration = admin.registration.lock().unwrap(); //#[allow_ci]
+        registration.clone()
+    };
+    registration.mtls_cert = Some(cert.clone());
+    reregister(admin, &registration, contact_ip(admin), status).await?;
+
+    *identity.write().unwrap() = context; //#[allow_ci]
+    *admin.registration.lock().unwrap() = registration; //#[allow_ci]
+
+    // Also updates the agent data audited by agent_data_audit
+    let mut agent_data = admin.quote_data.agent_data.lock().unwrap(); //#[allow_ci]
+    agent_data.set_mtls_cert(&cert)?;
+    agent_data.set_mtls_cert_chain(&chain)?;
+    agent_data.store(Path::new(&admin.config.agent_data_path))?;
+    Ok(())
+}
+
+fn maintenance_path(admin: &Admin) -> PathBuf {
+    Path::new(&admin.config.work_dir).join(MAINTENANCE_FILE)
+}
+
+fn maintenance_notify_url(admin: &Admin) -> Result<&str> {
+    admin
+        .config
+        .maintenance_notify_url
+        .as_deref()
+        .ok_or_else(|| {
+            Error::Configuration(
+                "maintenance_notify_url is not set, there is no one to notify"
+                    .to_string(),
+            )
+        })
+}
+
+/*
+ * Input: admin state and the maintenance in progress, None once it ended
+ *
+ * Posts whether the agent is in maintenance to maintenance_notify_url.
+ */
+async fn notify_maintenance(
+    admin: &Admin,
+    maintenance: Option<&Maintenance>,
+) -> Result<()> {
+    let url = maintenance_notify_url(admin)?;
+    let body = json!({
+        "agent_uuid": admin.config.agent_uuid,
+        "maintenance": maintenance,
+    });
+    let result = async {
+        let _ = reqwest::Client::builder()
+            .timeout(MAINTENANCE_NOTIFY_TIMEOUT)
+            .build()?
+            .post(url)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok::<(), Error>(())
+    }
+    .await;
+    result.map_err(|e| {
+        Error::Other(format!(
+            "the maintenance mode changed, but notifying {} failed: {}",
+            url, e
+        ))
+    })
+}
+
+fn set_log_level(admin: &Admin, level: &str) -> Result<()> {
+    let level = level.parse::<LevelFilter>().map_err(|e| {
+        Error::Other(format!("invalid log level {}: {}", level, e))
+    })?;
+    admin.log_handle.set_level(level);
+    Ok(())
+}
+
+async fn run_privileged(
+    request: Request,
+    admin: &Admin,
+    status: &AgentStatus,
+) -> Result<Response> {
+    let message = match request {
+        Request::Reregister => {
+            let registration = {
+                let registration = admin.registration.lock().unwrap(); //#[allow_ci]
+                registration.clone()
+            };
+            reregister(admin, &registration, contact_ip(admin), status)
+                .await?;
+            format!("Agent {} registered again", admin.config.agent_uuid)
+        }
+        Request::RotateMtlsCert => {
+            rotate_mtls_cert(admin, status).await?;
+            "mTLS certificate rotated".to_string()
+        }
+        Request::FlushQuoteCache => {
+            if let Some(cache) = &admin.quote_data.quote_cache {
+                cache.clear();
+            }
+            admin.quote_data.ima_ml.lock().unwrap().reset(); //#[allow_ci]
+            "Quote cache flushed".to_string()
+        }
+        Request::SelfTest => {
+            return Ok(Response::SelfTest(
+                self_test::run(admin.quote_data.clone(), &admin.config).await,
+            ));
+        }
+        Request::SetLogLevel { level } => {
+            set_log_level(admin, &level)?;
+            format!("Log level set to {}", level)
+        }
+        Request::Decrypt { path } => {
+            let data = vault::decrypt(&admin.quote_data, Path::new(&path))?;
+            return Ok(Response::Decrypted {
+                data: base64::encode(data),
+            });
+        }
+        Request::Quote { nonce } => {
+            let quote =
+                quotes_handler::local_identity(&admin.quote_data, &nonce)
+                    .await?;
+            return Ok(Response::Quote {
+                quote: serde_json::to_value(quote)?,
+            });
+        }
+        Request::Seal { data } => {
+            let sealed =
+                vault::seal(&admin.quote_data, &base64::decode(data)?)?;
+            return Ok(Response::Sealed {
+                data: base64::encode(sealed),
+            });
+        }
+        Request::Unseal { data } => {
+            let data =
+                vault::unseal(&admin.quote_data, &base64::decode(data)?)?;
+            return Ok(Response::Decrypted {
+                data: base64::encode(data),
+            });
+        }
+        Request::UnlockKeys => {
+            status.set_keys_locked(false);
+            "U and V keys are accepted again".to_string()
+        }
+        Request::Deactivate {
+            reason,
+            duration,
+            notify,
+        } => {
+            if notify {
+                let _ = maintenance_notify_url(admin)?;
+            }
+            let maintenance = status.start_maintenance(
+                reason,
+                duration.unwrap_or(DEFAULT_MAINTENANCE_DURATION),
+            );
+            if let Err(e) = maintenance.store(&maintenance_path(admin)) {
+                warn!("Unable to store the maintenance, it ends if the agent restarts: {}", e);
+            }
+            if notify {
+                notify_maintenance(admin, Some(&maintenance)).await?;
+            }
+            format!(
+                "Agent in maintenance, quotes are refused until it is activated or until {} (Unix time)",
+                maintenance.until
+            )
+        }
+        Request::Activate { notify } => {
+            if notify {
+                let _ = maintenance_notify_url(admin)?;
+            }
+            let ended = status.end_maintenance();
+            match fs::remove_file(maintenance_path(admin)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    warn!("Unable to remove the stored maintenance: {}", e)
+                }
+                _ => {}
+            }
+            if notify {
+                notify_maintenance(admin, None).await?;
+            }
+            match ended {
+                Some(_) => "Agent activated, quotes are served again",
+                None => "Agent was not in maintenance",
+            }
+            .to_string()
+        }
+        Request::Status | Request::Ready => {
+            return Ok(Response::Status(status_report(status, Some(admin))))
+        }
+    };
+    info!("Admin request completed: {}", message);
+    Ok(Response::Done { message })
+}
+
+fn status_report(
+    status: &AgentStatus,
+    admin: Option<&Admin>,
+) -> StatusReport {
+    let mut report = status.report();
+    if let Some(admin) = admin {
+        report.tpm_handles = admin.quote_data.tpm_handles.counts();
+        report.tpm_reconnections =
+            admin.quote_data.tpm.keys().reconnections();
+        if let Some(cache) = &admin.quote_data.quote_cache {
+            let (hits, misses) = cache.stats();
+            report.quote_cache_hits = hits;
+            report.quote_cache_misses = misses;
+        }
+    }
+    report
+}
+
+async fn handle(
+    request: Request,
+    uid: Option<u32>,
+    status: &AgentStatus,
+    admin: Option<&Admin>,
+) -> Response {
+    if !request.privileged() {
+        return Response::Status(status_report(status, admin));
+    }
+    if uid != Some(0) {
+        warn!("Refused privileged admin request from uid {:?}", uid);
+        return Response::Error {
+            message: "privileged operations are restricted to root"
+                .to_string(),
+        };
+    }
+    let admin = match admin {
+        Some(admin) => admin,
+        None => {
+            return Response::Error {
+                message: "privileged operations are not available"
+                    .to_string(),
+            }
+        }
+    };
+    match run_privileged(request, admin, status).await {
+        Ok(response) => response,
+        Err(e) => {
+            warn!("Admin request failed: {}", e);
+            let message = match e.remediation() {
+                Some(hint) => format!("{}. {}", e, hint),
+                None => e.to_string(),
+            };
+            Response::Error { message }
+        }
+    }
+}
+
+async fn read_request(stream: &mut UnixStream) -> Result<Vec<u8>> {
+    let len = check_frame_len(stream.read_u32().await?)?;
+    let mut buf = vec![0u8; len];
+    let _ = stream.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+async fn serve_connection(
+    mut stream: UnixStream,
+    status: &AgentStatus,
+    admin: Option<&Admin>,
+) -> Result<()> {
+    // Unknown credentials are treated as unprivileged
+    let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+
+    let buf = match tokio::time::timeout(
+        REQUEST_READ_TIMEOUT,
+        read_request(&mut stream),
+    )
+    .await
+    {
+        Ok(buf) => buf?,
+        Err(_) => {
+            return Err(Error::Other(format!(
+                "admin request from uid {:?} not received within {} seconds",
+                uid,
+                REQUEST_READ_TIMEOUT.as_secs()
+            )));
+        }
+    };
+
+    let response = match serde_json::from_slice::<Request>(&buf) {
+        Ok(request) => {
+            debug!("Admin request from uid {:?}: {:?}", uid, request);
+            handle(request, uid, status, admin).await
+        }
+        Err(e) => Response::Error {
+            message: format!("invalid request: {}", e),
+        },
+    };
+
+    let out = serde_json::to_vec(&response)?;
+    stream.write_u32(out.len() as u32).await?;
+    stream.write_all(&out).await?;
+    Ok(())
+}
+
+// Serves admin requests until the agent exits, one connection at a time,
+// see REQUEST_READ_TIMEOUT. Without the admin state only the status is
+// available.
+pub(crate) async fn serve(
+    listener: net::UnixListener,
+    status: Arc<AgentStatus>,
+    admin: Option<Arc<Admin>>,
+) -> Result<()> {
+    let listener = UnixListener::from_std(listener)?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        if let Err(e) =
+            serve_connection(stream, &status, admin.as_deref()).await
+        {
+            warn!("Admin request failed: {}", e);
+        }
+    }
+}
+
+// Client side, used by the agent subcommands to talk to the running agent
+pub(crate) fn request(path: &Path, request: &Request) -> Result<Response> {
+    let mut stream = net::UnixStream::connect(path).map_err(|e| {
+        Error::Other(format!(
+            "unable to connect to the agent admin socket {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    let out = serde_json::to_vec(request)?;
+    stream.write_all(&(out.len() as u32).to_be_bytes())?;
+    stream.write_all(&out)?;
+
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = check_frame_len(u32::from_be_bytes(len))?;
+    let mut buf = vec![0u8; len];
+    stream.read_exact(&mut buf)?;
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+/*
+ * Input: socket path, request and whether to print reports as JSON
+ *
+ * Implements the `keylime_agent status` and `keylime_agent admin`
+ * subcommands. A failed request or self-test is returned as an error, so the
+ * exit status reflects it.
+ */
+pub(crate) fn run_command(
+    path: &Path,
+    command: &Request,
+    json: bool,
+) -> Result<()> {
+    match request(path, command)? {
+        Response::Status(report) if *command == Request::Ready => {
+            if !report.ready() {
+                return Err(Error::Other(format!(
+                    "agent is not ready: registration {:?}, TPM stalled: {}, TPM diverged: {}",
+                    report.registration,
+                    report.tpm_stalled,
+                    report.tpm_diverged
+                )));
+            }
+            println!("Agent is ready");
+        }
+        Response::Status(report) if json => {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        Response::Status(report) => print!("{}", report),
+        Response::SelfTest(report) => {
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            if !report.passed() {
+                return Err(Error::Other("self-test failed".to_string()));
+            }
+        }
+        Response::Decrypted { data } => {
+            let data = base64::decode(data)?;
+            std::io::stdout().write_all(&data)?;
+        }
+        Response::Sealed { data } => println!("{}", data),
+        Response::Quote { quote } => {
+            println!("{}", serde_json::to_string_pretty(&quote)?);
+        }
+        Response::Done { message } => println!("{}", message),
+        Response::Error { message } => {
+            return Err(Error::Other(format!(
+                "admin request failed: {}",
+                message
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::RegistrationState;
+
+    #[actix_rt::test]
+    async fn test_status_request() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent.sock");
+        let listener = bind(&path).unwrap(); //#[allow_ci]
+
+        let status = Arc::new(AgentStatus::new("uuid"));
+        status.set_registration(RegistrationState::Activated);
+        let expected = status.report();
+        let _ = actix_rt::spawn(serve(listener, status, None));
+
+        // The client is blocking
+        let client_path = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            request(&client_path, &Request::Status)
+        })
+        .await
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+        assert_eq!(response, Response::Status(expected));
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[actix_rt::test]
+    async fn test_idle_connection() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent.sock");
+        let listener = bind(&path).unwrap(); //#[allow_ci]
+
+        let status = Arc::new(AgentStatus::new("uuid"));
+        let expected = status.report();
+        let _ = actix_rt::spawn(serve(listener, status, None));
+
+        // A client that never sends its request is dropped after the
+        // timeout, the next one is served
+        let _idle = net::UnixStream::connect(&path).unwrap(); //#[allow_ci]
+        let client_path = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            request(&client_path, &Request::Status)
+        })
+        .await
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+        assert_eq!(response, Response::Status(expected));
+    }
+
+    #[actix_rt::test]
+    async fn test_privileged_requests() {
+        let status = AgentStatus::new("uuid");
+        let request = Request::SetLogLevel {
+            level: "debug".to_string(),
+        };
+        assert!(request.privileged());
+        assert!(!Request::Status.privileged());
+        assert!(!Request::Ready.privileged());
+
+        // Only root is allowed
+        for uid in [None, Some(1000)] {
+            let response =
+                handle(Request::FlushQuoteCache, uid, &status, None).await;
+            assert_eq!(
+                response,
+                Response::Error {
+                    message: "privileged operations are restricted to root"
+                        .to_string()
+                }
+            );
+        }
+
+        let response =
+            handle(Request::FlushQuoteCache, Some(0), &status, None).await;
+        assert!(matches!(response, Response::Error { .. }));
+
+        // The status is available to anyone able to connect
+        let response =
+            handle(Request::Status, Some(1000), &status, None).await;
+        assert_eq!(response, Response::Status(status.report()));
+    }
+
+    #[test]
+    fn test_request_format() {
+        let request = Request::SetLogLevel {
+            level: "trace".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(), //#[allow_ci]
+            r#"{"command":"set_log_level","level":"trace"}"#
+        );
+        let request: Request =
+            serde_json::from_str(r#"{"command":"rotate_mtls_cert"}"#)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(request, Request::RotateMtlsCert);
+        let request: Request =
+            serde_json::from_str(r#"{"command":"deactivate"}"#).unwrap(); //#[allow_ci]
+        assert_eq!(
+            request,
+            Request::Deactivate {
+                reason: None,
+                duration: None,
+                notify: false
+            }
+        );
+        // The format the C API sends, see ffi.rs
+        let request: Request =
+            serde_json::from_str(r#"{"command":"seal","data":"AA=="}"#)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(
+            request,
+            Request::Seal {
+                data: "AA==".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_bind_refuses_other_files() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent.sock");
+        fs::write(&path, b"not a socket").unwrap(); //#[allow_ci]
+        assert!(bind(&path).is_err());
+    }
+
+    #[test]
+    fn test_frame_len() {
+        assert!(check_frame_len(MAX_FRAME_LEN).is_ok());
+        assert!(check_frame_len(MAX_FRAME_LEN + 1).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// AES-GCM payload format and HMAC of Keylime.
+//
+// Encrypted data is the IV, the ciphertext and the 16 bytes tag. Keylime uses
+// a 16 bytes IV, while SP 800-38D recommends 12 bytes, payloads are accepted
+// with either. The agent uses these through crypto.rs. The Python module of
+// the keylime_agent_sys library, see python.rs, does not link the agent code
+// and uses them directly, so this module only depends on OpenSSL. The
+// library exposes it for the benchmarks.
+
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    memcmp,
+    pkey::PKey,
+    sign::Signer,
+    symm::{self, Cipher},
+};
+use thiserror::Error;
+
+pub const IV_LEN: usize = 16;
+pub const GCM_IV_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum AeadError {
+    #[error("key length {0} does not correspond to valid GCM cipher")]
+    KeyLength(usize),
+    #[error("IV length {0} does not correspond to valid GCM cipher {1}")]
+    IvLength(usize, usize),
+    #[error("encrypted data is too short")]
+    TooShort,
+    #[error("hmac check failed")]
+    HmacMismatch,
+    #[error("{0}")]
+    Crypto(#[from] ErrorStack),
+}
+
+type Result<T> = std::result::Result<T, AeadError>;
+
+fn gcm_cipher(key: &[u8]) -> Result<Cipher> {
+    match key.len() {
+        16 => Ok(Cipher::aes_128_gcm()),
+        32 => Ok(Cipher::aes_256_gcm()),
+        other => Err(AeadError::KeyLength(other)),
+    }
+}
+
+pub fn compute_hmac(
+    digest: MessageDigest,
+    key: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(digest, &pkey)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+pub fn verify_hmac(
+    digest: MessageDigest,
+    key: &[u8],
+    data: &[u8],
+    hmac: &[u8],
+) -> Result<()> {
+    let expected = compute_hmac(digest, key, data)?;
+    if expected.len() != hmac.len() || !memcmp::eq(&expected, hmac) {
+        return Err(AeadError::HmacMismatch);
+    }
+    Ok(())
+}
+
+/*
+ * Input: AES key, IV and data
+ * Return: Result wrap the IV, ciphertext and tag
+ */
+pub fn encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let cipher = gcm_cipher(key)?;
+    if iv.len() != IV_LEN {
+        return Err(AeadError::IvLength(iv.len(), IV_LEN));
+    }
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext =
+        symm::encrypt_aead(cipher, key, Some(iv), &[], data, &mut tag)?;
+    Ok([iv, &ciphertext, &tag].concat())
+}
+
+/*
+ * Input: AES key, data and length of its IV
+ * Return: Result wrap the decrypted data
+ */
+pub fn decrypt_iv(key: &[u8], data: &[u8], iv_len: usize) -> Result<Vec<u8>> {
+    let cipher = gcm_cipher(key)?;
+    if data.len() < iv_len + TAG_LEN {
+        return Err(AeadError::TooShort);
+    }
+    let (iv, rest) = data.split_at(iv_len);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    Ok(symm::decrypt_aead(
+        cipher,
+        key,
+        Some(iv),
+        &[],
+        ciphertext,
+        tag,
+    )?)
+}
+
+/*
+ * Input: AES key and data
+ * Return: Result wrap the decrypted data
+ *
+ * Tries the 16 bytes IV of Keylime first, then the 12 bytes one. The error
+ * of the first attempt is returned when both fail.
+ */
+pub fn decrypt(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    decrypt_iv(key, data, IV_LEN)
+        .or_else(|e| decrypt_iv(key, data, GCM_IV_LEN).map_err(|_| e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = [1u8; 32];
+        let encrypted = encrypt(&key, &[2u8; IV_LEN], b"payload").unwrap(); //#[allow_ci]
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), b"payload"); //#[allow_ci]
+
+        // With the IV of SP 800-38D
+        let iv = [2u8; GCM_IV_LEN];
+        let mut tag = [0u8; TAG_LEN];
+        let ciphertext = symm::encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&iv),
+            &[],
+            b"payload",
+            &mut tag,
+        )
+        .unwrap(); //#[allow_ci]
+        let encrypted = [&iv[..], &ciphertext, &tag].concat();
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), b"payload"); //#[allow_ci]
+
+        assert!(encrypt(&key, &[2u8; GCM_IV_LEN], b"payload").is_err());
+        assert!(encrypt(&[1u8; 24], &[2u8; IV_LEN], b"payload").is_err());
+        assert!(matches!(
+            decrypt(&key, &[0u8; 20]),
+            Err(AeadError::TooShort)
+        ));
+    }
+
+    #[test]
+    fn test_verify_hmac() {
+        let digest = MessageDigest::sha384();
+        let hmac = compute_hmac(digest, b"key", b"data").unwrap(); //#[allow_ci]
+        assert!(verify_hmac(digest, b"key", b"data", &hmac).is_ok());
+        assert!(verify_hmac(digest, b"key", b"other", &hmac).is_err());
+        assert!(verify_hmac(digest, b"key", b"data", &hmac[1..]).is_err());
+    }
+}
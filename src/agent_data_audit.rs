@@ -0,0 +1,156 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Periodic audit of the persisted agent data.
+//
+// The AK, NK and mTLS certificate in use are loaded from 'agent_data_path'
+// on startup, or created and stored there. Every 'agent_data_audit_interval'
+// seconds the file is read back and written again when it is missing or no
+// longer matches them, e.g. after an operator restored an older backup.
+//
+// The AK is also read back from the TPM. When the TPM was cleared or replaced
+// under the running agent, the AK is gone and quotes fail until the agent is
+// restarted and registers a new one. This is logged as an error and reported
+// in the status rather than repaired, as the verifier has to be told.
+
+use crate::{
+    common::AgentData,
+    error::{Error, Result},
+    tpm, QuoteData,
+};
+use actix_web::web::Data;
+use log::*;
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tss_esapi::{traits::Marshall, Context};
+
+// Whether the file had to be written again
+fn repair(expected: &AgentData, path: &Path) -> Result<bool> {
+    match AgentData::load(path) {
+        Ok(stored) if stored == *expected => return Ok(false),
+        Ok(_) => warn!(
+            "{} does not match the agent data in use, storing it again",
+            path.display()
+        ),
+        Err(e) => warn!(
+            "Unable to read {}, storing the agent data in use again: {}",
+            path.display(),
+            e
+        ),
+    }
+    expected.store(path)?;
+    Ok(true)
+}
+
+// Compares the AK loaded in the TPM to the one in use
+fn check_ak(
+    context: &mut Context,
+    data: &QuoteData,
+    ak_public: &[u8],
+) -> Result<()> {
+    let ak_handle = data.tpm.key(data.ak_handle);
+    let (public, _, _) =
+        tpm::retry_tpm_command(|| context.read_public(ak_handle))?;
+    if public.marshall()? != ak_public {
+        return Err(Error::Other(
+            "the AK loaded in the TPM is not the one in use".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn run(context: &mut Context, data: &QuoteData, path: &Path) -> Result<()> {
+    // Held while storing, so that a rotated mTLS certificate is not
+    // overwritten with the previous one
+    let ak_public = {
+        let expected = data.agent_data.lock().unwrap(); //#[allow_ci]
+        let _ = repair(&expected, path)?;
+        expected.get_ak()?.public.marshall()?
+    };
+
+    match check_ak(context, data, &ak_public) {
+        Ok(()) => data.status.set_tpm_diverged(false),
+        Err(e) => {
+            error!(
+                "TPM state diverged from the agent data, restart the agent to register a new AK: {}",
+                e
+            );
+            data.status.set_tpm_diverged(true);
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Input: agent state, path of the agent data and the number of seconds
+ *        between checks
+ *
+ * Checks run on the TPM service. Failed checks are logged and retried on
+ * the next tick, so this only returns if the TPM service stopped.
+ */
+pub(crate) async fn audit(
+    data: Data<QuoteData>,
+    path: PathBuf,
+    interval: u64,
+) -> Result<()> {
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        let _ = ticker.tick().await;
+        let audit_data = data.clone();
+        let path = path.clone();
+        let result = data
+            .tpm
+            .run(move |context| Ok(run(context, &audit_data, &path)))
+            .await?;
+        if let Err(e) = result {
+            warn!("Unable to audit the agent data: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_repair() {
+        let data = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let expected = data.agent_data.lock().unwrap().clone(); //#[allow_ci]
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent_data.json");
+
+        assert!(repair(&expected, &path).unwrap()); //#[allow_ci]
+        assert!(!repair(&expected, &path).unwrap()); //#[allow_ci]
+
+        // A restored backup holding other keys
+        let mut restored = expected.clone();
+        restored.ak_negotiated = !restored.ak_negotiated;
+        restored.store(&path).unwrap(); //#[allow_ci]
+        assert!(repair(&expected, &path).unwrap()); //#[allow_ci]
+        assert_eq!(AgentData::load(&path).unwrap(), expected); //#[allow_ci]
+
+        fs::write(&path, "{").unwrap(); //#[allow_ci]
+        assert!(repair(&expected, &path).unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_run() {
+        let data = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent_data.json");
+
+        run(&mut data.tpm.lock(), &data, &path).unwrap(); //#[allow_ci]
+        assert!(path.exists());
+        assert!(!data.status.report().tpm_diverged);
+
+        // The AK is gone, as after a TPM clear
+        let mut context = data.tpm.lock();
+        context.flush_context(data.ak_handle.into()).unwrap(); //#[allow_ci]
+        run(&mut context, &data, &path).unwrap(); //#[allow_ci]
+        assert!(data.status.report().tpm_diverged);
+    }
+}
@@ -0,0 +1,337 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Attestation keys of each verifier.
+//
+// With 'ak_per_verifier' set, each verifier authenticated with a client
+// certificate gets its own AK, so that verifiers of different tenants do not
+// share the key their quotes are signed with. A verifier fetches its AK from
+// /keys/ak, which creates it under the EK on first use, then certifies it
+// against the EK like the registrar does: it makes a credential for the name
+// of the AK with the EK it trusts (TPM2_MakeCredential) and posts it to
+// /keys/ak/activate. The agent activates it (TPM2_ActivateCredential) and
+// answers with the HMAC of its UUID keyed with the released secret. From
+// then on, quotes requested by the verifier are signed with its AK. Other
+// clients, and verifiers whose AK is not activated, get quotes signed by the
+// registered AK.
+//
+// The TPM only holds a few objects at a time, so the AKs are not kept loaded:
+// their context is saved after each use and loaded for the next one, and they
+// are loaded again under the EK when the saved context is gone, e.g. after a
+// TPM reset. At most MAX_VERIFIERS AKs are kept, the one used the least
+// recently is dropped first and its verifier has to activate a new one.
+
+use crate::{
+    algorithms::{EccCurve, EncryptionAlgorithm},
+    common::KeylimeConfig,
+    error::{Error, Result},
+    tpm, QuoteData,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap, convert::TryFrom, sync::Mutex, time::Instant,
+};
+use tss_esapi::{
+    handles::KeyHandle,
+    structures::{Digest, PublicBuffer},
+    traits::Marshall,
+    utils::TpmsContext,
+    Context,
+};
+
+const MAX_VERIFIERS: usize = 64;
+
+// Response of /keys/ak
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct VerifierAkInfo {
+    // Base64 encoded TPM2B_PUBLIC of the AK
+    pub ak_tpm: String,
+    // Whether the verifier activated it, and quotes are signed with it
+    pub activated: bool,
+}
+
+#[derive(Debug)]
+struct VerifierAk {
+    ak: tpm::AKResult,
+    ak_tpm: String,
+    saved: Option<TpmsContext>,
+    activated: bool,
+    used: Instant,
+}
+
+impl VerifierAk {
+    fn info(&self) -> VerifierAkInfo {
+        VerifierAkInfo {
+            ak_tpm: self.ak_tpm.clone(),
+            activated: self.activated,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AkRegistry {
+    enc_alg: EncryptionAlgorithm,
+    ecc_curve: Option<EccCurve>,
+    // Persistent EK, the EK is created for each use otherwise
+    ek_handle: Option<String>,
+    encrypt_sessions: bool,
+    max_verifiers: usize,
+    aks: Mutex<HashMap<String, VerifierAk>>,
+}
+
+impl AkRegistry {
+    pub(crate) fn new(config: &KeylimeConfig) -> Self {
+        AkRegistry {
+            enc_alg: config.enc_alg,
+            ecc_curve: config.ak_ecc_curve(),
+            ek_handle: config.ek_handle.clone(),
+            encrypt_sessions: config.tpm_encrypt_sessions,
+            max_verifiers: MAX_VERIFIERS,
+            aks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Runs the operation with the EK, flushing it unless it is persistent
+    fn with_ek<T>(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        operation: impl FnOnce(&mut Context, KeyHandle) -> Result<T>,
+    ) -> Result<T> {
+        let ek_handle = tpm::create_ek(
+            ctx,
+            &data.tpm_handles,
+            self.enc_alg.into(),
+            self.ek_handle.as_deref(),
+        )?
+        .key_handle;
+        let result = operation(ctx, ek_handle);
+        if self.ek_handle.is_none() {
+            ctx.flush_context(ek_handle.into())?;
+            data.tpm_handles.release(ek_handle.into());
+        }
+        result
+    }
+
+    // Loads the AK from its saved context, or under the EK when it is gone
+    fn load(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        ak: &VerifierAk,
+    ) -> Result<KeyHandle> {
+        if let Some(saved) = ak.saved.clone() {
+            match ctx.context_load(saved) {
+                Ok(handle) => {
+                    data.tpm_handles.register(handle, "verifier_ak", false);
+                    return Ok(handle.into());
+                }
+                Err(e) => debug!(
+                    "Saved context of the AK lost ({}), loading it again",
+                    e
+                ),
+            }
+        }
+        self.with_ek(ctx, data, |ctx, ek_handle| {
+            tpm::load_ak(
+                ctx,
+                &data.tpm_handles,
+                ek_handle,
+                &ak.ak,
+                self.encrypt_sessions,
+            )
+        })
+    }
+
+    // Saves the context of the loaded AK and flushes it
+    fn unload(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        ak: &mut VerifierAk,
+        handle: KeyHandle,
+    ) {
+        ak.saved = ctx.context_save(handle.into()).ok();
+        if let Err(e) = ctx.flush_context(handle.into()) {
+            warn!("Unable to flush the AK of a verifier: {}", e);
+        }
+        data.tpm_handles.release(handle.into());
+    }
+
+    /*
+     * Input: Connection context, agent data and identity of the verifier
+     * Return: Result wrap the AK of the verifier, created if it has none
+     */
+    pub(crate) fn ak(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        verifier: &str,
+    ) -> Result<VerifierAkInfo> {
+        let mut aks = self.aks.lock().unwrap(); //#[allow_ci]
+        if let Some(ak) = aks.get_mut(verifier) {
+            ak.used = Instant::now();
+            return Ok(ak.info());
+        }
+
+        let ak = self.with_ek(ctx, data, |ctx, ek_handle| {
+            tpm::create_ak(
+                ctx,
+                ek_handle,
+                data.hash_alg.into(),
+                data.sign_alg.into(),
+                self.ecc_curve,
+                data.ak_policy.as_ref(),
+            )
+        })?;
+        let ak_tpm = base64::encode(
+            PublicBuffer::try_from(ak.public.clone())?.marshall()?,
+        );
+        if aks.len() >= self.max_verifiers {
+            let oldest = aks
+                .iter()
+                .min_by_key(|(_, ak)| ak.used)
+                .map(|(verifier, _)| verifier.clone());
+            if let Some(oldest) = oldest {
+                info!(
+                    "Dropping the AK of {}, used the least recently",
+                    oldest
+                );
+                let _ = aks.remove(&oldest);
+            }
+        }
+        info!("Created an AK for {}", verifier);
+        let ak = VerifierAk {
+            ak,
+            ak_tpm,
+            saved: None,
+            activated: false,
+            used: Instant::now(),
+        };
+        let info = ak.info();
+        let _ = aks.insert(verifier.to_string(), ak);
+        Ok(info)
+    }
+
+    /*
+     * Input: Connection context, agent data, identity of the verifier and
+     *        credential blob made for its AK
+     * Return: Result wrap the secret released by the credential activation
+     *
+     * The AK signs the quotes of the verifier once activated.
+     */
+    pub(crate) fn activate(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        verifier: &str,
+        keyblob: Vec<u8>,
+    ) -> Result<Digest> {
+        let mut aks = self.aks.lock().unwrap(); //#[allow_ci]
+        let ak = aks.get_mut(verifier).ok_or_else(|| {
+            Error::Other(format!("no AK was created for {}", verifier))
+        })?;
+        let secret = self.with_ek(ctx, data, |ctx, ek_handle| {
+            let handle = tpm::load_ak(
+                ctx,
+                &data.tpm_handles,
+                ek_handle,
+                &ak.ak,
+                self.encrypt_sessions,
+            )?;
+            let secret = tpm::activate_credential(
+                ctx,
+                &data.tpm_handles,
+                keyblob,
+                handle,
+                ek_handle,
+                self.encrypt_sessions,
+            );
+            self.unload(ctx, data, ak, handle);
+            secret
+        })?;
+        ak.activated = true;
+        ak.used = Instant::now();
+        info!("Activated the AK of {}", verifier);
+        Ok(secret)
+    }
+
+    /*
+     * Input: Connection context, agent data and identity of the verifier
+     * Return: Result wrap the handle of the activated AK of the verifier and
+     *         its public area, None when it has none
+     *
+     * The AK is loaded until it is passed to release().
+     */
+    pub(crate) fn signing_key(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        verifier: &str,
+    ) -> Result<Option<(KeyHandle, String)>> {
+        let mut aks = self.aks.lock().unwrap(); //#[allow_ci]
+        let ak = match aks.get_mut(verifier) {
+            Some(ak) if ak.activated => ak,
+            _ => return Ok(None),
+        };
+        ak.used = Instant::now();
+        let handle = self.load(ctx, data, ak)?;
+        Ok(Some((handle, ak.ak_tpm.clone())))
+    }
+
+    // Unloads the AK returned by signing_key()
+    pub(crate) fn release(
+        &self,
+        ctx: &mut Context,
+        data: &QuoteData,
+        verifier: &str,
+        handle: KeyHandle,
+    ) {
+        let mut aks = self.aks.lock().unwrap(); //#[allow_ci]
+        match aks.get_mut(verifier) {
+            Some(ak) => self.unload(ctx, data, ak, handle),
+            None => {
+                let _ = ctx.flush_context(handle.into());
+                data.tpm_handles.release(handle.into());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verifier_aks() {
+        let data = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let registry = AkRegistry::new(&KeylimeConfig::default());
+        let mut context = data.tpm.lock();
+
+        let first = registry.ak(&mut context, &data, "CN=verifier1").unwrap(); //#[allow_ci]
+        assert!(!first.activated);
+        // The same AK is returned until it is dropped
+        assert_eq!(
+            registry.ak(&mut context, &data, "CN=verifier1").unwrap(), //#[allow_ci]
+            first
+        );
+        let second =
+            registry.ak(&mut context, &data, "CN=verifier2").unwrap(); //#[allow_ci]
+        assert_ne!(first.ak_tpm, second.ak_tpm);
+
+        // Not used to sign until activated
+        assert!(registry
+            .signing_key(&mut context, &data, "CN=verifier1")
+            .unwrap() //#[allow_ci]
+            .is_none());
+        assert!(registry
+            .activate(&mut context, &data, "CN=other", vec![])
+            .is_err());
+        assert!(registry
+            .activate(&mut context, &data, "CN=verifier1", vec![0u8; 4])
+            .is_err());
+        assert!(data.tpm_handles.counts().get("verifier_ak").is_none());
+    }
+}
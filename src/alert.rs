@@ -0,0 +1,337 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Alerts on failures that prevent the attestation of the node.
+//
+// Many nodes run without central log shipping, where an agent that can no
+// longer be attested is only noticed once the verifier marks it failed. With
+// 'alert_destination' set, the agent sends an alert distinct from its logs
+// when the TPM becomes unusable, when the registration fails, and when a
+// revocation message fails verification. The destination is either a remote
+// syslog server, "syslog://host:port", receiving an RFC 5424 message over
+// UDP with the critical severity and the facility of 'syslog_facility', or a
+// webhook, "http(s)://...", receiving the alert as JSON. Each kind of alert
+// is sent once per run of the agent, so that a failure repeated on every
+// request does not flood the destination. Alerts are delivered from a
+// background thread so that the failing request is not delayed. Delivery is
+// not retried, failing to deliver an alert is only logged.
+
+use crate::{
+    error::{Error, Result},
+    log_output::SyslogFacility,
+};
+use log::*;
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    fmt, fs,
+    net::{ToSocketAddrs, UdpSocket},
+    process,
+    sync::Mutex,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const ALERT_TIMEOUT: Duration = Duration::from_secs(5);
+static HOSTNAME: &str = "/proc/sys/kernel/hostname";
+static APP_NAME: &str = "keylime_agent";
+// Severity of the syslog messages, as in RFC 5424
+const CRITICAL: u8 = 2;
+
+static ALERTS: Mutex<Option<Alerts>> = Mutex::new(None);
+// Deliveries not yet waited for, at most one per kind of alert
+static PENDING: Mutex<Vec<thread::JoinHandle<()>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AlertDestination {
+    // Address of the syslog server
+    Syslog(String),
+    // URL of the webhook
+    Webhook(String),
+}
+
+impl TryFrom<&str> for AlertDestination {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        if let Some(address) = value.strip_prefix("syslog://") {
+            if address.rsplit_once(':').is_none() {
+                return Err(Error::Configuration(format!(
+                    "alert_destination must include the port of the syslog server, got {}",
+                    value
+                )));
+            }
+            return Ok(AlertDestination::Syslog(address.to_string()));
+        }
+        if value.starts_with("http://") || value.starts_with("https://") {
+            return Ok(AlertDestination::Webhook(value.to_string()));
+        }
+        Err(Error::Configuration(format!(
+            "alert_destination must be a syslog:// or http(s):// URL, got {}",
+            value
+        )))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum AlertKind {
+    TpmUnusable,
+    RegistrationFailed,
+    RevocationVerificationFailed,
+}
+
+impl fmt::Display for AlertKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            AlertKind::TpmUnusable => "tpm_unusable",
+            AlertKind::RegistrationFailed => "registration_failed",
+            AlertKind::RevocationVerificationFailed => {
+                "revocation_verification_failed"
+            }
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct Alert {
+    agent_uuid: String,
+    kind: AlertKind,
+    message: String,
+    // Seconds since the epoch
+    time: u64,
+}
+
+#[derive(Debug)]
+struct Alerts {
+    destination: AlertDestination,
+    facility: SyslogFacility,
+    agent_uuid: String,
+    sent: HashSet<AlertKind>,
+}
+
+/*
+ * Input: alert destination, syslog facility and UUID of the agent
+ *
+ * Sets where the alerts are sent, they are dropped until then.
+ */
+pub(crate) fn init(
+    destination: AlertDestination,
+    facility: SyslogFacility,
+    agent_uuid: &str,
+) {
+    info!("Sending alerts to {:?}", destination);
+    let mut alerts = ALERTS.lock().unwrap(); //#[allow_ci]
+    *alerts = Some(Alerts {
+        destination,
+        facility,
+        agent_uuid: agent_uuid.to_string(),
+        sent: HashSet::new(),
+    });
+}
+
+/*
+ * Input: kind of the alert and what failed
+ *
+ * Sends the alert in the background, unless one of the same kind was
+ * already sent.
+ */
+pub(crate) fn raise(kind: AlertKind, message: &str) {
+    let (destination, facility, alert) = {
+        let mut alerts = ALERTS.lock().unwrap(); //#[allow_ci]
+        let alerts = match alerts.as_mut() {
+            Some(alerts) => alerts,
+            None => return,
+        };
+        if !alerts.sent.insert(kind) {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let alert = Alert {
+            agent_uuid: alerts.agent_uuid.clone(),
+            kind,
+            message: message.to_string(),
+            time,
+        };
+        (alerts.destination.clone(), alerts.facility, alert)
+    };
+
+    let delivery = thread::spawn(move || {
+        match deliver(&destination, facility, &alert) {
+            Ok(()) => info!("Sent the {} alert", kind),
+            Err(e) => warn!("Unable to send the {} alert: {}", kind, e),
+        }
+    });
+    PENDING.lock().unwrap().push(delivery); //#[allow_ci]
+}
+
+/*
+ * Waits for the alerts being delivered, for when the agent is about to exit.
+ * Each delivery is bounded by ALERT_TIMEOUT.
+ */
+pub(crate) fn flush() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap()); //#[allow_ci]
+    for delivery in pending {
+        let _ = delivery.join();
+    }
+}
+
+// RFC 5424 message of the alert, the timestamp is left to the server
+fn syslog_message(alert: &Alert, facility: SyslogFacility) -> Vec<u8> {
+    let hostname = fs::read_to_string(HOSTNAME)
+        .map(|hostname| hostname.trim().to_string())
+        .ok()
+        .filter(|hostname| !hostname.is_empty())
+        .unwrap_or_else(|| "-".to_string());
+    format!(
+        "<{}>1 - {} {} {} {} - agent {}: {}",
+        facility.code() * 8 + CRITICAL,
+        hostname,
+        APP_NAME,
+        process::id(),
+        alert.kind,
+        alert.agent_uuid,
+        alert.message
+    )
+    .into_bytes()
+}
+
+fn send_syslog(address: &str, message: &[u8]) -> Result<()> {
+    let server = address.to_socket_addrs()?.next().ok_or_else(|| {
+        Error::Other(format!("{} did not resolve to an address", address))
+    })?;
+    let local = if server.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local)?;
+    let _ = socket.send_to(message, server)?;
+    Ok(())
+}
+
+// The caller may run in the runtime of the HTTP server or outside of any
+// runtime, the webhook is called from a thread with its own runtime
+fn send_webhook(url: &str, alert: &Alert) -> Result<()> {
+    let url = url.to_string();
+    let body = serde_json::to_value(alert)?;
+    thread::spawn(move || -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        runtime.block_on(async {
+            let _ = reqwest::Client::builder()
+                .timeout(ALERT_TIMEOUT)
+                .build()?
+                .post(&url)
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok(())
+        })
+    })
+    .join()
+    .map_err(|_| Error::Other("the alert thread panicked".to_string()))?
+}
+
+fn deliver(
+    destination: &AlertDestination,
+    facility: SyslogFacility,
+    alert: &Alert,
+) -> Result<()> {
+    match destination {
+        AlertDestination::Syslog(address) => {
+            send_syslog(address, &syslog_message(alert, facility))
+        }
+        AlertDestination::Webhook(url) => send_webhook(url, alert),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web;
+    use serde_json::json;
+
+    fn alert() -> Alert {
+        Alert {
+            agent_uuid: "d432fbb3-d2f1-4a97-9ef7-75bd81c00000".to_string(),
+            kind: AlertKind::TpmUnusable,
+            message: "TPM Error".to_string(),
+            time: 1660000000,
+        }
+    }
+
+    #[test]
+    fn test_destination() {
+        assert_eq!(
+            AlertDestination::try_from("syslog://logs.example:514").unwrap(), //#[allow_ci]
+            AlertDestination::Syslog("logs.example:514".to_string())
+        );
+        assert_eq!(
+            AlertDestination::try_from("https://alerts.example/keylime")
+                .unwrap(), //#[allow_ci]
+            AlertDestination::Webhook(
+                "https://alerts.example/keylime".to_string()
+            )
+        );
+        assert!(AlertDestination::try_from("syslog://logs.example").is_err());
+        assert!(AlertDestination::try_from("logs.example:514").is_err());
+    }
+
+    #[test]
+    fn test_syslog() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap(); //#[allow_ci]
+        let address = server.local_addr().unwrap().to_string(); //#[allow_ci]
+        let facility = SyslogFacility::try_from("local0").unwrap(); //#[allow_ci]
+        deliver(&AlertDestination::Syslog(address), facility, &alert())
+            .unwrap(); //#[allow_ci]
+
+        let mut buf = [0u8; 1024];
+        let len = server.recv(&mut buf).unwrap(); //#[allow_ci]
+        let message = String::from_utf8_lossy(&buf[..len]);
+        // local0 (16) * 8 + critical (2)
+        assert!(message.starts_with("<130>1 - "));
+        assert!(message.contains(" keylime_agent "));
+        assert!(message.ends_with(
+            " tpm_unusable - agent d432fbb3-d2f1-4a97-9ef7-75bd81c00000: TPM Error"
+        ));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_webhook() {
+        use wiremock::{
+            matchers::{body_partial_json, method},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "agent_uuid": "d432fbb3-d2f1-4a97-9ef7-75bd81c00000",
+                "kind": "tpm_unusable",
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        mock_server.register(mock).await;
+
+        let url = mock_server.uri();
+        let result = web::block(move || {
+            deliver(
+                &AlertDestination::Webhook(url),
+                SyslogFacility::default(),
+                &alert(),
+            )
+        })
+        .await
+        .unwrap(); //#[allow_ci]
+        assert!(result.is_ok());
+    }
+}
@@ -6,8 +6,11 @@ use std::convert::TryFrom;
 use std::fmt;
 use thiserror::Error;
 use tss_esapi::{
-    interface_types::algorithm::{
-        AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm,
+    interface_types::{
+        algorithm::{
+            AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm,
+        },
+        ecc::EccCurve as TpmEccCurve,
     },
     structures::{HashScheme, SignatureScheme},
 };
@@ -21,6 +24,10 @@ pub enum AlgorithmError {
     Encrypt(String),
     #[error("{0}")]
     Sign(String),
+    #[error("{0}")]
+    Curve(String),
+    #[error("{0}")]
+    Hmac(String),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -32,6 +39,21 @@ pub enum HashAlgorithm {
     Sm3_256,
 }
 
+impl HashAlgorithm {
+    // Hash algorithms the agent can quote with
+    pub fn supported() -> Vec<HashAlgorithm> {
+        let mut supported = vec![
+            HashAlgorithm::Sha1,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Sha384,
+            HashAlgorithm::Sha512,
+        ];
+        #[cfg(feature = "sm")]
+        supported.push(HashAlgorithm::Sm3_256);
+        supported
+    }
+}
+
 impl TryFrom<&str> for HashAlgorithm {
     type Error = AlgorithmError;
 
@@ -41,6 +63,7 @@ impl TryFrom<&str> for HashAlgorithm {
             "sha256" => Ok(HashAlgorithm::Sha256),
             "sha384" => Ok(HashAlgorithm::Sha384),
             "sha512" => Ok(HashAlgorithm::Sha512),
+            #[cfg(feature = "sm")]
             "sm3_256" => Ok(HashAlgorithm::Sm3_256),
             _ => Err(AlgorithmError::Hash(format!(
                 "Hash algorithm {} is not supported by Keylime",
@@ -74,6 +97,24 @@ impl From<HashAlgorithm> for HashingAlgorithm {
     }
 }
 
+impl TryFrom<HashingAlgorithm> for HashAlgorithm {
+    type Error = AlgorithmError;
+
+    fn try_from(value: HashingAlgorithm) -> Result<Self, Self::Error> {
+        match value {
+            HashingAlgorithm::Sha1 => Ok(HashAlgorithm::Sha1),
+            HashingAlgorithm::Sha256 => Ok(HashAlgorithm::Sha256),
+            HashingAlgorithm::Sha384 => Ok(HashAlgorithm::Sha384),
+            HashingAlgorithm::Sha512 => Ok(HashAlgorithm::Sha512),
+            HashingAlgorithm::Sm3_256 => Ok(HashAlgorithm::Sm3_256),
+            other => Err(AlgorithmError::Hash(format!(
+                "Hash algorithm {:?} is not supported by Keylime",
+                other
+            ))),
+        }
+    }
+}
+
 impl From<HashAlgorithm> for MessageDigest {
     fn from(hash_algorithm: HashAlgorithm) -> Self {
         match hash_algorithm {
@@ -86,6 +127,75 @@ impl From<HashAlgorithm> for MessageDigest {
     }
 }
 
+// Digest of the HMACs binding the payload key and the credential activation
+// secret to the agent UUID (auth tags), and of the key verification
+// challenges. SHA-384 is the one of the Keylime protocol, the others are used
+// when the registrar requests them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HmacAlgorithm {
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl HmacAlgorithm {
+    pub fn supported() -> Vec<HmacAlgorithm> {
+        vec![
+            HmacAlgorithm::Sha256,
+            HmacAlgorithm::Sha384,
+            HmacAlgorithm::Sha512,
+        ]
+    }
+
+    // Length of the HMAC, in bytes
+    pub fn size(self) -> usize {
+        MessageDigest::from(self).size()
+    }
+}
+
+impl Default for HmacAlgorithm {
+    fn default() -> Self {
+        HmacAlgorithm::Sha384
+    }
+}
+
+impl TryFrom<&str> for HmacAlgorithm {
+    type Error = AlgorithmError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "sha256" => Ok(HmacAlgorithm::Sha256),
+            "sha384" => Ok(HmacAlgorithm::Sha384),
+            "sha512" => Ok(HmacAlgorithm::Sha512),
+            _ => Err(AlgorithmError::Hmac(format!(
+                "HMAC algorithm {} is not supported by Keylime",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for HmacAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = match self {
+            HmacAlgorithm::Sha256 => "sha256",
+            HmacAlgorithm::Sha384 => "sha384",
+            HmacAlgorithm::Sha512 => "sha512",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+impl From<HmacAlgorithm> for MessageDigest {
+    fn from(hmac_algorithm: HmacAlgorithm) -> Self {
+        match hmac_algorithm {
+            HmacAlgorithm::Sha256 => MessageDigest::sha256(),
+            HmacAlgorithm::Sha384 => MessageDigest::sha384(),
+            HmacAlgorithm::Sha512 => MessageDigest::sha512(),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum EncryptionAlgorithm {
     Rsa,
@@ -101,6 +211,12 @@ impl From<EncryptionAlgorithm> for AsymmetricAlgorithm {
     }
 }
 
+impl EncryptionAlgorithm {
+    pub fn supported() -> Vec<EncryptionAlgorithm> {
+        vec![EncryptionAlgorithm::Rsa, EncryptionAlgorithm::Ecc]
+    }
+}
+
 impl TryFrom<&str> for EncryptionAlgorithm {
     type Error = AlgorithmError;
 
@@ -133,6 +249,8 @@ pub enum SignAlgorithm {
     EcDsa,
     //    EcDaa, // Requires special SignatureScheme construction that is not yet implemented
     EcSchnorr,
+    #[cfg(feature = "sm")]
+    Sm2,
 }
 
 impl SignAlgorithm {
@@ -149,6 +267,43 @@ impl SignAlgorithm {
             SignAlgorithm::EcSchnorr => {
                 SignatureScheme::EcSchnorr { hash_scheme }
             }
+            #[cfg(feature = "sm")]
+            SignAlgorithm::Sm2 => SignatureScheme::Sm2 { hash_scheme },
+        }
+    }
+}
+
+impl SignAlgorithm {
+    pub fn supported() -> Vec<SignAlgorithm> {
+        let mut supported = vec![
+            SignAlgorithm::RsaSsa,
+            SignAlgorithm::RsaPss,
+            SignAlgorithm::EcDsa,
+            SignAlgorithm::EcSchnorr,
+        ];
+        #[cfg(feature = "sm")]
+        supported.push(SignAlgorithm::Sm2);
+        supported
+    }
+
+    // Whether the AK signing with this algorithm is an ECC key
+    pub fn is_ecc(self) -> bool {
+        match self {
+            SignAlgorithm::RsaSsa | SignAlgorithm::RsaPss => false,
+            SignAlgorithm::EcDsa | SignAlgorithm::EcSchnorr => true,
+            #[cfg(feature = "sm")]
+            SignAlgorithm::Sm2 => true,
+        }
+    }
+
+    // Curve of the AK signing with this algorithm, None for RSA AKs. SM2
+    // signatures are only defined on the SM2 curve, whatever is configured.
+    pub fn ak_curve(self, configured: EccCurve) -> Option<EccCurve> {
+        match self {
+            #[cfg(feature = "sm")]
+            SignAlgorithm::Sm2 => Some(EccCurve::Sm2P256),
+            alg if alg.is_ecc() => Some(configured),
+            _ => None,
         }
     }
 }
@@ -161,6 +316,8 @@ impl From<SignAlgorithm> for SignatureSchemeAlgorithm {
             SignAlgorithm::EcDsa => SignatureSchemeAlgorithm::EcDsa,
             //            SignAlgorithm::ECDAA => SignatureSchemeAlgorithm::EcDaa,
             SignAlgorithm::EcSchnorr => SignatureSchemeAlgorithm::EcSchnorr,
+            #[cfg(feature = "sm")]
+            SignAlgorithm::Sm2 => SignatureSchemeAlgorithm::Sm2,
         }
     }
 }
@@ -175,6 +332,8 @@ impl TryFrom<&str> for SignAlgorithm {
             "ecdsa" => Ok(SignAlgorithm::EcDsa),
             //            "ecdaa" => Ok(SignAlgorithm::EcDaa),
             "ecschnorr" => Ok(SignAlgorithm::EcSchnorr),
+            #[cfg(feature = "sm")]
+            "sm2" => Ok(SignAlgorithm::Sm2),
             _ => Err(AlgorithmError::Sign(format!(
                 "Signing algorithm {} not supported by Keylime",
                 value
@@ -191,7 +350,138 @@ impl fmt::Display for SignAlgorithm {
             SignAlgorithm::EcDsa => "ecdsa",
             //           SignAlgorithm::ECDAA => "ecdaa",
             SignAlgorithm::EcSchnorr => "ecschnorr",
+            #[cfg(feature = "sm")]
+            SignAlgorithm::Sm2 => "sm2",
         };
         write!(f, "{}", value)
     }
 }
+
+// Curve of ECC AKs
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EccCurve {
+    P256,
+    P384,
+    #[cfg(feature = "sm")]
+    Sm2P256,
+}
+
+impl EccCurve {
+    pub fn supported() -> Vec<EccCurve> {
+        let mut supported = vec![EccCurve::P256, EccCurve::P384];
+        #[cfg(feature = "sm")]
+        supported.push(EccCurve::Sm2P256);
+        supported
+    }
+}
+
+impl From<EccCurve> for TpmEccCurve {
+    fn from(curve: EccCurve) -> Self {
+        match curve {
+            EccCurve::P256 => TpmEccCurve::NistP256,
+            EccCurve::P384 => TpmEccCurve::NistP384,
+            #[cfg(feature = "sm")]
+            EccCurve::Sm2P256 => TpmEccCurve::Sm2P256,
+        }
+    }
+}
+
+impl TryFrom<&str> for EccCurve {
+    type Error = AlgorithmError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "p256" => Ok(EccCurve::P256),
+            "p384" => Ok(EccCurve::P384),
+            #[cfg(feature = "sm")]
+            "sm2_p256" => Ok(EccCurve::Sm2P256),
+            _ => Err(AlgorithmError::Curve(format!(
+                "ECC curve {} not supported by Keylime",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for EccCurve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = match self {
+            EccCurve::P256 => "p256",
+            EccCurve::P384 => "p384",
+            #[cfg(feature = "sm")]
+            EccCurve::Sm2P256 => "sm2_p256",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        for alg in HashAlgorithm::supported() {
+            let name = alg.to_string();
+            assert_eq!(HashAlgorithm::try_from(name.as_str()).unwrap(), alg); //#[allow_ci]
+        }
+        for alg in SignAlgorithm::supported() {
+            let name = alg.to_string();
+            assert_eq!(SignAlgorithm::try_from(name.as_str()).unwrap(), alg); //#[allow_ci]
+        }
+        for curve in EccCurve::supported() {
+            let name = curve.to_string();
+            assert_eq!(EccCurve::try_from(name.as_str()).unwrap(), curve); //#[allow_ci]
+        }
+        for alg in HmacAlgorithm::supported() {
+            let name = alg.to_string();
+            assert_eq!(HmacAlgorithm::try_from(name.as_str()).unwrap(), alg); //#[allow_ci]
+        }
+        assert!(HmacAlgorithm::try_from("sha1").is_err());
+    }
+
+    #[test]
+    fn test_hmac_len() {
+        assert_eq!(HmacAlgorithm::default().size(), 48);
+        assert_eq!(HmacAlgorithm::Sha256.size(), 32);
+    }
+
+    #[test]
+    fn test_ak_curve() {
+        assert_eq!(SignAlgorithm::RsaSsa.ak_curve(EccCurve::P384), None);
+        assert_eq!(
+            SignAlgorithm::EcDsa.ak_curve(EccCurve::P384),
+            Some(EccCurve::P384)
+        );
+    }
+
+    #[cfg(feature = "sm")]
+    #[test]
+    fn test_sm() {
+        assert_eq!(
+            HashingAlgorithm::from(HashAlgorithm::Sm3_256),
+            HashingAlgorithm::Sm3_256
+        );
+        assert_eq!(
+            SignatureSchemeAlgorithm::from(SignAlgorithm::Sm2),
+            SignatureSchemeAlgorithm::Sm2
+        );
+        assert_eq!(
+            SignAlgorithm::Sm2.ak_curve(EccCurve::P256),
+            Some(EccCurve::Sm2P256)
+        );
+        assert_eq!(
+            SignAlgorithm::Sm2.to_signature_scheme(HashAlgorithm::Sm3_256),
+            SignatureScheme::Sm2 {
+                hash_scheme: HashScheme::new(HashingAlgorithm::Sm3_256)
+            }
+        );
+    }
+
+    #[cfg(not(feature = "sm"))]
+    #[test]
+    fn test_sm_disabled() {
+        assert!(HashAlgorithm::try_from("sm3_256").is_err());
+        assert!(SignAlgorithm::try_from("sm2").is_err());
+    }
+}
@@ -0,0 +1,580 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Versions of the agent API.
+//
+// Each API version served is an entry of API_VERSIONS, listing the endpoints
+// it enables and how responses are serialized for it. The actix scopes are
+// generated from the table, so that adding a version is adding an entry, and
+// the table is reported by /version as the capability matrix of the agent.
+//
+// Quotes and keys are refused with a 503 response until the agent is
+// activated, e.g. while it registers again on request of the admin socket, so
+// that verifiers can tell this startup race from a failure of the agent and
+// retry. Quotes are refused the same way while the agent is in maintenance
+// mode, e.g. during a planned kernel update, with a "maintenance" status
+// rather than failing attestation.
+
+use crate::{
+    algorithms::HashAlgorithm,
+    common::{APIVersion, JsonWrapper},
+    error::{Error, Result},
+    errors_handler, info_handler, keys_handler, notifications_handler,
+    nvram_handler, quotes_handler,
+    status::RegistrationState,
+    tpm, QuoteData,
+};
+use actix_web::{
+    dev::{Service, ServiceRequest},
+    http::header,
+    web, HttpResponse, Route, Scope,
+};
+use futures::future::{ready, Either};
+use log::*;
+use openssl::hash::MessageDigest;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{convert::TryFrom, fmt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum Endpoint {
+    #[serde(rename = "agent/info")]
+    AgentInfo,
+    #[serde(rename = "agent/sbom")]
+    AgentSbom,
+    #[serde(rename = "agent/tpm/health")]
+    TpmHealth,
+    #[serde(rename = "keys/ak")]
+    VerifierAk,
+    #[serde(rename = "keys/ak/activate")]
+    VerifierAkActivate,
+    #[serde(rename = "keys/payload/chunks")]
+    PayloadChunks,
+    #[serde(rename = "keys/payload/file")]
+    PayloadFile,
+    #[serde(rename = "keys/pubkey")]
+    Pubkey,
+    #[serde(rename = "keys/ukey")]
+    UKey,
+    #[serde(rename = "keys/verify")]
+    Verify,
+    #[serde(rename = "keys/vkey")]
+    VKey,
+    #[serde(rename = "notifications/revocation")]
+    Revocation,
+    #[serde(rename = "nvram/{index}")]
+    Nvram,
+    #[serde(rename = "quotes/certify")]
+    Certify,
+    #[serde(rename = "quotes/identity")]
+    Identity,
+    #[serde(rename = "quotes/integrity")]
+    Integrity,
+}
+
+// Scopes grouping the endpoints, in the order they are mounted
+const SCOPES: &[&str] =
+    &["/agent", "/keys", "/notifications", "/nvram", "/quotes"];
+
+// Scopes only served once the agent is activated, and the delay in seconds
+// after which clients are told to retry
+const ACTIVATED_SCOPES: &[&str] = &["/keys", "/quotes"];
+const NOT_ACTIVATED_RETRY_AFTER: u64 = 5;
+// Scopes not served in maintenance mode, see `keylime_agent admin deactivate`
+const MAINTENANCE_SCOPES: &[&str] = &["/quotes"];
+const MAINTENANCE_RETRY_AFTER: u64 = 60;
+
+const V2_0_ENDPOINTS: &[Endpoint] = &[
+    Endpoint::PayloadChunks,
+    Endpoint::PayloadFile,
+    Endpoint::Pubkey,
+    Endpoint::UKey,
+    Endpoint::Verify,
+    Endpoint::VKey,
+    Endpoint::Revocation,
+    Endpoint::Identity,
+    Endpoint::Integrity,
+];
+
+const V2_1_ENDPOINTS: &[Endpoint] = &[
+    Endpoint::AgentInfo,
+    Endpoint::AgentSbom,
+    Endpoint::TpmHealth,
+    Endpoint::VerifierAk,
+    Endpoint::VerifierAkActivate,
+    Endpoint::PayloadChunks,
+    Endpoint::PayloadFile,
+    Endpoint::Pubkey,
+    Endpoint::UKey,
+    Endpoint::Verify,
+    Endpoint::VKey,
+    Endpoint::Revocation,
+    Endpoint::Nvram,
+    Endpoint::Certify,
+    Endpoint::Identity,
+    Endpoint::Integrity,
+];
+
+impl Endpoint {
+    // The scope of the endpoint and its path in the scope
+    fn path(self) -> (&'static str, &'static str) {
+        match self {
+            Endpoint::AgentInfo => ("/agent", "/info"),
+            Endpoint::AgentSbom => ("/agent", "/sbom"),
+            Endpoint::TpmHealth => ("/agent", "/tpm/health"),
+            Endpoint::VerifierAk => ("/keys", "/ak"),
+            Endpoint::VerifierAkActivate => ("/keys", "/ak/activate"),
+            Endpoint::PayloadChunks => ("/keys", "/payload/chunks"),
+            Endpoint::PayloadFile => ("/keys", "/payload/file"),
+            Endpoint::Pubkey => ("/keys", "/pubkey"),
+            Endpoint::UKey => ("/keys", "/ukey"),
+            Endpoint::Verify => ("/keys", "/verify"),
+            Endpoint::VKey => ("/keys", "/vkey"),
+            Endpoint::Revocation => ("/notifications", "/revocation"),
+            Endpoint::Nvram => ("/nvram", "/{index}"),
+            Endpoint::Certify => ("/quotes", "/certify"),
+            Endpoint::Identity => ("/quotes", "/identity"),
+            Endpoint::Integrity => ("/quotes", "/integrity"),
+        }
+    }
+
+    fn route(self) -> Route {
+        match self {
+            Endpoint::AgentInfo => web::get().to(info_handler::info),
+            Endpoint::AgentSbom => web::get().to(info_handler::sbom),
+            Endpoint::TpmHealth => web::get().to(info_handler::tpm_health),
+            Endpoint::VerifierAk => web::get().to(keys_handler::verifier_ak),
+            Endpoint::VerifierAkActivate => {
+                web::post().to(keys_handler::activate_verifier_ak)
+            }
+            Endpoint::PayloadChunks => {
+                web::post().to(keys_handler::payload_chunk)
+            }
+            Endpoint::PayloadFile => {
+                web::post().to(keys_handler::payload_file)
+            }
+            Endpoint::Pubkey => web::get().to(keys_handler::pubkey),
+            Endpoint::UKey => web::post().to(keys_handler::u_key),
+            Endpoint::Verify => web::get().to(keys_handler::verify),
+            Endpoint::VKey => web::post().to(keys_handler::v_key),
+            Endpoint::Revocation => {
+                web::post().to(notifications_handler::revocation)
+            }
+            Endpoint::Nvram => web::get().to(nvram_handler::nvram),
+            Endpoint::Certify => web::get().to(quotes_handler::certify),
+            Endpoint::Identity => web::get().to(quotes_handler::identity),
+            Endpoint::Integrity => web::get().to(quotes_handler::integrity),
+        }
+    }
+}
+
+// Handler of the requests to paths of the scope that are not endpoints
+fn scope_default(scope: &str) -> Route {
+    match scope {
+        "/agent" => web::to(errors_handler::agent_default),
+        "/keys" => web::to(errors_handler::keys_default),
+        "/notifications" => web::to(errors_handler::notifications_default),
+        "/nvram" => web::to(errors_handler::nvram_default),
+        _ => web::to(errors_handler::quotes_default),
+    }
+}
+
+/*
+ * Input: scope and request
+ * Return: the 503 response refusing the request while the agent is not
+ *         activated, None when it can be served
+ */
+fn not_activated(scope: &str, req: &ServiceRequest) -> Option<HttpResponse> {
+    if !ACTIVATED_SCOPES.contains(&scope) {
+        return None;
+    }
+    let registration = req
+        .app_data::<web::Data<QuoteData>>()?
+        .status
+        .report()
+        .registration;
+    if registration == RegistrationState::Activated {
+        return None;
+    }
+    warn!(
+        "{} {} returning 503 response. Agent is not activated yet",
+        req.method(),
+        req.path()
+    );
+    Some(
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, NOT_ACTIVATED_RETRY_AFTER))
+            .json(JsonWrapper {
+                code: 503,
+                status: "Agent is not activated yet".to_string(),
+                results: json!({
+                    "registration": registration,
+                    "retry_after": NOT_ACTIVATED_RETRY_AFTER,
+                }),
+            }),
+    )
+}
+
+/*
+ * Input: scope and request
+ * Return: the 503 response refusing the request while the agent is in
+ *         maintenance, None when it can be served
+ */
+fn in_maintenance(scope: &str, req: &ServiceRequest) -> Option<HttpResponse> {
+    if !MAINTENANCE_SCOPES.contains(&scope) {
+        return None;
+    }
+    let maintenance = req
+        .app_data::<web::Data<QuoteData>>()?
+        .status
+        .maintenance()?;
+    info!(
+        "{} {} returning 503 response. Agent is in maintenance",
+        req.method(),
+        req.path()
+    );
+    Some(
+        HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, MAINTENANCE_RETRY_AFTER))
+            .json(JsonWrapper {
+                code: 503,
+                status: "Agent is in maintenance".to_string(),
+                results: json!({
+                    "maintenance": maintenance,
+                    "retry_after": MAINTENANCE_RETRY_AFTER,
+                }),
+            }),
+    )
+}
+
+// Fields of the responses that depend on the API version
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Serializer {
+    // Boot time in integrity quotes
+    pub quote_boottime: bool,
+    // TPM clock in identity and integrity quotes
+    pub quote_clock_info: bool,
+}
+
+// Composition of the qualifying data of the quotes, which verifier versions
+// construct differently, set per API version with quote_qualifying_data
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum QualifyingData {
+    // The nonce of the request
+    Nonce,
+    // The nonce followed by the digest of the PEM encoded NK, with the hash
+    // algorithm of the quote
+    NoncePubkeyHash,
+}
+
+impl Default for QualifyingData {
+    fn default() -> Self {
+        QualifyingData::Nonce
+    }
+}
+
+impl TryFrom<&str> for QualifyingData {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "nonce" => Ok(QualifyingData::Nonce),
+            "nonce-pubkey-hash" => Ok(QualifyingData::NoncePubkeyHash),
+            _ => Err(Error::Configuration(format!(
+                "quote qualifying data must be 'nonce' or 'nonce-pubkey-hash', got {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for QualifyingData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QualifyingData::Nonce => write!(f, "nonce"),
+            QualifyingData::NoncePubkeyHash => write!(f, "nonce-pubkey-hash"),
+        }
+    }
+}
+
+/*
+ * Input: API version a quote was requested through, None outside of the
+ *        versioned API, and the compositions set in quote_qualifying_data
+ * Return: the composition of the qualifying data of the quote, the nonce
+ *         alone for versions not configured
+ */
+pub(crate) fn qualifying_data(
+    version: Option<APIVersion>,
+    configured: &[(APIVersion, QualifyingData)],
+) -> QualifyingData {
+    version
+        .and_then(|version| {
+            configured
+                .iter()
+                .find(|(configured, _)| *configured == version)
+        })
+        .map(|(_, composition)| *composition)
+        .unwrap_or_default()
+}
+
+// Length of the nonces sent by the Keylime verifier
+pub(crate) const VERIFIER_NONCE_LEN: usize = 20;
+
+/*
+ * Input: hash algorithm of the quotes and the compositions set in
+ *        quote_qualifying_data
+ * Return: whether the NK digest leaves room for the nonce of the verifier in
+ *         the qualifying data, only SHA-1 and SHA-256 digests do
+ */
+pub(crate) fn qualifying_data_fits(
+    hash_alg: HashAlgorithm,
+    configured: &[(APIVersion, QualifyingData)],
+) -> bool {
+    !configured.iter().any(|(_, composition)| {
+        *composition == QualifyingData::NoncePubkeyHash
+    }) || MessageDigest::from(hash_alg).size() + VERIFIER_NONCE_LEN
+        <= tpm::MAX_NONCE_SIZE
+}
+
+#[derive(Debug)]
+pub(crate) struct ApiVersion {
+    pub version: APIVersion,
+    pub endpoints: &'static [Endpoint],
+    pub serializer: Serializer,
+}
+
+// API versions served, from the oldest. Verifiers use the latest one they
+// support, v2.0 is kept for the registrar and older verifiers.
+pub(crate) const API_VERSIONS: &[ApiVersion] = &[
+    ApiVersion {
+        version: APIVersion::V2_0,
+        endpoints: V2_0_ENDPOINTS,
+        serializer: Serializer {
+            quote_boottime: false,
+            quote_clock_info: false,
+        },
+    },
+    ApiVersion {
+        version: APIVersion::V2_1,
+        endpoints: V2_1_ENDPOINTS,
+        serializer: Serializer {
+            quote_boottime: true,
+            quote_clock_info: true,
+        },
+    },
+];
+
+pub(crate) fn latest() -> &'static ApiVersion {
+    &API_VERSIONS[API_VERSIONS.len() - 1]
+}
+
+/*
+ * Input: path of a request
+ * Return: the API version served the request was made to, None for paths
+ *         outside of the versioned API
+ */
+pub(crate) fn of_path(path: &str) -> Option<&'static ApiVersion> {
+    let version = APIVersion::of_path(path)?;
+    API_VERSIONS.iter().find(|api| api.version == version)
+}
+
+impl ApiVersion {
+    // Scope serving the enabled endpoints under the version prefix
+    pub(crate) fn scope(&self) -> Scope {
+        let mut api = web::scope(&format!("/{}", self.version));
+        for &name in SCOPES {
+            let endpoints = self
+                .endpoints
+                .iter()
+                .filter(|endpoint| endpoint.path().0 == name)
+                .collect::<Vec<_>>();
+            if endpoints.is_empty() {
+                continue;
+            }
+            let mut scope = web::scope(name);
+            for endpoint in endpoints {
+                scope = scope.service(
+                    web::resource(endpoint.path().1).route(endpoint.route()),
+                );
+            }
+            api = api.service(
+                scope.default_service(scope_default(name)).wrap_fn(
+                    move |req, srv| match not_activated(name, &req)
+                        .or_else(|| in_maintenance(name, &req))
+                    {
+                        Some(response) => Either::Left(ready(Ok(
+                            req.into_response(response)
+                        ))),
+                        None => Either::Right(srv.call(req)),
+                    },
+                ),
+            );
+        }
+        api.default_service(web::to(errors_handler::api_default))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[test]
+    fn test_of_path() {
+        let api = of_path("/v2.1/quotes/integrity").unwrap(); //#[allow_ci]
+        assert_eq!(api.version, APIVersion::V2_1);
+        assert!(api.serializer.quote_boottime);
+        assert!(api.serializer.quote_clock_info);
+        assert!(
+            !of_path("/v2.0/quotes/integrity")
+                .unwrap() //#[allow_ci]
+                .serializer
+                .quote_boottime
+        );
+        assert!(of_path("/v1.0/quotes/integrity").is_none());
+        assert!(of_path("/version").is_none());
+        assert_eq!(latest().version, APIVersion::V2_1);
+        assert!(latest().endpoints.contains(&Endpoint::Nvram));
+        assert!(latest().endpoints.contains(&Endpoint::Certify));
+        assert!(latest().endpoints.contains(&Endpoint::AgentInfo));
+        assert!(latest().endpoints.contains(&Endpoint::AgentSbom));
+        assert!(latest().endpoints.contains(&Endpoint::TpmHealth));
+    }
+
+    #[test]
+    fn test_qualifying_data() {
+        let configured =
+            [(APIVersion::V2_1, QualifyingData::NoncePubkeyHash)];
+        assert_eq!(
+            qualifying_data(Some(APIVersion::V2_1), &configured),
+            QualifyingData::NoncePubkeyHash
+        );
+        assert_eq!(
+            qualifying_data(Some(APIVersion::V2_0), &configured),
+            QualifyingData::Nonce
+        );
+        assert_eq!(qualifying_data(None, &configured), QualifyingData::Nonce);
+        for composition in
+            [QualifyingData::Nonce, QualifyingData::NoncePubkeyHash]
+        {
+            assert_eq!(
+                QualifyingData::try_from(composition.to_string().as_str())
+                    .unwrap(), //#[allow_ci]
+                composition
+            );
+        }
+        assert!(QualifyingData::try_from("pubkey").is_err());
+    }
+
+    #[test]
+    fn test_qualifying_data_fits() {
+        let configured =
+            [(APIVersion::V2_1, QualifyingData::NoncePubkeyHash)];
+        assert!(qualifying_data_fits(HashAlgorithm::Sha256, &configured));
+        assert!(!qualifying_data_fits(HashAlgorithm::Sha384, &configured));
+        assert!(!qualifying_data_fits(HashAlgorithm::Sha512, &configured));
+        assert!(qualifying_data_fits(HashAlgorithm::Sha384, &[]));
+        assert!(qualifying_data_fits(HashAlgorithm::Sha512, &[]));
+    }
+
+    #[actix_rt::test]
+    async fn test_scope() {
+        let api = ApiVersion {
+            version: APIVersion::of_path("/v9.9").unwrap(), //#[allow_ci]
+            endpoints: &[Endpoint::Pubkey],
+            serializer: Serializer {
+                quote_boottime: false,
+                quote_clock_info: false,
+            },
+        };
+        let mut app =
+            test::init_service(App::new().service(api.scope())).await;
+
+        // Disabled endpoint in an enabled scope
+        let req = test::TestRequest::get()
+            .uri("/v9.9/keys/verify")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["status"]
+            .as_str()
+            .unwrap() //#[allow_ci]
+            .contains("in /keys/ interface"));
+
+        // Scope without any enabled endpoint
+        let req = test::TestRequest::get()
+            .uri("/v9.9/quotes/identity")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["status"]
+            .as_str()
+            .unwrap() //#[allow_ci]
+            .contains("/keys/ or /quotes/"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_not_activated() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .service(latest().scope()),
+        )
+        .await;
+        let uri = format!("/{}/keys/pubkey", latest().version);
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["results"]["registration"], "unregistered");
+
+        quotedata
+            .status
+            .set_registration(RegistrationState::Activated);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_maintenance() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        quotedata
+            .status
+            .set_registration(RegistrationState::Activated);
+        let _ = quotedata
+            .status
+            .start_maintenance(Some("kernel update".to_string()), 3600);
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .service(latest().scope()),
+        )
+        .await;
+
+        let uri = format!(
+            "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            latest().version
+        );
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 503);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["status"], "Agent is in maintenance");
+        assert_eq!(body["results"]["maintenance"]["reason"], "kernel update");
+
+        // Other scopes are still served
+        let uri = format!("/{}/keys/pubkey", latest().version);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+    }
+}
@@ -0,0 +1,178 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Circuit breaker around reads of the IMA and measured boot logs.
+//
+// When securityfs becomes unreadable, e.g. after being remounted, every
+// quote request would otherwise hit the failing file again. After
+// FAILURE_THRESHOLD consecutive failures the breaker opens and reads are
+// skipped, the quote then reports the log as temporarily unavailable. Once
+// the retry delay has passed a single read is let through as a probe:
+// success closes the breaker, failure opens it again with the delay doubled,
+// up to MAX_RETRY_DELAY.
+
+use log::*;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+const FAILURE_THRESHOLD: u32 = 3;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct BreakerState {
+    failures: u32,
+    // Set while the breaker is open: no read before this instant
+    retry_at: Option<Instant>,
+    retry_delay: Duration,
+}
+
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+    name: &'static str,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(name: &'static str) -> Self {
+        CircuitBreaker {
+            name,
+            state: Mutex::new(BreakerState {
+                failures: 0,
+                retry_at: None,
+                retry_delay: INITIAL_RETRY_DELAY,
+            }),
+        }
+    }
+
+    // Whether a read may be attempted at the given time. Letting a probe
+    // through pushes the next retry, so concurrent requests do not probe too.
+    fn allow_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap(); //#[allow_ci]
+        match state.retry_at {
+            Some(retry_at) if now < retry_at => false,
+            Some(_) => {
+                state.retry_at = Some(now + state.retry_delay);
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record_at(&self, success: bool, now: Instant) {
+        let mut state = self.state.lock().unwrap(); //#[allow_ci]
+        if success {
+            if state.retry_at.is_some() {
+                info!("Reading the {} works again", self.name);
+            }
+            state.failures = 0;
+            state.retry_at = None;
+            state.retry_delay = INITIAL_RETRY_DELAY;
+            return;
+        }
+
+        state.failures += 1;
+        if state.retry_at.is_some() {
+            // The probe failed
+            state.retry_delay =
+                std::cmp::min(state.retry_delay * 2, MAX_RETRY_DELAY);
+            state.retry_at = Some(now + state.retry_delay);
+        } else if state.failures >= FAILURE_THRESHOLD {
+            warn!(
+                "Reading the {} failed {} times, retrying in {} seconds",
+                self.name,
+                state.failures,
+                state.retry_delay.as_secs()
+            );
+            state.retry_at = Some(now + state.retry_delay);
+        }
+    }
+
+    /*
+     * Input: read operation
+     * Return: None when the breaker is open, the result of the read otherwise
+     */
+    pub(crate) fn call<T, E>(
+        &self,
+        read: impl FnOnce() -> Result<T, E>,
+    ) -> Option<Result<T, E>> {
+        if !self.allow_at(Instant::now()) {
+            return None;
+        }
+        let result = read();
+        self.record_at(result.is_ok(), Instant::now());
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = CircuitBreaker::new("log");
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD {
+            assert!(breaker.allow_at(now));
+            breaker.record_at(false, now);
+        }
+        assert!(!breaker.allow_at(now));
+        assert!(!breaker.allow_at(now + INITIAL_RETRY_DELAY / 2));
+
+        // A single probe is let through after the delay
+        let probe = now + INITIAL_RETRY_DELAY;
+        assert!(breaker.allow_at(probe));
+        assert!(!breaker.allow_at(probe));
+
+        // A failed probe doubles the delay
+        breaker.record_at(false, probe);
+        assert!(!breaker.allow_at(probe + INITIAL_RETRY_DELAY));
+        let probe = probe + INITIAL_RETRY_DELAY * 2;
+        assert!(breaker.allow_at(probe));
+
+        // A successful probe closes the breaker
+        breaker.record_at(true, probe);
+        assert!(breaker.allow_at(probe));
+        assert!(breaker.allow_at(probe));
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = CircuitBreaker::new("log");
+        let now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD - 1 {
+            breaker.record_at(false, now);
+        }
+        breaker.record_at(true, now);
+        breaker.record_at(false, now);
+        assert!(breaker.allow_at(now));
+    }
+
+    #[test]
+    fn test_max_retry_delay() {
+        let breaker = CircuitBreaker::new("log");
+        let mut now = Instant::now();
+        for _ in 0..FAILURE_THRESHOLD {
+            breaker.record_at(false, now);
+        }
+        for _ in 0..20 {
+            now += MAX_RETRY_DELAY;
+            assert!(breaker.allow_at(now));
+            breaker.record_at(false, now);
+        }
+        assert!(breaker.allow_at(now + MAX_RETRY_DELAY));
+    }
+
+    #[test]
+    fn test_call() {
+        let breaker = CircuitBreaker::new("log");
+        for _ in 0..FAILURE_THRESHOLD {
+            let result = breaker.call(|| Err::<(), &str>("unreadable"));
+            assert_eq!(result, Some(Err("unreadable")));
+        }
+        assert_eq!(breaker.call(|| Ok::<u32, &str>(1)), None);
+    }
+}
@@ -1,14 +1,29 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::algorithms::{EncryptionAlgorithm, HashAlgorithm, SignAlgorithm};
+use crate::alert::AlertDestination;
+use crate::algorithms::{
+    AlgorithmError, EccCurve, EncryptionAlgorithm, HashAlgorithm,
+    HmacAlgorithm, SignAlgorithm,
+};
+use crate::api::{self, QualifyingData, API_VERSIONS};
+use crate::drtm::DrtmSource;
 use crate::error::{Error, Result};
-use crate::{permissions, tpm};
+use crate::log_output::{LogDestination, SyslogFacility};
+use crate::mtls_enrollment::EnrollmentMode;
+use crate::payload_encryption::PayloadEncryption;
+use crate::pkcs11;
+use crate::tee_evidence::TeeSource;
+use crate::{
+    config_upgrade, container, crypto, membership, permissions, tpm,
+};
+use age::secrecy::ExposeSecret;
 use ini::Ini;
 use log::*;
 use openssl::{
     hash::{hash, MessageDigest},
     pkey::PKey,
+    rand::rand_bytes,
     x509::X509,
 };
 use picky_asn1_x509::SubjectPublicKeyInfo;
@@ -21,27 +36,34 @@ use std::fmt::Debug;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use tss_esapi::structures::{Private, Public};
+use tss_esapi::structures::{Private, Public, PublicBuffer};
 use tss_esapi::traits::Marshall;
 use tss_esapi::utils::PublicKey;
 use tss_esapi::{
-    structures::PcrSlot, traits::UnMarshall, utils::TpmsContext,
+    interface_types::algorithm::HashingAlgorithm, structures::PcrSlot,
+    traits::UnMarshall, utils::TpmsContext, Context,
 };
 use uuid::Uuid;
 
 /*
  * Constants and static variables
  */
+// API version used with the registrar, the versions served are listed in
+// api.rs
 pub const API_VERSION: &str = "v2.0";
 pub const STUB_VTPM: bool = false;
 pub const STUB_IMA: bool = true;
 pub const TPM_DATA_PCR: usize = 16;
+// PCRs 17 to 22 are only extended by the DRTM, from localities above 0
+pub const LAST_DRTM_PCR: usize = 22;
 pub const IMA_PCR: usize = 10;
+pub const MAX_PCR: usize = 23;
 pub static DEFAULT_CONFIG: &str = "/etc/keylime-agent.conf";
 pub static RSA_PUBLICKEY_EXPORTABLE: &str = "rsa placeholder";
 pub static TPM_TOOLS_PATH: &str = "/usr/local/bin/";
 pub static IMA_ML: &str =
     "/sys/kernel/security/ima/ascii_runtime_measurements";
+pub static IMA_POLICY: &str = "/sys/kernel/security/ima/policy";
 pub static MEASUREDBOOT_ML: &str =
     "/sys/kernel/security/tpm0/binary_bios_measurements";
 // The DEFAULT_CA_PATH is relative from WORK_DIR
@@ -58,9 +80,34 @@ pub static REV_ACTIONS_DIR: &str = "/usr/libexec/keylime";
 pub static REV_ACTIONS: &str = "";
 pub static ALLOW_PAYLOAD_REV_ACTIONS: bool = true;
 pub static ALLOW_INSECURE_PAYLOAD: bool = false;
+pub const REVOCATION_WEBHOOK_MAX_RETRIES: u32 = 10;
+pub const REVOCATION_WEBHOOK_MAX_QUEUED: usize = 100;
+// Limits on the extraction of zipped payloads, 0 disables a limit
+pub const PAYLOAD_MAX_EXTRACTED_SIZE: u64 = 0;
+pub const PAYLOAD_MAX_ENTRIES: u64 = 10000;
+pub const PAYLOAD_MAX_COMPRESSION_RATIO: u64 = 100;
+// No limit on the number of IMA entries per quote response by default
+pub const IMA_ML_MAX_ENTRIES: u64 = 0;
+pub const VERIFY_MAX_FAILURES: u32 = 10;
+pub const VERIFY_LOCKOUT: u64 = 300;
+pub const IMA_CHANGE_CHECK_INTERVAL: u64 = 2;
+pub const DISK_SPACE_CHECK_INTERVAL: u64 = 60;
+pub const DISK_SPACE_WARN_PERCENT: u64 = 10;
+pub const AGENT_DATA_AUDIT_INTERVAL: u64 = 300;
+// Do not wait for the TPM to become available by default
+pub const TPM_WAIT_TIMEOUT: u64 = 0;
+pub const TPM_OPERATION_TIMEOUT: u64 = 30;
+// Attempts of a TPM command the TPM asked to resubmit, and delay in
+// milliseconds before the first retry, doubled for each of the next ones
+pub const TPM_RETRY_ATTEMPTS: u32 = 5;
+pub const TPM_RETRY_DELAY: u64 = 100;
+// The agent event log lives in the secure mount, which like the PCRs is
+// cleared on reboot
+pub static AGENT_EVENT_LOG: &str = "agent_event_log";
+// The ADMIN_SOCKET is relative from WORK_DIR
+pub static ADMIN_SOCKET: &str = "agent.sock";
 
 pub const AGENT_UUID_LEN: usize = 36;
-pub const AUTH_TAG_LEN: usize = 96;
 pub const AES_128_KEY_LEN: usize = 16;
 pub const AES_256_KEY_LEN: usize = 32;
 pub const AES_BLOCK_SIZE: usize = 16;
@@ -85,12 +132,34 @@ cfg_if::cfg_if! {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord,
+)]
 pub(crate) struct APIVersion {
     major: u32,
     minor: u32,
 }
 
+impl APIVersion {
+    pub(crate) const V2_0: APIVersion = APIVersion { major: 2, minor: 0 };
+    // Adds the boot time to integrity quotes
+    pub(crate) const V2_1: APIVersion = APIVersion { major: 2, minor: 1 };
+
+    /*
+     * Input: path of a request
+     * Return: the API version the request was made to, None for paths
+     *         outside of the versioned API
+     */
+    pub(crate) fn of_path(path: &str) -> Option<APIVersion> {
+        let version = path.trim_start_matches('/').split('/').next()?;
+        let (major, minor) = version.strip_prefix('v')?.split_once('.')?;
+        Some(APIVersion {
+            major: major.parse().ok()?,
+            minor: minor.parse().ok()?,
+        })
+    }
+}
+
 impl std::fmt::Display for APIVersion {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "v{}.{}", self.major, self.minor)
@@ -161,6 +230,16 @@ impl SymmKey {
     }
 }
 
+// Zero the key, so that it does not linger in freed memory
+impl Drop for SymmKey {
+    fn drop(&mut self) {
+        for byte in self.bytes.iter_mut() {
+            // Volatile, so that the writes are not optimized away
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for SymmKey {
     type Error = String;
 
@@ -177,25 +256,151 @@ impl TryFrom<&[u8]> for SymmKey {
     }
 }
 
+// AK created for other algorithms than the ones in use, kept so that the
+// agent can switch back to them without creating and registering a new AK
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StoredAk {
+    pub hash_alg: HashAlgorithm,
+    pub sign_alg: SignAlgorithm,
+    #[serde(default)]
+    pub ecc_curve: Option<EccCurve>,
+    public: Vec<u8>,
+    private: Vec<u8>,
+}
+
+impl StoredAk {
+    fn matches(
+        &self,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        ecc_curve: Option<EccCurve>,
+    ) -> bool {
+        hash_alg == self.hash_alg
+            && sign_alg == self.sign_alg
+            && ecc_curve == self.ecc_curve
+    }
+}
+
+// NK sealed in the TPM, see 'nk_seal'. The TPM only seals up to 128 bytes,
+// so the NK is encrypted in the payload format with an AES key, and the key
+// is sealed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SealedNk {
+    // PCRs of the SHA-256 bank the key is bound to
+    pcrs: Vec<usize>,
+    public: Vec<u8>,
+    private: Vec<u8>,
+    encrypted_nk: Vec<u8>,
+}
+
+impl SealedNk {
+    // PCRs of the SHA-256 bank the key is bound to
+    pub(crate) fn pcrs(&self) -> &[usize] {
+        &self.pcrs
+    }
+
+    /*
+     * Input: Connection context and its handle registry, NK and PCRs to
+     *        bind it to
+     * Return: Result wrap the sealed NK
+     */
+    pub(crate) fn seal(
+        ctx: &mut Context,
+        handles: &tpm::HandleRegistry,
+        nk_priv: &PKey<openssl::pkey::Private>,
+        pcrs: &[usize],
+    ) -> Result<Self> {
+        let policy =
+            tpm::PcrPolicy::new(HashingAlgorithm::Sha256, pcrs, false)?;
+        let mut key = [0u8; AES_256_KEY_LEN];
+        rand_bytes(&mut key)?;
+        let mut iv = [0u8; AES_BLOCK_SIZE];
+        rand_bytes(&mut iv)?;
+        let encrypted_nk = crypto::encrypt_aead(
+            &key,
+            &iv,
+            &nk_priv.private_key_to_pem_pkcs8()?,
+        )?;
+        let (public, private) =
+            tpm::seal(ctx, handles, &key, policy.as_ref())?;
+        Ok(SealedNk {
+            pcrs: pcrs.to_vec(),
+            public: public.marshall()?,
+            private: private.to_vec(),
+            encrypted_nk,
+        })
+    }
+
+    /*
+     * Input: Connection context and its handle registry
+     * Return: Result wrap the NK, an error once the PCRs it is bound to
+     *         changed
+     */
+    pub(crate) fn unseal(
+        &self,
+        ctx: &mut Context,
+        handles: &tpm::HandleRegistry,
+    ) -> Result<PKey<openssl::pkey::Private>> {
+        let policy =
+            tpm::PcrPolicy::new(HashingAlgorithm::Sha256, &self.pcrs, false)?;
+        let key = tpm::unseal(
+            ctx,
+            handles,
+            Public::unmarshall(&self.public)?,
+            Private::try_from(self.private.clone())?,
+            policy.as_ref(),
+        )?;
+        let nk_priv = crypto::decrypt_aead(&key, &self.encrypted_nk)?;
+        Ok(PKey::private_key_from_pem(&nk_priv)?)
+    }
+}
+
 // TPM data and agent related that can be persisted and loaded on agent startup.
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub(crate) struct AgentData {
     pub ak_hash_alg: HashAlgorithm,
     pub ak_sign_alg: SignAlgorithm,
+    // Only set for ECC AKs. Older agent data does not have it, their ECC AK
+    // is then not reused as it was created on a different curve.
+    #[serde(default)]
+    pub ak_ecc_curve: Option<EccCurve>,
+    // Whether the AK algorithms were requested by the registrar rather than
+    // configured
+    #[serde(default)]
+    pub ak_negotiated: bool,
     ak_public: Vec<u8>,
     ak_private: Vec<u8>,
     nk_pub: Vec<u8>,
+    // Empty when the NK is sealed or kept in a PKCS#11 token
     nk_priv: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nk_sealed: Option<SealedNk>,
     mtls_cert: Option<Vec<u8>>,
+    // Intermediate CA certificates the enrolled mTLS certificate is served
+    // with, see mtls_enrollment.rs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mtls_cert_chain: Vec<String>,
+    // One AK per combination of algorithms used before
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    other_aks: Vec<StoredAk>,
+    // Set when age payloads are accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    age_identity: Option<String>,
+    // HMAC algorithm requested by the registrar, used over the configured
+    // one at the next starts. Kept by name so that agent data recording an
+    // algorithm the agent no longer supports still loads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hmac_alg: Option<String>,
 }
 
 impl AgentData {
     pub(crate) fn create(
         ak_hash_alg: HashAlgorithm,
         ak_sign_alg: SignAlgorithm,
+        ak_ecc_curve: Option<EccCurve>,
         ak: &tpm::AKResult,
         nk_pub: &PKey<openssl::pkey::Public>,
-        nk_priv: &PKey<openssl::pkey::Private>,
+        nk_priv: Option<&PKey<openssl::pkey::Private>>,
         mtls_cert: &Option<&X509>,
     ) -> Result<Self> {
         let ak_public = ak.public.marshall()?;
@@ -207,11 +412,21 @@ impl AgentData {
         Ok(Self {
             ak_hash_alg,
             ak_sign_alg,
+            ak_ecc_curve,
+            ak_negotiated: false,
             ak_public,
             ak_private,
             nk_pub: nk_pub.public_key_to_pem()?,
-            nk_priv: nk_priv.private_key_to_pem_pkcs8()?,
+            nk_priv: match nk_priv {
+                Some(nk_priv) => nk_priv.private_key_to_pem_pkcs8()?,
+                None => Vec::new(),
+            },
+            nk_sealed: None,
             mtls_cert,
+            mtls_cert_chain: Vec::new(),
+            other_aks: Vec::new(),
+            age_identity: None,
+            hmac_alg: None,
         })
     }
 
@@ -222,7 +437,7 @@ impl AgentData {
     }
 
     pub(crate) fn store(&self, path: &Path) -> Result<()> {
-        let file = File::create(path)?;
+        let file = permissions::create_file(path)?;
         serde_json::to_writer_pretty(file, self)?;
         Ok(())
     }
@@ -234,6 +449,24 @@ impl AgentData {
         Ok(tpm::AKResult { public, private })
     }
 
+    // Whether the NK is stored in clear, rather than sealed or kept in a
+    // PKCS#11 token
+    pub(crate) fn has_nk(&self) -> bool {
+        !self.nk_priv.is_empty()
+    }
+
+    pub(crate) fn sealed_nk(&self) -> Option<&SealedNk> {
+        self.nk_sealed.as_ref()
+    }
+
+    pub(crate) fn set_sealed_nk(&mut self, sealed: SealedNk) {
+        self.nk_sealed = Some(sealed);
+    }
+
+    pub(crate) fn get_nk_pub(&self) -> Result<PKey<openssl::pkey::Public>> {
+        Ok(PKey::public_key_from_pem(&self.nk_pub)?)
+    }
+
     pub(crate) fn get_nk(
         &self,
     ) -> Result<(PKey<openssl::pkey::Public>, PKey<openssl::pkey::Private>)>
@@ -243,6 +476,11 @@ impl AgentData {
         Ok((nk_pub, nk_priv))
     }
 
+    pub(crate) fn set_mtls_cert(&mut self, mtls_cert: &X509) -> Result<()> {
+        self.mtls_cert = Some(mtls_cert.to_pem()?);
+        Ok(())
+    }
+
     pub(crate) fn get_mtls_cert(&self) -> Result<Option<X509>> {
         match &self.mtls_cert {
             Some(cert) => Ok(Some(X509::from_pem(cert)?)),
@@ -250,16 +488,165 @@ impl AgentData {
         }
     }
 
+    pub(crate) fn set_mtls_cert_chain(
+        &mut self,
+        chain: &[X509],
+    ) -> Result<()> {
+        self.mtls_cert_chain = chain
+            .iter()
+            .map(|cert| Ok(String::from_utf8(cert.to_pem()?)?))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+
+    pub(crate) fn get_mtls_cert_chain(&self) -> Result<Vec<X509>> {
+        self.mtls_cert_chain
+            .iter()
+            .map(|cert| Ok(X509::from_pem(cert.as_bytes())?))
+            .collect()
+    }
+
+    pub(crate) fn set_age_identity(
+        &mut self,
+        identity: &age::x25519::Identity,
+    ) {
+        self.age_identity =
+            Some(identity.to_string().expose_secret().clone());
+    }
+
+    pub(crate) fn get_age_identity(
+        &self,
+    ) -> Result<Option<age::x25519::Identity>> {
+        match &self.age_identity {
+            Some(identity) => Ok(Some(identity.parse().map_err(|e| {
+                Error::Other(format!("Invalid age identity: {}", e))
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn set_hmac_alg(&mut self, hmac_alg: HmacAlgorithm) {
+        self.hmac_alg = Some(hmac_alg.to_string());
+    }
+
+    pub(crate) fn hmac_alg(&self) -> Result<Option<HmacAlgorithm>> {
+        match &self.hmac_alg {
+            Some(hmac_alg) => {
+                Ok(Some(HmacAlgorithm::try_from(hmac_alg.as_str())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // Keeps the HMAC algorithm requested by the registrar in the previous
+    // agent data
+    pub(crate) fn keep_hmac_alg(&mut self, previous: &AgentData) {
+        if self.hmac_alg.is_none() {
+            self.hmac_alg = previous.hmac_alg.clone();
+        }
+    }
+
     pub(crate) fn valid(
         &self,
         hash_alg: HashAlgorithm,
         sign_alg: SignAlgorithm,
+        ecc_curve: Option<EccCurve>,
+    ) -> bool {
+        hash_alg == self.ak_hash_alg
+            && sign_alg == self.ak_sign_alg
+            && ecc_curve == self.ak_ecc_curve
+    }
+
+    fn stored_ak(&self) -> StoredAk {
+        StoredAk {
+            hash_alg: self.ak_hash_alg,
+            sign_alg: self.ak_sign_alg,
+            ecc_curve: self.ak_ecc_curve,
+            public: self.ak_public.clone(),
+            private: self.ak_private.clone(),
+        }
+    }
+
+    /*
+     * Input: algorithms of the AK
+     * Return: whether an AK for them is stored
+     *
+     * Makes the AK stored for the algorithms the one in use, keeping the
+     * previous one among the other AKs.
+     */
+    pub(crate) fn select_ak(
+        &mut self,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        ecc_curve: Option<EccCurve>,
     ) -> bool {
-        hash_alg == self.ak_hash_alg && sign_alg == self.ak_sign_alg
+        if self.valid(hash_alg, sign_alg, ecc_curve) {
+            return true;
+        }
+        let position = match self
+            .other_aks
+            .iter()
+            .position(|ak| ak.matches(hash_alg, sign_alg, ecc_curve))
+        {
+            Some(position) => position,
+            None => return false,
+        };
+        let selected = self.other_aks.remove(position);
+        let previous = self.stored_ak();
+        self.other_aks.push(previous);
+        self.ak_hash_alg = selected.hash_alg;
+        self.ak_sign_alg = selected.sign_alg;
+        self.ak_ecc_curve = selected.ecc_curve;
+        self.ak_negotiated = false;
+        self.ak_public = selected.public;
+        self.ak_private = selected.private;
+        true
+    }
+
+    // Keeps the AKs of the previous agent data that were created for other
+    // algorithms than the AK in use
+    pub(crate) fn keep_aks(&mut self, previous: &AgentData) {
+        let aks = std::iter::once(previous.stored_ak())
+            .chain(previous.other_aks.iter().cloned());
+        for ak in aks {
+            let in_use = self.valid(ak.hash_alg, ak.sign_alg, ak.ecc_curve);
+            let kept = self.other_aks.iter().any(|other| {
+                other.matches(ak.hash_alg, ak.sign_alg, ak.ecc_curve)
+            });
+            if !in_use && !kept {
+                self.other_aks.push(ak);
+            }
+        }
+    }
+}
+
+// How the agent UUID is derived from the EK when agent_uuid is "hash_ek"
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub(crate) enum HashEkVersion {
+    // SHA-256 over the PEM encoding of the EK of 'tpm_encryption_alg'
+    Legacy,
+    // SHA-256 over the TPM2B_PUBLIC encoding of the RSA EK created from the
+    // default template, whatever 'tpm_encryption_alg' is set to
+    V2,
+}
+
+impl TryFrom<&str> for HashEkVersion {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "legacy" => Ok(HashEkVersion::Legacy),
+            "v2" => Ok(HashEkVersion::V2),
+            _ => Err(Error::Configuration(format!(
+                "hash_ek_version must be either 'legacy' or 'v2', got {}",
+                value
+            ))),
+        }
     }
 }
 
-#[derive(Clone, Debug)]
+// Serialized to measure the effective configuration, see self_measurement.rs
+#[derive(Clone, Debug, Serialize)]
 pub(crate) struct KeylimeConfig {
     pub agent_ip: String,
     pub agent_port: String,
@@ -268,44 +655,140 @@ pub(crate) struct KeylimeConfig {
     pub agent_uuid: String,
     pub agent_contact_ip: Option<String>,
     pub agent_contact_port: Option<u32>,
+    pub agent_contact_interface: Option<String>,
+    pub registration_metadata: Vec<String>,
+    pub agent_group: Option<String>,
+    pub agent_tags: Vec<String>,
     pub hash_alg: HashAlgorithm,
     pub enc_alg: EncryptionAlgorithm,
     pub sign_alg: SignAlgorithm,
+    // Used in this order when the TPM does not implement the algorithm
+    pub hash_alg_fallback: Vec<HashAlgorithm>,
+    pub enc_alg_fallback: Vec<EncryptionAlgorithm>,
+    pub sign_alg_fallback: Vec<SignAlgorithm>,
+    pub ecc_curve: EccCurve,
+    pub hmac_alg: HmacAlgorithm,
+    #[serde(skip)]
     pub agent_data: Option<AgentData>,
     pub agent_data_path: String,
     pub run_revocation: bool,
     pub revocation_cert: String,
     pub revocation_ip: String,
     pub revocation_port: String,
+    pub revocation_topics: Vec<String>,
     pub secure_size: String,
     pub payload_script: String,
+    pub run_payload_script: bool,
+    pub payload_file_dirs: Vec<String>,
+    pub payload_encryption: Vec<PayloadEncryption>,
+    pub payload_gnupg_home: Option<String>,
+    pub nvram_indices: Vec<u32>,
+    pub payload_counter_nv_index: Option<u32>,
     pub dec_payload_filename: String,
     pub key_filename: String,
     pub extract_payload_zip: bool,
+    pub payload_max_extracted_size: u64,
+    pub payload_max_entries: u64,
+    pub payload_max_compression_ratio: u64,
     pub keylime_ca_path: String,
     pub revocation_actions: String,
     pub revocation_actions_dir: String,
     pub allow_payload_revocation_actions: bool,
+    pub revocation_webhook: Option<String>,
+    pub revocation_webhook_max_retries: u32,
+    pub revocation_webhook_max_queued: usize,
     pub work_dir: String,
     pub mtls_enabled: bool,
+    // How the mTLS certificate is obtained, see mtls_enrollment.rs
+    pub mtls_cert_enrollment: EnrollmentMode,
+    pub mtls_cert_enrollment_url: Option<String>,
+    pub enable_http2: bool,
+    pub tls_session_resumption: bool,
+    // PKCS#11 URI of the NK when it is kept in a token, see pkcs11.rs
+    pub nk_pkcs11_uri: Option<String>,
+    pub nk_pkcs11_module: Option<String>,
+    #[serde(skip)]
+    pub nk_pkcs11_pin: Option<String>,
+    // Whether the NK stored in the agent data is sealed in the TPM, and the
+    // PCRs it is bound to
+    pub nk_seal: bool,
+    pub nk_seal_pcrs: Vec<usize>,
+    pub cors_allowed_origins: Vec<String>,
+    pub security_headers: bool,
     pub enable_insecure_payload: bool,
     pub run_as: Option<String>,
+    #[serde(skip)]
     pub tpm_ownerpassword: Option<String>,
     pub ek_handle: Option<String>,
+    pub ek_cert_path: Option<String>,
+    pub ek_cert_efi_var: Option<String>,
+    pub tpm_cert_store: Option<String>,
+    pub enable_iak_idevid: bool,
+    pub iak_idevid_alg: EncryptionAlgorithm,
+    pub iak_handle: Option<String>,
+    pub idevid_handle: Option<String>,
+    pub iak_cert_path: Option<String>,
+    pub idevid_cert_path: Option<String>,
+    pub ima_ml_max_entries: u64,
+    pub quote_nonce_replay_window: u64,
+    pub quote_qualifying_data: Vec<(APIVersion, QualifyingData)>,
+    pub quote_cache_ttl: u64,
+    pub verify_max_failures: u32,
+    pub verify_lockout: u64,
+    pub quote_key_lifetime: u64,
+    pub ak_per_verifier: bool,
+    pub x25519_key_wrap: bool,
+    pub ima_pcr: usize,
+    pub ima_change_webhook: Option<String>,
+    pub ima_change_check_interval: u64,
+    pub disk_space_check_interval: u64,
+    pub disk_space_warn_percent: u64,
+    pub agent_data_audit_interval: u64,
+    pub measure_agent_pcr: Option<usize>,
+    pub runtime_inventory_pcr: Option<usize>,
+    pub ak_policy_pcrs: Vec<usize>,
+    pub ak_policy_secret: bool,
+    pub drtm_log: DrtmSource,
+    pub tee_evidence: TeeSource,
+    pub hash_ek_version: HashEkVersion,
+    pub reject_sw_tpm: bool,
+    pub lock_keys_after_bootstrap: bool,
+    pub log_destination: LogDestination,
+    pub syslog_facility: SyslogFacility,
+    pub alert_destination: Option<AlertDestination>,
+    pub maintenance_notify_url: Option<String>,
+    pub lock_memory: bool,
+    pub disable_core_dumps: bool,
+    pub abort_on_panic: bool,
+    pub tcti: Option<String>,
+    pub tpm_wait_timeout: u64,
+    pub tpm_operation_timeout: u64,
+    pub tpm_encrypt_sessions: bool,
+    pub tpm_retry_attempts: u32,
+    pub tpm_retry_delay: u64,
+    pub admin_socket: Option<String>,
+    pub config_overrides_cert: Option<String>,
+    pub config_bundle_cert: Option<String>,
 }
 
 impl KeylimeConfig {
     pub fn build() -> Result<Self> {
-        let conf_name = config_file_get();
-        let conf = match Ini::load_from_file(&conf_name) {
-            Ok(file) => file,
-            Err(e) => {
-                error!(
-                    "Could not load keylime config file: {} due to error: {}",
-                    conf_name, e
-                );
-                return Err(Error::Ini(e));
-            }
+        let (conf_name, conf) = if container::enabled() {
+            (container::CONF_NAME.to_string(), container::conf()?)
+        } else {
+            let conf_name = config_file_get();
+            let conf = match Ini::load_from_file(&conf_name) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!(
+                        "Could not load keylime config file: {} due to error: {}",
+                        conf_name, e
+                    );
+                    return Err(Error::Ini(e));
+                }
+            };
+            let conf = config_upgrade::load(&conf_name, conf)?;
+            (conf_name, conf)
         };
 
         let agent_ip = config_get_env(
@@ -342,6 +825,46 @@ impl KeylimeConfig {
         let agent_contact_ip = cloudagent_contact_ip_get(&conf_name, &conf);
         let agent_contact_port =
             cloudagent_contact_port_get(&conf_name, &conf)?;
+        let agent_contact_interface = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "agent_contact_interface",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+        let registration_metadata = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "registration_metadata",
+        ) {
+            Ok(s) => s
+                .split(',')
+                .map(|p| p.trim().to_lowercase())
+                .filter(|p| !p.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        // Cohorts of the fleet the agent is in, see membership.rs
+        let agent_group =
+            match config_get(&conf_name, &conf, "cloud_agent", "agent_group")
+            {
+                Ok(s) if !s.trim().is_empty() => {
+                    Some(membership::check_name("agent_group", s.trim())?)
+                }
+                _ => None,
+            };
+        let agent_tags = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "agent_tags",
+        ) {
+            Ok(s) => membership::parse_tags(&s)?,
+            Err(_) => Vec::new(),
+        };
         let hash_alg = HashAlgorithm::try_from(
             config_get(&conf_name, &conf, "cloud_agent", "tpm_hash_alg")?
                 .as_str(),
@@ -359,23 +882,49 @@ impl KeylimeConfig {
             config_get(&conf_name, &conf, "cloud_agent", "tpm_signing_alg")?
                 .as_str(),
         )?;
-        // There was a typo in Python Keylime and this accounts for having a version
-        // of Keylime installed that still has this typo. TODO: Remove later
+        let hash_alg_fallback = algorithm_list(config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_hash_alg_fallback",
+        ))?;
+        let enc_alg_fallback = algorithm_list(config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_encryption_alg_fallback",
+        ))?;
+        let sign_alg_fallback = algorithm_list(config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_signing_alg_fallback",
+        ))?;
+        let ecc_curve = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_ecc_curve",
+        ) {
+            Ok(s) if !s.is_empty() => EccCurve::try_from(s.as_str())?,
+            _ => EccCurve::P256,
+        };
+        let hmac_alg =
+            match config_get(&conf_name, &conf, "cloud_agent", "hmac_alg") {
+                Ok(s) if !s.is_empty() => {
+                    HmacAlgorithm::try_from(s.as_str())?
+                }
+                _ => HmacAlgorithm::default(),
+            };
+        // The 'listen_notfications' typo of older files is fixed by
+        // config_upgrade
         let run_revocation = bool::from_str(
             &config_get(
                 &conf_name,
                 &conf,
                 "cloud_agent",
                 "listen_notifications",
-            )
-            .or_else(|_| {
-                config_get(
-                    &conf_name,
-                    &conf,
-                    "cloud_agent",
-                    "listen_notfications",
-                )
-            })?
+            )?
             .to_lowercase(),
         )?;
 
@@ -393,11 +942,106 @@ impl KeylimeConfig {
             "general",
             "receive_revocation_port",
         )?;
+        let revocation_topics = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "revocation_topics",
+        ) {
+            Ok(s) => s
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
 
         let secure_size =
             config_get(&conf_name, &conf, "cloud_agent", "secure_size")?;
         let payload_script =
             config_get(&conf_name, &conf, "cloud_agent", "payload_script")?;
+        let run_payload_script = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "run_payload_script",
+        ) {
+            Ok(run) => bool::from_str(&run.to_lowercase())?,
+            Err(_) => true,
+        };
+        let payload_file_dirs = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_file_dirs",
+        ) {
+            Ok(s) => s
+                .split(',')
+                .map(|d| d.trim().to_string())
+                .filter(|d| !d.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        let payload_encryption = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_encryption",
+        ) {
+            Ok(s) => s
+                .split(',')
+                .map(str::trim)
+                .filter(|e| !e.is_empty())
+                .map(PayloadEncryption::try_from)
+                .collect::<Result<Vec<PayloadEncryption>>>()?,
+            Err(_) => vec![PayloadEncryption::AesGcm],
+        };
+        let payload_gnupg_home = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_gnupg_home",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+        let nvram_indices = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "nvram_indices",
+        ) {
+            Ok(s) => s
+                .split(',')
+                .map(str::trim)
+                .filter(|i| !i.is_empty())
+                .map(|i| {
+                    parse_nv_index(i).ok_or_else(|| {
+                        Error::Configuration(format!(
+                            "nvram_indices must be a list of NV indices between 0x1000000 and 0x1ffffff, got {}",
+                            i
+                        ))
+                    })
+                })
+                .collect::<Result<Vec<u32>>>()?,
+            Err(_) => Vec::new(),
+        };
+        let payload_counter_nv_index = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_counter_nv_index",
+        ) {
+            Ok(s) if !s.trim().is_empty() => {
+                Some(parse_nv_index(s.trim()).ok_or_else(|| {
+                    Error::Configuration(format!(
+                        "payload_counter_nv_index must be an NV index between 0x1000000 and 0x1ffffff, got {}",
+                        s
+                    ))
+                })?)
+            }
+            _ => None,
+        };
         let dec_payload_filename =
             config_get(&conf_name, &conf, "cloud_agent", "dec_payload_file")?;
 
@@ -412,6 +1056,33 @@ impl KeylimeConfig {
             )?
             .to_lowercase(),
         )?;
+        let payload_max_extracted_size = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_max_extracted_size",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => PAYLOAD_MAX_EXTRACTED_SIZE,
+        };
+        let payload_max_entries = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_max_entries",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => PAYLOAD_MAX_ENTRIES,
+        };
+        let payload_max_compression_ratio = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "payload_max_compression_ratio",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => PAYLOAD_MAX_COMPRESSION_RATIO,
+        };
 
         let work_dir = config_get_env(
             &conf_name,
@@ -501,6 +1172,121 @@ impl KeylimeConfig {
             Err(_) => true,
         };
 
+        let mtls_cert_enrollment = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "mtls_cert_enrollment",
+        ) {
+            Ok(s) if !s.is_empty() => EnrollmentMode::try_from(s.as_str())?,
+            _ => EnrollmentMode::SelfSigned,
+        };
+
+        let mtls_cert_enrollment_url = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "mtls_cert_enrollment_url",
+        ) {
+            Ok(s) if s.starts_with("https://") => Some(s),
+            Ok(s) if !s.is_empty() => {
+                return Err(Error::Configuration(format!(
+                    "mtls_cert_enrollment_url must be an https:// URL, got {}",
+                    s
+                )));
+            }
+            _ => None,
+        };
+        if mtls_cert_enrollment != EnrollmentMode::SelfSigned
+            && mtls_cert_enrollment_url.is_none()
+        {
+            return Err(Error::Configuration(
+                "mtls_cert_enrollment_url is required to enroll the mTLS certificate"
+                    .to_string(),
+            ));
+        }
+
+        let enable_http2 = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "enable_http2",
+        ) {
+            Ok(enabled) => bool::from_str(&enabled.to_lowercase())?,
+            Err(_) => true,
+        };
+
+        let tls_session_resumption = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tls_session_resumption",
+        ) {
+            Ok(enabled) => bool::from_str(&enabled.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        let nk_pkcs11_option = |option: &str| match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            option,
+        ) {
+            Ok(s) if !s.trim().is_empty() => Some(s.trim().to_string()),
+            _ => None,
+        };
+        let nk_pkcs11_uri = nk_pkcs11_option("nk_pkcs11_uri");
+        let nk_pkcs11_module = nk_pkcs11_option("nk_pkcs11_module");
+        let nk_pkcs11_pin = nk_pkcs11_option("nk_pkcs11_pin");
+        if let Some(uri) = &nk_pkcs11_uri {
+            if !uri.starts_with("pkcs11:") {
+                return Err(Error::Configuration(format!(
+                    "nk_pkcs11_uri must be a PKCS#11 URI (RFC 7512), got {}",
+                    pkcs11::redact(uri)
+                )));
+            }
+        }
+
+        let nk_seal =
+            match config_get(&conf_name, &conf, "cloud_agent", "nk_seal") {
+                Ok(seal) => bool::from_str(&seal.to_lowercase())?,
+                Err(_) => false,
+            };
+
+        let nk_seal_pcrs = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "nk_seal_pcrs",
+        ) {
+            Ok(s) => parse_pcr_list("nk_seal_pcrs", &s)?,
+            Err(_) => Vec::new(),
+        };
+
+        let cors_allowed_origins = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "cors_allowed_origins",
+        ) {
+            Ok(s) => s
+                .split(',')
+                .map(|o| o.trim().to_string())
+                .filter(|o| !o.is_empty())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        let security_headers = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "security_headers",
+        ) {
+            Ok(enabled) => bool::from_str(&enabled.to_lowercase())?,
+            Err(_) => false,
+        };
+
         let enable_insecure_payload = match config_get(
             &conf_name,
             &conf,
@@ -522,41 +1308,740 @@ impl KeylimeConfig {
                 .ok()
                 .filter(|s| s != "generate");
 
-        Ok(KeylimeConfig {
-            agent_ip,
-            agent_port,
-            registrar_ip,
-            registrar_port,
-            agent_uuid,
-            agent_contact_ip,
-            agent_contact_port,
-            hash_alg,
-            enc_alg,
-            sign_alg,
-            agent_data,
-            agent_data_path: agent_data_path.display().to_string(),
+        // Fallbacks for the EK certificate, used when it is not in the TPM NV
+        let ek_cert_path = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ek_cert_path",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        let ek_cert_efi_var = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ek_cert_efi_var",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        // Manufacturer CA certificates the EK certificate is verified against
+        let tpm_cert_store = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_cert_store",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        // TCG device identity keys, see device_identity.rs
+        let enable_iak_idevid = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "enable_iak_idevid",
+        ) {
+            Ok(enabled) => bool::from_str(&enabled.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        let iak_idevid_alg = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "iak_idevid_alg",
+        ) {
+            Ok(s) if !s.is_empty() => {
+                EncryptionAlgorithm::try_from(s.as_str())?
+            }
+            _ => EncryptionAlgorithm::Rsa,
+        };
+
+        let iak_handle = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "iak_handle",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        let idevid_handle = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "idevid_handle",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        let iak_cert_path =
+            match config_get(&conf_name, &conf, "cloud_agent", "iak_cert") {
+                Ok(s) if !s.is_empty() => Some(s),
+                _ => None,
+            };
+
+        let idevid_cert_path =
+            match config_get(&conf_name, &conf, "cloud_agent", "idevid_cert")
+            {
+                Ok(s) if !s.is_empty() => Some(s),
+                _ => None,
+            };
+
+        let ima_ml_max_entries = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ima_ml_max_entries",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => IMA_ML_MAX_ENTRIES,
+        };
+
+        let quote_nonce_replay_window = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "quote_nonce_replay_window",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => 0,
+        };
+
+        let quote_qualifying_data = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "quote_qualifying_data",
+        ) {
+            Ok(s) => parse_qualifying_data(&s)?,
+            Err(_) => Vec::new(),
+        };
+        if let Some(hash_alg) = std::iter::once(&hash_alg)
+            .chain(&hash_alg_fallback)
+            .find(|hash_alg| {
+                !api::qualifying_data_fits(**hash_alg, &quote_qualifying_data)
+            })
+        {
+            return Err(Error::Configuration(format!(
+                "quote_qualifying_data nonce-pubkey-hash leaves no room for the {} bytes nonce of the verifier in quotes using {}, set tpm_hash_alg and tpm_hash_alg_fallback to shorter digests",
+                api::VERIFIER_NONCE_LEN, hash_alg
+            )));
+        }
+
+        let quote_cache_ttl = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "quote_cache_ttl",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => 0,
+        };
+
+        let verify_max_failures = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "verify_max_failures",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u32>()?,
+            _ => VERIFY_MAX_FAILURES,
+        };
+        let verify_lockout = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "verify_lockout",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => VERIFY_LOCKOUT,
+        };
+
+        let quote_key_lifetime = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "quote_key_lifetime",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => 0,
+        };
+        let ak_per_verifier = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ak_per_verifier",
+        ) {
+            Ok(s) if !s.is_empty() => bool::from_str(&s.to_lowercase())?,
+            _ => false,
+        };
+
+        let x25519_key_wrap = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "x25519_key_wrap",
+        ) {
+            Ok(s) if !s.is_empty() => bool::from_str(&s.to_lowercase())?,
+            _ => false,
+        };
+
+        let ima_pcr =
+            match config_get(&conf_name, &conf, "cloud_agent", "ima_pcr") {
+                Ok(s) if !s.is_empty() => s.parse::<usize>()?,
+                _ => IMA_PCR,
+            };
+        if ima_pcr > MAX_PCR {
+            return Err(Error::Configuration(format!(
+                "ima_pcr must be a PCR between 0 and {}, got {}",
+                MAX_PCR, ima_pcr
+            )));
+        }
+
+        let revocation_webhook = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "revocation_webhook",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        let revocation_webhook_max_retries = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "revocation_webhook_max_retries",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u32>()?,
+            _ => REVOCATION_WEBHOOK_MAX_RETRIES,
+        };
+
+        let revocation_webhook_max_queued = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "revocation_webhook_max_queued",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<usize>()?,
+            _ => REVOCATION_WEBHOOK_MAX_QUEUED,
+        };
+        if revocation_webhook_max_queued == 0 {
+            return Err(Error::Configuration(
+                "revocation_webhook_max_queued must be at least 1"
+                    .to_string(),
+            ));
+        }
+
+        let ima_change_webhook = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ima_change_webhook",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        let ima_change_check_interval = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ima_change_check_interval",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => IMA_CHANGE_CHECK_INTERVAL,
+        };
+        if ima_change_check_interval == 0 {
+            return Err(Error::Configuration(
+                "ima_change_check_interval must be at least 1 second"
+                    .to_string(),
+            ));
+        }
+
+        let disk_space_check_interval = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "disk_space_check_interval",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => DISK_SPACE_CHECK_INTERVAL,
+        };
+
+        let disk_space_warn_percent = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "disk_space_warn_percent",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => DISK_SPACE_WARN_PERCENT,
+        };
+        if disk_space_warn_percent > 100 {
+            return Err(Error::Configuration(format!(
+                "disk_space_warn_percent must be between 0 and 100, got {}",
+                disk_space_warn_percent
+            )));
+        }
+
+        let agent_data_audit_interval = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "agent_data_audit_interval",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => AGENT_DATA_AUDIT_INTERVAL,
+        };
+
+        // Negative values disable the self-measurement
+        let measure_agent_pcr = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "measure_agent_pcr",
+        ) {
+            Ok(s) if !s.is_empty() => match s.parse::<i64>()? {
+                pcr if pcr < 0 => None,
+                pcr if pcr as usize > MAX_PCR
+                    || pcr as usize == ima_pcr
+                    || (TPM_DATA_PCR..=LAST_DRTM_PCR)
+                        .contains(&(pcr as usize)) =>
+                {
+                    return Err(Error::Configuration(format!(
+                        "measure_agent_pcr must be a PCR between 0 and {} other than ima_pcr and {} to {}, got {}",
+                        MAX_PCR, TPM_DATA_PCR, LAST_DRTM_PCR, pcr
+                    )));
+                }
+                pcr => Some(pcr as usize),
+            },
+            _ => None,
+        };
+
+        let runtime_inventory_pcr = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "runtime_inventory_pcr",
+        ) {
+            Ok(s) if !s.is_empty() => match s.parse::<i64>()? {
+                pcr if pcr < 0 => None,
+                pcr if pcr as usize > MAX_PCR
+                    || pcr as usize == ima_pcr
+                    || Some(pcr as usize) == measure_agent_pcr
+                    || (TPM_DATA_PCR..=LAST_DRTM_PCR)
+                        .contains(&(pcr as usize)) =>
+                {
+                    return Err(Error::Configuration(format!(
+                        "runtime_inventory_pcr must be a PCR between 0 and {} other than ima_pcr, measure_agent_pcr and {} to {}, got {}",
+                        MAX_PCR, TPM_DATA_PCR, LAST_DRTM_PCR, pcr
+                    )));
+                }
+                pcr => Some(pcr as usize),
+            },
+            _ => None,
+        };
+
+        let ak_policy_pcrs = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ak_policy_pcrs",
+        ) {
+            Ok(s) => parse_pcr_list("ak_policy_pcrs", &s)?,
+            Err(_) => Vec::new(),
+        };
+
+        let ak_policy_secret = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "ak_policy_secret",
+        ) {
+            Ok(secret) => bool::from_str(&secret.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        let drtm_log =
+            match config_get(&conf_name, &conf, "cloud_agent", "drtm_log") {
+                Ok(s) if !s.is_empty() => DrtmSource::try_from(s.as_str())?,
+                _ => DrtmSource::Auto,
+            };
+
+        let tee_evidence = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tee_evidence",
+        ) {
+            Ok(s) if !s.is_empty() => TeeSource::try_from(s.as_str())?,
+            _ => TeeSource::Disabled,
+        };
+
+        let hash_ek_version = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "hash_ek_version",
+        ) {
+            Ok(s) if !s.is_empty() => HashEkVersion::try_from(s.as_str())?,
+            _ => HashEkVersion::Legacy,
+        };
+
+        let reject_sw_tpm = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "reject_sw_tpm",
+        ) {
+            Ok(reject) => bool::from_str(&reject.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        let log_destination = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "log_destination",
+        ) {
+            Ok(s) if !s.is_empty() => LogDestination::try_from(s.as_str())?,
+            _ => LogDestination::Stderr,
+        };
+
+        let syslog_facility = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "syslog_facility",
+        ) {
+            Ok(s) if !s.is_empty() => SyslogFacility::try_from(s.as_str())?,
+            _ => SyslogFacility::default(),
+        };
+
+        let alert_destination = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "alert_destination",
+        ) {
+            Ok(s) if !s.is_empty() => {
+                Some(AlertDestination::try_from(s.as_str())?)
+            }
+            _ => None,
+        };
+
+        let maintenance_notify_url = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "maintenance_notify_url",
+        ) {
+            Ok(s)
+                if s.starts_with("http://") || s.starts_with("https://") =>
+            {
+                Some(s)
+            }
+            Ok(s) if !s.is_empty() => {
+                return Err(Error::Configuration(format!(
+                    "maintenance_notify_url must be an http(s):// URL, got {}",
+                    s
+                )));
+            }
+            _ => None,
+        };
+
+        let lock_keys_after_bootstrap = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "lock_keys_after_bootstrap",
+        ) {
+            Ok(lock) => bool::from_str(&lock.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        let lock_memory =
+            match config_get(&conf_name, &conf, "cloud_agent", "lock_memory")
+            {
+                Ok(lock) => bool::from_str(&lock.to_lowercase())?,
+                Err(_) => false,
+            };
+
+        let disable_core_dumps = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "disable_core_dumps",
+        ) {
+            Ok(disable) => bool::from_str(&disable.to_lowercase())?,
+            Err(_) => true,
+        };
+
+        let abort_on_panic = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "abort_on_panic",
+        ) {
+            Ok(abort) => bool::from_str(&abort.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        // Validated here so that a typo is reported with the other
+        // configuration errors rather than as a TPM connection failure
+        let tcti = match config_get(&conf_name, &conf, "cloud_agent", "tcti")
+        {
+            Ok(s) if !s.is_empty() => {
+                let _ = tpm::parse_tcti(&s)?;
+                Some(s)
+            }
+            _ => None,
+        };
+
+        let tpm_wait_timeout = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_wait_timeout",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => TPM_WAIT_TIMEOUT,
+        };
+
+        let tpm_operation_timeout = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_operation_timeout",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => TPM_OPERATION_TIMEOUT,
+        };
+        if tpm_operation_timeout == 0 {
+            return Err(Error::Configuration(
+                "tpm_operation_timeout must be at least 1 second".to_string(),
+            ));
+        }
+
+        let tpm_encrypt_sessions = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_encrypt_sessions",
+        ) {
+            Ok(s) => bool::from_str(&s.to_lowercase())?,
+            Err(_) => false,
+        };
+
+        let tpm_retry_attempts = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_retry_attempts",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u32>()?,
+            _ => TPM_RETRY_ATTEMPTS,
+        };
+        if tpm_retry_attempts == 0 {
+            return Err(Error::Configuration(
+                "tpm_retry_attempts must be at least 1".to_string(),
+            ));
+        }
+
+        let tpm_retry_delay = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "tpm_retry_delay",
+        ) {
+            Ok(s) if !s.is_empty() => s.parse::<u64>()?,
+            _ => TPM_RETRY_DELAY,
+        };
+
+        // An empty value disables the admin socket
+        let admin_socket = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "admin_socket",
+        ) {
+            Ok(s) if s.is_empty() => None,
+            Ok(s) => Some(s),
+            Err(_) => Some(
+                Path::new(&work_dir)
+                    .join(ADMIN_SOCKET)
+                    .display()
+                    .to_string(),
+            ),
+        };
+
+        // Overrides from the registrar are ignored unless a certificate to
+        // verify them is set
+        let config_overrides_cert = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "config_overrides_cert",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        // Configuration bundles of payloads are ignored unless a certificate
+        // to verify them is set
+        let config_bundle_cert = match config_get(
+            &conf_name,
+            &conf,
+            "cloud_agent",
+            "config_bundle_cert",
+        ) {
+            Ok(s) if !s.is_empty() => Some(s),
+            _ => None,
+        };
+
+        Ok(KeylimeConfig {
+            agent_ip,
+            agent_port,
+            registrar_ip,
+            registrar_port,
+            agent_uuid,
+            agent_contact_ip,
+            agent_contact_port,
+            agent_contact_interface,
+            registration_metadata,
+            agent_group,
+            agent_tags,
+            hash_alg,
+            enc_alg,
+            sign_alg,
+            hash_alg_fallback,
+            enc_alg_fallback,
+            sign_alg_fallback,
+            ecc_curve,
+            hmac_alg,
+            agent_data,
+            agent_data_path: agent_data_path.display().to_string(),
             run_revocation,
             revocation_cert,
             revocation_ip,
             revocation_port,
+            revocation_topics,
             secure_size,
             payload_script,
+            run_payload_script,
+            payload_file_dirs,
+            payload_encryption,
+            payload_gnupg_home,
+            nvram_indices,
+            payload_counter_nv_index,
             dec_payload_filename,
             key_filename,
             extract_payload_zip,
+            payload_max_extracted_size,
+            payload_max_entries,
+            payload_max_compression_ratio,
             keylime_ca_path,
             revocation_actions,
             revocation_actions_dir,
             allow_payload_revocation_actions,
+            revocation_webhook,
+            revocation_webhook_max_retries,
+            revocation_webhook_max_queued,
             work_dir,
             mtls_enabled,
+            mtls_cert_enrollment,
+            mtls_cert_enrollment_url,
+            enable_http2,
+            tls_session_resumption,
+            nk_pkcs11_uri,
+            nk_pkcs11_module,
+            nk_pkcs11_pin,
+            nk_seal,
+            nk_seal_pcrs,
+            cors_allowed_origins,
+            security_headers,
             enable_insecure_payload,
             run_as,
             tpm_ownerpassword,
             ek_handle,
+            ek_cert_path,
+            ek_cert_efi_var,
+            tpm_cert_store,
+            enable_iak_idevid,
+            iak_idevid_alg,
+            iak_handle,
+            idevid_handle,
+            iak_cert_path,
+            idevid_cert_path,
+            ima_ml_max_entries,
+            quote_nonce_replay_window,
+            quote_qualifying_data,
+            quote_cache_ttl,
+            verify_max_failures,
+            verify_lockout,
+            quote_key_lifetime,
+            ak_per_verifier,
+            x25519_key_wrap,
+            ima_pcr,
+            ima_change_webhook,
+            ima_change_check_interval,
+            disk_space_check_interval,
+            disk_space_warn_percent,
+            agent_data_audit_interval,
+            measure_agent_pcr,
+            runtime_inventory_pcr,
+            ak_policy_pcrs,
+            ak_policy_secret,
+            drtm_log,
+            tee_evidence,
+            hash_ek_version,
+            reject_sw_tpm,
+            lock_keys_after_bootstrap,
+            log_destination,
+            syslog_facility,
+            alert_destination,
+            maintenance_notify_url,
+            lock_memory,
+            disable_core_dumps,
+            abort_on_panic,
+            tcti,
+            tpm_wait_timeout,
+            tpm_operation_timeout,
+            tpm_encrypt_sessions,
+            tpm_retry_attempts,
+            tpm_retry_delay,
+            admin_socket,
+            config_overrides_cert,
+            config_bundle_cert,
         })
     }
 
+    // Curve of the AK, None when the AK is an RSA key
+    pub fn ak_ecc_curve(&self) -> Option<EccCurve> {
+        self.sign_alg.ak_curve(self.ecc_curve)
+    }
+
+    // Authorization policy of the AK, None when it is authorized with its
+    // empty password
+    pub(crate) fn ak_policy(&self) -> Result<Option<tpm::PcrPolicy>> {
+        tpm::PcrPolicy::new(
+            self.hash_alg.into(),
+            &self.ak_policy_pcrs,
+            self.ak_policy_secret,
+        )
+    }
+
     // Update function for the uuid if it is set to "hash_ek"
     pub fn set_ek_uuid(&mut self, ek_pub: Public) -> Result<()> {
         // Converting Public TPM key to PEM
@@ -569,6 +2054,17 @@ impl KeylimeConfig {
         self.agent_uuid = hex::encode(hash);
         Ok(())
     }
+
+    // Update function for the uuid if it is set to "hash_ek" and
+    // hash_ek_version is "v2". The public area must be the one of the RSA EK
+    // created from the default template, so that the UUID does not depend on
+    // the configured algorithms.
+    pub fn set_ek_uuid_v2(&mut self, rsa_ek_pub: Public) -> Result<()> {
+        let ek_buffer = PublicBuffer::try_from(rsa_ek_pub)?.marshall()?;
+        let hash = hash(MessageDigest::sha256(), &ek_buffer)?;
+        self.agent_uuid = hex::encode(hash);
+        Ok(())
+    }
 }
 
 // Default test configuration. This should match the defaults in keylime-agent.conf
@@ -590,9 +2086,18 @@ impl Default for KeylimeConfig {
             agent_uuid: "d432fbb3-d2f1-4a97-9ef7-75bd81c00000".to_string(),
             agent_contact_ip: Some("127.0.0.1".to_string()),
             agent_contact_port: Some(9002),
+            agent_contact_interface: None,
+            registration_metadata: Vec::new(),
+            agent_group: None,
+            agent_tags: Vec::new(),
             hash_alg: HashAlgorithm::Sha256,
             enc_alg: EncryptionAlgorithm::Rsa,
             sign_alg: SignAlgorithm::RsaSsa,
+            hash_alg_fallback: Vec::new(),
+            enc_alg_fallback: Vec::new(),
+            sign_alg_fallback: Vec::new(),
+            ecc_curve: EccCurve::P256,
+            hmac_alg: HmacAlgorithm::default(),
             agent_data: None,
             agent_data_path: Path::new(WORK_DIR)
                 .join(AGENT_DATA)
@@ -602,21 +2107,94 @@ impl Default for KeylimeConfig {
             revocation_cert: "default".to_string(),
             revocation_ip: "127.0.0.1".to_string(),
             revocation_port: "8992".to_string(),
+            revocation_topics: Vec::new(),
             secure_size: "1m".to_string(),
             payload_script: "autorun.sh".to_string(),
+            run_payload_script: true,
+            payload_file_dirs: Vec::new(),
+            payload_encryption: vec![PayloadEncryption::AesGcm],
+            payload_gnupg_home: None,
+            nvram_indices: Vec::new(),
+            payload_counter_nv_index: None,
             dec_payload_filename: "decrypted_payload".to_string(),
             key_filename: "derived_tci_key".to_string(),
             extract_payload_zip: true,
+            payload_max_extracted_size: PAYLOAD_MAX_EXTRACTED_SIZE,
+            payload_max_entries: PAYLOAD_MAX_ENTRIES,
+            payload_max_compression_ratio: PAYLOAD_MAX_COMPRESSION_RATIO,
             keylime_ca_path: DEFAULT_CA_PATH.to_string(),
             revocation_actions: "".to_string(),
             revocation_actions_dir: "/usr/libexec/keylime".to_string(),
             allow_payload_revocation_actions: true,
+            revocation_webhook: None,
+            revocation_webhook_max_retries: REVOCATION_WEBHOOK_MAX_RETRIES,
+            revocation_webhook_max_queued: REVOCATION_WEBHOOK_MAX_QUEUED,
             work_dir: WORK_DIR.to_string(),
             mtls_enabled: true,
+            mtls_cert_enrollment: EnrollmentMode::SelfSigned,
+            mtls_cert_enrollment_url: None,
+            enable_http2: true,
+            tls_session_resumption: false,
+            nk_pkcs11_uri: None,
+            nk_pkcs11_module: None,
+            nk_pkcs11_pin: None,
+            nk_seal: false,
+            nk_seal_pcrs: Vec::new(),
+            cors_allowed_origins: Vec::new(),
+            security_headers: false,
             enable_insecure_payload: false,
             run_as,
             tpm_ownerpassword: None,
             ek_handle: None,
+            ek_cert_path: None,
+            ek_cert_efi_var: None,
+            tpm_cert_store: None,
+            enable_iak_idevid: false,
+            iak_idevid_alg: EncryptionAlgorithm::Rsa,
+            iak_handle: None,
+            idevid_handle: None,
+            iak_cert_path: None,
+            idevid_cert_path: None,
+            ima_ml_max_entries: IMA_ML_MAX_ENTRIES,
+            quote_nonce_replay_window: 0,
+            quote_qualifying_data: Vec::new(),
+            quote_cache_ttl: 0,
+            verify_max_failures: VERIFY_MAX_FAILURES,
+            verify_lockout: VERIFY_LOCKOUT,
+            quote_key_lifetime: 0,
+            ak_per_verifier: false,
+            x25519_key_wrap: false,
+            ima_pcr: IMA_PCR,
+            ima_change_webhook: None,
+            ima_change_check_interval: IMA_CHANGE_CHECK_INTERVAL,
+            disk_space_check_interval: DISK_SPACE_CHECK_INTERVAL,
+            disk_space_warn_percent: DISK_SPACE_WARN_PERCENT,
+            agent_data_audit_interval: AGENT_DATA_AUDIT_INTERVAL,
+            measure_agent_pcr: None,
+            runtime_inventory_pcr: None,
+            ak_policy_pcrs: Vec::new(),
+            ak_policy_secret: false,
+            drtm_log: DrtmSource::Auto,
+            tee_evidence: TeeSource::Disabled,
+            hash_ek_version: HashEkVersion::Legacy,
+            reject_sw_tpm: false,
+            lock_keys_after_bootstrap: false,
+            log_destination: LogDestination::Stderr,
+            syslog_facility: SyslogFacility::default(),
+            alert_destination: None,
+            maintenance_notify_url: None,
+            lock_memory: false,
+            disable_core_dumps: true,
+            abort_on_panic: false,
+            tcti: None,
+            tpm_wait_timeout: TPM_WAIT_TIMEOUT,
+            tpm_operation_timeout: TPM_OPERATION_TIMEOUT,
+            tpm_encrypt_sessions: false,
+            tpm_retry_attempts: TPM_RETRY_ATTEMPTS,
+            tpm_retry_delay: TPM_RETRY_DELAY,
+            admin_socket: None,
+            config_overrides_cert: None,
+            config_bundle_cert: None,
         }
     }
 }
@@ -648,6 +2226,90 @@ fn get_uuid(agent_uuid_config: &str) -> String {
     }
 }
 
+// Parses a hexadecimal NV index, None if it is not in the NV index range
+pub(crate) fn parse_nv_index(index: &str) -> Option<u32> {
+    let index =
+        u32::from_str_radix(index.trim_start_matches("0x"), 16).ok()?;
+    match index >> 24 {
+        0x01 => Some(index),
+        _ => None,
+    }
+}
+
+// Parses a comma separated list of "<API version>:<composition>", e.g.
+// "v2.0:nonce, v2.1:nonce-pubkey-hash", for API versions served
+fn parse_qualifying_data(
+    value: &str,
+) -> Result<Vec<(APIVersion, QualifyingData)>> {
+    let mut compositions: Vec<(APIVersion, QualifyingData)> = Vec::new();
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (version, composition) = entry
+            .split_once(':')
+            .and_then(|(version, composition)| {
+                let version = APIVersion::of_path(version.trim())?;
+                Some((version, composition.trim()))
+            })
+            .ok_or_else(|| {
+                Error::Configuration(format!(
+                    "quote_qualifying_data must be a comma separated list of <API version>:<composition>, got {}",
+                    entry
+                ))
+            })?;
+        if !API_VERSIONS.iter().any(|api| api.version == version) {
+            return Err(Error::Configuration(format!(
+                "quote_qualifying_data sets API {} which is not served",
+                version
+            )));
+        }
+        if compositions.iter().any(|(set, _)| *set == version) {
+            return Err(Error::Configuration(format!(
+                "quote_qualifying_data sets API {} more than once",
+                version
+            )));
+        }
+        compositions.push((version, QualifyingData::try_from(composition)?));
+    }
+    Ok(compositions)
+}
+
+// Parses a comma separated list of PCRs, without duplicates
+fn parse_pcr_list(option: &str, value: &str) -> Result<Vec<usize>> {
+    let mut pcrs: Vec<usize> = Vec::new();
+    for pcr in value.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let pcr = match pcr.parse::<usize>() {
+            Ok(pcr) if pcr <= MAX_PCR => pcr,
+            _ => {
+                return Err(Error::Configuration(format!(
+                    "{} must be a comma separated list of PCRs between 0 and {}, got {}",
+                    option, MAX_PCR, value
+                )))
+            }
+        };
+        if !pcrs.contains(&pcr) {
+            pcrs.push(pcr);
+        }
+    }
+    Ok(pcrs)
+}
+
+// Parses a comma separated list of algorithms, empty when the option is not
+// set
+fn algorithm_list<T>(value: Result<String>) -> Result<Vec<T>>
+where
+    T: for<'a> TryFrom<&'a str, Error = AlgorithmError>,
+{
+    let value = match value {
+        Ok(value) => value,
+        Err(_) => return Ok(Vec::new()),
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|alg| !alg.is_empty())
+        .map(|alg| T::try_from(alg).map_err(Error::from))
+        .collect()
+}
+
 /*
  * Return: Returns the configuration file provided in the environment variable
  * KEYLIME_CONFIG or defaults to /etc/keylime-agent.conf
@@ -655,7 +2317,7 @@ fn get_uuid(agent_uuid_config: &str) -> String {
  * Example call:
  * let config = config_file_get();
  */
-fn config_file_get() -> String {
+pub(crate) fn config_file_get() -> String {
     match env::var("KEYLIME_CONFIG") {
         Ok(cfg) => {
             // The variable length must be larger than 0 to accept
@@ -781,6 +2443,19 @@ fn config_get_env(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_api_version_of_path() {
+        assert_eq!(
+            APIVersion::of_path("/v2.1/quotes/integrity"),
+            Some(APIVersion::V2_1)
+        );
+        let v2_0 = APIVersion::of_path("/v2.0/keys/pubkey").unwrap(); //#[allow_ci]
+        assert!(v2_0 < APIVersion::V2_1);
+        assert_eq!(v2_0.to_string(), API_VERSION);
+        assert_eq!(APIVersion::of_path("/version"), None);
+        assert_eq!(APIVersion::of_path("/vx.1/keys"), None);
+    }
+
     #[test]
     fn test_config_get_parameters_exist() {
         //let result = config_get("keylime-agent.conf", "general", "cloudagent_port");
@@ -805,6 +2480,155 @@ mod tests {
         env::set_var("KEYLIME_CONFIG", conf_orig);
     }
 
+    #[test]
+    fn test_hash_ek_version() {
+        assert_eq!(
+            HashEkVersion::try_from("legacy").unwrap(), //#[allow_ci]
+            HashEkVersion::Legacy
+        );
+        assert_eq!(
+            HashEkVersion::try_from("v2").unwrap(), //#[allow_ci]
+            HashEkVersion::V2
+        );
+        assert!(HashEkVersion::try_from("v3").is_err());
+    }
+
+    #[test]
+    fn test_ak_ecc_curve() {
+        let mut config = KeylimeConfig {
+            ecc_curve: EccCurve::P384,
+            ..KeylimeConfig::default()
+        };
+        assert_eq!(config.ak_ecc_curve(), None);
+        config.sign_alg = SignAlgorithm::EcDsa;
+        assert_eq!(config.ak_ecc_curve(), Some(EccCurve::P384));
+        config.sign_alg = SignAlgorithm::EcSchnorr;
+        assert_eq!(config.ak_ecc_curve(), Some(EccCurve::P384));
+        assert!(EccCurve::try_from("p192").is_err());
+        #[cfg(feature = "sm")]
+        {
+            config.sign_alg = SignAlgorithm::Sm2;
+            assert_eq!(config.ak_ecc_curve(), Some(EccCurve::Sm2P256));
+        }
+    }
+
+    fn agent_data(hash_alg: HashAlgorithm, ak: u8) -> AgentData {
+        AgentData {
+            ak_hash_alg: hash_alg,
+            ak_sign_alg: SignAlgorithm::RsaSsa,
+            ak_ecc_curve: None,
+            ak_negotiated: false,
+            ak_public: vec![ak],
+            ak_private: vec![ak],
+            nk_pub: Vec::new(),
+            nk_priv: Vec::new(),
+            nk_sealed: None,
+            mtls_cert: None,
+            mtls_cert_chain: Vec::new(),
+            other_aks: Vec::new(),
+            age_identity: None,
+            hmac_alg: None,
+        }
+    }
+
+    #[test]
+    fn test_hmac_alg() {
+        let mut data = agent_data(HashAlgorithm::Sha256, 1);
+        assert_eq!(data.hmac_alg().unwrap(), None); //#[allow_ci]
+        data.set_hmac_alg(HmacAlgorithm::Sha512);
+        let mut created = agent_data(HashAlgorithm::Sha256, 2);
+        created.keep_hmac_alg(&data);
+        assert_eq!(
+            created.hmac_alg().unwrap(), //#[allow_ci]
+            Some(HmacAlgorithm::Sha512)
+        );
+
+        // Recorded by an agent supporting other algorithms
+        data.hmac_alg = Some("sha3_512".to_string());
+        assert!(data.hmac_alg().is_err());
+    }
+
+    #[test]
+    fn test_select_ak() {
+        let rsassa = SignAlgorithm::RsaSsa;
+        let mut data = agent_data(HashAlgorithm::Sha256, 1);
+        assert!(data.select_ak(HashAlgorithm::Sha256, rsassa, None));
+        assert!(!data.select_ak(HashAlgorithm::Sha384, rsassa, None));
+
+        // Switching to SHA-384 and back to SHA-256
+        let mut sha384 = agent_data(HashAlgorithm::Sha384, 2);
+        sha384.keep_aks(&data);
+        assert_eq!(sha384.other_aks.len(), 1);
+        let mut sha256 = sha384.clone();
+        assert!(sha256.select_ak(HashAlgorithm::Sha256, rsassa, None));
+        assert_eq!(sha256.ak_public, vec![1]);
+        assert_eq!(sha256.other_aks[0].public, vec![2]);
+
+        // An AK created again replaces the stored one
+        data = agent_data(HashAlgorithm::Sha256, 3);
+        data.keep_aks(&sha256);
+        assert_eq!(data.other_aks.len(), 1);
+        assert_eq!(data.other_aks[0].hash_alg, HashAlgorithm::Sha384);
+        assert!(!data.select_ak(
+            HashAlgorithm::Sha384,
+            SignAlgorithm::RsaPss,
+            None
+        ));
+    }
+
+    #[test]
+    fn test_parse_pcr_list() {
+        assert_eq!(
+            parse_pcr_list("ak_policy_pcrs", "0, 2,4,7,2").unwrap(), //#[allow_ci]
+            vec![0, 2, 4, 7]
+        );
+        assert!(parse_pcr_list("ak_policy_pcrs", "").unwrap().is_empty()); //#[allow_ci]
+        assert!(parse_pcr_list("ak_policy_pcrs", "24").is_err());
+        assert!(parse_pcr_list("ak_policy_pcrs", "boot").is_err());
+    }
+
+    #[test]
+    fn test_parse_qualifying_data() {
+        assert_eq!(
+            parse_qualifying_data("v2.0:nonce, v2.1:nonce-pubkey-hash")
+                .unwrap(), //#[allow_ci]
+            vec![
+                (APIVersion::V2_0, QualifyingData::Nonce),
+                (APIVersion::V2_1, QualifyingData::NoncePubkeyHash)
+            ]
+        );
+        assert!(parse_qualifying_data("").unwrap().is_empty()); //#[allow_ci]
+        assert!(parse_qualifying_data("v2.1").is_err());
+        assert!(parse_qualifying_data("v2.1:pubkey").is_err());
+        assert!(parse_qualifying_data("v1.0:nonce").is_err());
+        assert!(parse_qualifying_data("v2.1:nonce,v2.1:nonce").is_err());
+    }
+
+    #[test]
+    fn test_parse_nv_index() {
+        assert_eq!(parse_nv_index("0x1c00002"), Some(0x1c00002));
+        assert_eq!(parse_nv_index("1c0000a"), Some(0x1c0000a));
+        assert_eq!(parse_nv_index("0x81010001"), None);
+        assert_eq!(parse_nv_index("0x1z"), None);
+    }
+
+    #[test]
+    fn test_algorithm_list() {
+        assert_eq!(
+            algorithm_list::<HashAlgorithm>(Ok("sha384, sha256,".into()))
+                .unwrap(), //#[allow_ci]
+            vec![HashAlgorithm::Sha384, HashAlgorithm::Sha256]
+        );
+        assert_eq!(
+            algorithm_list::<SignAlgorithm>(Err(Error::Configuration(
+                "unset".into()
+            )))
+            .unwrap(), //#[allow_ci]
+            Vec::new()
+        );
+        assert!(algorithm_list::<HashAlgorithm>(Ok("md5".into())).is_err());
+    }
+
     #[test]
     fn test_get_uuid() {
         assert_eq!(get_uuid("openstack"), "openstack");
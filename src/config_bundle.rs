@@ -0,0 +1,380 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Configuration bundles delivered with tenant payloads.
+//
+// A payload may carry configuration updates for the agent in a
+// 'config_bundle.json' file at the root of the archive: a message signed by
+// the operator like revocation messages, only accepted when the signature
+// verifies against the certificate set in 'config_bundle_cert'. The message
+// holds a serial and the settings to change, with the names of the
+// configuration file. As the payload is encrypted under U and V, the bundle
+// is only readable once the verifier attested the agent.
+//
+// A bundle with settings that cannot be changed this way is rejected as a
+// whole, as is a bundle whose serial is below the one of the installed
+// bundle, so that an older bundle cannot be replayed, or equal to it with
+// other settings, so that each serial names a single bundle. The agent has no
+// live reload of its configuration: the verified bundle is stored in
+// keylime_dir and applied on top of the configuration file from the next
+// start, after the overrides of the registrar.
+
+use crate::{
+    common::KeylimeConfig,
+    crypto,
+    error::{Error, Result},
+    registrar_agent::SignedMessage,
+};
+use log::*;
+use openssl::sha::sha256;
+use serde::Deserialize;
+use std::{fs, io::Write, path::Path};
+
+pub(crate) static BUNDLE_FILE: &str = "config_bundle.json";
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Settings {
+    revocation_webhook: Option<String>,
+    revocation_webhook_max_retries: Option<u32>,
+    revocation_actions: Option<String>,
+    ima_change_webhook: Option<String>,
+    ima_change_check_interval: Option<u64>,
+    disk_space_check_interval: Option<u64>,
+    agent_data_audit_interval: Option<u64>,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Bundle {
+    serial: u64,
+    settings: Settings,
+}
+
+fn check_url(option: &str, url: &Option<String>) -> Result<()> {
+    match url {
+        Some(url)
+            if !url.starts_with("http://")
+                && !url.starts_with("https://") =>
+        {
+            Err(Error::Configuration(format!(
+                "{} must be an http(s):// URL, got {}",
+                option, url
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl Settings {
+    // The checks of KeylimeConfig::build for the same settings
+    fn check(&self) -> Result<()> {
+        check_url("revocation_webhook", &self.revocation_webhook)?;
+        check_url("ima_change_webhook", &self.ima_change_webhook)?;
+        if self.ima_change_check_interval == Some(0) {
+            return Err(Error::Configuration(
+                "ima_change_check_interval must be at least 1 second"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    // Returns the names of the changed settings
+    fn apply(self, config: &mut KeylimeConfig) -> Vec<&'static str> {
+        let mut applied = Vec::new();
+        if let Some(webhook) = self.revocation_webhook {
+            config.revocation_webhook = Some(webhook);
+            applied.push("revocation_webhook");
+        }
+        if let Some(retries) = self.revocation_webhook_max_retries {
+            config.revocation_webhook_max_retries = retries;
+            applied.push("revocation_webhook_max_retries");
+        }
+        if let Some(actions) = self.revocation_actions {
+            config.revocation_actions = actions;
+            applied.push("revocation_actions");
+        }
+        if let Some(webhook) = self.ima_change_webhook {
+            config.ima_change_webhook = Some(webhook);
+            applied.push("ima_change_webhook");
+        }
+        if let Some(interval) = self.ima_change_check_interval {
+            config.ima_change_check_interval = interval;
+            applied.push("ima_change_check_interval");
+        }
+        if let Some(interval) = self.disk_space_check_interval {
+            config.disk_space_check_interval = interval;
+            applied.push("disk_space_check_interval");
+        }
+        if let Some(interval) = self.agent_data_audit_interval {
+            config.agent_data_audit_interval = interval;
+            applied.push("agent_data_audit_interval");
+        }
+        applied
+    }
+}
+
+fn verify(signed: &SignedMessage, cert_path: &Path) -> Result<Bundle> {
+    let key = crypto::load_x509(cert_path)?.public_key()?;
+    if !crypto::asym_verify(&key, &signed.msg, &signed.signature)? {
+        return Err(Error::Other("invalid signature".to_string()));
+    }
+    let bundle: Bundle = serde_json::from_str(&signed.msg)?;
+    bundle.settings.check()?;
+    Ok(bundle)
+}
+
+fn read(path: &Path) -> Result<SignedMessage> {
+    Ok(serde_json::from_slice(&fs::read(path)?)?)
+}
+
+fn cert_path(config: &KeylimeConfig) -> Result<&Path> {
+    config
+        .config_bundle_cert
+        .as_deref()
+        .map(Path::new)
+        .ok_or_else(|| {
+            Error::Configuration("config_bundle_cert is not set".to_string())
+        })
+}
+
+/*
+ * Input: bundle of the payload, directory the installed bundle is stored in
+ *        and agent configuration
+ * Return: Result wrap the serial of the installed bundle
+ *
+ * The bundle is stored as signed, so that it is verified again at every
+ * start. Receiving the installed bundle again, e.g. with the payload sent on
+ * every start, leaves it in place. Bundles are compared by the digest of
+ * their message, the signature may differ for the same one.
+ */
+fn install_in(
+    path: &Path,
+    dir: &Path,
+    config: &KeylimeConfig,
+) -> Result<u64> {
+    let cert_path = cert_path(config)?;
+    let signed = read(path)?;
+    let bundle = verify(&signed, cert_path)?;
+
+    let installed_path = dir.join(BUNDLE_FILE);
+    if let Ok(installed) = read(&installed_path) {
+        match verify(&installed, cert_path) {
+            Ok(installed_bundle)
+                if installed_bundle.serial > bundle.serial =>
+            {
+                return Err(Error::Other(format!(
+                    "serial {} is below the one of the installed bundle, {}",
+                    bundle.serial, installed_bundle.serial
+                )));
+            }
+            Ok(installed_bundle)
+                if installed_bundle.serial == bundle.serial
+                    && sha256(installed.msg.as_bytes())
+                        != sha256(signed.msg.as_bytes()) =>
+            {
+                return Err(Error::Other(format!(
+                    "serial {} is the one of the installed bundle, with other settings",
+                    bundle.serial
+                )));
+            }
+            _ => (),
+        }
+    }
+
+    // Written to a temporary file first, so that a crash never leaves a
+    // partial bundle
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(&serde_json::to_vec(&signed)?)?;
+    file.as_file().sync_all()?;
+    let _ = file.persist(&installed_path)?;
+    Ok(bundle.serial)
+}
+
+/*
+ * Input: bundle file of the payload and agent configuration
+ *
+ * Verifies and installs the bundle, to be applied from the next start of
+ * the agent. A bundle that cannot be verified is ignored, the installed one
+ * is kept.
+ */
+pub(crate) fn install(path: &Path, config: &KeylimeConfig) {
+    match install_in(path, Path::new(&config.work_dir), config) {
+        Ok(serial) => info!(
+            "Installed configuration bundle {}, it applies from the next start of the agent",
+            serial
+        ),
+        Err(e) => error!(
+            "Ignoring the configuration bundle of the payload: {}",
+            e
+        ),
+    }
+}
+
+/*
+ * Input: agent configuration
+ *
+ * Applies the bundle installed by a previous payload, if any. A bundle that
+ * no longer verifies, e.g. once config_bundle_cert changed, is ignored.
+ */
+pub(crate) fn apply_installed(config: &mut KeylimeConfig) {
+    let path = Path::new(&config.work_dir).join(BUNDLE_FILE);
+    if !path.exists() {
+        return;
+    }
+    let bundle = cert_path(config)
+        .and_then(|cert_path| verify(&read(&path)?, cert_path));
+    match bundle {
+        Ok(bundle) => {
+            let serial = bundle.serial;
+            let applied = bundle.settings.apply(config);
+            info!(
+                "Applied configuration bundle {}: {}",
+                serial,
+                applied.join(", ")
+            );
+        }
+        Err(e) => warn!(
+            "Ignoring the configuration bundle installed in {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        rsa::Padding,
+        sign::{RsaPssSaltlen, Signer},
+    };
+
+    fn operator_key(cert_path: &Path) -> PKey<Private> {
+        let (_, priv_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&priv_key, "operator").unwrap(); //#[allow_ci]
+        fs::write(cert_path, cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        priv_key
+    }
+
+    fn sign(msg: &str, key: &PKey<Private>) -> SignedMessage {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap(); //#[allow_ci]
+        signer.set_rsa_padding(Padding::PKCS1_PSS).unwrap(); //#[allow_ci]
+        signer.set_rsa_mgf1_md(MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        signer
+            .set_rsa_pss_saltlen(RsaPssSaltlen::MAXIMUM_LENGTH)
+            .unwrap(); //#[allow_ci]
+        signer.update(msg.as_bytes()).unwrap(); //#[allow_ci]
+        let signature = signer.sign_to_vec().unwrap(); //#[allow_ci]
+        SignedMessage {
+            msg: msg.to_string(),
+            signature: base64::encode(signature),
+        }
+    }
+
+    fn write_bundle(
+        dir: &Path,
+        signed: &SignedMessage,
+    ) -> std::path::PathBuf {
+        let path = dir.join("payload_bundle.json");
+        fs::write(&path, serde_json::to_vec(signed).unwrap()).unwrap(); //#[allow_ci]
+        path
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cert_path = dir.path().join("operator.crt");
+        let key = operator_key(&cert_path);
+
+        let mut signed = sign(
+            r#"{"serial": 1, "settings": {"ima_change_check_interval": 30}}"#,
+            &key,
+        );
+        let bundle = verify(&signed, &cert_path).unwrap(); //#[allow_ci]
+        assert_eq!(bundle.settings.ima_change_check_interval, Some(30));
+
+        signed.msg =
+            r#"{"serial": 1, "settings": {"ima_change_check_interval": 1}}"#
+                .to_string();
+        assert!(verify(&signed, &cert_path).is_err());
+
+        // Only the listed settings can be changed, with valid values
+        let signed =
+            sign(r#"{"serial": 1, "settings": {"run_as": "root"}}"#, &key);
+        assert!(verify(&signed, &cert_path).is_err());
+        let signed = sign(
+            r#"{"serial": 1, "settings": {"revocation_webhook": "ftp://hook"}}"#,
+            &key,
+        );
+        assert!(verify(&signed, &cert_path).is_err());
+    }
+
+    #[test]
+    fn test_install() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cert_path = dir.path().join("operator.crt");
+        let key = operator_key(&cert_path);
+        let mut config = KeylimeConfig {
+            work_dir: dir.path().display().to_string(),
+            ..KeylimeConfig::default()
+        };
+
+        let newer = write_bundle(
+            dir.path(),
+            &sign(
+                r#"{"serial": 2, "settings": {"revocation_webhook": "https://hook.example"}}"#,
+                &key,
+            ),
+        );
+        // Refused without a certificate
+        assert!(install_in(&newer, dir.path(), &config).is_err());
+
+        config.config_bundle_cert = Some(cert_path.display().to_string());
+        assert_eq!(install_in(&newer, dir.path(), &config).unwrap(), 2); //#[allow_ci]
+
+        // Installing the same bundle again keeps it
+        assert_eq!(install_in(&newer, dir.path(), &config).unwrap(), 2); //#[allow_ci]
+
+        let older = write_bundle(
+            dir.path(),
+            &sign(
+                r#"{"serial": 1, "settings": {"agent_data_audit_interval": 0}}"#,
+                &key,
+            ),
+        );
+        assert!(install_in(&older, dir.path(), &config).is_err());
+
+        // So is a bundle with the same serial and other settings, while the
+        // same settings signed again are accepted
+        let replaced = write_bundle(
+            dir.path(),
+            &sign(
+                r#"{"serial": 2, "settings": {"agent_data_audit_interval": 0}}"#,
+                &key,
+            ),
+        );
+        assert!(install_in(&replaced, dir.path(), &config).is_err());
+        let resigned = write_bundle(
+            dir.path(),
+            &sign(
+                r#"{"serial": 2, "settings": {"revocation_webhook": "https://hook.example"}}"#,
+                &key,
+            ),
+        );
+        assert_eq!(install_in(&resigned, dir.path(), &config).unwrap(), 2); //#[allow_ci]
+
+        apply_installed(&mut config);
+        assert_eq!(
+            config.revocation_webhook.as_deref(),
+            Some("https://hook.example")
+        );
+        assert_eq!(
+            config.agent_data_audit_interval,
+            KeylimeConfig::default().agent_data_audit_interval
+        );
+    }
+}
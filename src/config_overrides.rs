@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Configuration overrides distributed by the registrar.
+//
+// Operators can hand out a small set of settings through the registrar
+// instead of editing the configuration of every agent: the revocation
+// notifier of the verifier and the topics received from it, and the URL the
+// verifier is notified of maintenance windows at. The agent is polled by the
+// verifier, it has no attestation interval to hand out. The registrar sends
+// them in the registration response, signed like revocation messages, and
+// they are only applied when the signature verifies against the certificate
+// set in 'config_overrides_cert'. Settings are named as in the configuration
+// file. A message with settings that cannot be overridden is rejected as a
+// whole.
+//
+// The signed message also names the agent it is made for and the time, in
+// seconds since the Unix epoch, after which it is no longer accepted, e.g.
+//   {"agent_uuid": "d432fbb3-...", "not_after": 1700000000,
+//    "receive_revocation_port": "8993"}
+// so that the overrides of an agent cannot be replayed to another one, nor
+// after the operator changed them.
+
+use crate::{
+    common::KeylimeConfig,
+    crypto,
+    error::{Error, Result},
+    registrar_agent::SignedMessage,
+};
+use log::*;
+use serde::Deserialize;
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigOverrides {
+    agent_uuid: String,
+    // Seconds since the Unix epoch
+    not_after: u64,
+    receive_revocation_ip: Option<String>,
+    receive_revocation_port: Option<String>,
+    // Comma separated, as in the configuration file
+    revocation_topics: Option<String>,
+    maintenance_notify_url: Option<String>,
+}
+
+impl ConfigOverrides {
+    // Returns the names of the overridden settings
+    fn apply(self, config: &mut KeylimeConfig) -> Vec<&'static str> {
+        let mut applied = Vec::new();
+        if let Some(ip) = self.receive_revocation_ip {
+            config.revocation_ip = ip;
+            applied.push("receive_revocation_ip");
+        }
+        if let Some(port) = self.receive_revocation_port {
+            config.revocation_port = port;
+            applied.push("receive_revocation_port");
+        }
+        if let Some(topics) = self.revocation_topics {
+            config.revocation_topics = topics
+                .split(',')
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .collect();
+            applied.push("revocation_topics");
+        }
+        if let Some(url) = self.maintenance_notify_url {
+            config.maintenance_notify_url = Some(url);
+            applied.push("maintenance_notify_url");
+        }
+        applied
+    }
+}
+
+/*
+ * Input: overrides received from the registrar, certificate verifying them,
+ *        UUID of the agent and current time in seconds since the Unix epoch
+ * Return: Result wrap the verified overrides
+ */
+fn verify(
+    signed: &SignedMessage,
+    cert_path: &Path,
+    agent_uuid: &str,
+    now: u64,
+) -> Result<ConfigOverrides> {
+    let key = crypto::load_x509(cert_path)?.public_key()?;
+    if !crypto::asym_verify(&key, &signed.msg, &signed.signature)? {
+        return Err(Error::Other("invalid signature".to_string()));
+    }
+    let overrides: ConfigOverrides = serde_json::from_str(&signed.msg)?;
+    if overrides.agent_uuid != agent_uuid {
+        return Err(Error::Other(format!(
+            "made for agent {}",
+            overrides.agent_uuid
+        )));
+    }
+    if now > overrides.not_after {
+        return Err(Error::Other(format!(
+            "expired at {}",
+            overrides.not_after
+        )));
+    }
+    Ok(overrides)
+}
+
+/*
+ * Input: overrides received from the registrar and agent configuration
+ *
+ * Applies the overrides to the configuration once verified. Overrides that
+ * cannot be verified are ignored, the local configuration is kept.
+ */
+pub(crate) fn apply(signed: &SignedMessage, config: &mut KeylimeConfig) {
+    let cert_path = match &config.config_overrides_cert {
+        Some(path) => path.clone(),
+        None => {
+            warn!("Ignoring the config overrides of the registrar, config_overrides_cert is not set");
+            return;
+        }
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    match verify(signed, Path::new(&cert_path), &config.agent_uuid, now) {
+        Ok(overrides) => {
+            let applied = overrides.apply(config);
+            info!(
+                "Applied config overrides from the registrar: {}",
+                applied.join(", ")
+            );
+        }
+        Err(e) => {
+            warn!("Ignoring the config overrides of the registrar: {}", e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        hash::MessageDigest,
+        rsa::Padding,
+        sign::{RsaPssSaltlen, Signer},
+    };
+    use std::fs;
+
+    fn sign(msg: &str, cert_path: &Path) -> SignedMessage {
+        let (_, priv_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&priv_key, "uuid").unwrap(); //#[allow_ci]
+        fs::write(cert_path, cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+
+        let mut signer =
+            Signer::new(MessageDigest::sha256(), &priv_key).unwrap(); //#[allow_ci]
+        signer.set_rsa_padding(Padding::PKCS1_PSS).unwrap(); //#[allow_ci]
+        signer.set_rsa_mgf1_md(MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        signer
+            .set_rsa_pss_saltlen(RsaPssSaltlen::MAXIMUM_LENGTH)
+            .unwrap(); //#[allow_ci]
+        signer.update(msg.as_bytes()).unwrap(); //#[allow_ci]
+        let signature = signer.sign_to_vec().unwrap(); //#[allow_ci]
+        SignedMessage {
+            msg: msg.to_string(),
+            signature: base64::encode(signature),
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cert_path = dir.path().join("overrides.crt");
+        let mut config = KeylimeConfig::default();
+        let signed = sign(
+            &format!(
+                r#"{{"agent_uuid": "{}", "not_after": {}, "receive_revocation_port": "8993", "revocation_topics": "revocation, agents"}}"#,
+                config.agent_uuid,
+                u64::MAX
+            ),
+            &cert_path,
+        );
+
+        // Ignored without a certificate
+        apply(&signed, &mut config);
+        assert_eq!(config.revocation_port, "8992");
+
+        config.config_overrides_cert = Some(cert_path.display().to_string());
+        apply(&signed, &mut config);
+        assert_eq!(config.revocation_port, "8993");
+        assert_eq!(config.revocation_topics, vec!["revocation", "agents"]);
+        assert_eq!(config.revocation_ip, "127.0.0.1");
+    }
+
+    #[test]
+    fn test_verify() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let cert_path = dir.path().join("overrides.crt");
+
+        let msg = r#"{"agent_uuid": "uuid", "not_after": 1000, "receive_revocation_ip": "10.0.0.1"}"#;
+        let mut signed = sign(msg, &cert_path);
+        let overrides = verify(&signed, &cert_path, "uuid", 1000).unwrap(); //#[allow_ci]
+        assert_eq!(
+            overrides.receive_revocation_ip,
+            Some("10.0.0.1".to_string())
+        );
+
+        // Bound to the agent and to the validity period
+        assert!(verify(&signed, &cert_path, "other", 1000).is_err());
+        assert!(verify(&signed, &cert_path, "uuid", 1001).is_err());
+
+        signed.msg = msg.replace("10.0.0.1", "10.0.0.2");
+        assert!(verify(&signed, &cert_path, "uuid", 1000).is_err());
+
+        // Only the listed settings can be overridden
+        let signed = sign(
+            r#"{"agent_uuid": "uuid", "not_after": 1000, "run_as": "root"}"#,
+            &cert_path,
+        );
+        assert!(verify(&signed, &cert_path, "uuid", 1000).is_err());
+        let signed = sign(
+            r#"{"agent_uuid": "uuid", "not_after": 1000, "revocation_actions": "local_action_rm_ssh.py"}"#,
+            &cert_path,
+        );
+        assert!(verify(&signed, &cert_path, "uuid", 1000).is_err());
+
+        // The agent and the validity period are required
+        let signed =
+            sign(r#"{"receive_revocation_ip": "10.0.0.1"}"#, &cert_path);
+        assert!(verify(&signed, &cert_path, "uuid", 1000).is_err());
+    }
+}
@@ -0,0 +1,384 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Versioning of the configuration file layout.
+//
+// keylime-agent.conf sets 'config_version' in [cloud_agent]. Older files are
+// translated to the current layout when the agent loads them, and a warning
+// lists every option that was renamed or dropped:
+//  - files without 'config_version' were written for version 1, which
+//    accepted the 'listen_notfications' typo of the Python Keylime
+//  - files with an [agent] section were written for the Python agent. Their
+//    options are laid over the defaults shipped in keylime-agent.conf, as the
+//    Python agent does not know about most options of this one
+// `keylime_agent upgrade-config <path>` writes the translated file, without
+// its comments, so that the warnings go away.
+
+use crate::{
+    error::{Error, Result},
+    permissions,
+};
+use ini::Ini;
+use log::*;
+use std::{fmt, path::Path};
+
+pub(crate) const CONFIG_VERSION: u64 = 2;
+
+pub(crate) static DEFAULT_CONFIG: &str =
+    include_str!("../keylime-agent.conf");
+static PYTHON_SECTION: &str = "agent";
+
+// Options of the Python agent whose name differs here, with their section
+// and name in the current layout. The others keep their name and go to
+// [cloud_agent].
+static PYTHON_RENAMES: &[(&str, &str, &str)] = &[
+    ("ip", "cloud_agent", "cloudagent_ip"),
+    ("port", "cloud_agent", "cloudagent_port"),
+    ("contact_ip", "cloud_agent", "agent_contact_ip"),
+    ("contact_port", "cloud_agent", "agent_contact_port"),
+    ("uuid", "cloud_agent", "agent_uuid"),
+    ("enable_agent_mtls", "cloud_agent", "mtls_cert_enabled"),
+    ("trusted_client_ca", "cloud_agent", "keylime_ca"),
+    (
+        "enable_revocation_notifications",
+        "cloud_agent",
+        "listen_notifications",
+    ),
+    (
+        "revocation_notification_ip",
+        "general",
+        "receive_revocation_ip",
+    ),
+    (
+        "revocation_notification_port",
+        "general",
+        "receive_revocation_port",
+    ),
+];
+
+// Options of the Python agent with no equivalent here, the agent generates
+// its own mTLS key and certificate and reads the logs from fixed paths
+static PYTHON_UNSUPPORTED: &[&str] = &[
+    "server_key",
+    "server_key_password",
+    "server_cert",
+    "measure_payload_pcr",
+    "exponential_backoff",
+    "ima_ml_path",
+    "measuredboot_ml_path",
+];
+
+// Options renamed since version 1, as (section, old name, new name)
+static V1_RENAMES: &[(&str, &str, &str)] =
+    &[("cloud_agent", "listen_notfications", "listen_notifications")];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Layout {
+    Python,
+    Version(u64),
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layout::Python => write!(f, "the Python agent"),
+            Layout::Version(version) => write!(f, "version {}", version),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Rename {
+    pub from: (String, String),
+    pub to: (String, String),
+}
+
+impl fmt::Display for Rename {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {} is now [{}] {}",
+            self.from.0, self.from.1, self.to.0, self.to.1
+        )
+    }
+}
+
+// Not Debug, as Ini is not
+pub(crate) struct Upgrade {
+    pub conf: Ini,
+    pub from: Layout,
+    pub renamed: Vec<Rename>,
+    // Options dropped as they are not supported, as "[section] name"
+    pub dropped: Vec<String>,
+}
+
+pub(crate) fn layout(conf: &Ini) -> Result<Layout> {
+    if conf.section(Some(PYTHON_SECTION)).is_some()
+        && conf.section(Some("cloud_agent")).is_none()
+    {
+        return Ok(Layout::Python);
+    }
+    match conf.get_from(Some("cloud_agent"), "config_version") {
+        Some(version) => match version.trim().parse::<u64>() {
+            Ok(number) if number > 0 => Ok(Layout::Version(number)),
+            _ => Err(Error::Configuration(format!(
+                "config_version must be a positive integer, got {}",
+                version
+            ))),
+        },
+        None => Ok(Layout::Version(1)),
+    }
+}
+
+// Python values are TOML-like: quoted strings, and lists in brackets whose
+// quotes are already removed by the INI parser
+fn python_value(value: &str) -> String {
+    let value = value.trim();
+    match value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+        Some(list) => list
+            .split(',')
+            .map(|item| item.trim().trim_matches(|c| c == '"' || c == '\''))
+            .filter(|item| !item.is_empty())
+            .collect::<Vec<&str>>()
+            .join(", "),
+        None => value.to_string(),
+    }
+}
+
+fn from_python(python: &Ini) -> Result<Upgrade> {
+    let mut conf = Ini::load_from_str(DEFAULT_CONFIG).map_err(|e| {
+        Error::Configuration(format!("invalid default configuration: {}", e))
+    })?;
+    let mut renamed = Vec::new();
+    let mut dropped = Vec::new();
+    if let Some(options) = python.section(Some(PYTHON_SECTION)) {
+        for (key, value) in options.iter() {
+            if key == "version" {
+                continue;
+            }
+            if PYTHON_UNSUPPORTED.contains(&key) {
+                dropped.push(format!("[{}] {}", PYTHON_SECTION, key));
+                continue;
+            }
+            let (section, name) = PYTHON_RENAMES
+                .iter()
+                .find(|(old, _, _)| *old == key)
+                .map(|(_, section, name)| (*section, *name))
+                .unwrap_or(("cloud_agent", key));
+            if name != key {
+                renamed.push(Rename {
+                    from: (PYTHON_SECTION.to_string(), key.to_string()),
+                    to: (section.to_string(), name.to_string()),
+                });
+            }
+            let _ = conf
+                .with_section(Some(section))
+                .set(name, python_value(value));
+        }
+    }
+    Ok(Upgrade {
+        conf,
+        from: Layout::Python,
+        renamed,
+        dropped,
+    })
+}
+
+fn from_version_1(mut conf: Ini) -> Upgrade {
+    let mut renamed = Vec::new();
+    for (section, old, new) in V1_RENAMES {
+        let options = match conf.section_mut(Some(*section)) {
+            Some(options) => options,
+            None => continue,
+        };
+        if options.contains_key(new) {
+            continue;
+        }
+        if let Some(value) = options.remove(old) {
+            options.insert(*new, value);
+            renamed.push(Rename {
+                from: (section.to_string(), old.to_string()),
+                to: (section.to_string(), new.to_string()),
+            });
+        }
+    }
+    Upgrade {
+        conf,
+        from: Layout::Version(1),
+        renamed,
+        dropped: Vec::new(),
+    }
+}
+
+/*
+ * Input: configuration as loaded
+ * Return: Result wrap the configuration in the current layout, None when it
+ *         already is
+ */
+pub(crate) fn upgrade(conf: &Ini) -> Result<Option<Upgrade>> {
+    let mut upgrade = match layout(conf)? {
+        Layout::Python => from_python(conf)?,
+        Layout::Version(1) => from_version_1(conf.clone()),
+        Layout::Version(version) => {
+            if version > CONFIG_VERSION {
+                warn!(
+                    "Configuration version {} is newer than the supported version {}, options may be ignored",
+                    version, CONFIG_VERSION
+                );
+            }
+            return Ok(None);
+        }
+    };
+    let _ = upgrade
+        .conf
+        .with_section(Some("cloud_agent"))
+        .set("config_version", CONFIG_VERSION.to_string());
+    Ok(Some(upgrade))
+}
+
+/*
+ * Input: name of the configuration file and its content
+ * Return: Result wrap the configuration in the current layout
+ *
+ * Used when the agent loads its configuration, the translation is only
+ * logged.
+ */
+pub(crate) fn load(conf_name: &str, conf: Ini) -> Result<Ini> {
+    let upgrade = match upgrade(&conf)? {
+        Some(upgrade) => upgrade,
+        None => return Ok(conf),
+    };
+    warn!(
+        "{} is laid out for {}, translated to version {}, run `keylime_agent upgrade-config` to update it",
+        conf_name, upgrade.from, CONFIG_VERSION
+    );
+    for rename in &upgrade.renamed {
+        warn!("Renamed option: {}", rename);
+    }
+    for option in &upgrade.dropped {
+        warn!("Unsupported option ignored: {}", option);
+    }
+    Ok(upgrade.conf)
+}
+
+/*
+ * Input: configuration file to upgrade and path to write the result to
+ * Return: Result wrap the upgrade, None when the file already was current
+ *
+ * The file is copied even when it is current, so that scripts can always
+ * use the output.
+ */
+pub(crate) fn write(source: &Path, output: &Path) -> Result<Option<Upgrade>> {
+    let conf = Ini::load_from_file(source)?;
+    let upgrade = upgrade(&conf)?;
+    let mut file = permissions::create_file(output)?;
+    match &upgrade {
+        Some(upgrade) => upgrade.conf.write_to(&mut file)?,
+        None => conf.write_to(&mut file)?,
+    }
+    Ok(upgrade)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let current = Ini::load_from_str(DEFAULT_CONFIG).unwrap(); //#[allow_ci]
+        assert_eq!(
+            layout(&current).unwrap(), //#[allow_ci]
+            Layout::Version(CONFIG_VERSION)
+        );
+        assert!(upgrade(&current).unwrap().is_none()); //#[allow_ci]
+
+        let python = Ini::load_from_str("[agent]\nversion = 2.0\n").unwrap(); //#[allow_ci]
+        assert_eq!(layout(&python).unwrap(), Layout::Python); //#[allow_ci]
+
+        let invalid =
+            Ini::load_from_str("[cloud_agent]\nconfig_version = two\n")
+                .unwrap(); //#[allow_ci]
+        assert!(layout(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_from_version_1() {
+        let conf = Ini::load_from_str(
+            "[cloud_agent]\nlisten_notfications = False\nrun_as = keylime:tss\n",
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(layout(&conf).unwrap(), Layout::Version(1)); //#[allow_ci]
+
+        let upgrade = upgrade(&conf).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(upgrade.from, Layout::Version(1));
+        assert_eq!(
+            upgrade.renamed[0].to_string(),
+            "[cloud_agent] listen_notfications is now [cloud_agent] listen_notifications"
+        );
+        let section = upgrade.conf.section(Some("cloud_agent")).unwrap(); //#[allow_ci]
+        assert_eq!(section.get("listen_notifications"), Some("False"));
+        assert_eq!(section.get("listen_notfications"), None);
+        assert_eq!(section.get("run_as"), Some("keylime:tss"));
+        assert_eq!(section.get("config_version"), Some("2"));
+    }
+
+    #[test]
+    fn test_from_python() {
+        let python = Ini::load_from_str(
+            r#"[agent]
+version = "2.0"
+ip = "0.0.0.0"
+uuid = "hash_ek"
+revocation_notification_port = 8993
+trusted_client_ca = ["default"]
+revocation_actions = []
+tpm_hash_alg = "sha384"
+server_key = "default"
+"#,
+        )
+        .unwrap(); //#[allow_ci]
+
+        let upgrade = upgrade(&python).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(upgrade.from, Layout::Python);
+        assert_eq!(upgrade.renamed.len(), 4);
+        assert_eq!(upgrade.dropped, vec!["[agent] server_key".to_string()]);
+
+        let conf = upgrade.conf;
+        let get = |section: &str, key: &str| {
+            conf.get_from(Some(section), key).map(str::to_string)
+        };
+        let expected = [
+            ("cloud_agent", "cloudagent_ip", "0.0.0.0"),
+            ("cloud_agent", "agent_uuid", "hash_ek"),
+            ("cloud_agent", "keylime_ca", "default"),
+            ("cloud_agent", "revocation_actions", ""),
+            ("cloud_agent", "tpm_hash_alg", "sha384"),
+            ("general", "receive_revocation_port", "8993"),
+            // Defaults fill in the options the Python agent does not have
+            ("cloud_agent", "secure_size", "1m"),
+        ];
+        for (section, key, value) in expected {
+            assert_eq!(get(section, key), Some(value.to_string()));
+        }
+        assert_eq!(get("cloud_agent", "server_key"), None);
+        assert_eq!(get("agent", "ip"), None);
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let source = dir.path().join("agent.conf");
+        let output = dir.path().join("keylime-agent.conf");
+        std::fs::write(&source, "[agent]\nport = 9003\n").unwrap(); //#[allow_ci]
+
+        let upgrade = write(&source, &output).unwrap(); //#[allow_ci]
+        assert_eq!(upgrade.map(|u| u.from), Some(Layout::Python));
+        let written = Ini::load_from_file(&output).unwrap(); //#[allow_ci]
+        let version = layout(&written).unwrap(); //#[allow_ci]
+        assert_eq!(version, Layout::Version(CONFIG_VERSION));
+        assert_eq!(
+            written.get_from(Some("cloud_agent"), "cloudagent_port"),
+            Some("9003")
+        );
+    }
+}
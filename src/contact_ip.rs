@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Contact IP following the address of a network interface.
+//
+// With 'agent_contact_interface' set, the contact IP sent to the registrar is
+// the address of that interface rather than a fixed value. The agent listens
+// for address changes on a netlink socket and registers the new contact IP
+// when the address changed, e.g. after a DHCP renumbering, so verifiers can
+// still reach it without a restart. A failed registration is retried until
+// it succeeds or the address changes again.
+
+use crate::{
+    admin_socket::{self, Admin},
+    error::Result,
+    status::AgentStatus,
+};
+use log::*;
+use std::{
+    ffi::CStr,
+    io, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::unix::io::{AsRawFd, RawFd},
+    ptr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{io::unix::AsyncFd, time};
+
+// Renumbering usually removes the old address before adding the new one,
+// wait for the changes to settle before reading the address
+const SETTLE_DELAY: Duration = Duration::from_secs(2);
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Netlink socket subscribed to the IPv4 and IPv6 address changes
+#[derive(Debug)]
+struct AddressEvents(RawFd);
+
+impl AsRawFd for AddressEvents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for AddressEvents {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::close(self.0) };
+    }
+}
+
+fn subscribe() -> Result<AddressEvents> {
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let events = AddressEvents(fd);
+
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+    addr.nl_groups =
+        (libc::RTMGRP_IPV4_IFADDR | libc::RTMGRP_IPV6_IFADDR) as u32;
+    if unsafe {
+        libc::bind(
+            fd,
+            ptr::addr_of!(addr).cast::<libc::sockaddr>(),
+            mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(events)
+}
+
+// Waits for the next address change. The content of the messages does not
+// matter, the address is read again after each of them.
+async fn next_change(events: &AsyncFd<AddressEvents>) -> Result<()> {
+    let mut buf = [0u8; 8192];
+    loop {
+        let mut guard = events.readable().await?;
+        let received = guard.try_io(|events| {
+            let len = unsafe {
+                libc::recv(
+                    events.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let e = io::Error::last_os_error();
+                // Changes were dropped, the address is read again anyway
+                if e.raw_os_error() == Some(libc::ENOBUFS) {
+                    return Ok(());
+                }
+                return Err(e);
+            }
+            Ok(())
+        });
+        if let Ok(result) = received {
+            return Ok(result?);
+        }
+    }
+}
+
+// IPv4 is preferred, IPv6 link-local addresses are not reachable from
+// other networks
+fn select_address(addrs: &[IpAddr]) -> Option<IpAddr> {
+    addrs
+        .iter()
+        .find(|addr| addr.is_ipv4())
+        .copied()
+        .or_else(|| {
+            addrs.iter().copied().find(|addr| match addr {
+                IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) != 0xfe80,
+                IpAddr::V4(_) => false,
+            })
+        })
+}
+
+/*
+ * Input: interface name
+ * Return: Result wrap the address to use as contact IP, None when the
+ *         interface has no usable address
+ */
+pub(crate) fn interface_address(name: &str) -> Result<Option<IpAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let mut addrs = Vec::new();
+    let mut current = ifaddrs;
+    while !current.is_null() {
+        let ifa = unsafe { &*current };
+        current = ifa.ifa_next;
+        if ifa.ifa_addr.is_null()
+            || unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes()
+                != name.as_bytes()
+        {
+            continue;
+        }
+        match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sin =
+                    unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in>() };
+                addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                    sin.sin_addr.s_addr,
+                ))));
+            }
+            libc::AF_INET6 => {
+                let sin6 =
+                    unsafe { &*ifa.ifa_addr.cast::<libc::sockaddr_in6>() };
+                addrs
+                    .push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(select_address(&addrs))
+}
+
+/*
+ * Input: interface name, admin state and agent status
+ *
+ * Registers the agent again each time the address of the interface changes,
+ * until the agent exits.
+ */
+pub(crate) async fn watch(
+    interface: String,
+    admin: Arc<Admin>,
+    status: Arc<AgentStatus>,
+) -> Result<()> {
+    let events = AsyncFd::new(subscribe()?)?;
+    info!("Watching the address of {} for the contact IP", interface);
+
+    let mut retry = false;
+    loop {
+        if retry {
+            tokio::select! {
+                result = next_change(&events) => result?,
+                _ = time::sleep(RETRY_DELAY) => {}
+            }
+        } else {
+            next_change(&events).await?;
+        }
+        time::sleep(SETTLE_DELAY).await;
+
+        let ip = match interface_address(&interface)? {
+            Some(ip) => ip.to_string(),
+            None => {
+                debug!(
+                    "{} has no address, keeping the contact IP",
+                    interface
+                );
+                continue;
+            }
+        };
+        if admin_socket::contact_ip(&admin).as_deref() == Some(ip.as_str()) {
+            retry = false;
+            continue;
+        }
+
+        info!("Address of {} changed to {}, registering it", interface, ip);
+        match admin_socket::update_contact_ip(&admin, &ip, &status).await {
+            Ok(()) => retry = false,
+            Err(e) => {
+                warn!(
+                    "Unable to register the contact IP {}, retrying in {} seconds: {}",
+                    ip,
+                    RETRY_DELAY.as_secs(),
+                    e
+                );
+                retry = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_select_address() {
+        let v4: IpAddr = "192.0.2.1".parse().unwrap(); //#[allow_ci]
+        let v6: IpAddr = "2001:db8::1".parse().unwrap(); //#[allow_ci]
+        let link_local: IpAddr = "fe80::1".parse().unwrap(); //#[allow_ci]
+
+        assert_eq!(select_address(&[v6, v4]), Some(v4));
+        assert_eq!(select_address(&[link_local, v6]), Some(v6));
+        assert_eq!(select_address(&[link_local]), None);
+        assert_eq!(select_address(&[]), None);
+    }
+
+    #[test]
+    fn test_interface_address() {
+        let lo = interface_address("lo").unwrap(); //#[allow_ci]
+        assert_eq!(lo, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+        let missing = interface_address("missing0").unwrap(); //#[allow_ci]
+        assert_eq!(missing, None);
+    }
+}
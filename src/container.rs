@@ -0,0 +1,170 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Zero-config mode of the container image.
+//
+// With KEYLIME_AGENT_ZERO_CONFIG set to true, the agent does not read a
+// configuration file: it starts from the defaults shipped in
+// keylime-agent.conf and needs the address of the registrar in REGISTRAR_IP,
+// and REGISTRAR_PORT if not the default one. mTLS being on by default, it
+// also needs the Keylime CA certificate, mounted in the default path under
+// the work directory or set with KEYLIME_AGENT_KEYLIME_CA. The remaining
+// options get values suited to a container:
+//  - the work directory is /tmp/keylime, discarded with the container, the
+//    agent data is not kept across restarts
+//  - 'agent_uuid' is taken from KEYLIME_AGENT_UUID, "hash_ek" by default so
+//    that a restarted container keeps its UUID
+//  - the agent listens on all interfaces and does not drop privileges, the
+//    user is the one the container runs as
+//  - the admin socket is in the work directory, for `docker exec`
+// Any other option of [cloud_agent] is set with a KEYLIME_AGENT_<OPTION>
+// environment variable, e.g. KEYLIME_AGENT_KEYLIME_CA, and the variables the
+// agent always reads, e.g. CLOUDAGENT_PORT, still apply.
+
+use crate::{
+    config_upgrade,
+    error::{Error, Result},
+    permissions,
+};
+use ini::Ini;
+use log::*;
+use std::{env, path::Path, str::FromStr};
+
+static ZERO_CONFIG_ENV: &str = "KEYLIME_AGENT_ZERO_CONFIG";
+static OPTION_ENV_PREFIX: &str = "KEYLIME_AGENT_";
+static UUID_ENV: &str = "KEYLIME_AGENT_UUID";
+static REGISTRAR_ENV: &str = "REGISTRAR_IP";
+static SECTION: &str = "cloud_agent";
+// Variables with the prefix that are not options
+static NOT_OPTIONS: &[&str] = &[
+    "KEYLIME_AGENT_ZERO_CONFIG",
+    "KEYLIME_AGENT_UUID",
+    "KEYLIME_AGENT_CONTACT_IP",
+    "KEYLIME_AGENT_CONTACT_PORT",
+];
+
+// Name of the configuration in the messages, in place of the file name
+pub(crate) static CONF_NAME: &str = "the zero-config defaults";
+
+static WORK_DIR: &str = "/tmp/keylime";
+static DEFAULT_UUID: &str = "hash_ek";
+
+// Whether the agent runs in zero-config mode
+pub(crate) fn enabled() -> bool {
+    env::var(ZERO_CONFIG_ENV)
+        .ok()
+        .and_then(|value| bool::from_str(&value.to_lowercase()).ok())
+        .unwrap_or(false)
+}
+
+/*
+ * Input: environment variables, as (name, value)
+ * Return: Result wrap the configuration synthesized from them
+ */
+fn conf_from<I>(vars: I) -> Result<Ini>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut conf = Ini::load_from_str(config_upgrade::DEFAULT_CONFIG)
+        .map_err(|e| {
+            Error::Configuration(format!(
+                "invalid default configuration: {}",
+                e
+            ))
+        })?;
+    let vars = vars.into_iter().collect::<Vec<_>>();
+    let var = |name: &str| {
+        vars.iter()
+            .find(|(var, value)| var == name && !value.is_empty())
+            .map(|(_, value)| value.clone())
+    };
+    if var(REGISTRAR_ENV).is_none() {
+        return Err(Error::Configuration(format!(
+            "{} must be set in zero-config mode",
+            REGISTRAR_ENV
+        )));
+    }
+
+    let admin_socket = Path::new(WORK_DIR).join("agent.sock");
+    let uuid = var(UUID_ENV).unwrap_or_else(|| DEFAULT_UUID.to_string());
+    let _ = conf
+        .with_section(Some(SECTION))
+        .set("keylime_dir", WORK_DIR)
+        .set("cloudagent_ip", "0.0.0.0")
+        .set("run_as", "")
+        .set("admin_socket", admin_socket.display().to_string())
+        .set("agent_uuid", uuid);
+
+    for (name, value) in &vars {
+        let option = match name.strip_prefix(OPTION_ENV_PREFIX) {
+            Some(option) if !NOT_OPTIONS.contains(&name.as_str()) => {
+                option.to_lowercase()
+            }
+            _ => continue,
+        };
+        debug!("Setting {} from {}", option, name);
+        let _ = conf.with_section(Some(SECTION)).set(option, value.as_str());
+    }
+    Ok(conf)
+}
+
+/*
+ * Return: Result wrap the configuration synthesized from the environment
+ *
+ * Creates the work directory, so that it exists whether the agent runs as
+ * root or not.
+ */
+pub(crate) fn conf() -> Result<Ini> {
+    let conf = conf_from(env::vars())?;
+    info!("Running in zero-config mode, no configuration file is read");
+    if let Some(work_dir) = conf.get_from(Some(SECTION), "keylime_dir") {
+        let work_dir = Path::new(work_dir);
+        if !work_dir.exists() {
+            permissions::create_dir(work_dir)?;
+        }
+    }
+    Ok(conf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_conf_from() {
+        let conf = conf_from(vars(&[
+            ("REGISTRAR_IP", "registrar.example"),
+            ("KEYLIME_AGENT_ZERO_CONFIG", "true"),
+            ("KEYLIME_AGENT_MTLS_CERT_ENABLED", "False"),
+            ("KEYLIME_AGENT_CONTACT_IP", "10.0.0.1"),
+        ]))
+        .unwrap(); //#[allow_ci]
+        let get = |option: &str| conf.get_from(Some(SECTION), option);
+        assert_eq!(get("keylime_dir"), Some(WORK_DIR));
+        assert_eq!(get("run_as"), Some(""));
+        assert_eq!(get("agent_uuid"), Some(DEFAULT_UUID));
+        assert_eq!(get("admin_socket"), Some("/tmp/keylime/agent.sock"));
+        assert_eq!(get("mtls_cert_enabled"), Some("False"));
+        assert_eq!(get("zero_config"), None);
+        assert_eq!(get("contact_ip"), None);
+
+        let conf = conf_from(vars(&[
+            ("REGISTRAR_IP", "registrar.example"),
+            ("KEYLIME_AGENT_UUID", "generate"),
+        ]))
+        .unwrap(); //#[allow_ci]
+        assert_eq!(
+            conf.get_from(Some(SECTION), "agent_uuid"),
+            Some("generate")
+        );
+
+        // The registrar cannot be defaulted
+        assert!(conf_from(vars(&[("REGISTRAR_IP", "")])).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// State of the agent logged when one of its threads panics.
+//
+// A panic otherwise only reports its message and location, which is rarely
+// enough to tell what the agent was doing in the field. The hook installed
+// at startup also logs the module that panicked, the status of the agent
+// (registration, payload and keys state, background tasks), the TPM handles
+// in use per operation and the last operations run by the agent: HTTP
+// requests, TPM operations and background tasks. The snapshot holds no key
+// material, and requests are recorded without their query, which carries
+// the nonces. With 'abort_on_panic', the agent aborts once the snapshot is
+// logged, so that the service manager restarts it.
+
+use crate::{
+    status::{AgentStatus, StatusReport},
+    tpm::HandleRegistry,
+};
+use log::*;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    panic::{self, Location},
+    process,
+    sync::{Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Operations kept for the snapshot, the older ones are dropped
+const MAX_OPERATIONS: usize = 32;
+
+static OPERATIONS: Mutex<VecDeque<Operation>> = Mutex::new(VecDeque::new());
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+struct Operation {
+    // Seconds since the Unix epoch
+    time: u64,
+    subsystem: &'static str,
+    description: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CrashReport {
+    module: String,
+    location: Option<String>,
+    thread: String,
+    // None when the state was locked by the thread that panicked
+    status: Option<StatusReport>,
+    tpm_handles: Option<BTreeMap<String, usize>>,
+    operations: Vec<Operation>,
+}
+
+/*
+ * Input: subsystem running the operation and what it does
+ *
+ * Records the operation for the snapshot logged on panic.
+ */
+pub(crate) fn record(
+    subsystem: &'static str,
+    description: impl Into<String>,
+) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // A poisoned lock still holds the operations before the panic
+    let mut operations = match OPERATIONS.lock() {
+        Ok(operations) => operations,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if operations.len() == MAX_OPERATIONS {
+        let _ = operations.pop_front();
+    }
+    operations.push_back(Operation {
+        time,
+        subsystem,
+        description: description.into(),
+    });
+}
+
+// The function that queued a closure, e.g. "quotes_handler::integrity" for
+// the TPM operation of an integrity quote
+pub(crate) fn operation_name<F>() -> String {
+    let name = std::any::type_name::<F>();
+    let mut name = name.strip_prefix("keylime_agent::").unwrap_or(name);
+    while let Some(outer) = name.strip_suffix("::{{closure}}") {
+        name = outer;
+    }
+    name.to_string()
+}
+
+// The operations recorded so far, oldest first, without waiting when they
+// are locked by the thread that panicked
+fn recent_operations() -> Vec<Operation> {
+    OPERATIONS
+        .try_lock()
+        .map(|operations| operations.iter().cloned().collect())
+        .unwrap_or_default()
+}
+
+// The agent module of a source file, e.g. "tpm" for "src/tpm.rs"
+fn module_of(file: &str) -> String {
+    file.strip_prefix("src/")
+        .and_then(|file| file.strip_suffix(".rs"))
+        .unwrap_or(file)
+        .to_string()
+}
+
+fn snapshot(
+    location: Option<&Location>,
+    status: &AgentStatus,
+    handles: &HandleRegistry,
+) -> CrashReport {
+    CrashReport {
+        module: location
+            .map(|location| module_of(location.file()))
+            .unwrap_or_else(|| "unknown".to_string()),
+        location: location.map(|location| {
+            format!("{}:{}", location.file(), location.line())
+        }),
+        thread: thread::current().name().unwrap_or("unnamed").to_string(),
+        status: status.try_report(),
+        tpm_handles: handles.try_counts(),
+        operations: recent_operations(),
+    }
+}
+
+/*
+ * Input: agent status, TPM handle registry and whether to abort on panic
+ *
+ * Installs the panic hook logging the snapshot, after the message printed
+ * by the default hook.
+ */
+pub(crate) fn install(
+    status: Arc<AgentStatus>,
+    handles: Arc<HandleRegistry>,
+    abort: bool,
+) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let report = snapshot(info.location(), &status, &handles);
+        match serde_json::to_string(&report) {
+            Ok(report) => error!("Agent panicked, state: {}", report),
+            Err(e) => error!("Agent panicked, state not available: {}", e),
+        }
+        if abort {
+            error!("Aborting the agent, 'abort_on_panic' is set");
+            process::abort();
+        }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_of() {
+        assert_eq!(module_of("src/tpm.rs"), "tpm");
+        assert_eq!(module_of("/registry/src/lib.rs"), "/registry/src/lib.rs");
+    }
+
+    #[test]
+    fn test_operation_name() {
+        fn queued<F: FnOnce()>(_: F) -> String {
+            operation_name::<F>()
+        }
+        assert_eq!(queued(|| {}), "crash_report::tests::test_operation_name");
+    }
+
+    #[test]
+    fn test_recent_operations() {
+        for i in 0..MAX_OPERATIONS + 1 {
+            record("test", format!("operation {}", i));
+        }
+        // Other tests record operations concurrently
+        let operations = OPERATIONS.lock().unwrap(); //#[allow_ci]
+        assert_eq!(operations.len(), MAX_OPERATIONS);
+        assert!(!operations
+            .iter()
+            .any(|operation| operation.description == "operation 0"));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let status = AgentStatus::new("uuid");
+        let handles = HandleRegistry::default();
+
+        let report = snapshot(Some(Location::caller()), &status, &handles);
+        assert_eq!(report.module, "crash_report");
+        assert_eq!(report.status.unwrap().agent_uuid, "uuid"); //#[allow_ci]
+        assert_eq!(report.tpm_handles, Some(BTreeMap::new()));
+
+        let report = snapshot(None, &status, &handles);
+        assert_eq!(report.module, "unknown");
+        assert_eq!(report.location, None);
+    }
+}
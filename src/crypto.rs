@@ -1,29 +1,44 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use log::*;
 use openssl::{
     asn1::Asn1Time,
+    derive::Deriver,
     encrypt::Decrypter,
     hash::MessageDigest,
-    memcmp,
     nid::Nid,
     pkcs5,
     pkey::{Id, PKey, PKeyRef, Private, Public},
     rsa::{Padding, Rsa},
     sign::{Signer, Verifier},
-    ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod, SslVerifyMode},
+    ssl::{
+        self, AlpnError, ClientHelloResponse, SslAcceptor,
+        SslAcceptorBuilder, SslContext, SslContextBuilder, SslMethod,
+        SslOptions, SslSessionCacheMode, SslVerifyMode,
+    },
+    stack::Stack,
     symm::Cipher,
     x509::store::X509StoreBuilder,
-    x509::{X509Name, X509},
+    x509::verify::X509VerifyFlags,
+    x509::{X509Name, X509Req, X509ReqBuilder, X509StoreContext, X509},
 };
 use std::fs;
 use std::path::Path;
 use std::string::String;
+use std::sync::{Arc, RwLock};
 
 use crate::{
+    aead::{self, GCM_IV_LEN},
+    algorithms::HmacAlgorithm,
+    common::KeylimeConfig,
     Error, Result, AES_128_KEY_LEN, AES_256_KEY_LEN, AES_BLOCK_SIZE,
 };
 
+// Info of the HKDF of the key wrapping U and V keys with X25519
+const X25519_WRAP_INFO: &[u8] = b"keylime u/v key wrap";
+const X25519_KEY_LEN: usize = 32;
+
 // Read a X509 cert or cert chain and outputs the first certificate
 pub(crate) fn load_x509(input_cert_path: &Path) -> Result<X509> {
     let contents = fs::read_to_string(&input_cert_path)?;
@@ -40,6 +55,69 @@ pub(crate) fn load_x509(input_cert_path: &Path) -> Result<X509> {
     Ok(cert)
 }
 
+/*
+ * Input: path of a PEM file, or of a directory of PEM or DER files
+ * Output: the certificates found
+ */
+pub(crate) fn load_ca_certs(path: &Path) -> Result<Vec<X509>> {
+    let files = if path.is_dir() {
+        let mut files = fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.sort();
+        files
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut certs = Vec::new();
+    for file in files.iter().filter(|file| file.is_file()) {
+        let data = fs::read(file)?;
+        match X509::stack_from_pem(&data) {
+            Ok(stack) if !stack.is_empty() => certs.extend(stack),
+            _ => match X509::from_der(&data) {
+                Ok(cert) => certs.push(cert),
+                Err(_) => {
+                    warn!("Ignoring {}, not a certificate", file.display())
+                }
+            },
+        }
+    }
+    Ok(certs)
+}
+
+/*
+ * Input: certificate, untrusted intermediate CA certificates and trusted CA
+ *        certificates
+ * Output: true if the certificate chains up to a trusted CA, otherwise false
+ *
+ * Unhandled critical extensions are ignored, EK certificates mark the TPM
+ * specific extensions critical, such as the subject alternative name holding
+ * the TPM manufacturer and model.
+ */
+pub(crate) fn verify_cert_chain(
+    cert: &X509,
+    intermediates: &[X509],
+    trusted: Vec<X509>,
+) -> Result<bool> {
+    let mut store = X509StoreBuilder::new()?;
+    for ca in trusted {
+        store.add_cert(ca)?;
+    }
+    store.set_flags(X509VerifyFlags::IGNORE_CRITICAL)?;
+    let store = store.build();
+
+    let mut chain = Stack::new()?;
+    for intermediate in intermediates {
+        chain.push(intermediate.clone())?;
+    }
+    let mut context = X509StoreContext::new()?;
+    Ok(
+        context
+            .init(&store, cert, &chain, |context| context.verify_cert())?,
+    )
+}
+
 pub(crate) fn rsa_generate(key_size: u32) -> Result<PKey<Private>> {
     PKey::from_rsa(Rsa::generate(key_size)?).map_err(Error::Crypto)
 }
@@ -52,6 +130,23 @@ pub(crate) fn rsa_generate_pair(
     Ok((public, private))
 }
 
+/*
+ * Input: PKCS#11 URI of the NK, module and PIN of the token
+ * Return: Result wrap the NK kept in the token
+ *
+ * The private key is a handle to the key in the token, OpenSSL runs its
+ * operations there.
+ */
+pub(crate) fn pkcs11_nk(
+    uri: &str,
+    module: Option<&str>,
+    pin: Option<&str>,
+) -> Result<(PKey<Public>, PKey<Private>)> {
+    let private = crate::pkcs11::load_private_key(uri, module, pin)?;
+    let public = pkey_pub_from_priv(private.clone())?;
+    Ok((public, private))
+}
+
 pub(crate) fn pkey_pub_from_priv(
     privkey: PKey<Private>,
 ) -> Result<PKey<Public>> {
@@ -98,15 +193,90 @@ pub(crate) fn generate_x509(key: &PKey<Private>, uuid: &str) -> Result<X509> {
     Ok(builder.build())
 }
 
+// Certificate signing request for the mTLS certificate, with the same subject
+// as the self-signed certificate, see mtls_enrollment.rs
+pub(crate) fn generate_csr(
+    key: &PKey<Private>,
+    uuid: &str,
+) -> Result<X509Req> {
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, uuid)?;
+    let name = name.build();
+
+    let mut builder = X509ReqBuilder::new()?;
+    builder.set_version(0)?;
+    builder.set_subject_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.sign(key, MessageDigest::sha256())?;
+
+    Ok(builder.build())
+}
+
+// Required to resume sessions when client certificates are verified
+static SESSION_ID_CONTEXT: &[u8] = b"keylime-agent";
+// ALPN protocols in order of preference
+static ALPN_HTTP2: &[u8] = b"\x02h2\x08http/1.1";
+static ALPN_HTTP1: &[u8] = b"\x08http/1.1";
+
+// TLS features of the agent server
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TlsOptions {
+    pub http2: bool,
+    pub session_resumption: bool,
+}
+
+impl From<&KeylimeConfig> for TlsOptions {
+    fn from(config: &KeylimeConfig) -> Self {
+        TlsOptions {
+            http2: config.enable_http2,
+            session_resumption: config.tls_session_resumption,
+        }
+    }
+}
+
+fn select_alpn(http2: bool, client: &[u8]) -> Option<&[u8]> {
+    let server = if http2 { ALPN_HTTP2 } else { ALPN_HTTP1 };
+    ssl::select_next_proto(server, client)
+}
+
+/*
+ * Input: SSL context builder and TLS options
+ *
+ * The protocol and sessions must be set on the acceptor and on the identity
+ * it switches to: ALPN is negotiated with the identity, while sessions are
+ * cached and tickets encrypted by the acceptor.
+ */
+fn configure_tls(
+    builder: &mut SslContextBuilder,
+    options: TlsOptions,
+) -> Result<()> {
+    builder.set_alpn_select_callback(move |_, client| {
+        select_alpn(options.http2, client).ok_or(AlpnError::NOACK)
+    });
+    builder.set_session_id_context(SESSION_ID_CONTEXT)?;
+    if options.session_resumption {
+        let _ = builder.set_session_cache_mode(SslSessionCacheMode::SERVER);
+    } else {
+        let _ = builder.set_options(SslOptions::NO_TICKET);
+        let _ = builder.set_session_cache_mode(SslSessionCacheMode::OFF);
+    }
+    Ok(())
+}
+
 pub(crate) fn generate_mtls_context(
     mtls_cert: &X509,
+    mtls_cert_chain: &[X509],
     key: &PKey<Private>,
     keylime_ca_cert: X509,
+    options: TlsOptions,
 ) -> Result<SslAcceptorBuilder> {
     let mut ssl_context_builder =
         SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
     ssl_context_builder.set_certificate(mtls_cert);
     ssl_context_builder.set_private_key(key);
+    for cert in mtls_cert_chain {
+        ssl_context_builder.add_extra_chain_cert(cert.clone())?;
+    }
 
     // Build verification cert store.
     let mut mtls_store_builder = X509StoreBuilder::new()?;
@@ -119,10 +289,60 @@ pub(crate) fn generate_mtls_context(
     verify_mode.set(SslVerifyMode::PEER, true);
     verify_mode.set(SslVerifyMode::FAIL_IF_NO_PEER_CERT, true);
     ssl_context_builder.set_verify(verify_mode);
+    configure_tls(&mut ssl_context_builder, options)?;
 
     Ok(ssl_context_builder)
 }
 
+// Certificate and key presented by the mTLS server. They can be replaced
+// while the server is running, new handshakes use the current ones.
+pub(crate) type MtlsIdentity = Arc<RwLock<SslContext>>;
+
+/*
+ * Input: mTLS certificate, the intermediate CA certificates it is served
+ *        with, its private key, the Keylime CA certificate and the TLS
+ *        options
+ * Output: SSL context holding the agent identity
+ *
+ * Switching a connection to another SSL context also replaces the store used
+ * to verify the peer certificate, so the CA is included here as well.
+ */
+pub(crate) fn generate_mtls_identity(
+    mtls_cert: &X509,
+    mtls_cert_chain: &[X509],
+    key: &PKey<Private>,
+    keylime_ca_cert: &X509,
+    options: TlsOptions,
+) -> Result<SslContext> {
+    let mut builder = SslContext::builder(SslMethod::tls())?;
+    builder.set_certificate(mtls_cert)?;
+    builder.set_private_key(key)?;
+    builder.check_private_key()?;
+    for cert in mtls_cert_chain {
+        builder.add_extra_chain_cert(cert.clone())?;
+    }
+
+    let mut mtls_store_builder = X509StoreBuilder::new()?;
+    mtls_store_builder.add_cert(keylime_ca_cert.clone())?;
+    builder.set_verify_cert_store(mtls_store_builder.build())?;
+    configure_tls(&mut builder, options)?;
+
+    Ok(builder.build())
+}
+
+// Makes every new connection use the identity current at the time of the
+// handshake, so it can be rotated without restarting the server
+pub(crate) fn enable_mtls_rotation(
+    ssl_context_builder: &mut SslAcceptorBuilder,
+    identity: MtlsIdentity,
+) {
+    ssl_context_builder.set_client_hello_callback(move |ssl, _alert| {
+        let context = identity.read().unwrap(); //#[allow_ci]
+        ssl.set_ssl_context(&context)?;
+        Ok(ClientHelloResponse::SUCCESS)
+    });
+}
+
 /*
  * Inputs: password to derive key
  *         shared salt
@@ -158,6 +378,17 @@ pub(crate) fn kdf(
     Ok(hex::encode(&key[..]))
 }
 
+/*
+ * Input: data to hash
+ * Output: SM3 digest of the data
+ *
+ * Used for the SM3 PCR bank of TPMs certified for the Chinese algorithms.
+ */
+#[cfg(feature = "sm")]
+pub(crate) fn sm3_digest(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(openssl::hash::hash(MessageDigest::sm3(), data)?.to_vec())
+}
+
 /*
  * Input: Trusted public key, and remote message and signature
  * Output: true if they are verified, otherwise false
@@ -178,6 +409,25 @@ pub(crate) fn asym_verify(
     Ok(verifier.verify(&base64::decode(signature.as_bytes())?)?)
 }
 
+/*
+ * Input: private key and message
+ * Output: signature of the message
+ *
+ * Signs with the parameters asym_verify() checks
+ */
+pub(crate) fn asym_sign(
+    key: &PKeyRef<Private>,
+    message: &[u8],
+) -> Result<Vec<u8>> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+    signer.set_rsa_mgf1_md(MessageDigest::sha256())?;
+    signer
+        .set_rsa_pss_saltlen(openssl::sign::RsaPssSaltlen::MAXIMUM_LENGTH)?;
+    signer.update(message)?;
+    Ok(signer.sign_to_vec()?)
+}
+
 /*
  * Inputs: OpenSSL RSA key
  *         ciphertext to be decrypted
@@ -207,75 +457,167 @@ pub(crate) fn rsa_oaep_decrypt(
     Ok(decrypted)
 }
 
+pub(crate) fn x25519_generate() -> Result<PKey<Private>> {
+    PKey::generate_x25519().map_err(Error::Crypto)
+}
+
+// HKDF-SHA256 (RFC 5869) of a key of the size of the digest, which is a
+// single expansion block
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> Result<Vec<u8>> {
+    let prk = compute_hmac(HmacAlgorithm::Sha256, salt, ikm)?;
+    compute_hmac(HmacAlgorithm::Sha256, &prk, &[info, &[1u8]].concat())
+}
+
+// AES-256 key wrapping a key for the X25519 public key of the recipient,
+// agreed with the other X25519 key
+fn x25519_wrap_key(
+    key: &PKeyRef<Private>,
+    peer: &[u8],
+    ephemeral: &[u8],
+    recipient: &[u8],
+) -> Result<Vec<u8>> {
+    let peer = PKey::public_key_from_raw_bytes(peer, Id::X25519)?;
+    let mut deriver = Deriver::new(key)?;
+    deriver.set_peer(&peer)?;
+    let shared = deriver.derive_to_vec()?;
+    hkdf_sha256(&[ephemeral, recipient].concat(), &shared, X25519_WRAP_INFO)
+}
+
+/*
+ * Input: X25519 private key of the agent and wrapped key
+ * Output: the unwrapped key
+ *
+ * ECIES construction: the wrapped key is the ephemeral X25519 public key of
+ * the sender, a 12 bytes IV, and the AES-256-GCM ciphertext and tag. The
+ * AES key is derived from the shared secret with HKDF-SHA256, salted with
+ * the ephemeral and agent public keys.
+ */
+pub(crate) fn x25519_unwrap(
+    priv_key: &PKey<Private>,
+    wrapped: &[u8],
+) -> Result<Vec<u8>> {
+    if wrapped.len() < X25519_KEY_LEN + GCM_IV_LEN + AES_BLOCK_SIZE {
+        return Err(Error::InvalidRequest);
+    }
+    let (ephemeral, sealed) = wrapped.split_at(X25519_KEY_LEN);
+    let key = x25519_wrap_key(
+        priv_key,
+        ephemeral,
+        ephemeral,
+        &priv_key.raw_public_key()?,
+    )?;
+    decrypt_aead_iv(&key, sealed, GCM_IV_LEN)
+}
+
 /*
- * Inputs: secret key
+ * Inputs: HMAC algorithm
+ *        secret key
  *        message to sign
  * Output: signed HMAC result
  *
  * Sign message and return HMAC result string
  */
-pub(crate) fn compute_hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
-    let pkey = PKey::hmac(key)?;
-    // SHA-384 is used as the underlying hash algorithm.
+pub(crate) fn compute_hmac(
+    alg: HmacAlgorithm,
+    key: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    // SHA-384 is the hash algorithm of the Keylime protocol, unless the
+    // registrar requested another one.
     //
     // Reference:
     // https://keylime-docs.readthedocs.io/en/latest/rest_apis.html#post--v1.0-keys-ukey
     // https://github.com/keylime/keylime/blob/910b38b296038b187a020c095dc747e9c46cbef3/keylime/crypto.py#L151
-    let mut signer = Signer::new(MessageDigest::sha384(), &pkey)?;
-    signer.update(data)?;
-    signer.sign_to_vec().map_err(Error::Crypto)
+    Ok(aead::compute_hmac(alg.into(), key, data)?)
 }
 
 pub(crate) fn verify_hmac(
+    alg: HmacAlgorithm,
     key: &[u8],
     data: &[u8],
     hmac: &[u8],
 ) -> Result<()> {
-    let pkey = PKey::hmac(key)?;
-    // SHA-384 is used as the underlying hash algorithm.
+    Ok(aead::verify_hmac(alg.into(), key, data, hmac)?)
+}
+
+pub(crate) fn decrypt_aead(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    decrypt_aead_iv(key, data, AES_BLOCK_SIZE)
+}
+
+/*
+ * Input: AES key, data and length of its IV
+ * Return: Result wrap the decrypted data
+ *
+ * Same as decrypt_aead(), for the 12 bytes IV recommended by SP 800-38D.
+ */
+pub(crate) fn decrypt_aead_iv(
+    key: &[u8],
+    data: &[u8],
+    iv_len: usize,
+) -> Result<Vec<u8>> {
+    // Parse out payload IV, tag, ciphertext.  Note that Keylime
+    // currently uses 16-byte IV, while the recommendation in SP
+    // 800-38D is 12-byte.
     //
     // Reference:
-    // https://keylime-docs.readthedocs.io/en/latest/rest_apis.html#post--v1.0-keys-ukey
-    // https://github.com/keylime/keylime/blob/910b38b296038b187a020c095dc747e9c46cbef3/keylime/crypto.py#L151
-    let mut signer = Signer::new(MessageDigest::sha384(), &pkey)?;
-    signer.update(data)?;
-
-    if !memcmp::eq(&signer.sign_to_vec()?, hmac) {
-        return Err(Error::Other("hmac check failed".to_string()));
-    }
+    // https://github.com/keylime/keylime/blob/1663a7702b3286152b38dbcb715a9eb6705e05e9/keylime/crypto.py#L191
+    Ok(aead::decrypt_iv(key, data, iv_len)?)
+}
 
-    Ok(())
+// Payload with either IV, see aead.rs
+pub(crate) fn decrypt_aead_payload(
+    key: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    Ok(aead::decrypt(key, data)?)
 }
 
-pub(crate) fn decrypt_aead(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+/*
+ * Input: AES key and data
+ * Return: Result wrap the decrypted data
+ *
+ * Decrypts the legacy payload format: the 16 bytes IV, the AES-CBC
+ * ciphertext with PKCS#7 padding and the HMAC-SHA384 of both, keyed with the
+ * same key. The HMAC is checked before anything is decrypted.
+ */
+pub(crate) fn decrypt_cbc_hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
     let cipher = match key.len() {
-        AES_128_KEY_LEN => Cipher::aes_128_gcm(),
-        AES_256_KEY_LEN => Cipher::aes_256_gcm(),
+        AES_128_KEY_LEN => Cipher::aes_128_cbc(),
+        AES_256_KEY_LEN => Cipher::aes_256_cbc(),
         other => {
             return Err(Error::Other(format!(
-                "key length {} does not correspond to valid GCM cipher",
+                "key length {} does not correspond to valid CBC cipher",
                 other
             )))
         }
     };
-
-    // Parse out payload IV, tag, ciphertext.  Note that Keylime
-    // currently uses 16-byte IV, while the recommendation in SP
-    // 800-38D is 12-byte.
-    //
-    // Reference:
-    // https://github.com/keylime/keylime/blob/1663a7702b3286152b38dbcb715a9eb6705e05e9/keylime/crypto.py#L191
-    if data.len() < AES_BLOCK_SIZE * 2 {
+    let hmac_len = HmacAlgorithm::Sha384.size();
+    if data.len() < AES_BLOCK_SIZE * 2 + hmac_len {
         return Err(Error::InvalidRequest);
     }
-    let (iv, rest) = data.split_at(AES_BLOCK_SIZE);
-    let (ciphertext, tag) = rest.split_at(rest.len() - AES_BLOCK_SIZE);
+    let (signed, hmac) = data.split_at(data.len() - hmac_len);
+    verify_hmac(HmacAlgorithm::Sha384, key, signed, hmac)?;
+    let (iv, ciphertext) = signed.split_at(AES_BLOCK_SIZE);
 
-    openssl::symm::decrypt_aead(cipher, key, Some(iv), &[], ciphertext, tag)
+    openssl::symm::decrypt(cipher, key, Some(iv), ciphertext)
         .map_err(Error::Crypto)
 }
 
+/*
+ * Input: AES key, IV and data
+ * Return: Result wrap the IV, ciphertext and tag, the format decrypt_aead()
+ *         reads
+ */
+pub(crate) fn encrypt_aead(
+    key: &[u8],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    Ok(aead::encrypt(key, iv, data)?)
+}
+
 pub mod testing {
+    pub(crate) use super::encrypt_aead;
     use super::*;
     use openssl::encrypt::Encrypter;
     use std::path::Path;
@@ -315,44 +657,26 @@ pub mod testing {
         Ok(encrypted)
     }
 
-    pub(crate) fn encrypt_aead(
-        key: &[u8],
-        iv: &[u8],
+    // The reverse of x25519_unwrap(), as done by the tenant
+    pub(crate) fn x25519_wrap(
+        recipient: &[u8],
         data: &[u8],
     ) -> Result<Vec<u8>> {
-        let cipher = match key.len() {
-            AES_128_KEY_LEN => Cipher::aes_128_gcm(),
-            AES_256_KEY_LEN => Cipher::aes_256_gcm(),
-            other => {
-                return Err(Error::Other(format!(
-                    "key length {} does not correspond to valid GCM cipher",
-                    other
-                )))
-            }
-        };
-        if iv.len() != AES_BLOCK_SIZE {
-            return Err(Error::Other(format!(
-                "IV length {} does not correspond to valid GCM cipher {}",
-                iv.len(),
-                AES_BLOCK_SIZE
-            )));
-        }
-        let mut tag = vec![0u8; AES_BLOCK_SIZE];
+        let ephemeral = PKey::generate_x25519()?;
+        let public = ephemeral.raw_public_key()?;
+        let key = x25519_wrap_key(&ephemeral, recipient, &public, recipient)?;
+        let mut iv = [0u8; GCM_IV_LEN];
+        openssl::rand::rand_bytes(&mut iv)?;
+        let mut tag = [0u8; AES_BLOCK_SIZE];
         let ciphertext = openssl::symm::encrypt_aead(
-            cipher,
-            key,
-            Some(iv),
+            Cipher::aes_256_gcm(),
+            &key,
+            Some(&iv),
             &[],
             data,
             &mut tag,
-        )
-        .map_err(Error::Crypto)?;
-        let mut result =
-            Vec::with_capacity(iv.len() + ciphertext.len() + tag.len());
-        result.extend(iv);
-        result.extend(ciphertext);
-        result.extend(tag);
-        Ok(result)
+        )?;
+        Ok([&public[..], &iv, &ciphertext, &tag].concat())
     }
 }
 
@@ -360,17 +684,49 @@ pub mod testing {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::common::SymmKey;
     use openssl::rsa::Rsa;
+    use proptest::{collection::vec, prelude::*};
+    use serde_json::Value;
+    use std::convert::TryFrom;
     use std::path::Path;
-    use testing::{encrypt_aead, rsa_import_pair, rsa_oaep_encrypt};
+    use testing::{
+        encrypt_aead, rsa_import_pair, rsa_oaep_encrypt, x25519_wrap,
+    };
+
+    // Load the vectors generated with the Python implementation
+    // (keylime/crypto.py) from test-data/crypto-vectors.json
+    fn load_vectors(kind: &str) -> Vec<Value> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("crypto-vectors.json");
+        let contents = fs::read_to_string(path).unwrap(); //#[allow_ci]
+        let vectors: Value = serde_json::from_str(&contents).unwrap(); //#[allow_ci]
+        vectors[kind].as_array().unwrap().to_vec() //#[allow_ci]
+    }
+
+    fn hex_field(vector: &Value, field: &str) -> Vec<u8> {
+        hex::decode(vector[field].as_str().unwrap()).unwrap() //#[allow_ci]
+    }
+
+    fn aes_key() -> impl Strategy<Value = Vec<u8>> {
+        prop_oneof![
+            vec(any::<u8>(), AES_128_KEY_LEN),
+            vec(any::<u8>(), AES_256_KEY_LEN),
+        ]
+    }
 
     // compare with the result from python output
     #[test]
     fn test_compute_hmac() {
         let key = String::from("mysecret");
         let message = String::from("hellothere");
-        let mac =
-            compute_hmac(key.as_bytes(), message.as_bytes()).map(hex::encode);
+        let mac = compute_hmac(
+            HmacAlgorithm::Sha384,
+            key.as_bytes(),
+            message.as_bytes(),
+        )
+        .map(hex::encode);
         assert_eq!(
             format!(
                 "{}{}",
@@ -379,6 +735,34 @@ mod tests {
             ),
             mac.unwrap() //#[allow_ci]
         );
+        let mac = compute_hmac(
+            HmacAlgorithm::Sha256,
+            key.as_bytes(),
+            message.as_bytes(),
+        )
+        .map(hex::encode);
+        assert_eq!(
+            "54641c220fd9b77f2a20e0977d13ffcb297b801b2eaf958c58b7e5370aa7abc2",
+            mac.unwrap() //#[allow_ci]
+        );
+        assert!(verify_hmac(
+            HmacAlgorithm::Sha512,
+            key.as_bytes(),
+            message.as_bytes(),
+            &hex::decode("54641c220fd9b77f2a20e0977d13ffcb297b801b2eaf958c58b7e5370aa7abc2").unwrap() //#[allow_ci]
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "sm")]
+    #[test]
+    fn test_sm3_digest() {
+        // Example 1 of GB/T 32905-2016
+        let digest = sm3_digest(b"abc").unwrap(); //#[allow_ci]
+        assert_eq!(
+            hex::encode(digest),
+            "66c7f0f462eeedd9d1f2d46bdc10e4e24167c4875cf2f7a2297da02b8f4ba8e0"
+        );
     }
 
     // Test KDF to ensure derived password matches result derived from Python
@@ -513,6 +897,79 @@ mod tests {
         assert!(matches!(result, Err(Error::InvalidRequest)));
     }
 
+    #[test]
+    fn test_decrypt_aead_iv() {
+        let key = b"0123456789012345";
+        let iv = b"ABCDEFGHIJKL";
+        let mut tag = [0u8; AES_BLOCK_SIZE];
+        let ciphertext = openssl::symm::encrypt_aead(
+            Cipher::aes_128_gcm(),
+            key,
+            Some(iv),
+            &[],
+            b"payload",
+            &mut tag,
+        )
+        .unwrap(); //#[allow_ci]
+        let data = [&iv[..], &ciphertext, &tag].concat();
+        assert_eq!(
+            decrypt_aead_iv(key, &data, iv.len()).unwrap(), //#[allow_ci]
+            b"payload"
+        );
+        assert!(decrypt_aead(key, &data).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_cbc_hmac() {
+        let key = b"01234567890123450123456789012345";
+        let iv = b"ABCDEFGHIJKLMNOP";
+        let ciphertext = openssl::symm::encrypt(
+            Cipher::aes_256_cbc(),
+            key,
+            Some(iv),
+            b"test string, longer than the block size",
+        )
+        .unwrap(); //#[allow_ci]
+        let signed = [&iv[..], &ciphertext].concat();
+        let hmac = compute_hmac(HmacAlgorithm::Sha384, key, &signed).unwrap(); //#[allow_ci]
+        let mut data = [&signed[..], &hmac].concat();
+        assert_eq!(
+            decrypt_cbc_hmac(key, &data).unwrap(), //#[allow_ci]
+            b"test string, longer than the block size"
+        );
+        assert!(decrypt_aead(key, &data).is_err());
+
+        data[AES_BLOCK_SIZE] ^= 1;
+        assert!(decrypt_cbc_hmac(key, &data).is_err());
+        assert!(matches!(
+            decrypt_cbc_hmac(key, &data[..AES_BLOCK_SIZE]),
+            Err(Error::InvalidRequest)
+        ));
+    }
+
+    #[test]
+    fn test_x25519_unwrap() {
+        let key = x25519_generate().unwrap(); //#[allow_ci]
+        let public = key.raw_public_key().unwrap(); //#[allow_ci]
+        let mut wrapped = x25519_wrap(&public, b"0123456789012345").unwrap(); //#[allow_ci]
+        assert_eq!(
+            x25519_unwrap(&key, &wrapped).unwrap(), //#[allow_ci]
+            b"0123456789012345"
+        );
+
+        // Not with another key
+        let other = x25519_generate().unwrap(); //#[allow_ci]
+        assert!(x25519_unwrap(&other, &wrapped).is_err());
+
+        let last = wrapped.len() - 1;
+        wrapped[last] ^= 1;
+        assert!(x25519_unwrap(&key, &wrapped).is_err());
+        assert!(matches!(
+            x25519_unwrap(&key, &wrapped[..X25519_KEY_LEN]),
+            Err(Error::InvalidRequest)
+        ));
+    }
+
     #[test]
     fn test_asym_verify() {
         // Import test keypair
@@ -537,4 +994,247 @@ mod tests {
 
         assert!(asym_verify(&public, &message, &signature).unwrap()) //#[allow_ci]
     }
+
+    #[test]
+    fn test_asym_sign() {
+        let (public, private) = rsa_import_pair(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data")
+                .join("test-rsa.pem"),
+        )
+        .unwrap(); //#[allow_ci]
+        let signature = asym_sign(&private, b"Hello World!").unwrap(); //#[allow_ci]
+        assert!(asym_verify(
+            &public,
+            "Hello World!",
+            &base64::encode(&signature)
+        )
+        .unwrap()); //#[allow_ci]
+        assert!(!asym_verify(
+            &public,
+            "Hello World?",
+            &base64::encode(&signature)
+        )
+        .unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_aead_python_vectors() {
+        for vector in load_vectors("aead") {
+            let key = hex_field(&vector, "key");
+            let iv = hex_field(&vector, "iv");
+            let plaintext = hex_field(&vector, "plaintext");
+            let ciphertext = hex_field(&vector, "ciphertext");
+
+            let encrypted = encrypt_aead(&key, &iv, &plaintext)
+                .expect("unable to encrypt");
+            assert_eq!(encrypted, ciphertext);
+
+            let decrypted =
+                decrypt_aead(&key, &ciphertext).expect("unable to decrypt");
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn test_hmac_python_vectors() {
+        for vector in load_vectors("hmac") {
+            let key = hex_field(&vector, "key");
+            let data = hex_field(&vector, "data");
+            let expected = hex_field(&vector, "hmac");
+
+            let mac = compute_hmac(HmacAlgorithm::Sha384, &key, &data)
+                .expect("unable to compute");
+            assert_eq!(mac, expected);
+            assert!(verify_hmac(
+                HmacAlgorithm::Sha384,
+                &key,
+                &data,
+                &expected
+            )
+            .is_ok());
+        }
+    }
+
+    #[test]
+    fn test_generate_mtls_identity() {
+        let (_, key) = rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let (_, other_key) = rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        let ca = generate_x509(&other_key, "ca").unwrap(); //#[allow_ci]
+
+        let options = TlsOptions::from(&KeylimeConfig::default());
+
+        assert!(
+            generate_mtls_identity(&cert, &[], &key, &ca, options).is_ok()
+        );
+        assert!(generate_mtls_identity(
+            &cert,
+            &[ca.clone()],
+            &key,
+            &ca,
+            options
+        )
+        .is_ok());
+        // The key must match the certificate
+        assert!(generate_mtls_identity(&cert, &[], &other_key, &ca, options)
+            .is_err());
+    }
+
+    #[test]
+    fn test_generate_csr() {
+        let (public, key) = rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let csr = generate_csr(&key, "uuid").unwrap(); //#[allow_ci]
+        assert!(csr.verify(&public).unwrap()); //#[allow_ci]
+        assert!(csr.public_key().unwrap().public_eq(&public)); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_select_alpn() {
+        let both = b"\x08http/1.1\x02h2";
+        assert_eq!(select_alpn(true, both), Some(&b"h2"[..]));
+        assert_eq!(select_alpn(false, both), Some(&b"http/1.1"[..]));
+        assert_eq!(
+            select_alpn(true, b"\x08http/1.1"),
+            Some(&b"http/1.1"[..])
+        );
+        assert_eq!(select_alpn(false, b"\x02h2"), None);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_aead_round_trip(
+            key in aes_key(),
+            iv in vec(any::<u8>(), AES_BLOCK_SIZE),
+            plaintext in vec(any::<u8>(), 0..4096),
+        ) {
+            let ciphertext = encrypt_aead(&key, &iv, &plaintext).unwrap(); //#[allow_ci]
+            prop_assert_eq!(
+                ciphertext.len(),
+                plaintext.len() + 2 * AES_BLOCK_SIZE
+            );
+            let decrypted = decrypt_aead(&key, &ciphertext).unwrap(); //#[allow_ci]
+            prop_assert_eq!(decrypted, plaintext);
+        }
+
+        #[test]
+        fn prop_aead_tampered_ciphertext(
+            key in aes_key(),
+            iv in vec(any::<u8>(), AES_BLOCK_SIZE),
+            plaintext in vec(any::<u8>(), 1..1024),
+            idx in any::<prop::sample::Index>(),
+        ) {
+            let mut ciphertext =
+                encrypt_aead(&key, &iv, &plaintext).unwrap(); //#[allow_ci]
+            // Flip one bit anywhere in iv || ciphertext || tag
+            let pos = idx.index(ciphertext.len());
+            ciphertext[pos] ^= 0x01;
+            prop_assert!(decrypt_aead(&key, &ciphertext).is_err());
+        }
+
+        #[test]
+        fn prop_aead_short_input(
+            key in aes_key(),
+            data in vec(any::<u8>(), 0..(2 * AES_BLOCK_SIZE)),
+        ) {
+            prop_assert!(matches!(
+                decrypt_aead(&key, &data),
+                Err(Error::InvalidRequest)
+            ));
+        }
+
+        #[test]
+        fn prop_hmac_round_trip(
+            key in vec(any::<u8>(), 1..256),
+            data in vec(any::<u8>(), 0..4096),
+        ) {
+            let mac = compute_hmac(HmacAlgorithm::Sha384, &key, &data).unwrap(); //#[allow_ci]
+            prop_assert_eq!(mac.len(), 48);
+            prop_assert!(verify_hmac(HmacAlgorithm::Sha384, &key, &data, &mac).is_ok());
+        }
+
+        #[test]
+        fn prop_hmac_wrong_data(
+            key in vec(any::<u8>(), 1..256),
+            data in vec(any::<u8>(), 1..1024),
+            idx in any::<prop::sample::Index>(),
+        ) {
+            let mac = compute_hmac(HmacAlgorithm::Sha384, &key, &data).unwrap(); //#[allow_ci]
+            let mut other = data.clone();
+            let pos = idx.index(other.len());
+            other[pos] ^= 0x01;
+            prop_assert!(verify_hmac(HmacAlgorithm::Sha384, &key, &other, &mac).is_err());
+        }
+
+        #[test]
+        fn prop_symm_key_xor(
+            (u, v) in prop_oneof![
+                (vec(any::<u8>(), AES_128_KEY_LEN),
+                 vec(any::<u8>(), AES_128_KEY_LEN)),
+                (vec(any::<u8>(), AES_256_KEY_LEN),
+                 vec(any::<u8>(), AES_256_KEY_LEN)),
+            ],
+        ) {
+            let u = SymmKey::try_from(u.as_slice()).unwrap(); //#[allow_ci]
+            let v = SymmKey::try_from(v.as_slice()).unwrap(); //#[allow_ci]
+            let k = u.xor(&v).unwrap(); //#[allow_ci]
+            // K = U xor V, so U = K xor V and V = K xor U
+            prop_assert_eq!(k.xor(&v).unwrap().bytes(), u.bytes()); //#[allow_ci]
+            prop_assert_eq!(k.xor(&u).unwrap().bytes(), v.bytes()); //#[allow_ci]
+            prop_assert_eq!(
+                u.xor(&v).unwrap().bytes(), //#[allow_ci]
+                v.xor(&u).unwrap().bytes() //#[allow_ci]
+            );
+        }
+
+        #[test]
+        fn prop_symm_key_invalid_length(
+            key in vec(any::<u8>(), 0..64)
+                .prop_filter("valid AES key length", |k| {
+                    k.len() != AES_128_KEY_LEN && k.len() != AES_256_KEY_LEN
+                }),
+        ) {
+            prop_assert!(SymmKey::try_from(key.as_slice()).is_err());
+        }
+    }
+
+    proptest! {
+        // Certificate generation is expensive, keep the number of cases low
+        #![proptest_config(ProptestConfig::with_cases(8))]
+
+        #[test]
+        fn prop_x509_generate_load(uuid in "[a-zA-Z0-9-]{1,64}") {
+            let rsa_key_path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data")
+                .join("test-rsa.pem");
+            let (pub_key, priv_key) = rsa_import_pair(&rsa_key_path)
+                .expect("unable to import RSA key pair");
+
+            let cert = generate_x509(&priv_key, &uuid).unwrap(); //#[allow_ci]
+
+            let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+            let cert_path = dir.path().join("cert.pem");
+            fs::write(&cert_path, cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+
+            let loaded = load_x509(&cert_path).unwrap(); //#[allow_ci]
+            prop_assert_eq!(
+                loaded.to_der().unwrap(), //#[allow_ci]
+                cert.to_der().unwrap() //#[allow_ci]
+            );
+            prop_assert!(loaded.public_key().unwrap().public_eq(&pub_key)); //#[allow_ci]
+
+            let cn = loaded
+                .subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .next()
+                .unwrap() //#[allow_ci]
+                .data()
+                .as_utf8()
+                .unwrap() //#[allow_ci]
+                .to_string();
+            prop_assert_eq!(cn, uuid);
+        }
+    }
 }
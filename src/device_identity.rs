@@ -0,0 +1,205 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Device identity of the TCG IDevID/IAK provisioning.
+//
+// Manufacturers following the TCG "TPM 2.0 Keys for Device Identity and
+// Attestation" specification provision an IAK, a restricted signing key
+// that can quote, and an IDevID identifying the device, and issue a
+// certificate for each. When 'enable_iak_idevid' is set, both keys are
+// loaded from 'iak_handle' and 'idevid_handle' or created again from the
+// default templates (see tpm::DeviceKey), and their public parts and
+// certificates are sent to the registrar along the EK and the AK. Verifiers
+// can then ask for quotes co-signed with the IAK, tying the attested PCRs to
+// the device certified by the manufacturer.
+
+use crate::{
+    algorithms::EncryptionAlgorithm,
+    common::KeylimeConfig,
+    ek_cert,
+    error::{Error, Result},
+    tpm::{self, DeviceKey, HandleRegistry},
+};
+use log::*;
+use openssl::pkey::{PKey, PKeyRef, Public};
+use picky_asn1_x509::SubjectPublicKeyInfo;
+use std::{convert::TryFrom, fs, path::Path};
+use tss_esapi::{
+    handles::KeyHandle, structures::PublicBuffer, traits::Marshall, Context,
+};
+
+// The IAK and IDevID in use, with their public parts marshalled as
+// TPM2B_PUBLIC and their certificates in DER
+#[derive(Clone, Debug)]
+pub(crate) struct DeviceIdentity {
+    pub iak_handle: KeyHandle,
+    pub iak_tpm: Vec<u8>,
+    pub iak_cert: Option<Vec<u8>>,
+    pub idevid_tpm: Vec<u8>,
+    pub idevid_cert: Option<Vec<u8>>,
+}
+
+fn public_key(public: tss_esapi::structures::Public) -> Result<PKey<Public>> {
+    let key = SubjectPublicKeyInfo::try_from(public)?;
+    let key_der = picky_asn1_der::to_vec(&key)?;
+    Ok(PKey::public_key_from_der(&key_der)?)
+}
+
+// The certificate is registered in DER, it can be stored in PEM or DER. It
+// has to certify the key loaded from the TPM, a certificate of another
+// device would be rejected by verifiers only much later.
+fn parse_cert(
+    data: &[u8],
+    alg: EncryptionAlgorithm,
+    key: &PKeyRef<Public>,
+) -> Result<Vec<u8>> {
+    let der = ek_cert::parse_cert(data, alg)?;
+    let cert = openssl::x509::X509::from_der(&der)?;
+    if !cert.public_key()?.public_eq(key) {
+        return Err(Error::Other(
+            "the certificate does not certify the key of the TPM".to_string(),
+        ));
+    }
+    Ok(der)
+}
+
+fn read_cert(
+    path: Option<&str>,
+    device_key: DeviceKey,
+    alg: EncryptionAlgorithm,
+    public: tss_esapi::structures::Public,
+) -> Result<Option<Vec<u8>>> {
+    let path = match path {
+        Some(path) => path,
+        None => {
+            warn!("No {} certificate configured", device_key.name());
+            return Ok(None);
+        }
+    };
+    let key = public_key(public)?;
+    let cert = fs::read(Path::new(path))
+        .map_err(Error::from)
+        .and_then(|data| parse_cert(&data, alg, &key))
+        .map_err(|e| {
+            Error::Configuration(format!(
+                "Unable to use the {} certificate {}: {}",
+                device_key.name(),
+                path,
+                e
+            ))
+        })?;
+    info!("Using the {} certificate from {}", device_key.name(), path);
+    Ok(Some(cert))
+}
+
+/*
+ * Input: Connection context, handle registry and agent configuration
+ * Return: The IAK and IDevID to register
+ *
+ * The IAK stays loaded for co-signing quotes, the IDevID is only needed for
+ * its public part and is flushed unless it is persistent.
+ */
+pub(crate) fn load(
+    ctx: &mut Context,
+    handles: &HandleRegistry,
+    config: &KeylimeConfig,
+) -> Result<DeviceIdentity> {
+    let alg = config.iak_idevid_alg;
+    let (iak_handle, iak_public) = tpm::load_device_key(
+        ctx,
+        handles,
+        DeviceKey::Iak,
+        alg,
+        config.iak_handle.as_deref(),
+    )?;
+    let (idevid_handle, idevid_public) = tpm::load_device_key(
+        ctx,
+        handles,
+        DeviceKey::IDevId,
+        alg,
+        config.idevid_handle.as_deref(),
+    )?;
+    if config.idevid_handle.is_none() {
+        ctx.flush_context(idevid_handle.into())?;
+        handles.release(idevid_handle.into());
+    }
+
+    let iak_cert = read_cert(
+        config.iak_cert_path.as_deref(),
+        DeviceKey::Iak,
+        alg,
+        iak_public.clone(),
+    )?;
+    let idevid_cert = read_cert(
+        config.idevid_cert_path.as_deref(),
+        DeviceKey::IDevId,
+        alg,
+        idevid_public.clone(),
+    )?;
+    info!("Loaded the IAK and IDevID for device identity");
+
+    Ok(DeviceIdentity {
+        iak_handle,
+        iak_tpm: PublicBuffer::try_from(iak_public)?.marshall()?,
+        iak_cert,
+        idevid_tpm: PublicBuffer::try_from(idevid_public)?.marshall()?,
+        idevid_cert,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+
+    #[test]
+    fn test_parse_cert() {
+        let (pub_key, priv_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&priv_key, "uuid").unwrap(); //#[allow_ci]
+        let der = cert.to_der().unwrap(); //#[allow_ci]
+        let pem = cert.to_pem().unwrap(); //#[allow_ci]
+        let rsa = EncryptionAlgorithm::Rsa;
+        assert_eq!(parse_cert(&der, rsa, &pub_key).unwrap(), der); //#[allow_ci]
+        assert_eq!(parse_cert(&pem, rsa, &pub_key).unwrap(), der); //#[allow_ci]
+
+        // The certificate of another key is refused
+        let (other_key, _) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        assert!(parse_cert(&der, rsa, &other_key).is_err());
+        assert!(parse_cert(&der, EncryptionAlgorithm::Ecc, &pub_key).is_err());
+    }
+
+    #[test]
+    fn test_templates() {
+        for alg in [EncryptionAlgorithm::Rsa, EncryptionAlgorithm::Ecc] {
+            let iak = DeviceKey::Iak.template(alg).unwrap(); //#[allow_ci]
+            let idevid = DeviceKey::IDevId.template(alg).unwrap(); //#[allow_ci]
+
+            // Only the IAK can quote
+            assert!(iak.object_attributes().restricted());
+            assert!(!idevid.object_attributes().restricted());
+            assert!(iak.object_attributes().sign_encrypt());
+            assert!(idevid.object_attributes().sign_encrypt());
+            assert_ne!(iak.auth_policy(), idevid.auth_policy());
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_load_device_keys() {
+        let mut ctx = tpm::get_tpm2_ctx().unwrap(); //#[allow_ci]
+        let handles = HandleRegistry::default();
+        let config = KeylimeConfig::default();
+        let identity = load(&mut ctx, &handles, &config).unwrap(); //#[allow_ci]
+        assert!(identity.iak_cert.is_none());
+        assert_ne!(identity.iak_tpm, identity.idevid_tpm);
+
+        // Created again from the templates, the keys are the same
+        ctx.flush_context(identity.iak_handle.into()).unwrap(); //#[allow_ci]
+        handles.release(identity.iak_handle.into());
+        let again = load(&mut ctx, &handles, &config).unwrap(); //#[allow_ci]
+        assert_eq!(identity.iak_tpm, again.iak_tpm);
+        assert_eq!(identity.idevid_tpm, again.idevid_tpm);
+        ctx.flush_context(again.iak_handle.into()).unwrap(); //#[allow_ci]
+        handles.release(again.iak_handle.into());
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::error::{Error, Result};
+use log::*;
+use std::{
+    ffi::CString, io, mem::MaybeUninit, os::unix::ffi::OsStrExt, path::Path,
+    path::PathBuf, time::Duration,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DiskUsage {
+    pub total: u64,
+    pub available: u64,
+}
+
+impl DiskUsage {
+    // Percentage of the file system still available to unprivileged users
+    pub(crate) fn free_percent(&self) -> u64 {
+        if self.total == 0 {
+            return 0;
+        }
+        self.available.saturating_mul(100) / self.total
+    }
+}
+
+/*
+ * Input: path to any file or directory on the file system to check
+ * Return: Result wrap the total and available size of the file system in
+ *         bytes
+ *
+ * Uses statvfs(3). The available size is what an unprivileged process can
+ * still write, which is what matters once the agent dropped privileges.
+ */
+pub(crate) fn disk_usage(path: &Path) -> Result<DiskUsage> {
+    let path_cstr = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    if unsafe { libc::statvfs(path_cstr.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+    let stat = unsafe { stat.assume_init() };
+
+    let frsize = stat.f_frsize as u64;
+    Ok(DiskUsage {
+        total: (stat.f_blocks as u64).saturating_mul(frsize),
+        available: (stat.f_bavail as u64).saturating_mul(frsize),
+    })
+}
+
+// Fails with Error::DiskSpace if writing 'required' bytes under 'path' would
+// exhaust the file system, so that callers can refuse the write up front
+// instead of failing halfway through it.
+pub(crate) fn check_space(path: &Path, required: u64) -> Result<()> {
+    let usage = disk_usage(path)?;
+    if required > usage.available {
+        return Err(Error::DiskSpace(format!(
+            "{} bytes needed in {}, but only {} bytes available",
+            required,
+            path.display(),
+            usage.available
+        )));
+    }
+    Ok(())
+}
+
+fn report(path: &Path, warn_percent: u64) {
+    match disk_usage(path) {
+        Ok(usage) if usage.free_percent() < warn_percent => {
+            warn!(
+                "Low disk space on {}: {} of {} bytes available ({}%)",
+                path.display(),
+                usage.available,
+                usage.total,
+                usage.free_percent()
+            );
+        }
+        Ok(usage) => {
+            debug!(
+                "Disk space on {}: {} of {} bytes available ({}%)",
+                path.display(),
+                usage.available,
+                usage.total,
+                usage.free_percent()
+            );
+        }
+        Err(e) => {
+            warn!("Unable to check disk space on {}: {}", path.display(), e);
+        }
+    }
+}
+
+/*
+ * Input: directories to watch, free space percentage below which a warning
+ *        is logged, and the number of seconds between checks
+ *
+ * Periodically logs the free space of each directory. This never returns,
+ * so it should be spawned as a detached task.
+ */
+pub(crate) async fn monitor(
+    paths: Vec<PathBuf>,
+    warn_percent: u64,
+    interval: u64,
+) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        let _ = ticker.tick().await;
+        for path in &paths {
+            report(path, warn_percent);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_usage() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let usage = disk_usage(temp_dir.path()).unwrap(); //#[allow_ci]
+        assert!(usage.total > 0);
+        assert!(usage.available <= usage.total);
+        assert!(usage.free_percent() <= 100);
+
+        assert!(disk_usage(&temp_dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_check_space() {
+        let temp_dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(check_space(temp_dir.path(), 0).is_ok());
+        assert!(matches!(
+            check_space(temp_dir.path(), u64::MAX),
+            Err(Error::DiskSpace(_))
+        ));
+    }
+
+    #[test]
+    fn test_free_percent() {
+        let usage = DiskUsage {
+            total: 1000,
+            available: 95,
+        };
+        assert_eq!(usage.free_percent(), 9);
+
+        let usage = DiskUsage {
+            total: 0,
+            available: 0,
+        };
+        assert_eq!(usage.free_percent(), 0);
+    }
+}
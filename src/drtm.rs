@@ -0,0 +1,354 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Event log of the dynamic root of trust (DRTM).
+//
+// With Intel TXT, the measured launch extends PCRs 17 and 18 with the SINIT
+// ACM, the MLE and their policies, none of which is in the UEFI event log. The
+// log of the launch is read once at start, as it does not change until the
+// next launch and reading it may need privileges the agent drops, and sent
+// with the quotes covering PCR 17 or 18, alongside the UEFI log:
+//
+// - with TrenchBoot Secure Launch, the kernel exposes the DRTM event log in
+//   securityfs, in the TCG format of the UEFI log;
+// - with tboot, the log is only readable from the TXT heap, as printed by
+//   'txt-stat'. The events of PCRs 17 and 18 are taken from its output and
+//   sent one by one.
+//
+// 'drtm_log' selects the source, "auto" takes the first one available.
+
+use crate::{
+    error::{Error, Result},
+    tpm,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fs, path::Path, process::Command};
+use tss_esapi::structures::PcrSlot;
+
+static SLAUNCH_EVENT_LOG: &str = "/sys/kernel/security/slaunch/eventlog";
+static TXT_STAT: &str = "txt-stat";
+// Printed by txt-stat when the platform booted through a measured launch
+static TXT_MEASURED_LAUNCH: &str = "TXT measured launch: TRUE";
+// PCRs extended by the measured launch
+const DRTM_PCRS: [u32; 2] = [17, 18];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum DrtmSource {
+    Auto,
+    // Secure Launch event log, in the TCG format
+    Slaunch,
+    // Output of txt-stat
+    Tboot,
+    Disabled,
+}
+
+impl TryFrom<&str> for DrtmSource {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(DrtmSource::Auto),
+            "slaunch" => Ok(DrtmSource::Slaunch),
+            "tboot" => Ok(DrtmSource::Tboot),
+            "none" => Ok(DrtmSource::Disabled),
+            _ => Err(Error::Configuration(format!(
+                "drtm_log must be one of 'auto', 'slaunch', 'tboot' or 'none', got {}",
+                value
+            ))),
+        }
+    }
+}
+
+// Event of the TXT event log
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DrtmEvent {
+    pub pcr: u32,
+    pub event_type: u32,
+    // e.g. "sha256"
+    pub hash_alg: String,
+    // Hex encoded
+    pub digest: String,
+    pub data: String,
+}
+
+// Sent with the quote as drtm_measurement_list
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct DrtmLog {
+    // Slaunch or Tboot, telling the verifier how to read the log
+    pub source: DrtmSource,
+    // Base64 encoded TCG event log, from Slaunch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_log: Option<String>,
+    // Events of PCRs 17 and 18, in the order of the log, from Tboot
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<DrtmEvent>,
+}
+
+fn read_slaunch(path: &Path) -> Result<DrtmLog> {
+    Ok(DrtmLog {
+        source: DrtmSource::Slaunch,
+        event_log: Some(base64::encode(fs::read(path)?)),
+        events: Vec::new(),
+    })
+}
+
+// Name of a TPM_ALG_ID of the log, or of the digest size without one
+fn hash_alg_name(alg: Option<u32>, digest: &str) -> String {
+    match (alg, digest.len() / 2) {
+        (Some(0x4), _) | (None, 20) => "sha1",
+        (Some(0xb), _) | (None, 32) => "sha256",
+        (Some(0xc), _) | (None, 48) => "sha384",
+        (Some(0xd), _) | (None, 64) => "sha512",
+        (Some(0x12), _) => "sm3_256",
+        _ => "unknown",
+    }
+    .to_string()
+}
+
+// Bytes printed by txt-stat, as "0a 1b 2c ", hex encoded
+fn hex_bytes(line: &str) -> Option<String> {
+    let mut hex = String::new();
+    for byte in line.split_whitespace() {
+        if byte.len() != 2 || !byte.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        hex.push_str(&byte.to_ascii_lowercase());
+    }
+    Some(hex)
+}
+
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+/*
+ * Input: output of txt-stat
+ * Return: the events of PCRs 17 and 18, None without a measured launch
+ *
+ * txt-stat prints each event of the log as
+ *     Event:
+ *         PCRIndex: 17
+ *             Type: 0x402
+ *           Digest: 0a 1b ...
+ *             Data: 32 bytes
+ *                   2c 3d ...
+ * with the algorithm of the log before its events, "Alg: 11" on TPM 2.0.
+ */
+fn parse_txt_stat(output: &str) -> Option<DrtmLog> {
+    if !output
+        .lines()
+        .any(|line| line.trim() == TXT_MEASURED_LAUNCH)
+    {
+        return None;
+    }
+
+    let mut alg = None;
+    let mut events = Vec::new();
+    let mut event: Option<DrtmEvent> = None;
+    let mut in_data = false;
+    for line in output.lines().map(str::trim) {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => {
+                if let (true, Some(event), Some(hex)) =
+                    (in_data, event.as_mut(), hex_bytes(line))
+                {
+                    event.data.push_str(&hex);
+                    continue;
+                }
+                in_data = false;
+                continue;
+            }
+        };
+        in_data = false;
+        match (key, event.as_mut()) {
+            ("Alg", _) => alg = parse_number(value),
+            ("Event", _) => {
+                events.extend(event.replace(DrtmEvent::default()))
+            }
+            ("PCRIndex", Some(event)) => {
+                event.pcr = parse_number(value).unwrap_or_default()
+            }
+            ("Type", Some(event)) => {
+                event.event_type = parse_number(value).unwrap_or_default()
+            }
+            ("Digest", Some(event)) => {
+                event.digest = hex_bytes(value).unwrap_or_default();
+                event.hash_alg = hash_alg_name(alg, &event.digest);
+            }
+            ("Data", Some(_)) => in_data = true,
+            _ => {}
+        }
+    }
+    events.extend(event);
+    events.retain(|event| DRTM_PCRS.contains(&event.pcr));
+
+    Some(DrtmLog {
+        source: DrtmSource::Tboot,
+        event_log: None,
+        events,
+    })
+}
+
+// None when the platform did not boot through a measured launch
+fn read_tboot() -> Result<Option<DrtmLog>> {
+    let output = Command::new(TXT_STAT).output()?;
+    if !output.status.success() {
+        return Err(Error::Other(format!(
+            "{} failed: {}",
+            TXT_STAT,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(parse_txt_stat(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/*
+ * Input: source of the log
+ * Return: the DRTM event log, None when there is none
+ *
+ * Failing to read the log from a configured source is only logged, quotes
+ * are then sent without it.
+ */
+pub(crate) fn read(source: DrtmSource) -> Option<DrtmLog> {
+    let slaunch = Path::new(SLAUNCH_EVENT_LOG);
+    let result = match source {
+        DrtmSource::Disabled => return None,
+        DrtmSource::Slaunch => read_slaunch(slaunch).map(Some),
+        DrtmSource::Tboot => read_tboot(),
+        DrtmSource::Auto if slaunch.exists() => {
+            read_slaunch(slaunch).map(Some)
+        }
+        // Most platforms have no measured launch, txt-stat is optional
+        DrtmSource::Auto => match read_tboot() {
+            Ok(log) => Ok(log),
+            Err(e) => {
+                debug!("No DRTM event log from {}: {}", TXT_STAT, e);
+                Ok(None)
+            }
+        },
+    };
+    match result {
+        Ok(Some(log)) => {
+            info!("Sending the DRTM event log from {:?}", log.source);
+            Some(log)
+        }
+        Ok(None) => {
+            if source != DrtmSource::Auto {
+                warn!("The platform did not boot through a TXT measured launch, no DRTM event log");
+            }
+            None
+        }
+        Err(e) => {
+            warn!("DRTM event log not available: {}", e);
+            None
+        }
+    }
+}
+
+// Whether the mask selects one of the PCRs of the measured launch
+pub(crate) fn requested(mask: &str) -> Result<bool> {
+    Ok(tpm::check_mask(mask, &PcrSlot::Slot17)?
+        || tpm::check_mask(mask, &PcrSlot::Slot18)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        assert_eq!(
+            DrtmSource::try_from("auto").unwrap(), //#[allow_ci]
+            DrtmSource::Auto
+        );
+        assert_eq!(
+            DrtmSource::try_from("none").unwrap(), //#[allow_ci]
+            DrtmSource::Disabled
+        );
+        assert!(DrtmSource::try_from("txt").is_err());
+    }
+
+    #[test]
+    fn test_read_slaunch() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("eventlog");
+        fs::write(&path, [0u8, 1, 2, 3]).unwrap(); //#[allow_ci]
+        let log = read_slaunch(&path).unwrap(); //#[allow_ci]
+        assert_eq!(log.source, DrtmSource::Slaunch);
+        assert_eq!(log.event_log.as_deref(), Some("AAECAw=="));
+        assert_eq!(
+            serde_json::to_value(&log).unwrap(), //#[allow_ci]
+            serde_json::json!({"source": "slaunch", "event_log": "AAECAw=="})
+        );
+    }
+
+    #[test]
+    fn test_parse_txt_stat() {
+        let launched = "\
+Intel(r) TXT Configuration Registers:
+\tSTS: 0x0001c091
+\t    senter_done: TRUE
+\tTXT measured launch: TRUE
+\tsecrets flag set: TRUE
+\t\t\t Log Descrption:
+\t\t\t             Alg: 11
+\t\t\t Event:
+\t\t\t     PCRIndex: 17
+\t\t\t         Type: 0x402
+\t\t\t       Digest: 0a 1b 2c 3d 0a 1b 2c 3d 0a 1b 2c 3d 0a 1b 2c 3d 0a 1b 2c 3d 0a 1b 2c 3d 0a 1b 2c 3d 0a 1b 2c 3d 
+\t\t\t         Data: 18 bytes
+\t\t\t             00 01 02 03 04 05 06 07 08 09 0a 0b 0c 0d 0e 0f 
+\t\t\t             10 11 
+\t\t\t Event:
+\t\t\t     PCRIndex: 0
+\t\t\t         Type: 0x3
+\t\t\t       Digest: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 
+\t\t\t         Data: 0 bytes
+\t\t\t Event:
+\t\t\t     PCRIndex: 18
+\t\t\t         Type: 0x40a
+\t\t\t       Digest: ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff ff 
+\t\t\t         Data: 0 bytes
+";
+        let log = parse_txt_stat(launched).unwrap(); //#[allow_ci]
+        assert_eq!(log.source, DrtmSource::Tboot);
+        assert_eq!(log.event_log, None);
+        assert_eq!(log.events.len(), 2);
+        assert_eq!(
+            log.events[0],
+            DrtmEvent {
+                pcr: 17,
+                event_type: 0x402,
+                hash_alg: "sha256".to_string(),
+                digest: "0a1b2c3d".repeat(8),
+                data: "000102030405060708090a0b0c0d0e0f1011".to_string(),
+            }
+        );
+        assert_eq!(log.events[1].pcr, 18);
+        assert_eq!(log.events[1].event_type, 0x40a);
+        assert_eq!(log.events[1].digest, "ff".repeat(32));
+        assert_eq!(log.events[1].data, "");
+
+        assert!(parse_txt_stat("\tTXT measured launch: FALSE\n").is_none());
+    }
+
+    #[test]
+    fn test_hash_alg_name() {
+        assert_eq!(hash_alg_name(Some(0x4), ""), "sha1");
+        assert_eq!(hash_alg_name(None, &"00".repeat(48)), "sha384");
+        assert_eq!(hash_alg_name(Some(0x12), &"00".repeat(32)), "sm3_256");
+    }
+
+    #[test]
+    fn test_requested() {
+        assert!(requested("0x20000").unwrap()); //#[allow_ci]
+        assert!(requested("0x40001").unwrap()); //#[allow_ci]
+        assert!(!requested("0x408001").unwrap()); //#[allow_ci]
+    }
+}
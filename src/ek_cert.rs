@@ -0,0 +1,402 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Fallbacks for the EK certificate.
+//
+// The certificate is normally read from the NV index the TCG defines for it.
+// Some platforms do not provision it there but ship it as a file or as an
+// EFI variable instead. When the NV read finds nothing, the certificate is
+// taken from 'ek_cert_path' and then from the EFI variable 'ek_cert_efi_var',
+// rather than registering without one and failing the verifier policy. The
+// key of the certificate has to match the algorithm of the EK, RSA or ECC.
+//
+// The certificates of the intermediate CAs the TPM manufacturer stored in
+// the NV indices reserved for the EK certificate chain are read as well and
+// sent to the registrar. With 'tpm_cert_store' set, the agent also verifies
+// the chain up to one of the manufacturer CAs of the store and reports the
+// result to the registrar. A chain that fails verification is only reported,
+// the registrar and verifier policies decide whether to trust the EK.
+
+use crate::{
+    algorithms::EncryptionAlgorithm,
+    common::KeylimeConfig,
+    crypto,
+    error::{Error, Result},
+    tpm,
+};
+use log::*;
+use openssl::{pkey::Id, x509::X509};
+use std::{fs, path::Path};
+use tss_esapi::Context;
+
+// NV indices of the EK certificate chain, as in the TCG EK Credential Profile
+const EK_CHAIN_FIRST_INDEX: u32 = 0x01c00100;
+const EK_CHAIN_LAST_INDEX: u32 = 0x01c001ff;
+
+static EFIVARS_DIR: &str = "/sys/firmware/efi/efivars";
+// efivarfs prefixes the value of a variable with its attributes
+const EFI_VAR_ATTRIBUTES_LEN: usize = 4;
+
+// The certificate is registered in DER, it can be stored in PEM or DER. Also
+// used for the certificates of the IAK and IDevID, see device_identity.rs
+pub(crate) fn parse_cert(
+    data: &[u8],
+    alg: EncryptionAlgorithm,
+) -> Result<Vec<u8>> {
+    let cert = X509::from_pem(data).or_else(|_| X509::from_der(data))?;
+    let id = cert.public_key()?.id();
+    let expected = match alg {
+        EncryptionAlgorithm::Rsa => Id::RSA,
+        EncryptionAlgorithm::Ecc => Id::EC,
+    };
+    if id != expected {
+        return Err(Error::Other(format!(
+            "the certificate key is not a {} key like the TPM key",
+            alg
+        )));
+    }
+    Ok(cert.to_der()?)
+}
+
+fn read_file(path: &Path, alg: EncryptionAlgorithm) -> Result<Vec<u8>> {
+    parse_cert(&fs::read(path)?, alg)
+}
+
+fn read_efi_var(
+    dir: &Path,
+    name: &str,
+    alg: EncryptionAlgorithm,
+) -> Result<Vec<u8>> {
+    let data = fs::read(dir.join(name))?;
+    if data.len() <= EFI_VAR_ATTRIBUTES_LEN {
+        return Err(Error::Other(format!("EFI variable {} is empty", name)));
+    }
+    parse_cert(&data[EFI_VAR_ATTRIBUTES_LEN..], alg)
+}
+
+/*
+ * Input: agent configuration
+ * Return: the EK certificate in DER, None when no fallback provides it
+ */
+pub(crate) fn fallback(config: &KeylimeConfig) -> Option<Vec<u8>> {
+    if let Some(path) = &config.ek_cert_path {
+        match read_file(Path::new(path), config.enc_alg) {
+            Ok(cert) => {
+                info!("Using the EK certificate from {}", path);
+                return Some(cert);
+            }
+            Err(e) => warn!(
+                "Unable to read the EK certificate from {}: {}",
+                path, e
+            ),
+        }
+    }
+    if let Some(name) = &config.ek_cert_efi_var {
+        match read_efi_var(Path::new(EFIVARS_DIR), name, config.enc_alg) {
+            Ok(cert) => {
+                info!("Using the EK certificate from EFI variable {}", name);
+                return Some(cert);
+            }
+            Err(e) => warn!(
+                "Unable to read the EK certificate from EFI variable {}: {}",
+                name, e
+            ),
+        }
+    }
+    None
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct EkCertChain {
+    // Intermediate CA certificates in DER, in the order of the NV indices
+    pub certs: Vec<Vec<u8>>,
+    // Whether the EK certificate chains up to a CA of 'tpm_cert_store', None
+    // when not verified
+    pub valid: Option<bool>,
+}
+
+// Length of the DER structure at the start of the data, with its header
+fn der_len(data: &[u8]) -> Option<usize> {
+    let first = *data.get(1)?;
+    if first < 0x80 {
+        return Some(2 + first as usize);
+    }
+    let count = (first & 0x7f) as usize;
+    if count == 0 || count > 4 {
+        return None;
+    }
+    let len = data
+        .get(2..2 + count)?
+        .iter()
+        .fold(0usize, |len, &byte| len << 8 | byte as usize);
+    Some(2 + count + len)
+}
+
+// An NV index of the chain holds one or more DER certificates, the index may
+// be larger than them and padded
+fn split_der(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut certs = Vec::new();
+    let mut rest = data;
+    // Certificates are DER sequences
+    while rest.first() == Some(&0x30) {
+        let len = match der_len(rest) {
+            Some(len) if len <= rest.len() => len,
+            _ => {
+                return Err(Error::Other(
+                    "truncated certificate in the EK certificate chain"
+                        .to_string(),
+                ))
+            }
+        };
+        let _ = X509::from_der(&rest[..len])?;
+        certs.push(rest[..len].to_vec());
+        rest = &rest[len..];
+    }
+    Ok(certs)
+}
+
+fn read_chain(context: &mut Context) -> Vec<Vec<u8>> {
+    let indices = match tpm::nv_indices(
+        context,
+        EK_CHAIN_FIRST_INDEX,
+        EK_CHAIN_LAST_INDEX,
+    ) {
+        Ok(indices) => indices,
+        Err(e) => {
+            warn!("Unable to list the EK certificate chain indices: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut certs = Vec::new();
+    for index in indices {
+        match tpm::nv_read(context, index).and_then(|data| split_der(&data)) {
+            Ok(found) => certs.extend(found),
+            Err(e) => warn!(
+                "Unable to read the EK certificate chain from NV index {:#x}: {}",
+                index, e
+            ),
+        }
+    }
+    certs
+}
+
+/*
+ * Input: EK certificate in DER, intermediate CA certificates in DER and the
+ *        path of the manufacturer CA certificates
+ * Return: whether the EK certificate chains up to one of the CAs
+ */
+fn verify_chain(
+    cert: &[u8],
+    chain: &[Vec<u8>],
+    store: &Path,
+) -> Result<bool> {
+    let cert = X509::from_der(cert)?;
+    let intermediates = chain
+        .iter()
+        .map(|cert| X509::from_der(cert))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let trusted = crypto::load_ca_certs(store)?;
+    if trusted.is_empty() {
+        return Err(Error::Configuration(format!(
+            "no CA certificate found in tpm_cert_store {}",
+            store.display()
+        )));
+    }
+    crypto::verify_cert_chain(&cert, &intermediates, trusted)
+}
+
+/*
+ * Input: Connection context, EK certificate in DER if any and the agent
+ *        configuration
+ * Return: the EK certificate chain read from the NV indices, and whether it
+ *         is valid when 'tpm_cert_store' is set
+ */
+pub(crate) fn chain(
+    context: &mut Context,
+    ek_cert: Option<&[u8]>,
+    config: &KeylimeConfig,
+) -> EkCertChain {
+    let certs = read_chain(context);
+    if !certs.is_empty() {
+        info!(
+            "Found {} certificates of the EK certificate chain in the TPM NV",
+            certs.len()
+        );
+    }
+    let valid = match (ek_cert, &config.tpm_cert_store) {
+        (Some(cert), Some(store)) => {
+            match verify_chain(cert, &certs, Path::new(store)) {
+                Ok(true) => {
+                    info!("EK certificate verified against {}", store);
+                    Some(true)
+                }
+                Ok(false) => {
+                    warn!(
+                        "EK certificate does not chain up to a CA of {}",
+                        store
+                    );
+                    Some(false)
+                }
+                Err(e) => {
+                    warn!("Unable to verify the EK certificate: {}", e);
+                    Some(false)
+                }
+            }
+        }
+        _ => None,
+    };
+    EkCertChain { certs, valid }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        nid::Nid,
+        pkey::{PKey, Private},
+        x509::{
+            extension::{BasicConstraints, KeyUsage},
+            X509Name,
+        },
+    };
+
+    fn test_cert() -> X509 {
+        let (_, priv_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        crypto::generate_x509(&priv_key, "uuid").unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn test_parse_cert() {
+        let cert = test_cert();
+        let der = cert.to_der().unwrap(); //#[allow_ci]
+        let pem = cert.to_pem().unwrap(); //#[allow_ci]
+        let rsa = EncryptionAlgorithm::Rsa;
+        assert_eq!(parse_cert(&der, rsa).unwrap(), der); //#[allow_ci]
+        assert_eq!(parse_cert(&pem, rsa).unwrap(), der); //#[allow_ci]
+        assert!(parse_cert(b"not a certificate", rsa).is_err());
+
+        // The certificate of an ECC EK has an EC key
+        assert!(parse_cert(&der, EncryptionAlgorithm::Ecc).is_err());
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap(); //#[allow_ci]
+        let key = EcKey::generate(&group).unwrap(); //#[allow_ci]
+        let key = PKey::from_ec_key(key).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        let der = cert.to_der().unwrap(); //#[allow_ci]
+        let ecc = EncryptionAlgorithm::Ecc;
+        assert_eq!(parse_cert(&der, ecc).unwrap(), der); //#[allow_ci]
+        assert!(parse_cert(&der, rsa).is_err());
+    }
+
+    #[test]
+    fn test_read_efi_var() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let der = test_cert().to_der().unwrap(); //#[allow_ci]
+        let mut data = vec![0x07, 0, 0, 0];
+        data.extend(&der);
+        let efivars = dir.path();
+        fs::write(efivars.join("EkCert-guid"), &data).unwrap(); //#[allow_ci]
+        fs::write(efivars.join("Empty-guid"), &data[..4]).unwrap(); //#[allow_ci]
+
+        let rsa = EncryptionAlgorithm::Rsa;
+        let cert = read_efi_var(efivars, "EkCert-guid", rsa).unwrap(); //#[allow_ci]
+        assert_eq!(cert, der);
+        assert!(read_efi_var(efivars, "Empty-guid", rsa).is_err());
+        assert!(read_efi_var(efivars, "Missing-guid", rsa).is_err());
+    }
+
+    #[test]
+    fn test_fallback() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("ek.crt");
+        let cert = test_cert();
+        fs::write(&path, cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+
+        let mut config = KeylimeConfig::default();
+        assert_eq!(fallback(&config), None);
+
+        config.ek_cert_path = Some(path.display().to_string());
+        assert_eq!(fallback(&config), cert.to_der().ok());
+    }
+
+    // Certificate of the key signed by the issuer, self-signed without one
+    fn issue(
+        name: &str,
+        key: &PKey<Private>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+        ca: bool,
+    ) -> X509 {
+        let mut subject = X509Name::builder().unwrap(); //#[allow_ci]
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap(); //#[allow_ci]
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_version(2).unwrap(); //#[allow_ci]
+        builder.set_subject_name(&subject).unwrap(); //#[allow_ci]
+        let (issuer_name, signer) = match issuer {
+            Some((cert, key)) => (cert.subject_name(), key),
+            None => (&*subject, key),
+        };
+        builder.set_issuer_name(issuer_name).unwrap(); //#[allow_ci]
+        let now = Asn1Time::days_from_now(0).unwrap(); //#[allow_ci]
+        let later = Asn1Time::days_from_now(1).unwrap(); //#[allow_ci]
+        builder.set_not_before(&now).unwrap(); //#[allow_ci]
+        builder.set_not_after(&later).unwrap(); //#[allow_ci]
+        builder.set_pubkey(key).unwrap(); //#[allow_ci]
+        if ca {
+            let constraints = BasicConstraints::new().critical().ca().build();
+            builder.append_extension(constraints.unwrap()).unwrap(); //#[allow_ci]
+            let usage = KeyUsage::new().key_cert_sign().build();
+            builder.append_extension(usage.unwrap()).unwrap(); //#[allow_ci]
+        }
+        builder.sign(signer, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        builder.build()
+    }
+
+    #[test]
+    fn test_split_der() {
+        let first = test_cert().to_der().unwrap(); //#[allow_ci]
+        let second = test_cert().to_der().unwrap(); //#[allow_ci]
+        let mut data = first.clone();
+        data.extend(&second);
+        data.extend(&[0xff; 16]);
+        assert_eq!(split_der(&data).unwrap(), vec![first.clone(), second]); //#[allow_ci]
+        assert!(split_der(&[0xff; 16]).unwrap().is_empty()); //#[allow_ci]
+        assert!(split_der(&first[..first.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let root_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let root = issue("root", &root_key, None, true);
+        let ca_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let ca =
+            issue("intermediate", &ca_key, Some((&root, &root_key)), true);
+        let ek_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let ek = issue("ek", &ek_key, Some((&ca, &ca_key)), false);
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let root_path = dir.path().join("root.pem");
+        fs::write(root_path, root.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        fs::write(dir.path().join("README"), "manufacturer CAs").unwrap(); //#[allow_ci]
+
+        let ek = ek.to_der().unwrap(); //#[allow_ci]
+        let chain = vec![ca.to_der().unwrap()]; //#[allow_ci]
+        assert!(verify_chain(&ek, &chain, dir.path()).unwrap()); //#[allow_ci]
+
+        // The intermediate CA is needed
+        assert!(!verify_chain(&ek, &[], dir.path()).unwrap()); //#[allow_ci]
+
+        // Other manufacturer
+        let other_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let other = issue("other", &other_key, None, true);
+        let other_path = dir.path().join("other.pem");
+        fs::write(&other_path, other.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        assert!(!verify_chain(&ek, &chain, &other_path).unwrap()); //#[allow_ci]
+
+        // A store without certificates is a configuration error
+        let empty = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(verify_chain(&ek, &chain, empty.path()).is_err());
+    }
+}
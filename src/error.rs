@@ -45,8 +45,30 @@ pub(crate) enum Error {
     #[error("Secure Mount error: {0})")]
     #[allow(unused)]
     SecureMount(String),
+    #[error("Insufficient disk space: {0}")]
+    DiskSpace(String),
+    #[error("Invalid payload chunk: {0}")]
+    PayloadChunk(String),
+    #[error("Invalid payload file: {0}")]
+    PayloadFile(String),
+    #[error("Payload archive refused: {0}")]
+    ExtractionLimit(crate::payload_limits::LimitExceeded),
+    #[error("Delivered key refused: {0}")]
+    KeyRejected(crate::keys_handler::KeyRejection),
     #[error("TPM in use")]
     TpmInUse,
+    #[error("TPM is in dictionary attack lockout ({0})")]
+    TpmLockout(tss_esapi::Error),
+    #[error("TPM authorization failed ({0})")]
+    TpmAuthFail(tss_esapi::Error),
+    #[error("TPM NV index is not initialized ({0})")]
+    TpmNvUninitialized(tss_esapi::Error),
+    #[error("TPM is out of object memory ({0})")]
+    TpmObjectMemory(tss_esapi::Error),
+    #[error("TPM is busy, it kept asking to retry the command ({0})")]
+    TpmRetry(tss_esapi::Error),
+    #[error("TPM operation timed out after {0} seconds")]
+    TpmTimeout(u64),
     #[error("UUID error")]
     Uuid(#[from] uuid::Error),
     #[error("Execution error: {0:?}, {1}")]
@@ -78,6 +100,8 @@ pub(crate) enum Error {
     Persist(#[from] tempfile::PersistError),
     #[error("Error joining threads: {0}")]
     Join(#[from] tokio::task::JoinError),
+    #[error("age decryption error: {0}")]
+    Age(#[from] age::DecryptError),
     #[error("Asn1DerError: {0}")]
     PickyAsn1(#[from] picky_asn1_der::Asn1DerError),
     #[error("{0}")]
@@ -107,6 +131,20 @@ impl Error {
         }
     }
 
+    // How the operator can fix known TPM failures, which are otherwise only
+    // reported as a TSS return code
+    pub(crate) fn remediation(&self) -> Option<&'static str> {
+        match self {
+            Error::TpmLockout(_) => Some("The TPM locked out authorizations after too many failures. Wait for the lockout recovery time to pass, or reset it with the lockout authorization, e.g. 'tpm2_dictionarylockout --clear-lockout'"),
+            Error::TpmAuthFail(_) => Some("Check 'tpm_ownerpassword' in keylime-agent.conf, repeated failures put the TPM in dictionary attack lockout"),
+            Error::TpmNvUninitialized(_) => Some("The NV index is defined but was never written, provision it (e.g. the EK certificate) before starting the agent"),
+            Error::TpmTimeout(_) => Some("The TPM did not answer in time, check its health, e.g. for thermal throttling, or raise 'tpm_operation_timeout' in keylime-agent.conf"),
+            Error::TpmRetry(_) => Some("The TPM is under contention or still running its self tests, raise 'tpm_retry_attempts' or 'tpm_retry_delay' in keylime-agent.conf if this persists"),
+            Error::TpmObjectMemory(_) => Some("Flush stale transient objects, e.g. with 'tpm2_flushcontext -t', and use the kernel resource manager (/dev/tpmrm0) or tpm2-abrmd so objects of other processes are swapped out"),
+            _ => None,
+        }
+    }
+
     pub(crate) fn stderr(&self) -> Result<String> {
         match self {
             Error::Execution(_, stderr) => Ok(stderr.to_owned()),
@@ -127,6 +165,17 @@ impl std::convert::TryFrom<std::process::Output> for Error {
     }
 }
 
+// The payload format errors keep the variants they had in crypto.rs
+impl From<crate::aead::AeadError> for Error {
+    fn from(err: crate::aead::AeadError) -> Self {
+        match err {
+            crate::aead::AeadError::TooShort => Error::InvalidRequest,
+            crate::aead::AeadError::Crypto(e) => Error::Crypto(e),
+            other => Error::Other(other.to_string()),
+        }
+    }
+}
+
 impl From<tss_esapi::Error> for Error {
     fn from(err: tss_esapi::Error) -> Self {
         let kind = if let Tss2Error(tss2_rc) = err {
@@ -134,10 +183,61 @@ impl From<tss_esapi::Error> for Error {
         } else {
             None
         };
-        let message = format!("{}", err);
-
-        Error::Tpm { err, kind, message }
+        match kind {
+            Some(Tss2ResponseCodeKind::Lockout) => Error::TpmLockout(err),
+            Some(Tss2ResponseCodeKind::AuthFail)
+            | Some(Tss2ResponseCodeKind::BadAuth) => Error::TpmAuthFail(err),
+            Some(Tss2ResponseCodeKind::NvUninitialized) => {
+                Error::TpmNvUninitialized(err)
+            }
+            Some(Tss2ResponseCodeKind::ObjectMemory) => {
+                Error::TpmObjectMemory(err)
+            }
+            Some(Tss2ResponseCodeKind::Retry)
+            | Some(Tss2ResponseCodeKind::Yielded)
+            | Some(Tss2ResponseCodeKind::Testing) => Error::TpmRetry(err),
+            _ => {
+                let message = format!("{}", err);
+                Error::Tpm { err, kind, message }
+            }
+        }
     }
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tss_esapi::constants::{response_code::Tss2ResponseCode, tss::*};
+
+    fn tpm_error(rc: u32) -> Error {
+        Error::from(Tss2Error(Tss2ResponseCode::from(rc)))
+    }
+
+    #[test]
+    fn test_tpm_error_mapping() {
+        assert!(matches!(tpm_error(TPM2_RC_LOCKOUT), Error::TpmLockout(_)));
+        assert!(matches!(
+            tpm_error(TPM2_RC_AUTH_FAIL),
+            Error::TpmAuthFail(_)
+        ));
+        assert!(matches!(
+            tpm_error(TPM2_RC_NV_UNINITIALIZED),
+            Error::TpmNvUninitialized(_)
+        ));
+        assert!(matches!(
+            tpm_error(TPM2_RC_OBJECT_MEMORY),
+            Error::TpmObjectMemory(_)
+        ));
+        for rc in [TPM2_RC_RETRY, TPM2_RC_YIELDED, TPM2_RC_TESTING] {
+            assert!(matches!(tpm_error(rc), Error::TpmRetry(_)));
+        }
+        assert!(tpm_error(TPM2_RC_LOCKOUT).remediation().is_some());
+
+        // Other failures keep the generic error without a hint
+        let other = tpm_error(TPM2_RC_VALUE);
+        assert!(matches!(other, Error::Tpm { .. }));
+        assert!(other.remediation().is_none());
+    }
+}
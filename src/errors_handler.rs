@@ -104,13 +104,13 @@ pub(crate) async fn keys_default(req: HttpRequest) -> impl Responder {
     match req.head().method {
         http::Method::GET => {
             error = 400;
-            message = "URI not supported, only /pubkey and /verify are supported for GET in /keys/ interface";
+            message = "URI not supported, only /ak, /pubkey and /verify are supported for GET in /keys/ interface";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
         http::Method::POST => {
             error = 400;
-            message = "URI not supported, only /ukey and /vkey are supported for POST in /keys/ interface";
+            message = "URI not supported, only /ak/activate, /ukey and /vkey are supported for POST in /keys/ interface";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
@@ -144,7 +144,7 @@ pub(crate) async fn quotes_default(req: HttpRequest) -> impl Responder {
     match req.head().method {
         http::Method::GET => {
             error = 400;
-            message = "URI not supported, only /identity and /integrity are supported for GET in /quotes/ interface";
+            message = "URI not supported, only /identity, /integrity and /certify are supported for GET in /quotes/ interface";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
@@ -167,6 +167,68 @@ pub(crate) async fn quotes_default(req: HttpRequest) -> impl Responder {
     response
 }
 
+pub(crate) async fn agent_default(req: HttpRequest) -> impl Responder {
+    let error;
+    let response;
+    let message;
+
+    match req.head().method {
+        http::Method::GET => {
+            error = 400;
+            message = "URI not supported, only /info, /sbom and /tpm/health are supported for GET in /agent/ interface";
+            response = HttpResponse::BadRequest()
+                .json(JsonWrapper::error(error, message));
+        }
+        _ => {
+            error = 405;
+            message = "Method is not supported in /agent/ interface";
+            response = HttpResponse::MethodNotAllowed()
+                .insert_header(http::header::Allow(vec![http::Method::GET]))
+                .json(JsonWrapper::error(error, message));
+        }
+    };
+
+    warn!(
+        "{} returning {} response. {}",
+        req.head().method,
+        error,
+        message
+    );
+
+    response
+}
+
+pub(crate) async fn nvram_default(req: HttpRequest) -> impl Responder {
+    let error;
+    let response;
+    let message;
+
+    match req.head().method {
+        http::Method::GET => {
+            error = 400;
+            message = "URI not supported, only /{index} is supported for GET in /nvram/ interface";
+            response = HttpResponse::BadRequest()
+                .json(JsonWrapper::error(error, message));
+        }
+        _ => {
+            error = 405;
+            message = "Method is not supported in /nvram/ interface";
+            response = HttpResponse::MethodNotAllowed()
+                .insert_header(http::header::Allow(vec![http::Method::GET]))
+                .json(JsonWrapper::error(error, message));
+        }
+    };
+
+    warn!(
+        "{} returning {} response. {}",
+        req.head().method,
+        error,
+        message
+    );
+
+    response
+}
+
 pub(crate) async fn notifications_default(
     req: HttpRequest,
 ) -> impl Responder {
@@ -338,6 +400,11 @@ mod tests {
         test_default(web::resource("/").to(quotes_default), "GET").await
     }
 
+    #[actix_rt::test]
+    async fn test_agent_default() {
+        test_default(web::resource("/").to(agent_default), "GET").await
+    }
+
     #[actix_rt::test]
     async fn test_notifications_default() {
         test_default(web::resource("/").to(notifications_default), "POST")
@@ -0,0 +1,401 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// C API of the agent, built as the keylime_agent_sys library.
+//
+// Host daemons written in C or C++ link with libkeylime_agent_sys and use the
+// attestation state of the agent running on the machine without going
+// through its HTTP API: the library is a client of the agent admin socket,
+// which has to be set with 'admin_socket'. It offers:
+//  - keylime_agent_status(), the status report of `keylime_agent status`
+//  - keylime_agent_identity_quote(), an identity quote, as served on
+//    /quotes/identity
+//  - keylime_agent_seal() and keylime_agent_unseal(), data encrypted under
+//    the payload key, which can only be unsealed while the agent is attested
+//    and not revoked, see vault.rs
+// All operations but the status are privileged, the calling process has to
+// run as root.
+//
+// The functions return 0 on success and -1 on failure, the reason of the
+// last failure in the calling thread is returned by keylime_agent_error().
+// Strings and buffers returned by the library are freed with
+// keylime_agent_string_free() and keylime_agent_buffer_free(). The
+// declarations are in dist/include/keylime_agent_sys.h.
+//
+// The protocol of the admin socket is the only interface between the library
+// and the agent, the library does not depend on the agent code so that its
+// ABI stays stable across agent versions. It only shares the self-contained
+// payload crypto and IMA measurement list reading of the agent, which are
+// public for the benchmarks, see benches/attestation.rs.
+//
+// With the 'python' feature, the library is also a Python module, see
+// python.rs.
+
+use serde_json::{json, Value};
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+    io::{Read, Write},
+    os::raw::{c_char, c_int},
+    os::unix::net::UnixStream,
+    ptr, slice,
+};
+
+#[doc(hidden)]
+pub mod aead;
+#[doc(hidden)]
+pub mod ima;
+#[cfg(feature = "python")]
+mod python;
+
+// Same limit as the agent, see admin_socket.rs
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+
+const OK: c_int = 0;
+const FAILED: c_int = -1;
+
+type Result<T> = std::result::Result<T, String>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_error(message: String) {
+    let message =
+        CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Runs the operation, recording its error for keylime_agent_error()
+fn run(operation: impl FnOnce() -> Result<()>) -> c_int {
+    match operation() {
+        Ok(()) => OK,
+        Err(e) => {
+            set_error(e);
+            FAILED
+        }
+    }
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char, name: &str) -> Result<&'a str> {
+    if arg.is_null() {
+        return Err(format!("{} is NULL", name));
+    }
+    CStr::from_ptr(arg)
+        .to_str()
+        .map_err(|e| format!("{} is not valid UTF-8: {}", name, e))
+}
+
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize) -> Result<&'a [u8]> {
+    if data.is_null() {
+        if len == 0 {
+            return Ok(&[]);
+        }
+        return Err("data is NULL".to_string());
+    }
+    Ok(slice::from_raw_parts(data, len))
+}
+
+/*
+ * Input: path of the admin socket and request
+ * Return: Result wrap the response of the agent
+ */
+fn request(socket: &str, request: &Value) -> Result<Value> {
+    let exchange = || -> std::io::Result<Vec<u8>> {
+        let mut stream = UnixStream::connect(socket)?;
+        let out = serde_json::to_vec(request)?;
+        stream.write_all(&(out.len() as u32).to_be_bytes())?;
+        stream.write_all(&out)?;
+
+        let mut len = [0u8; 4];
+        stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len);
+        if len > MAX_FRAME_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("response of {} bytes is too large", len),
+            ));
+        }
+        let mut buf = vec![0u8; len as usize];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    };
+    let response = exchange().map_err(|e| {
+        format!("admin request to the agent on {} failed: {}", socket, e)
+    })?;
+    let mut response: Value = serde_json::from_slice(&response)
+        .map_err(|e| format!("invalid response of the agent: {}", e))?;
+    if response["result"] == "error" {
+        return Err(format!(
+            "the agent refused the request: {}",
+            response["message"].as_str().unwrap_or_default()
+        ));
+    }
+    if let Some(response) = response.as_object_mut() {
+        let _ = response.remove("result");
+    }
+    Ok(response)
+}
+
+fn data_field(response: &Value) -> Result<Vec<u8>> {
+    let data = response["data"]
+        .as_str()
+        .ok_or_else(|| "the response of the agent has no data".to_string())?;
+    base64::decode(data)
+        .map_err(|e| format!("invalid data in the response: {}", e))
+}
+
+// The operations, shared by the C API and the Python module
+
+fn status(socket: &str) -> Result<Value> {
+    request(socket, &json!({ "command": "status" }))
+}
+
+fn identity_quote(socket: &str, nonce: &str) -> Result<Value> {
+    let mut response =
+        request(socket, &json!({ "command": "quote", "nonce": nonce }))?;
+    Ok(response["quote"].take())
+}
+
+fn seal(socket: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let data = base64::encode(data);
+    let response =
+        request(socket, &json!({ "command": "seal", "data": data }))?;
+    data_field(&response)
+}
+
+fn unseal(socket: &str, sealed: &[u8]) -> Result<Vec<u8>> {
+    let sealed = base64::encode(sealed);
+    let response =
+        request(socket, &json!({ "command": "unseal", "data": sealed }))?;
+    data_field(&response)
+}
+
+fn string_out(out: *mut *mut c_char, value: &Value) -> Result<()> {
+    if out.is_null() {
+        return Err("output pointer is NULL".to_string());
+    }
+    let value = CString::new(value.to_string()).map_err(|e| e.to_string())?;
+    unsafe { *out = value.into_raw() };
+    Ok(())
+}
+
+fn bytes_out(
+    out: *mut *mut u8,
+    out_len: *mut usize,
+    data: Vec<u8>,
+) -> Result<()> {
+    if out.is_null() || out_len.is_null() {
+        return Err("output pointer is NULL".to_string());
+    }
+    let data = data.into_boxed_slice();
+    unsafe {
+        *out_len = data.len();
+        *out = Box::into_raw(data).cast();
+    }
+    Ok(())
+}
+
+/// Reason of the last failure in the calling thread, NULL if none. The
+/// string is owned by the library and valid until the next call.
+#[no_mangle]
+pub extern "C" fn keylime_agent_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Status report of the agent, as a JSON object in `status`.
+///
+/// # Safety
+///
+/// `socket` is a NUL terminated string and `status` a valid pointer.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_agent_status(
+    socket: *const c_char,
+    status: *mut *mut c_char,
+) -> c_int {
+    run(|| {
+        let socket = str_arg(socket, "socket")?;
+        string_out(status, &self::status(socket)?)
+    })
+}
+
+/// Identity quote for `nonce`, as a JSON object in `quote`.
+///
+/// # Safety
+///
+/// `socket` and `nonce` are NUL terminated strings and `quote` a valid
+/// pointer.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_agent_identity_quote(
+    socket: *const c_char,
+    nonce: *const c_char,
+    quote: *mut *mut c_char,
+) -> c_int {
+    run(|| {
+        let socket = str_arg(socket, "socket")?;
+        let nonce = str_arg(nonce, "nonce")?;
+        string_out(quote, &identity_quote(socket, nonce)?)
+    })
+}
+
+/// Seals `len` bytes of `data` under the payload key.
+///
+/// # Safety
+///
+/// `socket` is a NUL terminated string, `data` points to `len` bytes, and
+/// `sealed` and `sealed_len` are valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_agent_seal(
+    socket: *const c_char,
+    data: *const u8,
+    len: usize,
+    sealed: *mut *mut u8,
+    sealed_len: *mut usize,
+) -> c_int {
+    run(|| {
+        let socket = str_arg(socket, "socket")?;
+        let data = bytes_arg(data, len)?;
+        bytes_out(sealed, sealed_len, seal(socket, data)?)
+    })
+}
+
+/// Unseals `len` bytes of `sealed` returned by keylime_agent_seal().
+///
+/// # Safety
+///
+/// `socket` is a NUL terminated string, `sealed` points to `len` bytes, and
+/// `data` and `data_len` are valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_agent_unseal(
+    socket: *const c_char,
+    sealed: *const u8,
+    len: usize,
+    data: *mut *mut u8,
+    data_len: *mut usize,
+) -> c_int {
+    run(|| {
+        let socket = str_arg(socket, "socket")?;
+        let sealed = bytes_arg(sealed, len)?;
+        bytes_out(data, data_len, unseal(socket, sealed)?)
+    })
+}
+
+/// Frees a string returned by the library.
+///
+/// # Safety
+///
+/// `string` was returned by the library and is not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_agent_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Frees a buffer of `len` bytes returned by the library.
+///
+/// # Safety
+///
+/// `buffer` and `len` were returned by the library and the buffer is not
+/// used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn keylime_agent_buffer_free(
+    buffer: *mut u8,
+    len: usize,
+) {
+    if !buffer.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(buffer, len)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::net::UnixListener, thread};
+
+    // Answers a single admin request the way the agent does
+    fn fake_agent(
+        listener: UnixListener,
+        answer: impl FnOnce(Value) -> Value + Send + 'static,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap(); //#[allow_ci]
+            let mut len = [0u8; 4];
+            stream.read_exact(&mut len).unwrap(); //#[allow_ci]
+            let mut buf = vec![0u8; u32::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap(); //#[allow_ci]
+            let request = serde_json::from_slice(&buf).unwrap(); //#[allow_ci]
+            let out = serde_json::to_vec(&answer(request)).unwrap(); //#[allow_ci]
+            stream.write_all(&(out.len() as u32).to_be_bytes()).unwrap(); //#[allow_ci]
+            stream.write_all(&out).unwrap(); //#[allow_ci]
+        })
+    }
+
+    #[test]
+    fn test_seal() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent.sock");
+        let socket = CString::new(path.to_str().unwrap()).unwrap(); //#[allow_ci]
+
+        let listener = UnixListener::bind(&path).unwrap(); //#[allow_ci]
+        let agent = fake_agent(listener, |request| {
+            assert_eq!(request["command"], "seal");
+            json!({ "result": "sealed", "data": request["data"] })
+        });
+        let mut sealed = ptr::null_mut();
+        let mut sealed_len = 0;
+        let data = b"secret";
+        let result = unsafe {
+            keylime_agent_seal(
+                socket.as_ptr(),
+                data.as_ptr(),
+                data.len(),
+                &mut sealed,
+                &mut sealed_len,
+            )
+        };
+        agent.join().unwrap(); //#[allow_ci]
+        assert_eq!(result, OK);
+        assert_eq!(
+            unsafe { slice::from_raw_parts(sealed, sealed_len) },
+            data
+        );
+        unsafe { keylime_agent_buffer_free(sealed, sealed_len) };
+    }
+
+    #[test]
+    fn test_errors() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent.sock");
+        let socket = CString::new(path.to_str().unwrap()).unwrap(); //#[allow_ci]
+        let mut status = ptr::null_mut();
+
+        // No agent listening
+        let result =
+            unsafe { keylime_agent_status(socket.as_ptr(), &mut status) };
+        assert_eq!(result, FAILED);
+        assert!(!keylime_agent_error().is_null());
+
+        let listener = UnixListener::bind(&path).unwrap(); //#[allow_ci]
+        let agent = fake_agent(
+            listener,
+            |_| json!({ "result": "error", "message": "refused" }),
+        );
+        let nonce = CString::new("1234").unwrap(); //#[allow_ci]
+        let result = unsafe {
+            keylime_agent_identity_quote(
+                socket.as_ptr(),
+                nonce.as_ptr(),
+                &mut status,
+            )
+        };
+        agent.join().unwrap(); //#[allow_ci]
+        assert_eq!(result, FAILED);
+        let error = unsafe { CStr::from_ptr(keylime_agent_error()) };
+        assert!(error.to_str().unwrap().ends_with("refused")); //#[allow_ci]
+        assert!(status.is_null());
+    }
+}
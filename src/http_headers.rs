@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Headers added to the responses of the HTTP server.
+//
+// Browser-based tools, e.g. dashboards, can only query the agent directly
+// when their origin is listed in 'cors_allowed_origins': responses to them
+// then carry the CORS headers, and the preflight requests browsers send
+// before POST requests are answered here rather than by the handlers. The
+// standard security headers are added when 'security_headers' is enabled.
+// Both are disabled by default.
+
+use crate::common::KeylimeConfig;
+use actix_web::{
+    dev::ServiceRequest,
+    http::{
+        header::{self, HeaderMap, HeaderValue},
+        Method,
+    },
+    HttpResponse,
+};
+
+// How long browsers can cache the result of a preflight request, in seconds
+const PREFLIGHT_MAX_AGE: &str = "600";
+
+// One year, as recommended for HSTS
+const HSTS: &str = "max-age=31536000";
+
+#[derive(Clone, Debug)]
+pub(crate) struct HttpHeaders {
+    allowed_origins: Vec<String>,
+    security_headers: bool,
+    tls: bool,
+}
+
+impl HttpHeaders {
+    pub(crate) fn new(config: &KeylimeConfig, tls: bool) -> Self {
+        HttpHeaders {
+            allowed_origins: config.cors_allowed_origins.clone(),
+            security_headers: config.security_headers,
+            tls,
+        }
+    }
+
+    // The origin of the request, if it is allowed
+    pub(crate) fn allowed_origin(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<HeaderValue> {
+        let origin = req.headers().get(header::ORIGIN)?;
+        let value = origin.to_str().ok()?;
+        self.allowed_origins
+            .iter()
+            .any(|allowed| {
+                allowed == "*" || allowed.eq_ignore_ascii_case(value)
+            })
+            .then(|| origin.clone())
+    }
+
+    // Response to a CORS preflight request from an allowed origin, None for
+    // the other requests
+    pub(crate) fn preflight(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<HttpResponse> {
+        if req.method() != Method::OPTIONS
+            || !req
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let origin = self.allowed_origin(req)?;
+
+        let mut builder = HttpResponse::NoContent();
+        let _ = builder
+            .insert_header((
+                header::ACCESS_CONTROL_ALLOW_METHODS,
+                "GET, POST",
+            ))
+            .insert_header((
+                header::ACCESS_CONTROL_MAX_AGE,
+                PREFLIGHT_MAX_AGE,
+            ));
+        if let Some(requested) =
+            req.headers().get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        {
+            let _ = builder.insert_header((
+                header::ACCESS_CONTROL_ALLOW_HEADERS,
+                requested.clone(),
+            ));
+        }
+        let mut response = builder.finish();
+        self.apply(Some(origin), response.headers_mut());
+        Some(response)
+    }
+
+    // Adds the CORS headers for the allowed origin of the request, if any,
+    // and the security headers
+    pub(crate) fn apply(
+        &self,
+        origin: Option<HeaderValue>,
+        headers: &mut HeaderMap,
+    ) {
+        if let Some(origin) = origin {
+            let _ =
+                headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        if self.security_headers {
+            let _ = headers.insert(
+                header::X_CONTENT_TYPE_OPTIONS,
+                HeaderValue::from_static("nosniff"),
+            );
+            if self.tls {
+                let _ = headers.insert(
+                    header::STRICT_TRANSPORT_SECURITY,
+                    HeaderValue::from_static(HSTS),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test;
+
+    fn http_headers(origins: &[&str], security_headers: bool) -> HttpHeaders {
+        HttpHeaders {
+            allowed_origins: origins.iter().map(|o| o.to_string()).collect(),
+            security_headers,
+            tls: true,
+        }
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let headers = http_headers(&["https://dashboard.example.com"], false);
+        let req = test::TestRequest::get()
+            .insert_header((header::ORIGIN, "https://Dashboard.example.com"))
+            .to_srv_request();
+        assert!(headers.allowed_origin(&req).is_some());
+
+        let req = test::TestRequest::get()
+            .insert_header((header::ORIGIN, "https://evil.example.com"))
+            .to_srv_request();
+        assert!(headers.allowed_origin(&req).is_none());
+        assert!(http_headers(&["*"], false).allowed_origin(&req).is_some());
+        assert!(http_headers(&[], false).allowed_origin(&req).is_none());
+
+        let req = test::TestRequest::get().to_srv_request();
+        assert!(http_headers(&["*"], false).allowed_origin(&req).is_none());
+    }
+
+    #[test]
+    fn test_preflight() {
+        let headers = http_headers(&["https://dashboard.example.com"], false);
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .insert_header((header::ORIGIN, "https://dashboard.example.com"))
+            .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "POST"))
+            .insert_header((
+                header::ACCESS_CONTROL_REQUEST_HEADERS,
+                "content-type",
+            ))
+            .to_srv_request();
+        let response = headers.preflight(&req).unwrap(); //#[allow_ci]
+        assert_eq!(response.status(), 204);
+        let allowed = response.headers();
+        let origin = allowed.get(header::ACCESS_CONTROL_ALLOW_ORIGIN);
+        assert_eq!(origin.unwrap(), "https://dashboard.example.com"); //#[allow_ci]
+        let requested = allowed.get(header::ACCESS_CONTROL_ALLOW_HEADERS);
+        assert_eq!(requested.unwrap(), "content-type"); //#[allow_ci]
+
+        // Not a preflight request
+        let req = test::TestRequest::get()
+            .insert_header((header::ORIGIN, "https://dashboard.example.com"))
+            .to_srv_request();
+        assert!(headers.preflight(&req).is_none());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut map = HeaderMap::new();
+        http_headers(&[], false).apply(None, &mut map);
+        assert!(map.is_empty());
+
+        http_headers(&[], true).apply(None, &mut map);
+        let nosniff = map.get(header::X_CONTENT_TYPE_OPTIONS);
+        assert_eq!(nosniff.unwrap(), "nosniff"); //#[allow_ci]
+        assert!(map.contains_key(header::STRICT_TRANSPORT_SECURITY));
+        assert!(!map.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        let mut map = HeaderMap::new();
+        let headers = HttpHeaders {
+            tls: false,
+            ..http_headers(&["*"], true)
+        };
+        let origin =
+            HeaderValue::from_static("https://dashboard.example.com");
+        headers.apply(Some(origin), &mut map);
+        assert_eq!(
+            map.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).unwrap(), //#[allow_ci]
+            "https://dashboard.example.com"
+        );
+        assert!(!map.contains_key(header::STRICT_TRANSPORT_SECURITY));
+    }
+}
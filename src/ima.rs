@@ -5,27 +5,27 @@ use log::*;
 use std::{
     collections::HashSet,
     fs::File,
-    io::{prelude::*, Error, SeekFrom},
+    io::{prelude::*, BufReader, Error, SeekFrom},
     path::Path,
 };
 
 /// IMAMeasurementList models the IMA measurement lists's last two known
 /// numbers of entries in the log and filesizes at that point
-#[derive(Debug)]
-pub(crate) struct ImaMeasurementList {
+#[derive(Debug, Default)]
+pub struct ImaMeasurementList {
     entries: HashSet<(u64, u64)>,
 }
 
 pub type IMAError = Result<(Option<String>, Option<u64>, Option<u64>), Error>;
 
 impl ImaMeasurementList {
-    pub(crate) fn new() -> ImaMeasurementList {
+    pub fn new() -> ImaMeasurementList {
         ImaMeasurementList {
             entries: HashSet::new(),
         }
     }
 
-    pub(crate) fn reset(&mut self) {
+    pub fn reset(&mut self) {
         self.entries = HashSet::new();
     }
 
@@ -55,7 +55,7 @@ impl ImaMeasurementList {
 /// automatically read from the 0-th entry.
 /// This function returns the measurement list and the entry from where it
 /// was read and the current number of entries in the file.
-pub(crate) fn read_measurement_list(
+pub fn read_measurement_list(
     ima_ml: &mut ImaMeasurementList,
     ima_file: &mut File,
     nth_entry: u64,
@@ -96,10 +96,77 @@ pub(crate) fn read_measurement_list(
     }
 }
 
+/// Read the PCR the kernel extends the IMA measurements into, as reported in
+/// the first entry of the measurement list. Returns None if the list is empty
+/// or the entry cannot be parsed.
+pub fn read_ima_pcr(ima_file: &mut File) -> Result<Option<usize>, Error> {
+    let _ = ima_file.seek(SeekFrom::Start(0))?;
+    let mut line = String::new();
+    let _ = BufReader::new(ima_file).read_line(&mut line)?;
+    Ok(line
+        .split_whitespace()
+        .next()
+        .and_then(|pcr| pcr.parse::<usize>().ok()))
+}
+
+/// Compute the SHA-256 digest of the IMA policy currently loaded in the
+/// kernel, as a hex string. Returns None if the policy cannot be read, which
+/// is the case when the kernel is built without CONFIG_IMA_READ_POLICY.
+pub fn ima_policy_digest(policy_path: &Path) -> Option<String> {
+    match std::fs::read(policy_path) {
+        Ok(policy) => Some(hex::encode(openssl::sha::sha256(&policy))),
+        Err(e) => {
+            debug!(
+                "Unable to read IMA policy {}: {}",
+                policy_path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Limit the measurement list to at most max_entries entries, where 0 means
+/// no limit. If entries had to be left out, the list is truncated in place
+/// and the number of entries kept is returned.
+pub fn limit_measurement_list(
+    ml: &mut String,
+    max_entries: u64,
+) -> Option<u64> {
+    if max_entries == 0 {
+        return None;
+    }
+
+    let mut offset: usize = 0;
+    for _ in 0..max_entries {
+        match ml[offset..].find('\n') {
+            None => return None,
+            Some(idx) => offset = offset + idx + 1,
+        }
+    }
+
+    if offset == ml.len() {
+        return None;
+    }
+    ml.truncate(offset);
+    Some(max_entries)
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::PathBuf;
     use tempfile::NamedTempFile;
 
+    // As common::ima_ml_path_get(), this module is also built in the
+    // keylime_agent_sys library, which does not have common.rs
+    fn ima_ml_path_get() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("ima")
+            .join("ascii_runtime_measurements")
+    }
+
     #[test]
     fn read_measurement_list_test() {
         let mut ima_ml = ImaMeasurementList::new();
@@ -133,4 +200,59 @@ mod tests {
         assert_eq!(nth_entry, Some(0));
         assert_eq!(ml.unwrap().find("0-entry").unwrap(), 0); //#[allow_ci]
     }
+
+    #[test]
+    fn read_ima_pcr_test() {
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        let mut ima_file = File::open(tf.path()).unwrap(); //#[allow_ci]
+        assert_eq!(read_ima_pcr(&mut ima_file).unwrap(), None); //#[allow_ci]
+
+        tf.write_all(b"11 1d8d532d463c9f8c205d0df7787669a85f93e260 ima-ng sha1:0000000000000000000000000000000000000000 boot_aggregate\n");
+        tf.flush();
+        let mut ima_file = File::open(tf.path()).unwrap(); //#[allow_ci]
+        assert_eq!(read_ima_pcr(&mut ima_file).unwrap(), Some(11)); //#[allow_ci]
+
+        let mut ima_file = File::open(ima_ml_path_get()).unwrap(); //#[allow_ci]
+        assert_eq!(read_ima_pcr(&mut ima_file).unwrap(), Some(10)); //#[allow_ci]
+    }
+
+    #[test]
+    fn ima_policy_digest_test() {
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(b"measure func=BPRM_CHECK\n");
+        tf.flush();
+        assert_eq!(
+            ima_policy_digest(tf.path()).unwrap(), //#[allow_ci]
+            hex::encode(openssl::sha::sha256(b"measure func=BPRM_CHECK\n"))
+        );
+
+        assert!(ima_policy_digest(Path::new("/nonexistent/policy")).is_none());
+    }
+
+    #[test]
+    fn limit_measurement_list_test() {
+        let filedata = "0-entry\n1-entry\n2-entry\n";
+
+        // No limit
+        let mut ml = String::from(filedata);
+        assert_eq!(limit_measurement_list(&mut ml, 0), None);
+        assert_eq!(ml, filedata);
+
+        // Limit larger than or equal to the number of entries
+        let mut ml = String::from(filedata);
+        assert_eq!(limit_measurement_list(&mut ml, 3), None);
+        assert_eq!(ml, filedata);
+        assert_eq!(limit_measurement_list(&mut ml, 4), None);
+        assert_eq!(ml, filedata);
+
+        // Limit smaller than the number of entries
+        let mut ml = String::from(filedata);
+        assert_eq!(limit_measurement_list(&mut ml, 2), Some(2));
+        assert_eq!(ml, "0-entry\n1-entry\n");
+
+        // Empty list
+        let mut ml = String::new();
+        assert_eq!(limit_measurement_list(&mut ml, 1), None);
+        assert!(ml.is_empty());
+    }
 }
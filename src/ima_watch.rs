@@ -0,0 +1,232 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Notification of new IMA measurements between quotes.
+//
+// The verifier only learns about new IMA entries on its next poll. With
+// 'ima_change_webhook' set, the agent checks the number of measurements and
+// violations reported by the kernel every 'ima_change_check_interval' seconds
+// and posts the new counts to the webhook when they grew, so that the
+// verifier (or whatever relays to it) can request a quote right away. A
+// notification that could not be delivered is sent again on the next check.
+
+use crate::error::{Error, Result};
+use log::*;
+use serde::Serialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub(crate) static IMA_SECURITYFS: &str = "/sys/kernel/security/ima";
+static MEASUREMENTS_COUNT: &str = "runtime_measurements_count";
+static VIOLATIONS: &str = "violations";
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+struct ImaCounters {
+    measurement_count: u64,
+    violations: u64,
+}
+
+impl ImaCounters {
+    fn grew_since(&self, other: &ImaCounters) -> bool {
+        self.measurement_count > other.measurement_count
+            || self.violations > other.violations
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ImaChange<'a> {
+    agent_id: &'a str,
+    #[serde(flatten)]
+    counters: ImaCounters,
+    new_measurements: u64,
+    new_violations: u64,
+}
+
+fn read_counter(path: &Path) -> Result<u64> {
+    let value = fs::read_to_string(path)?;
+    value.trim().parse::<u64>().map_err(|e| {
+        Error::Other(format!("invalid count in {}: {}", path.display(), e))
+    })
+}
+
+fn read_counters(dir: &Path) -> Result<ImaCounters> {
+    Ok(ImaCounters {
+        measurement_count: read_counter(&dir.join(MEASUREMENTS_COUNT))?,
+        violations: read_counter(&dir.join(VIOLATIONS))?,
+    })
+}
+
+async fn notify(
+    client: &reqwest::Client,
+    webhook: &str,
+    agent_uuid: &str,
+    current: ImaCounters,
+    notified: ImaCounters,
+) -> Result<()> {
+    let change = ImaChange {
+        agent_id: agent_uuid,
+        counters: current,
+        new_measurements: current
+            .measurement_count
+            .saturating_sub(notified.measurement_count),
+        new_violations: current
+            .violations
+            .saturating_sub(notified.violations),
+    };
+    let _ = client
+        .post(webhook)
+        .json(&change)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/*
+ * Input: IMA securityfs directory, webhook URL, agent UUID and the number of
+ *        seconds between checks
+ *
+ * Posts to the webhook each time new IMA measurements or violations appear,
+ * until the agent exits.
+ */
+pub(crate) async fn watch(
+    dir: PathBuf,
+    webhook: String,
+    agent_uuid: String,
+    interval: u64,
+) -> Result<()> {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+    // Only the changes from now on are notified
+    let mut notified = read_counters(&dir)?;
+    info!(
+        "Notifying {} of new IMA measurements, starting at {} measurements and {} violations",
+        webhook, notified.measurement_count, notified.violations
+    );
+
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+    loop {
+        let _ = ticker.tick().await;
+        let current = match read_counters(&dir) {
+            Ok(current) => current,
+            Err(e) => {
+                warn!("Unable to read the IMA counters: {}", e);
+                continue;
+            }
+        };
+        if !current.grew_since(&notified) {
+            continue;
+        }
+        match notify(&client, &webhook, &agent_uuid, current, notified).await
+        {
+            Ok(()) => {
+                debug!(
+                    "Notified {} of {} IMA measurements and {} violations",
+                    webhook, current.measurement_count, current.violations
+                );
+                notified = current;
+            }
+            Err(e) => warn!(
+                "Unable to notify {} of new IMA measurements, retrying on the next check: {}",
+                webhook, e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_counters(dir: &Path, measurement_count: u64, violations: u64) {
+        let count = format!("{}\n", measurement_count);
+        fs::write(dir.join(MEASUREMENTS_COUNT), count).unwrap(); //#[allow_ci]
+        let violations = format!("{}\n", violations);
+        fs::write(dir.join(VIOLATIONS), violations).unwrap(); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_read_counters() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        assert!(read_counters(dir.path()).is_err());
+
+        write_counters(dir.path(), 42, 1);
+        let counters = read_counters(dir.path()).unwrap(); //#[allow_ci]
+        assert_eq!(
+            counters,
+            ImaCounters {
+                measurement_count: 42,
+                violations: 1,
+            }
+        );
+
+        fs::write(dir.path().join(VIOLATIONS), "many").unwrap(); //#[allow_ci]
+        assert!(read_counters(dir.path()).is_err());
+    }
+
+    #[test]
+    fn test_grew_since() {
+        let notified = ImaCounters {
+            measurement_count: 10,
+            violations: 0,
+        };
+        assert!(!notified.grew_since(&notified));
+        assert!(ImaCounters {
+            measurement_count: 11,
+            violations: 0,
+        }
+        .grew_since(&notified));
+        assert!(ImaCounters {
+            measurement_count: 10,
+            violations: 1,
+        }
+        .grew_since(&notified));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_notify() {
+        use wiremock::{
+            matchers::{body_json, method},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_json(serde_json::json!({
+                "agent_id": "uuid",
+                "measurement_count": 12,
+                "violations": 1,
+                "new_measurements": 2,
+                "new_violations": 1
+            })))
+            .respond_with(ResponseTemplate::new(200));
+        mock_server.register(mock).await;
+
+        let client = reqwest::Client::new();
+        let notified = ImaCounters {
+            measurement_count: 10,
+            violations: 0,
+        };
+        let current = ImaCounters {
+            measurement_count: 12,
+            violations: 1,
+        };
+        let result =
+            notify(&client, &mock_server.uri(), "uuid", current, notified)
+                .await;
+        assert!(result.is_ok());
+
+        // Unexpected notifications are not matched by the mock
+        let result =
+            notify(&client, &mock_server.uri(), "uuid", notified, notified)
+                .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::{
+    common::JsonWrapper, membership::Membership,
+    tpm_capabilities::TpmCapabilities, tpm_health, version_handler,
+    QuoteData,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::Serialize;
+use serde_json::Value;
+
+// CycloneDX SBOM generated by build.rs from Cargo.lock and the features
+// enabled for the build
+const SBOM: &str = include_str!(concat!(env!("OUT_DIR"), "/sbom.json"));
+
+// Algorithms the agent is configured with, as in the agent configuration
+#[derive(Serialize, Debug)]
+struct ConfiguredAlgorithms {
+    hash_alg: String,
+    enc_alg: String,
+    sign_alg: String,
+}
+
+#[derive(Serialize, Debug)]
+struct AgentInfo<'a> {
+    agent_uuid: &'a str,
+    #[serde(flatten)]
+    membership: &'a Membership,
+    tpm: &'a TpmCapabilities,
+    algorithms: ConfiguredAlgorithms,
+    features: Vec<String>,
+}
+
+// This is the handler for the GET request for the TPM capabilities, so that
+// tenants can pick algorithms the agent and its TPM support
+pub async fn info(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    info!(
+        "GET invoked from {} with uri {}",
+        req.connection_info().peer_addr().unwrap_or("-"),
+        req.uri()
+    );
+
+    let response = JsonWrapper::success(AgentInfo {
+        agent_uuid: &data.agent_uuid,
+        membership: &data.membership,
+        tpm: &data.tpm_capabilities,
+        algorithms: ConfiguredAlgorithms {
+            hash_alg: data.hash_alg.to_string(),
+            enc_alg: data.enc_alg.to_string(),
+            sign_alg: data.sign_alg.to_string(),
+        },
+        features: version_handler::enabled_features(),
+    });
+
+    HttpResponse::Ok().json(response)
+}
+
+// This is the handler for the GET request for the SBOM, so that security
+// teams can query which crate versions and features each agent is built with
+pub async fn sbom(req: HttpRequest) -> impl Responder {
+    info!(
+        "GET invoked from {} with uri {}",
+        req.connection_info().peer_addr().unwrap_or("-"),
+        req.uri()
+    );
+
+    match serde_json::from_str::<Value>(SBOM) {
+        Ok(sbom) => HttpResponse::Ok().json(JsonWrapper::success(sbom)),
+        Err(e) => {
+            error!("Unable to parse the embedded SBOM: {}", e);
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to parse the embedded SBOM",
+            ))
+        }
+    }
+}
+
+// This is the handler for the GET request for the health of the TPM, so that
+// operators notice a TPM approaching dictionary attack lockout before
+// attestation fails
+pub async fn tpm_health(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    info!(
+        "GET invoked from {} with uri {}",
+        req.connection_info().peer_addr().unwrap_or("-"),
+        req.uri()
+    );
+
+    match tpm_health::read(data).await {
+        Ok(health) => {
+            if health.lockout.in_lockout {
+                warn!("The TPM is in dictionary attack lockout");
+            } else if health.lockout.failed_tries > 0 {
+                warn!(
+                    "The TPM counted {} authorization failures, {} left before lockout",
+                    health.lockout.failed_tries,
+                    health.lockout.remaining_tries()
+                );
+            }
+            HttpResponse::Ok().json(JsonWrapper::success(health))
+        }
+        Err(e) => {
+            error!("Unable to read the health of the TPM: {}", e);
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to read the health of the TPM",
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, App};
+    use serde_json::json;
+
+    #[actix_rt::test]
+    async fn test_sbom() {
+        let mut app = test::init_service(App::new().route(
+            &format!("/{}/agent/sbom", API_VERSION),
+            web::get().to(sbom),
+        ))
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/sbom", API_VERSION))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<Value> = test::read_body_json(resp).await;
+        assert_eq!(result.results["bomFormat"], "CycloneDX");
+        assert_eq!(
+            result.results["metadata"]["component"]["version"],
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(result.results["components"]
+            .as_array()
+            .unwrap() //#[allow_ci]
+            .iter()
+            .any(|component| component["name"] == "tss-esapi"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_info() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/agent/info", API_VERSION),
+                web::get().to(info),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/info", API_VERSION))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<Value> = test::read_body_json(resp).await;
+        assert_eq!(result.results["algorithms"]["hash_alg"], "sha256");
+        assert_eq!(
+            result.results["tpm"]["manufacturer"],
+            json!(quotedata.tpm_capabilities.manufacturer)
+        );
+        assert!(result.results["tpm"]["hash_algorithms"]
+            .as_array()
+            .unwrap() //#[allow_ci]
+            .contains(&json!("sha256")));
+        assert!(result.results["features"]
+            .as_array()
+            .unwrap() //#[allow_ci]
+            .contains(&json!("testing")));
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_tpm_health() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/agent/tpm/health", API_VERSION),
+                web::get().to(tpm_health),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/agent/tpm/health", API_VERSION))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<Value> = test::read_body_json(resp).await;
+        assert_eq!(result.results["lockout"]["in_lockout"], false);
+        assert!(result.results["lockout"]["max_tries"].is_u64());
+        assert!(result.results["counters"]["retries"].is_u64());
+    }
+}
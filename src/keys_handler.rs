@@ -2,17 +2,30 @@
 // Copyright 2021 Keylime Authors
 
 use crate::crypto;
+use crate::disk_usage;
+use crate::nonce_cache;
+use crate::payload_chunks::KeylimePayloadChunk;
+use crate::payload_encryption::PayloadEncryption;
+use crate::payload_file::{self, KeylimePayloadFile};
+use crate::peer_identity;
+use crate::telemetry;
 use crate::{
+    algorithms::HmacAlgorithm,
     common::{
-        JsonWrapper, KeySet, SymmKey, AES_BLOCK_SIZE, AGENT_UUID_LEN,
-        AUTH_TAG_LEN,
+        JsonWrapper, KeySet, SymmKey, AES_128_KEY_LEN, AES_256_KEY_LEN,
+        AES_BLOCK_SIZE, AGENT_UUID_LEN,
     },
     Error, QuoteData, Result,
 };
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{http::header, web, HttpRequest, HttpResponse, Responder};
 use log::*;
 use serde::{Deserialize, Serialize};
-use std::{convert::TryInto, sync::Arc};
+use serde_json::json;
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    sync::Arc,
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeylimeUKey {
@@ -20,16 +33,33 @@ pub struct KeylimeUKey {
     encrypted_key: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     payload: Option<String>,
+    // Format of the payload, AES-GCM with the U and V key when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    payload_encryption: Option<String>,
+    // How the key is wrapped, RSA-OAEP with the NK when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_wrap: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeylimeVKey {
     encrypted_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_wrap: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct KeylimePubkey {
     pubkey: String,
+    // Set when age payloads are accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    age_recipient: Option<String>,
+    // Set when x25519_key_wrap is: base64 encoded X25519 public key, and
+    // its signature by the NK, binding it to the quoted NK
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x25519_pubkey: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    x25519_pubkey_signature: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -42,6 +72,152 @@ pub struct KeylimeHMAC {
     hmac: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimeAkActivation {
+    // Base64 encoded credential blob made for the AK of the verifier
+    keyblob: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KeylimeAuthTag {
+    auth_tag: String,
+}
+
+// Why a delivered U or V key was refused. It is returned to the tenant or
+// verifier so that it redelivers the faulty element only: the keys and the
+// auth tag received before are kept.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub(crate) enum KeyRejection {
+    // The auth tag is not the hexadecimal encoding of an HMAC of the
+    // algorithm in use
+    TagLength { len: usize, expected: usize },
+    TagFormat,
+    // The key is wrapped with a method the agent does not accept
+    KeyWrap { key: String, wrap: String },
+    // The encrypted key is not base64 encoded, or not encrypted with the NK
+    KeyEncoding { key: String },
+    KeyDecryption { key: String },
+    // The decrypted key is neither an AES-128 nor an AES-256 key
+    KeyLength { key: String, len: usize },
+    // No combination of the U and V keys received is the key of the HMAC of
+    // the agent UUID in the auth tag: the tag was made for another agent, or
+    // a key does not belong with it
+    UuidBinding { combinations: usize },
+}
+
+impl fmt::Display for KeyRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeyRejection::TagLength { len, expected } => write!(
+                f,
+                "auth_tag is {} characters long, expected {}",
+                len, expected
+            ),
+            KeyRejection::TagFormat => {
+                write!(f, "auth_tag is not hexadecimal")
+            }
+            KeyRejection::KeyWrap { key, wrap } => write!(
+                f,
+                "{} is wrapped with {}, which is not accepted",
+                key, wrap
+            ),
+            KeyRejection::KeyEncoding { key } => {
+                write!(f, "encrypted {} is not base64 encoded", key)
+            }
+            KeyRejection::KeyDecryption { key } => write!(
+                f,
+                "{} cannot be decrypted with the NK of the agent",
+                key
+            ),
+            KeyRejection::KeyLength { key, len } => write!(
+                f,
+                "{} is {} bytes long, expected {} or {}",
+                key, len, AES_128_KEY_LEN, AES_256_KEY_LEN
+            ),
+            KeyRejection::UuidBinding { combinations } => write!(
+                f,
+                "auth_tag does not match the agent UUID for any of the {} U and V key combinations",
+                combinations
+            ),
+        }
+    }
+}
+
+// Response refusing a delivered key, with the reason in the results
+fn key_rejected(key: &str, rejection: &KeyRejection) -> HttpResponse {
+    warn!("POST {} returning 400 response. {}", key, rejection);
+    HttpResponse::BadRequest().json(JsonWrapper {
+        code: 400,
+        status: format!("Key refused: {}", rejection),
+        results: json!({ "rejection": rejection }),
+    })
+}
+
+// The HMAC of the auth tag, checked before it replaces the one received
+// before
+fn parse_auth_tag(
+    auth_tag: &str,
+    hmac_alg: HmacAlgorithm,
+) -> std::result::Result<Vec<u8>, KeyRejection> {
+    let expected = 2 * hmac_alg.size();
+    if auth_tag.len() != expected {
+        return Err(KeyRejection::TagLength {
+            len: auth_tag.len(),
+            expected,
+        });
+    }
+    hex::decode(auth_tag).map_err(|_| KeyRejection::TagFormat)
+}
+
+// The U or V key decrypted with the NK, or unwrapped with the X25519 key
+// when the sender wrapped it with X25519
+fn decrypt_key(
+    key: &str,
+    encrypted_key: &str,
+    key_wrap: Option<&str>,
+    quote_data: &QuoteData,
+) -> std::result::Result<SymmKey, KeyRejection> {
+    let x25519_key = match (key_wrap, &quote_data.x25519_key) {
+        (None, _) | (Some("rsa-oaep"), _) => None,
+        (Some("x25519"), Some(x25519_key)) => Some(x25519_key),
+        (Some(wrap), _) => {
+            return Err(KeyRejection::KeyWrap {
+                key: key.to_string(),
+                wrap: wrap.to_string(),
+            })
+        }
+    };
+    let encrypted_key = base64::decode(encrypted_key).map_err(|_| {
+        KeyRejection::KeyEncoding {
+            key: key.to_string(),
+        }
+    })?;
+    // Uses NK (key for encrypting data from verifier or tenant to agent in transit) to
+    // decrypt U and V keys, which will be combined into one key that can decrypt the
+    // payload.
+    //
+    // Reference:
+    // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
+    // keylime/crypto.py#L118
+    let decrypted_key = match x25519_key {
+        Some(x25519_key) => crypto::x25519_unwrap(x25519_key, &encrypted_key),
+        None => {
+            crypto::rsa_oaep_decrypt(&quote_data.priv_key, &encrypted_key)
+        }
+    }
+    .map_err(|_| KeyRejection::KeyDecryption {
+        key: key.to_string(),
+    })?;
+    decrypted_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| KeyRejection::KeyLength {
+            key: key.to_string(),
+            len: decrypted_key.len(),
+        })
+}
+
 // Attempt to combine U and V keys into the payload decryption key. An HMAC over
 // the agent's UUID using the decryption key must match the provided authentication
 // tag. Returning None is okay here in case we are still waiting on another handler to
@@ -50,13 +226,11 @@ pub(crate) fn try_combine_keys(
     keyset1: &mut KeySet,
     keyset2: &mut KeySet,
     uuid: &[u8],
-    auth_tag: &[u8; AUTH_TAG_LEN],
+    auth_tag: &[u8],
+    hmac_alg: HmacAlgorithm,
 ) -> Result<Option<SymmKey>> {
     // U, V keys and auth_tag must be present for this to succeed
-    if keyset1.is_empty()
-        || keyset2.is_empty()
-        || auth_tag == &[0u8; AUTH_TAG_LEN]
-    {
+    if keyset1.is_empty() || keyset2.is_empty() || auth_tag.is_empty() {
         debug!("Still waiting on u or v key or auth_tag");
         return Ok(None);
     }
@@ -67,9 +241,13 @@ pub(crate) fn try_combine_keys(
 
             // Computes HMAC over agent UUID with provided key (payload decryption key) and
             // checks that this matches the provided auth_tag.
-            let auth_tag = hex::decode(auth_tag)?;
-            if crypto::verify_hmac(symm_key_out.bytes(), uuid, &auth_tag)
-                .is_ok()
+            if crypto::verify_hmac(
+                hmac_alg,
+                symm_key_out.bytes(),
+                uuid,
+                auth_tag,
+            )
+            .is_ok()
             {
                 info!(
                     "Successfully derived symmetric payload decryption key"
@@ -82,9 +260,61 @@ pub(crate) fn try_combine_keys(
         }
     }
 
-    Err(Error::Other(
-        "HMAC check failed for all U and V key combinations".to_string(),
-    ))
+    Err(Error::KeyRejected(KeyRejection::UuidBinding {
+        combinations: keyset1.len() * keyset2.len(),
+    }))
+}
+
+// Response refusing U and V keys after the bootstrap completed, None while
+// they are accepted
+fn keys_locked(quote_data: &QuoteData, key: &str) -> Option<HttpResponse> {
+    if !quote_data.status.report().keys_locked {
+        return None;
+    }
+    warn!(
+        "POST {} returning 403 response. Keys are locked after bootstrap",
+        key
+    );
+    Some(HttpResponse::Forbidden().json(JsonWrapper::error(
+        403,
+        "Keys are locked after bootstrap, unlock them with the admin socket",
+    )))
+}
+
+// Response refusing the challenges of a locked out peer, None when they are
+// answered, see verify_lockout.rs
+fn verify_locked_out(
+    quote_data: &QuoteData,
+    peer: &str,
+) -> Option<HttpResponse> {
+    let remaining = quote_data.verify_lockout.as_ref()?.locked(peer)?;
+    warn!(
+        "GET key challenge returning 429 response. {} is locked out for {} seconds",
+        peer,
+        remaining.as_secs()
+    );
+    Some(
+        HttpResponse::TooManyRequests()
+            .insert_header((header::RETRY_AFTER, remaining.as_secs().max(1)))
+            .json(JsonWrapper::error(
+                429,
+                "Too many key verification challenges, retry later",
+            )),
+    )
+}
+
+// Counts a challenge of the peer as failed, see verify_lockout.rs
+fn verify_failed(quote_data: &QuoteData, peer: &str) {
+    if let Some(lockout) = &quote_data.verify_lockout {
+        let locked_out = lockout.failed(peer);
+        if locked_out {
+            warn!(
+                "Locking {} out of key verification after repeated challenges",
+                peer
+            );
+        }
+        quote_data.status.verify_failed(locked_out);
+    }
 }
 
 pub async fn u_key(
@@ -94,51 +324,125 @@ pub async fn u_key(
 ) -> impl Responder {
     debug!("Received ukey");
 
+    if let Some(response) = keys_locked(&quote_data, "ukey") {
+        return Ok(response);
+    }
+
+    // Selected once the key is checked, as a refused delivery must not
+    // change the payload format
+    let payload_encryption = match &body.payload_encryption {
+        None => None,
+        Some(encryption) => {
+            match PayloadEncryption::try_from(encryption.as_str()) {
+                Ok(selected)
+                    if quote_data.payload_decryptor.accepts(selected) =>
+                {
+                    Some(selected)
+                }
+                _ => {
+                    warn!(
+                    "POST ukey returning 400 response. Payload encryption {} is not accepted",
+                    encryption
+                );
+                    return Ok(HttpResponse::BadRequest().json(
+                        JsonWrapper::error(
+                            400,
+                            format!(
+                                "Payload encryption {} is not accepted",
+                                encryption
+                            ),
+                        ),
+                    ));
+                }
+            }
+        }
+    };
+
     // Use scope to unlock the mutexes before calling await
     {
+        let span = telemetry::span("keys.ukey");
+
         // must unwrap when using lock
         // https://github.com/rust-lang-nursery/failure/issues/192
         let mut global_current_keyset = quote_data.ukeys.lock().unwrap(); //#[allow_ci]
         let mut global_other_keyset = quote_data.vkeys.lock().unwrap(); //#[allow_ci]
         let mut global_symm_key = quote_data.payload_symm_key.lock().unwrap(); //#[allow_ci]
         let mut global_encr_payload = quote_data.encr_payload.lock().unwrap(); //#[allow_ci]
+        let mut global_ukey_payload = quote_data.ukey_payload.lock().unwrap(); //#[allow_ci]
         let mut global_auth_tag = quote_data.auth_tag.lock().unwrap(); //#[allow_ci]
 
-        // get key and decode it from web data
-        let encrypted_key =
-            base64::decode(&body.encrypted_key).map_err(Error::from)?;
-        // Uses NK (key for encrypting data from verifier or tenant to agent in transit) to
-        // decrypt U and V keys, which will be combined into one key that can decrypt the
-        // payload.
-        //
-        // Reference:
-        // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
-        // keylime/crypto.py#L118
-        let decrypted_key =
-            crypto::rsa_oaep_decrypt(&quote_data.priv_key, &encrypted_key)?;
-
-        let decrypted_key: SymmKey =
-            decrypted_key.as_slice().try_into().unwrap(); //#[allow_ci]
-
-        global_current_keyset.push(decrypted_key);
-
+        // Check the key and the auth tag before changing the state, so that
+        // a refused delivery can be corrected
+        let decrypted_key = match decrypt_key(
+            "ukey",
+            &body.encrypted_key,
+            body.key_wrap.as_deref(),
+            &quote_data,
+        ) {
+            Ok(key) => key,
+            Err(rejection) => return Ok(key_rejected("ukey", &rejection)),
+        };
         // note: the auth_tag shouldn't be base64 decoded here
-        global_auth_tag.copy_from_slice(body.auth_tag.as_bytes());
+        let auth_tag =
+            match parse_auth_tag(&body.auth_tag, quote_data.hmac_alg) {
+                Ok(auth_tag) => auth_tag,
+                Err(rejection) => {
+                    return Ok(key_rejected("ukey", &rejection))
+                }
+            };
+
+        // A redelivery naming no format goes back to the default
+        if let Some(encryption) = payload_encryption {
+            debug!("Payload encrypted with {}", encryption);
+        }
+        quote_data.payload_decryptor.select(payload_encryption);
 
         if let Some(payload) = &body.payload {
             let encr_payload =
                 base64::decode(&payload).map_err(Error::from)?;
-            global_encr_payload.extend(encr_payload.iter());
+
+            // The decrypted payload is about the size of the encrypted one
+            // and is written out to the secure mount
+            let required =
+                (global_encr_payload.len() + encr_payload.len()) as u64;
+            match disk_usage::check_space(&quote_data.secure_mount, required)
+            {
+                Err(Error::DiskSpace(message)) => {
+                    warn!("POST ukey returning 507 response. Payload refused: {}", message);
+                    return Ok(HttpResponse::InsufficientStorage()
+                        .json(JsonWrapper::error(507, message)));
+                }
+                Err(e) => {
+                    warn!("Unable to check disk space for payload: {}", e);
+                }
+                Ok(()) => {}
+            }
+
+            // A redelivered U key replaces the payload of the previous one
+            *global_ukey_payload = encr_payload;
         }
 
-        if let Some(symm_key) = try_combine_keys(
+        global_current_keyset.push(decrypted_key);
+        *global_auth_tag = auth_tag;
+
+        match try_combine_keys(
             &mut global_current_keyset,
             &mut global_other_keyset,
             quote_data.agent_uuid.as_bytes(),
             &global_auth_tag,
-        )? {
-            let _ = global_symm_key.replace(symm_key);
-            quote_data.payload_symm_key_cvar.notify_one();
+            quote_data.hmac_alg,
+        ) {
+            Ok(Some(symm_key)) => {
+                span.set_attribute("payload_key", "combined".to_string());
+                global_encr_payload.append(&mut global_ukey_payload);
+                let _ = global_symm_key.replace(symm_key);
+                quote_data.payload_symm_key_cvar.notify_one();
+            }
+            Ok(None) => {}
+            Err(Error::KeyRejected(rejection)) => {
+                return Ok(key_rejected("ukey", &rejection))
+            }
+            Err(e) => return Err(e.into()),
         }
     }
     HttpResponse::Ok().await
@@ -151,56 +455,227 @@ pub async fn v_key(
 ) -> impl Responder {
     debug!("Received vkey");
 
+    if let Some(response) = keys_locked(&quote_data, "vkey") {
+        return Ok(response);
+    }
+
     // Use scope to unlock the mutexes before calling await
     {
+        let span = telemetry::span("keys.vkey");
+
         // must unwrap when using lock
         // https://github.com/rust-lang-nursery/failure/issues/192
         let mut global_current_keyset = quote_data.vkeys.lock().unwrap(); //#[allow_ci]
         let mut global_other_keyset = quote_data.ukeys.lock().unwrap(); //#[allow_ci]
         let mut global_symm_key = quote_data.payload_symm_key.lock().unwrap(); //#[allow_ci]
         let mut global_encr_payload = quote_data.encr_payload.lock().unwrap(); //#[allow_ci]
+        let mut global_ukey_payload = quote_data.ukey_payload.lock().unwrap(); //#[allow_ci]
         let mut global_auth_tag = quote_data.auth_tag.lock().unwrap(); //#[allow_ci]
 
-        // get key and decode it from web data
-        let encrypted_key =
-            base64::decode(&body.encrypted_key).map_err(Error::from)?;
-
-        // Uses NK (key for encrypting data from verifier or tenant to agent in transit) to
-        // decrypt U and V keys, which will be combined into one key that can decrypt the
-        // payload.
-        //
-        // Reference:
-        // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
-        // keylime/crypto.py#L118
-        let decrypted_key =
-            crypto::rsa_oaep_decrypt(&quote_data.priv_key, &encrypted_key)?;
-
-        let decrypted_key: SymmKey =
-            decrypted_key.as_slice().try_into().unwrap(); //#[allow_ci]
+        let decrypted_key = match decrypt_key(
+            "vkey",
+            &body.encrypted_key,
+            body.key_wrap.as_deref(),
+            &quote_data,
+        ) {
+            Ok(key) => key,
+            Err(rejection) => return Ok(key_rejected("vkey", &rejection)),
+        };
 
         global_current_keyset.push(decrypted_key);
 
-        if let Some(symm_key) = try_combine_keys(
+        match try_combine_keys(
             &mut global_current_keyset,
             &mut global_other_keyset,
             quote_data.agent_uuid.as_bytes(),
             &global_auth_tag,
-        )? {
-            let _ = global_symm_key.replace(symm_key);
-            quote_data.payload_symm_key_cvar.notify_one();
+            quote_data.hmac_alg,
+        ) {
+            Ok(Some(symm_key)) => {
+                span.set_attribute("payload_key", "combined".to_string());
+                global_encr_payload.append(&mut global_ukey_payload);
+                let _ = global_symm_key.replace(symm_key);
+                quote_data.payload_symm_key_cvar.notify_one();
+            }
+            Ok(None) => {}
+            Err(Error::KeyRejected(rejection)) => {
+                return Ok(key_rejected("vkey", &rejection))
+            }
+            Err(e) => return Err(e.into()),
         }
     }
 
     HttpResponse::Ok().await
 }
 
+/*
+ * Input: chunk of the encrypted payload
+ * Return: progress of its upload session
+ *
+ * Chunks are assembled into the encrypted payload, so they have to be sent
+ * before the keys: once the payload key is derived, the payload is
+ * decrypted as it is.
+ */
+pub async fn payload_chunk(
+    body: web::Json<KeylimePayloadChunk>,
+    req: HttpRequest,
+    quote_data: web::Data<QuoteData>,
+) -> impl Responder {
+    debug!("Received payload chunk");
+
+    if let Some(response) = keys_locked(&quote_data, "payload chunk") {
+        return response;
+    }
+
+    let global_symm_key = quote_data.payload_symm_key.lock().unwrap(); //#[allow_ci]
+    let mut global_encr_payload = quote_data.encr_payload.lock().unwrap(); //#[allow_ci]
+    let mut uploads = quote_data.payload_chunks.lock().unwrap(); //#[allow_ci]
+
+    if global_symm_key.is_some() {
+        warn!("POST payload chunk returning 409 response. Payload key already derived");
+        return HttpResponse::Conflict().json(JsonWrapper::error(
+            409,
+            "Payload key already derived, chunks must be sent before the U and V keys",
+        ));
+    }
+
+    uploads.expire();
+    // The decoded chunk is smaller than its base64 encoding
+    let required = (global_encr_payload.len()
+        + uploads.size()
+        + body.chunk.len()) as u64;
+    match disk_usage::check_space(&quote_data.secure_mount, required) {
+        Err(Error::DiskSpace(message)) => {
+            warn!("POST payload chunk returning 507 response. Chunk refused: {}", message);
+            return HttpResponse::InsufficientStorage()
+                .json(JsonWrapper::error(507, message));
+        }
+        Err(e) => {
+            warn!("Unable to check disk space for payload chunk: {}", e);
+        }
+        Ok(()) => {}
+    }
+
+    match uploads.add(&body) {
+        Ok((progress, payload)) => {
+            if let Some(payload) = payload {
+                info!(
+                    "Payload upload {} complete, {} bytes in {} chunks",
+                    progress.session,
+                    payload.len(),
+                    progress.total
+                );
+                global_encr_payload.extend(payload.iter());
+            }
+            HttpResponse::Ok().json(JsonWrapper::success(progress))
+        }
+        Err(e) => {
+            warn!("POST payload chunk returning 400 response. {}", e);
+            HttpResponse::BadRequest()
+                .json(JsonWrapper::error(400, e.to_string()))
+        }
+    }
+}
+
+/*
+ * Input: reference to an encrypted payload file staged on disk
+ *
+ * Like chunks, the file has to be referenced before the keys are sent.
+ */
+pub async fn payload_file(
+    body: web::Json<KeylimePayloadFile>,
+    req: HttpRequest,
+    quote_data: web::Data<QuoteData>,
+) -> impl Responder {
+    debug!("Received payload file reference");
+
+    if let Some(response) = keys_locked(&quote_data, "payload file") {
+        return response;
+    }
+
+    if quote_data.payload_file_dirs.is_empty() {
+        warn!("POST payload file returning 403 response. No payload_file_dirs configured");
+        return HttpResponse::Forbidden().json(JsonWrapper::error(
+            403,
+            "Payload files are not allowed, see payload_file_dirs",
+        ));
+    }
+
+    let global_symm_key = quote_data.payload_symm_key.lock().unwrap(); //#[allow_ci]
+    let mut global_encr_payload = quote_data.encr_payload.lock().unwrap(); //#[allow_ci]
+
+    if global_symm_key.is_some() {
+        warn!("POST payload file returning 409 response. Payload key already derived");
+        return HttpResponse::Conflict().json(JsonWrapper::error(
+            409,
+            "Payload key already derived, the payload file must be referenced before the U and V keys",
+        ));
+    }
+
+    let payload =
+        match payload_file::read(&body, &quote_data.payload_file_dirs) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("POST payload file returning 400 response. {}", e);
+                return HttpResponse::BadRequest()
+                    .json(JsonWrapper::error(400, e.to_string()));
+            }
+        };
+
+    let required = (global_encr_payload.len() + payload.len()) as u64;
+    match disk_usage::check_space(&quote_data.secure_mount, required) {
+        Err(Error::DiskSpace(message)) => {
+            warn!("POST payload file returning 507 response. Payload refused: {}", message);
+            return HttpResponse::InsufficientStorage()
+                .json(JsonWrapper::error(507, message));
+        }
+        Err(e) => {
+            warn!("Unable to check disk space for payload: {}", e);
+        }
+        Ok(()) => {}
+    }
+
+    info!(
+        "Payload of {} bytes read from {}",
+        payload.len(),
+        &body.path
+    );
+    global_encr_payload.extend(payload.iter());
+    HttpResponse::Ok().json(JsonWrapper::success(json!({
+        "size": payload.len()
+    })))
+}
+
+// The X25519 public key and its signature by the NK, when X25519 key wrap
+// is accepted
+fn x25519_pubkey(data: &QuoteData) -> Result<Option<(String, String)>> {
+    let key = match &data.x25519_key {
+        Some(key) => key,
+        None => return Ok(None),
+    };
+    let public = key.raw_public_key()?;
+    let signature = crypto::asym_sign(&data.priv_key, &public)?;
+    Ok(Some((base64::encode(&public), base64::encode(&signature))))
+}
+
 pub async fn pubkey(
     req: HttpRequest,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    match crypto::pkey_pub_to_pem(&data.pub_key) {
-        Ok(pubkey) => {
-            let response = JsonWrapper::success(KeylimePubkey { pubkey });
+    let keys = crypto::pkey_pub_to_pem(&data.pub_key)
+        .and_then(|pubkey| Ok((pubkey, x25519_pubkey(&data)?)));
+    match keys {
+        Ok((pubkey, x25519)) => {
+            let (x25519_pubkey, x25519_pubkey_signature) = match x25519 {
+                Some((pubkey, signature)) => (Some(pubkey), Some(signature)),
+                None => (None, None),
+            };
+            let response = JsonWrapper::success(KeylimePubkey {
+                pubkey,
+                age_recipient: data.payload_decryptor.age_recipient(),
+                x25519_pubkey,
+                x25519_pubkey_signature,
+            });
             info!("GET pubkey returning 200 response.");
 
             HttpResponse::Ok().json(response)
@@ -220,7 +695,13 @@ pub async fn verify(
     req: HttpRequest,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
+    let peer = nonce_cache::client(&req);
+    if let Some(response) = verify_locked_out(&data, &peer) {
+        return response;
+    }
+
     if param.challenge.is_empty() {
+        verify_failed(&data, &peer);
         warn!(
             "GET key challenge returning 400 response. No challenge provided"
         );
@@ -229,6 +710,7 @@ pub async fn verify(
     }
 
     if !param.challenge.chars().all(char::is_alphanumeric) {
+        verify_failed(&data, &peer);
         warn!("GET key challenge returning 400 response. Parameters should be strictly alphanumeric: {}", param.challenge);
         return HttpResponse::BadRequest().json(JsonWrapper::error(
             400,
@@ -250,8 +732,14 @@ pub async fn verify(
         ));
     }
 
+    // The agent cannot tell whether the peer got the HMAC it expected
+    verify_failed(&data, &peer);
     let key = key.as_ref().unwrap(); //#[allow_ci]
-    match crypto::compute_hmac(key.bytes(), param.challenge.as_bytes()) {
+    match crypto::compute_hmac(
+        data.hmac_alg,
+        key.bytes(),
+        param.challenge.as_bytes(),
+    ) {
         Ok(hmac) => {
             let response = JsonWrapper::success(KeylimeHMAC {
                 hmac: hex::encode(hmac),
@@ -270,22 +758,150 @@ pub async fn verify(
     }
 }
 
+// The identity of the verifier, or the response refusing the request when
+// the agent has no AK per verifier or the client sent no certificate, see
+// ak_registry.rs
+fn verifier_of(
+    req: &HttpRequest,
+    data: &QuoteData,
+) -> std::result::Result<String, HttpResponse> {
+    if data.ak_registry.is_none() {
+        warn!(
+            "{} returning 400 response. ak_per_verifier is not enabled",
+            req.path()
+        );
+        return Err(HttpResponse::BadRequest().json(JsonWrapper::error(
+            400,
+            "ak_per_verifier is not enabled on the agent",
+        )));
+    }
+    match peer_identity::of_request(req) {
+        Some(verifier) => Ok(verifier),
+        None => {
+            warn!(
+                "{} returning 403 response. No client certificate",
+                req.path()
+            );
+            Err(HttpResponse::Forbidden().json(JsonWrapper::error(
+                403,
+                "An AK per verifier requires a client certificate",
+            )))
+        }
+    }
+}
+
+// This is the request of a verifier for its own AK, created on first use
+pub async fn verifier_ak(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let verifier = match verifier_of(&req, &data) {
+        Ok(verifier) => verifier,
+        Err(response) => return response,
+    };
+    let quote_data = data.clone();
+    let result = data
+        .tpm
+        .run(move |ctx| {
+            let registry =
+                quote_data.ak_registry.as_ref().ok_or_else(|| {
+                    Error::Other("ak_per_verifier is not enabled".to_string())
+                })?;
+            registry.ak(ctx, &quote_data, &verifier)
+        })
+        .await;
+    match result {
+        Ok(info) => {
+            info!("GET verifier AK returning 200 response.");
+            HttpResponse::Ok().json(JsonWrapper::success(info))
+        }
+        Err(e) => {
+            warn!("GET verifier AK failed: {}", e);
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to create the AK".to_string(),
+            ))
+        }
+    }
+}
+
+// This is the credential made by a verifier for its AK with the EK. It
+// returns the HMAC of the agent UUID keyed with the secret, as sent to the
+// registrar on activation.
+pub async fn activate_verifier_ak(
+    body: web::Json<KeylimeAkActivation>,
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let verifier = match verifier_of(&req, &data) {
+        Ok(verifier) => verifier,
+        Err(response) => return response,
+    };
+    let keyblob = match base64::decode(&body.keyblob) {
+        Ok(keyblob) => keyblob,
+        Err(e) => {
+            warn!("POST verifier AK activation returning 400 response. Invalid keyblob: {}", e);
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "keyblob must be base64 encoded",
+            ));
+        }
+    };
+    let quote_data = data.clone();
+    let result = data
+        .tpm
+        .run(move |ctx| {
+            let registry =
+                quote_data.ak_registry.as_ref().ok_or_else(|| {
+                    Error::Other("ak_per_verifier is not enabled".to_string())
+                })?;
+            registry.activate(ctx, &quote_data, &verifier, keyblob.clone())
+        })
+        .await
+        .and_then(|secret| {
+            crypto::compute_hmac(
+                data.hmac_alg,
+                base64::encode(secret.value()).as_bytes(),
+                data.agent_uuid.as_bytes(),
+            )
+        });
+    match result {
+        Ok(auth_tag) => {
+            info!("POST verifier AK activation returning 200 response.");
+            HttpResponse::Ok().json(JsonWrapper::success(KeylimeAuthTag {
+                auth_tag: hex::encode(auth_tag),
+            }))
+        }
+        Err(e) => {
+            warn!(
+                "POST verifier AK activation returning 400 response. {}",
+                e
+            );
+            HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("Unable to activate the AK: {}", e),
+            ))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::common::{
-        KeylimeConfig, AES_128_KEY_LEN, AES_256_KEY_LEN, API_VERSION,
-    };
+    use crate::common::{KeylimeConfig, API_VERSION};
     use crate::crypto::compute_hmac;
     #[cfg(feature = "testing")]
     use crate::crypto::testing::{
-        encrypt_aead, pkey_pub_from_pem, rsa_oaep_encrypt,
+        encrypt_aead, pkey_pub_from_pem, rsa_oaep_encrypt, x25519_wrap,
     };
     use actix_rt::Arbiter;
     use actix_web::{test, web, App};
     use openssl::{
-        encrypt::Encrypter, hash::MessageDigest, pkey::PKey, rsa::Padding,
-        sign::Signer,
+        encrypt::Encrypter,
+        hash::MessageDigest,
+        pkey::PKey,
+        rsa::Padding,
+        sign::{Signer, Verifier},
     };
     use std::env;
     use std::fs;
@@ -325,6 +941,8 @@ mod tests {
         let payload_symm_key_cvar_clone =
             Arc::clone(&quotedata.payload_symm_key_cvar);
         let encr_payload_clone = Arc::clone(&quotedata.encr_payload);
+        let payload_decryptor_clone =
+            Arc::clone(&quotedata.payload_decryptor);
         let test_config_clone = test_config.clone();
         let secure_mount = PathBuf::from(&quotedata.secure_mount);
 
@@ -333,6 +951,8 @@ mod tests {
                 payload_symm_key_clone,
                 payload_symm_key_cvar_clone,
                 encr_payload_clone,
+                payload_decryptor_clone,
+                None,
                 &test_config_clone,
                 &secure_mount,
             )
@@ -362,14 +982,19 @@ mod tests {
         let encrypted_key =
             rsa_oaep_encrypt(&quotedata.pub_key, u.bytes()).unwrap(); //#[allow_ci]
 
-        let auth_tag =
-            compute_hmac(k.bytes(), test_config.agent_uuid.as_bytes())
-                .unwrap(); //#[allow_ci]
+        let auth_tag = compute_hmac(
+            quotedata.hmac_alg,
+            k.bytes(),
+            test_config.agent_uuid.as_bytes(),
+        )
+        .unwrap(); //#[allow_ci]
 
         let ukey = KeylimeUKey {
             encrypted_key: base64::encode(&encrypted_key),
             auth_tag: hex::encode(auth_tag),
             payload: payload.map(base64::encode),
+            payload_encryption: None,
+            key_wrap: None,
         };
 
         let req = test::TestRequest::post()
@@ -385,6 +1010,7 @@ mod tests {
 
         let vkey = KeylimeVKey {
             encrypted_key: base64::encode(&encrypted_key),
+            key_wrap: None,
         };
 
         let req = test::TestRequest::post()
@@ -431,6 +1057,430 @@ mod tests {
         assert!(timestamp_path.exists());
     }
 
+    #[test]
+    fn test_key_rejections() {
+        let sha384 = HmacAlgorithm::Sha384;
+        assert!(parse_auth_tag(&"a".repeat(96), sha384).is_ok());
+        assert_eq!(
+            parse_auth_tag("abcd", sha384),
+            Err(KeyRejection::TagLength {
+                len: 4,
+                expected: 96
+            })
+        );
+        assert_eq!(
+            parse_auth_tag(&"z".repeat(96), sha384),
+            Err(KeyRejection::TagFormat)
+        );
+        // A tag of another HMAC algorithm
+        assert_eq!(
+            parse_auth_tag(&"a".repeat(96), HmacAlgorithm::Sha256),
+            Err(KeyRejection::TagLength {
+                len: 96,
+                expected: 64
+            })
+        );
+
+        // A tag made for another UUID, the keys are kept for a redelivery
+        let u: SymmKey = b"0123456789012345"[..].try_into().unwrap(); //#[allow_ci]
+        let v: SymmKey = b"ABCDEFGHIJABCDEF"[..].try_into().unwrap(); //#[allow_ci]
+        let k = u.xor(&v).unwrap(); //#[allow_ci]
+        let tag = compute_hmac(sha384, k.bytes(), b"other").unwrap(); //#[allow_ci]
+        let tag = parse_auth_tag(&hex::encode(tag), sha384).unwrap(); //#[allow_ci]
+        let mut ukeys = vec![u];
+        let mut vkeys = vec![v];
+        match try_combine_keys(&mut ukeys, &mut vkeys, b"uuid", &tag, sha384)
+        {
+            Err(Error::KeyRejected(rejection)) => assert_eq!(
+                rejection,
+                KeyRejection::UuidBinding { combinations: 1 }
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!((ukeys.len(), vkeys.len()), (1, 1));
+        let combined =
+            try_combine_keys(&mut ukeys, &mut vkeys, b"other", &tag, sha384)
+                .unwrap(); //#[allow_ci]
+        assert!(combined.is_some());
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_x25519_key_wrap() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let u = b"0123456789012345";
+        let encrypted_key = base64::encode(
+            rsa_oaep_encrypt(&fixture.pub_key, u).unwrap(), //#[allow_ci]
+        );
+
+        // Not accepted by default
+        assert_eq!(
+            decrypt_key("ukey", &encrypted_key, Some("x25519"), &fixture)
+                .unwrap_err(), //#[allow_ci]
+            KeyRejection::KeyWrap {
+                key: "ukey".to_string(),
+                wrap: "x25519".to_string()
+            }
+        );
+        assert!(matches!(
+            decrypt_key("ukey", &encrypted_key, Some("rsa-oaep"), &fixture),
+            Ok(key) if key.bytes() == u
+        ));
+
+        fixture.x25519_key = Some(crypto::x25519_generate().unwrap()); //#[allow_ci]
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/pubkey", API_VERSION),
+                web::get().to(pubkey),
+            ))
+            .await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/keys/pubkey", API_VERSION,))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<KeylimePubkey> =
+            test::read_body_json(resp).await;
+        let public =
+            base64::decode(result.results.x25519_pubkey.unwrap()).unwrap(); //#[allow_ci]
+        let signature = base64::decode(
+            result.results.x25519_pubkey_signature.unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+        let mut verifier =
+            Verifier::new(MessageDigest::sha256(), &quotedata.pub_key)
+                .unwrap(); //#[allow_ci]
+        verifier.set_rsa_padding(Padding::PKCS1_PSS).unwrap(); //#[allow_ci]
+        verifier.update(&public).unwrap(); //#[allow_ci]
+        assert!(verifier.verify(&signature).unwrap()); //#[allow_ci]
+
+        let wrapped = base64::encode(x25519_wrap(&public, u).unwrap()); //#[allow_ci]
+        assert!(matches!(
+            decrypt_key("ukey", &wrapped, Some("x25519"), &quotedata),
+            Ok(key) if key.bytes() == u
+        ));
+        // Not when the sender claims another wrap
+        assert!(decrypt_key("ukey", &wrapped, None, &quotedata).is_err());
+        assert!(
+            decrypt_key("ukey", &wrapped, Some("ecdh"), &quotedata).is_err()
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_keys_locked() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        quotedata.status.set_keys_locked(true);
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/vkey", API_VERSION),
+                web::post().to(v_key),
+            ))
+            .await;
+
+        let vkey = KeylimeVKey {
+            encrypted_key: base64::encode(b"not decrypted"),
+            key_wrap: None,
+        };
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/vkey", API_VERSION,))
+            .set_json(&vkey)
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+        assert!(quotedata.vkeys.lock().unwrap().is_empty()); //#[allow_ci]
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_payload_encryption_refused() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/ukey", API_VERSION),
+                web::post().to(u_key),
+            ))
+            .await;
+
+        // age payloads are not accepted by default
+        let ukey = KeylimeUKey {
+            encrypted_key: base64::encode(b"not decrypted"),
+            auth_tag: String::new(),
+            payload: None,
+            payload_encryption: Some("age".to_string()),
+            key_wrap: None,
+        };
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/ukey", API_VERSION,))
+            .set_json(&ukey)
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        assert!(quotedata.ukeys.lock().unwrap().is_empty()); //#[allow_ci]
+        assert_eq!(
+            quotedata.payload_decryptor.selected(),
+            PayloadEncryption::AesGcm
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_ukey_redelivery() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fixture.secure_mount = temp_workdir.path().to_path_buf();
+        let quotedata = web::Data::new(fixture);
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route(
+                    &format!("/{}/keys/ukey", API_VERSION),
+                    web::post().to(u_key),
+                )
+                .route(
+                    &format!("/{}/keys/vkey", API_VERSION),
+                    web::post().to(v_key),
+                ),
+        )
+        .await;
+
+        let u: SymmKey = b"0123456789012345"[..].try_into().unwrap(); //#[allow_ci]
+        let v: SymmKey = b"ABCDEFGHIJABCDEF"[..].try_into().unwrap(); //#[allow_ci]
+        let k = u.xor(&v).unwrap(); //#[allow_ci]
+        let payload = b"encrypted payload";
+
+        let ukey = |uuid: &[u8]| {
+            let auth_tag =
+                compute_hmac(quotedata.hmac_alg, k.bytes(), uuid).unwrap(); //#[allow_ci]
+            KeylimeUKey {
+                encrypted_key: base64::encode(
+                    rsa_oaep_encrypt(&quotedata.pub_key, u.bytes()).unwrap(), //#[allow_ci]
+                ),
+                auth_tag: hex::encode(auth_tag),
+                payload: Some(base64::encode(payload)),
+                payload_encryption: None,
+                key_wrap: None,
+            }
+        };
+
+        // The auth tag is made for another UUID, the V key is rejected
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/ukey", API_VERSION,))
+            .set_json(&ukey(b"other"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let vkey = KeylimeVKey {
+            encrypted_key: base64::encode(
+                rsa_oaep_encrypt(&quotedata.pub_key, v.bytes()).unwrap(), //#[allow_ci]
+            ),
+            key_wrap: None,
+        };
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/vkey", API_VERSION,))
+            .set_json(&vkey)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        assert!(quotedata.encr_payload.lock().unwrap().is_empty()); //#[allow_ci]
+
+        // The corrected U key is accepted and its payload kept once
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/ukey", API_VERSION,))
+            .set_json(&ukey(quotedata.agent_uuid.as_bytes()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(quotedata.payload_symm_key.lock().unwrap().is_some()); //#[allow_ci]
+        assert_eq!(
+            quotedata.encr_payload.lock().unwrap().as_slice(), //#[allow_ci]
+            &payload[..]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_payload_encryption_selected() {
+        use crate::payload_encryption::PayloadDecryptor;
+
+        let mut config = KeylimeConfig::default();
+        config.payload_encryption =
+            vec![PayloadEncryption::AesGcm, PayloadEncryption::AesCbcHmac];
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.payload_decryptor =
+            Arc::new(PayloadDecryptor::new(&config, None).unwrap()); //#[allow_ci]
+        let quotedata = web::Data::new(fixture);
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/ukey", API_VERSION),
+                web::post().to(u_key),
+            ))
+            .await;
+
+        let u: SymmKey = b"0123456789012345"[..].try_into().unwrap(); //#[allow_ci]
+        let auth_tag =
+            compute_hmac(quotedata.hmac_alg, u.bytes(), b"uuid").unwrap(); //#[allow_ci]
+        let ukey =
+            |encrypted_key: Vec<u8>, encryption: Option<&str>| KeylimeUKey {
+                encrypted_key: base64::encode(encrypted_key),
+                auth_tag: hex::encode(&auth_tag),
+                payload: None,
+                payload_encryption: encryption.map(str::to_string),
+                key_wrap: None,
+            };
+        let encrypted_key =
+            rsa_oaep_encrypt(&quotedata.pub_key, u.bytes()).unwrap(); //#[allow_ci]
+
+        // A rejected key does not select the format
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/ukey", API_VERSION,))
+            .set_json(&ukey(b"not decrypted".to_vec(), Some("aes-cbc-hmac")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        assert_eq!(
+            quotedata.payload_decryptor.selected(),
+            PayloadEncryption::AesGcm
+        );
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/ukey", API_VERSION,))
+            .set_json(&ukey(encrypted_key.clone(), Some("aes-cbc-hmac")))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            quotedata.payload_decryptor.selected(),
+            PayloadEncryption::AesCbcHmac
+        );
+
+        // A redelivery naming no format goes back to the default
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/ukey", API_VERSION,))
+            .set_json(&ukey(encrypted_key, None))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            quotedata.payload_decryptor.selected(),
+            PayloadEncryption::AesGcm
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_payload_chunk() {
+        use crate::payload_chunks::ChunkProgress;
+        use openssl::sha::sha256;
+
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fixture.secure_mount = temp_workdir.path().to_path_buf();
+        let quotedata = web::Data::new(fixture);
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/payload/chunks", API_VERSION),
+                web::post().to(payload_chunk),
+            ))
+            .await;
+
+        for (index, data) in [(1, b"def"), (0, b"abc")] {
+            let mut chunk = KeylimePayloadChunk {
+                session: "upload1".to_string(),
+                index,
+                total: 2,
+                sha256: hex::encode(sha256(data)),
+                chunk: base64::encode(data),
+            };
+            // Corrupted chunks are refused and can be sent again
+            chunk.chunk = base64::encode(b"xyz");
+            let req = test::TestRequest::post()
+                .uri(&format!("/{}/keys/payload/chunks", API_VERSION))
+                .set_json(&chunk)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 400);
+
+            chunk.chunk = base64::encode(data);
+            let req = test::TestRequest::post()
+                .uri(&format!("/{}/keys/payload/chunks", API_VERSION))
+                .set_json(&chunk)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+            let result: JsonWrapper<ChunkProgress> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.results.complete, index == 0);
+        }
+
+        let payload = quotedata.encr_payload.lock().unwrap(); //#[allow_ci]
+        assert_eq!(payload.as_slice(), b"abcdef");
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_payload_file() {
+        use openssl::sha::sha256;
+
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fixture.secure_mount = temp_workdir.path().to_path_buf();
+        let staged = temp_workdir.path().join("payload.enc");
+        fs::write(&staged, b"ciphertext").unwrap(); //#[allow_ci]
+        let quotedata = web::Data::new(fixture);
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/payload/file", API_VERSION),
+                web::post().to(payload_file),
+            ))
+            .await;
+
+        let reference = KeylimePayloadFile {
+            path: staged.display().to_string(),
+            sha256: hex::encode(sha256(b"ciphertext")),
+        };
+
+        // Refused unless payload_file_dirs is set
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/payload/file", API_VERSION))
+            .set_json(&reference)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.secure_mount = temp_workdir.path().to_path_buf();
+        fixture.payload_file_dirs = vec![temp_workdir.path().to_path_buf()];
+        let quotedata = web::Data::new(fixture);
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/payload/file", API_VERSION),
+                web::post().to(payload_file),
+            ))
+            .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{}/keys/payload/file", API_VERSION))
+            .set_json(&reference)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let payload = quotedata.encr_payload.lock().unwrap(); //#[allow_ci]
+        assert_eq!(payload.as_slice(), b"ciphertext");
+    }
+
     #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn test_pubkey() {
@@ -498,4 +1548,53 @@ mod tests {
 
         assert_eq!(&response_hmac, &expected);
     }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_verify_lockout() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.verify_lockout =
+            Some(crate::verify_lockout::VerifyLockout::new(
+                2,
+                std::time::Duration::from_secs(60),
+            ));
+        let quotedata = web::Data::new(fixture);
+
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/keys/verify", API_VERSION),
+                web::get().to(verify),
+            ))
+            .await;
+
+        let challenge = |challenge: &str| {
+            test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/keys/verify?challenge={}",
+                    API_VERSION, challenge
+                ))
+                .to_request()
+        };
+
+        // Challenges sent before the key is derived are not counted
+        let resp = test::call_service(&app, challenge("1234")).await;
+        assert_eq!(resp.status(), 400);
+        let resp = test::call_service(&app, challenge("12-34")).await;
+        assert_eq!(resp.status(), 400);
+
+        {
+            let mut symkey = quotedata.payload_symm_key.lock().unwrap(); //#[allow_ci]
+            *symkey = Some([0u8; 32].as_ref().try_into().unwrap()); //#[allow_ci]
+        }
+        let resp = test::call_service(&app, challenge("1234")).await;
+        assert!(resp.status().is_success());
+
+        let resp = test::call_service(&app, challenge("5678")).await;
+        assert_eq!(resp.status(), 429);
+        assert!(resp.headers().contains_key(header::RETRY_AFTER));
+
+        let report = quotedata.status.report();
+        assert_eq!(report.verify_failures, 2);
+        assert_eq!(report.verify_lockouts, 1);
+    }
 }
@@ -0,0 +1,204 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Runtime control of the agent log level.
+//
+// RUST_LOG sets the initial filter. The level can then be changed through the
+// admin socket, or cycled between info, debug and trace by sending SIGUSR1
+// to the agent, without a restart. The new level applies to the messages of
+// the agent itself, other crates keep the RUST_LOG filter.
+//
+// Records are written to stderr, or sent to journald or syslog once the
+// configuration is loaded (see log_output).
+
+use crate::{
+    error::{Error, Result},
+    log_output::LogSink,
+};
+use log::*;
+use std::{
+    cmp,
+    sync::{Arc, RwLock},
+};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+static AGENT_TARGET: &str = "keylime_agent";
+
+fn is_agent_target(target: &str) -> bool {
+    target == AGENT_TARGET
+        || target.starts_with(&format!("{}::", AGENT_TARGET))
+}
+
+// Level following the given one when cycling with SIGUSR1
+fn next_level(level: LevelFilter) -> LevelFilter {
+    match level {
+        LevelFilter::Info => LevelFilter::Debug,
+        LevelFilter::Debug => LevelFilter::Trace,
+        _ => LevelFilter::Info,
+    }
+}
+
+struct ReloadableLogger {
+    // Formats the records, filtering is done here instead
+    inner: env_logger::Logger,
+    filter: env_logger::filter::Filter,
+    level: Arc<RwLock<Option<LevelFilter>>>,
+    // Records go to stderr when unset, or when the sink fails
+    sink: Arc<RwLock<Option<LogSink>>>,
+    agent_uuid: Arc<RwLock<Option<String>>>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = *self.level.read().unwrap(); //#[allow_ci]
+        match level {
+            Some(level) if is_agent_target(metadata.target()) => {
+                metadata.level() <= level
+            }
+            _ => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let sink = self.sink.read().unwrap(); //#[allow_ci]
+        if let Some(sink) = sink.as_ref() {
+            let agent_uuid = self.agent_uuid.read().unwrap(); //#[allow_ci]
+            if sink.send(record, agent_uuid.as_deref()).is_ok() {
+                return;
+            }
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Handle to change the level of the installed logger
+#[derive(Clone, Debug)]
+pub(crate) struct LogHandle {
+    level: Arc<RwLock<Option<LevelFilter>>>,
+    // Most verbose level enabled by RUST_LOG
+    default: LevelFilter,
+    sink: Arc<RwLock<Option<LogSink>>>,
+    agent_uuid: Arc<RwLock<Option<String>>>,
+}
+
+impl LogHandle {
+    pub(crate) fn level(&self) -> LevelFilter {
+        let level = self.level.read().unwrap(); //#[allow_ci]
+        level.unwrap_or(self.default)
+    }
+
+    pub(crate) fn set_level(&self, level: LevelFilter) {
+        *self.level.write().unwrap() = Some(level); //#[allow_ci]
+        log::set_max_level(cmp::max(level, self.default));
+        info!("Log level set to {}", level);
+    }
+
+    // Sends the records to journald or syslog rather than stderr
+    pub(crate) fn set_sink(&self, sink: Option<LogSink>) {
+        *self.sink.write().unwrap() = sink; //#[allow_ci]
+    }
+
+    // Sets the AGENT_UUID field of the records sent to journald
+    pub(crate) fn set_agent_uuid(&self, agent_uuid: &str) {
+        *self.agent_uuid.write().unwrap() = Some(agent_uuid.to_string()); //#[allow_ci]
+    }
+
+    pub(crate) fn cycle(&self) -> LevelFilter {
+        let level = next_level(self.level());
+        self.set_level(level);
+        level
+    }
+}
+
+fn build_logger(filters: &str) -> (ReloadableLogger, LogHandle) {
+    let filter = env_logger::filter::Builder::new().parse(filters).build();
+    let inner = pretty_env_logger::formatted_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let handle = LogHandle {
+        level: Arc::new(RwLock::new(None)),
+        default: filter.filter(),
+        sink: Arc::new(RwLock::new(None)),
+        agent_uuid: Arc::new(RwLock::new(None)),
+    };
+    let logger = ReloadableLogger {
+        inner,
+        filter,
+        level: handle.level.clone(),
+        sink: handle.sink.clone(),
+        agent_uuid: handle.agent_uuid.clone(),
+    };
+    (logger, handle)
+}
+
+// Installs the logger, replacing pretty_env_logger::init()
+pub(crate) fn init() -> Result<LogHandle> {
+    let filters = std::env::var("RUST_LOG").unwrap_or_default();
+    let (logger, handle) = build_logger(&filters);
+    log::set_boxed_logger(Box::new(logger)).map_err(|e| {
+        Error::Other(format!("unable to install the logger: {}", e))
+    })?;
+    log::set_max_level(handle.default);
+    Ok(handle)
+}
+
+// Handles SIGUSR1 from then on. Its default action terminates the process,
+// so this is done first thing, the signals are queued until cycle_on_signal
+// runs.
+pub(crate) fn catch_signal() -> Result<Signal> {
+    Ok(signal(SignalKind::user_defined1())?)
+}
+
+// Cycles the log level on each SIGUSR1 until the agent exits
+pub(crate) async fn cycle_on_signal(
+    mut signals: Signal,
+    handle: LogHandle,
+) -> Result<()> {
+    while signals.recv().await.is_some() {
+        let level = handle.cycle();
+        // Always shown, so the new level can be found in the log
+        warn!("Received SIGUSR1, log level set to {}", level);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata(target: &str, level: Level) -> Metadata {
+        Metadata::builder().target(target).level(level).build()
+    }
+
+    #[test]
+    fn test_next_level() {
+        assert_eq!(next_level(LevelFilter::Info), LevelFilter::Debug);
+        assert_eq!(next_level(LevelFilter::Debug), LevelFilter::Trace);
+        assert_eq!(next_level(LevelFilter::Trace), LevelFilter::Info);
+        assert_eq!(next_level(LevelFilter::Warn), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_reloadable_filter() {
+        let (logger, handle) = build_logger("warn");
+        let agent_debug = metadata("keylime_agent::tpm", Level::Debug);
+        let other_debug = metadata("actix_server", Level::Debug);
+        assert_eq!(handle.level(), LevelFilter::Warn);
+        assert!(!logger.enabled(&agent_debug));
+
+        handle.set_level(LevelFilter::Debug);
+        assert!(logger.enabled(&agent_debug));
+        assert!(!logger.enabled(&other_debug));
+        assert!(!logger.enabled(&metadata("keylime_agent", Level::Trace)));
+
+        assert_eq!(handle.cycle(), LevelFilter::Trace);
+        assert!(logger.enabled(&metadata("keylime_agent", Level::Trace)));
+    }
+}
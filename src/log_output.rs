@@ -0,0 +1,383 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Log destinations other than stderr.
+//
+// With 'log_destination = journald', records are sent to journald over its
+// native protocol, with the structured fields AGENT_UUID, SUBSYSTEM, the
+// module of the agent logging the record, e.g. "tpm", and REQUEST_ID for the
+// records logged while handling a request. The request ID is the one sent in
+// the X-Request-Id header, or a new one, and is returned in that header. With
+// 'log_destination = syslog', records are sent to the local syslog daemon
+// over /dev/log with the facility set in 'syslog_facility'. The agent logs to
+// stderr until the configuration is loaded, and whenever the destination is
+// unreachable.
+
+use crate::error::{Error, Result};
+use actix_web::{
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+};
+use log::{Level, Record};
+use std::{
+    convert::TryFrom, future::Future, io, os::unix::net::UnixDatagram,
+    process,
+};
+use uuid::Uuid;
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub(crate) static REQUEST_ID_HEADER: &str = "x-request-id";
+static JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+static SYSLOG_SOCKET: &str = "/dev/log";
+static IDENTIFIER: &str = "keylime_agent";
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LogDestination {
+    Stderr,
+    Journald,
+    Syslog,
+}
+
+impl TryFrom<&str> for LogDestination {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "stderr" => Ok(LogDestination::Stderr),
+            "journald" => Ok(LogDestination::Journald),
+            "syslog" => Ok(LogDestination::Syslog),
+            _ => Err(Error::Configuration(format!(
+                "log_destination must be stderr, journald or syslog, got {}",
+                value
+            ))),
+        }
+    }
+}
+
+static FACILITIES: [&str; 24] = [
+    "kern",
+    "user",
+    "mail",
+    "daemon",
+    "auth",
+    "syslog",
+    "lpr",
+    "news",
+    "uucp",
+    "cron",
+    "authpriv",
+    "ftp",
+    "ntp",
+    "security",
+    "console",
+    "solaris-cron",
+    "local0",
+    "local1",
+    "local2",
+    "local3",
+    "local4",
+    "local5",
+    "local6",
+    "local7",
+];
+
+// Syslog facility code, as in RFC 5424
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct SyslogFacility(u8);
+
+impl Default for SyslogFacility {
+    fn default() -> Self {
+        SyslogFacility(3)
+    }
+}
+
+impl SyslogFacility {
+    pub(crate) fn code(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<&str> for SyslogFacility {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        FACILITIES
+            .iter()
+            .position(|facility| *facility == value)
+            .map(|code| SyslogFacility(code as u8))
+            .ok_or_else(|| {
+                Error::Configuration(format!(
+                    "syslog_facility must be one of {}, got {}",
+                    FACILITIES.join(", "),
+                    value
+                ))
+            })
+    }
+}
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// The module of the agent logging the record, or the crate for other crates
+fn subsystem(target: &str) -> &str {
+    match target.strip_prefix("keylime_agent::") {
+        Some(module) => module.split("::").next().unwrap_or(module),
+        None if target == "keylime_agent" => "main",
+        None => target.split("::").next().unwrap_or(target),
+    }
+}
+
+/*
+ * Input: request
+ * Return: the ID of the request in the logs
+ *
+ * The ID sent by the client is kept as long as it is printable and short,
+ * so that the logs of the client and the agent can be matched.
+ */
+pub(crate) fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.chars().all(|c| c.is_ascii_graphic())
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+/*
+ * Input: request ID and the call to the next service
+ * Return: the response of the call, with the request ID header
+ *
+ * Records logged while polling the call get the request ID. The service is
+ * called within the scope as well, as the middlewares log synchronously.
+ */
+pub(crate) fn in_request<F, B>(
+    request_id: String,
+    call: impl FnOnce() -> F,
+) -> impl Future<Output = std::result::Result<ServiceResponse<B>, actix_web::Error>>
+where
+    F: Future<
+        Output = std::result::Result<ServiceResponse<B>, actix_web::Error>,
+    >,
+{
+    let future = REQUEST_ID.sync_scope(request_id.clone(), call);
+    async move {
+        let mut res = REQUEST_ID.scope(request_id.clone(), future).await?;
+        if let Ok(value) = HeaderValue::from_str(&request_id) {
+            let _ = res
+                .headers_mut()
+                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+        Ok(res)
+    }
+}
+
+fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+// Appends a field of the journald native protocol, in its binary form when
+// the value spans several lines
+fn append_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        entry.extend_from_slice(value.as_bytes());
+    } else {
+        entry.push(b'=');
+        entry.extend_from_slice(value.as_bytes());
+    }
+    entry.push(b'\n');
+}
+
+fn journald_entry(
+    record: &Record,
+    agent_uuid: Option<&str>,
+    request_id: Option<&str>,
+) -> Vec<u8> {
+    let mut entry = Vec::new();
+    append_field(&mut entry, "MESSAGE", &record.args().to_string());
+    append_field(
+        &mut entry,
+        "PRIORITY",
+        &severity(record.level()).to_string(),
+    );
+    append_field(&mut entry, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    append_field(&mut entry, "SUBSYSTEM", subsystem(record.target()));
+    if let Some(file) = record.file() {
+        append_field(&mut entry, "CODE_FILE", file);
+    }
+    if let Some(line) = record.line() {
+        append_field(&mut entry, "CODE_LINE", &line.to_string());
+    }
+    if let Some(agent_uuid) = agent_uuid {
+        append_field(&mut entry, "AGENT_UUID", agent_uuid);
+    }
+    if let Some(request_id) = request_id {
+        append_field(&mut entry, "REQUEST_ID", request_id);
+    }
+    entry
+}
+
+fn syslog_entry(
+    record: &Record,
+    facility: SyslogFacility,
+    request_id: Option<&str>,
+) -> Vec<u8> {
+    let priority = facility.code() * 8 + severity(record.level());
+    let request = match request_id {
+        Some(id) => format!(" (request {})", id),
+        None => String::new(),
+    };
+    format!(
+        "<{}>{}[{}]: {}: {}{}",
+        priority,
+        IDENTIFIER,
+        process::id(),
+        subsystem(record.target()),
+        record.args(),
+        request
+    )
+    .into_bytes()
+}
+
+// Connection to journald or the syslog daemon
+#[derive(Debug)]
+pub(crate) struct LogSink {
+    destination: LogDestination,
+    facility: SyslogFacility,
+    socket: UnixDatagram,
+}
+
+impl LogSink {
+    /*
+     * Input: log destination and syslog facility
+     * Return: the connection to the destination, None for stderr
+     */
+    pub(crate) fn connect(
+        destination: LogDestination,
+        facility: SyslogFacility,
+    ) -> Result<Option<Self>> {
+        let path = match destination {
+            LogDestination::Stderr => return Ok(None),
+            LogDestination::Journald => JOURNALD_SOCKET,
+            LogDestination::Syslog => SYSLOG_SOCKET,
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path).map_err(|e| {
+            Error::Configuration(format!(
+                "Unable to log to {:?} through {}: {}",
+                destination, path, e
+            ))
+        })?;
+        Ok(Some(LogSink {
+            destination,
+            facility,
+            socket,
+        }))
+    }
+
+    pub(crate) fn send(
+        &self,
+        record: &Record,
+        agent_uuid: Option<&str>,
+    ) -> io::Result<()> {
+        let request_id = current_request_id();
+        let entry = match self.destination {
+            LogDestination::Journald => {
+                journald_entry(record, agent_uuid, request_id.as_deref())
+            }
+            _ => syslog_entry(record, self.facility, request_id.as_deref()),
+        };
+        let _ = self.socket.send(&entry)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_record(target: &str, message: &str, f: impl FnOnce(&Record)) {
+        f(&Record::builder()
+            .args(format_args!("{}", message))
+            .level(Level::Warn)
+            .target(target)
+            .file(Some("src/tpm.rs"))
+            .line(Some(12))
+            .build())
+    }
+
+    #[test]
+    fn test_subsystem() {
+        assert_eq!(subsystem("keylime_agent::tpm"), "tpm");
+        assert_eq!(subsystem("keylime_agent::tpm::testing"), "tpm");
+        assert_eq!(subsystem("keylime_agent"), "main");
+        assert_eq!(subsystem("actix_server::worker"), "actix_server");
+    }
+
+    #[test]
+    fn test_syslog_facility() {
+        assert_eq!(SyslogFacility::default(), SyslogFacility(3));
+        assert_eq!(
+            SyslogFacility::try_from("daemon").unwrap(), //#[allow_ci]
+            SyslogFacility::default()
+        );
+        assert_eq!(
+            SyslogFacility::try_from("local7").unwrap(), //#[allow_ci]
+            SyslogFacility(23)
+        );
+        assert!(SyslogFacility::try_from("local8").is_err());
+    }
+
+    #[test]
+    fn test_journald_entry() {
+        with_record("keylime_agent::tpm", "two\nlines", |record| {
+            let entry = journald_entry(record, Some("uuid"), Some("id"));
+            let mut expected = b"MESSAGE\n".to_vec();
+            expected.extend_from_slice(&9u64.to_le_bytes());
+            expected.extend_from_slice(b"two\nlines\n");
+            expected.extend_from_slice(
+                b"PRIORITY=4\nSYSLOG_IDENTIFIER=keylime_agent\nSUBSYSTEM=tpm\nCODE_FILE=src/tpm.rs\nCODE_LINE=12\nAGENT_UUID=uuid\nREQUEST_ID=id\n",
+            );
+            assert_eq!(entry, expected);
+        });
+    }
+
+    #[test]
+    fn test_syslog_entry() {
+        with_record("keylime_agent::tpm", "message", |record| {
+            let entry = syslog_entry(record, SyslogFacility(16), Some("id"));
+            assert_eq!(
+                String::from_utf8(entry).unwrap(), //#[allow_ci]
+                format!(
+                    "<132>keylime_agent[{}]: tpm: message (request id)",
+                    process::id()
+                )
+            );
+        });
+    }
+
+    #[actix_rt::test]
+    async fn test_request_id_scope() {
+        assert_eq!(current_request_id(), None);
+        let in_scope = REQUEST_ID
+            .scope("id".to_string(), async { current_request_id() })
+            .await;
+        assert_eq!(in_scope, Some("id".to_string()));
+    }
+}
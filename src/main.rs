@@ -33,21 +33,75 @@
 //  missing_docs: there is many functions missing documentations for now
 #![allow(unused, missing_docs)]
 
+mod admin_socket;
+mod aead;
+mod agent_data_audit;
+mod ak_registry;
+mod alert;
 mod algorithms;
+mod api;
+mod circuit_breaker;
 mod common;
+mod config_bundle;
+mod config_overrides;
+mod config_upgrade;
+mod contact_ip;
+mod container;
+mod crash_report;
 mod crypto;
+mod device_identity;
+mod disk_usage;
+mod drtm;
+mod ek_cert;
 mod error;
 mod errors_handler;
+mod http_headers;
 mod ima;
+mod ima_watch;
+mod info_handler;
 mod keys_handler;
+mod log_level;
+mod log_output;
+mod membership;
+mod memory_protection;
+mod metadata;
+mod mtls_enrollment;
+mod nonce_cache;
 mod notifications_handler;
+mod nvram_handler;
+mod payload_chunks;
+mod payload_counter;
+mod payload_encryption;
+mod payload_file;
+mod payload_limits;
+mod peer_identity;
 mod permissions;
+mod pkcs11;
+mod provision;
+mod quote_cache;
+mod quote_key;
 mod quotes_handler;
 mod registrar_agent;
 mod revocation;
+mod revocation_webhook;
+mod runtime_inventory;
+mod secure_delete;
 mod secure_mount;
+mod self_measurement;
+mod self_test;
 mod serialization;
+mod status;
+mod tee_evidence;
+mod telemetry;
 mod tpm;
+mod tpm_audit;
+mod tpm_capabilities;
+mod tpm_health;
+#[cfg(any(test, feature = "tpm-replay"))]
+mod tpm_replay;
+mod tpm_service;
+mod vault;
+mod verify_lockout;
 mod version_handler;
 
 use actix_web::{dev::Service, http, middleware, rt, web, App, HttpServer};
@@ -55,26 +109,29 @@ use clap::{Arg, Command as ClapApp};
 use common::*;
 use compress_tools::*;
 use error::{Error, Result};
-use futures::{future::TryFutureExt, try_join};
+use futures::{
+    future::{ready, Either, TryFutureExt},
+    try_join,
+};
 use ima::ImaMeasurementList;
 use log::*;
 use openssl::pkey::{PKey, Private, Public};
 use std::{
     convert::TryFrom,
     fs,
-    io::{BufReader, Read, Write},
+    io::{BufReader, Read, Seek, SeekFrom, Write},
     os::unix::fs::PermissionsExt,
     path::{Path, PathBuf},
     process::{Command, Stdio},
     str::FromStr,
-    sync::{Arc, Condvar, Mutex},
+    sync::{Arc, Condvar, Mutex, RwLock},
     time::Duration,
 };
 use tss_esapi::{
     handles::KeyHandle,
     interface_types::algorithm::AsymmetricAlgorithm,
     interface_types::resource_handles::Hierarchy,
-    structures::{Auth, PublicBuffer},
+    structures::{Auth, Digest, PublicBuffer},
     traits::Marshall,
     Context,
 };
@@ -89,42 +146,496 @@ static NOTFOUND: &[u8] = b"Not Found";
 // handle quotes.
 #[derive(Debug)]
 pub struct QuoteData {
-    tpmcontext: Mutex<Context>,
+    tpm: tpm_service::TpmService,
+    tpm_handles: Arc<tpm::HandleRegistry>,
     priv_key: PKey<Private>,
     pub_key: PKey<Public>,
+    // Set when x25519_key_wrap is, generated at each start
+    x25519_key: Option<PKey<Private>>,
     ak_handle: KeyHandle,
+    // Set when ak_policy_pcrs or ak_policy_secret is
+    ak_policy: Option<tpm::PcrPolicy>,
+    // Set when enable_iak_idevid is, for co-signing quotes
+    iak_handle: Option<KeyHandle>,
+    // AK, NK and mTLS certificate in use, as persisted in agent_data_path
+    agent_data: Mutex<AgentData>,
     ukeys: Mutex<KeySet>,
     vkeys: Mutex<KeySet>,
     payload_symm_key: Arc<Mutex<Option<SymmKey>>>,
     payload_symm_key_cvar: Arc<Condvar>,
     encr_payload: Arc<Mutex<Vec<u8>>>,
-    auth_tag: Mutex<[u8; AUTH_TAG_LEN]>,
+    // Payload of the last U key, moved to encr_payload once the payload key
+    // is derived
+    ukey_payload: Mutex<Vec<u8>>,
+    payload_decryptor: Arc<payload_encryption::PayloadDecryptor>,
+    payload_chunks: Mutex<payload_chunks::ChunkedUploads>,
+    payload_file_dirs: Vec<PathBuf>,
+    // NV indices readable through /nvram
+    nvram_indices: Vec<u32>,
+    // HMAC of the auth tag delivered with the U key, empty until received
+    auth_tag: Mutex<Vec<u8>>,
     hash_alg: algorithms::HashAlgorithm,
     enc_alg: algorithms::EncryptionAlgorithm,
     sign_alg: algorithms::SignAlgorithm,
+    // Digest of the auth tags and key verification HMACs, agreed with the
+    // registrar
+    hmac_alg: algorithms::HmacAlgorithm,
     agent_uuid: String,
+    // Group and tags, reported by /agent/info
+    membership: membership::Membership,
     revocation_cert: PathBuf,
     revocation_actions: String,
     revocation_actions_dir: PathBuf,
     allow_payload_revocation_actions: bool,
+    // Set when revocation_webhook is
+    revocation_webhook: Option<Arc<revocation_webhook::RevocationWebhook>>,
     secure_size: String,
     work_dir: PathBuf,
     ima_ml_file: Option<Mutex<fs::File>>,
     measuredboot_ml_file: Option<Mutex<fs::File>>,
+    // Read at start, see drtm.rs
+    drtm_log: Option<drtm::DrtmLog>,
+    // Set when tee_evidence is
+    tee_guest: Option<tee_evidence::TeeGuest>,
+    ima_ml_breaker: circuit_breaker::CircuitBreaker,
+    measuredboot_ml_breaker: circuit_breaker::CircuitBreaker,
     ima_ml: Mutex<ImaMeasurementList>,
+    ima_ml_max_entries: u64,
+    // Set when quote_nonce_replay_window is
+    nonce_cache: Option<nonce_cache::NonceCache>,
+    // Composition of the quote qualifying data per API version
+    quote_qualifying_data: Vec<(APIVersion, api::QualifyingData)>,
+    // Set when quote_cache_ttl is
+    quote_cache: Option<quote_cache::QuoteCache>,
+    // Set when verify_max_failures is
+    verify_lockout: Option<verify_lockout::VerifyLockout>,
+    // Set when quote_key_lifetime is
+    quote_keys: Option<quote_key::QuoteKeys>,
+    // Set when ak_per_verifier is
+    ak_registry: Option<ak_registry::AkRegistry>,
+    ima_pcr: usize,
+    // Mask of the PCRs allocated in the bank of hash_alg
+    allocated_pcrs: u32,
+    // Reported by /agent/info
+    tpm_capabilities: tpm_capabilities::TpmCapabilities,
+    tpm_operation_timeout: Duration,
     secure_mount: PathBuf,
+    status: Arc<status::AgentStatus>,
+    runtime_inventory: Option<Mutex<runtime_inventory::RuntimeInventory>>,
+    // Set when payload_counter_nv_index is
+    payload_counter: Option<payload_counter::PayloadCounter>,
+}
+
+// Values sent to the registrar, kept to register the agent again on request
+// of the admin socket
+#[derive(Clone, Debug)]
+pub(crate) struct RegistrationData {
+    ek_tpm: Vec<u8>,
+    ek_cert: Option<Vec<u8>>,
+    ek_cert_chain: ek_cert::EkCertChain,
+    ak_tpm: Vec<u8>,
+    mtls_cert: Option<openssl::x509::X509>,
+    sw_tpm: bool,
+    metadata: metadata::HostMetadata,
+    device_identity: Option<device_identity::DeviceIdentity>,
+}
+
+/*
+ * Input: TPM context and its handle registry, agent configuration,
+ *        registration data, AK handle, EK handle, agent status and whether
+ *        the AK algorithms can be negotiated
+ * Return: The registration response
+ *
+ * Registers the agent with the registrar and activates it. When no EK handle
+ * is given, the EK is created again for the credential activation. The EK is
+ * flushed afterwards unless it is persistent. The TPM context is only locked
+ * while the TPM is used, not while waiting for the registrar.
+ *
+ * When negotiating and the registrar requests other AK algorithms than the
+ * configured ones, the agent is not activated and the requested algorithms
+ * are set in the response, so that the agent registers again with a new AK.
+ * They are unset otherwise.
+ */
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn register_agent(
+    tpm_service: &tpm_service::TpmService,
+    tpm_handles: &Arc<tpm::HandleRegistry>,
+    config: &KeylimeConfig,
+    registration: &RegistrationData,
+    ak_handle: KeyHandle,
+    ek_handle: Option<KeyHandle>,
+    status: &status::AgentStatus,
+    negotiate: bool,
+) -> Result<registrar_agent::Registration> {
+    let span = telemetry::span("registration");
+    span.set_attribute("agent_uuid", config.agent_uuid.clone());
+    let result = register_and_activate(
+        tpm_service,
+        tpm_handles,
+        config,
+        registration,
+        ak_handle,
+        ek_handle,
+        status,
+        negotiate,
+        &span,
+    )
+    .await;
+    span.record(&result);
+    if let Some(hint) = result.as_ref().err().and_then(Error::remediation) {
+        error!("Registration failed: {}", hint);
+    }
+    if let Err(e) = &result {
+        alert::raise(
+            alert::AlertKind::RegistrationFailed,
+            &format!("Registration failed: {}", e),
+        );
+        // The agent exits when the registration fails, let the alert go out
+        // first
+        let _ = rt::task::spawn_blocking(alert::flush).await;
+    }
+    result
 }
 
-// Parameters are based on Python codebase:
-// https://github.com/keylime/keylime/blob/1ed43ac8f75d5c3bc3a3bbbbb5037f20cf3c5a6a/ \
-// keylime/crypto.py#L189
+#[allow(clippy::too_many_arguments)]
+async fn register_and_activate(
+    tpm_service: &tpm_service::TpmService,
+    tpm_handles: &Arc<tpm::HandleRegistry>,
+    config: &KeylimeConfig,
+    registration: &RegistrationData,
+    ak_handle: KeyHandle,
+    ek_handle: Option<KeyHandle>,
+    status: &status::AgentStatus,
+    negotiate: bool,
+    span: &telemetry::Span,
+) -> Result<registrar_agent::Registration> {
+    // Request keyblob material
+    let register_span = span.child("registrar.register");
+    let registered = registrar_agent::do_register_agent(
+        &config.registrar_ip,
+        &config.registrar_port,
+        &config.agent_uuid,
+        &registration.ek_tpm,
+        config.enc_alg,
+        registration.ek_cert.clone(),
+        &registration.ek_cert_chain,
+        &registration.ak_tpm,
+        registration.mtls_cert.as_ref(),
+        config.agent_contact_ip.clone(),
+        config.agent_contact_port,
+        registration.sw_tpm,
+        &registration.metadata,
+        registration.device_identity.as_ref(),
+        &membership::Membership::from(config),
+    )
+    .await;
+    register_span.record(&registered);
+    drop(register_span);
+    let mut registered = registered?;
+    info!("SUCCESS: Agent {} registered", config.agent_uuid);
+    status.set_registration(status::RegistrationState::Registered);
+
+    match registered.preferred_algorithms.take() {
+        Some(preferred)
+            if !api::qualifying_data_fits(
+                preferred.hash_alg,
+                &config.quote_qualifying_data,
+            ) =>
+        {
+            warn!(
+                "Registrar requested an AK using {}, which leaves no room for the nonce in the quote_qualifying_data, the current AK is kept",
+                preferred.hash_alg
+            );
+        }
+        Some(preferred)
+            if preferred.hash_alg != config.hash_alg
+                || preferred.sign_alg != config.sign_alg =>
+        {
+            if negotiate {
+                info!(
+                    "Registrar requested an AK using {} and {}",
+                    preferred.hash_alg, preferred.sign_alg
+                );
+                registered.preferred_algorithms = Some(preferred);
+                return Ok(registered);
+            }
+            warn!(
+                "Registrar requested an AK using {} and {}, the current AK is kept until the agent restarts",
+                preferred.hash_alg, preferred.sign_alg
+            );
+        }
+        _ => (),
+    }
+    let keyblob = std::mem::take(&mut registered.keyblob);
+
+    let activate_credential_span = span.child("tpm.activate_credential");
+    let tpm_handles = tpm_handles.clone();
+    let enc_alg = config.enc_alg;
+    let persistent_ek = config.ek_handle.clone();
+    let encrypt_sessions = config.tpm_encrypt_sessions;
+    let keys = tpm_service.keys();
+    let key = tpm_service
+        .run_idempotent(move |ctx| {
+            let ek_handle = match ek_handle {
+                Some(handle) => handle,
+                None => {
+                    tpm::create_ek(
+                        ctx,
+                        &tpm_handles,
+                        enc_alg.into(),
+                        persistent_ek.as_deref(),
+                    )?
+                    .key_handle
+                }
+            };
+            let key = tpm::activate_credential(
+                ctx,
+                &tpm_handles,
+                keyblob.clone(),
+                keys.get(ak_handle),
+                ek_handle,
+                encrypt_sessions,
+            );
+            // Flush EK if we created it
+            if persistent_ek.is_none() {
+                ctx.flush_context(ek_handle.into())?;
+                tpm_handles.release(ek_handle.into());
+            }
+            key
+        })
+        .await;
+    drop(activate_credential_span);
+    let key = key?;
+    let mackey = base64::encode(key.value());
+    // The HMAC algorithm requested by the registrar, or the configured one
+    let hmac_alg = registered.hmac_alg.unwrap_or(config.hmac_alg);
+    let auth_tag = crypto::compute_hmac(
+        hmac_alg,
+        mackey.as_bytes(),
+        config.agent_uuid.as_bytes(),
+    )?;
+    let auth_tag = hex::encode(&auth_tag);
+
+    let activate_span = span.child("registrar.activate");
+    let result = registrar_agent::do_activate_agent(
+        &config.registrar_ip,
+        &config.registrar_port,
+        &config.agent_uuid,
+        &auth_tag,
+        hmac_alg,
+    )
+    .await;
+    activate_span.record(&result);
+    result?;
+    info!("SUCCESS: Agent {} activated", config.agent_uuid);
+    status.set_registration(status::RegistrationState::Activated);
+    Ok(registered)
+}
+
+// Set auth for the Owner hierarchy, under which the NK is sealed, and when
+// the EK handle is given for the Endorsement hierarchy.  Note in the Python
+// implementation, tpm_ownerpassword option is also used for claiming
+// ownership of TPM access, which will not be implemented here.
+fn set_hierarchy_auth(
+    ctx: &mut Context,
+    config: &KeylimeConfig,
+) -> Result<()> {
+    if let Some(ref v) = config.tpm_ownerpassword {
+        let auth = Auth::try_from(v.as_bytes())?;
+        ctx.tr_set_auth(Hierarchy::Owner.into(), auth.clone())?;
+        if config.ek_handle.is_some() {
+            ctx.tr_set_auth(Hierarchy::Endorsement.into(), auth)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+ * Input: agent configuration, handle registry, data of the AK in use and
+ *        handles of the AK and IAK loaded at startup
+ * Return: How the TPM manager loads the keys again after reconnecting
+ *
+ * The keys are loaded in the new context as at startup. The EK is only
+ * needed to load the AK, it is flushed unless it is persistent.
+ */
+fn reload_keys(
+    config: &KeylimeConfig,
+    tpm_handles: Arc<tpm::HandleRegistry>,
+    ak: tpm::AKResult,
+    ak_handle: KeyHandle,
+    iak_handle: Option<KeyHandle>,
+) -> tpm::ReloadKeys {
+    let config = config.clone();
+    Box::new(move |ctx| {
+        // Transient objects of the previous connection are gone
+        tpm_handles.forget_all();
+        set_hierarchy_auth(ctx, &config)?;
+        let ek_handle = tpm::create_ek(
+            ctx,
+            &tpm_handles,
+            config.enc_alg.into(),
+            config.ek_handle.as_deref(),
+        )?
+        .key_handle;
+        let reloaded = tpm::load_ak(
+            ctx,
+            &tpm_handles,
+            ek_handle,
+            &ak,
+            config.tpm_encrypt_sessions,
+        );
+        if config.ek_handle.is_none() {
+            ctx.flush_context(ek_handle.into())?;
+            tpm_handles.release(ek_handle.into());
+        }
+        let mut handles = vec![(ak_handle, reloaded?)];
+        if let Some(iak_handle) = iak_handle {
+            let identity = device_identity::load(ctx, &tpm_handles, &config)?;
+            handles.push((iak_handle, identity.iak_handle));
+        }
+        Ok(handles)
+    })
+}
+
+/*
+ * Input: Connection context and its handle registry, EK handle and agent
+ *        configuration
+ * Return: The handle and the data of the new AK
+ *
+ * Creates an AK with the configured algorithms and policy and loads it.
+ */
+fn create_and_load_ak(
+    ctx: &mut Context,
+    tpm_handles: &tpm::HandleRegistry,
+    ek_handle: KeyHandle,
+    config: &KeylimeConfig,
+) -> Result<(KeyHandle, tpm::AKResult)> {
+    let ak = tpm::create_ak(
+        ctx,
+        ek_handle,
+        config.hash_alg.into(),
+        config.sign_alg.into(),
+        config.ak_ecc_curve(),
+        config.ak_policy()?.as_ref(),
+    )?;
+    let ak_handle = tpm::load_ak(
+        ctx,
+        tpm_handles,
+        ek_handle,
+        &ak,
+        config.tpm_encrypt_sessions,
+    )?;
+    Ok((ak_handle, ak))
+}
+
+/*
+ * Input: Connection context and its handle registry, EK handle, persisted
+ *        agent data and agent configuration
+ * Return: The handle and the data of the stored AK, None if there is none
+ *         for the configured algorithms and policy or it could not be loaded
+ *
+ * Selects the AK stored for the configured algorithms in the agent data, so
+ * that switching algorithms back and forth reuses the AK registered before.
+ * An AK bound to another policy, or to other PCR values, is not reused.
+ */
+fn load_stored_ak(
+    ctx: &mut Context,
+    tpm_handles: &tpm::HandleRegistry,
+    ek_handle: KeyHandle,
+    agent_data: &mut AgentData,
+    config: &KeylimeConfig,
+) -> Result<Option<(KeyHandle, tpm::AKResult)>> {
+    if !agent_data.select_ak(
+        config.hash_alg,
+        config.sign_alg,
+        config.ak_ecc_curve(),
+    ) {
+        warn!(
+            "No AK stored in {} for the current configuration, creating a new one",
+            AGENT_DATA
+        );
+        return Ok(None);
+    }
+    let ak_result = agent_data.get_ak()?;
+    let auth_policy = match config.ak_policy()? {
+        Some(policy) => policy.digest(ctx)?,
+        None => Digest::default(),
+    };
+    if ak_result.public.auth_policy().value() != auth_policy.value() {
+        warn!(
+            "The AK stored in {} is not bound to the configured policy or PCR values, creating a new one",
+            AGENT_DATA
+        );
+        return Ok(None);
+    }
+    match tpm::load_ak(
+        ctx,
+        tpm_handles,
+        ek_handle,
+        &ak_result,
+        config.tpm_encrypt_sessions,
+    ) {
+        Ok(ak_handle) => {
+            info!("Loaded old AK key from {}", AGENT_DATA);
+            Ok(Some((ak_handle, ak_result)))
+        }
+        Err(e) => {
+            warn!("Loading old AK key from {} failed: {}", AGENT_DATA, e);
+            Ok(None)
+        }
+    }
+}
+
+/*
+ * Input: Connection context and its handle registry, persisted agent data
+ *        and agent configuration
+ * Return: The NK
+ *
+ * Loads the NK from the PKCS#11 token when nk_pkcs11_uri is set, from the
+ * agent data otherwise. A new NK is generated when there is none, or when
+ * the sealed one cannot be unsealed because the PCRs it is bound to changed.
+ */
+fn load_nk(
+    ctx: &mut Context,
+    tpm_handles: &tpm::HandleRegistry,
+    agent_data: Option<&AgentData>,
+    config: &KeylimeConfig,
+) -> Result<(PKey<Public>, PKey<Private>)> {
+    if let Some(uri) = &config.nk_pkcs11_uri {
+        return crypto::pkcs11_nk(
+            uri,
+            config.nk_pkcs11_module.as_deref(),
+            config.nk_pkcs11_pin.as_deref(),
+        );
+    }
+    match agent_data {
+        Some(data) if data.has_nk() => return data.get_nk(),
+        Some(data) => match data.sealed_nk().map(|s| s.unseal(ctx, tpm_handles)) {
+            Some(Ok(nk_priv)) => {
+                info!("Unsealed the NK stored in {}", AGENT_DATA);
+                let nk_pub = crypto::pkey_pub_from_priv(nk_priv.clone())?;
+                return Ok((nk_pub, nk_priv));
+            }
+            Some(Err(e)) => warn!(
+                "Unsealing the NK stored in {} failed, creating a new one: {}",
+                AGENT_DATA, e
+            ),
+            None => (),
+        },
+        None => (),
+    }
+    crypto::rsa_generate_pair(2048)
+}
+
+// Decrypts the payload in the format selected by the tenant
 pub(crate) fn decrypt_payload(
     encr: Arc<Mutex<Vec<u8>>>,
     symm_key: &SymmKey,
+    decryptor: &payload_encryption::PayloadDecryptor,
 ) -> Result<Vec<u8>> {
     let payload = encr.lock().unwrap(); //#[allow_ci]
 
-    let decrypted = crypto::decrypt_aead(symm_key.bytes(), &payload)?;
+    let decrypted = decryptor.decrypt(&payload, symm_key)?;
 
     info!("Successfully decrypted payload");
     Ok(decrypted)
@@ -139,40 +650,65 @@ pub(crate) fn setup_unzipped(
 ) -> Result<(PathBuf, PathBuf, PathBuf)> {
     let unzipped = mount.join("unzipped");
 
-    // clear any old data
+    // clear any old data, overwriting the previous key and payload
     if Path::new(&unzipped).exists() {
-        fs::remove_dir_all(&unzipped)?;
+        secure_delete::remove_dir_all(&unzipped)?;
     }
 
     let dec_payload_path = unzipped.join(&config.dec_payload_filename);
     let key_path = unzipped.join(&config.key_filename);
 
-    fs::create_dir(&unzipped)?;
+    permissions::create_dir(&unzipped)?;
 
     Ok((unzipped, dec_payload_path, key_path))
 }
 
+// Write data to a temporary file next to the destination and sync it, so that
+// it can be atomically renamed into place once complete.
+fn write_temp_file(
+    path: &Path,
+    data: &[u8],
+) -> Result<tempfile::NamedTempFile> {
+    let dir = path.parent().ok_or_else(|| {
+        Error::Other(format!(
+            "Invalid file path {:?}: no parent directory",
+            path
+        ))
+    })?;
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+    Ok(file)
+}
+
 // write symm key data and decrypted payload data out to specified files
+//
+// Both are first fully written out to temporary files and only then renamed
+// into place, so a crash mid-write never leaves a partially written key or
+// payload behind for payload scripts to consume.
 pub(crate) fn write_out_key_and_payload(
     dec_payload: &[u8],
     dec_payload_path: &Path,
     key: &SymmKey,
     key_path: &Path,
 ) -> Result<()> {
-    let mut key_file = fs::File::create(key_path)?;
-    let bytes = key_file.write(key.bytes())?;
-    if bytes != key.bytes().len() {
-        return Err(Error::Other(format!("Error writing symm key to {:?}: key len is {}, but {} bytes were written", key_path, key.bytes().len(), bytes)));
-    }
+    let key_file = write_temp_file(key_path, key.bytes())?;
+    let dec_payload_file = write_temp_file(dec_payload_path, dec_payload)?;
+
+    let _ = key_file.persist(key_path)?;
     info!("Wrote payload decryption key to {:?}", key_path);
 
-    let mut dec_payload_file = fs::File::create(dec_payload_path)?;
-    let bytes = dec_payload_file.write(dec_payload)?;
-    if bytes != dec_payload.len() {
-        return Err(Error::Other(format!("Error writing decrypted payload to {:?}: payload len is {}, but {} bytes were written", dec_payload_path, dec_payload.len(), bytes)));
-    }
+    let _ = dec_payload_file.persist(dec_payload_path)?;
     info!("Wrote decrypted payload to {:?}", dec_payload_path);
 
+    // Make the renames durable
+    for dir in [key_path.parent(), dec_payload_path.parent()]
+        .iter()
+        .flatten()
+    {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
     Ok(())
 }
 
@@ -227,9 +763,17 @@ pub(crate) fn optional_unzip_payload(
         let zipped_payload = &config.dec_payload_filename;
         let zipped_payload_path = unzipped.join(zipped_payload);
 
-        info!("Unzipping payload {} to {:?}", &zipped_payload, unzipped);
-
         let mut source = fs::File::open(&zipped_payload_path)?;
+        let compressed = source.metadata()?.len();
+        let limits = payload_limits::ExtractionLimits::from(config);
+        let extracted =
+            payload_limits::check(&mut source, compressed, &limits)?;
+        let _ = source.seek(SeekFrom::Start(0))?;
+
+        info!(
+            "Unzipping payload {} ({} bytes extracted) to {:?}",
+            &zipped_payload, extracted, unzipped
+        );
         uncompress_archive(&mut source, unzipped, Ownership::Ignore)?;
     }
 
@@ -240,17 +784,46 @@ pub(crate) async fn run_encrypted_payload(
     symm_key: Arc<Mutex<Option<SymmKey>>>,
     symm_key_cvar: Arc<Condvar>,
     payload: Arc<Mutex<Vec<u8>>>,
+    decryptor: Arc<payload_encryption::PayloadDecryptor>,
+    delivery_counter: Option<(
+        &payload_counter::PayloadCounter,
+        &tpm_service::TpmService,
+    )>,
     config: &KeylimeConfig,
     mount: &Path,
 ) -> Result<()> {
     // do nothing until actix server's handlers have updated the symmetric key
-    let mut key = symm_key.lock().unwrap(); //#[allow_ci]
-    while key.is_none() {
-        key = symm_key_cvar.wait(key).unwrap(); //#[allow_ci]
+    let key = {
+        let mut key = symm_key.lock().unwrap(); //#[allow_ci]
+        while key.is_none() {
+            key = symm_key_cvar.wait(key).unwrap(); //#[allow_ci]
+        }
+        key.as_ref().unwrap().clone() //#[allow_ci]
+    };
+    let key = &key;
+
+    let span = telemetry::span("payload");
+    let dec_payload = {
+        let _guard = span.attach();
+        let _span = telemetry::span("payload.decrypt");
+        decrypt_payload(payload.clone(), key, &decryptor)?
+    };
+
+    // The payload is accepted, counted before it is used
+    if let Some((counter, tpm)) = delivery_counter {
+        let digest = counter.digest(&payload.lock().unwrap())?; //#[allow_ci]
+        counter.record(tpm, &digest).await?;
     }
 
-    let key = key.as_ref().unwrap(); //#[allow_ci]
-    let dec_payload = decrypt_payload(payload, key)?;
+    // Nothing is awaited from here, the span can stay current
+    let _guard = span.attach();
+
+    // Refuse the payload up front rather than failing halfway through
+    // writing it out
+    disk_usage::check_space(
+        mount,
+        (dec_payload.len() + key.bytes().len()) as u64,
+    )?;
 
     let (unzipped, dec_payload_path, key_path) =
         setup_unzipped(config, mount)?;
@@ -263,14 +836,30 @@ pub(crate) async fn run_encrypted_payload(
     )?;
 
     optional_unzip_payload(&unzipped, config)?;
+
+    let bundle = unzipped.join(config_bundle::BUNDLE_FILE);
+    if bundle.exists() {
+        config_bundle::install(&bundle, config);
+    }
+
     // there may also be also a separate init script
     match config.payload_script.as_str() {
         "" => {
             info!("No payload script specified, skipping");
         }
+        script if !config.run_payload_script => {
+            info!(
+                "Payload init script {} not run, 'run_payload_script' is disabled",
+                script
+            );
+        }
         script => {
             info!("Payload init script indicated: {}", script);
-            run(&unzipped, script, config.agent_uuid.as_str())?;
+            let script_span = telemetry::span("payload.script");
+            script_span.set_attribute("script", script.to_string());
+            let result = run(&unzipped, script, config.agent_uuid.as_str());
+            script_span.record(&result);
+            result?;
         }
     }
 
@@ -310,22 +899,47 @@ pub(crate) async fn run_encrypted_payload(
 }
 
 async fn worker(
+    quote_data: web::Data<QuoteData>,
     symm_key: Arc<Mutex<Option<SymmKey>>>,
     symm_key_cvar: Arc<Condvar>,
     payload: Arc<Mutex<Vec<u8>>>,
+    decryptor: Arc<payload_encryption::PayloadDecryptor>,
     config: KeylimeConfig,
     mount: PathBuf,
+    agent_status: Arc<status::AgentStatus>,
+    revocation_webhook: Option<Arc<revocation_webhook::RevocationWebhook>>,
 ) -> Result<()> {
     // Only run payload scripts if mTLS is enabled or 'enable_insecure_payload' option is set
     if config.mtls_enabled || config.enable_insecure_payload {
-        run_encrypted_payload(
+        agent_status.set_payload(status::PayloadState::Waiting);
+        let result = run_encrypted_payload(
             symm_key,
             symm_key_cvar,
             payload,
+            decryptor,
+            quote_data
+                .payload_counter
+                .as_ref()
+                .map(|counter| (counter, &quote_data.tpm)),
             &config,
             &mount,
         )
-        .await?;
+        .await;
+        if let Err(Error::ExtractionLimit(exceeded)) = &result {
+            agent_status.set_payload_limit(exceeded.clone());
+        }
+        agent_status.set_payload(match result {
+            Ok(_) if !config.run_payload_script => {
+                status::PayloadState::DeliveredExecutionDisabled
+            }
+            Ok(_) => status::PayloadState::Delivered,
+            Err(_) => status::PayloadState::Failed,
+        });
+        result?;
+        if config.lock_keys_after_bootstrap {
+            info!("Payload deployed, U and V keys are now refused");
+            agent_status.set_keys_locked(true);
+        }
     } else {
         warn!("agent mTLS is disabled, and unless 'enable_insecure_payload' is set to 'True', payloads cannot be deployed'");
     }
@@ -333,7 +947,13 @@ async fn worker(
     // If with-zmq feature is enabled, run the service listening for ZeroMQ messages
     #[cfg(feature = "with-zmq")]
     if config.run_revocation {
-        return revocation::run_revocation_service(&config, &mount).await;
+        return revocation::run_revocation_service(
+            &config,
+            &mount,
+            &agent_status,
+            revocation_webhook.as_deref(),
+        )
+        .await;
     }
 
     Ok(())
@@ -341,15 +961,307 @@ async fn worker(
 
 #[actix_web::main]
 async fn main() -> Result<()> {
+    let log_level_signal = log_level::catch_signal()?;
+
     // Print --help information
     let matches = ClapApp::new("keylime_agent")
         .about("A Rust implementation of the Keylime agent")
+        .version(env!("CARGO_PKG_VERSION"))
+        .long_version(concat!(
+            env!("CARGO_PKG_VERSION"),
+            " (",
+            env!("KEYLIME_AGENT_GIT_COMMIT"),
+            ")"
+        ))
         .override_usage(
             "sudo RUST_LOG=keylime_agent=trace ./target/debug/keylime_agent",
         )
+        .arg(
+            Arg::new("build-info")
+                .long("build-info")
+                .help("Print the build information as JSON and exit"),
+        )
+        .subcommand(
+            ClapApp::new("status")
+                .about("Print the status of the running agent")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .help("Print the status as JSON"),
+                )
+                .arg(Arg::new("ready").long("ready").help(
+                    "Only check that the agent is activated and its TPM responsive, for readiness probes",
+                )),
+        )
+        .subcommand(
+            ClapApp::new("upgrade-config")
+                .about("Write the configuration translated to the current layout, without comments")
+                .arg(
+                    Arg::new("output")
+                        .required(true)
+                        .help("File to write the configuration to"),
+                ),
+        )
+        .subcommand(
+            ClapApp::new("provision-tpm")
+                .about("Persist the EK and set the password of the owner and endorsement hierarchies")
+                .arg(
+                    Arg::new("ek-handle")
+                        .long("ek-handle")
+                        .takes_value(true)
+                        .help("Persistent handle of the EK, 0x81010001 for RSA and 0x81010002 for ECC EKs by default"),
+                )
+                .arg(
+                    Arg::new("current-password")
+                        .long("current-password")
+                        .takes_value(true)
+                        .help("Current password of the hierarchies, 'tpm_ownerpassword' by default"),
+                )
+                .arg(
+                    Arg::new("password")
+                        .long("password")
+                        .takes_value(true)
+                        .help("New password of the hierarchies, generated by default"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .takes_value(true)
+                        .help("File to write the configuration to, stdout by default"),
+                ),
+        )
+        .subcommand(
+            ClapApp::new("admin")
+                .about("Run a privileged operation on the running agent")
+                .subcommand_required(true)
+                .subcommand(ClapApp::new("reregister").about(
+                    "Register and activate the agent with the registrar again",
+                ))
+                .subcommand(ClapApp::new("rotate-mtls-cert").about(
+                    "Replace the mTLS certificate and register the new one",
+                ))
+                .subcommand(
+                    ClapApp::new("flush-quote-cache")
+                        .about("Drop the cached IMA measurement list offsets"),
+                )
+                .subcommand(
+                    ClapApp::new("self-test")
+                        .about("Run the TPM and measurement log self-tests")
+                        .arg(
+                            Arg::new("json")
+                                .long("json")
+                                .help("Print the report as JSON"),
+                        ),
+                )
+                .subcommand(
+                    ClapApp::new("set-log-level")
+                        .about("Change the log level of the agent")
+                        .arg(
+                            Arg::new("level")
+                                .required(true)
+                                .possible_values([
+                                    "off", "error", "warn", "info", "debug",
+                                    "trace",
+                                ]),
+                        ),
+                )
+                .subcommand(
+                    ClapApp::new("decrypt")
+                        .about("Decrypt a file with the payload key to stdout")
+                        .arg(
+                            Arg::new("path")
+                                .required(true)
+                                .help("File encrypted like the payloads"),
+                        ),
+                )
+                .subcommand(ClapApp::new("unlock-keys").about(
+                    "Accept U and V keys again after the bootstrap completed",
+                ))
+                .subcommand(
+                    ClapApp::new("deactivate")
+                        .about("Put the agent in maintenance, refusing quotes until it is activated")
+                        .arg(
+                            Arg::new("reason")
+                                .long("reason")
+                                .takes_value(true)
+                                .help("Why, reported with the refused quotes"),
+                        )
+                        .arg(
+                            Arg::new("duration")
+                                .long("duration")
+                                .takes_value(true)
+                                .help("Seconds after which the maintenance ends on its own, 3600 by default"),
+                        )
+                        .arg(Arg::new("notify").long("notify").help(
+                            "Notify 'maintenance_notify_url' of the maintenance",
+                        )),
+                )
+                .subcommand(
+                    ClapApp::new("activate")
+                        .about("End the maintenance, serving quotes again")
+                        .arg(Arg::new("notify").long("notify").help(
+                            "Notify 'maintenance_notify_url' the maintenance ended",
+                        )),
+                ),
+        )
         .get_matches();
 
-    pretty_env_logger::init();
+    if matches.is_present("build-info") {
+        let build_info = version_handler::BuildInfo::new();
+        println!("{}", serde_json::to_string_pretty(&build_info)?);
+        return Ok(());
+    }
+
+    if let Some(("upgrade-config", upgrade_matches)) = matches.subcommand() {
+        let source = config_file_get();
+        let output = upgrade_matches.value_of("output").unwrap_or_default();
+        match config_upgrade::write(Path::new(&source), Path::new(output))? {
+            Some(upgrade) => {
+                println!(
+                    "Translated {} from {} to version {}",
+                    source,
+                    upgrade.from,
+                    config_upgrade::CONFIG_VERSION
+                );
+                for rename in &upgrade.renamed {
+                    println!("  renamed: {}", rename);
+                }
+                for option in &upgrade.dropped {
+                    println!("  dropped: {}", option);
+                }
+            }
+            None => println!("{} is already up to date", source),
+        }
+        println!("Wrote {}", output);
+        return Ok(());
+    }
+
+    if let Some(("provision-tpm", provision_matches)) = matches.subcommand() {
+        let config = KeylimeConfig::build()?;
+        let options = provision::ProvisionOptions {
+            enc_alg: config.enc_alg,
+            ek_handle: provision_matches
+                .value_of("ek-handle")
+                .map(provision::parse_handle)
+                .transpose()?,
+            current_password: provision_matches
+                .value_of("current-password")
+                .map(str::to_string)
+                .or(config.tpm_ownerpassword),
+            password: provision_matches
+                .value_of("password")
+                .map(str::to_string),
+        };
+        let mut ctx = tpm::get_tpm2_ctx()?;
+        let output = provision_matches.value_of("output");
+        let provisioned =
+            provision::provision(&mut ctx, &options, output.map(Path::new))?;
+        if let Some(output) = output {
+            println!(
+                "Persisted the EK at {:#010x} and set the hierarchy password, wrote the configuration to {}",
+                provisioned.ek_handle, output
+            );
+        }
+        return Ok(());
+    }
+
+    let admin_command = match matches.subcommand() {
+        Some(("status", status_matches))
+            if status_matches.is_present("ready") =>
+        {
+            Some((admin_socket::Request::Ready, false))
+        }
+        Some(("status", status_matches)) => Some((
+            admin_socket::Request::Status,
+            status_matches.is_present("json"),
+        )),
+        Some(("admin", admin_matches)) => match admin_matches.subcommand() {
+            Some(("reregister", _)) => {
+                Some((admin_socket::Request::Reregister, false))
+            }
+            Some(("rotate-mtls-cert", _)) => {
+                Some((admin_socket::Request::RotateMtlsCert, false))
+            }
+            Some(("flush-quote-cache", _)) => {
+                Some((admin_socket::Request::FlushQuoteCache, false))
+            }
+            Some(("self-test", self_test_matches)) => Some((
+                admin_socket::Request::SelfTest,
+                self_test_matches.is_present("json"),
+            )),
+            Some(("set-log-level", level_matches)) => Some((
+                admin_socket::Request::SetLogLevel {
+                    level: level_matches
+                        .value_of("level")
+                        .unwrap_or_default()
+                        .to_string(),
+                },
+                false,
+            )),
+            // The agent does not share the working directory of the client
+            Some(("decrypt", decrypt_matches)) => Some((
+                admin_socket::Request::Decrypt {
+                    path: std::env::current_dir()?
+                        .join(
+                            decrypt_matches
+                                .value_of("path")
+                                .unwrap_or_default(),
+                        )
+                        .display()
+                        .to_string(),
+                },
+                false,
+            )),
+            Some(("unlock-keys", _)) => {
+                Some((admin_socket::Request::UnlockKeys, false))
+            }
+            Some(("deactivate", deactivate_matches)) => Some((
+                admin_socket::Request::Deactivate {
+                    reason: deactivate_matches
+                        .value_of("reason")
+                        .map(str::to_string),
+                    duration: deactivate_matches
+                        .value_of("duration")
+                        .map(|duration| {
+                            duration.parse::<u64>().map_err(|e| {
+                                Error::Configuration(format!(
+                                    "invalid maintenance duration {}: {}",
+                                    duration, e
+                                ))
+                            })
+                        })
+                        .transpose()?,
+                    notify: deactivate_matches.is_present("notify"),
+                },
+                false,
+            )),
+            Some(("activate", activate_matches)) => Some((
+                admin_socket::Request::Activate {
+                    notify: activate_matches.is_present("notify"),
+                },
+                false,
+            )),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    if let Some((command, json)) = admin_command {
+        let config = KeylimeConfig::build()?;
+        let admin_socket = config.admin_socket.ok_or_else(|| {
+            Error::Configuration(
+                "The admin socket is disabled, set 'admin_socket' to reach the running agent".to_string(),
+            )
+        })?;
+        return admin_socket::run_command(
+            Path::new(&admin_socket),
+            &command,
+            json,
+        );
+    }
+
+    let log_handle = log_level::init()?;
+    telemetry::init()?;
 
     let ima_ml_path = ima_ml_path_get();
     let ima_ml_file = if ima_ml_path.exists() {
@@ -393,12 +1305,19 @@ async fn main() -> Result<()> {
 
     // Load config
     let mut config = KeylimeConfig::build()?;
-
-    // The agent cannot run when a payload script is defined, but mTLS is disabled and insecure
-    // payloads are not explicitly enabled
+    log_handle.set_sink(log_output::LogSink::connect(
+        config.log_destination,
+        config.syslog_facility,
+    )?);
+    tpm::set_retry_policy(config.tpm_retry_attempts, config.tpm_retry_delay);
+    tpm::set_tcti(config.tcti.clone());
+
+    // The agent cannot run when a payload script is defined and run, but mTLS is disabled and
+    // insecure payloads are not explicitly enabled
     if !&config.mtls_enabled
         && !&config.enable_insecure_payload
         && !&config.payload_script.is_empty()
+        && config.run_payload_script
     {
         let message = "The agent mTLS is disabled and 'payload_script' is not empty. To allow the agent to run, 'enable_insecure_payload' has to be set to 'True'".to_string();
 
@@ -406,9 +1325,56 @@ async fn main() -> Result<()> {
         return Err(Error::Configuration(message));
     }
 
+    // The IMA PCR must match the one the kernel extends the measurements into
+    if let Some(ima_file) = &ima_ml_file {
+        let mut ima_file = ima_file.lock().unwrap(); //#[allow_ci]
+        match ima::read_ima_pcr(&mut ima_file) {
+            Ok(Some(pcr)) if pcr != config.ima_pcr => {
+                let message = format!("The IMA measurement list reports entries extended into PCR {}, but 'ima_pcr' is set to {}", pcr, config.ima_pcr);
+
+                error!("Configuration error: {}", &message);
+                return Err(Error::Configuration(message));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Unable to read the IMA PCR from the measurement list: {}", e);
+            }
+        }
+    }
+
+    // txt-stat reads the TXT heap from /dev/mem, only allowed before
+    // dropping privileges
+    let drtm_log = drtm::read(config.drtm_log);
+
+    // The TEE guest devices are only accessible to root
+    let tee_guest = tee_evidence::TeeGuest::open(config.tee_evidence)?;
+
     let work_dir = Path::new(&config.work_dir);
     let mount = secure_mount::mount(work_dir, &config.secure_size)?;
 
+    // Warn about artifacts left behind with loose permissions
+    let unzipped = mount.join("unzipped");
+    permissions::audit(&[
+        PathBuf::from(&config.agent_data_path),
+        mount.clone(),
+        unzipped.clone(),
+        unzipped.join(&config.key_filename),
+        unzipped.join(&config.dec_payload_filename),
+    ]);
+
+    // Bind the admin socket while still running as the user that started
+    // the agent, which is the only one allowed to connect
+    let admin_listener = match &config.admin_socket {
+        Some(path) => Some(admin_socket::bind(Path::new(path))?),
+        None => None,
+    };
+
+    // Lock the memory while still privileged, the lock applies to the pages
+    // allocated after dropping privileges too
+    if config.lock_memory {
+        memory_protection::lock_memory()?;
+    }
+
     // Drop privileges
     if let Some(user_group) = &config.run_as {
         permissions::chown(user_group, &mount)?;
@@ -421,18 +1387,42 @@ async fn main() -> Result<()> {
         info!("Running the service as {}...", user_group);
     }
 
-    info!("Starting server with API version {}...", API_VERSION);
+    if config.disable_core_dumps {
+        memory_protection::disable_core_dumps()?;
+    }
+
+    info!(
+        "Starting server with API versions {}...",
+        api::API_VERSIONS
+            .iter()
+            .map(|version| version.version.to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
 
-    let mut ctx = tpm::get_tpm2_ctx()?;
+    let mut ctx =
+        tpm::wait_for_tpm2_ctx(Duration::from_secs(config.tpm_wait_timeout))
+            .await?;
 
     //  Retrieve the TPM Vendor, this allows us to warn if someone is using a
     // Software TPM ("SW")
-    if tss_esapi::utils::get_tpm_vendor(&mut ctx)?.contains("SW") {
+    let sw_tpm = tss_esapi::utils::get_tpm_vendor(&mut ctx)?.contains("SW");
+    if sw_tpm {
+        if config.reject_sw_tpm {
+            let message = "A software TPM emulator was detected and 'reject_sw_tpm' is set to 'True'".to_string();
+
+            error!("Configuration error: {}", &message);
+            return Err(Error::Configuration(message));
+        }
         warn!("INSECURE: Keylime is using a software TPM emulator rather than a real hardware TPM.");
         warn!("INSECURE: The security of Keylime is NOT linked to a hardware root of trust.");
         warn!("INSECURE: Only use Keylime in this mode for testing or debugging purposes.");
     }
 
+    // Check the configured algorithms before creating any key with them
+    let tpm_capabilities = tpm_capabilities::discover(&mut ctx)?;
+    tpm_capabilities.negotiate(&mut config)?;
+
     cfg_if::cfg_if! {
         if #[cfg(feature = "legacy-python-actions")] {
             // Verify if the python shim is installed in the expected location
@@ -448,82 +1438,129 @@ async fn main() -> Result<()> {
         }
     }
 
-    // When the EK handle is given, set auth for the Owner and
-    // Endorsement hierarchies.  Note in the Python implementation,
-    // tpm_ownerpassword option is also used for claiming ownership of
-    // TPM access, which will not be implemented here.
-    if config.ek_handle.is_some() {
-        if let Some(ref v) = config.tpm_ownerpassword {
-            let auth = Auth::try_from(v.as_bytes())?;
-            ctx.tr_set_auth(Hierarchy::Owner.into(), auth.clone())?;
-            ctx.tr_set_auth(Hierarchy::Endorsement.into(), auth)?;
-        }
-    }
+    set_hierarchy_auth(&mut ctx, &config)?;
+
+    // Transient objects loaded by the agent, so that they can be flushed
+    // when the TPM runs out of memory
+    let tpm_handles = Arc::new(tpm::HandleRegistry::default());
 
     // Gather EK values and certs
-    let ek_result = tpm::create_ek(
+    let mut ek_result = tpm::create_ek(
         &mut ctx,
+        &tpm_handles,
         config.enc_alg.into(),
         config.ek_handle.as_deref(),
     )?;
+    if ek_result.ek_cert.is_none() {
+        ek_result.ek_cert = ek_cert::fallback(&config);
+    }
+    let ek_cert_chain =
+        ek_cert::chain(&mut ctx, ek_result.ek_cert.as_deref(), &config);
 
-    // Try to load persistent Agent data
-    let agent_data = config.agent_data.clone().and_then(|data|
-        match data.valid(config.hash_alg, config.sign_alg) {
-            true => Some(data),
-            false => {
-                warn!(
-                    "Not using old {} because it is not valid with current configuration",
-                    AGENT_DATA
-                );
-                None
-            },
-        }
-    );
+    let device_identity = if config.enable_iak_idevid {
+        Some(device_identity::load(&mut ctx, &tpm_handles, &config)?)
+    } else {
+        None
+    };
 
-    // Try to reuse old AK from Agent Data
-    let old_ak = match &agent_data {
-        Some(data) => {
-            let ak_result = data.get_ak()?;
-            match tpm::load_ak(&mut ctx, ek_result.key_handle, &ak_result) {
-                Ok(ak_handle) => {
-                    info!("Loaded old AK key from {}", AGENT_DATA);
-                    Some((ak_handle, ak_result))
-                }
-                Err(e) => {
-                    warn!(
-                        "Loading old AK key from {} failed: {}",
-                        AGENT_DATA, e
-                    );
-                    None
-                }
-            }
+    // AK algorithms negotiated with the registrar take precedence over the
+    // configured ones
+    if let Some(data) = config.agent_data.as_ref().filter(|d| d.ak_negotiated)
+    {
+        config.hash_alg = data.ak_hash_alg;
+        config.sign_alg = data.ak_sign_alg;
+    }
+    // So does the HMAC algorithm it requested, while the agent supports it
+    if let Some(data) = config.agent_data.as_ref() {
+        match data.hmac_alg() {
+            Ok(Some(hmac_alg)) => config.hmac_alg = hmac_alg,
+            Ok(None) => (),
+            Err(e) => warn!(
+                "Ignoring the HMAC algorithm recorded in the agent data: {}",
+                e
+            ),
         }
+    }
+
+    // Try to reuse the AK stored in the persistent Agent data for the
+    // current algorithms
+    let mut agent_data = config.agent_data.clone();
+    let old_ak = match &mut agent_data {
+        Some(data) => load_stored_ak(
+            &mut ctx,
+            &tpm_handles,
+            ek_result.key_handle,
+            data,
+            &config,
+        )?,
         None => None,
     };
 
     // Use old AK or generate a new one and update the AgentData
     let (ak_handle, ak) = match old_ak {
         Some((ak_handle, ak)) => (ak_handle, ak),
-        None => {
-            let new_ak = tpm::create_ak(
-                &mut ctx,
-                ek_result.key_handle,
-                config.hash_alg.into(),
-                config.sign_alg.into(),
-            )?;
-            let ak_handle =
-                tpm::load_ak(&mut ctx, ek_result.key_handle, &new_ak)?;
-            (ak_handle, new_ak)
-        }
+        None => create_and_load_ak(
+            &mut ctx,
+            &tpm_handles,
+            ek_result.key_handle,
+            &config,
+        )?,
     };
 
     if config.agent_uuid == "hash_ek" {
-        config.set_ek_uuid(ek_result.public.clone())?;
+        match config.hash_ek_version {
+            HashEkVersion::Legacy => {
+                config.set_ek_uuid(ek_result.public.clone())?
+            }
+            HashEkVersion::V2
+                if config.enc_alg == algorithms::EncryptionAlgorithm::Rsa =>
+            {
+                config.set_ek_uuid_v2(ek_result.public.clone())?
+            }
+            HashEkVersion::V2 => {
+                let rsa_ek_pub =
+                    tpm::read_rsa_ek_public(&mut ctx, &tpm_handles)?;
+                config.set_ek_uuid_v2(rsa_ek_pub)?
+            }
+        }
     }
 
+    log_handle.set_agent_uuid(&config.agent_uuid);
     info!("Agent UUID: {}", config.agent_uuid);
 
+    if let Some(destination) = &config.alert_destination {
+        alert::init(
+            destination.clone(),
+            config.syslog_facility,
+            &config.agent_uuid,
+        );
+    }
+
+    let agent_status = Arc::new(status::AgentStatus::new(&config.agent_uuid));
+    if let Some(maintenance) =
+        status::Maintenance::load(&work_dir.join(status::MAINTENANCE_FILE))
+    {
+        info!(
+            "Agent in maintenance until {} (Unix time), quotes are refused",
+            maintenance.until
+        );
+        agent_status.restore_maintenance(maintenance);
+    }
+    crash_report::install(
+        agent_status.clone(),
+        tpm_handles.clone(),
+        config.abort_on_panic,
+    );
+
+    if let Some(pcr) = config.measure_agent_pcr {
+        self_measurement::measure_agent(
+            &mut ctx,
+            &config,
+            pcr,
+            &mount.join(AGENT_EVENT_LOG),
+        )?;
+    }
+
     // Generate key pair for secure transmission of u, v keys. The u, v
     // keys are two halves of the key used to decrypt the workload after
     // the Identity and Integrity Quotes sent by the agent are validated
@@ -532,14 +1569,52 @@ async fn main() -> Result<()> {
     // Since we store the u key in memory, discarding this key, which
     // safeguards u and v keys in transit, is not part of the threat model.
 
-    let (nk_pub, nk_priv) = match &agent_data {
-        Some(data) => data.get_nk()?,
-        None => crypto::rsa_generate_pair(2048)?,
+    // The NK may be kept in a PKCS#11 token instead, and is then not stored
+    // with the agent data. Otherwise it is stored sealed in the TPM when
+    // nk_seal is set, and NKs stored in clear are then sealed.
+    let (nk_pub, nk_priv) =
+        load_nk(&mut ctx, &tpm_handles, agent_data.as_ref(), &config)?;
+    // The stored sealed NK is kept as long as it holds the same key, bound to
+    // the same PCRs
+    let stored_sealed_nk = match &agent_data {
+        Some(data) if !data.has_nk() => match data.sealed_nk() {
+            Some(sealed)
+                if sealed.pcrs() == config.nk_seal_pcrs.as_slice()
+                    && data.get_nk_pub()?.public_eq(&nk_pub) =>
+            {
+                Some(sealed.clone())
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let sealed_nk = match (&config.nk_pkcs11_uri, config.nk_seal) {
+        (None, true) => match stored_sealed_nk {
+            Some(sealed) => Some(sealed),
+            None => {
+                if agent_data.as_ref().map_or(false, AgentData::has_nk) {
+                    info!("Sealing the NK stored in clear in {}", AGENT_DATA);
+                }
+                Some(SealedNk::seal(
+                    &mut ctx,
+                    &tpm_handles,
+                    &nk_priv,
+                    &config.nk_seal_pcrs,
+                )?)
+            }
+        },
+        _ => None,
+    };
+    let stored_nk = match (&config.nk_pkcs11_uri, config.nk_seal) {
+        (None, false) => Some(&nk_priv),
+        _ => None,
     };
 
     let cert: openssl::x509::X509;
+    let mtls_cert_chain;
     let mtls_cert;
     let ssl_context;
+    let mtls;
     if config.mtls_enabled {
         let keylime_ca_cert =
             match crypto::load_x509(Path::new(&config.keylime_ca_path)) {
@@ -553,77 +1628,248 @@ async fn main() -> Result<()> {
                 }
             }?;
 
-        cert = match &agent_data {
+        // The stored certificate is not reused when the NK changed, e.g.
+        // when it was moved to a PKCS#11 token
+        let stored_cert = match &agent_data {
             Some(data) => match data.get_mtls_cert()? {
-                Some(cert) => cert,
-                None => crypto::generate_x509(&nk_priv, &config.agent_uuid)?,
+                Some(cert)
+                    if cert.public_key()?.public_eq(&nk_pub)
+                        && mtls_enrollment::reusable(
+                            &cert,
+                            config.mtls_cert_enrollment,
+                        )? =>
+                {
+                    Some((cert, data.get_mtls_cert_chain()?))
+                }
+                _ => None,
             },
-            None => crypto::generate_x509(&nk_priv, &config.agent_uuid)?,
+            None => None,
         };
+        let (issued_cert, issued_chain) = match stored_cert {
+            Some(stored) => stored,
+            None => {
+                mtls_enrollment::issue_at_start(
+                    &config,
+                    &nk_priv,
+                    &keylime_ca_cert,
+                )
+                .await?
+            }
+        };
+        cert = issued_cert;
+        mtls_cert_chain = issued_chain;
         mtls_cert = Some(&cert);
-        ssl_context = Some(crypto::generate_mtls_context(
+        let tls_options = crypto::TlsOptions::from(&config);
+        let identity = Arc::new(RwLock::new(crypto::generate_mtls_identity(
+            &cert,
+            &mtls_cert_chain,
+            &nk_priv,
+            &keylime_ca_cert,
+            tls_options,
+        )?));
+        let mut builder = crypto::generate_mtls_context(
             &cert,
+            &mtls_cert_chain,
             &nk_priv,
-            keylime_ca_cert,
-        )?);
+            keylime_ca_cert.clone(),
+            tls_options,
+        )?;
+        crypto::enable_mtls_rotation(&mut builder, identity.clone());
+        ssl_context = Some(builder);
+        mtls = Some((identity, keylime_ca_cert));
     } else {
         mtls_cert = None;
+        mtls_cert_chain = Vec::new();
         ssl_context = None;
+        mtls = None;
         warn!("mTLS disabled, Tenant and Verifier will reach out to agent via HTTP");
     }
 
+    let age_identity =
+        payload_encryption::age_identity(&config, agent_data.as_ref())?;
+
     // Store new AgentData
-    let agent_data_new = AgentData::create(
+    let mut agent_data_new = AgentData::create(
         config.hash_alg,
         config.sign_alg,
+        config.ak_ecc_curve(),
         &ak,
         &nk_pub,
-        &nk_priv,
+        stored_nk,
         &mtls_cert,
     )?;
+    agent_data_new.set_mtls_cert_chain(&mtls_cert_chain)?;
+    if let Some(sealed) = &sealed_nk {
+        agent_data_new.set_sealed_nk(sealed.clone());
+    }
+    if let Some(previous) = &agent_data {
+        agent_data_new.keep_aks(previous);
+        agent_data_new.keep_hmac_alg(previous);
+    }
+    if let Some(identity) = &age_identity {
+        agent_data_new.set_age_identity(identity);
+    }
     agent_data_new.store(Path::new(&config.agent_data_path))?;
 
-    {
-        // Request keyblob material
-        let keyblob = registrar_agent::do_register_agent(
-            &config.registrar_ip,
-            &config.registrar_port,
-            &config.agent_uuid,
-            &PublicBuffer::try_from(ek_result.public.clone())?.marshall()?,
-            ek_result.ek_cert,
-            &PublicBuffer::try_from(ak.public)?.marshall()?,
-            mtls_cert,
-            config.agent_contact_ip.clone(),
-            config.agent_contact_port,
-        )
-        .await?;
-        info!("SUCCESS: Agent {} registered", config.agent_uuid);
+    if let Some(interface) = &config.agent_contact_interface {
+        match contact_ip::interface_address(interface) {
+            Ok(Some(ip)) => config.agent_contact_ip = Some(ip.to_string()),
+            Ok(None) => warn!(
+                "{} has no address, using the configured contact IP",
+                interface
+            ),
+            Err(e) => warn!(
+                "Unable to read the address of {}, using the configured contact IP: {}",
+                interface, e
+            ),
+        }
+    }
 
-        let key = tpm::activate_credential(
-            &mut ctx,
-            keyblob,
-            ak_handle,
-            ek_result.key_handle,
-        )?;
-        // Flush EK if we created it
-        if config.ek_handle.is_none() {
-            ctx.flush_context(ek_result.key_handle.into())?;
+    let metadata_providers =
+        metadata::providers(&config.registration_metadata)?;
+    let registration = RegistrationData {
+        ek_tpm: PublicBuffer::try_from(ek_result.public.clone())?
+            .marshall()?,
+        ek_cert: ek_result.ek_cert,
+        ek_cert_chain,
+        ak_tpm: PublicBuffer::try_from(ak.public)?.marshall()?,
+        mtls_cert: mtls_cert.cloned(),
+        sw_tpm,
+        metadata: metadata::collect(&metadata_providers).await,
+        device_identity,
+    };
+    let tpm_service = tpm_service::TpmService::start(ctx)?;
+    let registered = register_agent(
+        &tpm_service,
+        &tpm_handles,
+        &config,
+        &registration,
+        ak_handle,
+        Some(ek_result.key_handle),
+        &agent_status,
+        true,
+    )
+    .await?;
+
+    // The registrar requested other AK algorithms, replace the AK and
+    // register again with the new one, if the TPM supports them
+    let preferred = registered.preferred_algorithms.filter(|preferred| {
+        let supported = tpm_capabilities.supports_ak(
+            preferred.hash_alg,
+            preferred.sign_alg,
+            config.ecc_curve,
+        );
+        if !supported {
+            warn!(
+                "Registrar requested an AK using {} and {}, which the TPM does not support, the current AK is kept",
+                preferred.hash_alg, preferred.sign_alg
+            );
         }
-        let mackey = base64::encode(key.value());
-        let auth_tag = crypto::compute_hmac(
-            mackey.as_bytes(),
-            config.agent_uuid.as_bytes(),
-        )?;
-        let auth_tag = hex::encode(&auth_tag);
+        supported
+    });
+    let (ak_handle, agent_data, registration, registered) = match preferred {
+        Some(preferred) => {
+            config.hash_alg = preferred.hash_alg;
+            config.sign_alg = preferred.sign_alg;
+            let mut previous = agent_data_new.clone();
+            let (new_ak_handle, new_ak) = {
+                let mut ctx = tpm_service.lock();
+                ctx.flush_context(ak_handle.into())?;
+                tpm_handles.release(ak_handle.into());
+                match load_stored_ak(
+                    &mut ctx,
+                    &tpm_handles,
+                    ek_result.key_handle,
+                    &mut previous,
+                    &config,
+                )? {
+                    Some(stored) => stored,
+                    None => create_and_load_ak(
+                        &mut ctx,
+                        &tpm_handles,
+                        ek_result.key_handle,
+                        &config,
+                    )?,
+                }
+            };
 
-        registrar_agent::do_activate_agent(
-            &config.registrar_ip,
-            &config.registrar_port,
-            &config.agent_uuid,
-            &auth_tag,
-        )
-        .await?;
-        info!("SUCCESS: Agent {} activated", config.agent_uuid);
+            let mut agent_data_new = AgentData::create(
+                config.hash_alg,
+                config.sign_alg,
+                config.ak_ecc_curve(),
+                &new_ak,
+                &nk_pub,
+                stored_nk,
+                &mtls_cert,
+            )?;
+            agent_data_new.set_mtls_cert_chain(&mtls_cert_chain)?;
+            if let Some(sealed) = &sealed_nk {
+                agent_data_new.set_sealed_nk(sealed.clone());
+            }
+            agent_data_new.keep_aks(&previous);
+            agent_data_new.keep_hmac_alg(&previous);
+            if let Some(identity) = &age_identity {
+                agent_data_new.set_age_identity(identity);
+            }
+            agent_data_new.ak_negotiated = true;
+            agent_data_new.store(Path::new(&config.agent_data_path))?;
+
+            let registration = RegistrationData {
+                ak_tpm: PublicBuffer::try_from(new_ak.public)?.marshall()?,
+                ..registration
+            };
+            let registered = register_agent(
+                &tpm_service,
+                &tpm_handles,
+                &config,
+                &registration,
+                new_ak_handle,
+                Some(ek_result.key_handle),
+                &agent_status,
+                false,
+            )
+            .await?;
+            (new_ak_handle, agent_data_new, registration, registered)
+        }
+        None => (ak_handle, agent_data_new, registration, registered),
+    };
+
+    // Recorded for the next starts, as the verifiers expect it
+    let mut agent_data = agent_data;
+    if let Some(hmac_alg) = registered.hmac_alg {
+        config.hmac_alg = hmac_alg;
+        if agent_data.hmac_alg().ok().flatten() != Some(hmac_alg) {
+            agent_data.set_hmac_alg(hmac_alg);
+            agent_data.store(Path::new(&config.agent_data_path))?;
+        }
+    }
+
+    let measured_config = match config.measure_agent_pcr {
+        Some(_) => {
+            Some(self_measurement::measure_config(&config, config.hash_alg)?)
+        }
+        None => None,
+    };
+    if let Some(overrides) = &registered.config_overrides {
+        config_overrides::apply(overrides, &mut config);
+    }
+    // Settings of the configuration bundle of a previous payload
+    config_bundle::apply_installed(&mut config);
+    if let (Some(pcr), Some(measured)) =
+        (config.measure_agent_pcr, measured_config)
+    {
+        let hash_alg = config.hash_alg;
+        let digest = self_measurement::measure_config(&config, hash_alg)?;
+        if digest != measured {
+            let event_log = mount.join(AGENT_EVENT_LOG);
+            tpm_service
+                .run(move |ctx| {
+                    self_measurement::measure_config_update(
+                        ctx, pcr, hash_alg, &digest, &event_log,
+                    )
+                })
+                .await?;
+        }
     }
 
     let mut encr_payload = Vec::new();
@@ -637,6 +1883,11 @@ async fn main() -> Result<()> {
     let symm_key_cvar = Arc::clone(&symm_key_cvar_arc);
     let payload = Arc::clone(&encr_payload_arc);
 
+    let payload_decryptor_arc = Arc::new(
+        payload_encryption::PayloadDecryptor::new(&config, age_identity)?,
+    );
+    let payload_decryptor = Arc::clone(&payload_decryptor_arc);
+
     let revocation_cert = revocation::get_revocation_cert_path(&config)?;
     let actions_dir = Path::new(&config.revocation_actions_dir)
         .canonicalize()
@@ -655,127 +1906,260 @@ async fn main() -> Result<()> {
             ))
         })?;
 
+    // Events left undelivered by previous runs are delivered too
+    let revocation_webhook = match &config.revocation_webhook {
+        Some(_) => {
+            Some(Arc::new(revocation_webhook::RevocationWebhook::open(
+                &work_dir.join(revocation_webhook::QUEUE_DIR),
+                config.revocation_webhook_max_queued,
+            )?))
+        }
+        None => None,
+    };
+
+    let runtime_inventory = match config.runtime_inventory_pcr {
+        Some(pcr) => {
+            Some(Mutex::new(runtime_inventory::RuntimeInventory::load(
+                pcr,
+                mount.join(AGENT_EVENT_LOG),
+                config.hash_alg,
+            )?))
+        }
+        None => None,
+    };
+
+    let payload_counter = match config.payload_counter_nv_index {
+        Some(index) => {
+            let mut ctx = tpm_service.lock();
+            Some(payload_counter::PayloadCounter::load(
+                &mut ctx,
+                index,
+                mount.join(AGENT_EVENT_LOG),
+                config.hash_alg,
+                agent_status.clone(),
+            )?)
+        }
+        None => None,
+    };
+
+    // Keys to load again when reconnecting after the connection to the TPM
+    // broke
+    tpm_service.set_reload(reload_keys(
+        &config,
+        tpm_handles.clone(),
+        agent_data.get_ak()?,
+        ak_handle,
+        registration
+            .device_identity
+            .as_ref()
+            .map(|identity| identity.iak_handle),
+    ));
+
+    let allocated_pcrs = {
+        let mut ctx = tpm_service.lock();
+        tpm::allocated_pcrs(&mut ctx, config.hash_alg)?
+    };
+
     let quotedata = web::Data::new(QuoteData {
-        tpmcontext: Mutex::new(ctx),
+        tpm: tpm_service,
+        tpm_handles,
         priv_key: nk_priv,
         pub_key: nk_pub,
+        x25519_key: if config.x25519_key_wrap {
+            info!("Generating the X25519 key for U and V key transport");
+            Some(crypto::x25519_generate()?)
+        } else {
+            None
+        },
         ak_handle,
+        ak_policy: config.ak_policy()?,
+        iak_handle: registration
+            .device_identity
+            .as_ref()
+            .map(|identity| identity.iak_handle),
+        agent_data: Mutex::new(agent_data),
         ukeys: Mutex::new(KeySet::default()),
         vkeys: Mutex::new(KeySet::default()),
         payload_symm_key: symm_key_arc,
         payload_symm_key_cvar: symm_key_cvar_arc,
         encr_payload: encr_payload_arc,
-        auth_tag: Mutex::new([0u8; AUTH_TAG_LEN]),
+        ukey_payload: Mutex::new(Vec::new()),
+        payload_decryptor: payload_decryptor_arc,
+        payload_chunks: Mutex::new(payload_chunks::ChunkedUploads::default()),
+        payload_file_dirs: config
+            .payload_file_dirs
+            .iter()
+            .map(PathBuf::from)
+            .collect(),
+        nvram_indices: config.nvram_indices.clone(),
+        auth_tag: Mutex::new(Vec::new()),
         hash_alg: config.hash_alg,
         enc_alg: config.enc_alg,
         sign_alg: config.sign_alg,
+        hmac_alg: config.hmac_alg,
         agent_uuid: config.agent_uuid.clone(),
+        membership: membership::Membership::from(&config),
         revocation_cert,
         revocation_actions: config.revocation_actions.clone(),
         revocation_actions_dir: actions_dir,
         allow_payload_revocation_actions: config
             .allow_payload_revocation_actions,
+        revocation_webhook: revocation_webhook.clone(),
         secure_size: config.secure_size.clone(),
         work_dir,
         ima_ml_file,
         measuredboot_ml_file,
+        drtm_log,
+        tee_guest,
+        ima_ml_breaker: circuit_breaker::CircuitBreaker::new(
+            "IMA measurement list",
+        ),
+        measuredboot_ml_breaker: circuit_breaker::CircuitBreaker::new(
+            "measured boot event log",
+        ),
         ima_ml: Mutex::new(ImaMeasurementList::new()),
+        ima_ml_max_entries: config.ima_ml_max_entries,
+        nonce_cache: match config.quote_nonce_replay_window {
+            0 => None,
+            window => Some(nonce_cache::NonceCache::new(
+                Duration::from_secs(window),
+            )),
+        },
+        quote_cache: match config.quote_cache_ttl {
+            0 => None,
+            ttl => {
+                Some(quote_cache::QuoteCache::new(Duration::from_secs(ttl)))
+            }
+        },
+        verify_lockout: match config.verify_max_failures {
+            0 => None,
+            max_failures => Some(verify_lockout::VerifyLockout::new(
+                max_failures,
+                Duration::from_secs(config.verify_lockout),
+            )),
+        },
+        quote_keys: match config.quote_key_lifetime {
+            0 => None,
+            lifetime => {
+                Some(quote_key::QuoteKeys::new(Duration::from_secs(lifetime)))
+            }
+        },
+        ak_registry: if config.ak_per_verifier {
+            if !config.mtls_enabled {
+                warn!("'ak_per_verifier' is set but mTLS is disabled, all quotes are signed with the registered AK");
+            }
+            Some(ak_registry::AkRegistry::new(&config))
+        } else {
+            None
+        },
+        ima_pcr: config.ima_pcr,
+        allocated_pcrs,
+        tpm_capabilities,
+        tpm_operation_timeout: Duration::from_secs(
+            config.tpm_operation_timeout,
+        ),
         secure_mount: PathBuf::from(&mount),
+        status: agent_status.clone(),
+        quote_qualifying_data: config.quote_qualifying_data.clone(),
+        runtime_inventory,
+        payload_counter,
     });
 
-    let actix_server =
-        HttpServer::new(move || {
-            App::new()
-                .wrap(middleware::ErrorHandlers::new().handler(
-                    http::StatusCode::NOT_FOUND,
-                    errors_handler::wrap_404,
-                ))
-                .wrap(middleware::Logger::new(
-                    "%r from %a result %s (took %D ms)",
-                ))
-                .wrap_fn(|req, srv| {
-                    info!(
-                        "{} invoked from {:?} with uri {}",
-                        req.head().method,
-                        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
-                        req.uri()
-                    );
-                    srv.call(req)
-                })
-                .app_data(quotedata.clone())
-                .app_data(
-                    web::JsonConfig::default()
-                        .error_handler(errors_handler::json_parser_error),
-                )
-                .app_data(
-                    web::QueryConfig::default()
-                        .error_handler(errors_handler::query_parser_error),
-                )
-                .app_data(
-                    web::PathConfig::default()
-                        .error_handler(errors_handler::path_parser_error),
-                )
-                .service(
-                    web::scope(&format!("/{}", API_VERSION))
-                        .service(
-                            web::scope("/keys")
-                                .service(web::resource("/pubkey").route(
-                                    web::get().to(keys_handler::pubkey),
-                                ))
-                                .service(web::resource("/ukey").route(
-                                    web::post().to(keys_handler::u_key),
-                                ))
-                                .service(web::resource("/verify").route(
-                                    web::get().to(keys_handler::verify),
-                                ))
-                                .service(web::resource("/vkey").route(
-                                    web::post().to(keys_handler::v_key),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::keys_default,
-                                )),
-                        )
-                        .service(
-                            web::scope("/notifications")
-                                .service(web::resource("/revocation").route(
-                                    web::post().to(
-                                        notifications_handler::revocation,
-                                    ),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::notifications_default,
-                                )),
-                        )
-                        .service(
-                            web::scope("/quotes")
-                                .service(web::resource("/identity").route(
-                                    web::get().to(quotes_handler::identity),
-                                ))
-                                .service(web::resource("/integrity").route(
-                                    web::get().to(quotes_handler::integrity),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::quotes_default,
-                                )),
-                        )
-                        .default_service(web::to(
-                            errors_handler::api_default,
-                        )),
-                )
-                .service(
-                    web::resource("/version")
-                        .route(web::get().to(version_handler::version)),
-                )
-                .service(
-                    web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
-                        .to(errors_handler::version_not_supported),
+    let admin = Arc::new(admin_socket::Admin {
+        config: config.clone(),
+        quote_data: quotedata.clone(),
+        registration: Mutex::new(registration),
+        mtls,
+        log_handle: log_handle.clone(),
+        contact_ip: Mutex::new(config.agent_contact_ip.clone()),
+    });
+
+    let worker_quote_data = quotedata.clone();
+
+    let http_headers = Arc::new(http_headers::HttpHeaders::new(
+        &config,
+        config.mtls_enabled && ssl_context.is_some(),
+    ));
+
+    let actix_server = HttpServer::new(move || {
+        let http_headers = http_headers.clone();
+        App::new()
+            .wrap(middleware::ErrorHandlers::new().handler(
+                http::StatusCode::NOT_FOUND,
+                errors_handler::wrap_404,
+            ))
+            .wrap(
+                middleware::Logger::new(
+                    "%r from %a (%{client}xi) result %s (took %D ms)",
                 )
-                .default_service(web::to(errors_handler::app_default))
-        })
-        // Disable default signal handlers.  See:
-        // https://github.com/actix/actix-web/issues/2739
-        // for details.
-        .disable_signals();
+                .custom_request_replace("client", peer_identity::client),
+            )
+            .wrap_fn(|req, srv| {
+                // No peer address over Unix sockets
+                info!(
+                    "{} invoked from {} ({}) with uri {}",
+                    req.head().method,
+                    req.connection_info().peer_addr().unwrap_or("-"),
+                    peer_identity::client(&req),
+                    req.uri()
+                );
+                crash_report::record(
+                    "http",
+                    format!("{} {}", req.head().method, req.path()),
+                );
+                srv.call(req)
+            })
+            .wrap_fn(|req, srv| {
+                let request_id = log_output::request_id(&req);
+                log_output::in_request(request_id, || srv.call(req))
+            })
+            .wrap_fn(move |req, srv| {
+                // Answered without reaching the handlers
+                if let Some(response) = http_headers.preflight(&req) {
+                    return Either::Left(ready(Ok::<_, actix_web::Error>(
+                        req.into_response(response),
+                    )));
+                }
+                let origin = http_headers.allowed_origin(&req);
+                let http_headers = http_headers.clone();
+                Either::Right(srv.call(req).map_ok(move |mut res| {
+                    http_headers.apply(origin, res.headers_mut());
+                    res.map_into_boxed_body()
+                }))
+            })
+            .app_data(quotedata.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(errors_handler::json_parser_error),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(errors_handler::query_parser_error),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(errors_handler::path_parser_error),
+            )
+            .configure(|cfg| {
+                for version in api::API_VERSIONS {
+                    let _ = cfg.service(version.scope());
+                }
+            })
+            .service(
+                web::resource("/version")
+                    .route(web::get().to(version_handler::version)),
+            )
+            .service(
+                web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
+                    .to(errors_handler::version_not_supported),
+            )
+            .default_service(web::to(errors_handler::app_default))
+    })
+    .on_connect(peer_identity::on_connect)
+    // Disable default signal handlers.  See:
+    // https://github.com/actix/actix-web/issues/2739
+    // for details.
+    .disable_signals();
 
     let server;
     if config.mtls_enabled && ssl_context.is_some() {
@@ -801,19 +2185,114 @@ async fn main() -> Result<()> {
         );
     };
 
+    if let Some(listener) = admin_listener {
+        let _ = rt::spawn(status::track(
+            agent_status.clone(),
+            "admin_socket",
+            admin_socket::serve(
+                listener,
+                agent_status.clone(),
+                Some(admin.clone()),
+            ),
+        ));
+    }
+
+    if config.agent_data_audit_interval > 0 {
+        let _ = rt::spawn(status::track(
+            agent_status.clone(),
+            "agent_data_audit",
+            agent_data_audit::audit(
+                admin.quote_data.clone(),
+                PathBuf::from(&config.agent_data_path),
+                config.agent_data_audit_interval,
+            ),
+        ));
+    }
+
+    if admin.mtls.is_some() {
+        let _ = rt::spawn(status::track(
+            agent_status.clone(),
+            "mtls_renewal",
+            mtls_enrollment::renew(admin.clone(), agent_status.clone()),
+        ));
+    }
+
+    if let Some(interface) = config.agent_contact_interface.clone() {
+        let _ = rt::spawn(status::track(
+            agent_status.clone(),
+            "contact_ip_watch",
+            contact_ip::watch(interface, admin, agent_status.clone()),
+        ));
+    }
+
+    if let (Some(webhook), Some(queue)) = (
+        config.revocation_webhook.clone(),
+        revocation_webhook.clone(),
+    ) {
+        let max_retries = config.revocation_webhook_max_retries;
+        let _ = rt::spawn(status::track(
+            agent_status.clone(),
+            "revocation_webhook",
+            async move { queue.deliver(webhook, max_retries).await },
+        ));
+    }
+
+    if let Some(webhook) = config.ima_change_webhook.clone() {
+        let _ = rt::spawn(status::track(
+            agent_status.clone(),
+            "ima_watch",
+            ima_watch::watch(
+                PathBuf::from(ima_watch::IMA_SECURITYFS),
+                webhook,
+                config.agent_uuid.clone(),
+                config.ima_change_check_interval,
+            ),
+        ));
+    }
+
+    let _ = rt::spawn(status::track(
+        agent_status.clone(),
+        "log_level_signal",
+        log_level::cycle_on_signal(log_level_signal, log_handle),
+    ));
+
+    if config.disk_space_check_interval > 0 {
+        // Detached, the monitor runs until the agent exits
+        agent_status.set_task("disk_monitor", status::TaskState::Running);
+        let _ = rt::spawn(disk_usage::monitor(
+            vec![PathBuf::from(&config.work_dir), PathBuf::from(&mount)],
+            config.disk_space_warn_percent,
+            config.disk_space_check_interval,
+        ));
+    }
+
     let server_handle = server.handle();
-    let server_task = rt::spawn(server).map_err(Error::from);
-    let worker_task = rt::spawn(worker(
-        symm_key,
-        symm_key_cvar,
-        payload,
-        config.clone(),
-        PathBuf::from(&mount),
+    let server_task = rt::spawn(status::track(
+        agent_status.clone(),
+        "http_server",
+        server.map_err(Error::from),
+    ))
+    .map_err(Error::from);
+    let worker_task = rt::spawn(status::track(
+        agent_status.clone(),
+        "worker",
+        worker(
+            worker_quote_data,
+            symm_key,
+            symm_key_cvar,
+            payload,
+            payload_decryptor,
+            config.clone(),
+            PathBuf::from(&mount),
+            agent_status.clone(),
+            revocation_webhook,
+        ),
     ))
     .map_err(Error::from);
 
     let result = try_join!(server_task, worker_task);
     server_handle.stop(true).await;
+    telemetry::shutdown();
     result.map(|_| ())
 }
 
@@ -842,21 +2321,39 @@ mod testing {
         pub(crate) fn fixture() -> Result<Self> {
             let test_config = KeylimeConfig::default();
             let mut ctx = tpm::get_tpm2_ctx()?;
+            let allocated_pcrs = tpm::allocated_pcrs(
+                &mut ctx,
+                algorithms::HashAlgorithm::Sha256,
+            )?;
+            let tpm_capabilities = tpm_capabilities::discover(&mut ctx)?;
 
             // Gather EK and AK key values and certs
-            let ek_result =
-                tpm::create_ek(&mut ctx, test_config.enc_alg.into(), None)?;
+            let tpm_handles = Arc::new(tpm::HandleRegistry::default());
+            let ek_result = tpm::create_ek(
+                &mut ctx,
+                &tpm_handles,
+                test_config.enc_alg.into(),
+                None,
+            )?;
 
             let ak_result = tpm::create_ak(
                 &mut ctx,
                 ek_result.key_handle,
                 test_config.hash_alg.into(),
                 test_config.sign_alg.into(),
+                test_config.ak_ecc_curve(),
+                None,
+            )?;
+            let ak_handle = tpm::load_ak(
+                &mut ctx,
+                &tpm_handles,
+                ek_result.key_handle,
+                &ak_result,
+                test_config.tpm_encrypt_sessions,
             )?;
-            let ak_handle =
-                tpm::load_ak(&mut ctx, ek_result.key_handle, &ak_result)?;
             let ak_tpm2b_pub =
-                PublicBuffer::try_from(ak_result.public)?.marshall()?;
+                PublicBuffer::try_from(ak_result.public.clone())?
+                    .marshall()?;
 
             let rsa_key_path = Path::new(env!("CARGO_MANIFEST_DIR"))
                 .join("test-data")
@@ -864,6 +2361,15 @@ mod testing {
 
             let (nk_pub, nk_priv) =
                 crypto::testing::rsa_import_pair(&rsa_key_path)?;
+            let agent_data = AgentData::create(
+                test_config.hash_alg,
+                test_config.sign_alg,
+                test_config.ak_ecc_curve(),
+                &ak_result,
+                &nk_pub,
+                Some(&nk_priv),
+                &None,
+            )?;
 
             let mut encr_payload = Vec::new();
 
@@ -904,31 +2410,74 @@ mod testing {
                 };
 
             Ok(QuoteData {
-                tpmcontext: Mutex::new(ctx),
+                tpm: tpm_service::TpmService::start(ctx)?,
+                tpm_handles,
                 priv_key: nk_priv,
                 pub_key: nk_pub,
+                x25519_key: None,
                 ak_handle,
+                ak_policy: None,
+                iak_handle: None,
+                agent_data: Mutex::new(agent_data),
                 ukeys: Mutex::new(KeySet::default()),
                 vkeys: Mutex::new(KeySet::default()),
                 payload_symm_key: symm_key_arc,
                 payload_symm_key_cvar: symm_key_cvar_arc,
                 encr_payload: encr_payload_arc,
-                auth_tag: Mutex::new([0u8; AUTH_TAG_LEN]),
+                ukey_payload: Mutex::new(Vec::new()),
+                payload_decryptor: Arc::new(
+                    payload_encryption::PayloadDecryptor::default(),
+                ),
+                payload_chunks: Mutex::new(
+                    payload_chunks::ChunkedUploads::default(),
+                ),
+                payload_file_dirs: Vec::new(),
+                nvram_indices: Vec::new(),
+                auth_tag: Mutex::new(Vec::new()),
                 hash_alg: algorithms::HashAlgorithm::Sha256,
                 enc_alg: algorithms::EncryptionAlgorithm::Rsa,
                 sign_alg: algorithms::SignAlgorithm::RsaSsa,
-                agent_uuid: test_config.agent_uuid,
+                hmac_alg: algorithms::HmacAlgorithm::default(),
+                agent_uuid: test_config.agent_uuid.clone(),
+                membership: membership::Membership::from(&test_config),
                 revocation_cert,
                 revocation_actions: String::from(""),
                 revocation_actions_dir: actions_dir,
                 allow_payload_revocation_actions: test_config
                     .allow_payload_revocation_actions,
+                revocation_webhook: None,
                 secure_size: test_config.secure_size,
                 work_dir,
                 ima_ml_file,
                 measuredboot_ml_file,
+                drtm_log: None,
+                tee_guest: None,
+                ima_ml_breaker: circuit_breaker::CircuitBreaker::new(
+                    "IMA measurement list",
+                ),
+                measuredboot_ml_breaker: circuit_breaker::CircuitBreaker::new(
+                    "measured boot event log",
+                ),
                 ima_ml: Mutex::new(ImaMeasurementList::new()),
+                ima_ml_max_entries: test_config.ima_ml_max_entries,
+                nonce_cache: None,
+                quote_cache: None,
+                verify_lockout: None,
+                quote_keys: None,
+                ak_registry: None,
+                ima_pcr: test_config.ima_pcr,
+                allocated_pcrs,
+                tpm_capabilities,
+                tpm_operation_timeout: Duration::from_secs(
+                    test_config.tpm_operation_timeout,
+                ),
                 secure_mount,
+                status: Arc::new(status::AgentStatus::new(
+                    &test_config.agent_uuid,
+                )),
+                quote_qualifying_data: Vec::new(),
+                runtime_inventory: None,
+                payload_counter: None,
             })
         }
     }
@@ -974,4 +2523,68 @@ echo hello > test-output
         .unwrap(); //#[allow_ci]
         assert!(dir.path().join("test-output").exists());
     }
+
+    #[test]
+    fn test_write_out_key_and_payload() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let key_path = dir.path().join("derived_tci_key");
+        let dec_payload_path = dir.path().join("decrypted_payload");
+        let key = SymmKey::try_from(&b"0123456789012345"[..]).unwrap(); //#[allow_ci]
+
+        // Replacing existing files works as well
+        fs::write(&key_path, b"old key").unwrap(); //#[allow_ci]
+
+        write_out_key_and_payload(
+            b"payload",
+            &dec_payload_path,
+            &key,
+            &key_path,
+        )
+        .unwrap(); //#[allow_ci]
+
+        assert_eq!(fs::read(&key_path).unwrap(), key.bytes()); //#[allow_ci]
+        assert_eq!(fs::read(&dec_payload_path).unwrap(), b"payload"); //#[allow_ci]
+
+        // No temporary files are left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2); //#[allow_ci]
+    }
+
+    #[cfg(feature = "testing")]
+    fn change_owner_auth(ctx: &mut Context, auth: Auth) -> Result<()> {
+        use tss_esapi::interface_types::{
+            resource_handles::AuthHandle, session_handles::AuthSession,
+        };
+        ctx.execute_with_session(Some(AuthSession::Password), |ctx| {
+            ctx.hierarchy_change_auth(AuthHandle::Owner, auth)
+        })?;
+        Ok(())
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_seal_nk_with_owner_password() {
+        let password = Auth::try_from(b"owner-secret".to_vec()).unwrap(); //#[allow_ci]
+        let mut ctx = tpm::get_tpm2_ctx().unwrap(); //#[allow_ci]
+        change_owner_auth(&mut ctx, password).unwrap(); //#[allow_ci]
+        drop(ctx);
+
+        // A new connection only knows the owner password from the
+        // configuration, with or without an EK handle
+        let config = KeylimeConfig {
+            tpm_ownerpassword: Some("owner-secret".to_string()),
+            ek_handle: None,
+            ..KeylimeConfig::default()
+        };
+        let mut ctx = tpm::get_tpm2_ctx().unwrap(); //#[allow_ci]
+        let handles = tpm::HandleRegistry::default();
+        let (_, nk_priv) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let result = set_hierarchy_auth(&mut ctx, &config)
+            .and_then(|_| SealedNk::seal(&mut ctx, &handles, &nk_priv, &[]))
+            .and_then(|sealed| sealed.unseal(&mut ctx, &handles));
+
+        // Restore the empty password before checking the result
+        let empty = Auth::try_from(Vec::new()).unwrap(); //#[allow_ci]
+        change_owner_auth(&mut ctx, empty).unwrap(); //#[allow_ci]
+        assert!(result.unwrap().public_eq(&nk_priv)); //#[allow_ci]
+    }
 }
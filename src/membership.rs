@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Group and tags of the agent in the fleet.
+//
+// 'agent_group' and 'agent_tags' place the agent in cohorts, e.g. all the
+// nodes with GPUs, so that verifier policies and revocation broadcasts can
+// target them without maintaining lists of UUIDs. They are sent to the
+// registrar and reported at /agent/info. An agent is in at most one group,
+// and has any number of tags. Names are made of letters, digits, '.', '_'
+// and '-'.
+
+use crate::{
+    common::KeylimeConfig,
+    error::{Error, Result},
+};
+use serde::{Deserialize, Serialize};
+
+const MAX_NAME_LEN: usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Membership {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl From<&KeylimeConfig> for Membership {
+    fn from(config: &KeylimeConfig) -> Self {
+        Membership {
+            group: config.agent_group.clone(),
+            tags: config.agent_tags.clone(),
+        }
+    }
+}
+
+/*
+ * Input: name of the option and group or tag name
+ * Return: the name, or a configuration error when it is not valid
+ */
+pub(crate) fn check_name(option: &str, name: &str) -> Result<String> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| {
+            c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-')
+        });
+    if !valid {
+        return Err(Error::Configuration(format!(
+            "{} must be made of up to {} letters, digits, '.', '_' or '-', got \"{}\"",
+            option, MAX_NAME_LEN, name
+        )));
+    }
+    Ok(name.to_string())
+}
+
+/*
+ * Input: comma separated tags
+ * Return: the tags, without duplicates, in the configured order
+ */
+pub(crate) fn parse_tags(value: &str) -> Result<Vec<String>> {
+    let mut tags: Vec<String> = Vec::new();
+    for tag in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let tag = check_name("agent_tags", tag)?;
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(tags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check_name() {
+        assert_eq!(
+            check_name("agent_group", "gpu-nodes").unwrap(), //#[allow_ci]
+            "gpu-nodes"
+        );
+        assert!(check_name("agent_group", "rack_4.eu-west").is_ok());
+        assert!(check_name("agent_group", "").is_err());
+        assert!(check_name("agent_group", "gpu nodes").is_err());
+        assert!(check_name("agent_group", &"a".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        assert_eq!(
+            parse_tags("gpu-nodes, eu-west,,gpu-nodes").unwrap(), //#[allow_ci]
+            vec!["gpu-nodes", "eu-west"]
+        );
+        assert!(parse_tags("").unwrap().is_empty()); //#[allow_ci]
+        assert!(parse_tags("gpu nodes").is_err());
+    }
+
+    #[test]
+    fn test_serialize() {
+        let membership = Membership {
+            group: Some("gpu-nodes".to_string()),
+            tags: vec!["eu-west".to_string()],
+        };
+        assert_eq!(
+            serde_json::to_value(&membership).unwrap(), //#[allow_ci]
+            json!({"group": "gpu-nodes", "tags": ["eu-west"]})
+        );
+        assert_eq!(
+            serde_json::to_value(Membership::default()).unwrap(), //#[allow_ci]
+            json!({})
+        );
+    }
+}
@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Keeping key material out of swap and core dumps.
+//
+// The NK private key is held by OpenSSL and the U, V and payload keys in
+// the agent memory. With 'lock_memory', every page of the agent is locked in
+// RAM, including the pages allocated later, as OpenSSL does not tell where
+// the NK key lives. With 'disable_core_dumps', the core size limit is set to
+// 0 and the process is made non-dumpable. The kernel resets the dumpable flag
+// when the credentials change, so it is only cleared once privileges are
+// dropped.
+
+use crate::error::{Error, Result};
+use log::*;
+use std::io;
+
+/*
+ * Locks the current and future pages of the process in RAM. Fails with a
+ * configuration error when the process is not allowed to lock that much
+ * memory.
+ */
+pub(crate) fn lock_memory() -> Result<()> {
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        let e = io::Error::last_os_error();
+        return Err(Error::Configuration(format!(
+            "'lock_memory' is set but the agent memory cannot be locked: {}. The agent needs CAP_IPC_LOCK or a larger RLIMIT_MEMLOCK",
+            e
+        )));
+    }
+    info!("Locked the agent memory in RAM");
+    Ok(())
+}
+
+// Sets the core size limit to 0, inherited by the payload scripts too
+fn disable_core_limit() -> io::Result<()> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn clear_dumpable() -> io::Result<()> {
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/*
+ * Prevents core dumps of the process, to be called once privileges are
+ * dropped
+ */
+pub(crate) fn disable_core_dumps() -> Result<()> {
+    disable_core_limit()?;
+    clear_dumpable()?;
+    debug!("Disabled core dumps of the agent");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disable_core_dumps() {
+        disable_core_dumps().unwrap(); //#[allow_ci]
+
+        let mut limit = libc::rlimit {
+            rlim_cur: 1,
+            rlim_max: 1,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_CORE, &mut limit) },
+            0
+        );
+        assert_eq!(limit.rlim_cur, 0);
+        assert_eq!(limit.rlim_max, 0);
+        assert_eq!(unsafe { libc::prctl(libc::PR_GET_DUMPABLE) }, 0);
+    }
+}
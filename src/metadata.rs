@@ -0,0 +1,419 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Host metadata sent on registration.
+//
+// The providers listed in 'registration_metadata' describe the host: its
+// hostname, and for cloud instances the instance ID, region and image ID
+// from the metadata service of the cloud. Verifier-side policy and inventory
+// can then correlate the agent with the instance without looking it up
+// elsewhere. Metadata is informative only, a provider that fails is skipped.
+
+use crate::error::{Error, Result};
+use futures::future::{BoxFuture, FutureExt};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{io, time::Duration};
+
+// Metadata services answer right away, off-cloud the address is unreachable
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+static AWS_METADATA_URL: &str = "http://169.254.169.254";
+static AZURE_METADATA_URL: &str = "http://169.254.169.254";
+static GCP_METADATA_URL: &str = "http://metadata.google.internal";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HostMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_id: Option<String>,
+}
+
+impl HostMetadata {
+    pub(crate) fn is_empty(&self) -> bool {
+        self == &HostMetadata::default()
+    }
+
+    // Values already set take precedence
+    fn merge(&mut self, other: HostMetadata) {
+        self.hostname = self.hostname.take().or(other.hostname);
+        self.instance_id = self.instance_id.take().or(other.instance_id);
+        self.region = self.region.take().or(other.region);
+        self.image_id = self.image_id.take().or(other.image_id);
+    }
+}
+
+pub(crate) trait MetadataProvider: std::fmt::Debug {
+    fn name(&self) -> &'static str;
+
+    fn fetch<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<HostMetadata>>;
+}
+
+#[derive(Debug)]
+struct Hostname;
+
+impl MetadataProvider for Hostname {
+    fn name(&self) -> &'static str {
+        "hostname"
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        _client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<HostMetadata>> {
+        let mut buf = [0u8; 256];
+        let result = if unsafe {
+            libc::gethostname(buf.as_mut_ptr().cast(), buf.len())
+        } != 0
+        {
+            Err(io::Error::last_os_error().into())
+        } else {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            Ok(HostMetadata {
+                hostname: Some(
+                    String::from_utf8_lossy(&buf[..len]).into_owned(),
+                ),
+                ..Default::default()
+            })
+        };
+        futures::future::ready(result).boxed()
+    }
+}
+
+// EC2 instance metadata service, with a session token (IMDSv2)
+#[derive(Debug)]
+struct Aws {
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AwsIdentityDocument {
+    instance_id: String,
+    region: String,
+    image_id: String,
+}
+
+impl MetadataProvider for Aws {
+    fn name(&self) -> &'static str {
+        "aws"
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<HostMetadata>> {
+        async move {
+            let token = client
+                .put(format!("{}/latest/api/token", self.base_url))
+                .header("X-aws-ec2-metadata-token-ttl-seconds", "60")
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+            let document: AwsIdentityDocument = client
+                .get(format!(
+                    "{}/latest/dynamic/instance-identity/document",
+                    self.base_url
+                ))
+                .header("X-aws-ec2-metadata-token", token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(HostMetadata {
+                instance_id: Some(document.instance_id),
+                region: Some(document.region),
+                image_id: Some(document.image_id),
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct Azure {
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureCompute {
+    vm_id: String,
+    location: String,
+    storage_profile: Option<AzureStorageProfile>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureStorageProfile {
+    image_reference: Option<AzureImageReference>,
+}
+
+#[derive(Deserialize)]
+struct AzureImageReference {
+    id: Option<String>,
+}
+
+impl MetadataProvider for Azure {
+    fn name(&self) -> &'static str {
+        "azure"
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<HostMetadata>> {
+        async move {
+            let compute: AzureCompute = client
+                .get(format!(
+                    "{}/metadata/instance/compute?api-version=2021-02-01",
+                    self.base_url
+                ))
+                .header("Metadata", "true")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            // Images from the marketplace have no ID
+            let image_id = compute
+                .storage_profile
+                .and_then(|profile| profile.image_reference)
+                .and_then(|image| image.id)
+                .filter(|id| !id.is_empty());
+            Ok(HostMetadata {
+                instance_id: Some(compute.vm_id),
+                region: Some(compute.location),
+                image_id,
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+}
+
+#[derive(Debug)]
+struct Gcp {
+    base_url: String,
+}
+
+#[derive(Deserialize)]
+struct GcpInstance {
+    id: serde_json::Value,
+    // projects/<number>/zones/<region>-<zone>
+    zone: String,
+    image: Option<String>,
+}
+
+// The region is the zone without its last component
+fn gcp_region(zone: &str) -> Option<String> {
+    let zone = zone.rsplit('/').next()?;
+    let (region, _) = zone.rsplit_once('-')?;
+    Some(region.to_string())
+}
+
+impl MetadataProvider for Gcp {
+    fn name(&self) -> &'static str {
+        "gcp"
+    }
+
+    fn fetch<'a>(
+        &'a self,
+        client: &'a reqwest::Client,
+    ) -> BoxFuture<'a, Result<HostMetadata>> {
+        async move {
+            let instance: GcpInstance = client
+                .get(format!(
+                    "{}/computeMetadata/v1/instance/?recursive=true",
+                    self.base_url
+                ))
+                .header("Metadata-Flavor", "Google")
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            // The ID is a number too large for some JSON parsers
+            let instance_id = match instance.id {
+                serde_json::Value::String(id) => id,
+                id => id.to_string(),
+            };
+            Ok(HostMetadata {
+                instance_id: Some(instance_id),
+                region: gcp_region(&instance.zone),
+                image_id: instance.image.filter(|image| !image.is_empty()),
+                ..Default::default()
+            })
+        }
+        .boxed()
+    }
+}
+
+/*
+ * Input: provider names, as set in 'registration_metadata'
+ * Return: Result wrap the providers, in the same order
+ */
+pub(crate) fn providers(
+    names: &[String],
+) -> Result<Vec<Box<dyn MetadataProvider>>> {
+    names
+        .iter()
+        .map(|name| -> Result<Box<dyn MetadataProvider>> {
+            match name.as_str() {
+                "hostname" => Ok(Box::new(Hostname)),
+                "aws" => Ok(Box::new(Aws {
+                    base_url: AWS_METADATA_URL.to_string(),
+                })),
+                "azure" => Ok(Box::new(Azure {
+                    base_url: AZURE_METADATA_URL.to_string(),
+                })),
+                "gcp" => Ok(Box::new(Gcp {
+                    base_url: GCP_METADATA_URL.to_string(),
+                })),
+                other => Err(Error::Configuration(format!(
+                    "unknown registration_metadata provider {}, expected hostname, aws, azure or gcp",
+                    other
+                ))),
+            }
+        })
+        .collect()
+}
+
+/*
+ * Input: metadata providers
+ * Return: the metadata they provided, the first provider setting a value
+ *         wins
+ */
+pub(crate) async fn collect(
+    providers: &[Box<dyn MetadataProvider>],
+) -> HostMetadata {
+    let mut metadata = HostMetadata::default();
+    if providers.is_empty() {
+        return metadata;
+    }
+    // The metadata services are link-local, a proxy set in the environment
+    // cannot reach them and would receive the IMDS token
+    let client = match reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .no_proxy()
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Unable to create the metadata client: {}", e);
+            return metadata;
+        }
+    };
+    for provider in providers {
+        match provider.fetch(&client).await {
+            Ok(provided) => metadata.merge(provided),
+            Err(e) => warn!(
+                "Unable to get the {} metadata, skipping it: {}",
+                provider.name(),
+                e
+            ),
+        }
+    }
+    metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_providers() {
+        let names = vec!["hostname".to_string(), "gcp".to_string()];
+        let providers = providers(&names).unwrap(); //#[allow_ci]
+        assert_eq!(providers[0].name(), "hostname");
+        assert_eq!(providers[1].name(), "gcp");
+        assert!(super::providers(&["openstack".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut metadata = HostMetadata {
+            hostname: Some("node".to_string()),
+            ..Default::default()
+        };
+        assert!(!metadata.is_empty());
+        metadata.merge(HostMetadata {
+            hostname: Some("other".to_string()),
+            region: Some("eu-west-1".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(metadata.hostname, Some("node".to_string()));
+        assert_eq!(metadata.region, Some("eu-west-1".to_string()));
+        assert!(HostMetadata::default().is_empty());
+    }
+
+    #[test]
+    fn test_gcp_region() {
+        assert_eq!(
+            gcp_region("projects/1234/zones/us-central1-a"),
+            Some("us-central1".to_string())
+        );
+        assert_eq!(gcp_region("zone"), None);
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_aws() {
+        use wiremock::{
+            matchers::{header, method, path},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let mock_server = MockServer::start().await;
+        let token = Mock::given(method("PUT"))
+            .and(path("/latest/api/token"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_string("token"),
+            );
+        mock_server.register(token).await;
+        let document = serde_json::json!({
+            "instanceId": "i-1234567890abcdef0",
+            "region": "eu-west-1",
+            "imageId": "ami-0abcdef1234567890",
+            "architecture": "x86_64"
+        });
+        let mock = Mock::given(method("GET"))
+            .and(path("/latest/dynamic/instance-identity/document"))
+            .and(header("X-aws-ec2-metadata-token", "token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(document));
+        mock_server.register(mock).await;
+
+        let aws: Box<dyn MetadataProvider> = Box::new(Aws {
+            base_url: mock_server.uri(),
+        });
+        let metadata = collect(&[aws]).await;
+        assert_eq!(
+            metadata,
+            HostMetadata {
+                hostname: None,
+                instance_id: Some("i-1234567890abcdef0".to_string()),
+                region: Some("eu-west-1".to_string()),
+                image_id: Some("ami-0abcdef1234567890".to_string()),
+            }
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_hostname() {
+        let hostname: Box<dyn MetadataProvider> = Box::new(Hostname);
+        let metadata = collect(&[hostname]).await;
+        assert!(metadata.hostname.is_some());
+    }
+}
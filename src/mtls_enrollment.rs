@@ -0,0 +1,379 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Enrollment of the mTLS certificate.
+//
+// By default the agent signs its mTLS certificate with the NK. With
+// 'mtls_cert_enrollment' set to 'est' or 'keylime-ca', it instead sends a
+// certificate signing request for the NK to 'mtls_cert_enrollment_url' and
+// serves the returned certificate along with the intermediate CA certificates
+// of its chain. The endpoint must present a certificate issued by the Keylime
+// CA or by a CA of the system trust store.
+//
+// With 'est', the URL is the EST base, e.g.
+// https://est.example.com/.well-known/est, and the certificate is requested
+// with the simpleenroll operation of RFC 7030. With 'keylime-ca', the PEM
+// encoded CSR is posted to the URL as {"csr": ...} and the response carries
+// the PEM encoded certificate and its chain in results.certificate.
+//
+// The certificate is kept in the agent data and reused at the next starts,
+// until a third of its validity is left, the NK changes or the enrollment
+// mode does. While the agent runs, it is renewed and registered again once a
+// third of its validity is left. An endpoint unreachable at start is retried
+// with an exponential backoff before giving up.
+
+use crate::{
+    admin_socket::{self, Admin},
+    common::{JsonWrapper, KeylimeConfig},
+    crypto,
+    error::{Error, Result},
+    status::AgentStatus,
+};
+use log::*;
+use openssl::{
+    asn1::{Asn1Time, TimeDiff},
+    pkcs7::Pkcs7,
+    pkey::{PKey, Private},
+    x509::{X509Req, X509VerifyResult, X509},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{convert::TryFrom, sync::Arc, time::Duration};
+use tokio::time;
+
+const ENROLLMENT_TIMEOUT: Duration = Duration::from_secs(30);
+// Attempts of the enrollment at start, about 4 minutes with the backoff
+const ENROLLMENT_ATTEMPTS: u32 = 10;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const RENEWAL_RETRY_DELAY: Duration = Duration::from_secs(300);
+// The certificate is renewed once 1 / RENEW_DIVISOR of its validity is left
+const RENEW_DIVISOR: i64 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum EnrollmentMode {
+    SelfSigned,
+    // Simple enrollment of RFC 7030
+    Est,
+    KeylimeCa,
+}
+
+impl TryFrom<&str> for EnrollmentMode {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "self-signed" => Ok(EnrollmentMode::SelfSigned),
+            "est" => Ok(EnrollmentMode::Est),
+            "keylime-ca" => Ok(EnrollmentMode::KeylimeCa),
+            _ => Err(Error::Configuration(format!(
+                "mtls_cert_enrollment must be one of 'self-signed', 'est' or 'keylime-ca', got {}",
+                value
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct KeylimeCaResults {
+    // PEM encoded certificate followed by its chain
+    certificate: String,
+}
+
+// Delay before the given failed attempt is retried
+fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    INITIAL_BACKOFF
+        .checked_mul(factor)
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+fn seconds(diff: TimeDiff) -> i64 {
+    i64::from(diff.days) * 86400 + i64::from(diff.secs)
+}
+
+/*
+ * Input: mTLS certificate
+ * Return: Result wrap the time left before it is due for renewal, zero when
+ *         it already is
+ */
+pub(crate) fn renew_in(cert: &X509) -> Result<Duration> {
+    let now = Asn1Time::days_from_now(0)?;
+    let validity = seconds(cert.not_before().diff(cert.not_after())?);
+    let left = seconds(now.diff(cert.not_after())?);
+    let due_in = left - validity / RENEW_DIVISOR;
+    Ok(Duration::from_secs(due_in.max(0) as u64))
+}
+
+/*
+ * Input: stored mTLS certificate and the enrollment mode
+ * Return: Result wrap whether the certificate can be reused
+ *
+ * A self-signed certificate is not reused once enrollment is configured, nor
+ * the other way round.
+ */
+pub(crate) fn reusable(cert: &X509, mode: EnrollmentMode) -> Result<bool> {
+    if renew_in(cert)?.is_zero() {
+        info!("Stored mTLS certificate is due for renewal");
+        return Ok(false);
+    }
+    let self_signed = cert.issued(cert) == X509VerifyResult::OK;
+    Ok(self_signed == (mode == EnrollmentMode::SelfSigned))
+}
+
+/*
+ * Input: certificates returned by the enrollment endpoint and the NK
+ * Return: Result wrap the certificate of the NK and the other certificates
+ */
+fn split_chain(
+    mut certs: Vec<X509>,
+    key: &PKey<Private>,
+) -> Result<(X509, Vec<X509>)> {
+    let mut leaf = None;
+    for (i, cert) in certs.iter().enumerate() {
+        if cert.public_key()?.public_eq(key) {
+            leaf = Some(i);
+            break;
+        }
+    }
+    match leaf {
+        Some(i) => {
+            let cert = certs.remove(i);
+            Ok((cert, certs))
+        }
+        None => Err(Error::Other(
+            "The enrollment endpoint returned no certificate for the NK"
+                .to_string(),
+        )),
+    }
+}
+
+async fn enroll_est(
+    client: &reqwest::Client,
+    url: &str,
+    csr: &X509Req,
+) -> Result<Vec<X509>> {
+    let addr = format!("{}/simpleenroll", url.trim_end_matches('/'));
+    let resp = client
+        .post(&addr)
+        .header("Content-Type", "application/pkcs10")
+        .header("Content-Transfer-Encoding", "base64")
+        .body(base64::encode(csr.to_der()?))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    // The base64 encoding of the response may be wrapped
+    let der = base64::decode(
+        resp.split_whitespace().collect::<String>().as_bytes(),
+    )?;
+    let pkcs7 = Pkcs7::from_der(&der)?;
+    match pkcs7.signed().and_then(|signed| signed.certificates()) {
+        Some(certs) => Ok(certs.iter().map(|cert| cert.to_owned()).collect()),
+        None => Err(Error::Other(format!(
+            "No certificate in the EST response of {}",
+            addr
+        ))),
+    }
+}
+
+async fn enroll_keylime_ca(
+    client: &reqwest::Client,
+    url: &str,
+    csr: &X509Req,
+) -> Result<Vec<X509>> {
+    let csr = String::from_utf8(csr.to_pem()?)?;
+    let resp: JsonWrapper<KeylimeCaResults> = client
+        .post(url)
+        .json(&json!({ "csr": csr }))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+    Ok(X509::stack_from_pem(resp.results.certificate.as_bytes())?)
+}
+
+/*
+ * Input: agent configuration, NK and Keylime CA certificate
+ * Return: Result wrap the mTLS certificate and the intermediate CA
+ *         certificates it is served with
+ *
+ * Signs the certificate with the NK, or has it issued by the enrollment
+ * endpoint.
+ */
+pub(crate) async fn issue(
+    config: &KeylimeConfig,
+    key: &PKey<Private>,
+    keylime_ca_cert: &X509,
+) -> Result<(X509, Vec<X509>)> {
+    let url = match (
+        config.mtls_cert_enrollment,
+        &config.mtls_cert_enrollment_url,
+    ) {
+        (EnrollmentMode::SelfSigned, _) => {
+            return Ok((
+                crypto::generate_x509(key, &config.agent_uuid)?,
+                Vec::new(),
+            ));
+        }
+        (_, Some(url)) => url,
+        (_, None) => {
+            return Err(Error::Configuration(
+                "mtls_cert_enrollment_url is not set".to_string(),
+            ));
+        }
+    };
+
+    let client = reqwest::Client::builder()
+        .timeout(ENROLLMENT_TIMEOUT)
+        .add_root_certificate(reqwest::Certificate::from_der(
+            &keylime_ca_cert.to_der()?,
+        )?)
+        .build()?;
+    let csr = crypto::generate_csr(key, &config.agent_uuid)?;
+    info!("Requesting the mTLS certificate from {}", url);
+    let certs = match config.mtls_cert_enrollment {
+        EnrollmentMode::Est => enroll_est(&client, url, &csr).await?,
+        _ => enroll_keylime_ca(&client, url, &csr).await?,
+    };
+
+    let (cert, chain) = split_chain(certs, key)?;
+    info!(
+        "Enrolled the mTLS certificate with {}, served with {} CA certificates",
+        url,
+        chain.len()
+    );
+    Ok((cert, chain))
+}
+
+/*
+ * Input: agent configuration, NK and Keylime CA certificate
+ * Return: Result wrap the mTLS certificate and the intermediate CA
+ *         certificates it is served with
+ *
+ * Same as issue, for the start of the agent: the enrollment endpoint may not
+ * be reachable yet, e.g. when started along with the node, so the requests
+ * that fail are retried.
+ */
+pub(crate) async fn issue_at_start(
+    config: &KeylimeConfig,
+    key: &PKey<Private>,
+    keylime_ca_cert: &X509,
+) -> Result<(X509, Vec<X509>)> {
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        match issue(config, key, keylime_ca_cert).await {
+            Err(Error::Reqwest(e)) if attempts < ENROLLMENT_ATTEMPTS => {
+                let delay = backoff(attempts);
+                warn!(
+                    "Unable to enroll the mTLS certificate, retrying in {} seconds: {}",
+                    delay.as_secs(),
+                    e
+                );
+                time::sleep(delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/*
+ * Input: admin state and agent status
+ *
+ * Renews the mTLS certificate once it is due, and again with each new one.
+ * A failed renewal is retried every RENEWAL_RETRY_DELAY.
+ */
+pub(crate) async fn renew(
+    admin: Arc<Admin>,
+    status: Arc<AgentStatus>,
+) -> Result<()> {
+    loop {
+        let cert = admin.registration.lock().unwrap().mtls_cert.clone(); //#[allow_ci]
+        let cert = match cert {
+            Some(cert) => cert,
+            None => return Ok(()),
+        };
+        let delay = renew_in(&cert)?;
+        if !delay.is_zero() {
+            info!(
+                "Renewing the mTLS certificate in {} days",
+                delay.as_secs() / 86400
+            );
+            time::sleep(delay).await;
+        }
+
+        match admin_socket::rotate_mtls_cert(&admin, &status).await {
+            Ok(()) => info!("Renewed the mTLS certificate"),
+            Err(e) => {
+                warn!(
+                    "Unable to renew the mTLS certificate, retrying in {} seconds: {}",
+                    RENEWAL_RETRY_DELAY.as_secs(),
+                    e
+                );
+                time::sleep(RENEWAL_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_enrollment_mode() {
+        assert_eq!(
+            EnrollmentMode::try_from("est").unwrap(), //#[allow_ci]
+            EnrollmentMode::Est
+        );
+        assert_eq!(
+            EnrollmentMode::try_from("keylime-ca").unwrap(), //#[allow_ci]
+            EnrollmentMode::KeylimeCa
+        );
+        assert!(EnrollmentMode::try_from("scep").is_err());
+    }
+
+    #[test]
+    fn test_reusable() {
+        let (_, key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        assert!(reusable(&cert, EnrollmentMode::SelfSigned).unwrap()); //#[allow_ci]
+        assert!(!reusable(&cert, EnrollmentMode::Est).unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_renew_in() {
+        let (_, key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+
+        // Valid for 356 days, renewed with a third of them left
+        let delay = renew_in(&cert).unwrap(); //#[allow_ci]
+        assert_eq!(delay.as_secs() / 86400, 237);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), INITIAL_BACKOFF);
+        assert_eq!(backoff(3), Duration::from_secs(4));
+        assert_eq!(backoff(ENROLLMENT_ATTEMPTS), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_split_chain() {
+        let (_, key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let (_, ca_key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        let ca = crypto::generate_x509(&ca_key, "ca").unwrap(); //#[allow_ci]
+
+        let (leaf, chain) =
+            split_chain(vec![ca.clone(), cert.clone()], &key).unwrap(); //#[allow_ci]
+        assert_eq!(leaf.to_der().unwrap(), cert.to_der().unwrap()); //#[allow_ci]
+        assert_eq!(chain.len(), 1);
+        assert!(split_chain(vec![ca], &key).is_err());
+    }
+}
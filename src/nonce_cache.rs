@@ -0,0 +1,129 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Refusal of nonces reused in quote requests.
+//
+// Verifiers send a fresh nonce with each quote request, so that an old quote
+// cannot be replayed as a current one. A nonce used again by the same client
+// means a misbehaving verifier or a request replayed against an agent without
+// mTLS. With 'quote_nonce_replay_window' set, the nonces of the quote and
+// certification requests are remembered for that many seconds per client, and
+// requests reusing one are refused with a 409 response. Clients are told
+// apart by their certificate with mTLS, by their address otherwise.
+
+use crate::peer_identity;
+use actix_web::HttpRequest;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Bound on the nonces remembered, the oldest ones are forgotten first
+const MAX_NONCES: usize = 10000;
+
+#[derive(Debug)]
+pub(crate) struct NonceCache {
+    window: Duration,
+    max_nonces: usize,
+    // Time each nonce was first used, by client and nonce
+    seen: Mutex<HashMap<(String, String), Instant>>,
+}
+
+// The client sending the request, its certificate identity with mTLS or its
+// address otherwise
+pub(crate) fn client(req: &HttpRequest) -> String {
+    match peer_identity::of_request(req) {
+        Some(identity) => identity,
+        None => req
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_else(|| "-".to_string()),
+    }
+}
+
+impl NonceCache {
+    pub(crate) fn new(window: Duration) -> Self {
+        NonceCache {
+            window,
+            max_nonces: MAX_NONCES,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /*
+     * Input: client and nonce of the request
+     * Return: whether the nonce is fresh, false when the client already used
+     *         it within the window
+     *
+     * Remembers the nonce for the window.
+     */
+    pub(crate) fn check(&self, client: &str, nonce: &str) -> bool {
+        self.check_at(client, nonce, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, nonce: &str, now: Instant) -> bool {
+        let mut seen = self.seen.lock().unwrap(); //#[allow_ci]
+        seen.retain(|_, used| {
+            now.saturating_duration_since(*used) < self.window
+        });
+
+        let key = (client.to_string(), nonce.to_string());
+        if seen.contains_key(&key) {
+            return false;
+        }
+        if seen.len() >= self.max_nonces {
+            let oldest = seen
+                .iter()
+                .min_by_key(|(_, used)| **used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                let _ = seen.remove(&oldest);
+            }
+        }
+        let _ = seen.insert(key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let cache = NonceCache::new(Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(cache.check_at("verifier", "nonce", start));
+        assert!(!cache.check_at("verifier", "nonce", start));
+        // Other clients and other nonces are not affected
+        assert!(cache.check_at("tenant", "nonce", start));
+        assert!(cache.check_at("verifier", "other", start));
+
+        // Accepted again once the window expired
+        let later = start + Duration::from_secs(61);
+        assert!(cache.check_at("verifier", "nonce", later));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let cache = NonceCache {
+            window: Duration::from_secs(60),
+            max_nonces: 3,
+            seen: Mutex::new(HashMap::new()),
+        };
+        let start = Instant::now();
+        for i in 0..3 {
+            let used = start + Duration::from_secs(i);
+            assert!(cache.check_at("verifier", &i.to_string(), used));
+        }
+        let now = start + Duration::from_secs(3);
+        assert!(cache.check_at("verifier", "new", now));
+        assert_eq!(cache.seen.lock().unwrap().len(), 3); //#[allow_ci]
+
+        // The oldest nonce was forgotten, making room forgot the next one
+        assert!(cache.check_at("verifier", "0", now));
+        assert!(!cache.check_at("verifier", "2", now));
+    }
+}
@@ -32,6 +32,8 @@ pub async fn revocation(
 
     revocation::process_revocation(
         json_body,
+        &data.status,
+        data.revocation_webhook.as_deref(),
         revocation_cert,
         secure_size,
         revocation_actions,
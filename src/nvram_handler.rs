@@ -0,0 +1,105 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::{
+    common::{parse_nv_index, JsonWrapper},
+    tpm, QuoteData,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KeylimeNvIndex {
+    index: String,
+    // Base64 encoded content of the NV index
+    data: String,
+}
+
+// Reads an NV index listed in 'nvram_indices', e.g. to retrieve the IDevID
+// or platform certificates provisioned in the TPM
+pub async fn nvram(
+    req: HttpRequest,
+    index: web::Path<String>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let index = match parse_nv_index(&index) {
+        Some(index) => index,
+        None => {
+            warn!(
+                "GET NV index returning 400 response. Not an NV index: {}",
+                index
+            );
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("Not an NV index: {}", index),
+            ));
+        }
+    };
+
+    if !data.nvram_indices.contains(&index) {
+        warn!(
+            "GET NV index returning 403 response. NV index {:#x} is not in nvram_indices",
+            index
+        );
+        return HttpResponse::Forbidden().json(JsonWrapper::error(
+            403,
+            format!("NV index {:#x} is not readable", index),
+        ));
+    }
+
+    match data
+        .tpm
+        .run_idempotent(move |context| tpm::nv_read(context, index))
+        .await
+    {
+        Ok(content) => {
+            info!("GET NV index {:#x} returning 200 response.", index);
+            HttpResponse::Ok().json(JsonWrapper::success(KeylimeNvIndex {
+                index: format!("{:#x}", index),
+                data: base64::encode(content),
+            }))
+        }
+        Err(e) => {
+            warn!("Unable to read NV index {:#x}: {}", index, e);
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                format!("Unable to read NV index {:#x}", index),
+            ))
+        }
+    }
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_nvram_refused() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.nvram_indices = vec![0x1c00002];
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/nvram/{{index}}", API_VERSION),
+                web::get().to(nvram),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/nvram/0x1c0000a", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 403);
+
+        // Persistent handles are not NV indices
+        let req = test::TestRequest::get()
+            .uri(&format!("/{}/nvram/0x81010001", API_VERSION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+}
@@ -0,0 +1,284 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Chunked delivery of encrypted payloads.
+//
+// A payload sent with the U key must fit in a single request body. Larger
+// payloads are sent beforehand to /keys/payload/chunks, split in chunks that
+// each carry the SHA-256 digest of their content. The chunks of an upload
+// session are kept until all of them arrived, in any order, and are then
+// appended to the encrypted payload in order. Each response lists the chunks
+// still missing, so an interrupted upload is resumed by sending only those.
+// Sessions that received no chunk for SESSION_TTL are dropped, and only
+// MAX_SESSIONS of them are kept open at once.
+
+use crate::error::{Error, Result};
+use log::*;
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+// Bounds the bookkeeping of a single session, not the payload size, which
+// is bounded by the space left in the secure mount
+const MAX_CHUNKS: u64 = 65536;
+const MAX_SESSION_LEN: usize = 64;
+// Bound the chunks held in memory by abandoned or concurrent uploads
+const MAX_SESSIONS: usize = 4;
+const SESSION_TTL: Duration = Duration::from_secs(600);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimePayloadChunk {
+    pub session: String,
+    pub index: u64,
+    pub total: u64,
+    // Hex encoded SHA-256 digest of the decoded chunk
+    pub sha256: String,
+    pub chunk: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct ChunkProgress {
+    pub session: String,
+    pub total: u64,
+    pub received: u64,
+    pub missing: Vec<u64>,
+    pub complete: bool,
+}
+
+#[derive(Debug)]
+struct Upload {
+    total: u64,
+    chunks: BTreeMap<u64, Vec<u8>>,
+    // When the last chunk was received
+    updated: Instant,
+}
+
+impl Upload {
+    fn progress(&self, session: &str) -> ChunkProgress {
+        let missing = (0..self.total)
+            .filter(|index| !self.chunks.contains_key(index))
+            .collect::<Vec<u64>>();
+        ChunkProgress {
+            session: session.to_string(),
+            total: self.total,
+            received: self.chunks.len() as u64,
+            complete: missing.is_empty(),
+            missing,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ChunkedUploads {
+    uploads: HashMap<String, Upload>,
+}
+
+impl ChunkedUploads {
+    // Drops the sessions that received no chunk for SESSION_TTL
+    pub(crate) fn expire(&mut self) {
+        let now = Instant::now();
+        self.uploads.retain(|session, upload| {
+            let alive = now.duration_since(upload.updated) < SESSION_TTL;
+            if !alive {
+                warn!(
+                    "Dropping payload upload {}, no chunk received for {} seconds",
+                    session,
+                    SESSION_TTL.as_secs()
+                );
+            }
+            alive
+        });
+    }
+
+    // Bytes held for the sessions not yet complete
+    pub(crate) fn size(&self) -> usize {
+        self.uploads
+            .values()
+            .flat_map(|upload| upload.chunks.values())
+            .map(|chunk| chunk.len())
+            .sum()
+    }
+
+    /*
+     * Input: chunk as received
+     * Return: Result wrap the progress of its session, and the assembled
+     *         payload once the session is complete
+     *
+     * Sending a chunk again with the same content is accepted, so that
+     * clients can retry requests whose response was lost.
+     */
+    pub(crate) fn add(
+        &mut self,
+        chunk: &KeylimePayloadChunk,
+    ) -> Result<(ChunkProgress, Option<Vec<u8>>)> {
+        if chunk.session.is_empty()
+            || chunk.session.len() > MAX_SESSION_LEN
+            || !chunk.session.chars().all(char::is_alphanumeric)
+        {
+            return Err(Error::PayloadChunk(format!(
+                "session must be 1 to {} alphanumeric characters",
+                MAX_SESSION_LEN
+            )));
+        }
+        if chunk.total == 0 || chunk.total > MAX_CHUNKS {
+            return Err(Error::PayloadChunk(format!(
+                "total must be between 1 and {}",
+                MAX_CHUNKS
+            )));
+        }
+        if chunk.index >= chunk.total {
+            return Err(Error::PayloadChunk(format!(
+                "index {} out of range for {} chunks",
+                chunk.index, chunk.total
+            )));
+        }
+
+        let data = base64::decode(&chunk.chunk)?;
+        let expected = hex::decode(&chunk.sha256)?;
+        if sha256(&data)[..] != expected[..] {
+            return Err(Error::PayloadChunk(format!(
+                "SHA-256 digest mismatch for chunk {}",
+                chunk.index
+            )));
+        }
+
+        self.expire();
+        if !self.uploads.contains_key(&chunk.session)
+            && self.uploads.len() >= MAX_SESSIONS
+        {
+            return Err(Error::PayloadChunk(format!(
+                "{} upload sessions already open",
+                MAX_SESSIONS
+            )));
+        }
+        let upload = self
+            .uploads
+            .entry(chunk.session.clone())
+            .or_insert_with(|| Upload {
+                total: chunk.total,
+                chunks: BTreeMap::new(),
+                updated: Instant::now(),
+            });
+        if upload.total != chunk.total {
+            return Err(Error::PayloadChunk(format!(
+                "session {} has {} chunks, not {}",
+                chunk.session, upload.total, chunk.total
+            )));
+        }
+        match upload.chunks.get(&chunk.index) {
+            Some(received) if received != &data => {
+                return Err(Error::PayloadChunk(format!(
+                    "chunk {} was already received with a different content",
+                    chunk.index
+                )));
+            }
+            Some(_) => {}
+            None => {
+                let _ = upload.chunks.insert(chunk.index, data);
+            }
+        }
+        upload.updated = Instant::now();
+
+        let progress = upload.progress(&chunk.session);
+        if !progress.complete {
+            return Ok((progress, None));
+        }
+        let payload = match self.uploads.remove(&chunk.session) {
+            Some(upload) => upload
+                .chunks
+                .into_iter()
+                .flat_map(|(_, chunk)| chunk)
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok((progress, Some(payload)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(
+        session: &str,
+        index: u64,
+        total: u64,
+        data: &[u8],
+    ) -> KeylimePayloadChunk {
+        KeylimePayloadChunk {
+            session: session.to_string(),
+            index,
+            total,
+            sha256: hex::encode(sha256(data)),
+            chunk: base64::encode(data),
+        }
+    }
+
+    #[test]
+    fn test_add() {
+        let mut uploads = ChunkedUploads::default();
+
+        let (progress, payload) =
+            uploads.add(&chunk("abc", 2, 3, b"ghi")).unwrap(); //#[allow_ci]
+        assert_eq!(progress.missing, vec![0, 1]);
+        assert!(!progress.complete);
+        assert!(payload.is_none());
+
+        let (progress, _) = uploads.add(&chunk("abc", 0, 3, b"abc")).unwrap(); //#[allow_ci]
+        assert_eq!(progress.received, 2);
+        assert_eq!(uploads.size(), 6);
+
+        // Retried chunks are accepted as long as they did not change
+        assert!(uploads.add(&chunk("abc", 0, 3, b"abc")).is_ok());
+        assert!(uploads.add(&chunk("abc", 0, 3, b"xyz")).is_err());
+
+        let (progress, payload) =
+            uploads.add(&chunk("abc", 1, 3, b"def")).unwrap(); //#[allow_ci]
+        assert!(progress.complete);
+        assert_eq!(payload, Some(b"abcdefghi".to_vec()));
+        assert_eq!(uploads.size(), 0);
+    }
+
+    #[test]
+    fn test_add_invalid() {
+        let mut uploads = ChunkedUploads::default();
+
+        assert!(uploads.add(&chunk("", 0, 1, b"abc")).is_err());
+        assert!(uploads.add(&chunk("../abc", 0, 1, b"abc")).is_err());
+        assert!(uploads.add(&chunk("abc", 0, 0, b"abc")).is_err());
+        assert!(uploads.add(&chunk("abc", 2, 2, b"abc")).is_err());
+        assert!(uploads
+            .add(&chunk("abc", 0, MAX_CHUNKS + 1, b"abc"))
+            .is_err());
+
+        let mut tampered = chunk("abc", 0, 2, b"abc");
+        tampered.chunk = base64::encode(b"abd");
+        assert!(uploads.add(&tampered).is_err());
+
+        assert!(uploads.add(&chunk("abc", 0, 2, b"abc")).is_ok());
+        assert!(uploads.add(&chunk("abc", 1, 3, b"def")).is_err());
+    }
+
+    #[test]
+    fn test_sessions() {
+        let mut uploads = ChunkedUploads::default();
+
+        for session in 0..MAX_SESSIONS {
+            assert!(uploads
+                .add(&chunk(&session.to_string(), 0, 2, b"abc"))
+                .is_ok());
+        }
+        assert!(uploads.add(&chunk("full", 0, 2, b"abc")).is_err());
+        // Open sessions still take chunks
+        assert!(uploads.add(&chunk("0", 0, 2, b"abc")).is_ok());
+
+        // Idle sessions are dropped with their chunks
+        let upload = uploads.uploads.get_mut("1").unwrap(); //#[allow_ci]
+        upload.updated = Instant::now() - SESSION_TTL;
+        uploads.expire();
+        assert_eq!(uploads.uploads.len(), MAX_SESSIONS - 1);
+        assert_eq!(uploads.size(), 3 * (MAX_SESSIONS - 1));
+        assert!(uploads.add(&chunk("full", 0, 2, b"abc")).is_ok());
+    }
+}
@@ -0,0 +1,142 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Monotonic counter of the payload deliveries.
+//
+// With 'payload_counter_nv_index' set, the agent keeps a TPM NV counter that
+// is incremented for each payload it accepts, once the payload decrypted
+// with the key combined from U and V and before it is written out or run.
+// Each delivery is recorded in the agent event log with the value the
+// counter reached and the digest of the encrypted payload, and the current
+// value is reported by `keylime_agent status`. The counter only goes up and
+// survives reboots, so an auditor finding its value matching the last
+// recorded delivery knows no payload was delivered outside of the recorded
+// ones.
+
+use crate::{
+    algorithms::HashAlgorithm,
+    error::{Error, Result},
+    permissions,
+    status::AgentStatus,
+    tpm,
+    tpm_service::TpmService,
+};
+use log::*;
+use openssl::hash::hash;
+use std::{io::Write, path::PathBuf, sync::Arc};
+use tss_esapi::Context;
+
+pub(crate) static DELIVERY_EVENT: &str = "payload-delivery";
+
+// Event log line, in the form
+// "<nv index> <hash_alg>:<hex digest> payload-delivery:<counter>"
+fn event_log_entry(
+    index: u32,
+    counter: u64,
+    hash_alg: HashAlgorithm,
+    digest: &[u8],
+) -> String {
+    format!(
+        "{:#x} {}:{} {}:{}\n",
+        index,
+        hash_alg,
+        hex::encode(digest),
+        DELIVERY_EVENT,
+        counter
+    )
+}
+
+#[derive(Debug)]
+pub(crate) struct PayloadCounter {
+    index: u32,
+    event_log: PathBuf,
+    hash_alg: HashAlgorithm,
+    status: Arc<AgentStatus>,
+}
+
+impl PayloadCounter {
+    /*
+     * Input: TPM context, NV index of the counter, path to the agent event
+     *        log, hash algorithm of the payload digests and agent status
+     * Return: Result wrap the counter
+     *
+     * The counter is defined in the TPM when the index is not defined yet.
+     */
+    pub(crate) fn load(
+        context: &mut Context,
+        index: u32,
+        event_log: PathBuf,
+        hash_alg: HashAlgorithm,
+        status: Arc<AgentStatus>,
+    ) -> Result<Self> {
+        let value = tpm::nv_counter(context, index)?;
+        info!("Payload delivery counter {:#x} at {}", index, value);
+        status.set_payload_counter(value);
+        Ok(PayloadCounter {
+            index,
+            event_log,
+            hash_alg,
+            status,
+        })
+    }
+
+    // Digest of the encrypted payload, as recorded in the event log
+    pub(crate) fn digest(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        Ok(hash(self.hash_alg.into(), payload)?.to_vec())
+    }
+
+    /*
+     * Input: TPM service and digest of the encrypted payload
+     * Return: Result wrap the value of the counter for this delivery
+     *
+     * The delivery is recorded with the value the counter is about to reach
+     * before it is incremented, so a failure leaves an entry without
+     * increment rather than an increment without entry. The delivery is
+     * refused when the counter reached another value, as it was then
+     * incremented outside of the agent.
+     */
+    pub(crate) async fn record(
+        &self,
+        tpm: &TpmService,
+        digest: &[u8],
+    ) -> Result<u64> {
+        let index = self.index;
+        let current = tpm
+            .run_idempotent(move |ctx| tpm::nv_counter(ctx, index))
+            .await?;
+        let expected = current.checked_add(1).ok_or_else(|| {
+            Error::Other(format!("NV counter {:#x} is exhausted", index))
+        })?;
+
+        let mut log = permissions::open_append(&self.event_log)?;
+        log.write_all(
+            event_log_entry(index, expected, self.hash_alg, digest)
+                .as_bytes(),
+        )?;
+        log.sync_data()?;
+
+        let value = tpm.run(move |ctx| tpm::nv_increment(ctx, index)).await?;
+        self.status.set_payload_counter(value);
+        if value != expected {
+            return Err(Error::Other(format!(
+                "NV counter {:#x} reached {} rather than {}, it was incremented outside of the agent",
+                index, value, expected
+            )));
+        }
+        info!("Payload delivery {} recorded", value);
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_entry() {
+        assert_eq!(
+            event_log_entry(0x1500020, 3, HashAlgorithm::Sha256, &[0xab]),
+            "0x1500020 sha256:ab payload-delivery:3\n"
+        );
+    }
+}
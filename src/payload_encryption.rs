@@ -0,0 +1,413 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Payloads encrypted with age or OpenPGP.
+//
+// By default the payload is encrypted with AES-GCM under the key combined
+// from U and V, AES-128 or AES-256 after the length of the key. The agent
+// accepts the 16 bytes IV of Keylime and the 12 bytes IV of SP 800-38D, and,
+// with 'aes-cbc-hmac' accepted, the AES-CBC and HMAC-SHA384 payloads of
+// older tenants. Tenants that name no format get their AES payload
+// decrypted with whichever of these constructions authenticates it.
+//
+// Tenants that already manage age or OpenPGP keys can instead
+// encrypt the payload to a key of the agent, naming the format in the
+// 'payload_encryption' field sent with the U key. The payload is still only
+// decrypted once U and V are combined, that is once the verifier attested
+// the agent, but its confidentiality no longer depends on U and V.
+//
+// The age X25519 identity is generated once and kept in the agent data, its
+// recipient is published by /keys/pubkey. OpenPGP payloads are decrypted by
+// gpg with the keyring in 'payload_gnupg_home', where the secret key can be
+// a regular one or one moved into the TPM with 'keytotpm'.
+
+use crate::{
+    common::{AgentData, KeylimeConfig, SymmKey},
+    crypto,
+    error::{Error, Result},
+};
+use log::*;
+use std::{
+    convert::TryFrom,
+    fmt,
+    io::{Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PayloadEncryption {
+    AesGcm,
+    AesCbcHmac,
+    Age,
+    OpenPgp,
+}
+
+impl Default for PayloadEncryption {
+    fn default() -> Self {
+        PayloadEncryption::AesGcm
+    }
+}
+
+impl TryFrom<&str> for PayloadEncryption {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "aes-gcm" => Ok(PayloadEncryption::AesGcm),
+            "aes-cbc-hmac" => Ok(PayloadEncryption::AesCbcHmac),
+            "age" => Ok(PayloadEncryption::Age),
+            "openpgp" => Ok(PayloadEncryption::OpenPgp),
+            _ => Err(Error::Configuration(format!(
+                "Payload encryption {} is not supported, use aes-gcm, aes-cbc-hmac, age or openpgp",
+                value
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for PayloadEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let value = match self {
+            PayloadEncryption::AesGcm => "aes-gcm",
+            PayloadEncryption::AesCbcHmac => "aes-cbc-hmac",
+            PayloadEncryption::Age => "age",
+            PayloadEncryption::OpenPgp => "openpgp",
+        };
+        write!(f, "{}", value)
+    }
+}
+
+/*
+ * Input: agent configuration and the agent data of the previous run
+ * Return: the age identity to decrypt payloads with, None when age payloads
+ *         are not accepted
+ *
+ * The identity of the previous run is kept, tenants encrypt to it for as
+ * long as the agent data is.
+ */
+pub(crate) fn age_identity(
+    config: &KeylimeConfig,
+    previous: Option<&AgentData>,
+) -> Result<Option<age::x25519::Identity>> {
+    if !config.payload_encryption.contains(&PayloadEncryption::Age) {
+        return Ok(None);
+    }
+    if let Some(identity) = previous
+        .map(AgentData::get_age_identity)
+        .transpose()?
+        .flatten()
+    {
+        return Ok(Some(identity));
+    }
+    info!("Generating the age identity for payloads");
+    Ok(Some(age::x25519::Identity::generate()))
+}
+
+fn decrypt_age(
+    identity: &age::x25519::Identity,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let decryptor =
+        match age::Decryptor::new(payload)? {
+            age::Decryptor::Recipients(decryptor) => decryptor,
+            age::Decryptor::Passphrase(_) => return Err(Error::Other(
+                "age payloads encrypted with a passphrase are not supported"
+                    .to_string(),
+            )),
+        };
+    let mut reader =
+        decryptor.decrypt(iter::once(identity as &dyn age::Identity))?;
+    let mut decrypted = Vec::new();
+    let _ = reader.read_to_end(&mut decrypted)?;
+    Ok(decrypted)
+}
+
+// The encrypted payload is not secret, it is handed to gpg in a temporary
+// file rather than through a pipe that would have to be written concurrently
+// with reading the output
+fn decrypt_openpgp(gnupg_home: &Path, payload: &[u8]) -> Result<Vec<u8>> {
+    let mut encrypted = tempfile::NamedTempFile::new()?;
+    encrypted.write_all(payload)?;
+    encrypted.as_file().sync_all()?;
+
+    let output = Command::new("gpg")
+        .arg("--homedir")
+        .arg(gnupg_home)
+        .args(["--batch", "--quiet", "--decrypt"])
+        .arg(encrypted.path())
+        .output()?;
+    if !output.status.success() {
+        return Err(Error::Execution(
+            output.status.code(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        ));
+    }
+    Ok(output.stdout)
+}
+
+// AES-GCM payload, with the IV of Keylime or the one of SP 800-38D
+fn decrypt_aes_gcm(symm_key: &SymmKey, payload: &[u8]) -> Result<Vec<u8>> {
+    crypto::decrypt_aead_payload(symm_key.bytes(), payload)
+}
+
+// The keys payloads can be decrypted with and the format selected by the
+// tenant for the current payload, None when the tenant named none
+#[derive(Default)]
+pub(crate) struct PayloadDecryptor {
+    accepted: Vec<PayloadEncryption>,
+    age_identity: Option<age::x25519::Identity>,
+    gnupg_home: Option<PathBuf>,
+    selected: Mutex<Option<PayloadEncryption>>,
+}
+
+// The age identity is secret, only its recipient is shown
+impl fmt::Debug for PayloadDecryptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PayloadDecryptor")
+            .field("accepted", &self.accepted)
+            .field("age_recipient", &self.age_recipient())
+            .field("gnupg_home", &self.gnupg_home)
+            .field("selected", &self.selected)
+            .finish()
+    }
+}
+
+impl PayloadDecryptor {
+    pub(crate) fn new(
+        config: &KeylimeConfig,
+        age_identity: Option<age::x25519::Identity>,
+    ) -> Result<Self> {
+        let gnupg_home = if config
+            .payload_encryption
+            .contains(&PayloadEncryption::OpenPgp)
+        {
+            let home = config.payload_gnupg_home.as_ref().ok_or_else(|| {
+                Error::Configuration(
+                    "'payload_encryption' accepts openpgp but 'payload_gnupg_home' is not set".to_string(),
+                )
+            })?;
+            let home = Path::new(home).canonicalize().map_err(|e| {
+                Error::Configuration(format!(
+                    "Path {} set in payload_gnupg_home not found: {}",
+                    home, e
+                ))
+            })?;
+            Some(home)
+        } else {
+            None
+        };
+        Ok(Self {
+            accepted: config.payload_encryption.clone(),
+            age_identity,
+            gnupg_home,
+            selected: Mutex::new(None),
+        })
+    }
+
+    // The age recipient tenants encrypt payloads to
+    pub(crate) fn age_recipient(&self) -> Option<String> {
+        self.age_identity
+            .as_ref()
+            .map(|identity| identity.to_public().to_string())
+    }
+
+    // Whether the agent accepts payloads in the format named by the tenant
+    pub(crate) fn accepts(&self, encryption: PayloadEncryption) -> bool {
+        self.accepted.contains(&encryption)
+    }
+
+    /*
+     * Input: payload format named by the tenant, None when it named none
+     *
+     * Selects the format the payload is decrypted with, an accepted one.
+     * Without a format the AES payload is detected.
+     */
+    pub(crate) fn select(&self, encryption: Option<PayloadEncryption>) {
+        let mut selected = self.selected.lock().unwrap(); //#[allow_ci]
+        *selected = encryption;
+    }
+
+    pub(crate) fn selected(&self) -> PayloadEncryption {
+        self.selected.lock().unwrap().unwrap_or_default() //#[allow_ci]
+    }
+
+    // The AES payload of a tenant that named no format, AES-GCM or, when
+    // accepted, AES-CBC with HMAC
+    fn decrypt_aes(
+        &self,
+        payload: &[u8],
+        symm_key: &SymmKey,
+    ) -> Result<(Vec<u8>, PayloadEncryption)> {
+        let gcm = match decrypt_aes_gcm(symm_key, payload) {
+            Ok(decrypted) => {
+                return Ok((decrypted, PayloadEncryption::AesGcm))
+            }
+            Err(e) => e,
+        };
+        if !self.accepted.contains(&PayloadEncryption::AesCbcHmac) {
+            return Err(gcm);
+        }
+        debug!("Not an AES-GCM payload ({}), trying AES-CBC with HMAC", gcm);
+        let decrypted = crypto::decrypt_cbc_hmac(symm_key.bytes(), payload)?;
+        Ok((decrypted, PayloadEncryption::AesCbcHmac))
+    }
+
+    /*
+     * Input: encrypted payload and the key combined from U and V
+     * Return: the decrypted payload
+     */
+    pub(crate) fn decrypt(
+        &self,
+        payload: &[u8],
+        symm_key: &SymmKey,
+    ) -> Result<Vec<u8>> {
+        let named = *self.selected.lock().unwrap(); //#[allow_ci]
+        let mut encryption = named.unwrap_or_default();
+        let decrypted = match encryption {
+            // Parameters are based on Python codebase:
+            // https://github.com/keylime/keylime/blob/1ed43ac8f75d5c3bc3a3bbbbb5037f20cf3c5a6a/ \
+            // keylime/crypto.py#L189
+            PayloadEncryption::AesGcm if named.is_none() => {
+                let (decrypted, detected) =
+                    self.decrypt_aes(payload, symm_key)?;
+                encryption = detected;
+                decrypted
+            }
+            PayloadEncryption::AesGcm => decrypt_aes_gcm(symm_key, payload)?,
+            PayloadEncryption::AesCbcHmac => {
+                crypto::decrypt_cbc_hmac(symm_key.bytes(), payload)?
+            }
+            PayloadEncryption::Age => match &self.age_identity {
+                Some(identity) => decrypt_age(identity, payload)?,
+                None => {
+                    return Err(Error::Other(
+                        "age payloads are not accepted".to_string(),
+                    ))
+                }
+            },
+            PayloadEncryption::OpenPgp => match &self.gnupg_home {
+                Some(home) => decrypt_openpgp(home, payload)?,
+                None => {
+                    return Err(Error::Other(
+                        "OpenPGP payloads are not accepted".to_string(),
+                    ))
+                }
+            },
+        };
+        debug!("Decrypted the {} payload", encryption);
+        Ok(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn test_payload_encryption_names() {
+        for encryption in [
+            PayloadEncryption::AesGcm,
+            PayloadEncryption::AesCbcHmac,
+            PayloadEncryption::Age,
+            PayloadEncryption::OpenPgp,
+        ] {
+            let name = encryption.to_string();
+            assert_eq!(
+                PayloadEncryption::try_from(name.as_str()).unwrap(), //#[allow_ci]
+                encryption
+            );
+        }
+        assert!(PayloadEncryption::try_from("gpg").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_age() {
+        let mut config = KeylimeConfig::default();
+        config.payload_encryption =
+            vec![PayloadEncryption::AesGcm, PayloadEncryption::Age];
+        let identity = age_identity(&config, None).unwrap().unwrap(); //#[allow_ci]
+        let recipient = identity.to_public();
+        let decryptor =
+            PayloadDecryptor::new(&config, Some(identity)).unwrap(); //#[allow_ci]
+        assert_eq!(decryptor.age_recipient(), Some(recipient.to_string()));
+
+        let encryptor =
+            age::Encryptor::with_recipients(vec![Box::new(recipient)])
+                .unwrap(); //#[allow_ci]
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted).unwrap(); //#[allow_ci]
+        writer.write_all(b"payload").unwrap(); //#[allow_ci]
+        writer.finish().unwrap(); //#[allow_ci]
+
+        // The U and V key is not used for age payloads
+        let symm_key: SymmKey = [0u8; 32][..].try_into().unwrap(); //#[allow_ci]
+        decryptor.select(Some(PayloadEncryption::Age));
+        assert_eq!(
+            decryptor.decrypt(&encrypted, &symm_key).unwrap(), //#[allow_ci]
+            b"payload"
+        );
+
+        // Not decrypted as AES-GCM
+        decryptor.select(Some(PayloadEncryption::AesGcm));
+        assert!(decryptor.decrypt(&encrypted, &symm_key).is_err());
+    }
+
+    #[test]
+    fn test_select_refused() {
+        let decryptor =
+            PayloadDecryptor::new(&KeylimeConfig::default(), None).unwrap(); //#[allow_ci]
+        assert!(!decryptor.accepts(PayloadEncryption::OpenPgp));
+        assert!(!decryptor.accepts(PayloadEncryption::Age));
+        assert!(decryptor.accepts(PayloadEncryption::AesGcm));
+        assert_eq!(decryptor.selected(), PayloadEncryption::AesGcm);
+        assert_eq!(decryptor.age_recipient(), None);
+    }
+
+    #[test]
+    fn test_decrypt_aes() {
+        let symm_key: SymmKey = [0x42u8; 32][..].try_into().unwrap(); //#[allow_ci]
+        let iv = [0x24u8; 16];
+        let ciphertext = openssl::symm::encrypt(
+            openssl::symm::Cipher::aes_256_cbc(),
+            symm_key.bytes(),
+            Some(&iv),
+            b"payload",
+        )
+        .unwrap(); //#[allow_ci]
+        let signed = [&iv[..], &ciphertext].concat();
+        let hmac = crypto::compute_hmac(
+            crate::algorithms::HmacAlgorithm::Sha384,
+            symm_key.bytes(),
+            &signed,
+        )
+        .unwrap(); //#[allow_ci]
+        let cbc = [&signed[..], &hmac].concat();
+        let gcm =
+            crypto::encrypt_aead(symm_key.bytes(), &iv, b"payload").unwrap(); //#[allow_ci]
+
+        // CBC payloads are refused unless accepted
+        let decryptor =
+            PayloadDecryptor::new(&KeylimeConfig::default(), None).unwrap(); //#[allow_ci]
+        assert_eq!(decryptor.decrypt(&gcm, &symm_key).unwrap(), b"payload"); //#[allow_ci]
+        assert!(decryptor.decrypt(&cbc, &symm_key).is_err());
+
+        let mut config = KeylimeConfig::default();
+        config.payload_encryption =
+            vec![PayloadEncryption::AesGcm, PayloadEncryption::AesCbcHmac];
+        let decryptor = PayloadDecryptor::new(&config, None).unwrap(); //#[allow_ci]
+
+        // Detected when the tenant named no format
+        assert_eq!(decryptor.decrypt(&cbc, &symm_key).unwrap(), b"payload"); //#[allow_ci]
+        assert_eq!(decryptor.decrypt(&gcm, &symm_key).unwrap(), b"payload"); //#[allow_ci]
+
+        // Only the named one otherwise
+        decryptor.select(Some(PayloadEncryption::AesGcm));
+        assert!(decryptor.decrypt(&cbc, &symm_key).is_err());
+        decryptor.select(Some(PayloadEncryption::AesCbcHmac));
+        assert_eq!(decryptor.decrypt(&cbc, &symm_key).unwrap(), b"payload"); //#[allow_ci]
+    }
+}
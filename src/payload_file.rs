@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Delivery of encrypted payloads staged on disk.
+//
+// Images can be built with the encrypted payload already in place. Instead
+// of the ciphertext, the tenant then sends the path of the file and its
+// SHA-256 digest to /keys/payload/file. Only files under the directories
+// listed in 'payload_file_dirs' are read, once symbolic links are resolved.
+// The content is then handled as if it was sent with the U key.
+
+use crate::error::{Error, Result};
+use openssl::sha::sha256;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimePayloadFile {
+    pub path: String,
+    // Hex encoded SHA-256 digest of the encrypted payload
+    pub sha256: String,
+}
+
+// Resolved path of the file, if it is in one of the allowed directories
+fn resolve(path: &Path, allowed_dirs: &[PathBuf]) -> Result<PathBuf> {
+    let path = path.canonicalize().map_err(|e| {
+        Error::PayloadFile(format!("{}: {}", path.display(), e))
+    })?;
+    let allowed = allowed_dirs.iter().any(|dir| match dir.canonicalize() {
+        Ok(dir) => path.starts_with(dir),
+        Err(_) => false,
+    });
+    if !allowed {
+        return Err(Error::PayloadFile(format!(
+            "{} is not in the directories allowed by payload_file_dirs",
+            path.display()
+        )));
+    }
+    Ok(path)
+}
+
+/*
+ * Input: payload file reference and the allowed directories
+ * Return: Result wrap the encrypted payload read from the file
+ */
+pub(crate) fn read(
+    file: &KeylimePayloadFile,
+    allowed_dirs: &[PathBuf],
+) -> Result<Vec<u8>> {
+    let path = resolve(Path::new(&file.path), allowed_dirs)?;
+    if !path.is_file() {
+        return Err(Error::PayloadFile(format!(
+            "{} is not a regular file",
+            path.display()
+        )));
+    }
+    let expected = hex::decode(&file.sha256)?;
+    let payload = fs::read(&path)?;
+    if sha256(&payload)[..] != expected[..] {
+        return Err(Error::PayloadFile(format!(
+            "SHA-256 digest mismatch for {}",
+            path.display()
+        )));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    fn reference(path: &Path, content: &[u8]) -> KeylimePayloadFile {
+        KeylimePayloadFile {
+            path: path.display().to_string(),
+            sha256: hex::encode(sha256(content)),
+        }
+    }
+
+    #[test]
+    fn test_read() {
+        let allowed = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let other = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let allowed_dirs = vec![allowed.path().to_path_buf()];
+
+        let staged = allowed.path().join("payload.enc");
+        fs::write(&staged, b"ciphertext").unwrap(); //#[allow_ci]
+        let payload = read(&reference(&staged, b"ciphertext"), &allowed_dirs);
+        assert_eq!(payload.unwrap(), b"ciphertext"); //#[allow_ci]
+        assert!(
+            read(&reference(&staged, b"tampered"), &allowed_dirs).is_err()
+        );
+        assert!(read(&reference(&staged, b"ciphertext"), &[]).is_err());
+        assert!(read(&reference(allowed.path(), b""), &allowed_dirs).is_err());
+
+        // Files outside of the allowed directories are refused, also when
+        // reached through a link or a relative path
+        let outside = other.path().join("secret");
+        fs::write(&outside, b"secret").unwrap(); //#[allow_ci]
+        assert!(read(&reference(&outside, b"secret"), &allowed_dirs).is_err());
+        let link = allowed.path().join("link");
+        symlink(&outside, &link).unwrap(); //#[allow_ci]
+        assert!(read(&reference(&link, b"secret"), &allowed_dirs).is_err());
+        let relative = allowed.path().join("..").join(
+            other.path().file_name().unwrap(), //#[allow_ci]
+        );
+        let relative = relative.join("secret");
+        assert!(
+            read(&reference(&relative, b"secret"), &allowed_dirs).is_err()
+        );
+    }
+}
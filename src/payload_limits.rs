@@ -0,0 +1,212 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Safety limits for the extraction of zipped payloads.
+//
+// A small archive can expand to fill the secure mount or hold millions of
+// entries. Before 'extract_payload_zip' unpacks a payload, the archive is read
+// through once, without writing anything, and refused as soon as it exceeds
+// the extracted size, the number of entries or the compression ratio allowed
+// in the configuration. The limit that tripped is reported in the status of
+// the payload, so that tenants can tell a malicious archive from a legitimate
+// one that only needs higher limits.
+
+use crate::{
+    common::KeylimeConfig,
+    error::{Error, Result},
+};
+use compress_tools::{ArchiveContents, ArchiveIterator};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    io::{Read, Seek},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Limit {
+    Size,
+    Entries,
+    Ratio,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LimitExceeded {
+    pub limit: Limit,
+    pub max: u64,
+    // Value reached when the extraction was refused, the whole archive can
+    // exceed it further
+    pub reached: u64,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.limit {
+            Limit::Size => write!(
+                f,
+                "extracted size over {} bytes (reached {})",
+                self.max, self.reached
+            ),
+            Limit::Entries => write!(
+                f,
+                "more than {} entries (reached {})",
+                self.max, self.reached
+            ),
+            Limit::Ratio => write!(
+                f,
+                "compression ratio over {} (reached {})",
+                self.max, self.reached
+            ),
+        }
+    }
+}
+
+// Zero disables a limit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ExtractionLimits {
+    pub max_size: u64,
+    pub max_entries: u64,
+    pub max_ratio: u64,
+}
+
+impl From<&KeylimeConfig> for ExtractionLimits {
+    fn from(config: &KeylimeConfig) -> Self {
+        ExtractionLimits {
+            max_size: config.payload_max_extracted_size,
+            max_entries: config.payload_max_entries,
+            max_ratio: config.payload_max_compression_ratio,
+        }
+    }
+}
+
+impl ExtractionLimits {
+    fn enforce(
+        &self,
+        size: u64,
+        entries: u64,
+        compressed: u64,
+    ) -> Result<()> {
+        let exceeded = |limit, max, reached| {
+            Err(Error::ExtractionLimit(LimitExceeded {
+                limit,
+                max,
+                reached,
+            }))
+        };
+        if self.max_size > 0 && size > self.max_size {
+            return exceeded(Limit::Size, self.max_size, size);
+        }
+        if self.max_entries > 0 && entries > self.max_entries {
+            return exceeded(Limit::Entries, self.max_entries, entries);
+        }
+        let ratio = size / compressed.max(1);
+        if self.max_ratio > 0 && ratio > self.max_ratio {
+            return exceeded(Limit::Ratio, self.max_ratio, ratio);
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Input: archive, its size and the limits to enforce
+ * Return: Result wrap the extracted size of the archive
+ *
+ * Decompresses the archive in memory chunk by chunk, stopping at the first
+ * limit exceeded.
+ */
+pub(crate) fn check<R: Read + Seek>(
+    source: R,
+    compressed: u64,
+    limits: &ExtractionLimits,
+) -> Result<u64> {
+    let mut size = 0u64;
+    let mut entries = 0u64;
+    for content in ArchiveIterator::from_read(source)? {
+        match content {
+            ArchiveContents::StartOfEntry(..) => entries += 1,
+            ArchiveContents::DataChunk(chunk) => {
+                size = size.saturating_add(chunk.len() as u64)
+            }
+            ArchiveContents::EndOfEntry => continue,
+            ArchiveContents::Err(e) => return Err(e.into()),
+        }
+        limits.enforce(size, entries, compressed)?;
+    }
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    const UNLIMITED: ExtractionLimits = ExtractionLimits {
+        max_size: 0,
+        max_entries: 0,
+        max_ratio: 0,
+    };
+
+    fn limit_of(result: Result<u64>) -> Option<Limit> {
+        match result {
+            Err(Error::ExtractionLimit(exceeded)) => Some(exceeded.limit),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join("payload.zip");
+        let compressed = fs::metadata(&path).unwrap().len(); //#[allow_ci]
+        let open = || fs::File::open(&path).unwrap(); //#[allow_ci]
+
+        let size = check(open(), compressed, &UNLIMITED).unwrap(); //#[allow_ci]
+        assert!(size > 0);
+
+        let limits = ExtractionLimits {
+            max_size: size - 1,
+            ..UNLIMITED
+        };
+        let result = check(open(), compressed, &limits);
+        assert_eq!(limit_of(result), Some(Limit::Size));
+
+        // As if the archive was a tenth of its size
+        let limits = ExtractionLimits {
+            max_ratio: 1,
+            ..UNLIMITED
+        };
+        let result = check(open(), compressed / 10, &limits);
+        assert_eq!(limit_of(result), Some(Limit::Ratio));
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = ExtractionLimits {
+            max_size: 1000,
+            max_entries: 2,
+            max_ratio: 10,
+        };
+        assert!(limits.enforce(1000, 2, 100).is_ok());
+        let result = limits.enforce(1001, 2, 200).map(|_| 0);
+        assert_eq!(limit_of(result), Some(Limit::Size));
+        let result = limits.enforce(100, 3, 100).map(|_| 0);
+        assert_eq!(limit_of(result), Some(Limit::Entries));
+        let result = limits.enforce(1000, 1, 50).map(|_| 0);
+        assert_eq!(limit_of(result), Some(Limit::Ratio));
+        assert!(UNLIMITED.enforce(u64::MAX, u64::MAX, 0).is_ok());
+    }
+
+    #[test]
+    fn test_display() {
+        let exceeded = LimitExceeded {
+            limit: Limit::Ratio,
+            max: 100,
+            reached: 250,
+        };
+        assert_eq!(
+            exceeded.to_string(),
+            "compression ratio over 100 (reached 250)"
+        );
+    }
+}
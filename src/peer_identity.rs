@@ -0,0 +1,140 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Identity of the clients in the request logs.
+//
+// With mTLS, the certificate a client authenticated with tells verifiers and
+// tenants apart where the address alone does not, e.g. behind a NAT. The
+// subject and SAN of the certificate are recorded when the connection is
+// accepted and logged with every request made on it.
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    dev::Extensions, dev::ServiceRequest, rt::net::TcpStream, HttpRequest,
+};
+use openssl::x509::{GeneralNameRef, X509Ref};
+use std::{any::Any, convert::TryFrom, net::IpAddr};
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PeerIdentity(String);
+
+fn format_name(name: &GeneralNameRef) -> Option<String> {
+    if let Some(dns) = name.dnsname() {
+        return Some(format!("DNS:{}", dns));
+    }
+    if let Some(ip) = name.ipaddress() {
+        let ip = match ip.len() {
+            4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+            16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+            _ => return None,
+        };
+        return Some(format!("IP:{}", ip));
+    }
+    if let Some(email) = name.email() {
+        return Some(format!("email:{}", email));
+    }
+    name.uri().map(|uri| format!("URI:{}", uri))
+}
+
+fn describe(cert: &X509Ref) -> String {
+    let subject = cert
+        .subject_name()
+        .entries()
+        .map(|entry| {
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|value| value.to_string())
+                .unwrap_or_default();
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            format!("{}={}", key, value)
+        })
+        .collect::<Vec<String>>()
+        .join(",");
+    let names = match cert.subject_alt_names() {
+        Some(names) => names
+            .iter()
+            .filter_map(format_name)
+            .collect::<Vec<String>>(),
+        None => Vec::new(),
+    };
+    if names.is_empty() {
+        subject
+    } else {
+        format!("{} SAN {}", subject, names.join(","))
+    }
+}
+
+/*
+ * Input: accepted connection and its data
+ *
+ * Records the identity of the client certificate on TLS connections.
+ */
+pub(crate) fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(tls) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(cert) = tls.ssl().peer_certificate() {
+            data.insert(PeerIdentity(describe(&cert)));
+        }
+    }
+}
+
+/*
+ * Input: request
+ * Return: the identity of the client, "-" without a client certificate
+ */
+pub(crate) fn client(req: &ServiceRequest) -> String {
+    match req.conn_data::<PeerIdentity>() {
+        Some(PeerIdentity(identity)) => identity.clone(),
+        None => "-".to_string(),
+    }
+}
+
+// The identity of the client certificate of a request being handled, None
+// without a client certificate
+pub(crate) fn of_request(req: &HttpRequest) -> Option<String> {
+    req.conn_data::<PeerIdentity>()
+        .map(|PeerIdentity(identity)| identity.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+    use openssl::{
+        asn1::Asn1Time,
+        hash::MessageDigest,
+        x509::{extension::SubjectAlternativeName, X509Name, X509},
+    };
+
+    #[test]
+    fn test_describe() {
+        let (_, key) = crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        assert_eq!(describe(&cert), "CN=uuid");
+
+        let mut name = X509Name::builder().unwrap(); //#[allow_ci]
+        name.append_entry_by_text("O", "Keylime").unwrap(); //#[allow_ci]
+        name.append_entry_by_text("CN", "verifier").unwrap(); //#[allow_ci]
+        let name = name.build();
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+        builder.set_issuer_name(&name).unwrap(); //#[allow_ci]
+        builder.set_pubkey(&key).unwrap(); //#[allow_ci]
+        let not_before = Asn1Time::days_from_now(0).unwrap(); //#[allow_ci]
+        let not_after = Asn1Time::days_from_now(1).unwrap(); //#[allow_ci]
+        builder.set_not_before(&not_before).unwrap(); //#[allow_ci]
+        builder.set_not_after(&not_after).unwrap(); //#[allow_ci]
+        let san = SubjectAlternativeName::new()
+            .dns("verifier.example")
+            .ip("192.0.2.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap(); //#[allow_ci]
+        builder.append_extension(san).unwrap(); //#[allow_ci]
+        builder.sign(&key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+
+        assert_eq!(
+            describe(&builder.build()),
+            "O=Keylime,CN=verifier SAN DNS:verifier.example,IP:192.0.2.1"
+        );
+    }
+}
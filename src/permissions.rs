@@ -4,15 +4,24 @@
 use crate::error::{Error, Result};
 use libc::{c_char, c_int, gid_t, uid_t};
 use log::*;
-use std::os::unix::ffi::OsStrExt;
+use std::os::unix::{
+    ffi::OsStrExt,
+    fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+};
 use std::{
     convert::{TryFrom, TryInto},
     ffi::CString,
-    io,
-    path::Path,
+    fs, io,
+    path::{Path, PathBuf},
     ptr,
 };
 
+// Modes for the files and directories written by the agent. These are
+// applied on creation, so the process umask still takes effect and a
+// stricter umask set by the administrator overrides them.
+pub(crate) const FILE_MODE: u32 = 0o600;
+pub(crate) const DIR_MODE: u32 = 0o700;
+
 pub(crate) struct UserIds {
     passwd: libc::passwd,
     group: libc::group,
@@ -170,3 +179,124 @@ pub(crate) fn chown(user_group: &str, path: &Path) -> Result<()> {
     info!("Changed file {} owner to {}.", path.display(), user_group);
     Ok(())
 }
+
+// Drop any permission bits not in 'mode' from an existing file or directory,
+// keeping the ones already removed by a stricter umask
+fn restrict_mode(
+    path: &Path,
+    metadata: &fs::Metadata,
+    mode: u32,
+) -> Result<()> {
+    let current = metadata.permissions().mode() & 0o777;
+    if current & !mode != 0 {
+        fs::set_permissions(
+            path,
+            fs::Permissions::from_mode(current & mode),
+        )?;
+    }
+    Ok(())
+}
+
+// Create or truncate a file for writing, readable and writable by the owner
+// only
+pub(crate) fn create_file(path: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(FILE_MODE)
+        .open(path)?;
+    // The mode only applies when the file is created, so tighten the
+    // permissions of a file that already existed
+    restrict_mode(path, &file.metadata()?, FILE_MODE)?;
+    Ok(file)
+}
+
+// Open a file for appending, creating it readable and writable by the owner
+// only if needed
+pub(crate) fn open_append(path: &Path) -> Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(FILE_MODE)
+        .open(path)?;
+    restrict_mode(path, &file.metadata()?, FILE_MODE)?;
+    Ok(file)
+}
+
+// Create a directory accessible by the owner only
+pub(crate) fn create_dir(path: &Path) -> Result<()> {
+    fs::DirBuilder::new().mode(DIR_MODE).create(path)?;
+    Ok(())
+}
+
+/*
+ * Input: paths of artifacts written by the agent
+ *
+ * Warn about existing artifacts that are accessible by users other than the
+ * owner, e.g. left behind by an older agent version. Paths that do not exist
+ * are skipped.
+ */
+pub(crate) fn audit(paths: &[PathBuf]) {
+    for path in paths {
+        let metadata = match fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => {
+                warn!(
+                    "Unable to check permissions of {}: {}",
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        let expected = if metadata.is_dir() {
+            DIR_MODE
+        } else {
+            FILE_MODE
+        };
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & !expected != 0 {
+            warn!(
+                "{} has permissions {:o}, which are looser than {:o}",
+                path.display(),
+                mode,
+                expected
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_file() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("artifact");
+
+        let _ = create_file(&path).unwrap(); //#[allow_ci]
+        let mode = fs::metadata(&path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o077, 0);
+
+        // Existing files with looser permissions are tightened
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))
+            .unwrap(); //#[allow_ci]
+        let _ = create_file(&path).unwrap(); //#[allow_ci]
+        let mode = fs::metadata(&path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_create_dir() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("secure");
+
+        create_dir(&path).unwrap(); //#[allow_ci]
+        let mode = fs::metadata(&path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o077, 0);
+        assert!(create_dir(&path).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// NK kept in a PKCS#11 token.
+//
+// With 'nk_pkcs11_uri' set, the NK is not generated by the agent but loaded
+// from an HSM, a smart card or the TPM itself through tpm2-pkcs11. The key is
+// loaded with the OpenSSL pkcs11 engine of libp11, which returns a key whose
+// operations run in the token: the OpenSSL code decrypting the U and V keys,
+// signing the mTLS certificate and running the TLS handshakes uses it as any
+// other key, while the private key never leaves the token. The engine stays
+// initialized for the life of the agent, as the key needs its session.
+//
+// Without the 'pkcs11' feature, configuring a URI is an error rather than
+// silently falling back to a key in memory.
+
+use crate::error::{Error, Result};
+use openssl::pkey::{PKey, Private};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "pkcs11")] {
+        use foreign_types::ForeignType;
+        use log::*;
+        use std::{
+            ffi::CString,
+            os::raw::{c_char, c_int, c_void},
+            ptr,
+        };
+
+        static ENGINE_ID: &str = "pkcs11";
+
+        #[allow(clippy::upper_case_acronyms)]
+        enum ENGINE {}
+
+        extern "C" {
+            fn ENGINE_by_id(id: *const c_char) -> *mut ENGINE;
+            fn ENGINE_ctrl_cmd_string(
+                e: *mut ENGINE,
+                cmd_name: *const c_char,
+                arg: *const c_char,
+                cmd_optional: c_int,
+            ) -> c_int;
+            fn ENGINE_init(e: *mut ENGINE) -> c_int;
+            fn ENGINE_free(e: *mut ENGINE) -> c_int;
+            fn ENGINE_load_private_key(
+                e: *mut ENGINE,
+                key_id: *const c_char,
+                ui_method: *mut c_void,
+                callback_data: *mut c_void,
+            ) -> *mut c_void;
+        }
+
+        fn engine_error(operation: &str) -> Error {
+            Error::Other(format!(
+                "{} failed: {}",
+                operation,
+                openssl::error::ErrorStack::get()
+            ))
+        }
+
+        fn cstring(value: &str) -> Result<CString> {
+            Ok(CString::new(value)?)
+        }
+
+        // Sets an option of the engine, before its initialization
+        fn engine_ctrl(engine: *mut ENGINE, cmd: &str, arg: &str) -> Result<()> {
+            let (cmd, arg) = (cstring(cmd)?, cstring(arg)?);
+            if unsafe {
+                ENGINE_ctrl_cmd_string(engine, cmd.as_ptr(), arg.as_ptr(), 0)
+            } != 1
+            {
+                return Err(engine_error("setting the pkcs11 engine options"));
+            }
+            Ok(())
+        }
+
+        /*
+         * Input: PKCS#11 URI of the key, module and PIN of the token
+         * Return: Result wrap the private key, whose operations run in the
+         *         token
+         */
+        pub(crate) fn load_private_key(
+            uri: &str,
+            module: Option<&str>,
+            pin: Option<&str>,
+        ) -> Result<PKey<Private>> {
+            let id = cstring(ENGINE_ID)?;
+            let engine = unsafe { ENGINE_by_id(id.as_ptr()) };
+            if engine.is_null() {
+                return Err(Error::Configuration(format!(
+                    "nk_pkcs11_uri is set but the OpenSSL pkcs11 engine is not available: {}",
+                    openssl::error::ErrorStack::get()
+                )));
+            }
+            let loaded = (|| {
+                if let Some(module) = module {
+                    engine_ctrl(engine, "MODULE_PATH", module)?;
+                }
+                if let Some(pin) = pin {
+                    engine_ctrl(engine, "PIN", pin)?;
+                }
+                if unsafe { ENGINE_init(engine) } != 1 {
+                    return Err(engine_error("initializing the pkcs11 engine"));
+                }
+                let uri = cstring(uri)?;
+                let key = unsafe {
+                    ENGINE_load_private_key(
+                        engine,
+                        uri.as_ptr(),
+                        ptr::null_mut(),
+                        ptr::null_mut(),
+                    )
+                };
+                if key.is_null() {
+                    return Err(engine_error("loading the NK from the token"));
+                }
+                Ok(unsafe { PKey::from_ptr(key.cast()) })
+            })();
+            // The functional reference taken by ENGINE_init is kept
+            let _ = unsafe { ENGINE_free(engine) };
+            let key = loaded?;
+            info!("Using the NK of the PKCS#11 token, {}", redact(uri));
+            Ok(key)
+        }
+    } else {
+        pub(crate) fn load_private_key(
+            _uri: &str,
+            _module: Option<&str>,
+            _pin: Option<&str>,
+        ) -> Result<PKey<Private>> {
+            Err(Error::Configuration(
+                "nk_pkcs11_uri is set but the agent was built without the 'pkcs11' feature"
+                    .to_string(),
+            ))
+        }
+    }
+}
+
+// The URI without the PIN it may carry, for the logs
+pub(crate) fn redact(uri: &str) -> String {
+    let (path, query) = match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    };
+    let attributes = |part: &str, separator: char| {
+        part.split(separator)
+            .map(|attribute| {
+                if attribute.starts_with("pin-value=") {
+                    "pin-value=***"
+                } else {
+                    attribute
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(&separator.to_string())
+    };
+    match query {
+        Some(query) => {
+            format!("{}?{}", attributes(path, ';'), attributes(query, '&'))
+        }
+        None => attributes(path, ';'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("pkcs11:token=keylime;object=nk"),
+            "pkcs11:token=keylime;object=nk"
+        );
+        assert_eq!(
+            redact("pkcs11:token=keylime;object=nk?pin-value=1234&module-name=tpm2"),
+            "pkcs11:token=keylime;object=nk?pin-value=***&module-name=tpm2"
+        );
+    }
+
+    #[cfg(not(feature = "pkcs11"))]
+    #[test]
+    fn test_disabled() {
+        assert!(load_private_key("pkcs11:object=nk", None, None).is_err());
+    }
+}
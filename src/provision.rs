@@ -0,0 +1,339 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Provisioning of the TPM before the first start of the agent.
+//
+// `keylime_agent provision-tpm` replaces the tpm2-tools steps operators run
+// to take ownership of the TPM: the EK is created and persisted with
+// TPM2_EvictControl, at the handle the TCG reserves for it by default, and
+// the authorization of the owner and endorsement hierarchies is set, to the
+// given password or to a generated one. The values the agent then needs,
+// 'ek_handle' and 'tpm_ownerpassword', are written in the format of
+// keylime-agent.conf. Running it again with the current password keeps the
+// persisted EK when it is the one of the TPM, and changes the password.
+//
+// The output file is written before the authorization changes, so that the
+// password is not lost when it cannot be written. When the authorization of
+// the endorsement hierarchy cannot be changed, the owner one goes back to the
+// current password, rather than leaving the hierarchies with different ones.
+
+use crate::{
+    algorithms::EncryptionAlgorithm,
+    error::{Error, Result},
+    permissions, tpm,
+};
+use log::*;
+use std::{convert::TryFrom, fs, io::Write, path::Path};
+use tss_esapi::{
+    abstraction::{ek, DefaultKey},
+    handles::{AuthHandle, ObjectHandle, PersistentTpmHandle, TpmHandle},
+    interface_types::{
+        dynamic_handles::Persistent,
+        resource_handles::{Hierarchy, Provision},
+        session_handles::AuthSession,
+    },
+    structures::{Auth, Public, PublicBuffer},
+    traits::Marshall,
+    Context,
+};
+
+// Persistent handles of the EKs, as in the TCG TPM v2.0 Provisioning Guidance
+const RSA_EK_HANDLE: u32 = 0x81010001;
+const ECC_EK_HANDLE: u32 = 0x81010002;
+// Bytes of the generated passwords, hex encoded
+const PASSWORD_LEN: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProvisionOptions {
+    pub enc_alg: EncryptionAlgorithm,
+    // Defaults to the handle of the TCG for the EK algorithm
+    pub ek_handle: Option<u32>,
+    // Authorization of the owner and endorsement hierarchies, empty when the
+    // TPM was cleared
+    pub current_password: Option<String>,
+    // Generated when not given
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Provisioned {
+    pub ek_handle: u32,
+    pub password: String,
+}
+
+impl Provisioned {
+    // Options of keylime-agent.conf to set for the agent to use the TPM
+    pub(crate) fn config(&self) -> String {
+        format!(
+            "# Written by keylime_agent provision-tpm, set in [cloud_agent]\n\
+             ek_handle = {:#010x}\n\
+             tpm_ownerpassword = {}\n",
+            self.ek_handle, self.password
+        )
+    }
+}
+
+pub(crate) fn default_ek_handle(alg: EncryptionAlgorithm) -> u32 {
+    match alg {
+        EncryptionAlgorithm::Rsa => RSA_EK_HANDLE,
+        EncryptionAlgorithm::Ecc => ECC_EK_HANDLE,
+    }
+}
+
+pub(crate) fn parse_handle(value: &str) -> Result<u32> {
+    let handle = u32::from_str_radix(value.trim_start_matches("0x"), 16)?;
+    let _ = PersistentTpmHandle::new(handle).map_err(|_| {
+        Error::Configuration(format!(
+            "{} is not a persistent handle, they range from 0x81000000 to 0x81ffffff",
+            value
+        ))
+    })?;
+    Ok(handle)
+}
+
+fn generate_password() -> Result<String> {
+    let mut bytes = [0u8; PASSWORD_LEN];
+    openssl::rand::rand_bytes(&mut bytes)?;
+    Ok(hex::encode(bytes))
+}
+
+fn marshalled(public: Public) -> Result<Vec<u8>> {
+    Ok(PublicBuffer::try_from(public)?.marshall()?)
+}
+
+// Public area of the object persisted at the handle, None when it is free
+fn persisted(context: &mut Context, handle: u32) -> Result<Option<Public>> {
+    let tpm_handle = TpmHandle::Persistent(PersistentTpmHandle::new(handle)?);
+    let mut object = match context.tr_from_tpm_public(tpm_handle) {
+        Ok(object) => object,
+        Err(_) => return Ok(None),
+    };
+    let public =
+        tpm::retry_tpm_command(|| context.read_public(object.into()));
+    context.tr_close(&mut object)?;
+    Ok(Some(public?.0))
+}
+
+// Creates the EK and persists it at the handle, unless it is already there
+fn persist_ek(
+    context: &mut Context,
+    alg: EncryptionAlgorithm,
+    handle: u32,
+) -> Result<()> {
+    let ek = ek::create_ek_object(context, alg.into(), DefaultKey)?;
+    let result = (|| -> Result<()> {
+        let (public, _, _) =
+            tpm::retry_tpm_command(|| context.read_public(ek))?;
+        let existing = match persisted(context, handle)? {
+            Some(existing) => existing,
+            None => {
+                let persistent =
+                    Persistent::Persistent(PersistentTpmHandle::new(handle)?);
+                let _ = tpm::retry_tpm_command(|| {
+                    context.execute_with_session(
+                        Some(AuthSession::Password),
+                        |ctx| {
+                            ctx.evict_control(
+                                Provision::Owner,
+                                ObjectHandle::from(ek),
+                                persistent,
+                            )
+                        },
+                    )
+                })?;
+                info!("Persisted the EK at {:#010x}", handle);
+                return Ok(());
+            }
+        };
+        if marshalled(existing)? != marshalled(public)? {
+            return Err(Error::Configuration(format!(
+                "{:#010x} holds another key, choose another handle or evict it with 'tpm2_evictcontrol -C o -c {:#010x}'",
+                handle, handle
+            )));
+        }
+        info!("The EK is already persisted at {:#010x}", handle);
+        Ok(())
+    })();
+    context.flush_context(ek.into())?;
+    result
+}
+
+fn change_hierarchy_auth(
+    context: &mut Context,
+    hierarchy: Hierarchy,
+    auth: Auth,
+) -> Result<()> {
+    let handle = match hierarchy {
+        Hierarchy::Owner => AuthHandle::Owner,
+        _ => AuthHandle::Endorsement,
+    };
+    tpm::retry_tpm_command(|| {
+        context.execute_with_session(Some(AuthSession::Password), |ctx| {
+            ctx.hierarchy_change_auth(handle, auth.clone())
+        })
+    })?;
+    // Authorizes the next commands, e.g. to go back on failure
+    context.tr_set_auth(hierarchy.into(), auth)?;
+    Ok(())
+}
+
+/*
+ * Input: Connection context, current and new authorization
+ *
+ * Hierarchies already changed go back to the current authorization when
+ * the next one cannot be changed.
+ */
+fn change_auth(
+    context: &mut Context,
+    current: Auth,
+    auth: Auth,
+) -> Result<()> {
+    let mut changed = Vec::new();
+    for hierarchy in [Hierarchy::Owner, Hierarchy::Endorsement] {
+        if let Err(e) =
+            change_hierarchy_auth(context, hierarchy, auth.clone())
+        {
+            for hierarchy in changed {
+                if let Err(restore) =
+                    change_hierarchy_auth(context, hierarchy, current.clone())
+                {
+                    error!(
+                        "Unable to restore the authorization of the {:?} hierarchy: {}",
+                        hierarchy, restore
+                    );
+                }
+            }
+            return Err(e);
+        }
+        changed.push(hierarchy);
+    }
+    Ok(())
+}
+
+/*
+ * Input: Connection context, provisioning options and file to write the
+ *        configuration to, stdout when none
+ * Return: the handle of the persisted EK and the hierarchy password
+ */
+pub(crate) fn provision(
+    context: &mut Context,
+    options: &ProvisionOptions,
+    output: Option<&Path>,
+) -> Result<Provisioned> {
+    let ek_handle = options
+        .ek_handle
+        .unwrap_or_else(|| default_ek_handle(options.enc_alg));
+    let password = match &options.password {
+        Some(password) => password.clone(),
+        None => generate_password()?,
+    };
+
+    let current = match &options.current_password {
+        Some(current) => {
+            let auth = Auth::try_from(current.as_bytes())?;
+            context.tr_set_auth(Hierarchy::Owner.into(), auth.clone())?;
+            context
+                .tr_set_auth(Hierarchy::Endorsement.into(), auth.clone())?;
+            auth
+        }
+        None => Auth::default(),
+    };
+    let auth = Auth::try_from(password.as_bytes())?;
+
+    persist_ek(context, options.enc_alg, ek_handle)?;
+
+    let provisioned = Provisioned {
+        ek_handle,
+        password,
+    };
+    if output.is_some() {
+        write_config(&provisioned, output)?;
+    }
+    if let Err(e) = change_auth(context, current, auth) {
+        if let Some(path) = output {
+            let _ = fs::remove_file(path);
+        }
+        return Err(e);
+    }
+    info!("Set the authorization of the owner and endorsement hierarchies");
+    if output.is_none() {
+        write_config(&provisioned, None)?;
+    }
+
+    Ok(provisioned)
+}
+
+/*
+ * Input: provisioning result and file to write the configuration to, stdout
+ *        when none
+ *
+ * The file holds the hierarchy password, it is only readable by its owner.
+ */
+fn write_config(
+    provisioned: &Provisioned,
+    output: Option<&Path>,
+) -> Result<()> {
+    match output {
+        Some(path) => {
+            let mut file = permissions::create_file(path)?;
+            file.write_all(provisioned.config().as_bytes())?;
+        }
+        None => print!("{}", provisioned.config()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_handle() {
+        assert_eq!(parse_handle("0x81010001").unwrap(), 0x81010001); //#[allow_ci]
+        assert_eq!(parse_handle("81000000").unwrap(), 0x81000000); //#[allow_ci]
+        assert!(parse_handle("0x01c00002").is_err());
+        assert!(parse_handle("handle").is_err());
+    }
+
+    #[test]
+    fn test_default_ek_handle() {
+        assert_eq!(default_ek_handle(EncryptionAlgorithm::Rsa), 0x81010001);
+        assert_eq!(default_ek_handle(EncryptionAlgorithm::Ecc), 0x81010002);
+    }
+
+    #[test]
+    fn test_write_config() {
+        let provisioned = Provisioned {
+            ek_handle: 0x81010001,
+            password: "secret".to_string(),
+        };
+        assert!(provisioned.config().contains("\nek_handle = 0x81010001\n"));
+        assert!(provisioned
+            .config()
+            .ends_with("tpm_ownerpassword = secret\n"));
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("provisioned.conf");
+        write_config(&provisioned, Some(&path)).unwrap(); //#[allow_ci]
+        let conf = ini::Ini::load_from_file(&path).unwrap(); //#[allow_ci]
+        assert_eq!(
+            conf.get_from(None::<String>, "ek_handle"),
+            Some("0x81010001")
+        );
+        assert_eq!(
+            conf.get_from(None::<String>, "tpm_ownerpassword"),
+            Some("secret")
+        );
+        let mode = std::os::unix::fs::PermissionsExt::mode(
+            &std::fs::metadata(&path).unwrap().permissions(), //#[allow_ci]
+        );
+        assert_eq!(mode & 0o077, 0);
+    }
+
+    #[test]
+    fn test_generate_password() {
+        let password = generate_password().unwrap(); //#[allow_ci]
+        assert_eq!(password.len(), 2 * PASSWORD_LEN);
+        assert_ne!(password, generate_password().unwrap()); //#[allow_ci]
+    }
+}
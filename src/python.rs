@@ -0,0 +1,279 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Python module of the agent.
+//
+// With the 'python' feature, libkeylime_agent_sys is also the
+// keylime_agent_sys Python module, so that existing Python tooling can get
+// quotes from the agent rather than shelling out to tpm2-tools:
+//
+//     import json, keylime_agent_sys
+//     agent = keylime_agent_sys.Agent("/var/run/keylime/agent.sock")
+//     quote = json.loads(agent.identity_quote("1234567890"))
+//
+// Agent offers the operations of the C API, see ffi.rs: the quotes are
+// assembled by the running agent, which holds the AK, and the agent can be
+// asked to register again. Registrar is a client of the registrar API, to
+// read what agents registered, e.g. their EK and AK. assemble_quote() puts
+// the outputs of tpm2_quote in the quote format of the agent, for tooling
+// still quoting with tpm2-tools. The module also has the crypto helpers
+// needed to handle the payloads of the agent outside of it: the HMAC of the
+// auth tag and the AES-GCM payload format, see aead.rs.
+//
+// The GIL is released while waiting for the agent and the registrar.
+
+use crate::aead;
+use openssl::{hash::MessageDigest, rand::rand_bytes};
+use pyo3::{exceptions::PyRuntimeError, prelude::*, types::PyBytes};
+use serde_json::{json, Value};
+
+// The HMAC algorithms of the agent, see HmacAlgorithm
+static HMAC_ALGORITHMS: &[&str] = &["sha256", "sha384", "sha512"];
+
+// Version of the registrar API the agent registers with, see common.rs
+const REGISTRAR_API_VERSION: &str = "v2.0";
+
+fn runtime_error(e: impl ToString) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+#[pyclass]
+struct Agent {
+    socket: String,
+}
+
+#[pymethods]
+impl Agent {
+    // Agent(socket), with the path set in 'admin_socket'
+    #[new]
+    fn new(socket: String) -> Self {
+        Agent { socket }
+    }
+
+    // Status report of the agent, as JSON
+    fn status(&self, py: Python) -> PyResult<String> {
+        let status = py.allow_threads(|| crate::status(&self.socket));
+        Ok(status.map_err(runtime_error)?.to_string())
+    }
+
+    // Identity quote for the alphanumerical nonce, as JSON
+    fn identity_quote(&self, py: Python, nonce: &str) -> PyResult<String> {
+        let quote =
+            py.allow_threads(|| crate::identity_quote(&self.socket, nonce));
+        Ok(quote.map_err(runtime_error)?.to_string())
+    }
+
+    // Data sealed under the payload key
+    fn seal<'p>(&self, py: Python<'p>, data: &[u8]) -> PyResult<&'p PyBytes> {
+        let sealed = py.allow_threads(|| crate::seal(&self.socket, data));
+        Ok(PyBytes::new(py, &sealed.map_err(runtime_error)?))
+    }
+
+    // Data unsealed, while the agent is attested and not revoked
+    fn unseal<'p>(
+        &self,
+        py: Python<'p>,
+        sealed: &[u8],
+    ) -> PyResult<&'p PyBytes> {
+        let data = py.allow_threads(|| crate::unseal(&self.socket, sealed));
+        Ok(PyBytes::new(py, &data.map_err(runtime_error)?))
+    }
+
+    // Registers the agent again, with its EK and AK
+    fn reregister(&self, py: Python) -> PyResult<String> {
+        let response = py.allow_threads(|| {
+            crate::request(&self.socket, &json!({ "command": "reregister" }))
+        });
+        let response = response.map_err(runtime_error)?;
+        Ok(response["message"].as_str().unwrap_or_default().to_string())
+    }
+}
+
+fn registrar_url(ip: &str, port: u16, path: &str) -> String {
+    format!(
+        "http://{}:{}/{}/agents/{}",
+        ip, port, REGISTRAR_API_VERSION, path
+    )
+}
+
+/*
+ * Input: URL of the registrar API
+ * Return: Result wrap the results of the response
+ */
+fn registrar_get(url: &str) -> Result<Value, String> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+    runtime.block_on(async {
+        let resp = reqwest::get(url).await.map_err(|e| {
+            format!("request to the registrar on {} failed: {}", url, e)
+        })?;
+        if !resp.status().is_success() {
+            return Err(format!(
+                "the registrar answered {} on {}",
+                resp.status(),
+                url
+            ));
+        }
+        let mut body: Value = resp.json().await.map_err(|e| {
+            format!("invalid response of the registrar: {}", e)
+        })?;
+        Ok(body["results"].take())
+    })
+}
+
+#[pyclass]
+struct Registrar {
+    ip: String,
+    port: u16,
+}
+
+#[pymethods]
+impl Registrar {
+    // Registrar(ip, port), with 'registrar_ip' and 'registrar_port'
+    #[new]
+    fn new(ip: String, port: u16) -> Self {
+        Registrar { ip, port }
+    }
+
+    // Registration of the agent, as JSON: its EK, EK certificate, AK and
+    // contact address
+    fn agent(&self, py: Python, uuid: &str) -> PyResult<String> {
+        let url = registrar_url(&self.ip, self.port, uuid);
+        let results = py.allow_threads(|| registrar_get(&url));
+        Ok(results.map_err(runtime_error)?.to_string())
+    }
+
+    // UUIDs of the registered agents, as JSON
+    fn agents(&self, py: Python) -> PyResult<String> {
+        let url = registrar_url(&self.ip, self.port, "");
+        let results = py.allow_threads(|| registrar_get(&url));
+        Ok(results.map_err(runtime_error)?.to_string())
+    }
+}
+
+// Quote in the format of the agent from the outputs of tpm2_quote: the
+// attestation (-m), the signature (-s) and the PCR values (-o, with
+// --pcrs_format serialized)
+#[pyfunction]
+fn assemble_quote(attest: &[u8], signature: &[u8], pcrs: &[u8]) -> String {
+    format!(
+        "r{}:{}:{}",
+        base64::encode(attest),
+        base64::encode(signature),
+        base64::encode(pcrs)
+    )
+}
+
+fn hmac_digest(alg: &str) -> PyResult<MessageDigest> {
+    match MessageDigest::from_name(alg) {
+        Some(digest) if HMAC_ALGORITHMS.contains(&alg) => Ok(digest),
+        _ => Err(runtime_error(format!(
+            "HMAC algorithm must be one of {}, got {}",
+            HMAC_ALGORITHMS.join(", "),
+            alg
+        ))),
+    }
+}
+
+// HMAC of the data, e.g. of the agent UUID with the payload key for the auth
+// tag
+#[pyfunction]
+fn compute_hmac<'p>(
+    py: Python<'p>,
+    alg: &str,
+    key: &[u8],
+    data: &[u8],
+) -> PyResult<&'p PyBytes> {
+    let hmac = aead::compute_hmac(hmac_digest(alg)?, key, data)
+        .map_err(runtime_error)?;
+    Ok(PyBytes::new(py, &hmac))
+}
+
+#[pyfunction]
+fn verify_hmac(
+    alg: &str,
+    key: &[u8],
+    data: &[u8],
+    tag: &[u8],
+) -> PyResult<bool> {
+    match aead::verify_hmac(hmac_digest(alg)?, key, data, tag) {
+        Ok(()) => Ok(true),
+        Err(aead::AeadError::HmacMismatch) => Ok(false),
+        Err(e) => Err(runtime_error(e)),
+    }
+}
+
+// Data encrypted in the payload format: IV, ciphertext and tag
+#[pyfunction]
+fn encrypt_aead<'p>(
+    py: Python<'p>,
+    key: &[u8],
+    data: &[u8],
+) -> PyResult<&'p PyBytes> {
+    let mut iv = [0u8; aead::IV_LEN];
+    rand_bytes(&mut iv).map_err(runtime_error)?;
+    let encrypted = aead::encrypt(key, &iv, data).map_err(runtime_error)?;
+    Ok(PyBytes::new(py, &encrypted))
+}
+
+// Payloads are decrypted with either IV, as the agent does
+#[pyfunction]
+fn decrypt_aead<'p>(
+    py: Python<'p>,
+    key: &[u8],
+    data: &[u8],
+) -> PyResult<&'p PyBytes> {
+    let plain = aead::decrypt(key, data).map_err(runtime_error)?;
+    Ok(PyBytes::new(py, &plain))
+}
+
+#[pymodule]
+fn keylime_agent_sys(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Agent>()?;
+    m.add_class::<Registrar>()?;
+    m.add_function(wrap_pyfunction!(assemble_quote, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_hmac, m)?)?;
+    m.add_function(wrap_pyfunction!(verify_hmac, m)?)?;
+    m.add_function(wrap_pyfunction!(encrypt_aead, m)?)?;
+    m.add_function(wrap_pyfunction!(decrypt_aead, m)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hmac_digest() {
+        for alg in HMAC_ALGORITHMS {
+            assert!(hmac_digest(alg).is_ok());
+        }
+        assert!(hmac_digest("md5").is_err());
+        assert!(hmac_digest("sha1").is_err());
+        assert!(hmac_digest("unknown").is_err());
+    }
+
+    #[test]
+    fn test_registrar_url() {
+        assert_eq!(
+            registrar_url("127.0.0.1", 8890, "d432fbb3"),
+            "http://127.0.0.1:8890/v2.0/agents/d432fbb3"
+        );
+        assert_eq!(
+            registrar_url("127.0.0.1", 8890, ""),
+            "http://127.0.0.1:8890/v2.0/agents/"
+        );
+    }
+
+    #[test]
+    fn test_assemble_quote() {
+        let quote = assemble_quote(b"attest", b"signature", b"pcrs");
+        let parts: Vec<&str> = quote[1..].split(':').collect();
+        assert!(quote.starts_with('r'));
+        assert_eq!(base64::decode(parts[0]).unwrap(), b"attest"); //#[allow_ci]
+        assert_eq!(base64::decode(parts[1]).unwrap(), b"signature"); //#[allow_ci]
+        assert_eq!(base64::decode(parts[2]).unwrap(), b"pcrs"); //#[allow_ci]
+    }
+}
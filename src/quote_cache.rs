@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Short-lived cache of quotes.
+//
+// A verifier polling often, or retrying a request that timed out on its side,
+// sends the same quote request again while the TPM is still busy with the
+// first one. With 'quote_cache_ttl' set, quotes are kept for that many seconds
+// keyed by everything that goes into them: the nonce, the PCR mask, the
+// additional banks, the IAK co-signing and the digests the agent extended into
+// PCRs itself. Identical requests arriving while a quote is being signed wait
+// for it instead of queueing another one on the TPM. As the nonce is part of
+// the key, a verifier sending a fresh nonce always gets a fresh quote, only
+// requests repeating a nonce within the TTL are served from the cache. Failed
+// quotes are not cached.
+
+use crate::{
+    algorithms::HashAlgorithm, error::Result, quotes_handler::KeylimeQuote,
+};
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+// Bound on the quotes cached, the oldest ones are dropped first
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct QuoteKey {
+    pub nonce: Vec<u8>,
+    pub mask: Option<String>,
+    pub banks: Vec<HashAlgorithm>,
+    pub iak_cosign: bool,
+    // Digests extended by the runtime inventory, so that a quote is not
+    // served once the agent extended a PCR again
+    pub extensions: usize,
+    // Verifier whose AK signs the quote, see ak_registry.rs
+    pub verifier: Option<String>,
+}
+
+type Entry = (Instant, Arc<OnceCell<KeylimeQuote>>);
+
+#[derive(Debug)]
+pub(crate) struct QuoteCache {
+    ttl: Duration,
+    max_entries: usize,
+    entries: Mutex<HashMap<QuoteKey, Entry>>,
+    // Requests served from the cache, including the ones waiting for a quote
+    // in progress, and requests that ran a quote
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl QuoteCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        QuoteCache {
+            ttl,
+            max_entries: MAX_ENTRIES,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Hits and misses since the start of the agent
+    pub(crate) fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    // Drops the cached quotes and resets the stats, quotes in progress are
+    // still delivered to the requests waiting for them
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear(); //#[allow_ci]
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+    }
+
+    fn cell(
+        &self,
+        key: QuoteKey,
+        now: Instant,
+    ) -> Arc<OnceCell<KeylimeQuote>> {
+        let mut entries = self.entries.lock().unwrap(); //#[allow_ci]
+        entries.retain(|_, (created, _)| {
+            now.saturating_duration_since(*created) < self.ttl
+        });
+
+        if let Some((_, cell)) = entries.get(&key) {
+            return cell.clone();
+        }
+        if entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (created, _))| *created)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                let _ = entries.remove(&oldest);
+            }
+        }
+        let cell = Arc::new(OnceCell::new());
+        let _ = entries.insert(key, (now, cell.clone()));
+        cell
+    }
+
+    /*
+     * Input: key of the quote and function running the quote
+     * Return: Result wrap the cached quote, or the one just run
+     *
+     * Waits for the quote in progress with the same key, if any. When it
+     * failed, the quote is run again.
+     */
+    pub(crate) async fn get_or_quote<F, Fut>(
+        &self,
+        key: QuoteKey,
+        quote: F,
+    ) -> Result<KeylimeQuote>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<KeylimeQuote>>,
+    {
+        let cell = self.cell(key, Instant::now());
+        let mut ran = false;
+        let cached = cell
+            .get_or_try_init(|| {
+                ran = true;
+                quote()
+            })
+            .await?;
+        if ran {
+            let _ = self.misses.fetch_add(1, Ordering::Relaxed);
+        } else {
+            let _ = self.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(cached.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use serde_json::json;
+
+    fn key(nonce: &str) -> QuoteKey {
+        QuoteKey {
+            nonce: nonce.as_bytes().to_vec(),
+            mask: Some("0x400".to_string()),
+            banks: vec![],
+            iak_cosign: false,
+            extensions: 0,
+            verifier: None,
+        }
+    }
+
+    fn keylime_quote(quote: &str) -> KeylimeQuote {
+        serde_json::from_value(json!({
+            "quote": quote,
+            "hash_alg": "sha256",
+            "enc_alg": "rsa",
+            "sign_alg": "rsassa",
+        }))
+        .unwrap() //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn test_get_or_quote() {
+        let cache = QuoteCache::new(Duration::from_secs(5));
+
+        let quote = cache
+            .get_or_quote(key("nonce"), || async { Ok(keylime_quote("r1")) })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote.quote, "r1");
+        let quote = cache
+            .get_or_quote(key("nonce"), || async { Ok(keylime_quote("r2")) })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote.quote, "r1");
+
+        // Another nonce, or the same nonce once the runtime inventory
+        // extended a PCR, is quoted again
+        let quote = cache
+            .get_or_quote(key("other"), || async { Ok(keylime_quote("r3")) })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote.quote, "r3");
+        let extended = QuoteKey {
+            extensions: 1,
+            ..key("nonce")
+        };
+        let quote = cache
+            .get_or_quote(extended, || async { Ok(keylime_quote("r4")) })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote.quote, "r4");
+
+        assert_eq!(cache.stats(), (1, 3));
+    }
+
+    #[actix_rt::test]
+    async fn test_clear() {
+        let cache = QuoteCache::new(Duration::from_secs(5));
+        let _ = cache
+            .get_or_quote(key("nonce"), || async { Ok(keylime_quote("r1")) })
+            .await
+            .unwrap(); //#[allow_ci]
+
+        cache.clear();
+        assert_eq!(cache.stats(), (0, 0));
+        let quote = cache
+            .get_or_quote(key("nonce"), || async { Ok(keylime_quote("r2")) })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote.quote, "r2");
+        assert_eq!(cache.stats(), (0, 1));
+    }
+
+    #[actix_rt::test]
+    async fn test_coalesced() {
+        let cache = QuoteCache::new(Duration::from_secs(5));
+        let (first, second) = futures::join!(
+            cache.get_or_quote(key("nonce"), || async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(keylime_quote("r1"))
+            }),
+            cache.get_or_quote(key("nonce"), || async {
+                Ok(keylime_quote("r2"))
+            }),
+        );
+        assert_eq!(first.unwrap().quote, "r1"); //#[allow_ci]
+        assert_eq!(second.unwrap().quote, "r1"); //#[allow_ci]
+        assert_eq!(cache.stats(), (1, 1));
+    }
+
+    #[actix_rt::test]
+    async fn test_errors_not_cached() {
+        let cache = QuoteCache::new(Duration::from_secs(5));
+        let result = cache
+            .get_or_quote(key("nonce"), || async {
+                Err(Error::TpmTimeout(5))
+            })
+            .await;
+        assert!(result.is_err());
+        let quote = cache
+            .get_or_quote(key("nonce"), || async { Ok(keylime_quote("r1")) })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(quote.quote, "r1");
+    }
+
+    #[test]
+    fn test_expiry() {
+        let cache = QuoteCache {
+            ttl: Duration::from_secs(5),
+            max_entries: 2,
+            entries: Mutex::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        };
+        let start = Instant::now();
+        let cell = cache.cell(key("nonce"), start);
+        assert!(Arc::ptr_eq(&cell, &cache.cell(key("nonce"), start)));
+        let later = start + Duration::from_secs(6);
+        assert!(!Arc::ptr_eq(&cell, &cache.cell(key("nonce"), later)));
+
+        // Bounded, the oldest quote is dropped first
+        let _ = cache.cell(key("a"), later + Duration::from_secs(1));
+        let _ = cache.cell(key("b"), later + Duration::from_secs(2));
+        let entries = cache.entries.lock().unwrap(); //#[allow_ci]
+        assert_eq!(entries.len(), 2);
+        assert!(!entries.contains_key(&key("nonce")));
+    }
+}
@@ -0,0 +1,177 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Short-lived keys signing quotes in place of the AK.
+//
+// With 'quote_key_lifetime' set, quotes are signed by a restricted signing
+// key created in the null hierarchy of the TPM and certified by the AK with
+// TPM2_Certify, rather than by the AK itself. The key is replaced by a new
+// one once it is older than the lifetime, on the next quote. This reduces the
+// use of the AK by verifiers polling often. Quotes signed by a quote key carry
+// its public area and certification, so that verifiers check the quote key
+// was certified by the registered AK, then the quote with the quote key. A
+// quote key does not survive a TPM reset, as the seed of the null hierarchy
+// changes, nor a reconnection to the TPM, it is created again after one.
+
+use crate::{tpm, QuoteData, Result};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tss_esapi::{
+    handles::KeyHandle, structures::PublicBuffer, traits::Marshall, Context,
+};
+
+// The quote key and its certification by the AK, sent with the quotes it
+// signs
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct QuoteKeyCertification {
+    // Base64 encoded TPM2B_PUBLIC of the quote key
+    pub public: String,
+    // Base64 encoded TPMS_ATTEST and TPMT_SIGNATURE of TPM2_Certify by the AK
+    pub attest: String,
+    pub signature: String,
+    // Seconds the key was in use when the quote was signed
+    pub age: u64,
+}
+
+#[derive(Debug)]
+struct QuoteKey {
+    handle: KeyHandle,
+    certification: QuoteKeyCertification,
+    created: Instant,
+    // Reconnections to the TPM before the key was created
+    reconnections: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct QuoteKeys {
+    lifetime: Duration,
+    current: Mutex<Option<QuoteKey>>,
+}
+
+impl QuoteKeys {
+    pub(crate) fn new(lifetime: Duration) -> Self {
+        QuoteKeys {
+            lifetime,
+            current: Mutex::new(None),
+        }
+    }
+
+    /*
+     * Input: Connection context and agent data
+     * Return: The handle of the quote key and its certification
+     *
+     * Replaces the quote key once it is older than the lifetime, flushing
+     * the previous one.
+     */
+    pub(crate) fn signing_key(
+        &self,
+        context: &mut Context,
+        data: &QuoteData,
+    ) -> Result<(KeyHandle, QuoteKeyCertification)> {
+        let mut current = self.current.lock().unwrap(); //#[allow_ci]
+        let reconnections = data.tpm.keys().reconnections();
+        if current
+            .as_ref()
+            .map_or(false, |key| key.reconnections != reconnections)
+        {
+            // Gone with the previous connection, along with its handle
+            info!(
+                "Replacing the quote key lost with the connection to the TPM"
+            );
+            *current = None;
+        }
+        if let Some(key) = current.as_ref() {
+            if key.created.elapsed() < self.lifetime {
+                let mut certification = key.certification.clone();
+                certification.age = key.created.elapsed().as_secs();
+                return Ok((key.handle, certification));
+            }
+        }
+
+        if let Some(previous) = current.take() {
+            if let Err(e) = context.flush_context(previous.handle.into()) {
+                warn!("Unable to flush the previous quote key: {}", e);
+            }
+            data.tpm_handles.release(previous.handle.into());
+        }
+
+        let key = create(context, data)?;
+        info!("Created a new quote key certified by the AK");
+        let result = (key.handle, key.certification.clone());
+        *current = Some(key);
+        Ok(result)
+    }
+}
+
+fn create(context: &mut Context, data: &QuoteData) -> Result<QuoteKey> {
+    let ak_handle = data.tpm.key(data.ak_handle);
+    let (handle, public) =
+        tpm::create_quote_key(context, &data.tpm_handles, ak_handle)?;
+    let certified = tpm::certify(
+        context,
+        handle,
+        ak_handle,
+        data.ak_policy.as_ref(),
+        &[],
+    );
+    let (attest, signature) = match certified {
+        Ok(certified) => certified,
+        Err(e) => {
+            let _ = context.flush_context(handle.into());
+            data.tpm_handles.release(handle.into());
+            return Err(e);
+        }
+    };
+    Ok(QuoteKey {
+        handle,
+        certification: QuoteKeyCertification {
+            public: base64::encode(
+                PublicBuffer::try_from(public)?.marshall()?,
+            ),
+            attest: base64::encode(attest),
+            signature: base64::encode(signature),
+            age: 0,
+        },
+        created: Instant::now(),
+        reconnections: data.tpm.keys().reconnections(),
+    })
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm::testing::vec_to_attest;
+    use tss_esapi::structures::AttestInfo;
+
+    #[test]
+    fn test_signing_key() {
+        let data = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let keys = QuoteKeys::new(Duration::from_secs(60));
+        let mut context = data.tpm.lock();
+        let (handle, certification) =
+            keys.signing_key(&mut context, &data).unwrap(); //#[allow_ci]
+
+        let attest =
+            vec_to_attest(&base64::decode(&certification.attest).unwrap()) //#[allow_ci]
+                .unwrap(); //#[allow_ci]
+        assert!(matches!(attest.attested(), AttestInfo::Certify { .. }));
+
+        // Reused until it expires
+        let (again, _) = keys.signing_key(&mut context, &data).unwrap(); //#[allow_ci]
+        assert_eq!(handle, again);
+
+        let keys = QuoteKeys::new(Duration::from_secs(0));
+        let _ = keys.signing_key(&mut context, &data).unwrap(); //#[allow_ci]
+        let (second, replaced) =
+            keys.signing_key(&mut context, &data).unwrap(); //#[allow_ci]
+        assert_eq!(replaced.age, 0);
+        context.flush_context(second.into()).unwrap(); //#[allow_ci]
+        context.flush_context(handle.into()).unwrap(); //#[allow_ci]
+    }
+}
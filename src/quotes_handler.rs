@@ -1,24 +1,49 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::{tpm, Error as KeylimeError, QuoteData};
+use crate::{
+    alert,
+    algorithms::HashAlgorithm,
+    api,
+    drtm::{self, DrtmLog},
+    nonce_cache, peer_identity,
+    quote_cache::QuoteKey,
+    quote_key::QuoteKeyCertification,
+    runtime_inventory::{self, InventorySnapshot},
+    tee_evidence::TeeEvidence,
+    telemetry, tpm,
+    tpm_audit::{self, PcrReadAudit},
+    Error as KeylimeError, QuoteData,
+};
 
-use crate::common::JsonWrapper;
+use crate::common::{
+    ima_ml_path_get, JsonWrapper, IMA_POLICY, MEASUREDBOOT_ML,
+};
 use crate::crypto;
-use crate::ima::read_measurement_list;
-use crate::serialization::serialize_maybe_base64;
+use crate::ima::{
+    ima_policy_digest, limit_measurement_list, read_measurement_list,
+};
+use crate::serialization::{
+    clock_info_from_quote, serialize_maybe_base64, ClockInfo,
+};
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use log::*;
+use openssl::hash::hash;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{read, read_to_string},
+    collections::BTreeMap,
+    convert::TryFrom,
+    fs::{read, read_to_string, File},
     io::{Read, Seek},
+    path::Path,
 };
 use tss_esapi::structures::PcrSlot;
 
 #[derive(Deserialize)]
 pub struct Ident {
     nonce: String,
+    // "1" to co-sign the quote with the IAK
+    iak_cosign: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -27,9 +52,31 @@ pub struct Integ {
     mask: String,
     partial: String,
     ima_ml_entry: Option<String>,
+    // Comma separated list of additional PCR banks to quote, e.g.
+    // "sha1,sha256"
+    banks: Option<String>,
+    // "1" to co-sign the quote with the IAK
+    iak_cosign: Option<String>,
+    // "1" to also send the PCR reads audited by the TPM, see tpm_audit.rs
+    audit: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Certify {
+    nonce: String,
+    // Key certifying the AK, "iak" (the default)
+    key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct KeylimeCertification {
+    // Base64 encoded TPMS_ATTEST and TPMT_SIGNATURE of TPM2_Certify
+    pub attest: String,
+    pub signature: String,
+    pub key: String,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub(crate) struct KeylimeQuote {
     pub quote: String, // 'r' + quote + sig + pcrblob
     pub hash_alg: String,
@@ -41,8 +88,379 @@ pub(crate) struct KeylimeQuote {
     pub ima_measurement_list: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mb_measurement_list: Option<String>,
+    // DRTM event log, when PCR 17 or 18 is quoted, see drtm.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub drtm_measurement_list: Option<DrtmLog>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ima_measurement_list_entry: Option<u64>,
+    // Set when the IMA measurement list was truncated to the configured
+    // maximum number of entries: the entry to request on the next poll
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ima_measurement_list_next_entry: Option<u64>,
+    // SHA-256 digest of the IMA policy loaded in the kernel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ima_policy_digest: Option<String>,
+    // Number of entries currently in the IMA measurement list
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ima_measurement_count: Option<u64>,
+    // Measurement lists that could not be read, the verifier should retry
+    // later rather than treat their absence as a failed attestation
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub temporarily_unavailable: Vec<String>,
+    // Since API 2.1, boot time in seconds since the epoch, so that verifiers
+    // can tell a reboot from a reset of the measurement lists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boottime: Option<u64>,
+    // Since API 2.1, TPM clock when the quote was signed, see
+    // serialization.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clock_info: Option<ClockInfo>,
+    // Loaded kernel modules and mounted file systems, see
+    // runtime_inventory.rs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime_inventory: Option<InventorySnapshot>,
+    // Hex encoded value of each quoted PCR by bank, when additional banks
+    // were requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pcr_banks: Option<BTreeMap<String, BTreeMap<u32, String>>>,
+    // Quote of the same PCRs signed with the IAK, in the format of quote,
+    // when co-signing was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iak_quote: Option<String>,
+    // Key that signed the quote in place of the AK and its certification by
+    // the AK, see quote_key.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote_key: Option<QuoteKeyCertification>,
+    // Base64 encoded TPM2B_PUBLIC of the AK of the verifier that signed the
+    // quote in place of the registered AK, see ak_registry.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verifier_ak: Option<String>,
+    // Report of the confidential VM bound to the quote, see tee_evidence.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tee_evidence: Option<TeeEvidence>,
+    // PCR reads audited by the TPM, when requested, see tpm_audit.rs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pcr_read_audit: Option<PcrReadAudit>,
+}
+
+static PROC_STAT: &str = "/proc/stat";
+
+fn parse_boottime(stat: &str) -> Option<u64> {
+    stat.lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn boottime() -> Option<u64> {
+    match read_to_string(PROC_STAT) {
+        Ok(stat) => parse_boottime(&stat),
+        Err(e) => {
+            warn!("Unable to read the boot time: {}", e);
+            None
+        }
+    }
+}
+
+// TPM clock of a quote sent to a verifier using a version of the API that
+// reports it
+fn clock_info(req: &HttpRequest, quote: &str) -> Option<ClockInfo> {
+    match api::of_path(req.path()) {
+        Some(version) if version.serializer.quote_clock_info => {
+            match clock_info_from_quote(quote) {
+                Ok(clock_info) => Some(clock_info),
+                Err(e) => {
+                    warn!("Unable to read the clock of the quote: {}", e);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+// Response refusing a nonce the client already used, None when the nonce is
+// fresh or reuse is not checked, see nonce_cache.rs
+fn nonce_reused(
+    req: &HttpRequest,
+    data: &QuoteData,
+    nonce: &str,
+) -> Option<HttpResponse> {
+    let cache = data.nonce_cache.as_ref()?;
+    let client = nonce_cache::client(req);
+    if cache.check(&client, nonce) {
+        return None;
+    }
+    warn!(
+        "{} {} returning 409 response. Nonce already used by {}: {}",
+        req.method(),
+        req.path(),
+        client,
+        nonce
+    );
+    Some(HttpResponse::Conflict().json(JsonWrapper::error(
+        409,
+        format!("Nonce was already used: {}", nonce),
+    )))
+}
+
+/*
+ * Input: request, agent state and nonce
+ * Return: the qualifying data of the quote, or the error response when the
+ *         nonce is too long for the composition set for the API version of
+ *         the request
+ */
+fn qualifying_data(
+    req: &HttpRequest,
+    data: &QuoteData,
+    nonce: &str,
+) -> Result<Vec<u8>, HttpResponse> {
+    let version = api::of_path(req.path()).map(|api| api.version);
+    let composition =
+        api::qualifying_data(version, &data.quote_qualifying_data);
+    match version {
+        Some(version) => info!(
+            "Quote qualifying data for API {}: {}",
+            version, composition
+        ),
+        None => info!("Quote qualifying data: {}", composition),
+    }
+    let mut qualifying_data = nonce.as_bytes().to_vec();
+    if composition == api::QualifyingData::NoncePubkeyHash {
+        let digest = crypto::pkey_pub_to_pem(&data.pub_key).and_then(|pem| {
+            Ok(hash(data.hash_alg.into(), pem.as_bytes())?.to_vec())
+        });
+        let digest = match digest {
+            Ok(digest) => digest,
+            Err(e) => return Err(quote_error(&e)),
+        };
+        if nonce.len() + digest.len() > tpm::MAX_NONCE_SIZE {
+            warn!(
+                "Get quote returning 400 response. Nonce too long for the {} qualifying data: {}",
+                composition,
+                nonce.len()
+            );
+            return Err(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!(
+                    "nonce is too long for the {} qualifying data, at most {} characters",
+                    composition,
+                    tpm::MAX_NONCE_SIZE - digest.len()
+                ),
+            )));
+        }
+        qualifying_data.extend_from_slice(&digest);
+    }
+    Ok(qualifying_data)
+}
+
+// Identity of the verifier whose AK signs the quote, when ak_per_verifier is
+// set, see ak_registry.rs
+fn verifier(req: &HttpRequest, data: &QuoteData) -> Option<String> {
+    data.ak_registry.as_ref()?;
+    peer_identity::of_request(req)
+}
+
+// Quote through the quote cache when quote_cache_ttl is set, see
+// quote_cache.rs
+async fn cached_quote(
+    key: QuoteKey,
+    data: &web::Data<QuoteData>,
+    span: &telemetry::Span,
+) -> Result<KeylimeQuote, KeylimeError> {
+    let quote = || {
+        tpm::timed_quote(
+            &key.nonce,
+            key.mask.as_deref(),
+            &key.banks,
+            key.iak_cosign,
+            key.verifier.as_deref(),
+            data.clone(),
+            span,
+        )
+    };
+    match &data.quote_cache {
+        Some(cache) => cache.get_or_quote(key.clone(), quote).await,
+        None => quote().await,
+    }
+}
+
+// Attaches the report of the confidential VM to the quote, when tee_evidence
+// is set. A report that cannot be fetched is listed as temporarily
+// unavailable.
+fn attach_tee_evidence(data: &QuoteData, quote: &mut KeylimeQuote) {
+    let guest = match &data.tee_guest {
+        Some(guest) => guest,
+        None => return,
+    };
+    let _span = telemetry::span("tee.report");
+    match guest.evidence(&quote.quote) {
+        Ok(evidence) => quote.tee_evidence = Some(evidence),
+        Err(e) => {
+            warn!("Unable to fetch the TEE attestation report: {}", e);
+            quote
+                .temporarily_unavailable
+                .push("tee_evidence".to_string());
+        }
+    }
+}
+
+// The remediation hint of known TPM failures is returned to the caller, so
+// it does not need access to the agent log to find out what went wrong
+fn quote_error(e: &KeylimeError) -> HttpResponse {
+    if matches!(
+        e,
+        KeylimeError::Tpm { .. }
+            | KeylimeError::TpmLockout(_)
+            | KeylimeError::TpmAuthFail(_)
+            | KeylimeError::TpmObjectMemory(_)
+            | KeylimeError::TpmRetry(_)
+            | KeylimeError::TpmTimeout(_)
+    ) {
+        alert::raise(
+            alert::AlertKind::TpmUnusable,
+            &format!("Unable to quote with the TPM: {}", e),
+        );
+    }
+    let message = match e.remediation() {
+        Some(hint) => {
+            warn!("Unable to retrieve quote: {}. {}", e, hint);
+            format!("Unable to retrieve quote: {}. {}", e, hint)
+        }
+        None => {
+            debug!("Unable to retrieve quote: {:?}", e);
+            "Unable to retrieve quote".to_string()
+        }
+    };
+    match e {
+        KeylimeError::TpmTimeout(_) => HttpResponse::GatewayTimeout()
+            .json(JsonWrapper::error(504, message)),
+        _ => HttpResponse::InternalServerError()
+            .json(JsonWrapper::error(500, message)),
+    }
+}
+
+// Bad request response when PCRs of the mask are not allocated in the bank
+fn check_allocated(
+    mask: &str,
+    allocated: u32,
+    bank: HashAlgorithm,
+) -> Option<HttpResponse> {
+    match tpm::unallocated_pcrs(mask, allocated) {
+        Ok(missing) if missing.is_empty() => None,
+        Ok(missing) => {
+            let missing = missing
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(", ");
+            warn!(
+                "Get quote returning 400 response. PCRs {} are not allocated in the {} bank",
+                missing, bank
+            );
+            Some(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("PCRs {} do not exist in the {} bank", missing, bank),
+            )))
+        }
+        Err(e) => {
+            warn!(
+                "Get quote returning 400 response. Invalid mask {}: {:?}",
+                mask, e
+            );
+            Some(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("mask is not a valid PCR mask: {}", mask),
+            )))
+        }
+    }
+}
+
+// Parses the banks parameter, None if one of them is not supported
+fn parse_banks(banks: &str) -> Option<Vec<HashAlgorithm>> {
+    let mut parsed = Vec::new();
+    for bank in banks.split(',') {
+        let bank = HashAlgorithm::try_from(bank).ok()?;
+        if !HashAlgorithm::supported().contains(&bank) {
+            return None;
+        }
+        if !parsed.contains(&bank) {
+            parsed.push(bank);
+        }
+    }
+    Some(parsed)
+}
+
+// Whether the quote is co-signed with the IAK, or the bad request response
+// when the parameter is invalid or the agent has no IAK
+fn parse_iak_cosign(
+    iak_cosign: Option<&str>,
+    data: &QuoteData,
+) -> Result<bool, HttpResponse> {
+    match iak_cosign {
+        None | Some("0") => Ok(false),
+        Some("1") if data.iak_handle.is_some() => Ok(true),
+        Some("1") => {
+            warn!("Get quote returning 400 response. IAK co-signing requested but the IAK is not enabled");
+            Err(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "iak_cosign requires enable_iak_idevid on the agent"
+                    .to_string(),
+            )))
+        }
+        Some(other) => {
+            warn!("Get quote returning 400 response. Invalid iak_cosign value: {}", other);
+            Err(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "iak_cosign must be '0' or '1'".to_string(),
+            )))
+        }
+    }
+}
+
+fn parse_audit(
+    audit: Option<&str>,
+    data: &QuoteData,
+) -> Result<bool, HttpResponse> {
+    match audit {
+        None | Some("0") => Ok(false),
+        Some("1") if data.ak_policy.is_none() => Ok(true),
+        Some("1") => {
+            warn!("Get quote returning 400 response. Audit requested but the AK is policy-bound");
+            Err(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "audit is not available with ak_policy_pcrs or ak_policy_secret"
+                    .to_string(),
+            )))
+        }
+        Some(other) => {
+            warn!(
+                "Get quote returning 400 response. Invalid audit value: {}",
+                other
+            );
+            Err(HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "audit must be '0' or '1'".to_string(),
+            )))
+        }
+    }
+}
+
+fn read_measuredboot_ml(file: &mut File) -> std::io::Result<Vec<u8>> {
+    let mut ml = Vec::<u8>::new();
+    file.rewind()?;
+    let _ = file.read_to_end(&mut ml)?;
+    Ok(ml)
+}
+
+// Replaces a measurement list file that failed to be read, e.g. because
+// securityfs was remounted, so the next read does not use the stale handle
+fn reopen(file: &mut File, path: &Path) {
+    match File::open(path) {
+        Ok(new_file) => *file = new_file,
+        Err(e) => debug!("Unable to reopen {}: {}", path.display(), e),
+    }
 }
 
 // This is a Quote request from the tenant, which does not check
@@ -80,21 +498,41 @@ pub async fn identity(
         ));
     }
 
+    let iak_cosign =
+        match parse_iak_cosign(param.iak_cosign.as_deref(), &data) {
+            Ok(iak_cosign) => iak_cosign,
+            Err(response) => return response,
+        };
+
+    if let Some(response) = nonce_reused(&req, &data, &param.nonce) {
+        return response;
+    }
+
+    let nonce = match qualifying_data(&req, &data, &param.nonce) {
+        Ok(nonce) => nonce,
+        Err(response) => return response,
+    };
+
     debug!("Calling Identity Quote with nonce: {}", param.nonce);
 
-    let mut quote =
-        match tpm::quote(param.nonce.as_bytes(), None, data.clone()) {
-            Ok(quote) => quote,
-            Err(e) => {
-                debug!("Unable to retrieve quote: {:?}", e);
-                return HttpResponse::InternalServerError().json(
-                    JsonWrapper::error(
-                        500,
-                        "Unable to retrieve quote".to_string(),
-                    ),
-                );
-            }
-        };
+    let span = telemetry::span("quote.identity");
+    let key = QuoteKey {
+        nonce,
+        mask: None,
+        banks: vec![],
+        iak_cosign,
+        extensions: 0,
+        verifier: verifier(&req, &data),
+    };
+    let result = cached_quote(key, &data, &span).await;
+
+    // Nothing is awaited from here, the span can stay current
+    let _guard = span.attach();
+
+    let mut quote = match result {
+        Ok(quote) => quote,
+        Err(e) => return quote_error(&e),
+    };
 
     match crypto::pkey_pub_to_pem(&data.pub_key) {
         Ok(pubkey) => quote.pubkey = Some(pubkey),
@@ -109,11 +547,165 @@ pub async fn identity(
         }
     }
 
+    quote.clock_info = clock_info(&req, &quote.quote);
+    attach_tee_evidence(&data, &mut quote);
+
+    data.status.quote_served();
     let response = JsonWrapper::success(quote);
     info!("GET identity quote returning 200 response");
     HttpResponse::Ok().json(response)
 }
 
+/*
+ * Input: agent state and nonce
+ * Return: Result wrap the identity quote
+ *
+ * Identity quote for the local clients of the admin socket, see ffi.rs.
+ */
+pub(crate) async fn local_identity(
+    data: &web::Data<QuoteData>,
+    nonce: &str,
+) -> Result<KeylimeQuote, KeylimeError> {
+    if nonce.is_empty()
+        || nonce.len() > tpm::MAX_NONCE_SIZE
+        || !nonce.chars().all(char::is_alphanumeric)
+    {
+        return Err(KeylimeError::Other(format!(
+            "the nonce must be 1 to {} alphanumerical characters",
+            tpm::MAX_NONCE_SIZE
+        )));
+    }
+    if data.status.maintenance().is_some() {
+        return Err(KeylimeError::Other(
+            "the agent is in maintenance, quotes are refused".to_string(),
+        ));
+    }
+
+    let span = telemetry::span("quote.identity");
+    let key = QuoteKey {
+        nonce: nonce.as_bytes().to_vec(),
+        mask: None,
+        banks: vec![],
+        iak_cosign: false,
+        extensions: 0,
+        verifier: None,
+    };
+    let mut quote = cached_quote(key, data, &span).await?;
+    quote.pubkey = Some(crypto::pkey_pub_to_pem(&data.pub_key)?);
+    attach_tee_evidence(data, &mut quote);
+    data.status.quote_served();
+    Ok(quote)
+}
+
+// This is a request to certify the AK, so that relying parties can check it
+// belongs to the same TPM as a key certified by the manufacturer without the
+// credential activation of the registrar. It returns TPM2_Certify(AK, key,
+// nonce). The EK cannot certify the AK as it is a decryption key, the IAK is
+// used instead.
+pub async fn certify(
+    req: HttpRequest,
+    param: web::Query<Certify>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    // nonce can only be in alphanumerical format
+    if !param.nonce.chars().all(char::is_alphanumeric) {
+        warn!("Get certification returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
+        return HttpResponse::BadRequest().json(JsonWrapper::error(
+            400,
+            format!("nonce should be strictly alphanumeric: {}", param.nonce),
+        ));
+    }
+
+    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
+        warn!("Get certification returning 400 response. Nonce is too long (max size {}): {}",
+              tpm::MAX_NONCE_SIZE,
+              param.nonce.len()
+        );
+        return HttpResponse::BadRequest().json(JsonWrapper::error(
+            400,
+            format!(
+                "Nonce is too long (max size: {}): {}",
+                tpm::MAX_NONCE_SIZE,
+                param.nonce.len()
+            ),
+        ));
+    }
+
+    let sign_handle = match (param.key.as_deref(), data.iak_handle) {
+        (None, Some(iak_handle)) | (Some("iak"), Some(iak_handle)) => {
+            iak_handle
+        }
+        (None, None) | (Some("iak"), None) => {
+            warn!("Get certification returning 400 response. The IAK is not enabled");
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "certifying the AK requires enable_iak_idevid on the agent"
+                    .to_string(),
+            ));
+        }
+        (Some("ek"), _) => {
+            warn!("Get certification returning 400 response. The EK cannot sign");
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                "the EK is a decryption key and cannot certify the AK, use the IAK"
+                    .to_string(),
+            ));
+        }
+        (Some(other), _) => {
+            warn!(
+                "Get certification returning 400 response. Unknown key: {}",
+                other
+            );
+            return HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("key must be 'iak', got {}", other),
+            ));
+        }
+    };
+
+    if let Some(response) = nonce_reused(&req, &data, &param.nonce) {
+        return response;
+    }
+
+    debug!("Calling Certify with nonce: {}", param.nonce);
+
+    let ak_handle = data.ak_handle;
+    let nonce = param.nonce.as_bytes().to_vec();
+    let keys = data.tpm.keys();
+    let result = data
+        .tpm
+        .run_idempotent(move |context| {
+            tpm::certify(
+                context,
+                keys.get(ak_handle),
+                keys.get(sign_handle),
+                None,
+                &nonce,
+            )
+        })
+        .await;
+
+    match result {
+        Ok((attest, signature)) => {
+            info!("GET certification returning 200 response");
+            HttpResponse::Ok().json(JsonWrapper::success(
+                KeylimeCertification {
+                    attest: base64::encode(attest),
+                    signature: base64::encode(signature),
+                    key: "iak".to_string(),
+                },
+            ))
+        }
+        Err(e) => {
+            warn!("Unable to certify the AK: {}", e);
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to certify the AK".to_string(),
+            ))
+        }
+    }
+}
+
 // This is a Quote request from the cloud verifier, which will check
 // integrity measurement. The PCRs included in the Quote will be specified
 // by the mask. It should return this data:
@@ -156,6 +748,59 @@ pub async fn integrity(
         ));
     }
 
+    // The quote covers exactly the PCRs of the mask, which must exist in
+    // the bank of the quote
+    if let Some(response) =
+        check_allocated(&param.mask, data.allocated_pcrs, data.hash_alg)
+    {
+        return response;
+    }
+
+    // Additional banks quoted along the bank of the agent, as the Python
+    // agent does for verifiers accepting several hash algorithms
+    let banks = match &param.banks {
+        Some(banks) => {
+            match parse_banks(banks) {
+                Some(banks) => banks,
+                None => {
+                    warn!("Get quote returning 400 response. Invalid PCR banks: {}", banks);
+                    return HttpResponse::BadRequest().json(JsonWrapper::error(
+                    400,
+                    format!(
+                        "banks should be a comma separated list of supported hash algorithms: {}",
+                        banks
+                    ),
+                ));
+                }
+            }
+        }
+        None => Vec::new(),
+    };
+    for &bank in banks.iter().filter(|&&bank| bank != data.hash_alg) {
+        let allocated = match data
+            .tpm
+            .run_idempotent(move |context| tpm::allocated_pcrs(context, bank))
+            .await
+        {
+            Ok(allocated) => allocated,
+            Err(e) => return quote_error(&e),
+        };
+        if let Some(response) = check_allocated(&param.mask, allocated, bank)
+        {
+            return response;
+        }
+    }
+
+    let iak_cosign =
+        match parse_iak_cosign(param.iak_cosign.as_deref(), &data) {
+            Ok(iak_cosign) => iak_cosign,
+            Err(response) => return response,
+        };
+    let audit = match parse_audit(param.audit.as_deref(), &data) {
+        Ok(audit) => audit,
+        Err(response) => return response,
+    };
+
     // If partial="0", include the public key in the quote
     let pubkey = match &param.partial[..] {
         "0" => {
@@ -184,11 +829,23 @@ pub async fn integrity(
         }
     };
 
+    if let Some(response) = nonce_reused(&req, &data, &param.nonce) {
+        return response;
+    }
+
+    let nonce = match qualifying_data(&req, &data, &param.nonce) {
+        Ok(nonce) => nonce,
+        Err(response) => return response,
+    };
+
     debug!(
         "Calling Integrity Quote with nonce: {}, mask: {}",
         param.nonce, param.mask
     );
 
+    let span = telemetry::span("quote.integrity");
+    span.set_attribute("mask", param.mask.clone());
+
     // If an index was provided, the request is for the entries starting from the given index
     // (iterative attestation). Otherwise the request is for the whole list.
     let nth_entry = match &param.ima_ml_entry {
@@ -196,47 +853,122 @@ pub async fn integrity(
         Some(idx) => idx.parse::<u64>().unwrap_or(0),
     };
 
+    // The IMA PCR is always quoted when the IMA measurement list is sent, so
+    // that the verifier can check the list against it
+    let mask = if data.ima_ml_file.is_some() {
+        match tpm::add_pcr_to_mask(&param.mask, data.ima_pcr) {
+            Ok(mask) => mask,
+            Err(e) => {
+                warn!(
+                    "Get quote returning 400 response. Invalid mask {}: {:?}",
+                    param.mask, e
+                );
+                return HttpResponse::BadRequest().json(JsonWrapper::error(
+                    400,
+                    format!("mask is not a valid PCR mask: {}", param.mask),
+                ));
+            }
+        }
+    } else {
+        param.mask.clone()
+    };
+
+    // The runtime inventory is measured before quoting, so that the quote
+    // covers it
+    let mask = match &data.runtime_inventory {
+        Some(inventory) => {
+            let pcr = inventory.lock().unwrap().pcr(); //#[allow_ci]
+            match tpm::add_pcr_to_mask(&mask, pcr) {
+                Ok(mask) => mask,
+                Err(e) => {
+                    warn!(
+                        "Get quote returning 400 response. Invalid mask {}: {:?}",
+                        param.mask, e
+                    );
+                    return HttpResponse::BadRequest().json(
+                        JsonWrapper::error(
+                            400,
+                            format!(
+                                "mask is not a valid PCR mask: {}",
+                                param.mask
+                            ),
+                        ),
+                    );
+                }
+            }
+        }
+        None => mask,
+    };
+    let runtime_inventory =
+        match runtime_inventory::measure(data.clone()).await {
+            Ok(snapshot) => snapshot,
+            Err(e) => return quote_error(&e),
+        };
+
+    // The audited reads are made apart from the quote, which may come from
+    // the cache
+    let pcr_read_audit = if audit {
+        match tpm_audit::audit_pcr_reads(
+            data.clone(),
+            nonce.clone(),
+            mask.clone(),
+        )
+        .await
+        {
+            Ok(pcr_read_audit) => Some(pcr_read_audit),
+            Err(e) => return quote_error(&e),
+        }
+    } else {
+        None
+    };
+
     // Generate the ID quote.
-    let id_quote = match tpm::quote(
-        param.nonce.as_bytes(),
-        Some(&param.mask),
-        data.clone(),
-    ) {
+    let key = QuoteKey {
+        nonce,
+        mask: Some(mask),
+        banks,
+        iak_cosign,
+        extensions: runtime_inventory
+            .as_ref()
+            .map_or(0, |snapshot| snapshot.digests.len()),
+        verifier: verifier(&req, &data),
+    };
+    let result = cached_quote(key, &data, &span).await;
+
+    // Nothing is awaited from here, the span can stay current
+    let _guard = span.attach();
+
+    let id_quote = match result {
         Ok(id_quote) => id_quote,
-        Err(e) => {
-            debug!("Unable to retrieve quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
-            );
-        }
+        Err(e) => return quote_error(&e),
     };
 
+    // Measurement lists not sent because reading them currently fails
+    let mut temporarily_unavailable = Vec::new();
+
     // If PCR 0 is included in the mask, obtain the measured boot
     let mut mb_measurement_list = None;
     match tpm::check_mask(&param.mask, &PcrSlot::Slot0) {
         Ok(true) => {
             if let Some(measuredboot_ml_file) = &data.measuredboot_ml_file {
-                let mut ml = Vec::<u8>::new();
+                let _span = telemetry::span("measuredboot.read_event_log");
                 let mut f = measuredboot_ml_file.lock().unwrap(); //#[allow_ci]
-                if let Err(e) = f.rewind() {
-                    debug!("Failed to rewind measured boot file: {}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve quote".to_string(),
-                        ),
-                    );
-                }
-                mb_measurement_list = match f.read_to_end(&mut ml) {
-                    Ok(_) => Some(base64::encode(ml)),
-                    Err(e) => {
+                match data
+                    .measuredboot_ml_breaker
+                    .call(|| read_measuredboot_ml(&mut f))
+                {
+                    Some(Ok(ml)) => {
+                        mb_measurement_list = Some(base64::encode(ml))
+                    }
+                    Some(Err(e)) => {
                         warn!("Could not read TPM2 event log: {}", e);
-                        None
+                        reopen(&mut f, Path::new(MEASUREDBOOT_ML));
+                        temporarily_unavailable
+                            .push("mb_measurement_list".to_string());
                     }
-                };
+                    None => temporarily_unavailable
+                        .push("mb_measurement_list".to_string()),
+                }
             }
         }
         Err(e) => {
@@ -251,38 +983,106 @@ pub async fn integrity(
         _ => (),
     }
 
+    // If PCR 17 or 18 is included in the mask, send the DRTM event log
+    let drtm_measurement_list = match drtm::requested(&param.mask) {
+        Ok(true) => data.drtm_log.clone(),
+        Ok(false) => None,
+        Err(e) => {
+            debug!("Unable to check PCR mask: {:?}", e);
+            return HttpResponse::InternalServerError().json(
+                JsonWrapper::error(
+                    500,
+                    "Unable to retrieve quote".to_string(),
+                ),
+            );
+        }
+    };
+
     // Generate the measurement list
-    let (ima_measurement_list, ima_measurement_list_entry, num_entries) =
-        if let Some(ima_file) = &data.ima_ml_file {
-            match read_measurement_list(
-                &mut data.ima_ml.lock().unwrap(), //#[allow_ci]
-                &mut ima_file.lock().unwrap(),    //#[allow_ci]
-                nth_entry,
-            ) {
-                Ok(result) => result,
-                Err(e) => {
-                    debug!("Unable to read measurement list: {:?}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve quote".to_string(),
-                        ),
-                    );
-                }
+    let (
+        mut ima_measurement_list,
+        ima_measurement_list_entry,
+        ima_measurement_count,
+    ) = if let Some(ima_file) = &data.ima_ml_file {
+        let _span = telemetry::span("ima.read_measurement_list");
+        let mut ima_ml = data.ima_ml.lock().unwrap(); //#[allow_ci]
+        let mut f = ima_file.lock().unwrap(); //#[allow_ci]
+        match data
+            .ima_ml_breaker
+            .call(|| read_measurement_list(&mut ima_ml, &mut f, nth_entry))
+        {
+            Some(Ok(result)) => result,
+            Some(Err(e)) => {
+                warn!("Unable to read measurement list: {:?}", e);
+                // The offsets cached for the old file may not apply anymore
+                ima_ml.reset();
+                reopen(&mut f, &ima_ml_path_get());
+                temporarily_unavailable
+                    .push("ima_measurement_list".to_string());
+                (None, None, None)
             }
-        } else {
-            (None, None, None)
-        };
+            None => {
+                temporarily_unavailable
+                    .push("ima_measurement_list".to_string());
+                (None, None, None)
+            }
+        }
+    } else {
+        (None, None, None)
+    };
+
+    // Cap the number of entries returned, so that a large delta since the
+    // last attestation does not result in a huge response. The verifier
+    // continues from the returned marker on its next poll.
+    let mut ima_measurement_list_next_entry = None;
+    if let (Some(ml), Some(entry)) =
+        (&mut ima_measurement_list, ima_measurement_list_entry)
+    {
+        if let Some(included) =
+            limit_measurement_list(ml, data.ima_ml_max_entries)
+        {
+            debug!(
+                "IMA measurement list truncated to {} entries starting at entry {}",
+                included, entry
+            );
+            ima_measurement_list_next_entry = Some(entry + included);
+        }
+    }
+
+    // Report the loaded IMA policy so verifiers can detect policy rollback
+    let ima_policy_digest = match &data.ima_ml_file {
+        Some(_) => ima_policy_digest(Path::new(IMA_POLICY)),
+        None => None,
+    };
+
+    // Fields added in later API versions are only sent to the verifiers
+    // requesting them
+    let boottime = match api::of_path(req.path()) {
+        Some(version) if version.serializer.quote_boottime => boottime(),
+        _ => None,
+    };
+    let clock_info = clock_info(&req, &id_quote.quote);
 
     // Generate the final quote based on the ID quote
-    let quote = KeylimeQuote {
+    let mut quote = KeylimeQuote {
         pubkey,
         ima_measurement_list,
         mb_measurement_list,
+        drtm_measurement_list,
         ima_measurement_list_entry,
+        ima_measurement_list_next_entry,
+        ima_policy_digest,
+        ima_measurement_count,
+        temporarily_unavailable,
+        boottime,
+        clock_info,
+        runtime_inventory,
+        pcr_read_audit,
         ..id_quote
     };
+    attach_tee_evidence(&data, &mut quote);
 
+    data.status.quote_served();
     let response = JsonWrapper::success(quote);
     info!("GET integrity quote returning 200 response");
     HttpResponse::Ok().json(response)
@@ -327,7 +1127,7 @@ mod tests {
         );
         assert!(result.results.quote.starts_with('r'));
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let mut context = quotedata.tpm.lock();
         tpm::testing::check_quote(
             &mut context,
             quotedata.ak_handle,
@@ -337,6 +1137,233 @@ mod tests {
         .expect("unable to verify quote");
     }
 
+    #[actix_rt::test]
+    async fn test_integrity_unallocated_pcrs() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+
+        // As if the bank only had PCRs 0-15
+        fixture.allocated_pcrs = 0xffff;
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.status, "PCRs 22 do not exist in the sha256 bank");
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_nonce_reused() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        fixture.nonce_cache = Some(nonce_cache::NonceCache::new(
+            std::time::Duration::from_secs(60),
+        ));
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/identity", API_VERSION),
+                web::get().to(identity),
+            ))
+            .await;
+        let uri = format!(
+            "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            API_VERSION
+        );
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 409);
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_banks() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=1&banks=sha1,sha256",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        assert!(result.results.quote.starts_with('r'));
+        let pcr_banks = result.results.pcr_banks.unwrap(); //#[allow_ci]
+        assert_eq!(
+            pcr_banks.keys().collect::<Vec<_>>(),
+            vec!["sha1", "sha256"]
+        );
+        // PCR 16 holds the NK digest, only extended in the agent bank
+        assert_eq!(
+            pcr_banks["sha1"].keys().collect::<Vec<_>>(),
+            vec![&15, &22]
+        );
+        assert_eq!(
+            pcr_banks["sha256"].keys().collect::<Vec<_>>(),
+            vec![&15, &16, &22]
+        );
+        assert_eq!(pcr_banks["sha1"][&15].len(), 40);
+        assert_eq!(pcr_banks["sha256"][&15].len(), 64);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=1&banks=sha1,md5",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_iak_cosign() {
+        let uri = format!(
+            "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=1&iak_cosign=1",
+            API_VERSION,
+        );
+
+        // Refused while the agent has no IAK
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let (iak_handle, _) = {
+            let mut context = fixture.tpm.lock();
+            tpm::load_device_key(
+                &mut context,
+                &fixture.tpm_handles,
+                tpm::DeviceKey::Iak,
+                crate::algorithms::EncryptionAlgorithm::Rsa,
+                None,
+            )
+            .unwrap() //#[allow_ci]
+        };
+        fixture.iak_handle = Some(iak_handle);
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let iak_quote = result.results.iak_quote.unwrap(); //#[allow_ci]
+        let mut context = quotedata.tpm.lock();
+        tpm::testing::check_quote(
+            &mut context,
+            iak_handle,
+            &iak_quote,
+            b"1234567890ABCDEFHIJ",
+        )
+        .expect("unable to verify IAK quote");
+    }
+
+    #[actix_rt::test]
+    async fn test_certify() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let (iak_handle, _) = {
+            let mut context = fixture.tpm.lock();
+            tpm::load_device_key(
+                &mut context,
+                &fixture.tpm_handles,
+                tpm::DeviceKey::Iak,
+                crate::algorithms::EncryptionAlgorithm::Rsa,
+                None,
+            )
+            .unwrap() //#[allow_ci]
+        };
+        fixture.iak_handle = Some(iak_handle);
+        let quotedata = web::Data::new(fixture);
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{}/quotes/certify", API_VERSION),
+                web::get().to(certify),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/certify?nonce=1234567890ABCDEFHIJ",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeCertification> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results.key, "iak");
+        let attest = base64::decode(&result.results.attest).unwrap(); //#[allow_ci]
+        let attest = tpm::testing::vec_to_attest(&attest).unwrap(); //#[allow_ci]
+        assert_eq!(attest.extra_data().value(), b"1234567890ABCDEFHIJ");
+        assert!(matches!(
+            attest.attested(),
+            tss_esapi::structures::AttestInfo::Certify { .. }
+        ));
+
+        // The EK is a decryption key
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/certify?nonce=1234567890ABCDEFHIJ&key=ek",
+                API_VERSION,
+            ))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+    }
+
+    #[test]
+    fn test_parse_banks() {
+        assert_eq!(
+            parse_banks("sha1,sha256,sha1"),
+            Some(vec![HashAlgorithm::Sha1, HashAlgorithm::Sha256])
+        );
+        assert_eq!(parse_banks("sha384"), Some(vec![HashAlgorithm::Sha384]));
+        assert_eq!(parse_banks("sha1,"), None);
+        assert_eq!(parse_banks("md5"), None);
+    }
+
     #[actix_rt::test]
     async fn test_integrity_pre() {
         let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
@@ -380,7 +1407,7 @@ mod tests {
                     );
                     assert!(result.results.quote.starts_with('r'));
 
-                    let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+                    let mut context = quotedata.tpm.lock();
                     tpm::testing::check_quote(
                         &mut context,
                         quotedata.ak_handle,
@@ -432,6 +1459,10 @@ mod tests {
                         result.results.ima_measurement_list.unwrap().as_str(), //#[allow_ci]
                         ima_ml
                     );
+                    assert_eq!(
+                        result.results.ima_measurement_count,
+                        Some(ima_ml.lines().count() as u64)
+                    );
                     assert!(result.results.quote.starts_with('r'));
                 }
                 Err(e) => panic!("Could not read IMA file: {}", e), //#[allow_ci]
@@ -440,7 +1471,7 @@ mod tests {
             panic!("IMA file was None"); //#[allow_ci]
         }
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let mut context = quotedata.tpm.lock();
         tpm::testing::check_quote(
             &mut context,
             quotedata.ak_handle,
@@ -478,4 +1509,97 @@ mod tests {
         assert!(result.results.ima_measurement_list.is_none());
         assert!(result.results.ima_measurement_list_entry.is_none());
     }
+
+    #[actix_rt::test]
+    async fn test_ima_ml_max_entries() {
+        let mut quotedata = QuoteData::fixture().unwrap(); //#[allow_ci]
+        quotedata.ima_ml_max_entries = 2;
+        let data = web::Data::new(quotedata);
+        let mut app =
+            test::init_service(App::new().app_data(data.clone()).route(
+                &format!("/{}/quotes/integrity", API_VERSION),
+                web::get().to(integrity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=0&ima_ml_entry=1",
+                API_VERSION,
+            ))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let result: JsonWrapper<KeylimeQuote> =
+            test::read_body_json(resp).await;
+        let ima_ml = result.results.ima_measurement_list.unwrap(); //#[allow_ci]
+        assert_eq!(ima_ml.lines().count(), 2);
+        assert_eq!(result.results.ima_measurement_list_entry, Some(1));
+        assert_eq!(result.results.ima_measurement_list_next_entry, Some(3));
+    }
+
+    #[test]
+    fn test_parse_boottime() {
+        let stat = "cpu  10 0 20 300\nintr 42\nbtime 1660000000\n";
+        assert_eq!(parse_boottime(stat), Some(1660000000));
+        assert_eq!(parse_boottime("cpu  10 0 20 300\n"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_integrity_boottime() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                "/{version}/quotes/integrity",
+                web::get().to(integrity),
+            ))
+            .await;
+
+        // Only sent to verifiers using API 2.1 or later
+        for (version, expected) in [("v2.0", false), ("v2.1", true)] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/integrity?nonce=1234567890ABCDEFHIJ&mask=0x408000&vmask=0x808000&partial=1",
+                    version,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.results.boottime.is_some(), expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_identity_clock_info() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route("/{version}/quotes/identity", web::get().to(identity)),
+        )
+        .await;
+
+        // Only sent to verifiers using API 2.1 or later
+        for (version, expected) in [("v2.0", false), ("v2.1", true)] {
+            let req = test::TestRequest::get()
+                .uri(&format!(
+                    "/{}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+                    version,
+                ))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_success());
+
+            let result: JsonWrapper<KeylimeQuote> =
+                test::read_body_json(resp).await;
+            assert_eq!(result.results.clock_info.is_some(), expected);
+        }
+    }
 }
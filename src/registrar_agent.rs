@@ -1,25 +1,45 @@
 use crate::error::Error;
 
-use crate::common::API_VERSION;
+use crate::algorithms::{
+    EncryptionAlgorithm, HashAlgorithm, HmacAlgorithm, SignAlgorithm,
+};
+use crate::device_identity::DeviceIdentity;
+use crate::ek_cert::EkCertChain;
+use crate::membership::Membership;
+use crate::metadata::HostMetadata;
 use crate::serialization::*;
+use crate::{api, common::API_VERSION};
 use log::*;
 use openssl::x509::X509;
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
+use std::convert::TryFrom;
 
 fn is_empty(buf: &[u8]) -> bool {
     buf.is_empty()
 }
 
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Register<'a> {
     #[serde(serialize_with = "serialize_maybe_base64")]
     ekcert: Option<Vec<u8>>,
+    // Base64 encoded intermediate CA certificates of the EK certificate, and
+    // whether the agent verified the chain, see ek_cert.rs
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    ek_cert_chain: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ek_cert_chain_valid: Option<bool>,
     #[serde(
         serialize_with = "serialize_as_base64",
         skip_serializing_if = "is_empty"
     )]
     ek_tpm: &'a [u8],
+    // Algorithm of the EK, "rsa" or "ecc" for NIST P-256 EKs
+    ek_alg: String,
     #[serde(serialize_with = "serialize_as_base64")]
     aik_tpm: &'a [u8],
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -28,17 +48,140 @@ struct Register<'a> {
     ip: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     port: Option<u32>,
+    // Only sent when the agent runs on a software TPM emulator, so that
+    // verifiers can refuse agents without a hardware root of trust
+    #[serde(skip_serializing_if = "is_false")]
+    tpm_emulator: bool,
+    supported_algorithms: SupportedAlgorithms,
+    // Hostname and cloud instance details, see metadata.rs
+    #[serde(default, skip_serializing_if = "HostMetadata::is_empty")]
+    metadata: HostMetadata,
+    // Group and tags of the agent, see membership.rs
+    #[serde(flatten)]
+    membership: Membership,
+    // IAK and IDevID of the TCG device identity, see device_identity.rs
+    #[serde(
+        serialize_with = "serialize_maybe_base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    iak_tpm: Option<Vec<u8>>,
+    #[serde(
+        serialize_with = "serialize_maybe_base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    iak_cert: Option<Vec<u8>>,
+    #[serde(
+        serialize_with = "serialize_maybe_base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    idevid_tpm: Option<Vec<u8>>,
+    #[serde(
+        serialize_with = "serialize_maybe_base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    idevid_cert: Option<Vec<u8>>,
+}
+
+// Algorithms the agent can use, so that the registrar can pick the ones the
+// verifier expects
+#[derive(Debug, Serialize, Deserialize)]
+struct SupportedAlgorithms {
+    hash: Vec<String>,
+    sign: Vec<String>,
+    encrypt: Vec<String>,
+    // Digests of the auth tags
+    hmac: Vec<String>,
+}
+
+impl SupportedAlgorithms {
+    fn new() -> Self {
+        SupportedAlgorithms {
+            hash: HashAlgorithm::supported()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            sign: SignAlgorithm::supported()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            encrypt: EncryptionAlgorithm::supported()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            hmac: HmacAlgorithm::supported()
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+        }
+    }
+}
+
+// AK algorithms requested by registrars implementing the negotiation
+#[derive(Debug, Serialize, Deserialize)]
+struct AkAlgorithms {
+    hash: String,
+    sign: String,
+}
+
+// Message signed by the operator, in the format of revocation messages
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SignedMessage {
+    pub msg: String,
+    pub signature: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct RegisterResponseResults {
     #[serde(deserialize_with = "deserialize_maybe_base64")]
     blob: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ak_algorithms: Option<AkAlgorithms>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    config_overrides: Option<SignedMessage>,
+    // HMAC algorithm requested by registrars implementing the negotiation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hmac_algorithm: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PreferredAlgorithms {
+    pub hash_alg: HashAlgorithm,
+    pub sign_alg: SignAlgorithm,
+}
+
+#[derive(Debug)]
+pub(crate) struct Registration {
+    pub keyblob: Vec<u8>,
+    // Set when the registrar prefers other AK algorithms
+    pub preferred_algorithms: Option<PreferredAlgorithms>,
+    pub config_overrides: Option<SignedMessage>,
+    // Set when the registrar requested an HMAC algorithm for the auth tags
+    pub hmac_alg: Option<HmacAlgorithm>,
+}
+
+fn preferred_algorithms(
+    algorithms: &AkAlgorithms,
+) -> crate::error::Result<PreferredAlgorithms> {
+    let hash_alg = HashAlgorithm::try_from(algorithms.hash.as_str())?;
+    let sign_alg = SignAlgorithm::try_from(algorithms.sign.as_str())?;
+    if !HashAlgorithm::supported().contains(&hash_alg)
+        || !SignAlgorithm::supported().contains(&sign_alg)
+    {
+        return Err(Error::Other(format!(
+            "{} and {} were not offered to the registrar",
+            hash_alg, sign_alg
+        )));
+    }
+    Ok(PreferredAlgorithms { hash_alg, sign_alg })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Activate<'a> {
     auth_tag: &'a str,
+    // Digest of the HMAC of the auth tag
+    hmac_algorithm: String,
+    // Latest API version served, so the registrar can tell verifiers
+    supported_version: &'a str,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -56,8 +199,14 @@ pub(crate) async fn do_activate_agent(
     registrar_port: &str,
     agent_uuid: &str,
     auth_tag: &str,
+    hmac_alg: HmacAlgorithm,
 ) -> crate::error::Result<()> {
-    let data = Activate { auth_tag };
+    let latest = api::latest().version.to_string();
+    let data = Activate {
+        auth_tag,
+        hmac_algorithm: hmac_alg.to_string(),
+        supported_version: &latest[1..],
+    };
 
     #[cfg(test)]
     let addr = format!("http://{}:{}", registrar_ip, registrar_port);
@@ -93,12 +242,18 @@ pub(crate) async fn do_register_agent(
     registrar_port: &str,
     agent_uuid: &str,
     ek_tpm: &[u8],
+    ek_alg: EncryptionAlgorithm,
     ekcert: Option<Vec<u8>>,
+    ek_cert_chain: &EkCertChain,
     aik_tpm: &[u8],
     mtls_cert_x509: Option<&X509>,
     ip: Option<String>,
     port: Option<u32>,
-) -> crate::error::Result<Vec<u8>> {
+    tpm_emulator: bool,
+    metadata: &HostMetadata,
+    device_identity: Option<&DeviceIdentity>,
+    membership: &Membership,
+) -> crate::error::Result<Registration> {
     let mtls_cert = match mtls_cert_x509 {
         Some(cert) => Some(String::from_utf8(cert.to_pem()?)?),
         None => Some("disabled".to_string()),
@@ -106,11 +261,29 @@ pub(crate) async fn do_register_agent(
 
     let data = Register {
         ekcert,
+        ek_cert_chain: ek_cert_chain
+            .certs
+            .iter()
+            .map(base64::encode)
+            .collect(),
+        ek_cert_chain_valid: ek_cert_chain.valid,
         ek_tpm,
+        ek_alg: ek_alg.to_string(),
         aik_tpm,
         mtls_cert,
         ip,
         port,
+        tpm_emulator,
+        supported_algorithms: SupportedAlgorithms::new(),
+        metadata: metadata.clone(),
+        membership: membership.clone(),
+        iak_tpm: device_identity.map(|identity| identity.iak_tpm.clone()),
+        iak_cert: device_identity
+            .and_then(|identity| identity.iak_cert.clone()),
+        idevid_tpm: device_identity
+            .map(|identity| identity.idevid_tpm.clone()),
+        idevid_cert: device_identity
+            .and_then(|identity| identity.idevid_cert.clone()),
     };
 
     #[cfg(test)]
@@ -142,11 +315,35 @@ pub(crate) async fn do_register_agent(
 
     let resp: Response<RegisterResponseResults> = resp.json().await?;
 
-    if resp.results.blob.is_some() {
-        Ok(resp.results.blob.unwrap()) //#[allow_ci]
-    } else {
-        Ok(Vec::new())
-    }
+    // A registrar asking for algorithms the agent did not offer is ignored,
+    // the current AK is registered instead
+    let preferred_algorithms = match &resp.results.ak_algorithms {
+        Some(algorithms) => match preferred_algorithms(algorithms) {
+            Ok(preferred) => Some(preferred),
+            Err(e) => {
+                warn!("Ignoring the AK algorithms of the registrar: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let hmac_alg = match resp.results.hmac_algorithm.as_deref() {
+        Some(hmac_alg) => match HmacAlgorithm::try_from(hmac_alg) {
+            Ok(hmac_alg) => Some(hmac_alg),
+            Err(e) => {
+                warn!("Ignoring the HMAC algorithm of the registrar: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    Ok(Registration {
+        keyblob: resp.results.blob.unwrap_or_default(),
+        preferred_algorithms,
+        config_overrides: resp.results.config_overrides,
+        hmac_alg,
+    })
 }
 
 #[cfg(feature = "testing")]
@@ -154,7 +351,7 @@ pub(crate) async fn do_register_agent(
 mod tests {
     use super::*;
     use crate::crypto;
-    use wiremock::matchers::{any, method};
+    use wiremock::matchers::{any, body_partial_json, method};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
     #[tokio::test]
@@ -162,7 +359,12 @@ mod tests {
         let response: Response<RegisterResponseResults> = Response {
             code: 200.into(),
             status: "OK".to_string(),
-            results: RegisterResponseResults { blob: None },
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
         };
 
         let mock_server = MockServer::start().await;
@@ -186,11 +388,17 @@ mod tests {
             uri[1],
             "uuid",
             &mock_data,
+            EncryptionAlgorithm::Rsa,
             Some((&mock_data).to_vec()),
+            &EkCertChain::default(),
             &mock_data,
             Some(&cert),
             None,
             None,
+            false,
+            &HostMetadata::default(),
+            None,
+            &Membership::default(),
         )
         .await;
         assert!(response.is_ok());
@@ -201,7 +409,12 @@ mod tests {
         let response: Response<RegisterResponseResults> = Response {
             code: 200.into(),
             status: "OK".to_string(),
-            results: RegisterResponseResults { blob: None },
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
         };
 
         let mock_server = MockServer::start().await;
@@ -225,22 +438,379 @@ mod tests {
             uri[1],
             "uuid",
             &mock_data,
+            EncryptionAlgorithm::Rsa,
             None,
+            &EkCertChain::default(),
             &mock_data,
             Some(&cert),
             None,
             None,
+            false,
+            &HostMetadata::default(),
+            None,
+            &Membership::default(),
         )
         .await;
         assert!(response.is_ok());
     }
 
+    #[tokio::test]
+    async fn mock_register_agent_tpm_emulator() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
+        };
+
+        // Only requests flagging the emulator get a successful response
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "tpm_emulator": true
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock_data = [0u8; 1];
+        let priv_key = crypto::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&priv_key, "uuid").unwrap(); //#[allow_ci]
+        let response = do_register_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            &mock_data,
+            EncryptionAlgorithm::Rsa,
+            None,
+            &EkCertChain::default(),
+            &mock_data,
+            Some(&cert),
+            None,
+            None,
+            true,
+            &HostMetadata::default(),
+            None,
+            &Membership::default(),
+        )
+        .await;
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_register_agent_metadata() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
+        };
+
+        // Only requests with the metadata get a successful response
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "metadata": {
+                    "hostname": "node",
+                    "region": "eu-west-1"
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock_data = [0u8; 1];
+        let metadata = HostMetadata {
+            hostname: Some("node".to_string()),
+            region: Some("eu-west-1".to_string()),
+            ..Default::default()
+        };
+        let response = do_register_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            &mock_data,
+            EncryptionAlgorithm::Rsa,
+            None,
+            &EkCertChain::default(),
+            &mock_data,
+            None,
+            None,
+            None,
+            false,
+            &metadata,
+            None,
+            &Membership::default(),
+        )
+        .await;
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_register_agent_membership() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
+        };
+
+        // Only requests with the group and tags get a successful response
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "group": "gpu-nodes",
+                "tags": ["eu-west", "rack-4"]
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock_data = [0u8; 1];
+        let membership = Membership {
+            group: Some("gpu-nodes".to_string()),
+            tags: vec!["eu-west".to_string(), "rack-4".to_string()],
+        };
+        let response = do_register_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            &mock_data,
+            EncryptionAlgorithm::Rsa,
+            None,
+            &EkCertChain::default(),
+            &mock_data,
+            None,
+            None,
+            None,
+            false,
+            &HostMetadata::default(),
+            None,
+            &membership,
+        )
+        .await;
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_register_agent_ecc_ek() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
+        };
+
+        // Only requests with the algorithm of the EK get a successful
+        // response
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "ek_alg": "ecc"
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock_data = [0u8; 1];
+        let response = do_register_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            &mock_data,
+            EncryptionAlgorithm::Ecc,
+            None,
+            &EkCertChain::default(),
+            &mock_data,
+            None,
+            None,
+            None,
+            false,
+            &HostMetadata::default(),
+            None,
+            &Membership::default(),
+        )
+        .await;
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_register_agent_device_identity() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
+        };
+
+        // Only requests with the IAK and IDevID get a successful response,
+        // the IDevID certificate is not sent when there is none
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "iak_tpm": "AQ==",
+                "iak_cert": "Ag==",
+                "idevid_tpm": "Aw=="
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock_data = [0u8; 1];
+        let device_identity = DeviceIdentity {
+            iak_handle: tss_esapi::handles::KeyHandle::from(0x81020000),
+            iak_tpm: vec![1],
+            iak_cert: Some(vec![2]),
+            idevid_tpm: vec![3],
+            idevid_cert: None,
+        };
+        let response = do_register_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            &mock_data,
+            EncryptionAlgorithm::Rsa,
+            None,
+            &EkCertChain::default(),
+            &mock_data,
+            None,
+            None,
+            None,
+            false,
+            &HostMetadata::default(),
+            Some(&device_identity),
+            &Membership::default(),
+        )
+        .await;
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn mock_register_agent_algorithms() {
+        let response: Response<RegisterResponseResults> = Response {
+            code: 200.into(),
+            status: "OK".to_string(),
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: Some(AkAlgorithms {
+                    hash: "sha384".to_string(),
+                    sign: "ecdsa".to_string(),
+                }),
+                config_overrides: None,
+                hmac_algorithm: Some("sha512".to_string()),
+            },
+        };
+
+        // The supported algorithms are sent on registration
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(serde_json::json!({
+                "supported_algorithms": {
+                    "encrypt": ["rsa", "ecc"],
+                    "hmac": ["sha256", "sha384", "sha512"]
+                }
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(response));
+        mock_server.register(mock).await;
+
+        let uri = mock_server.uri();
+        let uri = uri.split("//").collect::<Vec<&str>>()[1]
+            .split(':')
+            .collect::<Vec<&str>>();
+        assert_eq!(uri.len(), 2);
+
+        let mock_data = [0u8; 1];
+        let registration = do_register_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            &mock_data,
+            EncryptionAlgorithm::Rsa,
+            None,
+            &EkCertChain::default(),
+            &mock_data,
+            None,
+            None,
+            None,
+            false,
+            &HostMetadata::default(),
+            None,
+            &Membership::default(),
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        assert_eq!(
+            registration.preferred_algorithms,
+            Some(PreferredAlgorithms {
+                hash_alg: HashAlgorithm::Sha384,
+                sign_alg: SignAlgorithm::EcDsa,
+            })
+        );
+        assert_eq!(registration.hmac_alg, Some(HmacAlgorithm::Sha512));
+
+        let unknown = AkAlgorithms {
+            hash: "md5".to_string(),
+            sign: "ecdsa".to_string(),
+        };
+        assert!(preferred_algorithms(&unknown).is_err());
+    }
+
     #[tokio::test]
     async fn mock_register_agent_err() {
         let response: Response<RegisterResponseResults> = Response {
             code: 200.into(),
             status: "OK".to_string(),
-            results: RegisterResponseResults { blob: None },
+            results: RegisterResponseResults {
+                blob: None,
+                ak_algorithms: None,
+                config_overrides: None,
+                hmac_algorithm: None,
+            },
         };
 
         let mock_server = MockServer::start().await;
@@ -260,11 +830,17 @@ mod tests {
             uri[1],
             "uuid",
             &mock_data,
+            EncryptionAlgorithm::Rsa,
             Some((&mock_data).to_vec()),
+            &EkCertChain::default(),
             &mock_data,
             Some(&cert),
             None,
             None,
+            false,
+            &HostMetadata::default(),
+            None,
+            &Membership::default(),
         )
         .await;
         assert!(response.is_err());
@@ -281,6 +857,11 @@ mod tests {
 
         let mock_server = MockServer::start().await;
         let mock = Mock::given(method("PUT"))
+            .and(body_partial_json(serde_json::json!({
+                "auth_tag": "tag",
+                "hmac_algorithm": "sha384",
+                "supported_version": "2.1"
+            })))
             .respond_with(ResponseTemplate::new(200).set_body_json(response));
         mock_server.register(mock).await;
 
@@ -292,7 +873,14 @@ mod tests {
 
         let addr = format!("http://{}:{}", uri[0], uri[1]);
 
-        let response = do_activate_agent(uri[0], uri[1], "uuid", "tag").await;
+        let response = do_activate_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            "tag",
+            HmacAlgorithm::Sha384,
+        )
+        .await;
         assert!(response.is_ok());
     }
 
@@ -313,7 +901,14 @@ mod tests {
 
         let addr = format!("http://{}:{}", uri[0], uri[1]);
 
-        let response = do_activate_agent(uri[0], uri[1], "uuid", "tag").await;
+        let response = do_activate_agent(
+            uri[0],
+            uri[1],
+            "uuid",
+            "tag",
+            HmacAlgorithm::Sha384,
+        )
+        .await;
         assert!(response.is_err());
         assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
     }
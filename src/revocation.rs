@@ -4,10 +4,13 @@
 #[macro_use]
 use log::*;
 
+use crate::alert;
 use crate::common::{KeylimeConfig, REV_CERT};
 use crate::crypto;
 use crate::error::*;
+use crate::revocation_webhook::{RevocationEvent, RevocationWebhook};
 use crate::secure_mount;
+use crate::status::AgentStatus;
 
 use std::convert::TryInto;
 use std::fs;
@@ -266,10 +269,21 @@ pub(crate) fn get_revocation_cert_path(
     Ok(cert_path_buf)
 }
 
+/// Whether a verified revocation message is about the given agent
+fn is_for_agent(msg_payload: &Value, agent_uuid: &str) -> bool {
+    msg_payload["agent_id"].as_str() == Some(agent_uuid)
+}
+
 /// Process revocation message received from REST API or 0mq
+///
+/// A verified message about this agent is recorded in the status before the
+/// actions run, even if they fail. Verified messages are then queued for the
+/// revocation webhook, if any.
 #[allow(clippy::too_many_arguments)]
 pub(crate) fn process_revocation(
     body: Value,
+    status: &AgentStatus,
+    webhook: Option<&RevocationWebhook>,
     cert_path: &Path,
     secure_size: &str,
     config_actions: &str,
@@ -329,6 +343,11 @@ pub(crate) fn process_revocation(
                 "Revocation signature validated for revocation: {}",
                 msg_payload
             );
+            if is_for_agent(&msg_payload, &status.report().agent_uuid) {
+                warn!("Revocation processed for this agent");
+                status.set_revoked();
+            }
+            let event_message = msg_payload.clone();
             let outputs = run_revocation_actions(
                 msg_payload,
                 secure_size,
@@ -337,7 +356,21 @@ pub(crate) fn process_revocation(
                 allow_payload_revocation_actions,
                 work_dir,
                 mount,
-            )?;
+            );
+            if let Some(webhook) = webhook {
+                let event = RevocationEvent::new(
+                    &status.report().agent_uuid,
+                    event_message,
+                    outputs.is_ok(),
+                );
+                if let Err(e) = webhook.push(event) {
+                    warn!(
+                        "Unable to queue the revocation event for the webhook: {}",
+                        e
+                    );
+                }
+            }
+            let outputs = outputs?;
 
             for output in outputs {
                 if !output.stdout.is_empty() {
@@ -357,6 +390,10 @@ pub(crate) fn process_revocation(
         }
         _ => {
             error!("Invalid revocation message signature {}", body);
+            alert::raise(
+                alert::AlertKind::RevocationVerificationFailed,
+                "Invalid revocation message signature",
+            );
             Err(Error::InvalidRequest)
         }
     }
@@ -370,6 +407,8 @@ pub(crate) fn process_revocation(
 pub(crate) async fn run_revocation_service(
     config: &KeylimeConfig,
     mount: &Path,
+    status: &AgentStatus,
+    webhook: Option<&RevocationWebhook>,
 ) -> Result<()> {
     let work_dir = Path::new(&config.work_dir);
 
@@ -377,7 +416,12 @@ pub(crate) async fn run_revocation_service(
     let context = zmq::Context::new();
     let mysock = context.socket(zmq::SUB)?;
 
-    mysock.set_subscribe(b"")?;
+    if config.revocation_topics.is_empty() {
+        mysock.set_subscribe(b"")?;
+    }
+    for topic in &config.revocation_topics {
+        mysock.set_subscribe(topic.as_bytes())?;
+    }
 
     let endpoint =
         format!("tcp://{}:{}", config.revocation_ip, config.revocation_port);
@@ -411,6 +455,8 @@ pub(crate) async fn run_revocation_service(
         let body: Value = serde_json::from_str(rawbody.as_str())?;
         let _ = process_revocation(
             body,
+            status,
+            webhook,
             &revocation_cert,
             &config.secure_size,
             &config.revocation_actions,
@@ -742,8 +788,11 @@ mod tests {
         let work_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests");
         let tmpfs_dir = work_dir.join("tmpfs-dev");
 
+        let status = AgentStatus::new(&test_config.agent_uuid);
         let result = process_revocation(
             body,
+            &status,
+            None,
             &cert_path,
             &test_config.secure_size,
             &test_config.revocation_actions,
@@ -754,5 +803,15 @@ mod tests {
         );
 
         assert!(result.is_ok());
+        // The message is not about this agent
+        assert!(!status.report().revoked);
+    }
+
+    #[test]
+    fn test_is_for_agent() {
+        let msg = json!({"agent_id": "uuid", "type": "revocation"});
+        assert!(is_for_agent(&msg, "uuid"));
+        assert!(!is_for_agent(&msg, "other"));
+        assert!(!is_for_agent(&json!({"hello": "there"}), "uuid"));
     }
 }
@@ -0,0 +1,329 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Delivery of revocation events to a webhook.
+//
+// With 'revocation_webhook' set, each verified revocation message is posted
+// to the webhook once its actions ran, so that the orchestration can contain
+// the node. Events are queued as files in the work directory and only removed
+// once delivered, so that they are not lost while the webhook is unavailable,
+// nor when the agent restarts in the meantime. They are delivered one at a
+// time, oldest first. A failed delivery is retried with an exponential
+// backoff, up to 'revocation_webhook_max_retries' times before the event is
+// dropped. Beyond 'revocation_webhook_max_queued' events, the oldest ones are
+// dropped.
+
+use crate::{
+    error::{Error, Result},
+    permissions,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+
+pub(crate) static QUEUE_DIR: &str = "revocation_webhook";
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RevocationEvent {
+    pub agent_id: String,
+    // Seconds since the epoch
+    pub received: u64,
+    pub actions_succeeded: bool,
+    // The verified revocation message
+    pub message: Value,
+}
+
+impl RevocationEvent {
+    pub(crate) fn new(
+        agent_id: &str,
+        message: Value,
+        actions_succeeded: bool,
+    ) -> Self {
+        let received = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        RevocationEvent {
+            agent_id: agent_id.to_string(),
+            received,
+            actions_succeeded,
+            message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct QueuedEvent {
+    attempts: u32,
+    event: RevocationEvent,
+}
+
+// Delay before the given failed attempt is retried
+fn backoff(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    INITIAL_BACKOFF
+        .checked_mul(factor)
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF))
+}
+
+// Queued events are named after their sequence number, zero-padded so that
+// the names sort in the order of the events
+fn sequence_of(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(".json")?
+        .parse::<u64>()
+        .ok()
+}
+
+#[derive(Debug)]
+pub(crate) struct RevocationWebhook {
+    dir: PathBuf,
+    max_queued: usize,
+    next_sequence: AtomicU64,
+    pending: Notify,
+}
+
+impl RevocationWebhook {
+    /*
+     * Input: directory of the queue and the maximum number of queued events
+     * Return: the queue, with the events left undelivered by previous runs
+     */
+    pub(crate) fn open(dir: &Path, max_queued: usize) -> Result<Self> {
+        if !dir.exists() {
+            permissions::create_dir(dir)?;
+        }
+        let queue = RevocationWebhook {
+            dir: dir.to_path_buf(),
+            max_queued,
+            next_sequence: AtomicU64::new(0),
+            pending: Notify::new(),
+        };
+        let queued = queue.queued()?;
+        if let Some(last) = queued.last().and_then(|path| sequence_of(path)) {
+            queue.next_sequence.store(last + 1, Ordering::SeqCst);
+        }
+        if !queued.is_empty() {
+            info!(
+                "{} revocation events are queued for the webhook",
+                queued.len()
+            );
+        }
+        Ok(queue)
+    }
+
+    // The queued events, oldest first
+    fn queued(&self) -> Result<Vec<PathBuf>> {
+        let mut queued = fs::read_dir(&self.dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<PathBuf>>>()?;
+        queued.retain(|path| sequence_of(path).is_some());
+        queued.sort();
+        Ok(queued)
+    }
+
+    // Written to a temporary file first, so that a crash never leaves a
+    // partial event in the queue
+    fn store(&self, path: &Path, queued: &QueuedEvent) -> Result<()> {
+        let mut file = tempfile::NamedTempFile::new_in(&self.dir)?;
+        serde_json::to_writer(&mut file, queued)?;
+        file.as_file().sync_all()?;
+        let _ = file.persist(path)?;
+        Ok(())
+    }
+
+    /*
+     * Input: revocation event
+     *
+     * Queues the event for delivery, dropping the oldest events beyond the
+     * maximum.
+     */
+    pub(crate) fn push(&self, event: RevocationEvent) -> Result<()> {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::SeqCst);
+        let path = self.dir.join(format!("{:020}.json", sequence));
+        self.store(&path, &QueuedEvent { attempts: 0, event })?;
+
+        let queued = self.queued()?;
+        if queued.len() > self.max_queued {
+            for dropped in &queued[..queued.len() - self.max_queued] {
+                warn!(
+                    "Too many revocation events queued for the webhook, dropping {}",
+                    dropped.display()
+                );
+                fs::remove_file(dropped)?;
+            }
+        }
+        self.pending.notify_one();
+        Ok(())
+    }
+
+    async fn post(
+        client: &reqwest::Client,
+        webhook: &str,
+        event: &RevocationEvent,
+    ) -> Result<()> {
+        let _ = client
+            .post(webhook)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /*
+     * Input: webhook URL and the number of retries of a failed delivery
+     *
+     * Delivers the queued events until the agent exits.
+     */
+    pub(crate) async fn deliver(
+        &self,
+        webhook: String,
+        max_retries: u32,
+    ) -> Result<()> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+        info!("Delivering revocation events to {}", webhook);
+
+        loop {
+            let path = match self.queued()?.into_iter().next() {
+                Some(path) => path,
+                None => {
+                    self.pending.notified().await;
+                    continue;
+                }
+            };
+            let mut queued: QueuedEvent =
+                match fs::read(&path).map_err(Error::from).and_then(|data| {
+                    serde_json::from_slice(&data).map_err(Error::from)
+                }) {
+                    Ok(queued) => queued,
+                    Err(e) => {
+                        warn!(
+                            "Dropping unreadable revocation event {}: {}",
+                            path.display(),
+                            e
+                        );
+                        fs::remove_file(&path)?;
+                        continue;
+                    }
+                };
+
+            match Self::post(&client, &webhook, &queued.event).await {
+                Ok(()) => {
+                    info!("Delivered revocation event to {}", webhook);
+                    fs::remove_file(&path)?;
+                }
+                Err(e) => {
+                    queued.attempts += 1;
+                    if queued.attempts > max_retries {
+                        error!(
+                            "Dropping revocation event after {} failed deliveries to {}: {}",
+                            queued.attempts, webhook, e
+                        );
+                        fs::remove_file(&path)?;
+                        continue;
+                    }
+                    self.store(&path, &queued)?;
+                    let delay = backoff(queued.attempts);
+                    warn!(
+                        "Unable to deliver revocation event to {}, retrying in {} seconds: {}",
+                        webhook,
+                        delay.as_secs(),
+                        e
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(10), MAX_BACKOFF);
+        assert_eq!(backoff(100), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_queue() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let queue = RevocationWebhook::open(dir.path(), 2).unwrap(); //#[allow_ci]
+        for i in 0..3 {
+            let event =
+                RevocationEvent::new("uuid", json!({ "event": i }), true);
+            queue.push(event).unwrap(); //#[allow_ci]
+        }
+
+        // The oldest event was dropped
+        let queued = queue.queued().unwrap(); //#[allow_ci]
+        assert_eq!(queued.len(), 2);
+        assert_eq!(sequence_of(&queued[0]), Some(1));
+        assert_eq!(sequence_of(&queued[1]), Some(2));
+
+        // The queue is kept across runs, new events are queued after
+        let reopened = RevocationWebhook::open(dir.path(), 2).unwrap(); //#[allow_ci]
+        reopened
+            .push(RevocationEvent::new("uuid", json!({}), false))
+            .unwrap(); //#[allow_ci]
+        let queued = reopened.queued().unwrap(); //#[allow_ci]
+        assert_eq!(sequence_of(&queued[1]), Some(3));
+        let data = fs::read(&queued[0]).unwrap(); //#[allow_ci]
+        let first: QueuedEvent = serde_json::from_slice(&data).unwrap(); //#[allow_ci]
+        assert_eq!(first.event.message, json!({ "event": 2 }));
+        assert_eq!(first.attempts, 0);
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_deliver() {
+        use wiremock::{
+            matchers::{body_partial_json, method},
+            Mock, MockServer, ResponseTemplate,
+        };
+
+        let mock_server = MockServer::start().await;
+        let mock = Mock::given(method("POST"))
+            .and(body_partial_json(json!({
+                "agent_id": "uuid",
+                "actions_succeeded": true,
+            })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1);
+        mock_server.register(mock).await;
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let queue = RevocationWebhook::open(dir.path(), 10).unwrap(); //#[allow_ci]
+        queue
+            .push(RevocationEvent::new("uuid", json!({}), true))
+            .unwrap(); //#[allow_ci]
+
+        // Delivery runs until the agent exits, stop once the queue is empty
+        let _ = tokio::time::timeout(
+            Duration::from_secs(2),
+            queue.deliver(mock_server.uri(), 3),
+        )
+        .await;
+        assert!(queue.queued().unwrap().is_empty()); //#[allow_ci]
+    }
+}
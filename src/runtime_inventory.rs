@@ -0,0 +1,327 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Runtime inventory sent with integrity quotes.
+//
+// With 'runtime_inventory_pcr' set, integrity quotes include a snapshot of
+// the loaded kernel modules, with their taint flags, and of the mounted file
+// systems. Before quoting, the digest of the snapshot is extended into the
+// PCR when it changed since the last quote and recorded in the agent event
+// log. The quote then covers the PCR, and the verifier can replay it from
+// the digests sent with the snapshot, so policies like "no out-of-tree
+// modules" are backed by the TPM.
+
+use crate::{
+    algorithms::HashAlgorithm,
+    error::{Error, Result},
+    permissions, self_measurement, tpm, QuoteData,
+};
+use actix_web::web::Data;
+use log::*;
+use openssl::hash::hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+use tss_esapi::Context;
+
+pub(crate) static INVENTORY_EVENT: &str = "runtime-inventory";
+static PROC_MODULES: &str = "/proc/modules";
+static PROC_MOUNTS: &str = "/proc/mounts";
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Module {
+    name: String,
+    // Taint flags, e.g. "O" for out-of-tree and "E" for unsigned modules
+    taints: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Mount {
+    source: String,
+    target: String,
+    fstype: String,
+    options: String,
+}
+
+// Sorted, so that the digest only changes with the content
+#[derive(Debug, PartialEq, Serialize)]
+struct Inventory {
+    modules: Vec<Module>,
+    mounts: Vec<Mount>,
+}
+
+// Sent with the quote, the inventory as it was hashed and the digests
+// extended into the PCR, in order
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct InventorySnapshot {
+    pub pcr: usize,
+    pub inventory: String,
+    pub digests: Vec<String>,
+}
+
+// Lines of /proc/modules: name size refcount deps state address [(taints)]
+fn parse_modules(modules: &str) -> Vec<Module> {
+    let mut parsed = modules
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<&str>>();
+            let name = fields.first()?;
+            let taints = match fields.get(6) {
+                Some(flags) => flags.trim_matches(|c| c == '(' || c == ')'),
+                None => "",
+            };
+            Some(Module {
+                name: name.to_string(),
+                taints: taints.to_string(),
+            })
+        })
+        .collect::<Vec<Module>>();
+    parsed.sort_by(|a, b| a.name.cmp(&b.name));
+    parsed
+}
+
+// Lines of /proc/mounts: source target fstype options dump pass
+fn parse_mounts(mounts: &str) -> Vec<Mount> {
+    let mut parsed = mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some(Mount {
+                source: fields.next()?.to_string(),
+                target: fields.next()?.to_string(),
+                fstype: fields.next()?.to_string(),
+                options: fields.next()?.to_string(),
+            })
+        })
+        .collect::<Vec<Mount>>();
+    parsed.sort_by(|a, b| {
+        (&a.target, &a.source, &a.fstype)
+            .cmp(&(&b.target, &b.source, &b.fstype))
+    });
+    parsed
+}
+
+fn read_inventory(modules: &Path, mounts: &Path) -> Result<Inventory> {
+    Ok(Inventory {
+        modules: parse_modules(&fs::read_to_string(modules)?),
+        mounts: parse_mounts(&fs::read_to_string(mounts)?),
+    })
+}
+
+// Digests of the earlier snapshots, extended since the PCR was last reset
+fn load_digests(
+    event_log: &Path,
+    pcr: usize,
+    hash_alg: HashAlgorithm,
+) -> Result<Vec<Vec<u8>>> {
+    let log = match fs::read_to_string(event_log) {
+        Ok(log) => log,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let prefix = format!("{} {}:", pcr, hash_alg);
+    let mut digests = Vec::new();
+    for line in log.lines() {
+        let digest = match line
+            .strip_prefix(&prefix)
+            .and_then(|entry| entry.strip_suffix(INVENTORY_EVENT))
+        {
+            Some(digest) => digest.trim(),
+            None => continue,
+        };
+        digests.push(hex::decode(digest).map_err(|e| {
+            Error::Other(format!(
+                "invalid digest in {}: {}",
+                event_log.display(),
+                e
+            ))
+        })?);
+    }
+    Ok(digests)
+}
+
+#[derive(Debug)]
+pub(crate) struct RuntimeInventory {
+    pcr: usize,
+    event_log: PathBuf,
+    digests: Vec<Vec<u8>>,
+}
+
+impl RuntimeInventory {
+    /*
+     * Input: PCR to extend, path to the agent event log and hash algorithm
+     * Return: Result wrap the inventory state
+     *
+     * The digests extended by an earlier run of the agent are read back from
+     * the event log, which like the PCR is cleared on reboot.
+     */
+    pub(crate) fn load(
+        pcr: usize,
+        event_log: PathBuf,
+        hash_alg: HashAlgorithm,
+    ) -> Result<Self> {
+        let digests = load_digests(&event_log, pcr, hash_alg)?;
+        Ok(RuntimeInventory {
+            pcr,
+            event_log,
+            digests,
+        })
+    }
+
+    pub(crate) fn pcr(&self) -> usize {
+        self.pcr
+    }
+}
+
+fn snapshot(
+    context: &mut Context,
+    data: &QuoteData,
+) -> Result<Option<InventorySnapshot>> {
+    let state = match &data.runtime_inventory {
+        Some(state) => state,
+        None => return Ok(None),
+    };
+    let inventory = serde_json::to_string(&read_inventory(
+        Path::new(PROC_MODULES),
+        Path::new(PROC_MOUNTS),
+    )?)?;
+    let digest = hash(data.hash_alg.into(), inventory.as_bytes())?.to_vec();
+
+    let mut state = state.lock().unwrap(); //#[allow_ci]
+    if state.digests.last() != Some(&digest) {
+        // Logged first, so a failed extension leaves a log that does not
+        // replay rather than an unexplained PCR value
+        let mut log = permissions::open_append(&state.event_log)?;
+        log.write_all(
+            self_measurement::event_log_entry(
+                state.pcr,
+                data.hash_alg,
+                &digest,
+                INVENTORY_EVENT,
+            )
+            .as_bytes(),
+        )?;
+        log.sync_data()?;
+        tpm::extend_pcr(context, state.pcr, data.hash_alg, &digest)?;
+        debug!(
+            "Measured the runtime inventory into PCR {}: {}:{}",
+            state.pcr,
+            data.hash_alg,
+            hex::encode(&digest)
+        );
+        state.digests.push(digest);
+    }
+
+    Ok(Some(InventorySnapshot {
+        pcr: state.pcr,
+        inventory,
+        digests: state.digests.iter().map(hex::encode).collect(),
+    }))
+}
+
+/*
+ * Input: agent state
+ * Return: Result wrap the snapshot to send with the quote, None when the
+ *         inventory is disabled
+ *
+ * Runs on the TPM service like quotes, within the same
+ * tpm_operation_timeout.
+ */
+pub(crate) async fn measure(
+    data: Data<QuoteData>,
+) -> Result<Option<InventorySnapshot>> {
+    if data.runtime_inventory.is_none() {
+        return Ok(None);
+    }
+    let timeout = data.tpm_operation_timeout;
+    let status = data.status.clone();
+    let inventory_data = data.clone();
+    let task = data.tpm.run(move |context| {
+        let result = snapshot(context, &inventory_data);
+        inventory_data.status.tpm_completed();
+        result
+    });
+    match tokio::time::timeout(timeout, task).await {
+        Ok(result) => result,
+        Err(_) => {
+            status.tpm_timed_out();
+            warn!(
+                "Runtime inventory measurement did not complete within {} seconds, it keeps running in the background",
+                timeout.as_secs()
+            );
+            Err(Error::TpmTimeout(timeout.as_secs()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_modules() {
+        let modules = "\
+xfs 1597440 2 - Live 0x0000000000000000
+vboxdrv 565248 2 vboxnetadp,vboxnetflt, Live 0x0000000000000000 (OE)
+";
+        assert_eq!(
+            parse_modules(modules),
+            vec![
+                Module {
+                    name: "vboxdrv".to_string(),
+                    taints: "OE".to_string(),
+                },
+                Module {
+                    name: "xfs".to_string(),
+                    taints: "".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_mounts() {
+        let mounts = "\
+tmpfs /var/lib/keylime/secure tmpfs rw,relatime,size=1024k 0 0
+/dev/vda1 / xfs rw,seclabel,relatime 0 0
+";
+        let parsed = parse_mounts(mounts);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].target, "/");
+        assert_eq!(parsed[0].fstype, "xfs");
+        assert_eq!(parsed[1].source, "tmpfs");
+        assert_eq!(parsed[1].options, "rw,relatime,size=1024k");
+    }
+
+    #[test]
+    fn test_read_inventory() {
+        let inventory =
+            read_inventory(Path::new(PROC_MODULES), Path::new(PROC_MOUNTS))
+                .unwrap(); //#[allow_ci]
+        assert!(inventory.mounts.iter().any(|mount| mount.target == "/"));
+    }
+
+    #[test]
+    fn test_load_digests() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let event_log = dir.path().join("agent_event_log");
+        let digests = load_digests(&event_log, 15, HashAlgorithm::Sha256);
+        assert_eq!(digests.unwrap(), Vec::<Vec<u8>>::new()); //#[allow_ci]
+
+        let log = "\
+14 sha256:ab01 keylime-agent-binary
+15 sha256:cd02 runtime-inventory
+15 sha1:ef03 runtime-inventory
+15 sha256:0405 runtime-inventory
+";
+        fs::write(&event_log, log).unwrap(); //#[allow_ci]
+        let digests =
+            load_digests(&event_log, 15, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        assert_eq!(digests, vec![vec![0xcd, 0x02], vec![0x04, 0x05]]);
+    }
+}
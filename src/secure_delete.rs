@@ -0,0 +1,102 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Removal of decrypted payloads and payload decryption keys.
+//
+// File contents are overwritten with zeros and synced before the file is
+// unlinked, so the old plaintext does not linger in the memory backing the
+// secure mount until the pages are reused. This is best-effort only: tmpfs
+// pages may have been swapped out, and on SSDs or copy-on-write and
+// journaling file systems the overwrite is not guaranteed to hit the blocks
+// that held the original data.
+
+use crate::error::Result;
+use log::*;
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+const CHUNK_SIZE: usize = 4096;
+
+fn overwrite(path: &Path) -> Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    let mut remaining = file.metadata()?.len();
+    let zeros = [0u8; CHUNK_SIZE];
+
+    while remaining > 0 {
+        let len = std::cmp::min(remaining, CHUNK_SIZE as u64) as usize;
+        file.write_all(&zeros[..len])?;
+        remaining -= len as u64;
+    }
+    file.sync_all()?;
+    Ok(())
+}
+
+// Overwrite a file's contents and unlink it
+pub(crate) fn remove_file(path: &Path) -> Result<()> {
+    if let Err(e) = overwrite(path) {
+        // Still remove the file even if it could not be overwritten
+        warn!(
+            "Unable to overwrite {} before removal: {}",
+            path.display(),
+            e
+        );
+    }
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+// Like fs::remove_dir_all, but overwrites every regular file before
+// unlinking it. Symbolic links are removed, not followed.
+pub(crate) fn remove_dir_all(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            remove_dir_all(&entry.path())?;
+        } else if file_type.is_file() {
+            remove_file(&entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    fs::remove_dir(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overwrite() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("key");
+        let data = vec![0xa5u8; CHUNK_SIZE + 10];
+        fs::write(&path, &data).unwrap(); //#[allow_ci]
+
+        overwrite(&path).unwrap(); //#[allow_ci]
+        assert_eq!(fs::read(&path).unwrap(), vec![0u8; data.len()]); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_remove_dir_all() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unzipped = dir.path().join("unzipped");
+        let nested = unzipped.join("nested");
+        fs::create_dir_all(&nested).unwrap(); //#[allow_ci]
+        fs::write(unzipped.join("key"), b"secret").unwrap(); //#[allow_ci]
+        fs::write(nested.join("payload"), b"secret").unwrap(); //#[allow_ci]
+
+        // Links are removed without touching their target
+        let target = dir.path().join("target");
+        fs::write(&target, b"keep").unwrap(); //#[allow_ci]
+        std::os::unix::fs::symlink(&target, unzipped.join("link")).unwrap(); //#[allow_ci]
+
+        remove_dir_all(&unzipped).unwrap(); //#[allow_ci]
+        assert!(!unzipped.exists());
+        assert_eq!(fs::read(&target).unwrap(), b"keep"); //#[allow_ci]
+    }
+}
@@ -6,7 +6,6 @@ use super::*;
 use crate::error::{Error, Result};
 use std::fs;
 use std::io::BufRead;
-use std::os::unix::fs::PermissionsExt;
 use std::path::PathBuf;
 use std::process::Command;
 
@@ -87,7 +86,7 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
         warn!("Using /tmpfs-dev (dev environment)");
         let secure_dir_path = work_dir.join("tmpfs-dev");
         if !secure_dir_path.exists() {
-            fs::create_dir(&secure_dir_path).map_err(|e| {
+            permissions::create_dir(&secure_dir_path).map_err(|e| {
                 Error::SecureMount(format!(
                     "unable to create secure dir path: {:?}",
                     e
@@ -106,9 +105,9 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
     // file system.
     if !check_mount(&secure_dir_path)? {
         // Create directory if the directory is not exist. The
-        // directory permission is set to 448.
+        // directory permission is set to 448 (0o700).
         if !secure_dir_path.exists() {
-            fs::create_dir(&secure_dir_path).map_err(|e| {
+            permissions::create_dir(&secure_dir_path).map_err(|e| {
                 Error::SecureMount(format!(
                     "unable to create secure dir path: {:?}",
                     e
@@ -116,13 +115,6 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
             })?;
 
             info!("Directory {:?} created.", secure_dir_path);
-            let metadata = fs::metadata(&secure_dir_path).map_err(|e| {
-                Error::SecureMount(format!(
-                    "unable to get metadata for secure dir path: {:?}",
-                    e
-                ))
-            })?;
-            metadata.permissions().set_mode(0o750); // decimal 488
         }
 
         info!(
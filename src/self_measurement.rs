@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Measurement of the agent itself into a PCR at startup.
+//
+// The digests of the running agent binary and of its effective configuration
+// are extended into the PCR set with 'measure_agent_pcr', and each extension
+// is recorded in the agent event log so the verifier can replay the PCR and
+// match the digests against its policy. Settings received after the startup
+// measurement, from the registrar or a configuration bundle, change the
+// configuration, which is then measured again with a second configuration
+// event.
+
+use crate::{
+    algorithms::HashAlgorithm, common::KeylimeConfig, permissions, tpm,
+    Result,
+};
+use log::*;
+use openssl::hash::hash;
+use std::{fs, io::Write, path::Path};
+use tss_esapi::Context;
+
+pub(crate) static BINARY_EVENT: &str = "keylime-agent-binary";
+pub(crate) static CONFIG_EVENT: &str = "keylime-agent-config";
+
+fn measure_binary(hash_alg: HashAlgorithm) -> Result<Vec<u8>> {
+    let binary = fs::read(std::env::current_exe()?)?;
+    Ok(hash(hash_alg.into(), &binary)?.to_vec())
+}
+
+// Secrets are excluded from the serialized configuration
+pub(crate) fn measure_config(
+    config: &KeylimeConfig,
+    hash_alg: HashAlgorithm,
+) -> Result<Vec<u8>> {
+    let serialized = serde_json::to_vec(config)?;
+    Ok(hash(hash_alg.into(), &serialized)?.to_vec())
+}
+
+// Event log line, in the form "<pcr> <hash_alg>:<hex digest> <name>"
+pub(crate) fn event_log_entry(
+    pcr: usize,
+    hash_alg: HashAlgorithm,
+    digest: &[u8],
+    name: &str,
+) -> String {
+    format!("{} {}:{} {}\n", pcr, hash_alg, hex::encode(digest), name)
+}
+
+/*
+ * Input: TPM context, agent configuration, PCR to extend and path to the
+ *        agent event log
+ *
+ * Extends the digests of the agent binary and configuration into the PCR
+ * bank of the configured hash algorithm. Each entry is appended to the event
+ * log before the PCR is extended, so a failure leaves a log that does not
+ * replay rather than an unexplained PCR value.
+ */
+pub(crate) fn measure_agent(
+    context: &mut Context,
+    config: &KeylimeConfig,
+    pcr: usize,
+    event_log: &Path,
+) -> Result<()> {
+    let hash_alg = config.hash_alg;
+    let measurements = [
+        (BINARY_EVENT, measure_binary(hash_alg)?),
+        (CONFIG_EVENT, measure_config(config, hash_alg)?),
+    ];
+
+    for (name, digest) in measurements.iter() {
+        extend(context, pcr, hash_alg, name, digest, event_log)?;
+    }
+    Ok(())
+}
+
+/*
+ * Input: TPM context, PCR to extend, hash algorithm, digest of the
+ *        configuration and path to the agent event log
+ *
+ * Measures the configuration changed since measure_agent.
+ */
+pub(crate) fn measure_config_update(
+    context: &mut Context,
+    pcr: usize,
+    hash_alg: HashAlgorithm,
+    digest: &[u8],
+    event_log: &Path,
+) -> Result<()> {
+    extend(context, pcr, hash_alg, CONFIG_EVENT, digest, event_log)
+}
+
+fn extend(
+    context: &mut Context,
+    pcr: usize,
+    hash_alg: HashAlgorithm,
+    name: &str,
+    digest: &[u8],
+    event_log: &Path,
+) -> Result<()> {
+    let mut log = permissions::open_append(event_log)?;
+    log.write_all(event_log_entry(pcr, hash_alg, digest, name).as_bytes())?;
+    log.sync_data()?;
+    tpm::extend_pcr(context, pcr, hash_alg, digest)?;
+    info!(
+        "Measured {} into PCR {}: {}:{}",
+        name,
+        pcr,
+        hash_alg,
+        hex::encode(digest)
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_entry() {
+        assert_eq!(
+            event_log_entry(14, HashAlgorithm::Sha256, &[0xab, 0x01], "x"),
+            "14 sha256:ab01 x\n"
+        );
+    }
+
+    #[test]
+    fn test_measure_config() {
+        let mut config = KeylimeConfig::default();
+        let digest = measure_config(&config, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        assert_eq!(digest.len(), 32);
+
+        // Secrets do not affect the measurement
+        config.tpm_ownerpassword = Some("secret".to_string());
+        let with_secret =
+            measure_config(&config, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        assert_eq!(digest, with_secret);
+
+        config.ima_pcr = 11;
+        let changed = measure_config(&config, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        assert_ne!(digest, changed);
+    }
+}
@@ -0,0 +1,180 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Self-test report exported through the admin socket.
+//
+// Each check exercises one of the resources the agent needs to answer the
+// verifier, so a failing attestation can be narrowed down locally without
+// going through the network API.
+
+use crate::{
+    common::{ima_ml_path_get, KeylimeConfig, MEASUREDBOOT_ML},
+    disk_usage,
+    error::{Error, Result},
+    tpm, QuoteData,
+};
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io::Read, path::Path};
+use tss_esapi::Context;
+
+// Nonce of the test quote, it is never sent to a verifier
+const SELF_TEST_NONCE: &[u8] = b"keylimeselftest";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &str, result: Result<String>) -> Self {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+        Check {
+            name: name.to_string(),
+            passed,
+            detail,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct SelfTestReport {
+    pub checks: Vec<Check>,
+}
+
+impl SelfTestReport {
+    pub(crate) fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            let result = if check.passed { "PASS" } else { "FAIL" };
+            writeln!(f, "{} {:<20} {}", result, check.name, check.detail)?;
+        }
+        Ok(())
+    }
+}
+
+fn tpm_self_test(context: &mut Context) -> Result<String> {
+    context.self_test(false)?;
+    let (_, result) = context.get_test_result()?;
+    result?;
+    let vendor = tss_esapi::utils::get_tpm_vendor(context)?;
+    Ok(format!("vendor {}", vendor))
+}
+
+fn test_quote(context: &mut Context, data: &QuoteData) -> Result<String> {
+    let quote =
+        tpm::quote(context, SELF_TEST_NONCE, None, &[], false, None, data)?;
+    Ok(format!("{} bytes", quote.quote.len()))
+}
+
+fn read_measurement_list(path: &Path) -> Result<String> {
+    // Only the beginning is read, the full list can be large
+    let mut buf = [0u8; 4096];
+    let _ = fs::File::open(path)?.read(&mut buf)?;
+    Ok(format!("{} readable", path.display()))
+}
+
+fn disk_space(path: &Path, warn_percent: u64) -> Result<String> {
+    let usage = disk_usage::disk_usage(path)?;
+    let free = usage.free_percent();
+    if free < warn_percent {
+        return Err(Error::DiskSpace(format!(
+            "{} has {}% free",
+            path.display(),
+            free
+        )));
+    }
+    Ok(format!("{}% free", free))
+}
+
+/*
+ * Input: quote data and agent configuration
+ * Return: self-test report
+ *
+ * Runs all checks, a failing check does not prevent the following ones from
+ * running. The TPM checks run on the TPM service, the file checks on the
+ * blocking thread pool, so that neither holds up the runtime.
+ */
+pub(crate) async fn run(
+    data: web::Data<QuoteData>,
+    config: &KeylimeConfig,
+) -> SelfTestReport {
+    let quote_data = data.clone();
+    let (tpm, quote) = match data
+        .tpm
+        .run_idempotent(move |context| {
+            Ok((tpm_self_test(context), test_quote(context, &quote_data)))
+        })
+        .await
+    {
+        Ok(results) => results,
+        Err(e) => (Err(e), Err(Error::Other("not run".to_string()))),
+    };
+    let mut checks = vec![Check::new("tpm", tpm), Check::new("quote", quote)];
+
+    let ima_ml = data.ima_ml_file.is_some();
+    let measuredboot_ml = data.measuredboot_ml_file.is_some();
+    let secure_mount = data.secure_mount.clone();
+    let warn_percent = config.disk_space_warn_percent;
+    let file_checks = web::block(move || {
+        let mut checks = Vec::new();
+        if ima_ml {
+            checks.push(Check::new(
+                "ima_log",
+                read_measurement_list(&ima_ml_path_get()),
+            ));
+        }
+        if measuredboot_ml {
+            checks.push(Check::new(
+                "measuredboot_log",
+                read_measurement_list(Path::new(MEASUREDBOOT_ML)),
+            ));
+        }
+        checks.push(Check::new(
+            "secure_mount",
+            disk_space(&secure_mount, warn_percent),
+        ));
+        checks
+    })
+    .await;
+    match file_checks {
+        Ok(file_checks) => checks.extend(file_checks),
+        Err(e) => checks.push(Check::new(
+            "files",
+            Err(Error::Other(format!("unable to run the checks: {}", e))),
+        )),
+    }
+    SelfTestReport { checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut report = SelfTestReport {
+            checks: vec![Check::new("ok", Ok("fine".to_string()))],
+        };
+        assert!(report.passed());
+        assert!(report.to_string().starts_with("PASS ok"));
+
+        report.checks.push(Check::new(
+            "failed",
+            Err(Error::Other("broken".to_string())),
+        ));
+        assert!(!report.passed());
+        assert!(report.to_string().contains("FAIL failed"));
+        assert!(report.to_string().contains("broken"));
+    }
+}
@@ -1,8 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use crate::Error;
 use serde::{Deserialize, Serialize};
 use serde_json::Number;
+use std::convert::TryInto;
 
 #[derive(Debug, Deserialize)]
 struct WrappedBase64Encoded(
@@ -52,3 +54,143 @@ where
     Option::<WrappedBase64Encoded>::deserialize(deserializer)
         .map(|wrapped| wrapped.map(|wrapped| wrapped.0))
 }
+
+// TPMS_CLOCK_INFO of the TPMS_ATTEST structure of a quote. Verifiers detect
+// TPM resets with reset_count and restart_count, and replayed quotes with a
+// clock going backwards while both counters are unchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct ClockInfo {
+    // Milliseconds the TPM was powered since its last clear
+    pub clock: u64,
+    // TPM Reset since the last clear, usually reboots
+    pub reset_count: u32,
+    // TPM Restart or TPM Resume since the last reset, usually resumes
+    pub restart_count: u32,
+    // Whether no clock greater than this one was reported before
+    pub safe: bool,
+}
+
+// Bytes of a marshalled TPM structure, read in order
+struct Marshalled<'a>(&'a [u8]);
+
+impl<'a> Marshalled<'a> {
+    fn take(&mut self, len: usize) -> crate::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(Error::Conversion(
+                "truncated TPMS_ATTEST structure".to_string(),
+            ));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> crate::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> crate::Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap())) //#[allow_ci]
+    }
+
+    fn u32(&mut self) -> crate::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap())) //#[allow_ci]
+    }
+
+    fn u64(&mut self) -> crate::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap())) //#[allow_ci]
+    }
+
+    // Skips a TPM2B structure
+    fn sized(&mut self) -> crate::Result<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+}
+
+// TPM_GENERATED_VALUE, the magic of the structures signed by the TPM
+const TPM_GENERATED_VALUE: u32 = 0xff544347;
+
+/*
+ * Input: marshalled TPMS_ATTEST structure
+ * Return: its TPMS_CLOCK_INFO
+ */
+pub(crate) fn clock_info_from_attest(
+    attest: &[u8],
+) -> crate::Result<ClockInfo> {
+    let mut attest = Marshalled(attest);
+    if attest.u32()? != TPM_GENERATED_VALUE {
+        return Err(Error::Conversion(
+            "TPMS_ATTEST structure not generated by a TPM".to_string(),
+        ));
+    }
+    let _type = attest.u16()?;
+    let _qualified_signer = attest.sized()?;
+    let _extra_data = attest.sized()?;
+    Ok(ClockInfo {
+        clock: attest.u64()?,
+        reset_count: attest.u32()?,
+        restart_count: attest.u32()?,
+        safe: attest.u8()? != 0,
+    })
+}
+
+/*
+ * Input: quote in the format sent to verifiers, 'r' followed by the base64
+ *        encoded TPMS_ATTEST, signature and PCR values separated by ':'
+ * Return: the TPMS_CLOCK_INFO of the quote
+ */
+pub(crate) fn clock_info_from_quote(quote: &str) -> crate::Result<ClockInfo> {
+    let attest = quote
+        .strip_prefix('r')
+        .and_then(|quote| quote.split(':').next())
+        .ok_or_else(|| {
+            Error::Conversion("quote not in the expected format".to_string())
+        })?;
+    let attest = base64::decode(attest)
+        .map_err(|e| Error::Conversion(e.to_string()))?;
+    clock_info_from_attest(&attest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TPMS_ATTEST of a quote with a 2 bytes signer name and a 3 bytes nonce
+    fn attest() -> Vec<u8> {
+        let mut attest = Vec::new();
+        attest.extend_from_slice(&TPM_GENERATED_VALUE.to_be_bytes());
+        attest.extend_from_slice(&0x8018u16.to_be_bytes());
+        attest.extend_from_slice(&[0, 2, 0xaa, 0xbb]);
+        attest.extend_from_slice(&[0, 3, 1, 2, 3]);
+        attest.extend_from_slice(&123456789u64.to_be_bytes());
+        attest.extend_from_slice(&7u32.to_be_bytes());
+        attest.extend_from_slice(&2u32.to_be_bytes());
+        attest.push(1);
+        attest.extend_from_slice(&[0; 8]);
+        attest
+    }
+
+    #[test]
+    fn test_clock_info_from_attest() {
+        let expected = ClockInfo {
+            clock: 123456789,
+            reset_count: 7,
+            restart_count: 2,
+            safe: true,
+        };
+        assert_eq!(clock_info_from_attest(&attest()).unwrap(), expected); //#[allow_ci]
+
+        let quote = format!("r{}:c2ln:cGNycw==", base64::encode(attest()));
+        assert_eq!(clock_info_from_quote(&quote).unwrap(), expected); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_clock_info_invalid() {
+        let attest = attest();
+        assert!(clock_info_from_attest(&attest[..20]).is_err());
+        assert!(clock_info_from_attest(&attest[4..]).is_err());
+        assert!(clock_info_from_quote(&base64::encode(&attest)).is_err());
+        assert!(clock_info_from_quote("rnot base64:c2ln").is_err());
+    }
+}
@@ -0,0 +1,540 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Runtime state of the agent, as reported by `keylime_agent status` through
+// the admin socket.
+
+use crate::{crash_report, error::Result, payload_limits::LimitExceeded};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    future::Future,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Seconds a maintenance lasts when no duration is given
+pub(crate) const DEFAULT_MAINTENANCE_DURATION: u64 = 3600;
+// The maintenance in progress, in work_dir, so that it outlasts a restart of
+// the agent until it ends
+pub(crate) const MAINTENANCE_FILE: &str = "maintenance.json";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RegistrationState {
+    Unregistered,
+    Registered,
+    Activated,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum PayloadState {
+    // Payloads are not accepted without mTLS or enable_insecure_payload
+    Disabled,
+    Waiting,
+    Delivered,
+    // Stored, but payload_script is not run, see run_payload_script
+    DeliveredExecutionDisabled,
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TaskState {
+    Running,
+    Finished,
+    Failed,
+}
+
+// Set with `keylime_agent admin deactivate`, quotes are refused until
+// `keylime_agent admin activate` or until it expires
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Maintenance {
+    // Seconds since the Unix epoch
+    pub since: u64,
+    pub until: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl Maintenance {
+    pub(crate) fn store(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+
+    // The stored maintenance, None when there is none or it expired
+    pub(crate) fn load(path: &Path) -> Option<Maintenance> {
+        let maintenance: Maintenance =
+            serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+        if maintenance.until <= now() {
+            return None;
+        }
+        Some(maintenance)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StatusReport {
+    pub agent_uuid: String,
+    pub version: String,
+    pub registration: RegistrationState,
+    // Seconds since the Unix epoch
+    pub last_quote: Option<u64>,
+    pub payload: PayloadState,
+    // Why the payload archive was refused, see payload_limits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_limit: Option<LimitExceeded>,
+    // Value of the TPM counter of payload deliveries, set when
+    // payload_counter_nv_index is, see payload_counter
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_counter: Option<u64>,
+    pub tasks: BTreeMap<String, TaskState>,
+    // Set once a revocation message for this agent has been processed
+    #[serde(default)]
+    pub revoked: bool,
+    // Set while U and V keys are refused, see lock_keys_after_bootstrap
+    #[serde(default)]
+    pub keys_locked: bool,
+    // Set while the agent is in maintenance mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<Maintenance>,
+    // Key verification challenges counted as failed, and peers locked out
+    // for sending too many, see verify_lockout
+    #[serde(default)]
+    pub verify_failures: u64,
+    #[serde(default)]
+    pub verify_lockouts: u64,
+    // TPM operations that missed their deadline, and whether no operation
+    // completed since the last one did
+    #[serde(default)]
+    pub tpm_timeouts: u64,
+    #[serde(default)]
+    pub tpm_stalled: bool,
+    // Set when the AK is no longer loaded in the TPM, see agent_data_audit
+    #[serde(default)]
+    pub tpm_diverged: bool,
+    // Times the connection to the TPM broke and was established again, see
+    // tpm::TpmManager
+    #[serde(default)]
+    pub tpm_reconnections: u64,
+    // Quote requests served from the quote cache and quoted, only known to
+    // the running agent, see quote_cache
+    #[serde(default)]
+    pub quote_cache_hits: u64,
+    #[serde(default)]
+    pub quote_cache_misses: u64,
+    // Transient TPM objects and sessions loaded per creating operation, only
+    // known to the running agent
+    #[serde(default)]
+    pub tpm_handles: BTreeMap<String, usize>,
+}
+
+impl StatusReport {
+    // Whether the agent can serve attestation requests: activated and with
+    // a responsive TPM that still holds its AK
+    pub(crate) fn ready(&self) -> bool {
+        self.registration == RegistrationState::Activated
+            && !self.tpm_stalled
+            && !self.tpm_diverged
+    }
+}
+
+impl fmt::Display for StatusReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Agent UUID:   {}", self.agent_uuid)?;
+        writeln!(f, "Version:      {}", self.version)?;
+        writeln!(f, "Registration: {:?}", self.registration)?;
+        match self.last_quote {
+            Some(time) => writeln!(f, "Last quote:   {} (Unix time)", time)?,
+            None => writeln!(f, "Last quote:   never")?,
+        }
+        match &self.payload_limit {
+            Some(exceeded) => writeln!(
+                f,
+                "Payload:      {:?}, archive with {}",
+                self.payload, exceeded
+            )?,
+            None => writeln!(f, "Payload:      {:?}", self.payload)?,
+        }
+        if let Some(counter) = self.payload_counter {
+            writeln!(f, "Payload counter: {}", counter)?;
+        }
+        writeln!(f, "Revoked:      {}", self.revoked)?;
+        writeln!(f, "Keys locked:  {}", self.keys_locked)?;
+        match &self.maintenance {
+            Some(Maintenance {
+                since,
+                until,
+                reason: Some(reason),
+            }) => writeln!(
+                f,
+                "Maintenance:  from {} to {} (Unix time), {}",
+                since, until, reason
+            )?,
+            Some(Maintenance { since, until, .. }) => writeln!(
+                f,
+                "Maintenance:  from {} to {} (Unix time)",
+                since, until
+            )?,
+            None => writeln!(f, "Maintenance:  no")?,
+        }
+        writeln!(
+            f,
+            "Verify:       {} failed, {} lockouts",
+            self.verify_failures, self.verify_lockouts
+        )?;
+        writeln!(f, "TPM timeouts: {}", self.tpm_timeouts)?;
+        writeln!(f, "TPM stalled:  {}", self.tpm_stalled)?;
+        writeln!(f, "TPM diverged: {}", self.tpm_diverged)?;
+        writeln!(f, "TPM reconnections: {}", self.tpm_reconnections)?;
+        writeln!(
+            f,
+            "Quote cache:  {} hits, {} misses",
+            self.quote_cache_hits, self.quote_cache_misses
+        )?;
+        writeln!(f, "Tasks:")?;
+        for (name, state) in &self.tasks {
+            writeln!(f, "  {:<20} {:?}", name, state)?;
+        }
+        writeln!(f, "TPM handles:")?;
+        for (owner, count) in &self.tpm_handles {
+            writeln!(f, "  {:<20} {}", owner, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct AgentStatus {
+    report: Mutex<StatusReport>,
+}
+
+impl AgentStatus {
+    pub(crate) fn new(agent_uuid: &str) -> Self {
+        AgentStatus {
+            report: Mutex::new(StatusReport {
+                agent_uuid: agent_uuid.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                registration: RegistrationState::Unregistered,
+                last_quote: None,
+                payload: PayloadState::Disabled,
+                payload_limit: None,
+                payload_counter: None,
+                tasks: BTreeMap::new(),
+                revoked: false,
+                keys_locked: false,
+                maintenance: None,
+                verify_failures: 0,
+                verify_lockouts: 0,
+                tpm_timeouts: 0,
+                tpm_stalled: false,
+                tpm_diverged: false,
+                tpm_reconnections: 0,
+                quote_cache_hits: 0,
+                quote_cache_misses: 0,
+                tpm_handles: BTreeMap::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn report(&self) -> StatusReport {
+        let report = self.report.lock().unwrap(); //#[allow_ci]
+        report.clone()
+    }
+
+    // Like report, but None rather than waiting when the status is locked,
+    // for the panic hook which may run while the status is being updated
+    pub(crate) fn try_report(&self) -> Option<StatusReport> {
+        self.report.try_lock().ok().map(|report| report.clone())
+    }
+
+    pub(crate) fn set_registration(&self, state: RegistrationState) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.registration = state;
+    }
+
+    pub(crate) fn set_payload(&self, state: PayloadState) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.payload = state;
+    }
+
+    pub(crate) fn set_payload_limit(&self, exceeded: LimitExceeded) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.payload_limit = Some(exceeded);
+    }
+
+    pub(crate) fn set_payload_counter(&self, counter: u64) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.payload_counter = Some(counter);
+    }
+
+    pub(crate) fn set_revoked(&self) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.revoked = true;
+    }
+
+    pub(crate) fn set_keys_locked(&self, locked: bool) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.keys_locked = locked;
+    }
+
+    /*
+     * Input: why the agent is put in maintenance and for how many seconds
+     * Return: the maintenance, or the one already in progress
+     */
+    pub(crate) fn start_maintenance(
+        &self,
+        reason: Option<String>,
+        duration: u64,
+    ) -> Maintenance {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        let now = now();
+        if matches!(&report.maintenance, Some(m) if m.until <= now) {
+            report.maintenance = None;
+        }
+        report
+            .maintenance
+            .get_or_insert_with(|| Maintenance {
+                since: now,
+                until: now.saturating_add(duration),
+                reason,
+            })
+            .clone()
+    }
+
+    // The maintenance stored before the agent restarted, see
+    // Maintenance::load
+    pub(crate) fn restore_maintenance(&self, maintenance: Maintenance) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.maintenance = Some(maintenance);
+    }
+
+    // Returns the maintenance that ended, None if there was none
+    pub(crate) fn end_maintenance(&self) -> Option<Maintenance> {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.maintenance.take()
+    }
+
+    // The maintenance in progress, None once it expired
+    pub(crate) fn maintenance(&self) -> Option<Maintenance> {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        if matches!(&report.maintenance, Some(m) if m.until <= now()) {
+            report.maintenance = None;
+        }
+        report.maintenance.clone()
+    }
+
+    pub(crate) fn verify_failed(&self, locked_out: bool) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.verify_failures += 1;
+        if locked_out {
+            report.verify_lockouts += 1;
+        }
+    }
+
+    pub(crate) fn tpm_timed_out(&self) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.tpm_timeouts += 1;
+        report.tpm_stalled = true;
+    }
+
+    pub(crate) fn tpm_completed(&self) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.tpm_stalled = false;
+    }
+
+    pub(crate) fn set_tpm_diverged(&self, diverged: bool) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.tpm_diverged = diverged;
+    }
+
+    pub(crate) fn set_task(&self, name: &str, state: TaskState) {
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        let _ = report.tasks.insert(name.to_string(), state);
+    }
+
+    pub(crate) fn quote_served(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let mut report = self.report.lock().unwrap(); //#[allow_ci]
+        report.last_quote = Some(now);
+    }
+}
+
+// Runs a background task, keeping its state up to date in the status
+pub(crate) async fn track<F, T>(
+    status: Arc<AgentStatus>,
+    name: &'static str,
+    task: F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    status.set_task(name, TaskState::Running);
+    crash_report::record("task", format!("{} started", name));
+    let result = task.await;
+    match result {
+        Ok(_) => status.set_task(name, TaskState::Finished),
+        Err(_) => status.set_task(name, TaskState::Failed),
+    }
+    crash_report::record("task", format!("{} stopped", name));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    #[actix_rt::test]
+    async fn test_track() {
+        let status = Arc::new(AgentStatus::new("uuid"));
+
+        let _ = track(status.clone(), "ok", async { Ok(()) }).await;
+        let _ = track(status.clone(), "failed", async {
+            Err::<(), Error>(Error::Other("failure".to_string()))
+        })
+        .await;
+
+        let report = status.report();
+        assert_eq!(report.tasks["ok"], TaskState::Finished);
+        assert_eq!(report.tasks["failed"], TaskState::Failed);
+    }
+
+    #[test]
+    fn test_report() {
+        let status = AgentStatus::new("uuid");
+        assert_eq!(status.report().last_quote, None);
+
+        status.set_registration(RegistrationState::Activated);
+        status.set_payload(PayloadState::Waiting);
+        status.quote_served();
+
+        let report = status.report();
+        assert_eq!(report.registration, RegistrationState::Activated);
+        assert_eq!(report.payload, PayloadState::Waiting);
+        assert!(report.last_quote.is_some());
+        assert!(report.to_string().contains("Activated"));
+        assert!(report.ready());
+
+        status.set_payload(PayloadState::DeliveredExecutionDisabled);
+        let report = serde_json::to_value(status.report()).unwrap(); //#[allow_ci]
+        assert_eq!(report["payload"], "delivered_execution_disabled");
+        assert!(report.get("payload_limit").is_none());
+        assert!(report.get("payload_counter").is_none());
+
+        status.set_payload_counter(7);
+        let report = status.report();
+        assert!(report.to_string().contains("Payload counter: 7\n"));
+        let report = serde_json::to_value(report).unwrap(); //#[allow_ci]
+        assert_eq!(report["payload_counter"], 7);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let status = AgentStatus::new("uuid");
+        assert!(status.report().to_string().contains("Maintenance:  no"));
+        assert!(status.end_maintenance().is_none());
+
+        let started =
+            status.start_maintenance(Some("kernel".to_string()), 3600);
+        assert_eq!(started.until, started.since + 3600);
+        // Starting again keeps the maintenance in progress
+        assert_eq!(status.start_maintenance(None, 60), started);
+        let report = status.report();
+        assert!(report.to_string().contains(", kernel\n"));
+        let report = serde_json::to_value(report).unwrap(); //#[allow_ci]
+        assert_eq!(report["maintenance"]["reason"], "kernel");
+
+        // Stored until it expires
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join(MAINTENANCE_FILE);
+        started.store(&path).unwrap(); //#[allow_ci]
+        assert_eq!(Maintenance::load(&path), Some(started.clone()));
+        let expired = Maintenance {
+            until: started.since,
+            ..started.clone()
+        };
+        expired.store(&path).unwrap(); //#[allow_ci]
+        assert_eq!(Maintenance::load(&path), None);
+
+        assert_eq!(status.end_maintenance(), Some(started));
+        assert!(status.maintenance().is_none());
+
+        // Expired maintenances end on their own
+        let _ = status.start_maintenance(None, 0);
+        assert!(status.maintenance().is_none());
+    }
+
+    #[test]
+    fn test_payload_limit() {
+        use crate::payload_limits::Limit;
+
+        let status = AgentStatus::new("uuid");
+        status.set_payload(PayloadState::Failed);
+        status.set_payload_limit(LimitExceeded {
+            limit: Limit::Entries,
+            max: 10000,
+            reached: 10001,
+        });
+
+        let report = status.report();
+        assert!(report
+            .to_string()
+            .contains("Failed, archive with more than 10000 entries"));
+        let report = serde_json::to_value(report).unwrap(); //#[allow_ci]
+        assert_eq!(
+            report["payload_limit"],
+            serde_json::json!({
+                "limit": "entries",
+                "max": 10000,
+                "reached": 10001
+            })
+        );
+    }
+
+    #[test]
+    fn test_verify_failures() {
+        let status = AgentStatus::new("uuid");
+        status.verify_failed(false);
+        status.verify_failed(true);
+        let report = status.report();
+        assert_eq!(report.verify_failures, 2);
+        assert_eq!(report.verify_lockouts, 1);
+        assert!(report.to_string().contains("2 failed, 1 lockouts"));
+    }
+
+    #[test]
+    fn test_tpm_timeouts() {
+        let status = AgentStatus::new("uuid");
+        status.set_registration(RegistrationState::Activated);
+
+        status.tpm_timed_out();
+        let report = status.report();
+        assert_eq!(report.tpm_timeouts, 1);
+        assert!(!report.ready());
+
+        status.tpm_completed();
+        let report = status.report();
+        assert_eq!(report.tpm_timeouts, 1);
+        assert!(report.ready());
+
+        status.set_tpm_diverged(true);
+        let report = status.report();
+        assert!(report.to_string().contains("TPM diverged: true"));
+        assert!(!report.ready());
+    }
+}
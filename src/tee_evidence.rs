@@ -0,0 +1,328 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Attestation reports of confidential VMs.
+//
+// In an AMD SEV-SNP or Intel TDX guest, the TPM is a vTPM emulated by the
+// guest firmware or a paravisor, and its quotes can only be trusted once the
+// verifier knows they come from a genuine TEE. With 'tee_evidence' set, the
+// agent asks the TEE for a report through the guest driver, /dev/sev-guest or
+// /dev/tdx_guest, and sends it with each quote. The 64 bytes of data the
+// report carries are the SHA-512 digest of the quote as sent, so that the
+// verifier can check the report was produced for this quote, and so is as
+// fresh as its nonce. The device is opened at start, as it is only accessible
+// to root.
+//
+// The SNP report is signed by the VCEK of the processor. The TDX report is
+// only verifiable on the same platform, the verifier is expected to have it
+// turned into a quote by the quoting enclave of the host.
+
+use crate::error::{Error, Result};
+use log::*;
+use openssl::hash::{hash, MessageDigest};
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::TryFrom,
+    fs::{File, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::Mutex,
+};
+
+static SEV_GUEST: &str = "/dev/sev-guest";
+static TDX_GUEST: &str = "/dev/tdx_guest";
+
+const REPORT_DATA_LEN: usize = 64;
+
+// _IOWR('S', 0x0, struct snp_guest_request_ioctl), linux/sev-guest.h
+const SNP_GET_REPORT: u64 = 0xc020_5300;
+const SNP_MSG_VERSION: u8 = 1;
+const SNP_RESP_LEN: usize = 4000;
+// Offset of the report in the response, after the status, the size and
+// reserved bytes
+const SNP_REPORT_OFFSET: usize = 32;
+
+// _IOWR('T', 1, struct tdx_report_req), linux/tdx-guest.h
+const TDX_CMD_GET_REPORT0: u64 = 0xc440_5401;
+const TDX_REPORT_LEN: usize = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TeePlatform {
+    SevSnp,
+    Tdx,
+}
+
+// Value of 'tee_evidence'
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TeeSource {
+    // The platform whose guest device exists
+    Auto,
+    Platform(TeePlatform),
+    Disabled,
+}
+
+impl TryFrom<&str> for TeeSource {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "auto" => Ok(TeeSource::Auto),
+            "sev_snp" => Ok(TeeSource::Platform(TeePlatform::SevSnp)),
+            "tdx" => Ok(TeeSource::Platform(TeePlatform::Tdx)),
+            "none" => Ok(TeeSource::Disabled),
+            _ => Err(Error::Configuration(format!(
+                "tee_evidence must be one of 'auto', 'sev_snp', 'tdx' or 'none', got {}",
+                value
+            ))),
+        }
+    }
+}
+
+// Sent with the quote as tee_evidence
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TeeEvidence {
+    pub platform: TeePlatform,
+    // Base64 encoded report, whose data is the SHA-512 digest of the quote
+    pub report: String,
+}
+
+#[repr(C)]
+struct SnpReportReq {
+    user_data: [u8; REPORT_DATA_LEN],
+    vmpl: u32,
+    rsvd: [u8; 28],
+}
+
+#[repr(C)]
+struct SnpGuestRequest {
+    msg_version: u8,
+    req_data: u64,
+    resp_data: u64,
+    // Firmware and hypervisor errors
+    exitinfo2: u64,
+}
+
+#[repr(C)]
+struct TdxReportReq {
+    reportdata: [u8; REPORT_DATA_LEN],
+    tdreport: [u8; TDX_REPORT_LEN],
+}
+
+fn device_path(platform: TeePlatform) -> &'static Path {
+    match platform {
+        TeePlatform::SevSnp => Path::new(SEV_GUEST),
+        TeePlatform::Tdx => Path::new(TDX_GUEST),
+    }
+}
+
+// Report of the SNP response: status, size, reserved bytes and the report
+fn parse_snp_response(resp: &[u8]) -> Result<Vec<u8>> {
+    let field = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&resp[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+    let status = field(0);
+    if status != 0 {
+        return Err(Error::Other(format!(
+            "the SEV firmware refused the report request, status {:#x}",
+            status
+        )));
+    }
+    let size = field(4) as usize;
+    resp.get(SNP_REPORT_OFFSET..SNP_REPORT_OFFSET + size)
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| {
+            Error::Other(format!("invalid SEV report size {}", size))
+        })
+}
+
+fn ioctl<T>(file: &File, request: u64, arg: &mut T) -> io::Result<()> {
+    let ret =
+        unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg as *mut T) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn snp_report(
+    file: &File,
+    report_data: [u8; REPORT_DATA_LEN],
+) -> Result<Vec<u8>> {
+    let mut req = SnpReportReq {
+        user_data: report_data,
+        vmpl: 0,
+        rsvd: [0u8; 28],
+    };
+    let mut resp = vec![0u8; SNP_RESP_LEN];
+    let mut request = SnpGuestRequest {
+        msg_version: SNP_MSG_VERSION,
+        req_data: &mut req as *mut SnpReportReq as u64,
+        resp_data: resp.as_mut_ptr() as u64,
+        exitinfo2: 0,
+    };
+    ioctl(file, SNP_GET_REPORT, &mut request).map_err(|e| {
+        Error::Other(format!(
+            "SNP_GET_REPORT failed: {}, exitinfo2 {:#x}",
+            e, request.exitinfo2
+        ))
+    })?;
+    parse_snp_response(&resp)
+}
+
+fn tdx_report(
+    file: &File,
+    report_data: [u8; REPORT_DATA_LEN],
+) -> Result<Vec<u8>> {
+    let mut req = TdxReportReq {
+        reportdata: report_data,
+        tdreport: [0u8; TDX_REPORT_LEN],
+    };
+    ioctl(file, TDX_CMD_GET_REPORT0, &mut req).map_err(|e| {
+        Error::Other(format!("TDX_CMD_GET_REPORT0 failed: {}", e))
+    })?;
+    Ok(req.tdreport.to_vec())
+}
+
+// Data of the report bound to the quote
+pub(crate) fn report_data(quote: &str) -> Result<[u8; REPORT_DATA_LEN]> {
+    let digest = hash(MessageDigest::sha512(), quote.as_bytes())?;
+    let mut data = [0u8; REPORT_DATA_LEN];
+    data.copy_from_slice(&digest);
+    Ok(data)
+}
+
+#[derive(Debug)]
+pub(crate) struct TeeGuest {
+    platform: TeePlatform,
+    device: Mutex<File>,
+}
+
+impl TeeGuest {
+    fn open_platform(platform: TeePlatform) -> Result<Self> {
+        let device = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device_path(platform))?;
+        Ok(TeeGuest {
+            platform,
+            device: Mutex::new(device),
+        })
+    }
+
+    /*
+     * Input: value of tee_evidence
+     * Return: the guest device, None when disabled or not available
+     *
+     * A configured platform whose device cannot be opened is an error, as
+     * verifiers requiring the reports would fail the agent.
+     */
+    pub(crate) fn open(source: TeeSource) -> Result<Option<Self>> {
+        let guest = match source {
+            TeeSource::Disabled => return Ok(None),
+            TeeSource::Platform(platform) => {
+                Self::open_platform(platform).map_err(|e| {
+                    Error::Configuration(format!(
+                        "tee_evidence is set to {:?} but {} cannot be opened: {}",
+                        platform,
+                        device_path(platform).display(),
+                        e
+                    ))
+                })?
+            }
+            TeeSource::Auto => {
+                let platform = [TeePlatform::SevSnp, TeePlatform::Tdx]
+                    .iter()
+                    .copied()
+                    .find(|&platform| device_path(platform).exists());
+                match platform {
+                    Some(platform) => Self::open_platform(platform)?,
+                    None => {
+                        debug!("Not running in a confidential VM, no TEE evidence");
+                        return Ok(None);
+                    }
+                }
+            }
+        };
+        info!(
+            "Sending {:?} attestation reports with quotes",
+            guest.platform
+        );
+        Ok(Some(guest))
+    }
+
+    /*
+     * Input: quote, as sent to the verifier
+     * Return: Result wrap the report bound to the quote
+     */
+    pub(crate) fn evidence(&self, quote: &str) -> Result<TeeEvidence> {
+        let report_data = report_data(quote)?;
+        let device = self.device.lock().unwrap(); //#[allow_ci]
+        let report = match self.platform {
+            TeePlatform::SevSnp => snp_report(&device, report_data)?,
+            TeePlatform::Tdx => tdx_report(&device, report_data)?,
+        };
+        Ok(TeeEvidence {
+            platform: self.platform,
+            report: base64::encode(report),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_source() {
+        assert_eq!(
+            TeeSource::try_from("sev_snp").unwrap(), //#[allow_ci]
+            TeeSource::Platform(TeePlatform::SevSnp)
+        );
+        assert_eq!(
+            TeeSource::try_from("none").unwrap(), //#[allow_ci]
+            TeeSource::Disabled
+        );
+        assert!(TeeSource::try_from("sev").is_err());
+    }
+
+    #[test]
+    fn test_ioctl_sizes() {
+        // The sizes encoded in the ioctl numbers
+        assert_eq!(std::mem::size_of::<SnpGuestRequest>(), 32);
+        assert_eq!((SNP_GET_REPORT >> 16) & 0x3fff, 32);
+        assert_eq!(std::mem::size_of::<TdxReportReq>(), 1088);
+        assert_eq!((TDX_CMD_GET_REPORT0 >> 16) & 0x3fff, 1088);
+        assert_eq!(std::mem::size_of::<SnpReportReq>(), 96);
+    }
+
+    #[test]
+    fn test_parse_snp_response() {
+        let mut resp = vec![0u8; SNP_RESP_LEN];
+        resp[4..8].copy_from_slice(&1184u32.to_le_bytes());
+        resp[SNP_REPORT_OFFSET] = 2;
+        let report = parse_snp_response(&resp).unwrap(); //#[allow_ci]
+        assert_eq!(report.len(), 1184);
+        assert_eq!(report[0], 2);
+
+        resp[0] = 0x16;
+        assert!(parse_snp_response(&resp).is_err());
+        resp[0] = 0;
+        resp[4..8].copy_from_slice(&4000u32.to_le_bytes());
+        assert!(parse_snp_response(&resp).is_err());
+    }
+
+    #[test]
+    fn test_report_data() {
+        let data = report_data("r1234").unwrap(); //#[allow_ci]
+        assert_eq!(
+            data.to_vec(),
+            hash(MessageDigest::sha512(), b"r1234").unwrap().to_vec() //#[allow_ci]
+        );
+        assert_ne!(data, report_data("r1235").unwrap()); //#[allow_ci]
+    }
+}
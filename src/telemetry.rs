@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// OpenTelemetry traces of the attestation flows.
+//
+// With the 'otel' feature, spans are exported over OTLP to the collector set
+// by the standard OTEL_EXPORTER_OTLP_ENDPOINT environment variable. Without
+// it, the same calls compile to nothing, so the instrumented code does not
+// need to be conditional.
+//
+// A span started with span() is a child of the current span. Making a span
+// current with attach() is only valid in code that does not await while the
+// guard is alive; async code creates its children explicitly with child().
+
+use crate::error::Result;
+use std::fmt::Display;
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "otel")] {
+        use crate::error::Error;
+        use opentelemetry::{
+            global,
+            sdk::{trace, Resource},
+            trace::{StatusCode, TraceContextExt, Tracer},
+            Context, ContextGuard, KeyValue,
+        };
+
+        static TRACER_NAME: &str = "keylime_agent";
+
+        #[derive(Debug)]
+        pub(crate) struct Span {
+            cx: Context,
+        }
+
+        pub(crate) struct Guard(ContextGuard);
+
+        pub(crate) fn span(name: &'static str) -> Span {
+            let span = global::tracer(TRACER_NAME).start(name);
+            Span {
+                cx: Context::current_with_span(span),
+            }
+        }
+
+        impl Span {
+            pub(crate) fn child(&self, name: &'static str) -> Span {
+                let span = global::tracer(TRACER_NAME)
+                    .start_with_context(name, &self.cx);
+                Span {
+                    cx: Context::current_with_span(span),
+                }
+            }
+
+            pub(crate) fn attach(&self) -> Guard {
+                Guard(self.cx.clone().attach())
+            }
+
+            pub(crate) fn set_attribute(
+                &self,
+                key: &'static str,
+                value: String,
+            ) {
+                self.cx.span().set_attribute(KeyValue::new(key, value));
+            }
+
+            pub(crate) fn record<T, E: Display>(
+                &self,
+                result: &std::result::Result<T, E>,
+            ) {
+                if let Err(e) = result {
+                    self.cx
+                        .span()
+                        .set_status(StatusCode::Error, e.to_string());
+                }
+            }
+        }
+
+        impl Drop for Span {
+            fn drop(&mut self) {
+                self.cx.span().end();
+            }
+        }
+
+        // Installs the OTLP exporter, spans are sent in batches from the
+        // Tokio runtime
+        pub(crate) fn init() -> Result<()> {
+            let _ = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+                .with_trace_config(trace::config().with_resource(
+                    Resource::new(vec![KeyValue::new(
+                        "service.name",
+                        TRACER_NAME,
+                    )]),
+                ))
+                .install_batch(opentelemetry::runtime::Tokio)
+                .map_err(|e| {
+                    Error::Other(format!(
+                        "unable to set up the OpenTelemetry exporter: {}",
+                        e
+                    ))
+                })?;
+            Ok(())
+        }
+
+        // Sends the spans not exported yet
+        pub(crate) fn shutdown() {
+            global::shutdown_tracer_provider();
+        }
+    } else {
+        #[derive(Debug)]
+        pub(crate) struct Span;
+
+        pub(crate) struct Guard;
+
+        pub(crate) fn span(_name: &'static str) -> Span {
+            Span
+        }
+
+        impl Span {
+            pub(crate) fn child(&self, _name: &'static str) -> Span {
+                Span
+            }
+
+            pub(crate) fn attach(&self) -> Guard {
+                Guard
+            }
+
+            pub(crate) fn set_attribute(
+                &self,
+                _key: &'static str,
+                _value: String,
+            ) {
+            }
+
+            pub(crate) fn record<T, E: Display>(
+                &self,
+                _result: &std::result::Result<T, E>,
+            ) {
+            }
+        }
+
+        pub(crate) fn init() -> Result<()> {
+            Ok(())
+        }
+
+        pub(crate) fn shutdown() {}
+    }
+}
@@ -5,13 +5,25 @@
 use log::*;
 use serde::ser::{Error, Serialize, SerializeStruct, Serializer};
 use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::convert::{TryFrom, TryInto};
 use std::io::prelude::*;
+use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
+use std::sync::{
+    atomic::{AtomicU32, AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::{Duration, Instant};
 use tss_esapi::structures::PublicBuffer;
 
 use crate::{
-    quotes_handler::KeylimeQuote, Error as KeylimeError, QuoteData, Result,
+    algorithms::{
+        EccCurve, EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+    },
+    common::{TPM_RETRY_ATTEMPTS, TPM_RETRY_DELAY},
+    quotes_handler::KeylimeQuote,
+    telemetry, Error as KeylimeError, QuoteData, Result,
 };
 
 use actix_web::web::Data;
@@ -20,6 +32,7 @@ use openssl::{
     hash::{Hasher, MessageDigest},
     memcmp,
     pkey::{Id, PKeyRef, Public},
+    rand::rand_bytes,
 };
 
 use tss_esapi::{
@@ -28,26 +41,40 @@ use tss_esapi::{
         cipher::Cipher,
         ek,
         pcr::{read_all, PcrData},
-        DefaultKey,
+        DefaultKey, KeyCustomization,
+    },
+    attributes::{
+        object::ObjectAttributesBuilder, session::SessionAttributesBuilder,
+        NvIndexAttributesBuilder,
     },
-    attributes::session::SessionAttributesBuilder,
     constants::{
+        response_code::{Tss2ResponseCode, Tss2ResponseCodeKind},
         session_type::SessionType,
         tss::{TPM2_ALG_NULL, TPM2_ST_ATTEST_QUOTE},
+        CapabilityType, NvIndexType, PropertyTag,
     },
     handles::{
-        AuthHandle, KeyHandle, PcrHandle, PersistentTpmHandle, TpmHandle,
+        AuthHandle, KeyHandle, NvIndexHandle, NvIndexTpmHandle, ObjectHandle,
+        PcrHandle, PersistentTpmHandle, SessionHandle, TpmHandle,
     },
     interface_types::{
         algorithm::{
-            AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm,
+            AsymmetricAlgorithm, EccSchemeAlgorithm, HashingAlgorithm,
+            PublicAlgorithm, RsaSchemeAlgorithm, SignatureSchemeAlgorithm,
         },
+        key_bits::RsaKeyBits,
+        resource_handles::{Hierarchy, NvAuth, Provision},
         session_handles::AuthSession,
     },
     structures::{
-        Attest, AttestInfo, Digest, DigestValues, EncryptedSecret,
-        HashScheme, IdObject, Name, PcrSelectionList,
-        PcrSelectionListBuilder, PcrSlot, Signature, SignatureScheme,
+        Attest, AttestInfo, CapabilityData, Digest, DigestValues,
+        EccParameter, EccPoint, EccScheme, EncryptedSecret, HashScheme,
+        IdObject, KeyDerivationFunctionScheme, KeyedHashScheme, Name,
+        NvPublicBuilder, PcrSelectionList, PcrSelectionListBuilder, PcrSlot,
+        Private, PublicBuilder, PublicEccParameters,
+        PublicEccParametersBuilder, PublicKeyRsa, PublicKeyedHashParameters,
+        PublicRsaParametersBuilder, RsaExponent, RsaScheme, SensitiveData,
+        Signature, SignatureScheme, SymmetricDefinitionObject,
     },
     tcti_ldr::TctiNameConf,
     traits::Marshall,
@@ -69,15 +96,50 @@ pub const TPML_PCR_SELECTION_SIZE: usize =
 pub const TPMS_PCR_SELECTION_SIZE: usize =
     std::mem::size_of::<TPMS_PCR_SELECTION>();
 
+// TCTI set with the 'tcti' option, set from the configuration at startup
+static TCTI: Mutex<Option<String>> = Mutex::new(None);
+
+// The TCTI backends offered by the TSS, see README.md
+const TCTI_BACKENDS: [&str; 4] = ["device", "mssim", "swtpm", "tabrmd"];
+
 /*
- * Input: None
- * Return: Connection context
+ * Input: TCTI name and configuration, e.g. "swtpm:host=localhost,port=2321"
+ * Return: the parsed TCTI
  *
- * Example call:
- * let mut ctx = tpm::get_tpm2_ctx();
+ * Fails with a configuration error naming the supported backends when the
+ * backend is unknown.
  */
-pub(crate) fn get_tpm2_ctx() -> Result<Context> {
-    let tcti_path = match std::env::var("TCTI") {
+pub(crate) fn parse_tcti(value: &str) -> Result<TctiNameConf> {
+    let backend = value.split(':').next().unwrap_or_default();
+    if !TCTI_BACKENDS.contains(&backend) {
+        return Err(KeylimeError::Configuration(format!(
+            "TCTI backend '{}' is not supported, use one of {}, e.g. device:/dev/tpmrm0 or swtpm:host=localhost,port=2321",
+            backend,
+            TCTI_BACKENDS.join(", ")
+        )));
+    }
+    TctiNameConf::from_str(value).map_err(|e| {
+        KeylimeError::Configuration(format!(
+            "Invalid TCTI configuration '{}': {}",
+            value, e
+        ))
+    })
+}
+
+// Sets the TCTI used to connect to the TPM, None to fall back to the TCTI
+// environment variable
+pub(crate) fn set_tcti(tcti: Option<String>) {
+    *TCTI.lock().unwrap() = tcti; //#[allow_ci]
+}
+
+// The TCTI used to connect to the TPM: the one set in the configuration,
+// the one of the TCTI environment variable, or the TPM device otherwise
+pub(crate) fn tcti_name() -> String {
+    let configured = TCTI.lock().unwrap().clone(); //#[allow_ci]
+    if let Some(tcti) = configured {
+        return tcti;
+    }
+    match std::env::var("TCTI") {
         Ok(val) => val,
         Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
             "device:/dev/tpmrm0"
@@ -85,12 +147,484 @@ pub(crate) fn get_tpm2_ctx() -> Result<Context> {
             "device:/dev/tpm0"
         }
         .to_string(),
-    };
+    }
+}
 
-    let tcti = TctiNameConf::from_str(&tcti_path)?;
+/*
+ * Input: None
+ * Return: Connection context
+ *
+ * Example call:
+ * let mut ctx = tpm::get_tpm2_ctx();
+ */
+pub(crate) fn get_tpm2_ctx() -> Result<Context> {
+    let tcti = parse_tcti(&tcti_name())?;
     Context::new(tcti).map_err(|e| e.into())
 }
 
+// Delays between attempts to connect to a TPM that is not available yet
+const TPM_WAIT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const TPM_WAIT_MAX_DELAY: Duration = Duration::from_secs(16);
+
+// Doubles the delay, without exceeding the maximum delay or the time left
+fn next_tpm_wait_delay(delay: Duration, remaining: Duration) -> Duration {
+    std::cmp::min(std::cmp::min(delay * 2, TPM_WAIT_MAX_DELAY), remaining)
+}
+
+/*
+ * Input: How long to keep retrying
+ * Return: Connection context
+ *
+ * Like get_tpm2_ctx, but keeps retrying with an exponential backoff until
+ * the timeout expires. In VMs the vTPM device can be hotplugged after the
+ * agent started.
+ */
+pub(crate) async fn wait_for_tpm2_ctx(timeout: Duration) -> Result<Context> {
+    let start = std::time::Instant::now();
+    let mut delay = TPM_WAIT_INITIAL_DELAY;
+
+    loop {
+        match get_tpm2_ctx() {
+            Ok(ctx) => {
+                if start.elapsed() >= TPM_WAIT_INITIAL_DELAY {
+                    info!(
+                        "TPM became available after {} seconds",
+                        start.elapsed().as_secs()
+                    );
+                }
+                return Ok(ctx);
+            }
+            Err(e) => {
+                let remaining = timeout.saturating_sub(start.elapsed());
+                if remaining == Duration::ZERO {
+                    return Err(e);
+                }
+                delay = std::cmp::min(delay, remaining);
+                warn!(
+                    "TPM not available ({}), retrying in {} seconds",
+                    e,
+                    delay.as_secs_f32()
+                );
+                tokio::time::sleep(delay).await;
+                delay = next_tpm_wait_delay(delay, remaining);
+            }
+        }
+    }
+}
+
+// How TPM commands the TPM asked to resubmit are retried, set from the
+// configuration at startup. The TSS already resubmits them a few times in a
+// row, which is not enough for a TPM under contention or running its self
+// tests.
+static RETRY_ATTEMPTS: AtomicU32 = AtomicU32::new(TPM_RETRY_ATTEMPTS);
+static RETRY_DELAY_MS: AtomicU64 = AtomicU64::new(TPM_RETRY_DELAY);
+const TPM_RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+// Contention on the TPM since the start of the agent: commands resubmitted,
+// commands refused at least once, commands still refused after all attempts
+// and the time spent waiting between attempts
+static RETRIES: AtomicU64 = AtomicU64::new(0);
+static RETRIES_CONTENDED: AtomicU64 = AtomicU64::new(0);
+static RETRIES_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+static RETRY_WAIT_MS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct RetryCounts {
+    pub retries: u64,
+    pub contended: u64,
+    pub exhausted: u64,
+    pub wait_ms: u64,
+}
+
+/*
+ * Input: number of attempts and delay before the first retry, in
+ *        milliseconds
+ *
+ * Sets how commands are retried by retry_tpm_command.
+ */
+pub(crate) fn set_retry_policy(attempts: u32, delay_ms: u64) {
+    RETRY_ATTEMPTS.store(attempts, Ordering::Relaxed);
+    RETRY_DELAY_MS.store(delay_ms, Ordering::Relaxed);
+}
+
+// Contention met by retry_tpm_command
+pub(crate) fn retry_counts() -> RetryCounts {
+    RetryCounts {
+        retries: RETRIES.load(Ordering::Relaxed),
+        contended: RETRIES_CONTENDED.load(Ordering::Relaxed),
+        exhausted: RETRIES_EXHAUSTED.load(Ordering::Relaxed),
+        wait_ms: RETRY_WAIT_MS.load(Ordering::Relaxed),
+    }
+}
+
+// Picks the wait before a retry between half the backoff delay and the full
+// delay, from a random value. When the device is shared, e.g. /dev/tpmrm0 by
+// the pods of a node, the clients refused by the same busy TPM would
+// otherwise retry in lockstep and collide again.
+fn jittered_delay(delay: Duration, random: u32) -> Duration {
+    let half = delay / 2;
+    half + half.mul_f64(f64::from(random) / f64::from(u32::MAX))
+}
+
+fn random_u32() -> u32 {
+    let mut bytes = [0u8; 4];
+    match rand_bytes(&mut bytes) {
+        Ok(()) => u32::from_ne_bytes(bytes),
+        Err(_) => u32::MAX,
+    }
+}
+
+// The TPM did not run the command and asks for it to be resubmitted: it is
+// busy (TPM_RC_RETRY), yielded to another command (TPM_RC_YIELDED) or is
+// still running its self tests (TPM_RC_TESTING)
+fn is_retryable(err: &tss_esapi::Error) -> bool {
+    matches!(
+        err,
+        tss_esapi::Error::Tss2Error(rc) if matches!(
+            rc.kind(),
+            Some(Tss2ResponseCodeKind::Retry)
+                | Some(Tss2ResponseCodeKind::Yielded)
+                | Some(Tss2ResponseCodeKind::Testing)
+        )
+    )
+}
+
+/*
+ * Input: ESAPI call
+ * Return: the result of the first attempt that was not refused as
+ *         retryable, or the last refusal
+ *
+ * Resubmits the call with an exponential backoff and jitter while the TPM
+ * asks for it. Only single TPM commands are wrapped, as the command was not
+ * run when the TPM refuses it, while retrying a sequence would run its first
+ * commands again. Called on the TPM thread, so sleeping only delays the
+ * commands queued behind.
+ */
+pub(crate) fn retry_tpm_command<T>(
+    mut call: impl FnMut() -> tss_esapi::Result<T>,
+) -> tss_esapi::Result<T> {
+    let attempts = RETRY_ATTEMPTS.load(Ordering::Relaxed).max(1);
+    let mut delay =
+        Duration::from_millis(RETRY_DELAY_MS.load(Ordering::Relaxed));
+    let mut attempt = 1;
+    loop {
+        match call() {
+            Err(e) if is_retryable(&e) => {
+                // Counted even when retries are off, to show the contention
+                if attempt == 1 {
+                    let _ = RETRIES_CONTENDED.fetch_add(1, Ordering::Relaxed);
+                }
+                if attempt >= attempts {
+                    warn!(
+                        "TPM still busy after {} attempts: {}",
+                        attempts, e
+                    );
+                    let _ = RETRIES_EXHAUSTED.fetch_add(1, Ordering::Relaxed);
+                    return Err(e);
+                }
+                let wait = jittered_delay(delay, random_u32());
+                debug!(
+                    "TPM asked to retry the command ({}), attempt {} of {} in {} ms",
+                    e,
+                    attempt + 1,
+                    attempts,
+                    wait.as_millis()
+                );
+                let _ = RETRIES.fetch_add(1, Ordering::Relaxed);
+                let _ = RETRY_WAIT_MS
+                    .fetch_add(wait.as_millis() as u64, Ordering::Relaxed);
+                std::thread::sleep(wait);
+                delay = std::cmp::min(delay * 2, TPM_RETRY_MAX_DELAY);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Transient objects and sessions loaded in the TPM by the agent.
+//
+// Each handle is registered with the operation that created it and its
+// creation time, and released once flushed. Anything else left in the
+// registry is a leak, the TPM only holds a few transient objects and
+// sessions at a time, so leaks end up failing every operation.
+//
+// TPM operations are serialized by the lock on the context, so when one of
+// them fails because the TPM is out of object memory, every registered
+// handle that is not kept across operations is left over from an earlier
+// operation and can be flushed.
+#[derive(Debug, Default)]
+pub(crate) struct HandleRegistry {
+    handles: Mutex<Vec<TrackedHandle>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TrackedHandle {
+    handle: ObjectHandle,
+    owner: &'static str,
+    created: Instant,
+    // Still needed after the operation that loaded it, like the AK
+    keep: bool,
+}
+
+impl TrackedHandle {
+    fn warn_leaked(&self, action: &str) {
+        warn!(
+            "TPM handle {:?} created by {} {} seconds ago was leaked, {}",
+            self.handle,
+            self.owner,
+            self.created.elapsed().as_secs(),
+            action
+        );
+    }
+}
+
+fn count_owners(handles: &[TrackedHandle]) -> BTreeMap<String, usize> {
+    let mut counts = BTreeMap::new();
+    for tracked in handles {
+        *counts.entry(tracked.owner.to_string()).or_insert(0) += 1;
+    }
+    counts
+}
+
+impl HandleRegistry {
+    pub(crate) fn register(
+        &self,
+        handle: ObjectHandle,
+        owner: &'static str,
+        keep: bool,
+    ) {
+        let mut handles = self.handles.lock().unwrap(); //#[allow_ci]
+        handles.push(TrackedHandle {
+            handle,
+            owner,
+            created: Instant::now(),
+            keep,
+        });
+    }
+
+    // To be called once the handle was flushed
+    pub(crate) fn release(&self, handle: ObjectHandle) {
+        let mut handles = self.handles.lock().unwrap(); //#[allow_ci]
+        handles.retain(|tracked| tracked.handle != handle);
+    }
+
+    // Number of registered handles per owner
+    pub(crate) fn counts(&self) -> BTreeMap<String, usize> {
+        let handles = self.handles.lock().unwrap(); //#[allow_ci]
+        count_owners(&handles)
+    }
+
+    // Like counts, but None rather than waiting when the registry is locked
+    pub(crate) fn try_counts(&self) -> Option<BTreeMap<String, usize>> {
+        self.handles
+            .try_lock()
+            .ok()
+            .map(|handles| count_owners(&handles))
+    }
+
+    fn take_stale(&self) -> Vec<TrackedHandle> {
+        let mut handles = self.handles.lock().unwrap(); //#[allow_ci]
+        let (keep, stale) = handles.iter().partition(|tracked| tracked.keep);
+        *handles = keep;
+        stale
+    }
+
+    /*
+     * Input: Connection context
+     * Return: Number of handles flushed
+     *
+     * Flushes the handles that are not kept across operations. Handles the
+     * TPM already dropped are only forgotten.
+     */
+    pub(crate) fn flush_stale(&self, context: &mut Context) -> usize {
+        let stale = self.take_stale();
+        for tracked in &stale {
+            tracked.warn_leaked("flushing it");
+            if let Err(e) = context.flush_context(tracked.handle) {
+                debug!(
+                    "Unable to flush stale handle {:?}: {}",
+                    tracked.handle, e
+                );
+            }
+        }
+        stale.len()
+    }
+
+    // Forgets every handle, once the connection they were loaded through is
+    // gone along with them
+    pub(crate) fn forget_all(&self) {
+        let mut handles = self.handles.lock().unwrap(); //#[allow_ci]
+        handles.clear();
+    }
+}
+
+impl Drop for HandleRegistry {
+    fn drop(&mut self) {
+        if let Ok(handles) = self.handles.get_mut() {
+            for tracked in handles.iter().filter(|tracked| !tracked.keep) {
+                tracked.warn_leaked("it was never flushed");
+            }
+        }
+    }
+}
+
+// Reconnection to the TPM after communication errors.
+//
+// When the connection to the TPM breaks, e.g. the kernel resource manager
+// behind /dev/tpmrm0 restarted, every command fails with a TCTI error and the
+// transient objects loaded through the connection are gone. The TPM manager
+// then opens a new context, loads the keys of the agent in it again and the
+// TPM service runs the failed operation once more. The rest of the agent
+// keeps the handles the keys got at startup, which are translated to the
+// handles of the reloaded keys. Until the keys to reload are set, once the
+// agent is registered, errors are returned as they are.
+
+// Layers and base codes of the TSS response codes, as in tss2_common.h
+const TSS2_RC_LAYER_SHIFT: u32 = 16;
+const TSS2_TCTI_RC_LAYER: u32 = 10 << TSS2_RC_LAYER_SHIFT;
+const TSS2_RESMGR_RC_LAYER: u32 = 11 << TSS2_RC_LAYER_SHIFT;
+const TSS2_BASE_RC_NO_CONNECTION: u32 = 8;
+const TSS2_BASE_RC_IO_ERROR: u32 = 10;
+
+// The command did not reach the TPM, or its response was lost, because the
+// connection to the device or to the resource manager broke
+fn is_communication_error(err: &KeylimeError) -> bool {
+    let err = match err {
+        KeylimeError::Tpm { err, .. } => err,
+        _ => return false,
+    };
+    [TSS2_TCTI_RC_LAYER, TSS2_RESMGR_RC_LAYER]
+        .iter()
+        .flat_map(|layer| {
+            [TSS2_BASE_RC_NO_CONNECTION, TSS2_BASE_RC_IO_ERROR]
+                .map(|code| layer | code)
+        })
+        .any(|rc| {
+            *err == tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(rc))
+        })
+}
+
+// Loads the keys of the agent in a new context, and returns the pairs of
+// handles the keys had at startup and in the new context
+pub(crate) type ReloadKeys = Box<
+    dyn FnMut(&mut Context) -> Result<Vec<(KeyHandle, KeyHandle)>> + Send,
+>;
+
+// Handles of the reloaded keys, shared with the callers of the TPM service
+#[derive(Debug, Default)]
+pub(crate) struct ReloadedKeys {
+    reconnections: AtomicU64,
+    handles: Mutex<Vec<(KeyHandle, KeyHandle)>>,
+}
+
+impl ReloadedKeys {
+    /*
+     * Input: handle of a key loaded at startup
+     * Return: handle of the key in the current context
+     *
+     * Only consistent on the TPM thread, where the context is not replaced
+     * while an operation runs.
+     */
+    pub(crate) fn get(&self, handle: KeyHandle) -> KeyHandle {
+        let handles = self.handles.lock().unwrap(); //#[allow_ci]
+        handles
+            .iter()
+            .find(|(loaded, _)| *loaded == handle)
+            .map(|(_, reloaded)| *reloaded)
+            .unwrap_or(handle)
+    }
+
+    // Number of times the connection to the TPM was established again
+    pub(crate) fn reconnections(&self) -> u64 {
+        self.reconnections.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct TpmManager {
+    context: Context,
+    reload: Option<ReloadKeys>,
+    keys: Arc<ReloadedKeys>,
+}
+
+impl std::fmt::Debug for TpmManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("TpmManager")
+            .field("context", &self.context)
+            .field("reconnections", &self.keys.reconnections())
+            .finish()
+    }
+}
+
+impl Deref for TpmManager {
+    type Target = Context;
+
+    fn deref(&self) -> &Context {
+        &self.context
+    }
+}
+
+impl DerefMut for TpmManager {
+    fn deref_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+}
+
+impl TpmManager {
+    pub(crate) fn new(context: Context) -> Self {
+        TpmManager {
+            context,
+            reload: None,
+            keys: Arc::new(ReloadedKeys::default()),
+        }
+    }
+
+    pub(crate) fn keys(&self) -> Arc<ReloadedKeys> {
+        self.keys.clone()
+    }
+
+    // Enables the reconnection, with how to load the keys again
+    pub(crate) fn set_reload(&mut self, reload: ReloadKeys) {
+        self.reload = Some(reload);
+    }
+
+    /*
+     * Input: error of a failed operation
+     * Return: whether the connection was established again, so that an
+     *         idempotent operation can be run again
+     *
+     * When reconnecting fails, the next operation failing tries again.
+     */
+    pub(crate) fn recover(&mut self, error: &KeylimeError) -> bool {
+        if self.reload.is_none() || !is_communication_error(error) {
+            return false;
+        }
+        warn!("Lost the connection to the TPM ({}), reconnecting", error);
+        match self.reconnect() {
+            Ok(()) => {
+                info!("Reconnected to the TPM and reloaded the keys");
+                true
+            }
+            Err(e) => {
+                error!("Unable to reconnect to the TPM: {}", e);
+                false
+            }
+        }
+    }
+
+    fn reconnect(&mut self) -> Result<()> {
+        let mut context = get_tpm2_ctx()?;
+        let handles = match self.reload.as_mut() {
+            Some(reload) => reload(&mut context)?,
+            None => Vec::new(),
+        };
+        // Dropping the previous context closes the broken connection
+        self.context = context;
+        *self.keys.handles.lock().unwrap() = handles; //#[allow_ci]
+        let _ = self.keys.reconnections.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
 // Holds the output of create_ek
 #[derive(Clone, Debug)]
 pub struct EKResult {
@@ -100,13 +634,14 @@ pub struct EKResult {
 }
 
 /*
- * Input: Connection context, asymmetric algo, existing key handle in hex (optional)
+ * Input: Connection context, handle registry, asymmetric algo, existing key handle in hex (optional)
  * Return: (Key handle, public cert, TPM public object)
  * Example call:
- * let (key, cert, tpm_pub) = tpm::create_ek(context, AsymmetricAlgorithm::Rsa, None)
+ * let (key, cert, tpm_pub) = tpm::create_ek(context, handles, AsymmetricAlgorithm::Rsa, None)
  */
 pub(crate) fn create_ek(
     context: &mut Context,
+    handles: &HandleRegistry,
     alg: AsymmetricAlgorithm,
     handle: Option<&str>,
 ) -> Result<EKResult> {
@@ -120,7 +655,11 @@ pub(crate) fn create_ek(
                 ))?
                 .into()
         }
-        None => ek::create_ek_object(context, alg, DefaultKey)?,
+        None => {
+            let key_handle = ek::create_ek_object(context, alg, DefaultKey)?;
+            handles.register(key_handle.into(), "create_ek", false);
+            key_handle
+        }
     };
     let cert = match ek::retrieve_ek_pubcert(context, alg) {
         Ok(v) => Some(v),
@@ -129,7 +668,8 @@ pub(crate) fn create_ek(
             None
         }
     };
-    let (tpm_pub, _, _) = context.read_public(key_handle)?;
+    let (tpm_pub, _, _) =
+        retry_tpm_command(|| context.read_public(key_handle))?;
     Ok(EKResult {
         key_handle,
         ek_cert: cert,
@@ -137,6 +677,387 @@ pub(crate) fn create_ek(
     })
 }
 
+// Returns the public area of the RSA EK created from the default template.
+// The key is flushed right away, it is only needed for deriving the agent
+// UUID independently of the configured EK algorithm.
+pub(crate) fn read_rsa_ek_public(
+    context: &mut Context,
+    handles: &HandleRegistry,
+) -> Result<tss_esapi::structures::Public> {
+    let key_handle =
+        ek::create_ek_object(context, AsymmetricAlgorithm::Rsa, DefaultKey)?;
+    handles.register(key_handle.into(), "read_rsa_ek_public", false);
+    let (tpm_pub, _, _) =
+        retry_tpm_command(|| context.read_public(key_handle))?;
+    context.flush_context(key_handle.into())?;
+    handles.release(key_handle.into());
+    Ok(tpm_pub)
+}
+
+// authPolicy of the default IAK and IDevID templates of the TCG "TPM 2.0
+// Keys for Device Identity and Attestation" specification, for SHA-256
+const IAK_AUTH_POLICY_SHA256: [u8; 32] = [
+    0x54, 0x37, 0x18, 0x23, 0x26, 0xe4, 0x14, 0xfc, 0xa7, 0x97, 0xd5, 0xf1,
+    0x74, 0x61, 0x5a, 0x16, 0x41, 0xf6, 0x12, 0x55, 0x79, 0x7c, 0x3a, 0x2b,
+    0x22, 0xc2, 0x1d, 0x12, 0x0b, 0x2d, 0x1e, 0x07,
+];
+const IDEVID_AUTH_POLICY_SHA256: [u8; 32] = [
+    0xad, 0x6b, 0x3a, 0x22, 0x84, 0xfd, 0x69, 0x8a, 0x07, 0x10, 0xbf, 0x5c,
+    0xc1, 0xb9, 0xbd, 0xf1, 0x5e, 0x25, 0x32, 0xe3, 0xf6, 0x01, 0xfa, 0x4b,
+    0x93, 0xa6, 0xa8, 0xfa, 0x8d, 0xe5, 0x79, 0xea,
+];
+
+// Device identity keys of the TCG IDevID/IAK provisioning. Both are signing
+// keys of the endorsement hierarchy: the IAK is restricted, so that it can
+// quote, the IDevID is not and identifies the device to other parties.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DeviceKey {
+    Iak,
+    IDevId,
+}
+
+impl DeviceKey {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            DeviceKey::Iak => "IAK",
+            DeviceKey::IDevId => "IDevID",
+        }
+    }
+
+    fn auth_policy(self) -> &'static [u8] {
+        match self {
+            DeviceKey::Iak => &IAK_AUTH_POLICY_SHA256,
+            DeviceKey::IDevId => &IDEVID_AUTH_POLICY_SHA256,
+        }
+    }
+
+    // The templates set the name of the key as unique field, so that the
+    // IAK and the IDevID differ although created from the same seed
+    fn unique(self) -> &'static [u8] {
+        match self {
+            DeviceKey::Iak => b"IAK",
+            DeviceKey::IDevId => b"IDEVID",
+        }
+    }
+
+    /*
+     * Input: RSA for the RSA 2048 template, ECC for the NIST P-256 one
+     * Return: The default template of the key, with SHA-256 as name and
+     *         signing hash algorithm
+     */
+    pub(crate) fn template(
+        self,
+        alg: EncryptionAlgorithm,
+    ) -> Result<tss_esapi::structures::Public> {
+        let restricted = self == DeviceKey::Iak;
+        let attributes = ObjectAttributesBuilder::new()
+            .with_fixed_tpm(true)
+            .with_fixed_parent(true)
+            .with_sensitive_data_origin(true)
+            .with_user_with_auth(true)
+            .with_admin_with_policy(true)
+            .with_restricted(restricted)
+            .with_sign_encrypt(true)
+            .build()?;
+        let builder = PublicBuilder::new()
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(attributes)
+            .with_auth_policy(Digest::try_from(self.auth_policy().to_vec())?);
+        let builder = match alg {
+            EncryptionAlgorithm::Rsa => builder
+                .with_public_algorithm(PublicAlgorithm::Rsa)
+                .with_rsa_parameters(
+                    PublicRsaParametersBuilder::new()
+                        .with_scheme(RsaScheme::create(
+                            RsaSchemeAlgorithm::RsaSsa,
+                            Some(HashingAlgorithm::Sha256),
+                        )?)
+                        .with_key_bits(RsaKeyBits::Rsa2048)
+                        .with_exponent(RsaExponent::default())
+                        .with_symmetric(SymmetricDefinitionObject::Null)
+                        .with_is_signing_key(true)
+                        .with_is_decryption_key(false)
+                        .with_restricted(restricted)
+                        .build()?,
+                )
+                .with_rsa_unique_identifier(PublicKeyRsa::try_from(
+                    self.unique().to_vec(),
+                )?),
+            EncryptionAlgorithm::Ecc => builder
+                .with_public_algorithm(PublicAlgorithm::Ecc)
+                .with_ecc_parameters(
+                    PublicEccParametersBuilder::new()
+                        .with_ecc_scheme(EccScheme::create(
+                            EccSchemeAlgorithm::EcDsa,
+                            Some(HashingAlgorithm::Sha256),
+                            None,
+                        )?)
+                        .with_curve(EccCurve::P256.into())
+                        .with_key_derivation_function_scheme(
+                            KeyDerivationFunctionScheme::Null,
+                        )
+                        .with_symmetric(SymmetricDefinitionObject::Null)
+                        .with_is_signing_key(true)
+                        .with_is_decryption_key(false)
+                        .with_restricted(restricted)
+                        .build()?,
+                )
+                .with_ecc_unique_identifier(EccPoint::new(
+                    EccParameter::try_from(self.unique().to_vec())?,
+                    EccParameter::default(),
+                )),
+        };
+        Ok(builder.build()?)
+    }
+}
+
+/*
+ * Input: Connection context, handle registry, device identity key, its
+ *        algorithm and its persistent handle in hex (optional)
+ * Return: The handle and the public area of the key
+ *
+ * Without persistent handle, the key is created from its default template,
+ * which gives back the key the manufacturer certified when it provisioned
+ * the device from the same template. The key stays loaded for quotes.
+ */
+pub(crate) fn load_device_key(
+    context: &mut Context,
+    handles: &HandleRegistry,
+    key: DeviceKey,
+    alg: EncryptionAlgorithm,
+    handle: Option<&str>,
+) -> Result<(KeyHandle, tss_esapi::structures::Public)> {
+    let key_handle = match handle {
+        Some(v) => {
+            let handle = u32::from_str_radix(v.trim_start_matches("0x"), 16)?;
+            context
+                .tr_from_tpm_public(TpmHandle::Persistent(
+                    PersistentTpmHandle::new(handle)?,
+                ))?
+                .into()
+        }
+        None => {
+            let template = key.template(alg)?;
+            let key_handle = retry_tpm_command(|| {
+                context.execute_with_nullauth_session(|ctx| {
+                    ctx.create_primary(
+                        Hierarchy::Endorsement,
+                        template.clone(),
+                        None,
+                        None,
+                        None,
+                        None,
+                    )
+                })
+            })?
+            .key_handle;
+            handles.register(key_handle.into(), key.name(), true);
+            key_handle
+        }
+    };
+    let (tpm_pub, _, _) =
+        retry_tpm_command(|| context.read_public(key_handle))?;
+    Ok((key_handle, tpm_pub))
+}
+
+/*
+ * Input: Connection context, handle registry and AK handle
+ * Return: The handle and the public area of a new restricted signing key
+ *
+ * The key has the algorithms and scheme of the AK. It is a primary key of
+ * the null hierarchy, whose seed changes on every TPM reset, so that it
+ * cannot outlive the boot it was certified in. Used as the short-lived quote
+ * key, see quote_key.rs.
+ */
+pub(crate) fn create_quote_key(
+    context: &mut Context,
+    handles: &HandleRegistry,
+    ak_handle: KeyHandle,
+) -> Result<(KeyHandle, tss_esapi::structures::Public)> {
+    let (ak_public, _, _) =
+        retry_tpm_command(|| context.read_public(ak_handle))?;
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_sensitive_data_origin(true)
+        .with_user_with_auth(true)
+        .with_restricted(true)
+        .with_sign_encrypt(true)
+        .build()?;
+    let builder = PublicBuilder::new().with_object_attributes(attributes);
+    let builder = match ak_public {
+        tss_esapi::structures::Public::Rsa {
+            name_hashing_algorithm,
+            parameters,
+            ..
+        } => builder
+            .with_public_algorithm(PublicAlgorithm::Rsa)
+            .with_name_hashing_algorithm(name_hashing_algorithm)
+            .with_rsa_parameters(parameters)
+            .with_rsa_unique_identifier(PublicKeyRsa::default()),
+        tss_esapi::structures::Public::Ecc {
+            name_hashing_algorithm,
+            parameters,
+            ..
+        } => builder
+            .with_public_algorithm(PublicAlgorithm::Ecc)
+            .with_name_hashing_algorithm(name_hashing_algorithm)
+            .with_ecc_parameters(parameters)
+            .with_ecc_unique_identifier(EccPoint::default()),
+        _ => {
+            return Err(KeylimeError::Other(
+                "the AK is neither an RSA nor an ECC key".to_string(),
+            ))
+        }
+    };
+    let template = builder.build()?;
+
+    let result = retry_tpm_command(|| {
+        context.execute_with_nullauth_session(|ctx| {
+            ctx.create_primary(
+                Hierarchy::Null,
+                template.clone(),
+                None,
+                None,
+                None,
+                None,
+            )
+        })
+    })?;
+    handles.register(result.key_handle.into(), "quote_key", true);
+    Ok((result.key_handle, result.out_public))
+}
+
+/*
+ * Input: Connection context, handle registry and operation
+ * Return: The result of the operation
+ *
+ * Runs the operation with the storage primary key of the owner hierarchy,
+ * created from the default template for each use and flushed afterwards. The
+ * key is the same as long as the owner seed does not change.
+ */
+fn with_storage_primary<T>(
+    context: &mut Context,
+    handles: &HandleRegistry,
+    operation: impl FnOnce(&mut Context, KeyHandle) -> Result<T>,
+) -> Result<T> {
+    let template = tss_esapi::utils::create_restricted_decryption_rsa_public(
+        SymmetricDefinitionObject::AES_128_CFB,
+        RsaKeyBits::Rsa2048,
+        RsaExponent::default(),
+    )?;
+    let primary = retry_tpm_command(|| {
+        context.execute_with_nullauth_session(|ctx| {
+            ctx.create_primary(
+                Hierarchy::Owner,
+                template.clone(),
+                None,
+                None,
+                None,
+                None,
+            )
+        })
+    })?
+    .key_handle;
+    handles.register(primary.into(), "storage_primary", false);
+    let result = operation(context, primary);
+    context.flush_context(primary.into())?;
+    handles.release(primary.into());
+    result
+}
+
+/*
+ * Input: Connection context, handle registry, data of up to 128 bytes and
+ *        policy to bind it to
+ * Return: The public and private areas of the sealed object
+ *
+ * The data is sealed under the storage primary key. With a policy, it can
+ * only be unsealed while the PCRs hold the values they had when it was
+ * sealed, see PcrPolicy.
+ */
+pub(crate) fn seal(
+    context: &mut Context,
+    handles: &HandleRegistry,
+    data: &[u8],
+    policy: Option<&PcrPolicy>,
+) -> Result<(tss_esapi::structures::Public, Private)> {
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_user_with_auth(policy.is_none())
+        .build()?;
+    let builder = PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::KeyedHash)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(attributes)
+        .with_keyed_hash_parameters(PublicKeyedHashParameters::new(
+            KeyedHashScheme::Null,
+        ))
+        .with_keyed_hash_unique_identifier(Digest::default());
+    let builder = match policy {
+        Some(policy) => builder.with_auth_policy(policy.digest(context)?),
+        None => builder,
+    };
+    let template = builder.build()?;
+    let data = SensitiveData::try_from(data.to_vec())?;
+
+    with_storage_primary(context, handles, |context, primary| {
+        let sealed = retry_tpm_command(|| {
+            context.execute_with_nullauth_session(|ctx| {
+                ctx.create(
+                    primary,
+                    template.clone(),
+                    None,
+                    Some(data.clone()),
+                    None,
+                    None,
+                )
+            })
+        })?;
+        Ok((sealed.out_public, sealed.out_private))
+    })
+}
+
+/*
+ * Input: Connection context, handle registry, sealed object returned by
+ *        seal() and its policy
+ * Return: The sealed data
+ *
+ * Fails with TPM_RC_POLICY_FAIL once the PCRs of the policy changed.
+ */
+pub(crate) fn unseal(
+    context: &mut Context,
+    handles: &HandleRegistry,
+    public: tss_esapi::structures::Public,
+    private: Private,
+    policy: Option<&PcrPolicy>,
+) -> Result<Vec<u8>> {
+    with_storage_primary(context, handles, |context, primary| {
+        let sealed = retry_tpm_command(|| {
+            context.execute_with_nullauth_session(|ctx| {
+                ctx.load(primary, private.clone(), public.clone())
+            })
+        })?;
+        handles.register(sealed.into(), "sealed_object", false);
+        let data = match policy {
+            Some(policy) => policy.authorize(context, |ctx, session| {
+                ctx.execute_with_session(Some(session), |ctx| {
+                    ctx.unseal(sealed.into())
+                })
+            }),
+            None => retry_tpm_command(|| {
+                context.execute_with_nullauth_session(|ctx| {
+                    ctx.unseal(sealed.into())
+                })
+            })
+            .map_err(KeylimeError::from),
+        };
+        context.flush_context(sealed.into())?;
+        handles.release(sealed.into());
+        Ok(data?.to_vec())
+    })
+}
+
 // Ensure that TPML_PCR_SELECTION and TPML_DIGEST have known sizes
 assert_eq_size!(TPML_PCR_SELECTION, [u8; 132]);
 assert_eq_size!(TPML_DIGEST, [u8; 532]);
@@ -288,42 +1209,344 @@ pub(crate) struct AKResult {
     pub private: tss_esapi::structures::Private,
 }
 
-/* Creates AK
-*/
+// Sets the curve of ECC AKs, the tss-esapi AK template always uses NIST P-192,
+// and the authorization policy of policy-bound AKs
+#[derive(Debug)]
+struct AkTemplate {
+    ecc_parameters: Option<PublicEccParameters>,
+    auth_policy: Option<Digest>,
+}
+
+impl AkTemplate {
+    fn new(
+        hash_alg: HashingAlgorithm,
+        sign_alg: SignatureSchemeAlgorithm,
+        ecc_curve: Option<EccCurve>,
+    ) -> Result<Self> {
+        let curve = match ecc_curve {
+            Some(curve) => curve,
+            None => {
+                return Ok(AkTemplate {
+                    ecc_parameters: None,
+                    auth_policy: None,
+                })
+            }
+        };
+        let scheme = match sign_alg {
+            SignatureSchemeAlgorithm::EcDsa => EccSchemeAlgorithm::EcDsa,
+            SignatureSchemeAlgorithm::EcSchnorr => {
+                EccSchemeAlgorithm::EcSchnorr
+            }
+            #[cfg(feature = "sm")]
+            SignatureSchemeAlgorithm::Sm2 => EccSchemeAlgorithm::Sm2,
+            other => {
+                return Err(KeylimeError::Other(format!(
+                    "signing scheme {:?} cannot be used with an ECC AK",
+                    other
+                )))
+            }
+        };
+        Ok(AkTemplate {
+            ecc_parameters: Some(PublicEccParameters::new(
+                SymmetricDefinitionObject::Null,
+                EccScheme::create(scheme, Some(hash_alg), None)?,
+                curve.into(),
+                KeyDerivationFunctionScheme::Null,
+            )),
+            auth_policy: None,
+        })
+    }
+}
+
+impl KeyCustomization for AkTemplate {
+    // A policy-bound AK can only sign in a policy session
+    fn attributes(
+        &self,
+        attributes_builder: ObjectAttributesBuilder,
+    ) -> ObjectAttributesBuilder {
+        match self.auth_policy {
+            Some(_) => attributes_builder.with_user_with_auth(false),
+            None => attributes_builder,
+        }
+    }
+
+    fn template(&self, template_builder: PublicBuilder) -> PublicBuilder {
+        let template_builder = match self.ecc_parameters {
+            Some(parameters) => {
+                template_builder.with_ecc_parameters(parameters)
+            }
+            None => template_builder,
+        };
+        match &self.auth_policy {
+            Some(policy) => template_builder.with_auth_policy(policy.clone()),
+            None => template_builder,
+        }
+    }
+}
+
+// Authorization policy of a TPM object, such as the AK set with
+// ak_policy_pcrs and ak_policy_secret or sealed data: the object is only
+// usable while the PCRs hold the values they had when it was created, and,
+// with PolicySecret, when the endorsement hierarchy authorizes it. Quotes and
+// certifications by the AK, or unsealing, are then authorized with a policy
+// session satisfying it, in place of the empty password.
+#[derive(Clone, Debug)]
+pub(crate) struct PcrPolicy {
+    // Name algorithm of the object, the policy digest is computed with it
+    hash_alg: HashingAlgorithm,
+    pcrs: Option<PcrSelectionList>,
+    secret: bool,
+}
+
+impl PcrPolicy {
+    /*
+     * Input: name algorithm of the object, PCRs of its bank to bind it to and
+     *        whether to require PolicySecret(TPM_RH_ENDORSEMENT)
+     * Return: the policy, None when there is nothing to require
+     */
+    pub(crate) fn new(
+        hash_alg: HashingAlgorithm,
+        pcrs: &[usize],
+        secret: bool,
+    ) -> Result<Option<Self>> {
+        if pcrs.is_empty() && !secret {
+            return Ok(None);
+        }
+        let pcrs = if pcrs.is_empty() {
+            None
+        } else {
+            let slots = pcrs
+                .iter()
+                .map(|&pcr| PcrSlot::try_from(1u32 << pcr))
+                .collect::<std::result::Result<Vec<PcrSlot>, _>>()?;
+            Some(
+                PcrSelectionListBuilder::new()
+                    .with_selection(hash_alg, &slots)
+                    .build()?,
+            )
+        };
+        Ok(Some(PcrPolicy {
+            hash_alg,
+            pcrs,
+            secret,
+        }))
+    }
+
+    // Starts a session of the type satisfying the policy, to be flushed by
+    // the caller
+    fn start(
+        &self,
+        ctx: &mut Context,
+        session_type: SessionType,
+    ) -> Result<AuthSession> {
+        let symmetric = Cipher::aes_128_cfb().try_into()?;
+        let session = retry_tpm_command(|| {
+            ctx.start_auth_session(
+                None,
+                None,
+                None,
+                session_type,
+                symmetric,
+                self.hash_alg,
+            )
+        })?
+        .ok_or_else(|| {
+            KeylimeError::Other("no policy session was started".to_string())
+        })?;
+        if let Err(e) = self.apply(ctx, session) {
+            let _ = ctx.flush_context(SessionHandle::from(session).into());
+            return Err(e);
+        }
+        Ok(session)
+    }
+
+    fn apply(&self, ctx: &mut Context, session: AuthSession) -> Result<()> {
+        if let Some(pcrs) = &self.pcrs {
+            // An empty digest stands for the current values of the PCRs
+            retry_tpm_command(|| {
+                ctx.policy_pcr(
+                    session.try_into()?,
+                    Digest::default(),
+                    pcrs.clone(),
+                )
+            })?;
+        }
+        if self.secret {
+            let _ = retry_tpm_command(|| {
+                ctx.execute_with_nullauth_session(|context| {
+                    context.policy_secret(
+                        session.try_into()?,
+                        AuthHandle::Endorsement,
+                        Default::default(),
+                        Default::default(),
+                        Default::default(),
+                        None,
+                    )
+                })
+            })?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Input: Connection context
+     * Return: The authPolicy of an AK bound to the policy, over the current
+     *         values of the PCRs
+     */
+    pub(crate) fn digest(&self, ctx: &mut Context) -> Result<Digest> {
+        let session = self.start(ctx, SessionType::Trial)?;
+        let digest =
+            retry_tpm_command(|| ctx.policy_get_digest(session.try_into()?));
+        ctx.flush_context(SessionHandle::from(session).into())?;
+        Ok(digest?)
+    }
+
+    /*
+     * Input: Connection context and command to authorize
+     * Return: The result of the command
+     *
+     * Runs the command with a policy session satisfying the policy, to be
+     * used as the authorization of the AK, and flushes the session. The
+     * command fails with TPM_RC_POLICY_FAIL once the PCRs changed.
+     */
+    fn authorize<T>(
+        &self,
+        ctx: &mut Context,
+        mut command: impl FnMut(&mut Context, AuthSession) -> tss_esapi::Result<T>,
+    ) -> Result<T> {
+        let session = self.start(ctx, SessionType::Policy)?;
+        let result = retry_tpm_command(|| command(ctx, session));
+        ctx.flush_context(SessionHandle::from(session).into())?;
+        Ok(result?)
+    }
+}
+
+// Upper bound on the curves listed by the TPM, there are about a dozen
+const MAX_ECC_CURVES: u32 = 64;
+
+// Fails with a configuration error naming the curve when the TPM does not
+// implement it, rather than with the TSS error of the AK creation
+fn check_ecc_curve(ctx: &mut Context, curve: EccCurve) -> Result<()> {
+    let (capability, _) = retry_tpm_command(|| {
+        ctx.get_capability(CapabilityType::EccCurves, 0, MAX_ECC_CURVES)
+    })?;
+    let implemented = match capability {
+        CapabilityData::EccCurves(curves) => curves.contains(&curve.into()),
+        _ => false,
+    };
+    if !implemented {
+        return Err(KeylimeError::Configuration(format!(
+            "the TPM does not implement the ECC curve {} set in tpm_ecc_curve",
+            curve
+        )));
+    }
+    Ok(())
+}
+
+/*
+ * Input: Connection context, EK handle, hash and signing algorithms, the
+ *        curve for ECC signing schemes and the authorization policy
+ * Return: The created AK, not loaded
+ */
 pub(crate) fn create_ak(
     ctx: &mut Context,
     handle: KeyHandle,
     hash_alg: HashingAlgorithm,
     sign_alg: SignatureSchemeAlgorithm,
+    ecc_curve: Option<EccCurve>,
+    policy: Option<&PcrPolicy>,
 ) -> Result<AKResult> {
-    let ak =
-        ak::create_ak(ctx, handle, hash_alg, sign_alg, None, DefaultKey)?;
+    if let Some(curve) = ecc_curve {
+        check_ecc_curve(ctx, curve)?;
+    }
+    let mut template = AkTemplate::new(hash_alg, sign_alg, ecc_curve)?;
+    if let Some(policy) = policy {
+        template.auth_policy = Some(policy.digest(ctx)?);
+    }
+    let ak = ak::create_ak(ctx, handle, hash_alg, sign_alg, None, template)?;
     Ok(AKResult {
         public: ak.out_public,
         private: ak.out_private,
     })
 }
 
+/*
+ * Input: Connection context, handle registry, EK handle, AK to load and
+ *        whether to encrypt the session
+ * Return: The handle of the loaded AK
+ *
+ * With encrypted sessions, the AK is loaded in a policy session salted with
+ * the EK rather than in the unsalted session of tss_esapi::abstraction::ak.
+ */
 pub(crate) fn load_ak(
     ctx: &mut Context,
+    handles: &HandleRegistry,
     handle: KeyHandle,
     ak: &AKResult,
+    encrypt_sessions: bool,
 ) -> Result<KeyHandle> {
-    let ak_handle = ak::load_ak(
-        ctx,
-        handle,
-        None,
-        ak.private.clone(),
-        ak.public.clone(),
-    )?;
+    let ak_handle = if encrypt_sessions {
+        let ek_auth =
+            create_empty_session(ctx, SessionType::Policy, Some(handle))?;
+        let ek_auth_handle: ObjectHandle =
+            SessionHandle::from(ek_auth).into();
+        handles.register(ek_auth_handle, "load_ak", false);
+
+        let result = load_ak_with_session(ctx, handle, ak, ek_auth);
+        ctx.flush_context(ek_auth_handle)?;
+        handles.release(ek_auth_handle);
+        result?
+    } else {
+        ak::load_ak(ctx, handle, None, ak.private.clone(), ak.public.clone())?
+    };
+    // The AK is used for every quote
+    handles.register(ak_handle.into(), "load_ak", true);
     Ok(ak_handle)
 }
 
+fn load_ak_with_session(
+    ctx: &mut Context,
+    ek: KeyHandle,
+    ak: &AKResult,
+    ek_auth: AuthSession,
+) -> Result<KeyHandle> {
+    let _ = retry_tpm_command(|| {
+        ctx.execute_with_nullauth_session(|context| {
+            context.policy_secret(
+                ek_auth.try_into()?,
+                AuthHandle::Endorsement,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                None,
+            )
+        })
+    })?;
+
+    retry_tpm_command(|| {
+        ctx.execute_with_session(Some(ek_auth), |context| {
+            context.load(ek, ak.private.clone(), ak.public.clone())
+        })
+    })
+    .map_err(KeylimeError::from)
+}
+
 const TSS_MAGIC: u32 = 3135029470;
 
+fn short_keyblob(len: usize) -> KeylimeError {
+    KeylimeError::Other(format!(
+        "Error parsing cred and secret; blob of {} bytes is too short",
+        len
+    ))
+}
+
+// The blob comes from the registrar, or from verifiers with ak_per_verifier
 fn parse_cred_and_secret(
     keyblob: Vec<u8>,
 ) -> Result<(IdObject, EncryptedSecret)> {
+    if keyblob.len() < 10 {
+        return Err(short_keyblob(keyblob.len()));
+    }
     let magic = u32::from_be_bytes(keyblob[0..4].try_into().unwrap()); //#[allow_ci]
     let version = u32::from_be_bytes(keyblob[4..8].try_into().unwrap()); //#[allow_ci]
 
@@ -338,6 +1561,9 @@ fn parse_cred_and_secret(
     }
 
     let credsize = u16::from_be_bytes(keyblob[8..10].try_into().unwrap()); //#[allow_ci]
+    if keyblob.len() < 12 + credsize as usize {
+        return Err(short_keyblob(keyblob.len()));
+    }
     let secretsize = u16::from_be_bytes(
         keyblob[(10 + credsize as usize)..(12 + credsize as usize)]
             .try_into()
@@ -353,18 +1579,25 @@ fn parse_cred_and_secret(
     Ok((credential, secret))
 }
 
+// With a salt key, the session key is derived from a salt encrypted to that
+// key instead of only from the nonces seen on the bus, so that the
+// parameters encrypted in the session are hidden from interposers.
 fn create_empty_session(
     ctx: &mut Context,
     ses_type: SessionType,
+    salt_key: Option<KeyHandle>,
 ) -> Result<AuthSession> {
-    let session = ctx.start_auth_session(
-        None,
-        None,
-        None,
-        ses_type,
-        Cipher::aes_128_cfb().try_into()?,
-        HashingAlgorithm::Sha256,
-    )?;
+    let symmetric = Cipher::aes_128_cfb().try_into()?;
+    let session = retry_tpm_command(|| {
+        ctx.start_auth_session(
+            salt_key,
+            None,
+            None,
+            ses_type,
+            symmetric,
+            HashingAlgorithm::Sha256,
+        )
+    })?;
     let (ses_attrs, ses_attrs_mask) = SessionAttributesBuilder::new()
         .with_encrypt(true)
         .with_decrypt(true)
@@ -373,40 +1606,84 @@ fn create_empty_session(
     Ok(session.unwrap()) //#[allow_ci]
 }
 
+/*
+ * Input: Connection context, handle registry, credential blob from the
+ *        registrar, AK and EK handles, and whether to encrypt the session
+ * Return: The secret released by the credential activation
+ *
+ * With encrypted sessions, the policy session authorizing the EK is salted
+ * with the EK, so that the released secret is encrypted on the bus.
+ */
 pub(crate) fn activate_credential(
     ctx: &mut Context,
+    handles: &HandleRegistry,
     keyblob: Vec<u8>,
     ak: KeyHandle,
     ek: KeyHandle,
+    encrypt_sessions: bool,
 ) -> Result<Digest> {
     let (credential, secret) = parse_cred_and_secret(keyblob)?;
 
-    let ek_auth = create_empty_session(ctx, SessionType::Policy)?;
+    let salt_key = if encrypt_sessions { Some(ek) } else { None };
+    let ek_auth = create_empty_session(ctx, SessionType::Policy, salt_key)?;
+    let ek_auth_handle: ObjectHandle = SessionHandle::from(ek_auth).into();
+    handles.register(ek_auth_handle, "activate_credential", false);
+
+    let result = activate_credential_with_session(
+        ctx, credential, secret, ak, ek, ek_auth,
+    );
+    // The policy session is not needed anymore, whatever the result
+    ctx.flush_context(ek_auth_handle)?;
+    handles.release(ek_auth_handle);
+    result
+}
 
+fn activate_credential_with_session(
+    ctx: &mut Context,
+    credential: IdObject,
+    secret: EncryptedSecret,
+    ak: KeyHandle,
+    ek: KeyHandle,
+    ek_auth: AuthSession,
+) -> Result<Digest> {
     // We authorize ses2 with PolicySecret(ENDORSEMENT) as per PolicyA
-    let _ = ctx.execute_with_nullauth_session(|context| {
-        context.policy_secret(
-            ek_auth.try_into()?,
-            AuthHandle::Endorsement,
-            Default::default(),
-            Default::default(),
-            Default::default(),
-            None,
-        )
+    let _ = retry_tpm_command(|| {
+        ctx.execute_with_nullauth_session(|context| {
+            context.policy_secret(
+                ek_auth.try_into()?,
+                AuthHandle::Endorsement,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                None,
+            )
+        })
     })?;
 
-    ctx.execute_with_sessions(
-        (Some(AuthSession::Password), Some(ek_auth), None),
-        |context| context.activate_credential(ak, ek, credential, secret),
-    )
+    retry_tpm_command(|| {
+        ctx.execute_with_sessions(
+            (Some(AuthSession::Password), Some(ek_auth), None),
+            |context| {
+                context.activate_credential(
+                    ak,
+                    ek,
+                    credential.clone(),
+                    secret.clone(),
+                )
+            },
+        )
+    })
     .map_err(KeylimeError::from)
 }
 
 // Takes a public PKey and returns a DigestValue of it.
 // Note: Currently, this creates a DigestValue including both SHA256 and
-// SHA1 because these banks are checked by Keylime on the Python side.
+// SHA1 because these banks are checked by Keylime on the Python side. With
+// the 'sm' feature, the SM3 digest is added when quoting the SM3 bank.
+#[cfg_attr(not(feature = "sm"), allow(unused_variables))]
 pub(crate) fn pubkey_to_tpm_digest(
     pubkey: &PKeyRef<Public>,
+    hash_alg: HashAlgorithm,
 ) -> Result<DigestValues> {
     let mut keydigest = DigestValues::new();
 
@@ -430,64 +1707,358 @@ pub(crate) fn pubkey_to_tpm_digest(
     hasher.update(&keybytes);
     let mut hashvec: Vec<u8> = hasher.finish().into();
     keydigest.set(HashingAlgorithm::Sha1, Digest::try_from(hashvec)?);
+    // SM3, only allocated on TPMs certified for the Chinese algorithms
+    #[cfg(feature = "sm")]
+    if hash_alg == HashAlgorithm::Sm3_256 {
+        let hashvec = crate::crypto::sm3_digest(&keybytes)?;
+        keydigest.set(HashingAlgorithm::Sm3_256, Digest::try_from(hashvec)?);
+    }
 
     Ok(keydigest)
 }
 
-// Reads a mask in the form of some hex value, ex. "0x408000",
-// translating bits that are set to pcrs to include in the list.
-//
-// The masks are sent from the tenant and cloud verifier to indicate
-// the PCRs to include in a Quote. The LSB in the mask corresponds to
-// PCR0. For example, keylime-agent.conf specifies PCRs 15 and 22 under
-// [tenant][tpm_policy]. As a bit mask, this would be represented as
-// 0b010000001000000000000000, which translates to 0x408000.
-//
-// The mask is a string because it is sent as a string from the tenant
-// and verifier. The output from this function can be used to call a
-// Quote from the TSS ESAPI.
-//
-pub(crate) fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
-    let mut pcrs = Vec::new();
+// Reads a mask in the form of some hex value, ex. "0x408000",
+// translating bits that are set to pcrs to include in the list.
+//
+// The masks are sent from the tenant and cloud verifier to indicate
+// the PCRs to include in a Quote. The LSB in the mask corresponds to
+// PCR0. For example, keylime-agent.conf specifies PCRs 15 and 22 under
+// [tenant][tpm_policy]. As a bit mask, this would be represented as
+// 0b010000001000000000000000, which translates to 0x408000.
+//
+// The mask is a string because it is sent as a string from the tenant
+// and verifier. The output from this function can be used to call a
+// Quote from the TSS ESAPI.
+//
+pub(crate) fn read_mask(mask: &str) -> Result<Vec<PcrSlot>> {
+    let mut pcrs = Vec::new();
+
+    let num = u32::from_str_radix(mask.trim_start_matches("0x"), 16)?;
+
+    // check which bits are set
+    for i in 0..32 {
+        if num & (1 << i) != 0 {
+            pcrs.push(
+                match i {
+                    0 => PcrSlot::Slot0,
+                    1 => PcrSlot::Slot1,
+                    2 => PcrSlot::Slot2,
+                    3 => PcrSlot::Slot3,
+                    4 => PcrSlot::Slot4,
+                    5 => PcrSlot::Slot5,
+                    6 => PcrSlot::Slot6,
+                    7 => PcrSlot::Slot7,
+                    8 => PcrSlot::Slot8,
+                    9 => PcrSlot::Slot9,
+                    10 => PcrSlot::Slot10,
+                    11 => PcrSlot::Slot11,
+                    12 => PcrSlot::Slot12,
+                    13 => PcrSlot::Slot13,
+                    14 => PcrSlot::Slot14,
+                    15 => PcrSlot::Slot15,
+                    16 => PcrSlot::Slot16,
+                    17 => PcrSlot::Slot17,
+                    18 => PcrSlot::Slot18,
+                    19 => PcrSlot::Slot19,
+                    20 => PcrSlot::Slot20,
+                    21 => PcrSlot::Slot21,
+                    22 => PcrSlot::Slot22,
+                    23 => PcrSlot::Slot23,
+                    bit => return Err(KeylimeError::Other(format!("malformed mask in integrity quote: only pcrs 0-23 can be included, but mask included pcr {:?}", bit))),
+                },
+            )
+        }
+    }
+
+    Ok(pcrs)
+}
+
+// Returns the mask with the given PCR added to it
+pub(crate) fn add_pcr_to_mask(mask: &str, pcr: usize) -> Result<String> {
+    let num = u32::from_str_radix(mask.trim_start_matches("0x"), 16)?;
+    if pcr > 23 {
+        return Err(KeylimeError::Other(format!(
+            "only pcrs 0-23 can be included in a mask, got pcr {}",
+            pcr
+        )));
+    }
+    Ok(format!("{:#x}", num | (1 << pcr)))
+}
+
+// Upper bound on the banks listed by the TPM
+const MAX_PCR_BANKS: u32 = 16;
+
+// Returns the mask of the PCRs allocated in the hash algorithm's bank. Banks
+// can be left unallocated, and some TPMs implement fewer than 24 PCRs.
+pub(crate) fn allocated_pcrs(
+    context: &mut Context,
+    hash_alg: HashAlgorithm,
+) -> Result<u32> {
+    let (capability, _) = retry_tpm_command(|| {
+        context.get_capability(CapabilityType::AssignedPcr, 0, MAX_PCR_BANKS)
+    })?;
+    let selections = match capability {
+        CapabilityData::AssignedPcr(selections) => selections,
+        other => {
+            return Err(KeylimeError::Other(format!(
+                "unexpected capability data for the allocated PCRs: {:?}",
+                other
+            )))
+        }
+    };
+    let hash_alg = HashingAlgorithm::from(hash_alg);
+    Ok(selections
+        .get_selections()
+        .iter()
+        .filter(|selection| selection.hashing_algorithm() == hash_alg)
+        .flat_map(|selection| selection.selected())
+        .fold(0, |mask, slot| mask | u32::from(slot)))
+}
+
+/*
+ * Input: PCR mask and the mask of the allocated PCRs
+ * Return: Result wrap the numbers of the PCRs in the mask that are not
+ *         allocated
+ */
+pub(crate) fn unallocated_pcrs(
+    mask: &str,
+    allocated: u32,
+) -> Result<Vec<usize>> {
+    // Fails on PCRs past 23
+    let _ = read_mask(mask)?;
+    let num = u32::from_str_radix(mask.trim_start_matches("0x"), 16)?;
+    Ok((0..24)
+        .filter(|pcr| num & !allocated & (1 << pcr) != 0)
+        .collect())
+}
+
+// Largest read of an NV index, when the TPM does not report
+// TPM_PT_NV_BUFFER_MAX. The specification requires at least 512 bytes.
+const NV_BUFFER_MAX: usize = 512;
+
+/*
+ * Input: size of an NV index and the largest read the TPM accepts
+ * Return: offset and size of each read needed to read the whole index
+ */
+fn nv_chunks(size: usize, max: usize) -> Result<Vec<(u16, u16)>> {
+    let mut chunks = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let chunk = max.min(size - offset);
+        chunks.push((u16::try_from(offset)?, u16::try_from(chunk)?));
+        offset += chunk;
+    }
+    Ok(chunks)
+}
+
+/*
+ * Input: Connection context and NV index
+ * Return: Result wrap the content of the NV index
+ *
+ * Indices larger than the TPM buffer are read in several chunks. Indices
+ * readable by the owner are read with the owner authorization, the others
+ * with their own empty authorization.
+ */
+pub(crate) fn nv_read(context: &mut Context, index: u32) -> Result<Vec<u8>> {
+    let max = match retry_tpm_command(|| {
+        context.get_tpm_property(PropertyTag::NvBufferMax)
+    })? {
+        Some(max) if max > 0 => max as usize,
+        _ => NV_BUFFER_MAX,
+    };
+    let nv_index = NvIndexTpmHandle::new(index)?;
+    let nv_handle = NvIndexHandle::from(retry_tpm_command(|| {
+        context.tr_from_tpm_public(TpmHandle::NvIndex(nv_index))
+    })?);
+
+    // The handle is closed whether the read succeeded or not
+    let result = (|| -> Result<Vec<u8>> {
+        let (public, _) =
+            retry_tpm_command(|| context.nv_read_public(nv_handle))?;
+        let auth = if public.attributes().owner_read() {
+            NvAuth::Owner
+        } else {
+            NvAuth::NvIndex(nv_handle)
+        };
+        let mut data = Vec::with_capacity(public.data_size());
+        for (offset, size) in nv_chunks(public.data_size(), max)? {
+            let chunk = retry_tpm_command(|| {
+                context.execute_with_nullauth_session(|ctx| {
+                    ctx.nv_read(auth, nv_handle, size, offset)
+                })
+            })?;
+            data.extend_from_slice(chunk.value());
+        }
+        Ok(data)
+    })();
+
+    let mut object = ObjectHandle::from(nv_handle);
+    context.tr_close(&mut object)?;
+    result
+}
+
+/*
+ * Input: Connection context, first and last NV index of the range
+ * Return: the NV indices defined in the range
+ */
+pub(crate) fn nv_indices(
+    context: &mut Context,
+    first: u32,
+    last: u32,
+) -> Result<Vec<u32>> {
+    let (capability, _) = retry_tpm_command(|| {
+        context.get_capability(
+            CapabilityType::Handles,
+            first,
+            last - first + 1,
+        )
+    })?;
+    Ok(match capability {
+        CapabilityData::Handles(handles) => handles
+            .iter()
+            .map(|&handle| u32::from(handle))
+            .filter(|&index| index <= last)
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+// Size of the NV indices of type counter
+const NV_COUNTER_SIZE: usize = 8;
+
+fn nv_counter_value(data: &[u8]) -> Result<u64> {
+    let bytes = <[u8; NV_COUNTER_SIZE]>::try_from(data).map_err(|_| {
+        KeylimeError::Other(format!(
+            "NV counter of {} bytes rather than {}",
+            data.len(),
+            NV_COUNTER_SIZE
+        ))
+    })?;
+    Ok(u64::from_be_bytes(bytes))
+}
+
+/*
+ * Input: Connection context and NV index
+ * Return: Result wrap the value of the counter
+ *
+ * Defines the index as a monotonic counter of the owner hierarchy when it
+ * is not defined yet. A new counter starts at a value chosen by the TPM and
+ * cannot be read before its first increment, so it is incremented once.
+ * Defined indices that are not counters are refused.
+ */
+pub(crate) fn nv_counter(context: &mut Context, index: u32) -> Result<u64> {
+    let nv_index = NvIndexTpmHandle::new(index)?;
+    if !nv_indices(context, index, index)?.contains(&index) {
+        let attributes = NvIndexAttributesBuilder::new()
+            .with_nv_index_type(NvIndexType::Counter)
+            .with_owner_write(true)
+            .with_owner_read(true)
+            .with_no_da(true)
+            .build()?;
+        let public = NvPublicBuilder::new()
+            .with_nv_index(nv_index)
+            .with_index_name_algorithm(HashingAlgorithm::Sha256)
+            .with_index_attributes(attributes)
+            .with_data_area_size(NV_COUNTER_SIZE)
+            .build()?;
+        let nv_handle = retry_tpm_command(|| {
+            context.execute_with_nullauth_session(|ctx| {
+                ctx.nv_define_space(Provision::Owner, None, public.clone())
+            })
+        })?;
+        let mut object = ObjectHandle::from(nv_handle);
+        context.tr_close(&mut object)?;
+        info!("Defined the NV counter {:#x}", index);
+        return nv_increment(context, index);
+    }
 
-    let num = u32::from_str_radix(mask.trim_start_matches("0x"), 16)?;
+    let nv_handle = NvIndexHandle::from(retry_tpm_command(|| {
+        context.tr_from_tpm_public(TpmHandle::NvIndex(nv_index))
+    })?);
+    let public = retry_tpm_command(|| context.nv_read_public(nv_handle));
+    let mut object = ObjectHandle::from(nv_handle);
+    context.tr_close(&mut object)?;
+    let (public, _) = public?;
+    if public.attributes().index_type()? != NvIndexType::Counter {
+        return Err(KeylimeError::Configuration(format!(
+            "NV index {:#x} is defined but is not a counter",
+            index
+        )));
+    }
+    if !public.attributes().written() {
+        return nv_increment(context, index);
+    }
+    nv_counter_value(&nv_read(context, index)?)
+}
 
-    // check which bits are set
-    for i in 0..32 {
-        if num & (1 << i) != 0 {
-            pcrs.push(
-                match i {
-                    0 => PcrSlot::Slot0,
-                    1 => PcrSlot::Slot1,
-                    2 => PcrSlot::Slot2,
-                    3 => PcrSlot::Slot3,
-                    4 => PcrSlot::Slot4,
-                    5 => PcrSlot::Slot5,
-                    6 => PcrSlot::Slot6,
-                    7 => PcrSlot::Slot7,
-                    8 => PcrSlot::Slot8,
-                    9 => PcrSlot::Slot9,
-                    10 => PcrSlot::Slot10,
-                    11 => PcrSlot::Slot11,
-                    12 => PcrSlot::Slot12,
-                    13 => PcrSlot::Slot13,
-                    14 => PcrSlot::Slot14,
-                    15 => PcrSlot::Slot15,
-                    16 => PcrSlot::Slot16,
-                    17 => PcrSlot::Slot17,
-                    18 => PcrSlot::Slot18,
-                    19 => PcrSlot::Slot19,
-                    20 => PcrSlot::Slot20,
-                    21 => PcrSlot::Slot21,
-                    22 => PcrSlot::Slot22,
-                    23 => PcrSlot::Slot23,
-                    bit => return Err(KeylimeError::Other(format!("malformed mask in integrity quote: only pcrs 0-23 can be included, but mask included pcr {:?}", bit))),
-                },
-            )
+/*
+ * Input: Connection context and NV index of a counter
+ * Return: Result wrap the value of the counter once incremented
+ */
+pub(crate) fn nv_increment(context: &mut Context, index: u32) -> Result<u64> {
+    let nv_index = NvIndexTpmHandle::new(index)?;
+    let nv_handle = NvIndexHandle::from(retry_tpm_command(|| {
+        context.tr_from_tpm_public(TpmHandle::NvIndex(nv_index))
+    })?);
+    let result = retry_tpm_command(|| {
+        context.execute_with_nullauth_session(|ctx| {
+            ctx.nv_increment(NvAuth::Owner, nv_handle)
+        })
+    });
+    let mut object = ObjectHandle::from(nv_handle);
+    context.tr_close(&mut object)?;
+    result?;
+    nv_counter_value(&nv_read(context, index)?)
+}
+
+// Extends a digest into the given PCR of the hash algorithm's bank
+pub(crate) fn extend_pcr(
+    context: &mut Context,
+    pcr: usize,
+    hash_alg: HashAlgorithm,
+    digest: &[u8],
+) -> Result<()> {
+    let handle = match pcr {
+        0 => PcrHandle::Pcr0,
+        1 => PcrHandle::Pcr1,
+        2 => PcrHandle::Pcr2,
+        3 => PcrHandle::Pcr3,
+        4 => PcrHandle::Pcr4,
+        5 => PcrHandle::Pcr5,
+        6 => PcrHandle::Pcr6,
+        7 => PcrHandle::Pcr7,
+        8 => PcrHandle::Pcr8,
+        9 => PcrHandle::Pcr9,
+        10 => PcrHandle::Pcr10,
+        11 => PcrHandle::Pcr11,
+        12 => PcrHandle::Pcr12,
+        13 => PcrHandle::Pcr13,
+        14 => PcrHandle::Pcr14,
+        15 => PcrHandle::Pcr15,
+        16 => PcrHandle::Pcr16,
+        17 => PcrHandle::Pcr17,
+        18 => PcrHandle::Pcr18,
+        19 => PcrHandle::Pcr19,
+        20 => PcrHandle::Pcr20,
+        21 => PcrHandle::Pcr21,
+        22 => PcrHandle::Pcr22,
+        23 => PcrHandle::Pcr23,
+        _ => {
+            return Err(KeylimeError::Other(format!(
+                "only pcrs 0-23 can be extended, got pcr {}",
+                pcr
+            )))
         }
-    }
+    };
 
-    Ok(pcrs)
+    let mut vals = DigestValues::new();
+    vals.set(hash_alg.into(), Digest::try_from(digest)?);
+    retry_tpm_command(|| {
+        context.execute_with_nullauth_session(|ctx| {
+            ctx.pcr_extend(handle, vals.clone())
+        })
+    })?;
+    Ok(())
 }
 
 //This checks if a PCR is contained in a mask
@@ -532,27 +2103,113 @@ pub(crate) fn encode_quote_string(
     Ok(quote)
 }
 
+// The TPM operations needed to assemble a quote. Quote assembly is written
+// against this trait rather than directly against the TSS ESAPI Context so
+// that recorded TSS transcripts can be replayed in place of a real TPM (see
+// the tpm_replay module).
+pub(crate) trait TpmQuoteOps {
+    // Resets the given PCR and extends the digest into it
+    fn pcr_reset_and_extend(
+        &mut self,
+        pcr: PcrHandle,
+        digest: DigestValues,
+    ) -> Result<()>;
+
+    // Reads all the PCRs in the selection list
+    fn pcr_read_all(&mut self, pcrlist: PcrSelectionList) -> Result<PcrData>;
+
+    // Quotes the PCRs in the selection list with the given AK and nonce, in
+    // a session satisfying the policy of the AK if it has one
+    fn quote_pcrs(
+        &mut self,
+        ak_handle: KeyHandle,
+        ak_policy: Option<&PcrPolicy>,
+        nonce: tss_esapi::structures::Data,
+        sign_scheme: SignatureScheme,
+        pcrlist: PcrSelectionList,
+    ) -> Result<(Attest, Signature)>;
+}
+
+impl TpmQuoteOps for Context {
+    fn pcr_reset_and_extend(
+        &mut self,
+        pcr: PcrHandle,
+        digest: DigestValues,
+    ) -> Result<()> {
+        retry_tpm_command(|| {
+            self.execute_with_nullauth_session(|ctx| ctx.pcr_reset(pcr))
+        })?;
+        retry_tpm_command(|| {
+            self.execute_with_nullauth_session(|ctx| {
+                ctx.pcr_extend(pcr, digest.clone())
+            })
+        })
+        .map_err(KeylimeError::from)
+    }
+
+    fn pcr_read_all(&mut self, pcrlist: PcrSelectionList) -> Result<PcrData> {
+        retry_tpm_command(|| {
+            self.execute_without_session(|ctx| read_all(ctx, pcrlist.clone()))
+        })
+        .map_err(KeylimeError::from)
+    }
+
+    fn quote_pcrs(
+        &mut self,
+        ak_handle: KeyHandle,
+        ak_policy: Option<&PcrPolicy>,
+        nonce: tss_esapi::structures::Data,
+        sign_scheme: SignatureScheme,
+        pcrlist: PcrSelectionList,
+    ) -> Result<(Attest, Signature)> {
+        if let Some(policy) = ak_policy {
+            return policy.authorize(self, |ctx, session| {
+                ctx.execute_with_session(Some(session), |ctx| {
+                    ctx.quote(
+                        ak_handle,
+                        nonce.clone(),
+                        sign_scheme,
+                        pcrlist.clone(),
+                    )
+                })
+            });
+        }
+        retry_tpm_command(|| {
+            self.execute_with_nullauth_session(|ctx| {
+                ctx.quote(
+                    ak_handle,
+                    nonce.clone(),
+                    sign_scheme,
+                    pcrlist.clone(),
+                )
+            })
+        })
+        .map_err(KeylimeError::from)
+    }
+}
+
 // This function extends Pcr16 with the digest, then creates a PcrList
 // from the given mask and pcr16.
 // Note: Currently, this will build the list for both SHA256 and SHA1 as
 // necessary for the Python components of Keylime.
-pub(crate) fn build_pcr_list(
-    context: &mut Context,
+// The PCRs of the mask are also selected in each of the additional banks,
+// pcr16 is only extended and selected in the bank of hash_alg.
+pub(crate) fn build_pcr_list<T: TpmQuoteOps + ?Sized>(
+    context: &mut T,
     digest: DigestValues,
     mask: Option<&str>,
     hash_alg: HashingAlgorithm,
+    banks: &[HashingAlgorithm],
 ) -> Result<PcrSelectionList> {
     // extend digest into pcr16
-    context.execute_with_nullauth_session(|ctx| {
-        ctx.pcr_reset(PcrHandle::Pcr16)?;
-        ctx.pcr_extend(PcrHandle::Pcr16, digest.to_owned())
-    })?;
+    context.pcr_reset_and_extend(PcrHandle::Pcr16, digest)?;
 
     // translate mask to vec of pcrs
-    let mut pcrs = match mask {
+    let mask_pcrs = match mask {
         Some(m) => read_mask(m)?,
         None => Vec::new(),
     };
+    let mut pcrs = mask_pcrs.clone();
 
     // add pcr16 if it isn't in the vec already
     if !pcrs.iter().any(|&pcr| pcr == PcrSlot::Slot16) {
@@ -562,6 +2219,11 @@ pub(crate) fn build_pcr_list(
 
     let mut pcrlist = PcrSelectionListBuilder::new();
     pcrlist = pcrlist.with_selection(hash_alg, &pcrs);
+    for &bank in banks {
+        if bank != hash_alg && !mask_pcrs.is_empty() {
+            pcrlist = pcrlist.with_selection(bank, &mask_pcrs);
+        }
+    }
     let pcrlist = pcrlist.build()?;
 
     Ok(pcrlist)
@@ -580,15 +2242,48 @@ pub(crate) fn build_pcr_list(
 // https://github.com/keylime/keylime/blob/2dd9e5c968f33bf77110092af9268d13db1806c6/ \
 // keylime/tpm/tpm_main.py#L965
 //
-pub(crate) fn make_pcr_blob(
-    context: &mut Context,
+pub(crate) fn make_pcr_blob<T: TpmQuoteOps + ?Sized>(
+    context: &mut T,
     pcrlist: PcrSelectionList,
 ) -> Result<(PcrSelectionList, PcrData)> {
-    let pcr_data = context
-        .execute_without_session(|ctx| read_all(ctx, pcrlist.clone()))?;
+    let pcr_data = context.pcr_read_all(pcrlist.clone())?;
     Ok((pcrlist, pcr_data))
 }
 
+/*
+ * Input: PCR selection of a quote and the PCR values read for it
+ * Return: Result wrap the hex encoded value of each PCR, by bank
+ */
+pub(crate) fn pcr_banks_to_map(
+    pcrlist: &PcrSelectionList,
+    pcr_data: &PcrData,
+) -> Result<BTreeMap<String, BTreeMap<u32, String>>> {
+    let mut banks = BTreeMap::new();
+    for selection in pcrlist.get_selections() {
+        let bank = HashAlgorithm::try_from(selection.hashing_algorithm())?;
+        let pcr_bank = pcr_data
+            .pcr_bank(selection.hashing_algorithm())
+            .ok_or_else(|| {
+                KeylimeError::Other(format!("no {} bank read", bank))
+            })?;
+        let values: &mut BTreeMap<u32, String> =
+            banks.entry(bank.to_string()).or_default();
+        for slot in selection.selected() {
+            let digest = pcr_bank.get_digest(slot).ok_or_else(|| {
+                KeylimeError::Other(format!(
+                    "PCR {:?} missing from the {} bank",
+                    slot, bank
+                ))
+            })?;
+            let _ = values.insert(
+                u32::from(slot).trailing_zeros(),
+                hex::encode(digest.value()),
+            );
+        }
+    }
+    Ok(banks)
+}
+
 // Takes a TSS ESAPI HashingAlgorithm and returns the corresponding OpenSSL
 // MessageDigest.
 fn hash_alg_to_message_digest(
@@ -597,6 +2292,10 @@ fn hash_alg_to_message_digest(
     match hash_alg {
         HashingAlgorithm::Sha256 => Ok(MessageDigest::sha256()),
         HashingAlgorithm::Sha1 => Ok(MessageDigest::sha1()),
+        HashingAlgorithm::Sha384 => Ok(MessageDigest::sha384()),
+        HashingAlgorithm::Sha512 => Ok(MessageDigest::sha512()),
+        #[cfg(feature = "sm")]
+        HashingAlgorithm::Sm3_256 => Ok(MessageDigest::sm3()),
         other => Err(KeylimeError::Other(format!(
             "Unsupported hashing algorithm: {:?}",
             other
@@ -642,9 +2341,15 @@ fn check_if_pcr_data_and_attestation_match(
 
 const NUM_ATTESTATION_ATTEMPTS: i32 = 5;
 
-fn perform_quote_and_pcr_read(
-    mut context: &mut Context,
+// The PCR values are read before the quote, so they can change in between.
+// The values read are checked against the PCR digest signed in the quote,
+// and read again on mismatch. Verifiers check the same digest, so the values
+// returned are always the ones quoted. Verifiers wanting the reads vouched
+// for by the TPM request them audited, see tpm_audit.rs.
+fn perform_quote_and_pcr_read<T: TpmQuoteOps + ?Sized>(
+    context: &mut T,
     ak_handle: KeyHandle,
+    ak_policy: Option<&PcrPolicy>,
     nonce: &[u8],
     pcrlist: PcrSelectionList,
     sign_scheme: SignatureScheme,
@@ -657,8 +2362,9 @@ fn perform_quote_and_pcr_read(
         let (pcrs_read, pcr_data) = make_pcr_blob(context, pcrlist.clone())?;
 
         // create quote
-        let (attestation, sig) = context.quote(
+        let (attestation, sig) = context.quote_pcrs(
             ak_handle,
+            ak_policy,
             nonce.clone(),
             sign_scheme,
             pcrs_read.clone(),
@@ -686,47 +2392,308 @@ fn perform_quote_and_pcr_read(
     ))
 }
 
-pub(crate) fn quote(
+// Assembles a KeylimeQuote: extends the NK digest into PCR16, reads the
+// selected PCRs, quotes them and encodes the result in the format expected
+// by the Python side of Keylime. When additional banks are requested, the
+// PCR values of every bank quoted are returned alongside. When an IAK handle
+// is given, the same PCRs are quoted again with the IAK, which signs with the
+// scheme of its template. The policy is the one of the signing key, when it
+// is a policy-bound AK.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn assemble_quote<T: TpmQuoteOps + ?Sized>(
+    context: &mut T,
+    ak_handle: KeyHandle,
+    ak_policy: Option<&PcrPolicy>,
+    iak_handle: Option<KeyHandle>,
+    nk_digest: DigestValues,
     nonce: &[u8],
     mask: Option<&str>,
-    data: Data<QuoteData>,
+    banks: &[HashAlgorithm],
+    hash_alg: HashAlgorithm,
+    enc_alg: EncryptionAlgorithm,
+    sign_alg: SignAlgorithm,
 ) -> Result<KeylimeQuote> {
-    let nk_digest = pubkey_to_tpm_digest(&data.pub_key)?;
+    let pcrlist = {
+        let _span = telemetry::span("tpm.build_pcr_list");
+        let banks = banks
+            .iter()
+            .map(|&bank| bank.into())
+            .collect::<Vec<HashingAlgorithm>>();
+        build_pcr_list(context, nk_digest, mask, hash_alg.into(), &banks)?
+    };
 
-    // must unwrap here due to lock mechanism
-    // https://github.com/rust-lang-nursery/failure/issues/192
-    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+    let (attestation, sig, pcrs_read, pcr_data) = {
+        let _span = telemetry::span("tpm.quote_and_pcr_read");
+        perform_quote_and_pcr_read(
+            context,
+            ak_handle,
+            ak_policy,
+            nonce,
+            pcrlist.clone(),
+            sign_alg.to_signature_scheme(hash_alg),
+            hash_alg.into(),
+        )?
+    };
 
-    let pcrlist =
-        build_pcr_list(&mut context, nk_digest, mask, data.hash_alg.into())?;
+    let iak_quote = match iak_handle {
+        Some(iak_handle) => {
+            let _span = telemetry::span("tpm.iak_quote");
+            let (attestation, sig, pcrs_read, pcr_data) =
+                perform_quote_and_pcr_read(
+                    context,
+                    iak_handle,
+                    None,
+                    nonce,
+                    pcrlist,
+                    SignatureScheme::Null,
+                    HashingAlgorithm::Sha256,
+                )?;
+            Some(encode_quote_string(attestation, sig, pcrs_read, pcr_data)?)
+        }
+        None => None,
+    };
 
-    let (attestation, sig, pcrs_read, pcr_data) = context
-        .execute_with_nullauth_session(|ctx| {
-            perform_quote_and_pcr_read(
-                ctx,
-                data.ak_handle,
-                nonce,
-                pcrlist,
-                data.sign_alg.to_signature_scheme(data.hash_alg),
-                data.hash_alg.into(),
-            )
-        })?;
+    let pcr_banks = if banks.is_empty() {
+        None
+    } else {
+        Some(pcr_banks_to_map(&pcrs_read, &pcr_data)?)
+    };
 
     let tpm_quote =
         encode_quote_string(attestation, sig, pcrs_read, pcr_data)?;
 
     Ok(KeylimeQuote {
         quote: tpm_quote,
-        hash_alg: data.hash_alg.to_string(),
-        enc_alg: data.enc_alg.to_string(),
-        sign_alg: data.sign_alg.to_string(),
+        hash_alg: hash_alg.to_string(),
+        enc_alg: enc_alg.to_string(),
+        sign_alg: sign_alg.to_string(),
         pubkey: None,
         ima_measurement_list: None,
         mb_measurement_list: None,
+        drtm_measurement_list: None,
+        tee_evidence: None,
         ima_measurement_list_entry: None,
+        ima_measurement_list_next_entry: None,
+        ima_policy_digest: None,
+        ima_measurement_count: None,
+        temporarily_unavailable: Vec::new(),
+        boottime: None,
+        clock_info: None,
+        runtime_inventory: None,
+        pcr_banks,
+        iak_quote,
+        quote_key: None,
+        verifier_ak: None,
+        pcr_read_audit: None,
     })
 }
 
+pub(crate) fn quote(
+    context: &mut Context,
+    nonce: &[u8],
+    mask: Option<&str>,
+    banks: &[HashAlgorithm],
+    iak_cosign: bool,
+    verifier: Option<&str>,
+    data: &QuoteData,
+) -> Result<KeylimeQuote> {
+    let span = telemetry::span("tpm.quote");
+    let _guard = span.attach();
+
+    let nk_digest = pubkey_to_tpm_digest(&data.pub_key, data.hash_alg)?;
+    let iak_handle = if iak_cosign {
+        Some(data.tpm.key(data.iak_handle.ok_or_else(|| {
+            KeylimeError::Other(
+                "quote co-signing requested but no IAK is loaded".to_string(),
+            )
+        })?))
+    } else {
+        None
+    };
+
+    // Signed by the activated AK of the verifier when it has one, else by
+    // the quote key certified by the AK when enabled
+    let verifier_key = match (&data.ak_registry, verifier) {
+        (Some(registry), Some(verifier)) => registry
+            .signing_key(context, data, verifier)?
+            .map(|key| (registry, verifier, key)),
+        _ => None,
+    };
+    let (sign_handle, sign_policy, quote_key, verifier_ak) =
+        match (&verifier_key, &data.quote_keys) {
+            (Some((_, _, (handle, ak_tpm))), _) => {
+                (*handle, data.ak_policy.as_ref(), None, Some(ak_tpm.clone()))
+            }
+            (None, Some(keys)) => {
+                let (handle, certification) =
+                    keys.signing_key(context, data)?;
+                (handle, None, Some(certification), None)
+            }
+            (None, None) => (
+                data.tpm.key(data.ak_handle),
+                data.ak_policy.as_ref(),
+                None,
+                None,
+            ),
+        };
+    let with_quote_key = |quote: KeylimeQuote| KeylimeQuote {
+        quote_key: quote_key.clone(),
+        verifier_ak: verifier_ak.clone(),
+        ..quote
+    };
+
+    let assemble = |tpm: &mut dyn TpmQuoteOps| {
+        assemble_quote(
+            tpm,
+            sign_handle,
+            sign_policy,
+            iak_handle,
+            nk_digest,
+            nonce,
+            mask,
+            banks,
+            data.hash_alg,
+            data.enc_alg,
+            data.sign_alg,
+        )
+    };
+
+    // Unloads the AK of the verifier, whether the quote succeeded or not
+    let release = |context: &mut Context| {
+        if let Some((registry, verifier, (handle, _))) = &verifier_key {
+            registry.release(context, data, verifier, *handle);
+        }
+    };
+
+    // When recording is enabled, capture the TSS calls made while
+    // assembling the quote so they can be replayed in tests
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "tpm-replay")] {
+            if let Some(path) = crate::tpm_replay::record_path() {
+                let result = crate::tpm_replay::record(
+                    context,
+                    &path,
+                    assemble,
+                );
+                release(context);
+                return result.map(with_quote_key);
+            }
+        }
+    }
+
+    let result = match assemble(context) {
+        // Make room by flushing what earlier operations left behind, and
+        // retry once rather than failing until the agent is restarted
+        Err(KeylimeError::TpmObjectMemory(e)) => {
+            let flushed = data.tpm_handles.flush_stale(context);
+            warn!(
+                "TPM out of object memory ({}), flushed {} stale handles and retrying the quote",
+                e, flushed
+            );
+            assemble(context)
+        }
+        result => result,
+    };
+    release(context);
+    result.map(with_quote_key)
+}
+
+/*
+ * Input: Connection context, handle of the key to certify, handle of the
+ *        certifying key, its policy when it is a policy-bound AK, and nonce
+ * Return: The marshalled TPMS_ATTEST and TPMT_SIGNATURE of TPM2_Certify
+ *
+ * The certifying key signs with the scheme of its template. Both keys are
+ * authorized with their empty password, unless the certifying key has a
+ * policy.
+ */
+pub(crate) fn certify(
+    context: &mut Context,
+    object_handle: KeyHandle,
+    sign_handle: KeyHandle,
+    sign_policy: Option<&PcrPolicy>,
+    nonce: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let nonce: tss_esapi::structures::Data = nonce.try_into()?;
+    let certify = |ctx: &mut Context, sign_session: AuthSession| {
+        ctx.execute_with_sessions(
+            (Some(AuthSession::Password), Some(sign_session), None),
+            |ctx| {
+                ctx.certify(
+                    object_handle.into(),
+                    sign_handle,
+                    nonce.clone(),
+                    SignatureScheme::Null,
+                )
+            },
+        )
+    };
+    let (attestation, signature) = match sign_policy {
+        Some(policy) => policy.authorize(context, certify)?,
+        None => {
+            retry_tpm_command(|| certify(context, AuthSession::Password))?
+        }
+    };
+    Ok((attestation.marshall()?, signature.marshall()?))
+}
+
+/*
+ * Input: nonce, PCR mask, additional PCR banks, whether to co-sign with the
+ *        IAK, verifier whose AK signs the quote, agent data and the span of
+ *        the request
+ * Return: Result wrap the quote
+ *
+ * Runs quote() on the TPM service, giving up after the configured
+ * tpm_operation_timeout. A quote still queued at the deadline is dropped. A
+ * command sent to the TPM cannot be interrupted, so a quote already running
+ * completes in the background and then releases the context, which stays
+ * usable by the next requests.
+ */
+pub(crate) async fn timed_quote(
+    nonce: &[u8],
+    mask: Option<&str>,
+    banks: &[HashAlgorithm],
+    iak_cosign: bool,
+    verifier: Option<&str>,
+    data: Data<QuoteData>,
+    parent: &telemetry::Span,
+) -> Result<KeylimeQuote> {
+    let timeout = data.tpm_operation_timeout;
+    let status = data.status.clone();
+    let nonce = nonce.to_vec();
+    let mask = mask.map(str::to_string);
+    let banks = banks.to_vec();
+    let verifier = verifier.map(str::to_string);
+    let span = parent.child("tpm.service");
+    let quote_data = data.clone();
+    let task = data.tpm.run_idempotent(move |context| {
+        let _guard = span.attach();
+        let result = quote(
+            context,
+            &nonce,
+            mask.as_deref(),
+            &banks,
+            iak_cosign,
+            verifier.as_deref(),
+            &quote_data,
+        );
+        quote_data.status.tpm_completed();
+        result
+    });
+
+    match tokio::time::timeout(timeout, task).await {
+        Ok(result) => result,
+        Err(_) => {
+            status.tpm_timed_out();
+            warn!(
+                "TPM quote did not complete within {} seconds, it keeps running in the background",
+                timeout.as_secs()
+            );
+            Err(KeylimeError::TpmTimeout(timeout.as_secs()))
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod testing {
     use super::*;
@@ -736,7 +2703,7 @@ pub mod testing {
 
     macro_rules! create_unmarshal_fn {
         ($func:ident, $tpmobj:ty, $unmarshal:ident) => {
-            fn $func(val: &[u8]) -> Result<$tpmobj> {
+            pub(crate) fn $func(val: &[u8]) -> Result<$tpmobj> {
                 let mut resp = <$tpmobj>::default();
                 let mut offset = 0;
 
@@ -764,6 +2731,12 @@ pub mod testing {
         Tss2_MU_TPMT_SIGNATURE_Unmarshal
     );
 
+    // Builds an Attest from its marshalled form
+    pub(crate) fn vec_to_attest(val: &[u8]) -> Result<Attest> {
+        let att: AttestBuffer = val.try_into()?;
+        att.try_into().map_err(KeylimeError::from)
+    }
+
     fn vec_to_pcrdata(val: &[u8]) -> Result<(PcrSelectionList, PcrData)> {
         let mut reader = std::io::Cursor::new(val);
         let mut pcrsel_vec = [0u8; TPML_PCR_SELECTION_SIZE];
@@ -927,7 +2900,109 @@ fn quote_encode_decode() {
 #[test]
 fn pubkey_to_digest() {
     let (key, _) = crate::crypto::rsa_generate_pair(2048).unwrap(); //#[allow_ci]
-    let digest = pubkey_to_tpm_digest(&key).unwrap(); //#[allow_ci]
+    let digest = pubkey_to_tpm_digest(&key, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+}
+
+#[test]
+fn tcti_backends() {
+    assert!(parse_tcti("device:/dev/tpmrm0").is_ok());
+    assert!(parse_tcti("swtpm:host=localhost,port=2321").is_ok());
+    assert!(parse_tcti("mssim:").is_ok());
+
+    let err = parse_tcti("libtpms:").unwrap_err(); //#[allow_ci]
+    assert!(matches!(err, KeylimeError::Configuration(_)));
+    assert!(err.to_string().contains("device, mssim, swtpm, tabrmd"));
+    assert!(parse_tcti("swtpm:port=notaport").is_err());
+}
+
+#[test]
+fn short_keyblobs() {
+    let mut keyblob = TSS_MAGIC.to_be_bytes().to_vec();
+    keyblob.extend_from_slice(&1u32.to_be_bytes());
+    assert!(parse_cred_and_secret(keyblob.clone()).is_err());
+    // A credential of 16 bytes announced, none sent
+    keyblob.extend_from_slice(&16u16.to_be_bytes());
+    assert!(parse_cred_and_secret(keyblob).is_err());
+}
+
+#[test]
+fn retry_jitter() {
+    let delay = Duration::from_millis(400);
+    assert_eq!(jittered_delay(delay, 0), Duration::from_millis(200));
+    assert_eq!(jittered_delay(delay, u32::MAX), delay);
+    let wait = jittered_delay(delay, u32::MAX / 2);
+    assert!(wait > Duration::from_millis(200) && wait < delay);
+}
+
+#[test]
+fn tpm_wait_delay() {
+    let long = Duration::from_secs(600);
+    assert_eq!(
+        next_tpm_wait_delay(Duration::from_secs(1), long),
+        Duration::from_secs(2)
+    );
+    assert_eq!(
+        next_tpm_wait_delay(Duration::from_secs(16), long),
+        TPM_WAIT_MAX_DELAY
+    );
+    assert_eq!(
+        next_tpm_wait_delay(Duration::from_secs(4), Duration::from_secs(3)),
+        Duration::from_secs(3)
+    );
+}
+
+#[test]
+fn retry_tpm_commands() {
+    use tss_esapi::constants::{
+        response_code::Tss2ResponseCode,
+        tss::{TPM2_RC_RETRY, TPM2_RC_TESTING, TPM2_RC_VALUE},
+    };
+
+    let refusal =
+        |rc| tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(rc));
+
+    // Resubmitted until the TPM runs it
+    let mut calls = 0;
+    let result = retry_tpm_command(|| {
+        calls += 1;
+        match calls {
+            1 => Err(refusal(TPM2_RC_RETRY)),
+            2 => Err(refusal(TPM2_RC_TESTING)),
+            _ => Ok(calls),
+        }
+    });
+    assert_eq!(result.unwrap(), 3); //#[allow_ci]
+
+    // Other failures are not retried
+    let mut calls = 0;
+    let result: tss_esapi::Result<()> = retry_tpm_command(|| {
+        calls += 1;
+        Err(refusal(TPM2_RC_VALUE))
+    });
+    assert!(result.is_err());
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn communication_errors() {
+    use tss_esapi::constants::tss::{TPM2_RC_RETRY, TPM2_RC_VALUE};
+
+    let error = |rc| {
+        KeylimeError::from(tss_esapi::Error::Tss2Error(
+            Tss2ResponseCode::from(rc),
+        ))
+    };
+    assert!(is_communication_error(&error(
+        TSS2_TCTI_RC_LAYER | TSS2_BASE_RC_IO_ERROR
+    )));
+    assert!(is_communication_error(&error(
+        TSS2_RESMGR_RC_LAYER | TSS2_BASE_RC_NO_CONNECTION
+    )));
+    assert!(!is_communication_error(&error(TPM2_RC_VALUE)));
+    assert!(!is_communication_error(&error(TPM2_RC_RETRY)));
+    assert!(!is_communication_error(&KeylimeError::Other(
+        "failed".to_string()
+    )));
 }
 
 #[test]
@@ -984,3 +3059,278 @@ fn mask() {
 
     assert!(read_mask("0x1ffffff").is_err());
 }
+
+#[test]
+fn add_to_mask() {
+    assert_eq!(add_pcr_to_mask("0x0", 10).unwrap(), "0x400"); //#[allow_ci]
+    assert_eq!(add_pcr_to_mask("0x408000", 10).unwrap(), "0x408400"); //#[allow_ci]
+    assert_eq!(add_pcr_to_mask("0x400", 10).unwrap(), "0x400"); //#[allow_ci]
+    assert_eq!(add_pcr_to_mask("1", 23).unwrap(), "0x800001"); //#[allow_ci]
+    assert!(add_pcr_to_mask("0x0", 24).is_err());
+    assert!(add_pcr_to_mask("0xz", 10).is_err());
+}
+
+#[test]
+fn unallocated() {
+    assert_eq!(unallocated_pcrs("0x408000", 0xffffff).unwrap(), vec![]); //#[allow_ci]
+    assert_eq!(unallocated_pcrs("0x408000", 0xffff).unwrap(), vec![22]); //#[allow_ci]
+    assert_eq!(unallocated_pcrs("0x5", 0x0).unwrap(), vec![0, 2]); //#[allow_ci]
+    assert!(unallocated_pcrs("0x1000000", 0xffffffff).is_err());
+    assert!(unallocated_pcrs("0xz", 0xffffff).is_err());
+}
+
+#[test]
+fn nv_read_chunks() {
+    assert_eq!(nv_chunks(0, 512).unwrap(), vec![]); //#[allow_ci]
+    assert_eq!(nv_chunks(512, 512).unwrap(), vec![(0, 512)]); //#[allow_ci]
+    assert_eq!(
+        nv_chunks(1300, 512).unwrap(), //#[allow_ci]
+        vec![(0, 512), (512, 512), (1024, 276)]
+    );
+    assert!(nv_chunks(70000, 1024).is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn nv_read_index() {
+    use tss_esapi::structures::MaxNvBuffer;
+
+    // Larger than the TPM buffer, so that it is read in several chunks
+    let content = (0..1500).map(|i| i as u8).collect::<Vec<u8>>();
+    let index = NvIndexTpmHandle::new(0x1500016).unwrap(); //#[allow_ci]
+    let mut context = get_tpm2_ctx().unwrap(); //#[allow_ci]
+    let attributes = NvIndexAttributesBuilder::new()
+        .with_owner_write(true)
+        .with_owner_read(true)
+        .build()
+        .unwrap(); //#[allow_ci]
+    let public = NvPublicBuilder::new()
+        .with_nv_index(index)
+        .with_index_name_algorithm(HashingAlgorithm::Sha256)
+        .with_index_attributes(attributes)
+        .with_data_area_size(content.len())
+        .build()
+        .unwrap(); //#[allow_ci]
+    let nv_handle = context
+        .execute_with_nullauth_session(|ctx| {
+            ctx.nv_define_space(Provision::Owner, None, public)
+        })
+        .unwrap(); //#[allow_ci]
+    let chunks = nv_chunks(content.len(), 512).unwrap(); //#[allow_ci]
+    for (offset, size) in chunks {
+        let range = offset as usize..(offset + size) as usize;
+        let chunk = MaxNvBuffer::try_from(content[range].to_vec()).unwrap(); //#[allow_ci]
+        context
+            .execute_with_nullauth_session(|ctx| {
+                ctx.nv_write(NvAuth::Owner, nv_handle, chunk, offset)
+            })
+            .unwrap(); //#[allow_ci]
+    }
+
+    let result = nv_read(&mut context, 0x1500016);
+    context
+        .execute_with_nullauth_session(|ctx| {
+            ctx.nv_undefine_space(Provision::Owner, nv_handle)
+        })
+        .unwrap(); //#[allow_ci]
+    assert_eq!(result.unwrap(), content); //#[allow_ci]
+    assert!(nv_read(&mut context, 0x1500016).is_err());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn nv_counter_increment() {
+    let mut context = get_tpm2_ctx().unwrap(); //#[allow_ci]
+    let value = nv_counter(&mut context, 0x1500017).unwrap(); //#[allow_ci]
+    assert_eq!(nv_counter(&mut context, 0x1500017).unwrap(), value); //#[allow_ci]
+    let result = nv_increment(&mut context, 0x1500017);
+
+    let nv_index = NvIndexTpmHandle::new(0x1500017).unwrap(); //#[allow_ci]
+    let nv_handle = NvIndexHandle::from(
+        context
+            .tr_from_tpm_public(TpmHandle::NvIndex(nv_index))
+            .unwrap(), //#[allow_ci]
+    );
+    context
+        .execute_with_nullauth_session(|ctx| {
+            ctx.nv_undefine_space(Provision::Owner, nv_handle)
+        })
+        .unwrap(); //#[allow_ci]
+    assert_eq!(result.unwrap(), value + 1); //#[allow_ci]
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn load_ak_encrypted_session() {
+    let mut context = get_tpm2_ctx().unwrap(); //#[allow_ci]
+    let handles = HandleRegistry::default();
+    let ek =
+        create_ek(&mut context, &handles, AsymmetricAlgorithm::Rsa, None)
+            .unwrap(); //#[allow_ci]
+    let ak = create_ak(
+        &mut context,
+        ek.key_handle,
+        HashingAlgorithm::Sha256,
+        SignatureSchemeAlgorithm::RsaSsa,
+        None,
+        None,
+    )
+    .unwrap(); //#[allow_ci]
+
+    // The same AK is loaded, whether the session is salted or not
+    let mut names = Vec::new();
+    for encrypt_sessions in [false, true] {
+        let ak_handle = load_ak(
+            &mut context,
+            &handles,
+            ek.key_handle,
+            &ak,
+            encrypt_sessions,
+        )
+        .unwrap(); //#[allow_ci]
+        let (_, name, _) = context.read_public(ak_handle).unwrap(); //#[allow_ci]
+        names.push(name.value().to_vec());
+        context.flush_context(ak_handle.into()).unwrap(); //#[allow_ci]
+        handles.release(ak_handle.into());
+    }
+    assert_eq!(names[0], names[1]);
+    context.flush_context(ek.key_handle.into()).unwrap(); //#[allow_ci]
+    handles.release(ek.key_handle.into());
+
+    // The policy sessions were flushed
+    assert!(handles.counts().is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn policy_bound_ak() {
+    let mut context = get_tpm2_ctx().unwrap(); //#[allow_ci]
+    let handles = HandleRegistry::default();
+    let ek =
+        create_ek(&mut context, &handles, AsymmetricAlgorithm::Rsa, None)
+            .unwrap(); //#[allow_ci]
+    context
+        .execute_with_nullauth_session(|ctx| ctx.pcr_reset(PcrHandle::Pcr23))
+        .unwrap(); //#[allow_ci]
+    let policy = PcrPolicy::new(HashingAlgorithm::Sha256, &[23], false)
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+    let ak = create_ak(
+        &mut context,
+        ek.key_handle,
+        HashingAlgorithm::Sha256,
+        SignatureSchemeAlgorithm::RsaSsa,
+        None,
+        Some(&policy),
+    )
+    .unwrap(); //#[allow_ci]
+    assert_eq!(
+        ak.public.auth_policy(),
+        &policy.digest(&mut context).unwrap() //#[allow_ci]
+    );
+    let ak_handle =
+        load_ak(&mut context, &handles, ek.key_handle, &ak, false).unwrap(); //#[allow_ci]
+
+    let pcrlist = PcrSelectionListBuilder::new()
+        .with_selection(HashingAlgorithm::Sha256, &[PcrSlot::Slot23])
+        .build()
+        .unwrap(); //#[allow_ci]
+    let nonce =
+        tss_esapi::structures::Data::try_from(b"nonce".to_vec()).unwrap(); //#[allow_ci]
+    let quote = |context: &mut Context, policy: Option<&PcrPolicy>| {
+        context.quote_pcrs(
+            ak_handle,
+            policy,
+            nonce.clone(),
+            SignatureScheme::Null,
+            pcrlist.clone(),
+        )
+    };
+    // The password does not authorize the AK anymore
+    assert!(quote(&mut context, None).is_err());
+    assert!(quote(&mut context, Some(&policy)).is_ok());
+
+    // Neither does the policy once the PCR changed
+    let mut digest = DigestValues::new();
+    digest.set(
+        HashingAlgorithm::Sha256,
+        Digest::try_from(vec![1u8; 32]).unwrap(), //#[allow_ci]
+    );
+    context
+        .execute_with_nullauth_session(|ctx| {
+            ctx.pcr_extend(PcrHandle::Pcr23, digest)
+        })
+        .unwrap(); //#[allow_ci]
+    assert!(quote(&mut context, Some(&policy)).is_err());
+
+    context.flush_context(ak_handle.into()).unwrap(); //#[allow_ci]
+    handles.release(ak_handle.into());
+    context.flush_context(ek.key_handle.into()).unwrap(); //#[allow_ci]
+    handles.release(ek.key_handle.into());
+    assert!(handles.counts().is_empty());
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn seal_unseal() {
+    let mut context = get_tpm2_ctx().unwrap(); //#[allow_ci]
+    let handles = HandleRegistry::default();
+    let (public, private) =
+        seal(&mut context, &handles, b"secret", None).unwrap(); //#[allow_ci]
+    assert_eq!(
+        unseal(&mut context, &handles, public, private, None).unwrap(), //#[allow_ci]
+        b"secret"
+    );
+
+    context
+        .execute_with_nullauth_session(|ctx| ctx.pcr_reset(PcrHandle::Pcr23))
+        .unwrap(); //#[allow_ci]
+    let policy = PcrPolicy::new(HashingAlgorithm::Sha256, &[23], false)
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+    let (public, private) =
+        seal(&mut context, &handles, b"secret", Some(&policy)).unwrap(); //#[allow_ci]
+    let sealed = || (public.clone(), private.clone());
+    let (p, s) = sealed();
+    assert!(unseal(&mut context, &handles, p, s, None).is_err());
+    let (p, s) = sealed();
+    assert!(unseal(&mut context, &handles, p, s, Some(&policy)).is_ok());
+
+    // Not once the PCR changed
+    let mut digest = DigestValues::new();
+    digest.set(
+        HashingAlgorithm::Sha256,
+        Digest::try_from(vec![1u8; 32]).unwrap(), //#[allow_ci]
+    );
+    context
+        .execute_with_nullauth_session(|ctx| {
+            ctx.pcr_extend(PcrHandle::Pcr23, digest)
+        })
+        .unwrap(); //#[allow_ci]
+    let (p, s) = sealed();
+    assert!(unseal(&mut context, &handles, p, s, Some(&policy)).is_err());
+    assert!(handles.counts().is_empty());
+}
+
+#[test]
+fn handle_registry_stale() {
+    let handles = HandleRegistry::default();
+    let ak = ObjectHandle::from(1);
+    let ek = ObjectHandle::from(2);
+    let session = ObjectHandle::from(3);
+    handles.register(ak, "load_ak", true);
+    handles.register(ek, "create_ek", false);
+    handles.register(session, "activate_credential", false);
+    handles.release(ek);
+    let counts = handles.counts();
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts["load_ak"], 1);
+    assert_eq!(counts["activate_credential"], 1);
+
+    let stale = handles.take_stale();
+    assert_eq!(stale.len(), 1);
+    assert_eq!(stale[0].handle, session);
+    assert_eq!(stale[0].owner, "activate_credential");
+    assert_eq!(handles.counts().len(), 1);
+    assert!(handles.take_stale().is_empty());
+}
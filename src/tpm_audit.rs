@@ -0,0 +1,399 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Audit of the PCR reads of integrity quotes.
+//
+// With "audit=1" in the quote request, the PCRs of the quote are also read
+// in an HMAC session with the audit attribute set. The session audit digest,
+// which covers the TPM2_PCR_Read commands and their responses, is then
+// signed by the AK with TPM2_GetSessionAuditDigest, qualified with the nonce
+// of the quote. The verifier recomputes the digest from the PCR values
+// returned and checks them against the PCR digest of the quote, so that the
+// PCR values are vouched for by the TPM rather than by the agent.
+//
+// tss-esapi 7.1 offers neither TPM2_GetSessionAuditDigest nor access to the
+// ESAPI context it wraps, so the audited commands go through the ESAPI
+// directly, on a second connection to the TPM. The AK is copied to that
+// connection with TPM2_ContextSave and TPM2_ContextLoad, which needs a
+// resource manager: the TCTI must be the kernel one, /dev/tpmrm0, or
+// tpm2-abrmd. The audit is signed with an empty endorsement hierarchy
+// authorization and is not available with a policy-bound AK.
+
+use crate::{
+    error::{Error, Result},
+    tpm, QuoteData,
+};
+use actix_web::web::Data;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap, convert::TryFrom, ffi::CString, ptr::null_mut,
+};
+use tss_esapi::{
+    abstraction::pcr::PcrData,
+    constants::{
+        response_code::Tss2ResponseCode,
+        tss::{
+            TPM2_SE_HMAC, TPMA_SESSION_AUDIT, TPMA_SESSION_CONTINUESESSION,
+        },
+    },
+    handles::KeyHandle,
+    interface_types::algorithm::HashingAlgorithm,
+    structures::{
+        Data as TpmData, DigestList, PcrSelectionList,
+        PcrSelectionListBuilder, PcrSlot, Signature, SignatureScheme,
+        SymmetricDefinition,
+    },
+    traits::Marshall,
+    tss2_esys::{
+        Esys_ContextLoad, Esys_Finalize, Esys_FlushContext, Esys_Free,
+        Esys_GetSessionAuditDigest, Esys_Initialize, Esys_PCR_Read,
+        Esys_StartAuthSession, Esys_TRSess_SetAttributes,
+        Tss2_TctiLdr_Finalize, Tss2_TctiLdr_Initialize, ESYS_CONTEXT,
+        ESYS_TR, ESYS_TR_NONE, ESYS_TR_PASSWORD, ESYS_TR_RH_ENDORSEMENT,
+        TPM2B_ATTEST, TPM2B_DATA, TPML_DIGEST, TPML_PCR_SELECTION,
+        TPMS_CONTEXT, TPMT_SIGNATURE, TPMT_SIG_SCHEME, TPMT_SYM_DEF, TSS2_RC,
+        TSS2_TCTI_CONTEXT,
+    },
+    Context,
+};
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct AuditedPcrRead {
+    pub pcr_update_counter: u32,
+    // Hex encoded value of each PCR read, by bank
+    pub pcrs: BTreeMap<String, BTreeMap<u32, String>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct PcrReadAudit {
+    // Base64 encoded TPMS_ATTEST and TPMT_SIGNATURE of
+    // TPM2_GetSessionAuditDigest
+    pub attest: String,
+    pub signature: String,
+    // Responses of the audited TPM2_PCR_Read commands, in order. A command
+    // returns at most 8 PCRs, the remaining ones are read by the next.
+    pub reads: Vec<AuditedPcrRead>,
+}
+
+fn check(rc: TSS2_RC) -> Result<()> {
+    if rc == 0 {
+        return Ok(());
+    }
+    Err(tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(rc)).into())
+}
+
+// Copies a structure allocated by the ESAPI and frees it
+unsafe fn take<T: Copy>(ptr: *mut T) -> T {
+    let value = *ptr;
+    Esys_Free(ptr.cast());
+    value
+}
+
+// Second ESAPI connection to the TPM, closed when dropped
+struct Connection {
+    tcti: *mut TSS2_TCTI_CONTEXT,
+    esys: *mut ESYS_CONTEXT,
+}
+
+impl Connection {
+    fn open() -> Result<Self> {
+        let name = tpm::tcti_name();
+        if !name.starts_with("device:/dev/tpmrm")
+            && !name.starts_with("tabrmd")
+        {
+            return Err(Error::Configuration(format!(
+                "audited PCR reads need a TPM resource manager, the TCTI {} has none",
+                name
+            )));
+        }
+        let conf = CString::try_from(tpm::parse_tcti(&name)?)?;
+        let mut connection = Connection {
+            tcti: null_mut(),
+            esys: null_mut(),
+        };
+        check(unsafe {
+            Tss2_TctiLdr_Initialize(conf.as_ptr(), &mut connection.tcti)
+        })?;
+        check(unsafe {
+            Esys_Initialize(&mut connection.esys, connection.tcti, null_mut())
+        })?;
+        Ok(connection)
+    }
+
+    fn flush(&self, handle: ESYS_TR) {
+        if handle != ESYS_TR_NONE {
+            let _ = unsafe { Esys_FlushContext(self.esys, handle) };
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.esys.is_null() {
+                Esys_Finalize(&mut self.esys);
+            }
+            if !self.tcti.is_null() {
+                Tss2_TctiLdr_Finalize(&mut self.tcti);
+            }
+        }
+    }
+}
+
+/*
+ * Input: connection, AK and audit session, nonce, PCR selection and
+ *        signature scheme
+ * Return: Result wrap the audit and the PCRs read
+ */
+fn read_and_sign(
+    connection: &Connection,
+    ak: ESYS_TR,
+    session: ESYS_TR,
+    nonce: &[u8],
+    mut pcrlist: PcrSelectionList,
+    sign_scheme: SignatureScheme,
+) -> Result<(PcrReadAudit, PcrData)> {
+    let mut reads = Vec::new();
+    let mut pcr_data = PcrData::new();
+    while !pcrlist.is_empty() {
+        let selection: TPML_PCR_SELECTION = pcrlist.clone().into();
+        let mut pcr_update_counter = 0;
+        let mut selection_out = null_mut();
+        let mut values = null_mut();
+        check(unsafe {
+            Esys_PCR_Read(
+                connection.esys,
+                session,
+                ESYS_TR_NONE,
+                ESYS_TR_NONE,
+                &selection,
+                &mut pcr_update_counter,
+                &mut selection_out,
+                &mut values,
+            )
+        })?;
+        let (selection_out, values): (TPML_PCR_SELECTION, TPML_DIGEST) =
+            unsafe { (take(selection_out), take(values)) };
+        let pcrs_read = PcrSelectionList::try_from(selection_out)?;
+        let digests = DigestList::try_from(values)?;
+        let read = PcrData::create(&pcrs_read, &digests)?;
+        reads.push(AuditedPcrRead {
+            pcr_update_counter,
+            pcrs: tpm::pcr_banks_to_map(&pcrs_read, &read)?,
+        });
+        pcr_data.add(&pcrs_read, &digests)?;
+        pcrlist.subtract(&pcrs_read)?;
+    }
+
+    let qualifying_data: TPM2B_DATA = TpmData::try_from(nonce)?.into();
+    let scheme: TPMT_SIG_SCHEME = sign_scheme.into();
+    let mut attest = null_mut();
+    let mut signature = null_mut();
+    check(unsafe {
+        Esys_GetSessionAuditDigest(
+            connection.esys,
+            ESYS_TR_RH_ENDORSEMENT,
+            ak,
+            session,
+            ESYS_TR_PASSWORD,
+            ESYS_TR_PASSWORD,
+            ESYS_TR_NONE,
+            &qualifying_data,
+            &scheme,
+            &mut attest,
+            &mut signature,
+        )
+    })?;
+    let (attest, signature): (TPM2B_ATTEST, TPMT_SIGNATURE) =
+        unsafe { (take(attest), take(signature)) };
+    let attest = &attest.attestationData[..attest.size as usize];
+    let signature = Signature::try_from(signature)?.marshall()?;
+
+    Ok((
+        PcrReadAudit {
+            attest: base64::encode(attest),
+            signature: base64::encode(signature),
+            reads,
+        },
+        pcr_data,
+    ))
+}
+
+/*
+ * Input: TPM context, AK, nonce, PCR selection, hash algorithm of the audit
+ *        session and signature scheme of the AK
+ * Return: Result wrap the signed audit of the PCR reads and the PCRs read
+ */
+pub(crate) fn audited_pcr_read(
+    context: &mut Context,
+    ak_handle: KeyHandle,
+    nonce: &[u8],
+    pcrlist: PcrSelectionList,
+    hash_alg: HashingAlgorithm,
+    sign_scheme: SignatureScheme,
+) -> Result<(PcrReadAudit, PcrData)> {
+    let ak_context =
+        TPMS_CONTEXT::try_from(context.context_save(ak_handle.into())?)?;
+    let symmetric = TPMT_SYM_DEF::try_from(SymmetricDefinition::Null)?;
+
+    let connection = Connection::open()?;
+    let mut ak = ESYS_TR_NONE;
+    check(unsafe {
+        Esys_ContextLoad(connection.esys, &ak_context, &mut ak)
+    })?;
+    let mut session = ESYS_TR_NONE;
+    let result = check(unsafe {
+        Esys_StartAuthSession(
+            connection.esys,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            ESYS_TR_NONE,
+            std::ptr::null(),
+            TPM2_SE_HMAC,
+            &symmetric,
+            hash_alg.into(),
+            &mut session,
+        )
+    })
+    .and_then(|_| {
+        let attributes = TPMA_SESSION_AUDIT | TPMA_SESSION_CONTINUESESSION;
+        check(unsafe {
+            Esys_TRSess_SetAttributes(
+                connection.esys,
+                session,
+                attributes,
+                attributes,
+            )
+        })
+    })
+    .and_then(|_| {
+        read_and_sign(&connection, ak, session, nonce, pcrlist, sign_scheme)
+    });
+    connection.flush(session);
+    connection.flush(ak);
+    result
+}
+
+/*
+ * Input: PCR mask of the quote and its hash algorithm
+ * Return: Result wrap the PCRs audited, the ones of the mask and PCR 16
+ */
+fn audit_selection(
+    mask: &str,
+    hash_alg: HashingAlgorithm,
+) -> Result<PcrSelectionList> {
+    let mut pcrs = tpm::read_mask(mask)?;
+    if !pcrs.contains(&PcrSlot::Slot16) {
+        pcrs.push(PcrSlot::Slot16);
+    }
+    Ok(PcrSelectionListBuilder::new()
+        .with_selection(hash_alg, &pcrs)
+        .build()?)
+}
+
+/*
+ * Input: agent data, nonce and PCR mask of the quote
+ * Return: Result wrap the signed audit of the PCR reads
+ *
+ * Runs on the TPM service, with the AK registered for the agent.
+ */
+pub(crate) async fn audit_pcr_reads(
+    data: Data<QuoteData>,
+    nonce: Vec<u8>,
+    mask: String,
+) -> Result<PcrReadAudit> {
+    let quote_data = data.clone();
+    data.tpm
+        .run_idempotent(move |context| {
+            let hash_alg: HashingAlgorithm = quote_data.hash_alg.into();
+            let pcrlist = audit_selection(&mask, hash_alg)?;
+            let result = audited_pcr_read(
+                context,
+                quote_data.tpm.key(quote_data.ak_handle),
+                &nonce,
+                pcrlist,
+                hash_alg,
+                quote_data.sign_alg.to_signature_scheme(quote_data.hash_alg),
+            );
+            quote_data.status.tpm_completed();
+            result.map(|(audit, _)| audit)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_audit_selection() {
+        let pcrlist =
+            audit_selection("0x408000", HashingAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        let selections = pcrlist.get_selections();
+        assert_eq!(selections.len(), 1);
+        assert_eq!(
+            selections[0].selected(),
+            vec![PcrSlot::Slot15, PcrSlot::Slot16, PcrSlot::Slot22]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_audited_pcr_read() {
+        use tss_esapi::interface_types::algorithm::{
+            AsymmetricAlgorithm, SignatureSchemeAlgorithm,
+        };
+
+        if Connection::open().is_err() {
+            // The TPM is reached without a resource manager
+            return;
+        }
+        let mut context = tpm::get_tpm2_ctx().unwrap(); //#[allow_ci]
+        let handles = tpm::HandleRegistry::default();
+        let ek = tpm::create_ek(
+            &mut context,
+            &handles,
+            AsymmetricAlgorithm::Rsa,
+            None,
+        )
+        .unwrap(); //#[allow_ci]
+        let ak = tpm::create_ak(
+            &mut context,
+            ek.key_handle,
+            HashingAlgorithm::Sha256,
+            SignatureSchemeAlgorithm::RsaSsa,
+            None,
+            None,
+        )
+        .unwrap(); //#[allow_ci]
+        let ak_handle =
+            tpm::load_ak(&mut context, &handles, ek.key_handle, &ak, false)
+                .unwrap(); //#[allow_ci]
+
+        let pcrlist =
+            audit_selection("0x1", HashingAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        let (audit, pcr_data) = audited_pcr_read(
+            &mut context,
+            ak_handle,
+            b"nonce",
+            pcrlist,
+            HashingAlgorithm::Sha256,
+            SignatureScheme::Null,
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(audit.reads.len(), 1);
+        assert_eq!(audit.reads[0].pcrs["sha256"].len(), 2);
+        assert!(pcr_data.pcr_bank(HashingAlgorithm::Sha256).is_some());
+
+        let attest = tpm::testing::vec_to_attest(
+            &base64::decode(&audit.attest).unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(attest.extra_data().value(), b"nonce");
+
+        context.flush_context(ak_handle.into()).unwrap(); //#[allow_ci]
+        context.flush_context(ek.key_handle.into()).unwrap(); //#[allow_ci]
+    }
+}
@@ -0,0 +1,403 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// What the TPM of the agent is and which algorithms it implements.
+//
+// Discovered once at startup with TPM2_GetCapability and reported by
+// /agent/info, so that tenants can pick algorithms both the TPM and the
+// agent support rather than finding out from a failed registration. Only the
+// algorithms the agent can use are listed.
+//
+// The configured algorithms are checked against them before any key is
+// created. An algorithm the TPM does not implement is replaced by the first
+// implemented one of its fallback option, e.g. 'tpm_hash_alg_fallback', and
+// the agent refuses to start when there is none, rather than failing with TSS
+// errors at quote time. The hash algorithm also needs PCRs allocated in its
+// bank, and ECC signing schemes the curve in 'tpm_ecc_curve'. The AK
+// algorithms requested by the registrar are checked the same way.
+
+use crate::{
+    algorithms::{
+        EccCurve, EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+    },
+    error::Result,
+    tpm,
+};
+use serde::{Serialize, Serializer};
+use std::fmt::Display;
+use tss_esapi::{
+    constants::{AlgorithmIdentifier, CapabilityType, PropertyTag},
+    interface_types::algorithm::{
+        AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm,
+    },
+    structures::CapabilityData,
+    Context,
+};
+
+// Upper bound on the algorithms listed by the TPM, there are a few dozen
+const MAX_ALGORITHMS: u32 = 128;
+const MAX_ECC_CURVES: u32 = 64;
+
+// Algorithms are reported by their names in the agent configuration
+fn names<T: Display, S: Serializer>(
+    values: &[T],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|value| value.to_string()))
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct TpmCapabilities {
+    // Vendor ID of the manufacturer, e.g. "IFX"
+    pub manufacturer: String,
+    // Vendor specific strings, e.g. the model
+    pub vendor: String,
+    pub firmware_version: String,
+    #[serde(serialize_with = "names")]
+    pub hash_algorithms: Vec<HashAlgorithm>,
+    #[serde(serialize_with = "names")]
+    pub sign_algorithms: Vec<SignAlgorithm>,
+    #[serde(serialize_with = "names")]
+    pub encryption_algorithms: Vec<EncryptionAlgorithm>,
+    #[serde(serialize_with = "names")]
+    pub ecc_curves: Vec<EccCurve>,
+    // Hash algorithms of the PCR banks with PCRs allocated
+    #[serde(serialize_with = "names")]
+    pub pcr_banks: Vec<HashAlgorithm>,
+}
+
+// The vendor ID is four ASCII characters, padded with spaces or NULs
+fn vendor_id(value: u32) -> String {
+    value
+        .to_be_bytes()
+        .iter()
+        .filter(|c| c.is_ascii_graphic())
+        .map(|&c| c as char)
+        .collect()
+}
+
+// The firmware version is vendor specific, made of four 16-bit numbers
+fn firmware_version(version1: u32, version2: u32) -> String {
+    format!(
+        "{}.{}.{}.{}",
+        version1 >> 16,
+        version1 & 0xffff,
+        version2 >> 16,
+        version2 & 0xffff
+    )
+}
+
+fn property(context: &mut Context, tag: PropertyTag) -> Result<u32> {
+    let value = tpm::retry_tpm_command(|| context.get_tpm_property(tag))?;
+    Ok(value.unwrap_or_default())
+}
+
+fn implemented_algorithms(
+    context: &mut Context,
+) -> Result<Vec<AlgorithmIdentifier>> {
+    let (capability, _) = tpm::retry_tpm_command(|| {
+        context.get_capability(CapabilityType::Algorithms, 0, MAX_ALGORITHMS)
+    })?;
+    Ok(match capability {
+        CapabilityData::Algorithms(algorithms) => algorithms
+            .iter()
+            .map(|algorithm| algorithm.algorithm_identifier())
+            .collect(),
+        _ => Vec::new(),
+    })
+}
+
+fn implemented_curves(context: &mut Context) -> Result<Vec<EccCurve>> {
+    let (capability, _) = tpm::retry_tpm_command(|| {
+        context.get_capability(CapabilityType::EccCurves, 0, MAX_ECC_CURVES)
+    })?;
+    let curves = match capability {
+        CapabilityData::EccCurves(curves) => curves,
+        _ => return Ok(Vec::new()),
+    };
+    Ok(EccCurve::supported()
+        .into_iter()
+        .filter(|&curve| curves.contains(&curve.into()))
+        .collect())
+}
+
+/*
+ * Input: Connection context
+ * Return: the TPM identity and the algorithms it implements that the agent
+ *         supports
+ */
+pub(crate) fn discover(context: &mut Context) -> Result<TpmCapabilities> {
+    let algorithms = implemented_algorithms(context)?;
+    let implemented = |id: AlgorithmIdentifier| algorithms.contains(&id);
+
+    let hash_algorithms = HashAlgorithm::supported()
+        .into_iter()
+        .filter(|&alg| implemented(HashingAlgorithm::from(alg).into()))
+        .collect::<Vec<_>>();
+    let sign_algorithms = SignAlgorithm::supported()
+        .into_iter()
+        .filter(|&alg| {
+            implemented(SignatureSchemeAlgorithm::from(alg).into())
+        })
+        .collect();
+    let encryption_algorithms = EncryptionAlgorithm::supported()
+        .into_iter()
+        .filter(|&alg| implemented(AsymmetricAlgorithm::from(alg).into()))
+        .collect::<Vec<_>>();
+    let ecc_curves =
+        if encryption_algorithms.contains(&EncryptionAlgorithm::Ecc) {
+            implemented_curves(context)?
+        } else {
+            Vec::new()
+        };
+
+    let mut pcr_banks = Vec::new();
+    for &alg in &hash_algorithms {
+        if tpm::allocated_pcrs(context, alg)? != 0 {
+            pcr_banks.push(alg);
+        }
+    }
+
+    Ok(TpmCapabilities {
+        manufacturer: vendor_id(property(
+            context,
+            PropertyTag::Manufacturer,
+        )?),
+        vendor: tss_esapi::utils::get_tpm_vendor(context)?,
+        firmware_version: firmware_version(
+            property(context, PropertyTag::FirmwareVersion1)?,
+            property(context, PropertyTag::FirmwareVersion2)?,
+        ),
+        hash_algorithms,
+        sign_algorithms,
+        encryption_algorithms,
+        ecc_curves,
+        pcr_banks,
+    })
+}
+
+// Comma separated names, for the messages
+fn joined<T: Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/*
+ * Input: configuration option, configured algorithm, its fallbacks and the
+ *        algorithms usable with the TPM
+ * Return: the configured algorithm if usable, the first usable fallback
+ *         otherwise
+ */
+fn negotiate<T: Copy + PartialEq + Display>(
+    option: &str,
+    configured: T,
+    fallbacks: &[T],
+    usable: &[T],
+) -> Result<T> {
+    if usable.contains(&configured) {
+        return Ok(configured);
+    }
+    match fallbacks.iter().find(|alg| usable.contains(alg)) {
+        Some(&fallback) => {
+            warn!(
+                "The TPM does not support {} '{}', falling back to {}",
+                option, configured, fallback
+            );
+            Ok(fallback)
+        }
+        None => Err(Error::Configuration(format!(
+            "The TPM does not support {} '{}' nor any of '{}_fallback', it supports {}",
+            option,
+            configured,
+            option,
+            joined(usable)
+        ))),
+    }
+}
+
+impl TpmCapabilities {
+    // Signing algorithms implemented along with the curve they need
+    fn usable_sign_algorithms(
+        &self,
+        ecc_curve: EccCurve,
+    ) -> Vec<SignAlgorithm> {
+        self.sign_algorithms
+            .iter()
+            .copied()
+            .filter(|alg| match alg.ak_curve(ecc_curve) {
+                Some(curve) => self.ecc_curves.contains(&curve),
+                None => true,
+            })
+            .collect()
+    }
+
+    /*
+     * Input: hash and signing algorithms of an AK and the configured curve
+     * Return: whether the TPM supports them, as checked by negotiate
+     */
+    pub(crate) fn supports_ak(
+        &self,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        ecc_curve: EccCurve,
+    ) -> bool {
+        self.pcr_banks.contains(&hash_alg)
+            && self.usable_sign_algorithms(ecc_curve).contains(&sign_alg)
+    }
+
+    /*
+     * Input: agent configuration
+     *
+     * Replaces the configured algorithms the TPM does not support by their
+     * fallbacks.
+     */
+    pub(crate) fn negotiate(&self, config: &mut KeylimeConfig) -> Result<()> {
+        config.hash_alg = negotiate(
+            "tpm_hash_alg",
+            config.hash_alg,
+            &config.hash_alg_fallback,
+            &self.pcr_banks,
+        )?;
+        config.enc_alg = negotiate(
+            "tpm_encryption_alg",
+            config.enc_alg,
+            &config.enc_alg_fallback,
+            &self.encryption_algorithms,
+        )?;
+        let sign_algorithms = self.usable_sign_algorithms(config.ecc_curve);
+        config.sign_alg = negotiate(
+            "tpm_signing_alg",
+            config.sign_alg,
+            &config.sign_alg_fallback,
+            &sign_algorithms,
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vendor_id() {
+        assert_eq!(vendor_id(0x49465800), "IFX");
+        assert_eq!(vendor_id(0x494e5443), "INTC");
+        assert_eq!(vendor_id(0x49424d20), "IBM");
+    }
+
+    #[test]
+    fn test_firmware_version() {
+        assert_eq!(firmware_version(0x0007_0055, 0x0002_0000), "7.85.2.0");
+    }
+
+    #[test]
+    fn test_serialize_names() {
+        let capabilities = TpmCapabilities {
+            manufacturer: "IBM".to_string(),
+            vendor: "SW   TPM".to_string(),
+            firmware_version: "0.0.0.0".to_string(),
+            hash_algorithms: vec![HashAlgorithm::Sha1, HashAlgorithm::Sha256],
+            sign_algorithms: vec![SignAlgorithm::RsaSsa],
+            encryption_algorithms: vec![EncryptionAlgorithm::Rsa],
+            ecc_curves: Vec::new(),
+            pcr_banks: vec![HashAlgorithm::Sha256],
+        };
+        let value = serde_json::to_value(&capabilities).unwrap(); //#[allow_ci]
+        assert_eq!(
+            value["hash_algorithms"],
+            serde_json::json!(["sha1", "sha256"])
+        );
+        assert_eq!(value["sign_algorithms"], serde_json::json!(["rsassa"]));
+        assert_eq!(value["pcr_banks"], serde_json::json!(["sha256"]));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let usable = [HashAlgorithm::Sha1, HashAlgorithm::Sha256];
+        let fallbacks = [HashAlgorithm::Sha384, HashAlgorithm::Sha256];
+
+        let alg = negotiate("hash", HashAlgorithm::Sha1, &fallbacks, &usable);
+        assert_eq!(alg.unwrap(), HashAlgorithm::Sha1); //#[allow_ci]
+        let alg =
+            negotiate("hash", HashAlgorithm::Sha512, &fallbacks, &usable);
+        assert_eq!(alg.unwrap(), HashAlgorithm::Sha256); //#[allow_ci]
+        assert!(
+            negotiate("hash", HashAlgorithm::Sha512, &[], &usable).is_err()
+        );
+    }
+
+    #[test]
+    fn test_negotiate_config() {
+        let capabilities = TpmCapabilities {
+            manufacturer: "IBM".to_string(),
+            vendor: "SW   TPM".to_string(),
+            firmware_version: "0.0.0.0".to_string(),
+            hash_algorithms: vec![
+                HashAlgorithm::Sha256,
+                HashAlgorithm::Sha384,
+            ],
+            sign_algorithms: vec![
+                SignAlgorithm::RsaSsa,
+                SignAlgorithm::EcDsa,
+            ],
+            encryption_algorithms: vec![
+                EncryptionAlgorithm::Rsa,
+                EncryptionAlgorithm::Ecc,
+            ],
+            ecc_curves: vec![EccCurve::P256],
+            // No PCRs allocated in the SHA-384 bank
+            pcr_banks: vec![HashAlgorithm::Sha256],
+        };
+        let mut config = KeylimeConfig {
+            hash_alg: HashAlgorithm::Sha384,
+            hash_alg_fallback: vec![HashAlgorithm::Sha256],
+            sign_alg: SignAlgorithm::EcDsa,
+            sign_alg_fallback: vec![SignAlgorithm::RsaSsa],
+            ecc_curve: EccCurve::P384,
+            ..Default::default()
+        };
+        capabilities.negotiate(&mut config).unwrap(); //#[allow_ci]
+        assert_eq!(config.hash_alg, HashAlgorithm::Sha256);
+        assert_eq!(config.enc_alg, EncryptionAlgorithm::Rsa);
+        // The curve is not implemented
+        assert_eq!(config.sign_alg, SignAlgorithm::RsaSsa);
+
+        config.sign_alg = SignAlgorithm::EcDsa;
+        config.sign_alg_fallback = Vec::new();
+        assert!(capabilities.negotiate(&mut config).is_err());
+
+        assert!(capabilities.supports_ak(
+            HashAlgorithm::Sha256,
+            SignAlgorithm::EcDsa,
+            EccCurve::P256
+        ));
+        assert!(!capabilities.supports_ak(
+            HashAlgorithm::Sha384,
+            SignAlgorithm::RsaSsa,
+            EccCurve::P256
+        ));
+        assert!(!capabilities.supports_ak(
+            HashAlgorithm::Sha256,
+            SignAlgorithm::EcDsa,
+            EccCurve::P384
+        ));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_discover() {
+        let mut context = tpm::get_tpm2_ctx().unwrap(); //#[allow_ci]
+        let capabilities = discover(&mut context).unwrap(); //#[allow_ci]
+        assert!(capabilities
+            .hash_algorithms
+            .contains(&HashAlgorithm::Sha256));
+        assert!(capabilities.pcr_banks.contains(&HashAlgorithm::Sha256));
+        assert!(capabilities
+            .encryption_algorithms
+            .contains(&EncryptionAlgorithm::Rsa));
+    }
+}
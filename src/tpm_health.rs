@@ -0,0 +1,151 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Health of the TPM, reported by /agent/tpm/health.
+//
+// Once the TPM enters dictionary attack (DA) lockout, e.g. after other
+// software on the node failed to authorize too many times, every command
+// needing authorization fails and attestation stops without the agent
+// having done anything wrong. The endpoint reports the DA state read with
+// TPM2_GetCapability (TPM_PT_PERMANENT and TPM_PT_LOCKOUT_*), so that
+// operators see the failed tries approach the limit before it is reached,
+// along with the result of the last TPM self test and the counters the agent
+// keeps of the commands the TPM refused as busy and of its reconnections.
+// The counters show how much the agent contends for a TPM shared with other
+// users, e.g. when it runs as a DaemonSet next to other pods using
+// /dev/tpmrm0.
+// The values are read at every request, on the TPM service.
+
+use crate::{error::Result, tpm, QuoteData};
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use tss_esapi::{constants::PropertyTag, Context};
+
+// inLockout of TPMA_PERMANENT
+const PERMANENT_IN_LOCKOUT: u32 = 1 << 9;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct LockoutStatus {
+    pub in_lockout: bool,
+    // Authorization failures counted, TPM_PT_LOCKOUT_COUNTER
+    pub failed_tries: u32,
+    // Failures entering lockout, TPM_PT_MAX_AUTH_FAIL
+    pub max_tries: u32,
+    // Seconds after which a failure is forgotten, TPM_PT_LOCKOUT_INTERVAL
+    pub recovery_interval: u32,
+    // Seconds before lockoutAuth can be used again after failing,
+    // TPM_PT_LOCKOUT_RECOVERY
+    pub lockout_recovery: u32,
+}
+
+impl LockoutStatus {
+    // Failures left before the TPM enters lockout
+    pub(crate) fn remaining_tries(&self) -> u32 {
+        self.max_tries.saturating_sub(self.failed_tries)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct SelfTestStatus {
+    pub passed: bool,
+    // Response code of TPM2_GetTestResult when it failed
+    pub detail: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TpmCounters {
+    // Commands resubmitted as the TPM was busy, commands refused at least
+    // once, those still refused after all attempts and the milliseconds
+    // waited before resubmitting
+    pub retries: u64,
+    pub contended_commands: u64,
+    pub busy_failures: u64,
+    pub retry_wait_ms: u64,
+    pub reconnections: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TpmHealth {
+    // Neither in lockout nor failing its self test
+    pub healthy: bool,
+    pub lockout: LockoutStatus,
+    pub self_test: SelfTestStatus,
+    pub counters: TpmCounters,
+}
+
+fn property(context: &mut Context, tag: PropertyTag) -> Result<u32> {
+    let value = tpm::retry_tpm_command(|| context.get_tpm_property(tag))?;
+    Ok(value.unwrap_or_default())
+}
+
+fn lockout_status(context: &mut Context) -> Result<LockoutStatus> {
+    Ok(LockoutStatus {
+        in_lockout: property(context, PropertyTag::Permanent)?
+            & PERMANENT_IN_LOCKOUT
+            != 0,
+        failed_tries: property(context, PropertyTag::LockoutCounter)?,
+        max_tries: property(context, PropertyTag::MaxAuthFail)?,
+        recovery_interval: property(context, PropertyTag::LockoutInterval)?,
+        lockout_recovery: property(context, PropertyTag::LockoutRecovery)?,
+    })
+}
+
+// Result of the last self test, without running a new one
+fn self_test_status(context: &mut Context) -> Result<SelfTestStatus> {
+    let (_, result) = tpm::retry_tpm_command(|| context.get_test_result())?;
+    Ok(match result {
+        Ok(()) => SelfTestStatus {
+            passed: true,
+            detail: String::new(),
+        },
+        Err(e) => SelfTestStatus {
+            passed: false,
+            detail: e.to_string(),
+        },
+    })
+}
+
+/*
+ * Input: quote data
+ * Return: Result wrap the health of the TPM
+ */
+pub(crate) async fn read(data: web::Data<QuoteData>) -> Result<TpmHealth> {
+    let (lockout, self_test) = data
+        .tpm
+        .run_idempotent(|context| {
+            Ok((lockout_status(context)?, self_test_status(context)?))
+        })
+        .await?;
+    let retry_counts = tpm::retry_counts();
+    Ok(TpmHealth {
+        healthy: !lockout.in_lockout && self_test.passed,
+        lockout,
+        self_test,
+        counters: TpmCounters {
+            retries: retry_counts.retries,
+            contended_commands: retry_counts.contended,
+            busy_failures: retry_counts.exhausted,
+            retry_wait_ms: retry_counts.wait_ms,
+            reconnections: data.tpm.keys().reconnections(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remaining_tries() {
+        let mut lockout = LockoutStatus {
+            in_lockout: false,
+            failed_tries: 2,
+            max_tries: 3,
+            recovery_interval: 7200,
+            lockout_recovery: 86400,
+        };
+        assert_eq!(lockout.remaining_tries(), 1);
+        lockout.failed_tries = 5;
+        assert_eq!(lockout.remaining_tries(), 0);
+    }
+}
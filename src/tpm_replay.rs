@@ -0,0 +1,519 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Record/replay support for the TSS calls made while assembling a quote.
+//
+// With the tpm-replay feature enabled and KEYLIME_TPM_RECORD set to a file
+// path, the agent writes a transcript of the TPM calls made for each quote
+// to that file. The Replayer plays such a transcript back in place of a TPM,
+// so the quote assembly logic can be tested without any TPM at all.
+
+use crate::{
+    algorithms::HashAlgorithm, permissions, serialization::*, Error, Result,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryFrom, fs::File, path::Path};
+use tss_esapi::{
+    abstraction::pcr::PcrData,
+    structures::{PcrSelectionList, PcrSlot},
+};
+
+#[cfg(feature = "tpm-replay")]
+use crate::tpm::{PcrPolicy, TpmQuoteOps};
+#[cfg(feature = "tpm-replay")]
+use log::*;
+#[cfg(feature = "tpm-replay")]
+use std::path::PathBuf;
+#[cfg(feature = "tpm-replay")]
+use tss_esapi::{
+    handles::{KeyHandle, PcrHandle},
+    structures::{Attest, Data, DigestValues, Signature, SignatureScheme},
+    traits::Marshall,
+    Context,
+};
+
+#[cfg(feature = "tpm-replay")]
+pub(crate) static TPM_RECORD_ENV: &str = "KEYLIME_TPM_RECORD";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PcrValue {
+    pub bank: HashAlgorithm,
+    pub pcr: u32,
+    #[serde(
+        serialize_with = "serialize_as_base64",
+        deserialize_with = "deserialize_as_base64"
+    )]
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "call", rename_all = "snake_case")]
+pub(crate) enum TpmCall {
+    PcrResetAndExtend {
+        pcr: String,
+    },
+    PcrReadAll {
+        values: Vec<PcrValue>,
+    },
+    QuotePcrs {
+        #[serde(
+            serialize_with = "serialize_as_base64",
+            deserialize_with = "deserialize_as_base64"
+        )]
+        nonce: Vec<u8>,
+        #[serde(
+            serialize_with = "serialize_as_base64",
+            deserialize_with = "deserialize_as_base64"
+        )]
+        attestation: Vec<u8>,
+        #[serde(
+            serialize_with = "serialize_as_base64",
+            deserialize_with = "deserialize_as_base64"
+        )]
+        signature: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Transcript {
+    pub calls: Vec<TpmCall>,
+}
+
+impl Transcript {
+    pub(crate) fn store(&self, path: &Path) -> Result<()> {
+        let file = permissions::create_file(path)?;
+        serde_json::to_writer_pretty(file, self).map_err(Error::from)
+    }
+}
+
+fn pcr_index_to_slot(pcr: u32) -> Result<PcrSlot> {
+    let mask = 1u32.checked_shl(pcr).ok_or_else(|| {
+        Error::Other(format!("invalid PCR index {} in transcript", pcr))
+    })?;
+    PcrSlot::try_from(mask).map_err(Error::from)
+}
+
+// Lists the (bank, pcr) pairs selected, in selection order
+fn selected_pcrs(
+    pcrlist: &PcrSelectionList,
+) -> Result<Vec<(HashAlgorithm, u32)>> {
+    let mut selected = Vec::new();
+    for sel in pcrlist.get_selections() {
+        let bank = HashAlgorithm::try_from(sel.hashing_algorithm())?;
+        for slot in &sel.selected() {
+            selected.push((bank, u32::from(*slot).trailing_zeros()));
+        }
+    }
+    Ok(selected)
+}
+
+fn pcr_data_to_values(
+    pcrlist: &PcrSelectionList,
+    pcr_data: &PcrData,
+) -> Result<Vec<PcrValue>> {
+    let mut values = Vec::new();
+    for (bank, pcr) in selected_pcrs(pcrlist)? {
+        let digest = pcr_data
+            .pcr_bank(bank.into())
+            .and_then(|pcr_bank| {
+                pcr_bank.get_digest(pcr_index_to_slot(pcr).ok()?)
+            })
+            .ok_or_else(|| {
+                Error::Other(format!(
+                    "PCR {} missing from {} bank",
+                    pcr, bank
+                ))
+            })?;
+        values.push(PcrValue {
+            bank,
+            pcr,
+            digest: digest.value().to_vec(),
+        });
+    }
+    Ok(values)
+}
+
+// Wraps a TSS ESAPI Context, recording the calls made through it
+#[cfg(feature = "tpm-replay")]
+pub(crate) struct Recorder<'a> {
+    context: &'a mut Context,
+    transcript: Transcript,
+}
+
+#[cfg(feature = "tpm-replay")]
+impl<'a> Recorder<'a> {
+    pub(crate) fn new(context: &'a mut Context) -> Self {
+        Recorder {
+            context,
+            transcript: Transcript::default(),
+        }
+    }
+
+    pub(crate) fn into_transcript(self) -> Transcript {
+        self.transcript
+    }
+}
+
+#[cfg(feature = "tpm-replay")]
+impl TpmQuoteOps for Recorder<'_> {
+    fn pcr_reset_and_extend(
+        &mut self,
+        pcr: PcrHandle,
+        digest: DigestValues,
+    ) -> Result<()> {
+        self.context.pcr_reset_and_extend(pcr, digest)?;
+        self.transcript.calls.push(TpmCall::PcrResetAndExtend {
+            pcr: format!("{:?}", pcr),
+        });
+        Ok(())
+    }
+
+    fn pcr_read_all(&mut self, pcrlist: PcrSelectionList) -> Result<PcrData> {
+        let pcr_data = self.context.pcr_read_all(pcrlist.clone())?;
+        self.transcript.calls.push(TpmCall::PcrReadAll {
+            values: pcr_data_to_values(&pcrlist, &pcr_data)?,
+        });
+        Ok(pcr_data)
+    }
+
+    fn quote_pcrs(
+        &mut self,
+        ak_handle: KeyHandle,
+        ak_policy: Option<&PcrPolicy>,
+        nonce: Data,
+        sign_scheme: SignatureScheme,
+        pcrlist: PcrSelectionList,
+    ) -> Result<(Attest, Signature)> {
+        let nonce_vec = nonce.value().to_vec();
+        let (attestation, sig) = self.context.quote_pcrs(
+            ak_handle,
+            ak_policy,
+            nonce,
+            sign_scheme,
+            pcrlist,
+        )?;
+        self.transcript.calls.push(TpmCall::QuotePcrs {
+            nonce: nonce_vec,
+            attestation: attestation.marshall()?,
+            signature: sig.marshall()?,
+        });
+        Ok((attestation, sig))
+    }
+}
+
+#[cfg(feature = "tpm-replay")]
+pub(crate) fn record_path() -> Option<PathBuf> {
+    std::env::var_os(TPM_RECORD_ENV).map(PathBuf::from)
+}
+
+// Runs f against a Recorder wrapping the context and stores the resulting
+// transcript at path, whether or not f succeeded.
+#[cfg(feature = "tpm-replay")]
+pub(crate) fn record<F, R>(
+    context: &mut Context,
+    path: &Path,
+    f: F,
+) -> Result<R>
+where
+    F: FnOnce(&mut dyn TpmQuoteOps) -> Result<R>,
+{
+    let mut recorder = Recorder::new(context);
+    let result = f(&mut recorder);
+    recorder.into_transcript().store(path)?;
+    info!("Recorded TPM transcript to {}", path.display());
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+    use crate::tpm::{self, PcrPolicy, TpmQuoteOps};
+    use std::{collections::VecDeque, convert::TryInto};
+    use tss_esapi::{
+        handles::{KeyHandle, PcrHandle},
+        structures::{
+            Attest, Data, Digest, DigestList, DigestValues,
+            PcrSelectionListBuilder, Signature, SignatureScheme,
+        },
+    };
+
+    // A TPML_DIGEST can hold at most 8 digests
+    const MAX_DIGESTS_PER_READ: usize = 8;
+
+    impl Transcript {
+        pub(crate) fn load(path: &Path) -> Result<Self> {
+            let file = File::open(path)?;
+            serde_json::from_reader(file).map_err(Error::from)
+        }
+    }
+
+    impl TpmCall {
+        fn name(&self) -> &'static str {
+            match self {
+                TpmCall::PcrResetAndExtend { .. } => "pcr_reset_and_extend",
+                TpmCall::PcrReadAll { .. } => "pcr_read_all",
+                TpmCall::QuotePcrs { .. } => "quote_pcrs",
+            }
+        }
+    }
+
+    pub(crate) fn pcr_data_from_values(
+        values: &[PcrValue],
+    ) -> Result<PcrData> {
+        let mut banks: Vec<HashAlgorithm> = Vec::new();
+        for value in values {
+            if !banks.contains(&value.bank) {
+                banks.push(value.bank);
+            }
+        }
+
+        let mut pcr_data = PcrData::new();
+        for bank in banks {
+            let bank_values: Vec<&PcrValue> =
+                values.iter().filter(|v| v.bank == bank).collect();
+            for chunk in bank_values.chunks(MAX_DIGESTS_PER_READ) {
+                let mut slots = Vec::new();
+                let mut digests = DigestList::new();
+                for value in chunk {
+                    slots.push(pcr_index_to_slot(value.pcr)?);
+                    digests.add(Digest::try_from(value.digest.clone())?)?;
+                }
+                let selection = PcrSelectionListBuilder::new()
+                    .with_selection(bank.into(), &slots)
+                    .build()?;
+                pcr_data.add(&selection, &digests)?;
+            }
+        }
+        Ok(pcr_data)
+    }
+
+    // Plays back a recorded transcript in place of a TPM. Any divergence
+    // between the calls made and the recorded ones is reported as an error.
+    pub(crate) struct Replayer {
+        calls: VecDeque<TpmCall>,
+    }
+
+    impl Replayer {
+        pub(crate) fn new(transcript: Transcript) -> Self {
+            Replayer {
+                calls: transcript.calls.into(),
+            }
+        }
+
+        pub(crate) fn load(path: &Path) -> Result<Self> {
+            Ok(Replayer::new(Transcript::load(path)?))
+        }
+
+        // Whether all recorded calls have been replayed
+        pub(crate) fn is_finished(&self) -> bool {
+            self.calls.is_empty()
+        }
+
+        fn next_call(&mut self, expected: &str) -> Result<TpmCall> {
+            self.calls.pop_front().ok_or_else(|| {
+                Error::Other(format!(
+                    "TPM transcript exhausted, expected a {} call",
+                    expected
+                ))
+            })
+        }
+    }
+
+    fn divergence(expected: &str, detail: String) -> Error {
+        Error::Other(format!(
+            "TPM transcript diverged on {} call: {}",
+            expected, detail
+        ))
+    }
+
+    impl TpmQuoteOps for Replayer {
+        fn pcr_reset_and_extend(
+            &mut self,
+            pcr: PcrHandle,
+            _digest: DigestValues,
+        ) -> Result<()> {
+            let expected = "pcr_reset_and_extend";
+            match self.next_call(expected)? {
+                TpmCall::PcrResetAndExtend { pcr: recorded }
+                    if recorded == format!("{:?}", pcr) =>
+                {
+                    Ok(())
+                }
+                TpmCall::PcrResetAndExtend { pcr: recorded } => {
+                    Err(divergence(
+                        expected,
+                        format!("recorded {}, got {:?}", recorded, pcr),
+                    ))
+                }
+                other => Err(divergence(
+                    expected,
+                    format!("recorded a {} call", other.name()),
+                )),
+            }
+        }
+
+        fn pcr_read_all(
+            &mut self,
+            pcrlist: PcrSelectionList,
+        ) -> Result<PcrData> {
+            let expected = "pcr_read_all";
+            match self.next_call(expected)? {
+                TpmCall::PcrReadAll { values } => {
+                    let requested = selected_pcrs(&pcrlist)?;
+                    let recorded: Vec<(HashAlgorithm, u32)> =
+                        values.iter().map(|v| (v.bank, v.pcr)).collect();
+                    if requested != recorded {
+                        return Err(divergence(
+                            expected,
+                            format!(
+                                "recorded PCRs {:?}, got {:?}",
+                                recorded, requested
+                            ),
+                        ));
+                    }
+                    pcr_data_from_values(&values)
+                }
+                other => Err(divergence(
+                    expected,
+                    format!("recorded a {} call", other.name()),
+                )),
+            }
+        }
+
+        fn quote_pcrs(
+            &mut self,
+            _ak_handle: KeyHandle,
+            _ak_policy: Option<&PcrPolicy>,
+            nonce: Data,
+            _sign_scheme: SignatureScheme,
+            _pcrlist: PcrSelectionList,
+        ) -> Result<(Attest, Signature)> {
+            let expected = "quote_pcrs";
+            match self.next_call(expected)? {
+                TpmCall::QuotePcrs {
+                    nonce: recorded,
+                    attestation,
+                    signature,
+                } => {
+                    if recorded != nonce.value() {
+                        return Err(divergence(
+                            expected,
+                            "nonce does not match".to_string(),
+                        ));
+                    }
+                    let attestation =
+                        tpm::testing::vec_to_attest(&attestation)?;
+                    let sig: Signature =
+                        tpm::testing::vec_to_sig(&signature)?.try_into()?;
+                    Ok((attestation, sig))
+                }
+                other => Err(divergence(
+                    expected,
+                    format!("recorded a {} call", other.name()),
+                )),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::*;
+    use super::*;
+    use crate::{
+        algorithms::{EncryptionAlgorithm, SignAlgorithm},
+        tpm::{self, TpmQuoteOps},
+    };
+    use std::{fs, path::PathBuf};
+    use tss_esapi::{
+        handles::{KeyHandle, PcrHandle},
+        structures::{DigestValues, PcrSelectionListBuilder},
+    };
+
+    fn test_data_path(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join(name)
+    }
+
+    fn replay_quote(
+        nonce: &[u8],
+        mask: Option<&str>,
+    ) -> (Replayer, Result<crate::quotes_handler::KeylimeQuote>) {
+        let mut replayer = Replayer::load(
+            &test_data_path("tpm-transcripts").join("quote-sha1.json"),
+        )
+        .expect("unable to load transcript");
+        let quote = tpm::assemble_quote(
+            &mut replayer,
+            KeyHandle::from(0x81010002),
+            None,
+            None,
+            DigestValues::new(),
+            nonce,
+            mask,
+            &[],
+            HashAlgorithm::Sha1,
+            EncryptionAlgorithm::Rsa,
+            SignAlgorithm::RsaSsa,
+        );
+        (replayer, quote)
+    }
+
+    #[test]
+    fn test_replay_quote() {
+        let (replayer, quote) = replay_quote(b"TEST", None);
+        let quote = quote.expect("unable to replay quote");
+
+        let expected = fs::read_to_string(test_data_path("test-quote.txt"))
+            .expect("unable to read test-quote.txt");
+        assert_eq!(quote.quote, expected.trim_end());
+        assert_eq!(quote.hash_alg, "sha1");
+        assert_eq!(quote.enc_alg, "rsa");
+        assert_eq!(quote.sign_alg, "rsassa");
+        assert!(replayer.is_finished());
+    }
+
+    #[test]
+    fn test_replay_nonce_divergence() {
+        let (_, quote) = replay_quote(b"OTHER", None);
+        assert!(quote.is_err());
+    }
+
+    #[test]
+    fn test_replay_selection_divergence() {
+        let (_, quote) = replay_quote(b"TEST", Some("0x1"));
+        assert!(quote.is_err());
+    }
+
+    #[test]
+    fn test_replay_exhausted() {
+        let mut replayer = Replayer::new(Transcript::default());
+        assert!(replayer
+            .pcr_reset_and_extend(PcrHandle::Pcr16, DigestValues::new())
+            .is_err());
+    }
+
+    #[test]
+    fn test_pcr_values_round_trip() {
+        let values: Vec<PcrValue> = (0..12)
+            .map(|pcr| PcrValue {
+                bank: HashAlgorithm::Sha256,
+                pcr,
+                digest: vec![pcr as u8; 32],
+            })
+            .collect();
+        let pcr_data =
+            pcr_data_from_values(&values).expect("unable to build PcrData");
+        let slots: Vec<PcrSlot> = (0..12)
+            .map(|pcr| pcr_index_to_slot(pcr).expect("invalid PCR index"))
+            .collect();
+        let pcrlist = PcrSelectionListBuilder::new()
+            .with_selection(HashAlgorithm::Sha256.into(), &slots)
+            .build()
+            .expect("unable to build selection");
+        let decoded = pcr_data_to_values(&pcrlist, &pcr_data)
+            .expect("unable to read back PcrData");
+        assert_eq!(decoded, values);
+    }
+}
@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Dedicated thread for the TPM.
+//
+// Once the agent is up, handlers and background tasks do not lock the TPM
+// context themselves. They send the operation to the TPM service, which runs
+// the operations one at a time on its own thread and sends each result back.
+// Waiting for the TPM then neither blocks the HTTP workers nor ties up one
+// thread of the blocking pool per concurrent request. Operations whose caller
+// stopped waiting while they were queued, e.g. past tpm_operation_timeout,
+// are skipped rather than sent to a TPM that is already late. When the
+// connection to the TPM breaks, the TPM manager reconnects, see
+// tpm::TpmManager. Operations sent with run_idempotent then run again, the
+// others fail: they may have changed the TPM before the connection broke,
+// e.g. incremented an NV counter or extended a PCR.
+
+use crate::{
+    crash_report,
+    error::{Error, Result},
+    tpm::{ReloadKeys, ReloadedKeys, TpmManager},
+};
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+};
+use tokio::sync::{mpsc, oneshot};
+use tss_esapi::{handles::KeyHandle, Context};
+
+type Operation = Box<dyn FnOnce(&mut TpmManager) + Send>;
+
+fn stopped() -> Error {
+    Error::Other("the TPM service thread stopped".to_string())
+}
+
+#[derive(Debug)]
+pub(crate) struct TpmService {
+    context: Arc<Mutex<TpmManager>>,
+    keys: Arc<ReloadedKeys>,
+    queue: mpsc::UnboundedSender<Operation>,
+}
+
+impl TpmService {
+    pub(crate) fn start(context: Context) -> Result<Self> {
+        let manager = TpmManager::new(context);
+        let keys = manager.keys();
+        let context = Arc::new(Mutex::new(manager));
+        let (queue, mut operations) = mpsc::unbounded_channel::<Operation>();
+        let thread_context = context.clone();
+        let _ = thread::Builder::new().name("tpm".to_string()).spawn(
+            move || {
+                while let Some(operation) = operations.blocking_recv() {
+                    let mut context = thread_context.lock().unwrap(); //#[allow_ci]
+                    operation(&mut context);
+                }
+            },
+        )?;
+        Ok(TpmService {
+            context,
+            keys,
+            queue,
+        })
+    }
+
+    /*
+     * Input: operation to run with the TPM context
+     * Return: Result of the operation
+     *
+     * The operation runs on the TPM thread once the ones queued before it
+     * completed. Dropping the returned future before it starts cancels it,
+     * once started it runs to completion in the background. The operation
+     * runs once: when the connection to the TPM broke, it fails and the
+     * next operations run on the new connection.
+     */
+    pub(crate) async fn run<T, F>(&self, operation: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Context) -> Result<T> + Send + 'static,
+    {
+        self.send::<T, F>(Box::new(move |manager| {
+            let result = operation(&mut **manager);
+            if let Err(e) = &result {
+                let _ = manager.recover(e);
+            }
+            result
+        }))
+        .await
+    }
+
+    /*
+     * Input: operation to run with the TPM context
+     * Return: Result of the operation
+     *
+     * As run, but the operation runs a second time after the TPM manager
+     * reconnected to the TPM, so handles of keys loaded at startup are
+     * translated with keys(). Only for operations that can run twice
+     * without harm: reads, quotes and loads.
+     */
+    pub(crate) async fn run_idempotent<T, F>(
+        &self,
+        mut operation: F,
+    ) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnMut(&mut Context) -> Result<T> + Send + 'static,
+    {
+        self.send::<T, F>(Box::new(move |manager| {
+            let mut result = operation(&mut **manager);
+            if let Err(e) = &result {
+                if manager.recover(e) {
+                    result = operation(&mut **manager);
+                }
+            }
+            result
+        }))
+        .await
+    }
+
+    // Queues the operation, named after F in crash reports
+    async fn send<T, F>(
+        &self,
+        operation: Box<dyn FnOnce(&mut TpmManager) -> Result<T> + Send>,
+    ) -> Result<T>
+    where
+        T: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let operation: Operation = Box::new(move |manager| {
+            if reply.is_closed() {
+                return;
+            }
+            crash_report::record("tpm", crash_report::operation_name::<F>());
+            let _ = reply.send(operation(manager));
+        });
+        self.queue.send(operation).map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    // Direct access, only for the startup of the agent before the service
+    // is shared, and for tests
+    pub(crate) fn lock(&self) -> MutexGuard<'_, TpmManager> {
+        self.context.lock().unwrap() //#[allow_ci]
+    }
+
+    // Handles of the keys reloaded after reconnections to the TPM
+    pub(crate) fn keys(&self) -> Arc<ReloadedKeys> {
+        self.keys.clone()
+    }
+
+    // Handle of a key loaded at startup in the current context
+    pub(crate) fn key(&self, handle: KeyHandle) -> KeyHandle {
+        self.keys.get(handle)
+    }
+
+    // Enables the reconnection to the TPM, once the keys of the agent are
+    // loaded
+    pub(crate) fn set_reload(&self, reload: ReloadKeys) {
+        self.lock().set_reload(reload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tpm;
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_run() {
+        let service =
+            TpmService::start(tpm::get_tpm2_ctx().unwrap()).unwrap(); //#[allow_ci]
+
+        let random = service
+            .run(|context| Ok(context.get_random(8)?.value().to_vec()))
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(random.len(), 8);
+
+        let result = service
+            .run(|_| Err::<(), Error>(Error::Other("failed".to_string())))
+            .await;
+        assert!(result.is_err());
+
+        // Operations dropped while queued behind a locked context are
+        // skipped
+        let guard = service.lock();
+        let queued = service.run(|_| -> Result<()> {
+            panic!("cancelled operation ran"); //#[allow_ci]
+        });
+        let timed_out = tokio::time::timeout(
+            std::time::Duration::from_millis(10),
+            queued,
+        )
+        .await;
+        assert!(timed_out.is_err());
+        drop(guard);
+        assert!(service.run(|_| Ok(())).await.is_ok());
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_reconnect() {
+        use tss_esapi::constants::response_code::Tss2ResponseCode;
+
+        let service =
+            TpmService::start(tpm::get_tpm2_ctx().unwrap()).unwrap(); //#[allow_ci]
+        let loaded = KeyHandle::from(0x80000001);
+        let reloaded = KeyHandle::from(0x80000002);
+        // TCTI layer (10) I/O error (10)
+        let broken = || {
+            Error::from(tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(
+                0xa000a,
+            )))
+        };
+
+        // Not reconnecting before the keys to reload are set
+        let result = service.run(move |_| Err::<(), Error>(broken())).await;
+        assert!(result.is_err());
+        assert_eq!(service.keys().reconnections(), 0);
+
+        service.set_reload(Box::new(move |_| Ok(vec![(loaded, reloaded)])));
+        let mut attempts = 0;
+        let keys = service.keys();
+        let handle = service
+            .run_idempotent(move |_| {
+                attempts += 1;
+                match attempts {
+                    1 => Err(broken()),
+                    _ => Ok(keys.get(loaded)),
+                }
+            })
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(handle, reloaded);
+        assert_eq!(service.key(loaded), reloaded);
+        assert_eq!(service.keys().reconnections(), 1);
+
+        // Other operations reconnect but fail rather than running again
+        let result = service.run(move |_| Err::<(), Error>(broken())).await;
+        assert!(result.is_err());
+        assert_eq!(service.keys().reconnections(), 2);
+    }
+}
@@ -0,0 +1,153 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Decryption of local files gated by the attestation state.
+//
+// Files encrypted under the payload key K, in the same format as payloads,
+// can be decrypted through the admin socket. This is refused until the agent
+// is activated and K has been derived from the U and V keys, and for good
+// once a revocation message for this agent has been processed, so that
+// secrets stay sealed on a machine that failed attestation.
+//
+// Local applications can also seal data under K themselves, and unseal it
+// later under the same conditions, see ffi.rs.
+
+use crate::{
+    common::SymmKey,
+    crypto,
+    error::{Error, Result},
+    status::{RegistrationState, StatusReport},
+    QuoteData, AES_BLOCK_SIZE,
+};
+use log::*;
+use openssl::rand::rand_bytes;
+use std::{fs, path::Path};
+
+// The decrypted file is sent back base64 encoded in a single admin frame
+pub(crate) const MAX_FILE_LEN: u64 = 512 * 1024;
+
+fn check_state(report: &StatusReport, operation: &str) -> Result<()> {
+    if report.revoked {
+        return Err(Error::Other(format!(
+            "the agent was revoked, {} is refused",
+            operation
+        )));
+    }
+    if report.registration != RegistrationState::Activated {
+        return Err(Error::Other(format!(
+            "the agent is not activated, {} is refused",
+            operation
+        )));
+    }
+    Ok(())
+}
+
+// The payload key, once the attestation state allows its use
+fn payload_key(data: &QuoteData, operation: &str) -> Result<SymmKey> {
+    check_state(&data.status.report(), operation)?;
+    let key = data.payload_symm_key.lock().unwrap(); //#[allow_ci]
+    key.clone().ok_or_else(|| {
+        Error::Other(format!(
+            "the payload key is not available yet, {} is refused",
+            operation
+        ))
+    })
+}
+
+fn decrypt_file(key: &SymmKey, path: &Path) -> Result<Vec<u8>> {
+    let len = fs::metadata(path)?.len();
+    if len > MAX_FILE_LEN {
+        return Err(Error::Other(format!(
+            "{} is {} bytes, files up to {} bytes can be decrypted",
+            path.display(),
+            len,
+            MAX_FILE_LEN
+        )));
+    }
+    let encrypted = fs::read(path)?;
+    crypto::decrypt_aead(key.bytes(), &encrypted)
+}
+
+/*
+ * Input: agent state and path of the encrypted file
+ * Return: Result wrap the decrypted content
+ *
+ * Decrypts the file with the payload key, if the attestation state allows
+ * it.
+ */
+pub(crate) fn decrypt(data: &QuoteData, path: &Path) -> Result<Vec<u8>> {
+    let key = payload_key(data, "decryption")?;
+    let decrypted = decrypt_file(&key, path)?;
+    info!("Decrypted {} for an admin request", path.display());
+    Ok(decrypted)
+}
+
+fn check_len(len: usize) -> Result<()> {
+    if len as u64 > MAX_FILE_LEN {
+        return Err(Error::Other(format!(
+            "{} bytes of data, up to {} bytes can be sealed",
+            len, MAX_FILE_LEN
+        )));
+    }
+    Ok(())
+}
+
+/*
+ * Input: agent state and data
+ * Return: Result wrap the data encrypted with the payload key
+ */
+pub(crate) fn seal(data: &QuoteData, plain: &[u8]) -> Result<Vec<u8>> {
+    check_len(plain.len())?;
+    let key = payload_key(data, "sealing")?;
+    let mut iv = [0u8; AES_BLOCK_SIZE];
+    rand_bytes(&mut iv)?;
+    crypto::encrypt_aead(key.bytes(), &iv, plain)
+}
+
+/*
+ * Input: agent state and data returned by seal()
+ * Return: Result wrap the data, if the attestation state allows it
+ */
+pub(crate) fn unseal(data: &QuoteData, sealed: &[u8]) -> Result<Vec<u8>> {
+    check_len(sealed.len())?;
+    let key = payload_key(data, "unsealing")?;
+    crypto::decrypt_aead(key.bytes(), sealed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{crypto::testing::encrypt_aead, status::AgentStatus};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_check_state() {
+        let status = AgentStatus::new("uuid");
+        assert!(check_state(&status.report(), "decryption").is_err());
+
+        status.set_registration(RegistrationState::Activated);
+        assert!(check_state(&status.report(), "decryption").is_ok());
+
+        status.set_revoked();
+        assert!(check_state(&status.report(), "decryption").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_file() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("secret.enc");
+        let key = SymmKey::try_from(&[0x42u8; 32][..]).unwrap(); //#[allow_ci]
+        let iv = [0x24u8; 16];
+        let encrypted = encrypt_aead(key.bytes(), &iv, b"secret").unwrap(); //#[allow_ci]
+        fs::write(&path, &encrypted).unwrap(); //#[allow_ci]
+        let decrypted = decrypt_file(&key, &path).unwrap(); //#[allow_ci]
+        assert_eq!(decrypted, b"secret");
+
+        let other = SymmKey::try_from(&[0x43u8; 32][..]).unwrap(); //#[allow_ci]
+        assert!(decrypt_file(&other, &path).is_err());
+
+        let too_long = vec![0u8; MAX_FILE_LEN as usize + 1];
+        fs::write(&path, too_long).unwrap(); //#[allow_ci]
+        assert!(decrypt_file(&key, &path).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+// Lockout of peers sending repeated key verification challenges.
+//
+// /keys/verify returns the HMAC of a challenge with the derived key, so that
+// the tenant can check the agent derived the same key. Without mTLS, anyone
+// reaching the agent can use it to check guesses of the key online. The agent
+// cannot check the HMAC the peer compares, so every challenge it answers
+// counts as a failed one, as well as the malformed ones: a tenant only sends
+// one once the key is derived, while challenges refused because the key is
+// not derived yet are not counted, as tenants poll until it is. A peer
+// reaching 'verify_max_failures' within 'verify_lockout' seconds is refused
+// with a 429 response for 'verify_lockout' seconds. Peers are told apart by
+// their certificate with mTLS, by their address otherwise.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Bound on the peers tracked, the ones tracked the longest are forgotten
+// first
+const MAX_PEERS: usize = 10000;
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    // First failure counted, the count is reset once the window passed
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub(crate) struct VerifyLockout {
+    max_failures: u32,
+    lockout: Duration,
+    max_peers: usize,
+    peers: Mutex<HashMap<String, Failures>>,
+}
+
+impl VerifyLockout {
+    pub(crate) fn new(max_failures: u32, lockout: Duration) -> Self {
+        VerifyLockout {
+            max_failures,
+            lockout,
+            max_peers: MAX_PEERS,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /*
+     * Input: peer sending the challenge
+     * Return: how long the peer remains locked out, None when it is not
+     */
+    pub(crate) fn locked(&self, peer: &str) -> Option<Duration> {
+        self.locked_at(peer, Instant::now())
+    }
+
+    fn locked_at(&self, peer: &str, now: Instant) -> Option<Duration> {
+        let peers = self.peers.lock().unwrap(); //#[allow_ci]
+        let until = peers.get(peer)?.locked_until?;
+        Some(until.saturating_duration_since(now)).filter(|d| !d.is_zero())
+    }
+
+    /*
+     * Input: peer that sent a failed challenge
+     * Return: whether the peer is now locked out
+     */
+    pub(crate) fn failed(&self, peer: &str) -> bool {
+        self.failed_at(peer, Instant::now())
+    }
+
+    fn failed_at(&self, peer: &str, now: Instant) -> bool {
+        let mut peers = self.peers.lock().unwrap(); //#[allow_ci]
+        let lockout = self.lockout;
+        let expired = |failures: &Failures| match failures.locked_until {
+            Some(until) => until <= now,
+            None => now.saturating_duration_since(failures.since) >= lockout,
+        };
+
+        if !peers.contains_key(peer) && peers.len() >= self.max_peers {
+            peers.retain(|_, failures| !expired(failures));
+            if peers.len() >= self.max_peers {
+                let oldest = peers
+                    .iter()
+                    .min_by_key(|(_, failures)| failures.since)
+                    .map(|(peer, _)| peer.clone());
+                if let Some(oldest) = oldest {
+                    let _ = peers.remove(&oldest);
+                }
+            }
+        }
+
+        let failures = peers.entry(peer.to_string()).or_insert(Failures {
+            count: 0,
+            since: now,
+            locked_until: None,
+        });
+        if expired(failures) {
+            *failures = Failures {
+                count: 0,
+                since: now,
+                locked_until: None,
+            };
+        }
+        failures.count += 1;
+        if failures.count >= self.max_failures {
+            failures.locked_until = Some(now + lockout);
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lockout() {
+        let lockout = VerifyLockout::new(3, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(!lockout.failed_at("tenant", start));
+        assert!(!lockout.failed_at("tenant", start));
+        assert_eq!(lockout.locked_at("tenant", start), None);
+        assert!(lockout.failed_at("tenant", start));
+        let later = start + Duration::from_secs(10);
+        assert_eq!(
+            lockout.locked_at("tenant", later),
+            Some(Duration::from_secs(50))
+        );
+        // Other peers are not affected
+        assert_eq!(lockout.locked_at("verifier", later), None);
+
+        // Failures are counted from scratch once the lockout expired
+        let later = start + Duration::from_secs(60);
+        assert_eq!(lockout.locked_at("tenant", later), None);
+        assert!(!lockout.failed_at("tenant", later));
+    }
+
+    #[test]
+    fn test_window() {
+        let lockout = VerifyLockout::new(2, Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(!lockout.failed_at("tenant", start));
+        // The first failure is out of the window
+        let later = start + Duration::from_secs(61);
+        assert!(!lockout.failed_at("tenant", later));
+        assert!(lockout.failed_at("tenant", later));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let lockout = VerifyLockout {
+            max_failures: 2,
+            lockout: Duration::from_secs(60),
+            max_peers: 2,
+            peers: Mutex::new(HashMap::new()),
+        };
+        let start = Instant::now();
+        assert!(!lockout.failed_at("a", start));
+        assert!(!lockout.failed_at("b", start + Duration::from_secs(1)));
+        assert!(!lockout.failed_at("c", start + Duration::from_secs(2)));
+        assert_eq!(lockout.peers.lock().unwrap().len(), 2); //#[allow_ci]
+
+        // The failure of the oldest peer was forgotten
+        let now = start + Duration::from_secs(3);
+        assert!(!lockout.failed_at("a", now));
+        assert!(lockout.failed_at("c", now));
+    }
+}
@@ -1,26 +1,108 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2022 Keylime Authors
 
-use crate::common::{JsonWrapper, API_VERSION};
+use crate::api::{self, Endpoint, Serializer};
+use crate::common::JsonWrapper;
+use crate::tpm;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use log::*;
 use serde::{Deserialize, Serialize};
 
+// Features that change the agent behavior, and whether they are enabled
+const FEATURES: &[(&str, bool)] = &[
+    ("with-zmq", cfg!(feature = "with-zmq")),
+    (
+        "legacy-python-actions",
+        cfg!(feature = "legacy-python-actions"),
+    ),
+    ("testing", cfg!(feature = "testing")),
+    ("tpm-replay", cfg!(feature = "tpm-replay")),
+    ("otel", cfg!(feature = "otel")),
+    ("sm", cfg!(feature = "sm")),
+    ("pkcs11", cfg!(feature = "pkcs11")),
+    ("python", cfg!(feature = "python")),
+];
+
+// Names of the features enabled at build time
+pub(crate) fn enabled_features() -> Vec<String> {
+    FEATURES
+        .iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+// What was built and what it runs against, for support triage. The values
+// that are only known at build time are collected by build.rs.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct BuildInfo {
+    pub version: String,
+    pub git_commit: String,
+    pub features: Vec<String>,
+    pub tss_esapi_version: String,
+    pub tss2_esys_version: String,
+    pub tcti: String,
+    pub openssl_version: String,
+}
+
+impl BuildInfo {
+    pub(crate) fn new() -> Self {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_commit: env!("KEYLIME_AGENT_GIT_COMMIT").to_string(),
+            features: enabled_features(),
+            tss_esapi_version: env!("KEYLIME_AGENT_TSS_ESAPI_VERSION")
+                .to_string(),
+            tss2_esys_version: env!("KEYLIME_AGENT_TSS2_ESYS_VERSION")
+                .to_string(),
+            tcti: tpm::tcti_name(),
+            openssl_version: openssl::version::version().to_string(),
+        }
+    }
+}
+
+// Entry of the capability matrix, see api.rs
+#[derive(Serialize, Deserialize, Debug)]
+struct VersionCapabilities {
+    version: String,
+    endpoints: Vec<Endpoint>,
+    serializer: Serializer,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct KeylimeVersion {
+    // The latest version, the only one older verifiers look at
     supported_version: String,
+    supported_versions: Vec<String>,
+    capabilities: Vec<VersionCapabilities>,
+    build_info: BuildInfo,
 }
 
 // This is the handler for the GET request for the API version
 pub async fn version(req: HttpRequest) -> impl Responder {
     info!(
-        "GET invoked from {:?} with uri {}",
-        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+        "GET invoked from {} with uri {}",
+        req.connection_info().peer_addr().unwrap_or("-"),
         req.uri()
     );
 
+    let capabilities = api::API_VERSIONS
+        .iter()
+        .map(|api| VersionCapabilities {
+            version: api.version.to_string()[1..].to_string(),
+            endpoints: api.endpoints.to_vec(),
+            serializer: api.serializer,
+        })
+        .collect::<Vec<VersionCapabilities>>();
+    let versions = capabilities
+        .iter()
+        .map(|capabilities| capabilities.version.clone())
+        .collect::<Vec<String>>();
     let response = JsonWrapper::success(KeylimeVersion {
-        supported_version: API_VERSION[1..].to_string(),
+        supported_version: versions[versions.len() - 1].clone(),
+        supported_versions: versions,
+        capabilities,
+        build_info: BuildInfo::new(),
     });
 
     HttpResponse::Ok().json(response)
@@ -46,6 +128,20 @@ mod tests {
 
         let body: JsonWrapper<KeylimeVersion> =
             test::read_body_json(resp).await;
-        assert_eq!(body.results.supported_version, API_VERSION[1..]);
+        assert_eq!(body.results.supported_version, "2.1");
+        assert_eq!(body.results.supported_versions, vec!["2.0", "2.1"]);
+        let latest = &body.results.capabilities[1];
+        assert_eq!(latest.version, "2.1");
+        assert!(latest.endpoints.contains(&Endpoint::Integrity));
+        assert!(latest.serializer.quote_boottime);
+        assert_eq!(
+            body.results.build_info.version,
+            env!("CARGO_PKG_VERSION")
+        );
+        assert!(body
+            .results
+            .build_info
+            .features
+            .contains(&"testing".to_string()));
     }
 }